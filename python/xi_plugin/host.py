@@ -49,6 +49,19 @@ class PluginPeer(RpcPeer):
     def get_selections(self, view_id):
         return self.send_rpc_sync('get_selections', {'view_id': view_id})
 
+    def service_request(self, view_id, service, method, params):
+        return self.send_rpc_sync('service_request', {'view_id': view_id,
+                                                      'service': service,
+                                                      'method': method,
+                                                      'params': params})
+
+    def begin_undo_group(self, view_id):
+        return self.send_rpc_sync('begin_undo_group', {'view_id': view_id})
+
+    def end_undo_group(self, view_id, group_token):
+        return self.send_rpc_sync('end_undo_group', {'view_id': view_id,
+                                                     'group_token': group_token})
+
 
 class PluginHost(object):
     """Handles raw RPC calls, updating state and calling plugin methods
@@ -111,6 +124,11 @@ class PluginHost(object):
             params['view'] = view
         return getattr(self.plugin, method)(**params)
 
+    def service_request(self, peer, view_id, consumer, service, method, params):
+        '''Request from another plugin for a service this plugin provides.'''
+        view = self.views[view_id]
+        return self.plugin.service_request(view, consumer, service, method, params)
+
     def _initialize_buffers(self, peer, buffer_info):
         for buf in buffer_info:
             lines = LineCache(peer, **buf)
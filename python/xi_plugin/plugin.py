@@ -46,6 +46,10 @@ class Plugin(object):
         self.print_err("update: {}".format(view.view_id))
         return PLUGIN_ACK_OK
 
+    def service_request(self, view, consumer, service, method, params):
+        self.print_err("service_request: {} {} from {}".format(service, method, consumer))
+        return None
+
     def shutdown(self):
         self.print_err("shutdown")
         pass
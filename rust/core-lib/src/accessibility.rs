@@ -0,0 +1,166 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The tracking of changes to a view for accessibility clients, such as
+//! screen readers, which poll for what changed since they last asked
+//! rather than keep a cache of the view's lines.
+
+use std::collections::VecDeque;
+
+use xi_rope::rope::RopeInfo;
+use xi_rope::delta::{Delta, Transformer};
+use xi_rope::interval::Interval;
+
+/// The most changes remembered. A client asking about changes older than
+/// these is told to read the view again.
+pub const MAX_CHANGES: usize = 256;
+
+/// The changes since a token, as returned by `ChangeLog::since`.
+#[derive(Debug, PartialEq)]
+pub struct Changes {
+    /// The ranges of the text which changed, in its current offsets, in
+    /// order and without overlaps.
+    pub ranges: Vec<Interval>,
+    pub selection_changed: bool,
+    /// Whether the text was wrapped differently, so that visual lines
+    /// outside `ranges` may also have changed.
+    pub wrap_changed: bool,
+}
+
+/// A log of a view's changes, each stamped with a generation. A client is
+/// given the current generation as a token, and asks for the changes
+/// since it.
+#[derive(Debug, Default)]
+pub struct ChangeLog {
+    generation: u64,
+    /// The ranges of the text inserted by edits, and their generations,
+    /// oldest first. Ranges are kept in the offsets of the current text.
+    edits: VecDeque<(u64, Interval)>,
+    /// The generation of the newest edit which was forgotten.
+    forgotten: u64,
+    selection_generation: u64,
+    wrap_generation: u64,
+}
+
+impl ChangeLog {
+    /// Returns the token for the changes so far.
+    pub fn token(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn after_edit(&mut self, delta: &Delta<RopeInfo>) {
+        self.generation += 1;
+        let mut transformer = Transformer::new(delta);
+        for &mut (_, ref mut iv) in self.edits.iter_mut() {
+            let start = transformer.transform(iv.start(), false);
+            let end = transformer.transform(iv.end(), true);
+            *iv = Interval::new_closed_open(start, end);
+        }
+        let (iv, new_len) = delta.summary();
+        self.edits.push_back((self.generation, Interval::new_closed_open(iv.start(),
+                                                                         iv.start() + new_len)));
+        while self.edits.len() > MAX_CHANGES {
+            self.forgotten = self.edits.pop_front().unwrap().0;
+        }
+    }
+
+    pub fn selection_changed(&mut self) {
+        self.generation += 1;
+        self.selection_generation = self.generation;
+    }
+
+    pub fn wrap_changed(&mut self) {
+        self.generation += 1;
+        self.wrap_generation = self.generation;
+    }
+
+    /// Returns the changes since `token` was current, or `None` if they
+    /// are no longer known, or `token` was never given out.
+    pub fn since(&self, token: u64) -> Option<Changes> {
+        if token < self.forgotten || token > self.generation {
+            return None;
+        }
+        let mut edited = self.edits.iter()
+            .filter(|&&(generation, _)| generation > token)
+            .map(|&(_, iv)| iv)
+            .collect::<Vec<_>>();
+        edited.sort_by_key(|iv| (iv.start(), iv.end()));
+        let mut ranges: Vec<Interval> = Vec::new();
+        for iv in edited {
+            match ranges.last_mut() {
+                Some(last) if iv.start() <= last.end() => {
+                    *last = Interval::new_closed_open(last.start(), last.end().max(iv.end()));
+                    continue;
+                }
+                _ => (),
+            }
+            ranges.push(iv);
+        }
+        Some(Changes {
+            ranges: ranges,
+            selection_changed: self.selection_generation > token,
+            wrap_changed: self.wrap_generation > token,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xi_rope::rope::Rope;
+
+    fn edit(log: &mut ChangeLog, start: usize, end: usize, new_text: &str, len: usize) {
+        let iv = Interval::new_closed_open(start, end);
+        log.after_edit(&Delta::simple_edit(iv, Rope::from(new_text), len));
+    }
+
+    fn ranges(log: &ChangeLog, token: u64) -> Vec<(usize, usize)> {
+        log.since(token).unwrap().ranges.iter().map(|iv| (iv.start(), iv.end())).collect()
+    }
+
+    #[test]
+    fn changes_since() {
+        let mut log = ChangeLog::default();
+        let start = log.token();
+        edit(&mut log, 10, 10, "abc", 20);
+        let after_first = log.token();
+        // a deletion before the first edit moves it
+        edit(&mut log, 0, 2, "", 23);
+        assert_eq!(ranges(&log, start), vec![(0, 0), (8, 11)]);
+        assert_eq!(ranges(&log, after_first), vec![(0, 0)]);
+        edit(&mut log, 9, 15, "x", 21);
+        assert_eq!(ranges(&log, start), vec![(0, 0), (8, 10)]);
+        assert!(!log.since(start).unwrap().selection_changed);
+
+        let before_sel = log.token();
+        log.selection_changed();
+        let changes = log.since(before_sel).unwrap();
+        assert!(changes.ranges.is_empty() && changes.selection_changed && !changes.wrap_changed);
+        assert!(log.since(log.token() + 1).is_none());
+    }
+
+    #[test]
+    fn old_changes_are_forgotten() {
+        let mut log = ChangeLog::default();
+        let start = log.token();
+        for len in 0..MAX_CHANGES {
+            edit(&mut log, len, len, "a", len);
+        }
+        assert!(log.since(start).is_some());
+        edit(&mut log, 0, 0, "a", MAX_CHANGES);
+        assert!(log.since(start).is_none());
+        assert_eq!(ranges(&log, log.token() - 1), vec![(0, 1)]);
+        assert_eq!(ranges(&log, start + 1), vec![(0, 1), (2, MAX_CHANGES + 1)]);
+    }
+}
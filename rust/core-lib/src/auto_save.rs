@@ -0,0 +1,113 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Saving buffers to their files automatically, as they are edited.
+
+use std::time::{Duration, Instant};
+
+/// When buffers are saved automatically.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AutoSave {
+    /// Only when the user asks.
+    Off,
+    /// Once the buffer hasn't been edited for `Config::auto_save_delay`.
+    AfterDelay,
+    /// When a view into the buffer loses focus.
+    OnFocusLost,
+}
+
+impl Default for AutoSave {
+    fn default() -> Self {
+        AutoSave::Off
+    }
+}
+
+/// Tracks when a buffer is next due to be saved. Each edit postpones the
+/// save, so that a burst of edits results in a single save.
+///
+/// The current time is always passed in, rather than read from the clock.
+#[derive(Debug, Default)]
+pub struct AutoSaveTimer {
+    deadline: Option<Instant>,
+}
+
+impl AutoSaveTimer {
+    /// Notes an edit at `now`, postponing the save until `delay` after it.
+    /// Returns the time at which the save is due.
+    pub fn edited(&mut self, now: Instant, delay: Duration) -> Instant {
+        let deadline = now + delay;
+        self.deadline = Some(deadline);
+        deadline
+    }
+
+    /// Returns `true` if a save is due at `now`, in which case the timer
+    /// is reset.
+    pub fn take_due(&mut self, now: Instant) -> bool {
+        match self.deadline {
+            Some(deadline) if deadline <= now => {
+                self.deadline = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Cancels any pending save, as when the buffer has been saved anyway.
+    pub fn cancel(&mut self) {
+        self.deadline = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn burst_of_edits() {
+        let start = Instant::now();
+        let delay = ms(500);
+        let mut timer = AutoSaveTimer::default();
+        assert!(!timer.take_due(start));
+        // typing every 100ms keeps postponing the save
+        let mut deadlines = Vec::new();
+        for i in 0..10 {
+            deadlines.push(timer.edited(start + ms(i * 100), delay));
+            assert!(!timer.take_due(start + ms(i * 100 + 50)));
+        }
+        // the timers scheduled for earlier deadlines find nothing to do
+        for &deadline in deadlines[..9].iter() {
+            assert!(!timer.take_due(deadline));
+        }
+        assert!(timer.take_due(deadlines[9]));
+        // and the save happens once
+        assert!(!timer.take_due(deadlines[9] + ms(1000)));
+    }
+
+    #[test]
+    fn cancel() {
+        let start = Instant::now();
+        let mut timer = AutoSaveTimer::default();
+        let deadline = timer.edited(start, ms(500));
+        timer.cancel();
+        assert!(!timer.take_due(deadline));
+        let deadline = timer.edited(start + ms(600), ms(500));
+        assert!(!timer.take_due(deadline - ms(1)));
+        assert!(timer.take_due(deadline + ms(1)));
+    }
+}
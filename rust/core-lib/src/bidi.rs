@@ -0,0 +1,673 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The Unicode bidirectional algorithm (UAX #9), which resolves the
+//! embedding levels of a line mixing left-to-right and right-to-left
+//! text, and the order its runs are displayed in.
+//!
+//! Each line is a paragraph, whose direction is that of its first strong
+//! character. Bidi classes are known for the common scripts, rather than
+//! from the full Unicode data, and only ASCII brackets are paired.
+
+use self::BidiClass::*;
+
+/// The deepest embedding level.
+const MAX_DEPTH: u8 = 125;
+/// The most brackets which are paired on a line.
+const MAX_BRACKETS: usize = 63;
+
+/// How the caret moves left and right.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CaretMovement {
+    /// In the order the text is stored, so that moving right in
+    /// right-to-left text moves the caret left.
+    Logical,
+    /// In the order the text is displayed.
+    Visual,
+}
+
+impl Default for CaretMovement {
+    fn default() -> Self {
+        CaretMovement::Logical
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BidiClass {
+    L, R, AL, EN, ES, ET, AN, CS, NSM, BN, B, S, WS, ON,
+    LRE, LRO, RLE, RLO, PDF, LRI, RLI, FSI, PDI,
+}
+
+pub fn bidi_class(c: char) -> BidiClass {
+    match c {
+        '0'..='9' | '\u{B2}' | '\u{B3}' | '\u{B9}' | '\u{6F0}'..='\u{6F9}' | '\u{2070}' |
+        '\u{2074}'..='\u{2079}' | '\u{2080}'..='\u{2089}' | '\u{FF10}'..='\u{FF19}' => EN,
+        '+' | '-' | '\u{207A}' | '\u{207B}' | '\u{208A}' | '\u{208B}' | '\u{2212}' |
+        '\u{FE62}' | '\u{FE63}' | '\u{FF0B}' | '\u{FF0D}' => ES,
+        '#' | '$' | '%' | '\u{A2}'..='\u{A5}' | '\u{B0}' | '\u{B1}' | '\u{609}' | '\u{60A}' |
+        '\u{66A}' | '\u{2030}'..='\u{2034}' | '\u{20A0}'..='\u{20CF}' => ET,
+        ',' | '.' | '/' | ':' | '\u{A0}' | '\u{60C}' | '\u{202F}' | '\u{2044}' |
+        '\u{FF0C}' | '\u{FF0E}' | '\u{FF0F}' | '\u{FF1A}' => CS,
+        '\n' | '\r' | '\u{1C}'..='\u{1E}' | '\u{85}' | '\u{2029}' => B,
+        '\t' | '\u{B}' | '\u{1F}' => S,
+        ' ' | '\u{C}' | '\u{1680}' | '\u{2000}'..='\u{200A}' | '\u{2028}' | '\u{205F}' |
+        '\u{3000}' => WS,
+        '\u{0}'..='\u{8}' | '\u{E}'..='\u{1B}' | '\u{7F}'..='\u{84}' | '\u{86}'..='\u{9F}' |
+        '\u{AD}' | '\u{180E}' | '\u{200B}'..='\u{200D}' | '\u{2060}'..='\u{2064}' |
+        '\u{FEFF}' => BN,
+        '\u{200E}' => L,
+        '\u{200F}' => R,
+        '\u{61C}' => AL,
+        '\u{202A}' => LRE,
+        '\u{202B}' => RLE,
+        '\u{202C}' => PDF,
+        '\u{202D}' => LRO,
+        '\u{202E}' => RLO,
+        '\u{2066}' => LRI,
+        '\u{2067}' => RLI,
+        '\u{2068}' => FSI,
+        '\u{2069}' => PDI,
+        '\u{300}'..='\u{36F}' | '\u{483}'..='\u{489}' | '\u{591}'..='\u{5BD}' | '\u{5BF}' |
+        '\u{5C1}' | '\u{5C2}' | '\u{5C4}' | '\u{5C5}' | '\u{5C7}' | '\u{610}'..='\u{61A}' |
+        '\u{64B}'..='\u{65F}' | '\u{670}' | '\u{6D6}'..='\u{6DC}' | '\u{6DF}'..='\u{6E4}' |
+        '\u{6E7}' | '\u{6E8}' | '\u{6EA}'..='\u{6ED}' | '\u{711}' | '\u{730}'..='\u{74A}' |
+        '\u{7A6}'..='\u{7B0}' | '\u{7EB}'..='\u{7F3}' | '\u{1AB0}'..='\u{1AFF}' |
+        '\u{1DC0}'..='\u{1DFF}' | '\u{20D0}'..='\u{20FF}' | '\u{FB1E}' | '\u{FE00}'..='\u{FE0F}' |
+        '\u{FE20}'..='\u{FE2F}' => NSM,
+        '\u{600}'..='\u{605}' | '\u{660}'..='\u{669}' | '\u{66B}' | '\u{66C}' | '\u{6DD}' => AN,
+        '\u{590}'..='\u{5FF}' | '\u{7C0}'..='\u{85F}' | '\u{FB1D}'..='\u{FB4F}' |
+        '\u{10800}'..='\u{10FFF}' | '\u{1E800}'..='\u{1EDFF}' => R,
+        '\u{600}'..='\u{7BF}' | '\u{860}'..='\u{8FF}' | '\u{FB50}'..='\u{FDFF}' |
+        '\u{FE70}'..='\u{FEFE}' | '\u{1EE00}'..='\u{1EEFF}' => AL,
+        c if c.is_alphanumeric() => L,
+        c if c.is_whitespace() => WS,
+        _ => ON,
+    }
+}
+
+/// A run of a line at one embedding level, as byte offsets into the line.
+/// Text at an odd level is right-to-left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelRun {
+    pub start: usize,
+    pub end: usize,
+    pub level: u8,
+}
+
+impl LevelRun {
+    pub fn is_rtl(&self) -> bool {
+        self.level % 2 == 1
+    }
+}
+
+/// The resolved levels of a line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineBidi {
+    /// The paragraph level, 1 if the line is right-to-left.
+    pub level: u8,
+    /// The runs of the line, in logical order.
+    pub runs: Vec<LevelRun>,
+}
+
+fn is_removed(class: BidiClass) -> bool {
+    match class {
+        RLE | LRE | RLO | LRO | PDF | BN => true,
+        _ => false,
+    }
+}
+
+fn is_isolate_initiator(class: BidiClass) -> bool {
+    match class {
+        LRI | RLI | FSI => true,
+        _ => false,
+    }
+}
+
+fn is_neutral(class: BidiClass) -> bool {
+    match class {
+        B | S | WS | ON | LRI | RLI | FSI | PDI => true,
+        _ => false,
+    }
+}
+
+/// The direction of a resolved class, for neutrals, in which numbers are
+/// right-to-left.
+fn strong_direction(class: BidiClass) -> Option<BidiClass> {
+    match class {
+        L => Some(L),
+        R | AL | EN | AN => Some(R),
+        _ => None,
+    }
+}
+
+fn direction_of_level(level: u8) -> BidiClass {
+    if level % 2 == 1 { R } else { L }
+}
+
+fn next_level(level: u8, rtl: bool) -> u8 {
+    if rtl { (level + 1) | 1 } else { (level + 2) & !1 }
+}
+
+/// Returns the level of the first strong character of `classes`, skipping
+/// isolates. If `in_isolate`, the search ends at the isolate's PDI.
+fn first_strong_level(classes: &[BidiClass], in_isolate: bool) -> Option<u8> {
+    let mut depth = 0;
+    for &class in classes {
+        match class {
+            L if depth == 0 => return Some(0),
+            R | AL if depth == 0 => return Some(1),
+            LRI | RLI | FSI => depth += 1,
+            PDI if depth > 0 => depth -= 1,
+            PDI if in_isolate => return None,
+            _ => (),
+        }
+    }
+    None
+}
+
+#[derive(Clone, Copy)]
+struct Embedding {
+    level: u8,
+    override_class: Option<BidiClass>,
+    isolate: bool,
+}
+
+/// Resolves the explicit levels of `initial` (rules X1 to X8), returning
+/// the levels and the classes, with overrides applied.
+fn explicit_levels(initial: &[BidiClass], para: u8) -> (Vec<u8>, Vec<BidiClass>) {
+    let mut levels = vec![para; initial.len()];
+    let mut classes = initial.to_vec();
+    let mut stack = vec![Embedding { level: para, override_class: None, isolate: false }];
+    let mut overflow_isolates = 0;
+    let mut overflow_embeddings = 0;
+    let mut valid_isolates = 0;
+    for (i, &class) in initial.iter().enumerate() {
+        let top = *stack.last().unwrap();
+        match class {
+            RLE | LRE | RLO | LRO => {
+                levels[i] = top.level;
+                let level = next_level(top.level, class == RLE || class == RLO);
+                if level <= MAX_DEPTH && overflow_isolates == 0 && overflow_embeddings == 0 {
+                    let override_class = match class {
+                        RLO => Some(R),
+                        LRO => Some(L),
+                        _ => None,
+                    };
+                    stack.push(Embedding { level: level, override_class: override_class,
+                                           isolate: false });
+                } else if overflow_isolates == 0 {
+                    overflow_embeddings += 1;
+                }
+            }
+            LRI | RLI | FSI => {
+                levels[i] = top.level;
+                if let Some(class) = top.override_class {
+                    classes[i] = class;
+                }
+                let rtl = match class {
+                    RLI => true,
+                    LRI => false,
+                    _ => first_strong_level(&initial[i + 1..], true) == Some(1),
+                };
+                let level = next_level(top.level, rtl);
+                if level <= MAX_DEPTH && overflow_isolates == 0 && overflow_embeddings == 0 {
+                    valid_isolates += 1;
+                    stack.push(Embedding { level: level, override_class: None, isolate: true });
+                } else {
+                    overflow_isolates += 1;
+                }
+            }
+            PDI => {
+                if overflow_isolates > 0 {
+                    overflow_isolates -= 1;
+                } else if valid_isolates > 0 {
+                    overflow_embeddings = 0;
+                    while !stack.last().unwrap().isolate {
+                        stack.pop();
+                    }
+                    stack.pop();
+                    valid_isolates -= 1;
+                }
+                let top = *stack.last().unwrap();
+                levels[i] = top.level;
+                if let Some(class) = top.override_class {
+                    classes[i] = class;
+                }
+            }
+            PDF => {
+                levels[i] = top.level;
+                if overflow_isolates > 0 {
+                } else if overflow_embeddings > 0 {
+                    overflow_embeddings -= 1;
+                } else if !top.isolate && stack.len() >= 2 {
+                    stack.pop();
+                }
+            }
+            B => levels[i] = para,
+            BN => levels[i] = top.level,
+            _ => {
+                levels[i] = top.level;
+                if let Some(class) = top.override_class {
+                    classes[i] = class;
+                }
+            }
+        }
+    }
+    (levels, classes)
+}
+
+/// Returns the isolating run sequences (rule X10) of the characters
+/// `kept`, which are those not removed by rule X9, as indices into `kept`.
+fn isolating_run_sequences(kept: &[usize], levels: &[u8], initial: &[BidiClass])
+    -> Vec<Vec<usize>>
+{
+    // the matching PDI of each isolate initiator
+    let mut matching = vec![None; kept.len()];
+    let mut is_matched = vec![false; kept.len()];
+    let mut open = Vec::new();
+    for (k, &i) in kept.iter().enumerate() {
+        match initial[i] {
+            LRI | RLI | FSI => open.push(k),
+            PDI => if let Some(initiator) = open.pop() {
+                matching[initiator] = Some(k);
+                is_matched[k] = true;
+            },
+            B => open.clear(),
+            _ => (),
+        }
+    }
+
+    let mut runs: Vec<Vec<usize>> = Vec::new();
+    let mut run_of = vec![0; kept.len()];
+    for k in 0..kept.len() {
+        let same_level = k > 0 && levels[kept[k]] == levels[kept[k - 1]];
+        if !same_level {
+            runs.push(Vec::new());
+        }
+        runs.last_mut().unwrap().push(k);
+        run_of[k] = runs.len() - 1;
+    }
+
+    let mut sequences = Vec::new();
+    for run in runs.iter() {
+        if is_matched[run[0]] {
+            continue;
+        }
+        let mut sequence = run.clone();
+        while let Some(pdi) = matching[*sequence.last().unwrap()] {
+            sequence.extend(runs[run_of[pdi]].iter());
+        }
+        sequences.push(sequence);
+    }
+    sequences
+}
+
+/// Resolves the weak and neutral classes of an isolating run sequence
+/// (rules W1 to N2), and its implicit levels (I1 and I2).
+fn resolve_sequence(sequence: &[usize], chars: &[char], classes: &[BidiClass],
+                    levels: &mut [u8], sos: BidiClass, eos: BidiClass) {
+    let level = levels[sequence[0]];
+    let mut t = sequence.iter().map(|&i| classes[i]).collect::<Vec<_>>();
+    let n = t.len();
+
+    // W1
+    for j in 0..n {
+        if t[j] == NSM {
+            t[j] = match j {
+                0 => sos,
+                _ if is_isolate_initiator(t[j - 1]) || t[j - 1] == PDI => ON,
+                _ => t[j - 1],
+            };
+        }
+    }
+    // W2 and W3
+    let mut last_strong = sos;
+    for class in t.iter_mut() {
+        match *class {
+            L | R => last_strong = *class,
+            AL => {
+                last_strong = AL;
+                *class = R;
+            }
+            EN if last_strong == AL => *class = AN,
+            _ => (),
+        }
+    }
+    // W4
+    for j in 1..n.saturating_sub(1) {
+        match (t[j - 1], t[j], t[j + 1]) {
+            (EN, ES, EN) | (EN, CS, EN) => t[j] = EN,
+            (AN, CS, AN) => t[j] = AN,
+            _ => (),
+        }
+    }
+    // W5
+    let mut j = 0;
+    while j < n {
+        if t[j] != ET {
+            j += 1;
+            continue;
+        }
+        let end = (j..n).find(|&k| t[k] != ET).unwrap_or(n);
+        if (j > 0 && t[j - 1] == EN) || (end < n && t[end] == EN) {
+            for class in t[j..end].iter_mut() {
+                *class = EN;
+            }
+        }
+        j = end;
+    }
+    // W6 and W7
+    let mut last_strong = sos;
+    for class in t.iter_mut() {
+        match *class {
+            ES | ET | CS => *class = ON,
+            L | R => last_strong = *class,
+            EN if last_strong == L => *class = L,
+            _ => (),
+        }
+    }
+
+    let embedding = direction_of_level(level);
+    // N0, for ASCII brackets
+    let mut pairs = Vec::new();
+    let mut open: Vec<(usize, char)> = Vec::new();
+    for j in 0..n {
+        if t[j] != ON {
+            continue;
+        }
+        match chars[sequence[j]] {
+            c @ '(' | c @ '[' | c @ '{' => {
+                if open.len() == MAX_BRACKETS {
+                    break;
+                }
+                open.push((j, c));
+            }
+            c @ ')' | c @ ']' | c @ '}' => {
+                let opener = match c { ')' => '(', ']' => '[', _ => '{' };
+                if let Some(pos) = open.iter().rposition(|&(_, o)| o == opener) {
+                    pairs.push((open[pos].0, j));
+                    open.truncate(pos);
+                }
+            }
+            _ => (),
+        }
+    }
+    pairs.sort();
+    for (open, close) in pairs {
+        let inside = t[open + 1..close].iter().filter_map(|&c| strong_direction(c))
+            .collect::<Vec<_>>();
+        let direction = if inside.contains(&embedding) {
+            embedding
+        } else if !inside.is_empty() {
+            let before = t[..open].iter().rev().filter_map(|&c| strong_direction(c)).next()
+                .unwrap_or(sos);
+            if before != embedding { before } else { embedding }
+        } else {
+            continue;
+        };
+        t[open] = direction;
+        t[close] = direction;
+    }
+
+    // N1 and N2
+    let mut j = 0;
+    while j < n {
+        if !is_neutral(t[j]) {
+            j += 1;
+            continue;
+        }
+        let end = (j..n).find(|&k| !is_neutral(t[k])).unwrap_or(n);
+        let before = if j == 0 { sos } else { strong_direction(t[j - 1]).unwrap_or(embedding) };
+        let after = if end == n { eos } else { strong_direction(t[end]).unwrap_or(embedding) };
+        let direction = if before == after { before } else { embedding };
+        for class in t[j..end].iter_mut() {
+            *class = direction;
+        }
+        j = end;
+    }
+
+    // I1 and I2
+    for (j, &i) in sequence.iter().enumerate() {
+        levels[i] += match (level % 2, t[j]) {
+            (0, R) => 1,
+            (0, AN) | (0, EN) => 2,
+            (1, L) | (1, EN) | (1, AN) => 1,
+            _ => 0,
+        };
+    }
+}
+
+/// Resolves the levels of `line`, or returns `None` if it has no
+/// right-to-left text, so that all of it is at level 0.
+pub fn resolve(line: &str) -> Option<LineBidi> {
+    let chars = line.char_indices().collect::<Vec<_>>();
+    let initial = chars.iter().map(|&(_, c)| bidi_class(c)).collect::<Vec<_>>();
+    let is_rtl = |class: &BidiClass| match *class {
+        R | AL | AN | RLE | RLO | RLI => true,
+        _ => false,
+    };
+    if !initial.iter().any(is_rtl) {
+        return None;
+    }
+    let para = first_strong_level(&initial, false).unwrap_or(0);
+    let (mut levels, classes) = explicit_levels(&initial, para);
+
+    let kept = (0..chars.len()).filter(|&i| !is_removed(initial[i])).collect::<Vec<_>>();
+    let just_chars = chars.iter().map(|&(_, c)| c).collect::<Vec<_>>();
+    let explicit = levels.clone();
+    for sequence in isolating_run_sequences(&kept, &explicit, &initial) {
+        let sequence = sequence.iter().map(|&k| kept[k]).collect::<Vec<_>>();
+        let first = sequence[0];
+        let last = *sequence.last().unwrap();
+        let level = explicit[first];
+        let before = kept.iter().rev().find(|&&i| i < first).map_or(para, |&i| explicit[i]);
+        let after = match kept.iter().find(|&&i| i > last) {
+            _ if is_isolate_initiator(initial[last]) => para,
+            Some(&i) => explicit[i],
+            None => para,
+        };
+        resolve_sequence(&sequence, &just_chars, &classes, &mut levels,
+                         direction_of_level(level.max(before)),
+                         direction_of_level(level.max(after)));
+    }
+
+    // removed characters take the level of the character before them
+    for i in 0..chars.len() {
+        if is_removed(initial[i]) {
+            levels[i] = if i == 0 { para } else { levels[i - 1] };
+        }
+    }
+    // L1: separators, and the whitespace before them and at the end of
+    // the line, are at the paragraph level
+    let mut trailing = true;
+    for i in (0..chars.len()).rev() {
+        match initial[i] {
+            S | B => {
+                levels[i] = para;
+                trailing = true;
+            }
+            WS | LRI | RLI | FSI | PDI if trailing => levels[i] = para,
+            class if trailing && is_removed(class) => levels[i] = para,
+            _ => trailing = false,
+        }
+    }
+
+    let mut runs: Vec<LevelRun> = Vec::new();
+    for (i, &(start, c)) in chars.iter().enumerate() {
+        let end = start + c.len_utf8();
+        match runs.last_mut() {
+            Some(run) if run.level == levels[i] => {
+                run.end = end;
+                continue;
+            }
+            _ => (),
+        }
+        runs.push(LevelRun { start: start, end: end, level: levels[i] });
+    }
+    Some(LineBidi { level: para, runs: runs })
+}
+
+/// Returns the runs of `bidi` within `start..end` of its line, in the
+/// order they are displayed, from left to right.
+pub fn visual_runs(bidi: &LineBidi, start: usize, end: usize) -> Vec<LevelRun> {
+    let mut runs = bidi.runs.iter()
+        .filter(|run| run.end > start && run.start < end)
+        .map(|run| LevelRun { start: run.start.max(start), end: run.end.min(end), ..*run })
+        .collect::<Vec<_>>();
+    let highest = match runs.iter().map(|run| run.level).max() {
+        Some(level) => level,
+        None => return runs,
+    };
+    let lowest_odd = runs.iter().map(|run| run.level).min().unwrap() | 1;
+    // L2: from the highest level to the lowest odd level, each sequence
+    // of runs at that level or higher is reversed
+    let mut level = highest;
+    while level >= lowest_odd {
+        let mut i = 0;
+        while i < runs.len() {
+            if runs[i].level < level {
+                i += 1;
+                continue;
+            }
+            let end = (i..runs.len()).find(|&j| runs[j].level < level).unwrap_or(runs.len());
+            runs[i..end].reverse();
+            i = end;
+        }
+        level -= 1;
+    }
+    runs
+}
+
+/// Returns the parts of `sel_start..sel_end` in each run of `start..end`
+/// of the line, in the order they are displayed, so that a selection
+/// crossing direction boundaries can be drawn as contiguous ranges.
+pub fn visual_ranges(bidi: &LineBidi, start: usize, end: usize, sel_start: usize,
+                     sel_end: usize) -> Vec<(usize, usize)> {
+    visual_runs(bidi, start, end).iter()
+        .filter(|run| run.end > sel_start && run.start < sel_end)
+        .map(|run| (run.start.max(sel_start), run.end.min(sel_end)))
+        .collect()
+}
+
+/// Returns the offset of the caret at `offset`, within `start..end` of
+/// `line`, moved one character left or right as displayed, or `None` if
+/// it is at that edge. Where two offsets are displayed at the same place,
+/// as at a boundary between directions, one of them is skipped.
+pub fn move_visually(line: &str, bidi: &LineBidi, start: usize, end: usize, offset: usize,
+                     right: bool) -> Option<usize> {
+    let end = start + line[start..end].trim_end_matches(|c| c == '\n' || c == '\r').len();
+    // the characters, with their combining marks, from left to right
+    let mut slots = Vec::new();
+    for run in visual_runs(bidi, start, end) {
+        let mut clusters: Vec<(usize, usize)> = Vec::new();
+        for (ix, c) in line[run.start..run.end].char_indices() {
+            let (char_start, char_end) = (run.start + ix, run.start + ix + c.len_utf8());
+            match clusters.last_mut() {
+                Some(cluster) if bidi_class(c) == NSM => cluster.1 = char_end,
+                _ => clusters.push((char_start, char_end)),
+            }
+        }
+        if run.is_rtl() {
+            clusters.reverse();
+        }
+        slots.extend(clusters.into_iter().map(|(s, e)| (s, e, run.is_rtl())));
+    }
+    let n = slots.len();
+    if n == 0 {
+        return None;
+    }
+    // the offset at the left edge of slot `b`, preferring the edge of a
+    // left-to-right character
+    let offset_of_boundary = |b: usize| match (b.checked_sub(1).map(|b| slots[b]), slots.get(b)) {
+        (Some((_, e, false)), _) => e,
+        (_, Some(&(_, e, true))) => e,
+        (_, Some(&(s, _, false))) => s,
+        (Some((s, _, true)), None) => s,
+        (None, None) => unreachable!(),
+    };
+    let boundary = (0..n + 1).find(|&b| offset_of_boundary(b) == offset)?;
+    match right {
+        true if boundary < n => Some(offset_of_boundary(boundary + 1)),
+        false if boundary > 0 => Some(offset_of_boundary(boundary - 1)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runs(line: &str) -> Vec<(usize, usize, u8)> {
+        resolve(line).unwrap().runs.iter().map(|r| (r.start, r.end, r.level)).collect()
+    }
+
+    fn visual(line: &str) -> Vec<(usize, usize, u8)> {
+        let bidi = resolve(line).unwrap();
+        visual_runs(&bidi, 0, line.len()).iter().map(|r| (r.start, r.end, r.level)).collect()
+    }
+
+    #[test]
+    fn level_runs() {
+        assert_eq!(resolve("plain ascii\n"), None);
+        // hebrew in a left-to-right line
+        assert_eq!(runs("abc אבג def\n"), vec![(0, 4, 0), (4, 10, 1), (10, 15, 0)]);
+        // english and numbers in a right-to-left line
+        assert_eq!(runs("אבג abc 123."), vec![(0, 7, 1), (7, 14, 2), (14, 15, 1)]);
+        assert_eq!(resolve("אבג").unwrap().level, 1);
+        // arabic digits after arabic letters are arabic numbers
+        assert_eq!(runs("سعر 42"), vec![(0, 7, 1), (7, 9, 2)]);
+        // an override, and the trailing whitespace of a line
+        assert_eq!(runs("\u{202E}abc\u{202C} "), vec![(0, 3, 0), (3, 6, 1), (6, 10, 0)]);
+        // an isolate keeps its contents from affecting what surrounds it
+        assert_eq!(runs("a \u{2067}b\u{2069} 1"), vec![(0, 5, 0), (5, 6, 2), (6, 11, 0)]);
+        // brackets around right-to-left text are right-to-left
+        assert_eq!(runs("x ב(ג)"), vec![(0, 2, 0), (2, 8, 1)]);
+    }
+
+    #[test]
+    fn visual_order() {
+        assert_eq!(visual("abc אבג def"), vec![(0, 4, 0), (4, 10, 1), (10, 14, 0)]);
+        assert_eq!(visual("אבג abc 123."), vec![(14, 15, 1), (7, 14, 2), (0, 7, 1)]);
+        let bidi = resolve("אבג abc 123.").unwrap();
+        assert_eq!(visual_runs(&bidi, 2, 9), vec![
+            LevelRun { start: 7, end: 9, level: 2 },
+            LevelRun { start: 2, end: 7, level: 1 },
+        ]);
+        // a selection crossing direction boundaries
+        assert_eq!(visual_ranges(&bidi, 0, 15, 4, 15), vec![(14, 15), (7, 14), (4, 7)]);
+    }
+
+    #[test]
+    fn visual_caret_movement() {
+        let moves = |line: &str, mut offset: usize, right: bool| {
+            let bidi = resolve(line).unwrap();
+            let mut offsets = Vec::new();
+            while let Some(next) = move_visually(line, &bidi, 0, line.len(), offset, right) {
+                offsets.push(next);
+                offset = next;
+            }
+            offsets
+        };
+        let ltr = "ab אב cd\n";
+        assert_eq!(moves(ltr, 0, true), vec![1, 2, 3, 5, 7, 8, 9, 10]);
+        assert_eq!(moves(ltr, 10, false), vec![9, 8, 7, 5, 3, 2, 1, 0]);
+        // in a right-to-left line, moving left moves forward
+        let rtl = "אב cd";
+        assert_eq!(moves(rtl, 0, false), vec![2, 4, 7, 6, 5]);
+        assert_eq!(moves(rtl, 5, true), vec![6, 7, 4, 2, 0]);
+        // a combining mark moves with its letter
+        assert_eq!(moves("אּב", 0, false), vec![4, 6]);
+    }
+}
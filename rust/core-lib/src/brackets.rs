@@ -0,0 +1,233 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Matching brackets, for moving and selecting by bracketed groups, as
+//! by s-expressions.
+
+use xi_rope::rope::Rope;
+use xi_rope::interval::Interval;
+
+const BRACKETS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('{', '}')];
+
+/// The number of bytes scanned in each direction for brackets.
+const SCAN_LIMIT: usize = 1 << 20;
+
+/// Returns the whole lines of `text` scanned for the brackets around the
+/// region from `start` to `end`.
+pub fn scan_window(text: &Rope, start: usize, end: usize) -> Interval {
+    let scan_start = text.offset_of_line(text.line_of_offset(start.saturating_sub(SCAN_LIMIT)));
+    let scan_end_line = text.line_of_offset((end + SCAN_LIMIT).min(text.len())) + 1;
+    let scan_end = if scan_end_line > text.line_of_offset(text.len()) { text.len() } else {
+        text.offset_of_line(scan_end_line)
+    };
+    Interval::new_closed_open(scan_start, scan_end)
+}
+
+/// The matched pairs of brackets around a region of text, as the offsets of
+/// the opening and closing brackets.
+#[derive(Debug)]
+pub struct BracketPairs {
+    /// Ordered by the opening bracket.
+    pairs: Vec<(usize, usize)>,
+}
+
+impl BracketPairs {
+    /// Matches the brackets around the region from `start` to `end`.
+    ///
+    /// Brackets within `literals`, such as strings and comments, are
+    /// ignored. If `literals` is `None`, brackets within double quoted
+    /// strings are. Fails if the brackets are unbalanced, other than beyond
+    /// the scanned text.
+    pub fn new(text: &Rope, start: usize, end: usize, literals: Option<&[Interval]>)
+               -> Result<Self, String> {
+        let window = scan_window(text, start, end);
+        let (scan_start, scan_end) = (window.start(), window.end());
+
+        let mut literals = literals.map(|literals| {
+            let mut literals = literals.to_vec();
+            literals.sort_by_key(|iv| iv.start());
+            literals
+        });
+        let mut next_literal = 0;
+        let mut in_string = false;
+        let mut escaped = false;
+        let mut open = Vec::new();
+        let mut pairs = Vec::new();
+        let unbalanced = |offset: usize, c: char| {
+            format!("unbalanced '{}' on line {}", c, text.line_of_offset(offset) + 1)
+        };
+
+        let scanned = text.slice_to_string(scan_start, scan_end);
+        for (ix, c) in scanned.char_indices() {
+            let offset = scan_start + ix;
+            match literals {
+                Some(ref mut literals) => {
+                    while next_literal < literals.len() && literals[next_literal].end() <= offset {
+                        next_literal += 1;
+                    }
+                    if literals[next_literal..].iter().take_while(|iv| iv.start() <= offset)
+                        .any(|iv| iv.contains(offset)) {
+                        continue;
+                    }
+                }
+                None => {
+                    if in_string {
+                        match c {
+                            '\\' => escaped = !escaped,
+                            '"' if !escaped => in_string = false,
+                            _ => escaped = false,
+                        }
+                        continue;
+                    } else if c == '"' {
+                        in_string = true;
+                        escaped = false;
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(&(_, close)) = BRACKETS.iter().find(|&&(o, _)| o == c) {
+                open.push((offset, close));
+            } else if BRACKETS.iter().any(|&(_, cl)| cl == c) {
+                match open.pop() {
+                    Some((open_offset, close)) if close == c => pairs.push((open_offset, offset)),
+                    Some(_) => return Err(unbalanced(offset, c)),
+                    // the opening bracket may be before the scanned text
+                    None if scan_start > 0 => (),
+                    None => return Err(unbalanced(offset, c)),
+                }
+            }
+        }
+        if scan_end == text.len() {
+            if let Some(&(offset, _)) = open.last() {
+                return Err(unbalanced(offset, text.slice_to_string(offset, offset + 1)
+                    .chars().next().unwrap()));
+            }
+        }
+        pairs.sort();
+        Ok(BracketPairs { pairs: pairs })
+    }
+
+    /// Returns the innermost pair enclosing the region from `start` to `end`.
+    pub fn enclosing(&self, start: usize, end: usize) -> Option<(usize, usize)> {
+        self.pairs.iter()
+            .filter(|&&(open, close)| open < start && end <= close)
+            .last()
+            .cloned()
+    }
+
+    /// Returns the innermost pair enclosing `pair`.
+    fn parent(&self, pair: (usize, usize)) -> Option<(usize, usize)> {
+        self.enclosing(pair.0, pair.1 + 1)
+    }
+
+    /// Returns the last pair before `offset`, within the same pair as it.
+    pub fn group_before(&self, offset: usize) -> Option<(usize, usize)> {
+        let parent = self.enclosing(offset, offset);
+        self.pairs.iter().cloned()
+            .filter(|&(_, close)| close < offset)
+            .max_by_key(|&(_, close)| close)
+            .filter(|&pair| self.parent(pair) == parent)
+    }
+
+    /// Returns the first pair after `offset`, within the same pair as it.
+    pub fn group_after(&self, offset: usize) -> Option<(usize, usize)> {
+        let parent = self.enclosing(offset, offset);
+        self.pairs.iter().cloned()
+            .find(|&(open, _)| open >= offset)
+            .filter(|&pair| self.parent(pair) == parent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r#"{"a": [1, {"b": "(]"}], "c": {"d": [[], [2]]}}"#;
+
+    const LISP: &str = "(defun sq (x)\n  \"Squares (x.\"\n  (* x x))\n\n(sq 3)\n";
+
+    fn pairs(text: &str, start: usize, end: usize) -> Result<BracketPairs, String> {
+        BracketPairs::new(&Rope::from(text), start, end, None)
+    }
+
+    fn slice(text: &str, pair: Option<(usize, usize)>) -> &str {
+        let (open, close) = pair.unwrap();
+        &text[open..close + 1]
+    }
+
+    #[test]
+    fn nested_json() {
+        let caret = JSON.find('2').unwrap();
+        let pairs = pairs(JSON, caret, caret).unwrap();
+        let inner = pairs.enclosing(caret, caret);
+        assert_eq!(slice(JSON, inner), "[2]");
+        let (open, close) = inner.unwrap();
+        let outer = pairs.enclosing(open, close + 1);
+        assert_eq!(slice(JSON, outer), "[[], [2]]");
+        let outer = outer.unwrap();
+        assert_eq!(slice(JSON, pairs.enclosing(outer.0, outer.1 + 1)), r#"{"d": [[], [2]]}"#);
+        // the brackets in the string are ignored
+        let caret = JSON.find('1').unwrap();
+        assert_eq!(slice(JSON, pairs.enclosing(caret, caret)), r#"[1, {"b": "(]"}]"#);
+    }
+
+    #[test]
+    fn groups() {
+        let caret = JSON.find(", [2]").unwrap();
+        let pairs = pairs(JSON, caret, caret).unwrap();
+        assert_eq!(slice(JSON, pairs.group_before(caret)), "[]");
+        assert_eq!(slice(JSON, pairs.group_after(caret)), "[2]");
+        // groups must be siblings
+        let caret = JSON.find("\"c\"").unwrap();
+        assert_eq!(slice(JSON, pairs.group_before(caret)), r#"[1, {"b": "(]"}]"#);
+        assert_eq!(slice(JSON, pairs.group_after(caret)), r#"{"d": [[], [2]]}"#);
+        let caret = JSON.find("[[").unwrap() + 1;
+        assert_eq!(pairs.group_before(caret), None);
+    }
+
+    #[test]
+    fn lisp() {
+        let caret = LISP.find("x x").unwrap();
+        let pairs = pairs(LISP, caret, caret).unwrap();
+        assert_eq!(slice(LISP, pairs.enclosing(caret, caret)), "(* x x)");
+        let caret = LISP.find("Squares").unwrap();
+        assert!(slice(LISP, pairs.enclosing(caret, caret)).starts_with("(defun"));
+        assert_eq!(slice(LISP, pairs.group_before(caret)), "(x)");
+        let caret = LISP.find("\n\n").unwrap() + 1;
+        assert!(slice(LISP, pairs.group_before(caret)).starts_with("(defun"));
+        assert_eq!(slice(LISP, pairs.group_after(caret)), "(sq 3)");
+        assert_eq!(pairs.enclosing(caret, caret), None);
+    }
+
+    #[test]
+    fn literals() {
+        // with scopes, the quote in the comment doesn't start a string
+        let text = "(a ; \" (\n b)";
+        let literals = [Interval::new_closed_open(3, 8)];
+        let pairs = BracketPairs::new(&Rope::from(text), 11, 11, Some(&literals)).unwrap();
+        assert_eq!(pairs.enclosing(11, 11), Some((0, 11)));
+        assert!(BracketPairs::new(&Rope::from(text), 11, 11, None).is_err());
+    }
+
+    #[test]
+    fn unbalanced() {
+        let text = "(defun f (x)\n  (+ x 1)\n\n(f 2)\n";
+        assert_eq!(pairs(text, 0, 0).unwrap_err(), "unbalanced '(' on line 1");
+        let text = "(a [b) c]";
+        assert_eq!(pairs(text, 4, 4).unwrap_err(), "unbalanced ')' on line 1");
+        let text = "a)\n(b)";
+        assert_eq!(pairs(text, 4, 4).unwrap_err(), "unbalanced ')' on line 1");
+    }
+}
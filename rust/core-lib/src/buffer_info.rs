@@ -0,0 +1,151 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The status of a buffer and its file, such as a front-end shows in a
+//! status bar.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use xi_rope::rope::Rope;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+impl LineEnding {
+    /// Returns the line ending of the first line of `text`, or `Lf` if it
+    /// has a single line.
+    pub fn detect(text: &Rope) -> Self {
+        if text.line_of_offset(text.len()) == 0 {
+            return LineEnding::Lf;
+        }
+        let end = text.offset_of_line(1);
+        if end >= 2 && text.byte_at(end - 2) == b'\r' {
+            LineEnding::Crlf
+        } else {
+            LineEnding::Lf
+        }
+    }
+}
+
+/// The state of a buffer's file, as of when it was last opened or saved.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileInfo {
+    pub size: Option<u64>,
+    /// The time the file was last modified, in seconds since the epoch.
+    pub modified: Option<u64>,
+    pub read_only: bool,
+}
+
+impl FileInfo {
+    /// Reads the state of the file at `path`, which is the default if it
+    /// doesn't exist.
+    pub fn read(path: &Path) -> Self {
+        match fs::metadata(path) {
+            Ok(metadata) => FileInfo {
+                size: Some(metadata.len()),
+                modified: metadata.modified().ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|duration| duration.as_secs()),
+                read_only: metadata.permissions().readonly(),
+            },
+            Err(_) => FileInfo::default(),
+        }
+    }
+}
+
+/// Everything about a buffer which is shown in a status bar. This is
+/// computed from the editor's state each time, so that it always agrees
+/// with the other notifications derived from the same state.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BufferInfo {
+    pub path: Option<PathBuf>,
+    pub display_name: String,
+    pub language_id: String,
+    /// Files are always read and written as UTF-8.
+    pub encoding: &'static str,
+    pub bom: bool,
+    pub line_ending: LineEnding,
+    pub read_only: bool,
+    pub pristine: bool,
+    pub file_size: Option<u64>,
+    pub last_saved: Option<u64>,
+    /// If true, the buffer is a scratch buffer, which is never saved.
+    pub scratch: bool,
+}
+
+impl BufferInfo {
+    pub fn new(path: Option<&Path>, language_id: &str, text: &Rope, file: &FileInfo,
+               pristine: bool) -> Self {
+        let display_name = path.and_then(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "untitled".to_owned());
+        let bom = text.next_codepoint_offset(0)
+            .map_or(false, |end| text.slice_to_string(0, end) == "\u{feff}");
+        BufferInfo {
+            path: path.map(Path::to_owned),
+            display_name: display_name,
+            language_id: language_id.to_owned(),
+            encoding: "utf-8",
+            bom: bom,
+            line_ending: LineEnding::detect(text),
+            read_only: file.read_only,
+            pristine: pristine,
+            file_size: file.size,
+            last_saved: file.modified,
+            scratch: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_endings() {
+        assert_eq!(LineEnding::detect(&Rope::from("")), LineEnding::Lf);
+        assert_eq!(LineEnding::detect(&Rope::from("a\r")), LineEnding::Lf);
+        assert_eq!(LineEnding::detect(&Rope::from("a\nb\r\n")), LineEnding::Lf);
+        assert_eq!(LineEnding::detect(&Rope::from("a\r\nb\n")), LineEnding::Crlf);
+        assert_eq!(LineEnding::detect(&Rope::from("\r\n")), LineEnding::Crlf);
+    }
+
+    #[test]
+    fn untitled() {
+        let text = Rope::from("\u{feff}hello");
+        let info = BufferInfo::new(None, "plaintext", &text, &FileInfo::default(), true);
+        assert_eq!(info.display_name, "untitled");
+        assert!(info.bom);
+        let info = BufferInfo::new(Some(Path::new("/tmp/a.rs")), "rust", &Rope::from("x"),
+                                   &FileInfo::default(), false);
+        assert_eq!(info.display_name, "a.rs");
+        assert!(!info.bom);
+    }
+
+    #[test]
+    fn non_ascii() {
+        // offsets near the start or a line ending may be within a character
+        assert_eq!(LineEnding::detect(&Rope::from("é\n")), LineEnding::Lf);
+        assert_eq!(LineEnding::detect(&Rope::from("é\r\n")), LineEnding::Crlf);
+        let info = BufferInfo::new(None, "plaintext", &Rope::from("aaé"), &FileInfo::default(),
+                                   true);
+        assert!(!info.bom);
+    }
+}
@@ -0,0 +1,344 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Core's own syntax highlighting, a layer of scopes parsed with syntect,
+//! for buffers which no syntax plugin highlights.
+//!
+//! Lines are parsed in chunks, as background work. After an edit, they are
+//! parsed again from the first edited line until the state at the start of
+//! a line is the same as before. syntect's parse states can't be sent
+//! between threads, so they are kept in a table local to the thread which
+//! parses them; an editor only holds the key of its buffer's parse.
+//!
+//! Parsing needs syntect's regex engine, which core is only built with
+//! given the `builtin-syntax` feature; without it, no buffer is parsed.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use xi_rope::interval::Interval;
+use xi_rope::rope::{LinesMetric, Rope};
+use xi_rope::spans::SpansBuilder;
+
+use layers::{CoreLayer, LayerId, Scopes};
+use plugins::PluginPid;
+use syntax::SyntaxDefinition;
+use tabs::DocumentCtx;
+
+/// The layer of the built-in syntax highlighting.
+const LAYER: LayerId = LayerId::Core(CoreLayer::Syntax);
+
+/// The most lines parsed in one step of background work.
+const LINES_PER_CHUNK: usize = 200;
+
+static NEXT_PARSE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The lines parsed in one chunk.
+struct Chunk {
+    /// The offset of the end of the last line parsed.
+    end: usize,
+    /// The spans of the lines, relative to the start of the first, with
+    /// the ids of their scope stacks.
+    spans: Vec<(Interval, u32)>,
+    /// The scope stacks first seen in the chunk, which take the next ids.
+    new_stacks: Vec<Vec<String>>,
+    /// The first line left to parse, if any.
+    frontier: Option<usize>,
+}
+
+#[cfg(feature = "builtin-syntax")]
+mod parse {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::iter;
+
+    use syntect::parsing::{ParseState, Scope, ScopeStack, SyntaxSet, SCOPE_REPO};
+
+    use xi_rope::interval::Interval;
+    use xi_rope::rope::{LinesMetric, Rope};
+
+    use syntax::SyntaxDefinition;
+    use super::Chunk;
+
+    thread_local! {
+        static SYNTAX_SET: SyntaxSet = SyntaxSet::load_defaults_newlines();
+        static PARSES: RefCell<HashMap<usize, Parse>> = RefCell::new(HashMap::new());
+    }
+
+    /// The parse of a buffer, kept by the thread which parses it.
+    struct Parse {
+        /// The state at the start of each line, where it is known.
+        line_states: Vec<Option<(ParseState, ScopeStack)>>,
+        /// The ids in the layer of the scope stacks added to it.
+        stack_ids: HashMap<Vec<Scope>, u32>,
+    }
+
+    /// Starts the parse `id` with `syntax`, if syntect knows it. Returns
+    /// true if it did.
+    pub fn start(id: usize, syntax: &SyntaxDefinition) -> bool {
+        let initial = syntax.extension().and_then(|extension| SYNTAX_SET.with(|syntax_set| {
+            syntax_set.find_syntax_by_extension(extension)
+                .map(|syntax| (ParseState::new(syntax), ScopeStack::new()))
+        }));
+        PARSES.with(|parses| match initial {
+            Some(initial) => {
+                let parse = Parse { line_states: vec![Some(initial)], stack_ids: HashMap::new() };
+                parses.borrow_mut().insert(id, parse);
+                true
+            }
+            None => {
+                parses.borrow_mut().remove(&id);
+                false
+            }
+        })
+    }
+
+    pub fn stop(id: usize) {
+        let _ = PARSES.try_with(|parses| parses.borrow_mut().remove(&id));
+    }
+
+    /// Forgets the states at the start of the lines after `first` up to
+    /// `old_last`, which an edit replaced with those up to `new_last`.
+    /// Returns false if there is no parse `id`.
+    pub fn invalidate(id: usize, first: usize, old_last: usize, new_last: usize) -> bool {
+        PARSES.with(|parses| {
+            let mut parses = parses.borrow_mut();
+            let states = match parses.get_mut(&id) {
+                Some(parse) => &mut parse.line_states,
+                None => return false,
+            };
+            let end = (old_last + 1).min(states.len());
+            if first < end {
+                states.splice(first + 1..end, iter::repeat(None).take(new_last - first));
+            }
+            true
+        })
+    }
+
+    /// Parses up to `max_lines` lines of `text` from `start_line`, stopping
+    /// early if the state at the start of a line is the same as before.
+    pub fn parse_lines(id: usize, text: &Rope, start_line: usize, max_lines: usize) -> Chunk {
+        let n_lines = text.measure::<LinesMetric>() + 1;
+        let start = text.offset_of_line(start_line);
+        let mut chunk = Chunk { end: start, spans: Vec::new(), new_stacks: Vec::new(),
+                                frontier: None };
+        PARSES.with(|parses| {
+            let mut parses = parses.borrow_mut();
+            let parse = parses.get_mut(&id).expect("missing parse");
+            let mut line = start_line;
+            chunk.frontier = loop {
+                if line == start_line + max_lines {
+                    break Some(line);
+                }
+                let end = if line + 1 < n_lines {
+                    text.offset_of_line(line + 1)
+                } else {
+                    text.len()
+                };
+                let (mut state, mut stack) = parse.line_states[line].clone()
+                    .expect("parsing from an unknown state");
+                let line_text = text.slice_to_string(chunk.end, end);
+                let offset = chunk.end - start;
+                let mut prev_cursor = 0;
+                {
+                    let mut push_span = |stack: &ScopeStack, cursor: usize| {
+                        if stack.is_empty() || cursor == prev_cursor {
+                            prev_cursor = cursor;
+                            return;
+                        }
+                        let next_id = parse.stack_ids.len() as u32;
+                        let new_stacks = &mut chunk.new_stacks;
+                        let id = *parse.stack_ids.entry(stack.as_slice().to_vec())
+                            .or_insert_with(|| {
+                                let repo = SCOPE_REPO.lock().unwrap();
+                                new_stacks.push(stack.as_slice().iter()
+                                                .map(|&scope| repo.to_string(scope))
+                                                .collect());
+                                next_id
+                            });
+                        let iv = Interval::new_closed_open(offset + prev_cursor, offset + cursor);
+                        chunk.spans.push((iv, id));
+                        prev_cursor = cursor;
+                    };
+                    for (cursor, op) in state.parse_line(&line_text) {
+                        push_span(&stack, cursor);
+                        stack.apply(&op);
+                    }
+                    push_span(&stack, line_text.len());
+                }
+                chunk.end = end;
+                line += 1;
+
+                let next = Some((state, stack));
+                let converged = parse.line_states.get(line) == Some(&next);
+                if line == parse.line_states.len() {
+                    parse.line_states.push(next);
+                } else {
+                    parse.line_states[line] = next;
+                }
+                if line >= n_lines {
+                    break None;
+                }
+                if converged {
+                    // parse on from before the next line, if any, whose
+                    // state an edit made unknown
+                    break (line..n_lines)
+                        .find(|&l| parse.line_states.get(l).map_or(true, Option::is_none))
+                        .map(|l| l - 1);
+                }
+            };
+        });
+        chunk
+    }
+}
+
+#[cfg(not(feature = "builtin-syntax"))]
+mod parse {
+    use xi_rope::rope::Rope;
+
+    use syntax::SyntaxDefinition;
+    use super::Chunk;
+
+    pub fn start(_id: usize, _syntax: &SyntaxDefinition) -> bool {
+        false
+    }
+
+    pub fn stop(_id: usize) {}
+
+    pub fn invalidate(_id: usize, _first: usize, _old_last: usize, _new_last: usize) -> bool {
+        false
+    }
+
+    pub fn parse_lines(_id: usize, _text: &Rope, _start_line: usize, _max_lines: usize)
+                       -> Chunk {
+        unreachable!("parsing without the builtin-syntax feature")
+    }
+}
+
+/// The state of a buffer's built-in syntax highlighting.
+pub struct BuiltinSyntax {
+    /// The key of the buffer's parse.
+    id: usize,
+    /// The syntax the layer is parsed with; if it is `Plaintext`, or
+    /// unknown to syntect, there is no layer.
+    syntax: SyntaxDefinition,
+    /// The first line left to parse, if any.
+    frontier: Option<usize>,
+    /// The number of lines of the text, as of the last edit.
+    n_lines: usize,
+    /// The plugin whose layer replaced this one, if any.
+    displaced_by: Option<PluginPid>,
+}
+
+impl BuiltinSyntax {
+    pub fn new() -> Self {
+        BuiltinSyntax {
+            id: NEXT_PARSE_ID.fetch_add(1, Ordering::Relaxed),
+            syntax: SyntaxDefinition::Plaintext,
+            frontier: None,
+            n_lines: 1,
+            displaced_by: None,
+        }
+    }
+
+    /// The syntax the layer should be parsed with, given the buffer's.
+    fn wanted<'a>(&self, syntax: &'a SyntaxDefinition) -> &'a SyntaxDefinition {
+        if self.displaced_by.is_some() { &SyntaxDefinition::Plaintext } else { syntax }
+    }
+
+    /// Returns true if there are lines left to parse, or the layer is of
+    /// another syntax than `syntax`, that of the buffer or `Plaintext` if
+    /// it shouldn't be highlighted.
+    pub fn has_work(&self, syntax: &SyntaxDefinition) -> bool {
+        self.frontier.is_some() || self.wanted(syntax) != &self.syntax
+    }
+
+    /// Parses the next chunk of lines into the layer, first starting again
+    /// if the syntax changed. Returns the region whose styles changed.
+    pub fn parse_chunk(&mut self, syntax: &SyntaxDefinition, text: &Rope, styles: &mut Scopes,
+                       doc_ctx: &DocumentCtx) -> Option<Interval> {
+        if self.wanted(syntax) != &self.syntax {
+            let syntax = self.wanted(syntax).clone();
+            let removed = self.reset(syntax, text, styles);
+            if self.frontier.is_none() {
+                return removed;
+            }
+        }
+        let start_line = self.frontier?;
+        let start = text.offset_of_line(start_line);
+        let chunk = parse::parse_lines(self.id, text, start_line, LINES_PER_CHUNK);
+        self.frontier = chunk.frontier;
+        if !chunk.new_stacks.is_empty() {
+            if let Err(err) = styles.add_scopes(LAYER, chunk.new_stacks, doc_ctx) {
+                print_err!("built-in syntax: {}", err);
+            }
+        }
+        let iv = Interval::new_closed_open(start, chunk.end);
+        let mut sb = SpansBuilder::new(iv.size());
+        for (span_iv, id) in chunk.spans {
+            sb.add_span(span_iv, id);
+        }
+        styles.update_layer(LAYER, iv, sb.build(), None, None).ok()?;
+        Some(iv)
+    }
+
+    /// Removes the layer, and starts parsing the text again with `syntax`
+    /// if syntect knows it. Returns the region whose styles changed.
+    fn reset(&mut self, syntax: SyntaxDefinition, text: &Rope, styles: &mut Scopes)
+             -> Option<Interval> {
+        self.frontier = if parse::start(self.id, &syntax) { Some(0) } else { None };
+        self.syntax = syntax;
+        self.n_lines = text.measure::<LinesMetric>() + 1;
+        styles.remove_layer(LAYER)
+            .map(|_| Interval::new_closed_open(0, text.len()))
+    }
+
+    /// Notes the edit of `text` which replaced `iv` with `new_len` bytes, so
+    /// that the lines from the first it touched are parsed again.
+    pub fn after_edit(&mut self, text: &Rope, iv: Interval, new_len: usize) {
+        let n_lines = text.measure::<LinesMetric>() + 1;
+        let old_n_lines = self.n_lines;
+        self.n_lines = n_lines;
+        let first = text.line_of_offset(iv.start());
+        let new_last = text.line_of_offset(iv.start() + new_len);
+        let old_last = (new_last + old_n_lines).saturating_sub(n_lines);
+        if parse::invalidate(self.id, first, old_last, new_last) {
+            self.frontier = Some(self.frontier.map_or(first, |frontier| frontier.min(first)));
+        }
+    }
+
+    /// Notes that `plugin` has added scopes, so that its layer replaces
+    /// this one. Returns the region whose styles changed.
+    pub fn displace(&mut self, plugin: PluginPid, text: &Rope, styles: &mut Scopes)
+                    -> Option<Interval> {
+        if self.displaced_by.is_some() {
+            return None;
+        }
+        self.displaced_by = Some(plugin);
+        self.reset(SyntaxDefinition::Plaintext, text, styles)
+    }
+
+    /// Notes that the layer of `plugin` was removed, so that if it replaced
+    /// this one, the text is parsed again.
+    pub fn plugin_stopped(&mut self, plugin: PluginPid) {
+        if self.displaced_by == Some(plugin) {
+            self.displaced_by = None;
+        }
+    }
+}
+
+impl Drop for BuiltinSyntax {
+    fn drop(&mut self) {
+        parse::stop(self.id);
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! User configurable settings.
+
+use std::collections::BTreeMap;
+use std::mem;
+use serde_json::{self, Value};
+
+use syntax::SyntaxDefinition;
+use indent::{IndentRules, IndentStyle};
+use auto_save::AutoSave;
+use spellcheck::SpellcheckConfig;
+use plugins::PluginLimits;
+use sanitize::PasteSanitize;
+use bidi::CaretMovement;
+use undo_spill::UndoHistory;
+use messages::{ids, Message};
+
+/// Settings shared by all buffers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    /// If true, new lines are indented according to the language's rules.
+    pub autoindent: bool,
+    /// User provided indent rules, keyed by syntax name (as in
+    /// `"python"`), which replace the builtin rules for that syntax.
+    pub indent_rules: BTreeMap<String, IndentRules>,
+    /// The number of columns between tab stops, and per level of
+    /// indentation.
+    pub tab_size: usize,
+    /// If true, tabs and indentation are inserted as spaces.
+    pub translate_tabs_to_spaces: bool,
+    /// If true, trailing whitespace is removed when a buffer is saved.
+    pub trim_whitespace_on_save: bool,
+    /// If true, a newline is added to the end of a buffer when it is saved,
+    /// if one is not present.
+    pub final_newline_on_save: bool,
+    /// If true, whitespace which will be removed on save is marked in
+    /// updates with the reserved pending cleanup style.
+    pub show_pending_cleanup: bool,
+    /// When buffers with a file are saved automatically.
+    pub auto_save: AutoSave,
+    /// With `AutoSave::AfterDelay`, the time in milliseconds since the
+    /// last edit after which a buffer is saved.
+    pub auto_save_delay: u64,
+    /// The words spell-checking plugins are given to check.
+    pub spellcheck: SpellcheckConfig,
+    /// The limits on the resources each plugin may use.
+    pub plugin_limits: PluginLimits,
+    /// What is done with control characters, escape sequences and other
+    /// suspicious characters in pasted text.
+    pub paste_sanitize: PasteSanitize,
+    /// If true, non-breaking spaces in pasted text become spaces when it
+    /// is sanitized.
+    pub paste_nbsp_to_space: bool,
+    /// If true, the text recorded with incidents, the internal errors
+    /// core recovers from, is redacted.
+    pub redact_incidents: bool,
+    /// Whether the caret moves left and right in the order text is
+    /// stored, or as it is displayed, which differ in right-to-left text.
+    pub caret_movement: CaretMovement,
+    /// Whether undo groups which fall out of the in-memory window are
+    /// forgotten, or spilled to disk so that they can still be undone.
+    pub undo_history: UndoHistory,
+    /// If true, the sizes of a plugin's layer of styles are logged when
+    /// it stops, for debugging plugins.
+    pub log_plugin_stats: bool,
+    /// If true, core highlights the syntax of buffers itself until a
+    /// plugin adds scopes to them.
+    pub builtin_syntax: bool,
+    /// If true, the carriage returns of CRLF line endings are shown as
+    /// trailing whitespace while invisible characters are shown.
+    pub show_invisible_cr: bool,
+    /// The most spans plugins' layers keep for a buffer; past it, those
+    /// away from what the views show are dropped, and asked of the plugins
+    /// again as they are scrolled to. 0 keeps them all.
+    pub span_budget: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            autoindent: true,
+            indent_rules: BTreeMap::new(),
+            tab_size: 4,
+            translate_tabs_to_spaces: true,
+            trim_whitespace_on_save: false,
+            final_newline_on_save: false,
+            show_pending_cleanup: false,
+            auto_save: AutoSave::Off,
+            auto_save_delay: 1000,
+            spellcheck: SpellcheckConfig::default(),
+            plugin_limits: PluginLimits::default(),
+            paste_sanitize: PasteSanitize::Off,
+            paste_nbsp_to_space: false,
+            redact_incidents: true,
+            caret_movement: CaretMovement::Logical,
+            undo_history: UndoHistory::Memory,
+            log_plugin_stats: false,
+            builtin_syntax: true,
+            show_invisible_cr: false,
+            span_budget: 4_000_000,
+        }
+    }
+}
+
+impl Config {
+    /// Applies a set of changes, which should be a JSON object whose keys
+    /// are config fields. Object values are merged with the existing value,
+    /// other values replace it. A key such as `"spellcheck.language"` sets
+    /// a single field of an object.
+    ///
+    /// If the changes are invalid, returns an error and leaves `self`
+    /// unmodified.
+    pub fn apply_changes(&mut self, changes: &Value) -> Result<(), String> {
+        let changes = match changes.as_object() {
+            Some(obj) => obj,
+            None => return Err("config changes must be an object".to_owned()),
+        };
+        let mut current = serde_json::to_value(&*self).unwrap();
+        for (key, value) in changes.iter() {
+            let (key, value) = match key.find('.') {
+                Some(ix) => {
+                    let mut field = serde_json::Map::new();
+                    field.insert(key[ix + 1..].to_owned(), value.to_owned());
+                    (&key[..ix], Value::Object(field))
+                }
+                None => (key.as_str(), value.to_owned()),
+            };
+            let existing = match current.get_mut(key) {
+                Some(existing) => existing,
+                None => return Err(format!("unknown config key '{}'", key)),
+            };
+            match (existing, value) {
+                (&mut Value::Object(ref mut existing), Value::Object(value)) => {
+                    for (k, v) in value.into_iter() {
+                        existing.insert(k, v);
+                    }
+                }
+                (existing, value) => *existing = value,
+            }
+        }
+        *self = serde_json::from_value(current).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    pub fn indent_style(&self) -> IndentStyle {
+        IndentStyle {
+            tab_size: self.tab_size.max(1),
+            use_spaces: self.translate_tabs_to_spaces,
+        }
+    }
+
+    /// Returns the indent rules for `syntax`, preferring those provided
+    /// by the user.
+    pub fn indent_rules(&self, syntax: &SyntaxDefinition) -> IndentRules {
+        let key = serde_json::to_value(syntax).unwrap();
+        key.as_str().and_then(|key| self.indent_rules.get(key))
+            .map(|rules| rules.to_owned())
+            .unwrap_or_else(|| IndentRules::for_syntax(syntax))
+    }
+}
+
+/// The changes the user made to the config, which aren't applied while
+/// the user's config is disabled, as in safe mode, so that only the
+/// defaults are used.
+#[derive(Debug, Default)]
+pub struct UserConfig {
+    /// The changes, in the order they were made.
+    changes: Vec<Value>,
+    disabled: bool,
+}
+
+impl UserConfig {
+    pub fn disabled() -> Self {
+        UserConfig { changes: Vec::new(), disabled: true }
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+
+    /// Disables the user's config, returning `config` to the defaults.
+    pub fn disable(&mut self, config: &mut Config) {
+        self.disabled = true;
+        *config = Config::default();
+    }
+
+    /// Applies `changes` to `config`, as with `Config::apply_changes`.
+    /// While the user's config is disabled, the changes are instead kept
+    /// unchecked until it is reloaded, so that a bad config reports no
+    /// errors.
+    pub fn modify(&mut self, config: &mut Config, changes: &Value) -> Result<(), String> {
+        if !self.disabled {
+            config.apply_changes(changes)?;
+        }
+        self.changes.push(changes.to_owned());
+        Ok(())
+    }
+
+    /// Enables the user's config, applying the changes made so far to the
+    /// defaults. Returns the errors of the changes which were invalid,
+    /// which are dropped.
+    pub fn reload(&mut self, config: &mut Config) -> Vec<Message> {
+        self.disabled = false;
+        *config = Config::default();
+        let mut errors = Vec::new();
+        let changes = mem::replace(&mut self.changes, Vec::new());
+        for changes in changes {
+            match config.apply_changes(&changes) {
+                Ok(()) => self.changes.push(changes),
+                Err(err) => errors.push(Message::new(ids::config_changes_invalid)
+                                        .param("changes", changes.clone())
+                                        .param("error", err)),
+            }
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_changes() {
+        let mut config = Config::default();
+        assert!(config.apply_changes(&json!({"autoindent": false})).is_ok());
+        assert_eq!(config.autoindent, false);
+        assert!(config.apply_changes(&json!({"no_such_key": 1})).is_err());
+        assert!(config.apply_changes(&json!({"autoindent": "yes"})).is_err());
+        assert_eq!(config.autoindent, false);
+        assert!(config.apply_changes(&json!({"auto_save": "after_delay"})).is_ok());
+        assert_eq!(config.auto_save, AutoSave::AfterDelay);
+        assert!(config.apply_changes(&json!({"auto_save": "sometimes"})).is_err());
+        assert!(config.apply_changes(&json!({"spellcheck.language": "de_DE"})).is_ok());
+        assert_eq!(config.spellcheck.language, "de_DE");
+        assert!(config.apply_changes(&json!({"spellcheck.langauge": "fr_FR"})).is_err());
+        assert!(config.apply_changes(&json!({"autoindent.x": true})).is_err());
+        assert!(config.apply_changes(&json!({"plugin_limits.max_spans": 10})).is_ok());
+        assert_eq!(config.plugin_limits.max_spans, 10);
+    }
+
+    #[test]
+    fn disabled_user_config() {
+        let mut config = Config::default();
+        let mut user_config = UserConfig::disabled();
+        // while disabled, changes are neither applied nor checked
+        assert!(user_config.modify(&mut config, &json!({"tab_size": 2})).is_ok());
+        assert!(user_config.modify(&mut config, &json!({"no_such_key": 1})).is_ok());
+        assert_eq!(config, Config::default());
+        let errors = user_config.reload(&mut config);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].id(), ids::config_changes_invalid);
+        assert_eq!(errors[0].params()["changes"], json!({"no_such_key": 1}));
+        assert!(!user_config.is_disabled());
+        assert_eq!(config.tab_size, 2);
+        assert!(user_config.modify(&mut config, &json!({"autoindent": "yes"})).is_err());
+        assert!(user_config.modify(&mut config, &json!({"autoindent": false})).is_ok());
+        user_config.disable(&mut config);
+        assert_eq!(config, Config::default());
+        assert!(user_config.reload(&mut config).is_empty());
+        assert_eq!((config.tab_size, config.autoindent), (2, false));
+    }
+
+    #[test]
+    fn user_indent_rules() {
+        let mut config = Config::default();
+        let python = SyntaxDefinition::Python;
+        assert_eq!(config.indent_rules(&python), IndentRules::for_syntax(&python));
+        let changes = json!({"indent_rules": {"python": {"increase_after": [":", "\\"]}}});
+        config.apply_changes(&changes).unwrap();
+        let rules = config.indent_rules(&python);
+        assert_eq!(rules.increase_after, vec![":".to_owned(), "\\".to_owned()]);
+        assert!(rules.decrease_after.is_empty());
+        assert_eq!(config.indent_rules(&SyntaxDefinition::Rust),
+                   IndentRules::for_syntax(&SyntaxDefinition::Rust));
+    }
+}
@@ -13,33 +13,56 @@
 // limitations under the License.
 
 use std::borrow::Cow;
-use std::cmp::{min, max};
-use std::fs::File;
+use std::cmp::{min, max, Reverse};
+use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::io::Write;
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
 use std::mem;
-use serde_json::Value;
+use std::time::{Duration, Instant};
+use serde_json::{self, Value};
 
 use xi_rope::rope::{LinesMetric, Rope, RopeInfo};
 use xi_rope::interval::Interval;
 use xi_rope::delta::{self, Delta, Transformer};
 use xi_rope::engine::{Engine, RevId, RevToken};
-use xi_rope::spans::SpansBuilder;
+use xi_rope::spans::{Spans, SpansBuilder};
 use xi_rpc::RemoteError;
 
-use view::View;
-use word_boundaries::WordCursor;
+use view::{Granularity, View};
+use word_boundaries::{Segmentation, Segmenter, WordCursor};
 use movement::{Movement, region_movement};
 use selection::{Affinity, Selection, SelRegion};
 
 use tabs::{BufferIdentifier, ViewIdentifier, DocumentCtx};
-use rpc::{self, GestureType};
-use syntax::SyntaxDefinition;
-use plugins::rpc_types::{PluginUpdate, PluginEdit, ScopeSpan, PluginBufferInfo,
-ClientPluginInfo};
+use rpc::{self, BlockClipboard, GestureType};
+use syntax::{SyntaxDefinition, MODELINE_LINES};
+use plugins::rpc_types::{PluginUpdate, PluginEdit, ScopeSpan, SpanOp, SpanUpdate, StyleSpan,
+PluginBufferInfo, ClientPluginInfo};
 use plugins::{PluginPid, Command};
-use layers::Scopes;
+use layers::{EditPolicy, LayerOp, Scopes};
+use styles::{Style, SYNTAX_PRIORITY_DEFAULT};
+use line_metadata::{LineMetadata, BRACKET_DELTA};
+use metrics::{self, BufferMetrics};
+use config::Config;
+use indent::IndentStyle;
+use brackets::{self, BracketPairs};
+use surround::{self, Delimiters};
+use auto_save::AutoSaveTimer;
+use replace::{self, ReplaceOptions, Replacement};
+use buffer_info::{BufferInfo, FileInfo};
+use spellcheck::{self, DictionaryScope, SpellcheckConfig};
+use sanitize::{self, PasteAction, PasteSanitize};
+use history;
+use html;
+use builtin_syntax::BuiltinSyntax;
+use invisibles::Invisibles;
+use messages::{ids, Message};
+use expr::{Expr, OnError};
+use incidents::{self, CaretContext, DeltaRecord, Incident};
+use bidi::{self, CaretMovement};
+use undo_spill::{self, SpilledGroup, UndoHistory, UndoSpill};
+use scratch::{self, Scratch, ScratchStyle};
 
 
 #[cfg(not(target_os = "fuchsia"))]
@@ -53,7 +76,52 @@ const FLAG_SELECT: u64 = 2;
 // better to keep it low to expose bugs in the GC during casual testing.
 const MAX_UNDOS: usize = 20;
 
-const TAB_SIZE: usize = 4;
+
+/// Undos and redos which change more than this many bytes are applied to
+/// the buffer in chunks, so that other requests can be handled meanwhile.
+const CHUNKED_UNDO_THRESHOLD: usize = 1 << 20;
+
+/// The number of lines replaced by each chunk of a chunked undo.
+const UNDO_CHUNK_LINES: usize = 10_000;
+
+/// The bytes of text restyled at a time for a new theme, in the
+/// background.
+const RESTYLE_CHUNK: usize = 1 << 16;
+
+/// Plugins' changes to the styles are told to the views at once when the
+/// region they span grows past this many bytes, rather than waiting for
+/// the plugin's updates to end.
+const PENDING_STYLES_MAX: usize = 1 << 16;
+
+/// The time after the last edit of the lines which may determine the
+/// syntax of a buffer without a language before it is detected, in ms.
+const SYNTAX_DETECTION_DELAY: u64 = 250;
+
+/// The time after a plugin's last edit in an undo group it opened at which
+/// the group is closed, if the plugin hasn't closed it, in ms.
+const PLUGIN_UNDO_GROUP_TIMEOUT: u64 = 2000;
+
+/// The time after an update is sent to the plugins at which the edits
+/// they sent in response are applied, even if some plugins haven't yet
+/// responded, in ms.
+const PLUGIN_EDIT_WINDOW_TIMEOUT: u64 = 5000;
+
+/// The most revisions for which the plugins' responses are awaited at
+/// once; the responses to older revisions are no longer awaited.
+const PLUGIN_EDIT_WINDOW_REVS: usize = 16;
+
+/// The longest the plugins of a buffer with no visible views go without
+/// an update after it is edited, in ms.
+const HIDDEN_UPDATE_INTERVAL: u64 = 5000;
+
+/// Moves of the caret by more than this many lines, by commands which
+/// jump, are recorded in the view's jump list.
+const JUMP_LINES: usize = 10;
+
+/// Operations which commit more than this many deltas, or which replace
+/// more than `BULK_EDIT_BYTES` bytes, are made as bulk edits.
+const BULK_EDIT_DELTAS: usize = 16;
+const BULK_EDIT_BYTES: usize = 1 << 16;
 
 // Maximum returned result from plugin get_data RPC.
 const MAX_SIZE_LIMIT: usize = 1024 * 1024;
@@ -64,6 +132,25 @@ pub struct Editor {
     path: Option<PathBuf>,
     buffer_id: BufferIdentifier,
     syntax: SyntaxDefinition,
+    /// If true, the syntax was chosen with `set_language`, and is never
+    /// detected.
+    explicit_syntax: bool,
+    /// If true, lines which may determine the syntax have been edited
+    /// since detection was last scheduled.
+    syntax_edited: bool,
+    /// When the syntax is next detected. Bursts of edits are debounced
+    /// like auto-saves.
+    syntax_detection: AutoSaveTimer,
+    /// Core's highlighting of the syntax, until a plugin does it.
+    builtin_syntax: BuiltinSyntax,
+    /// Whether the buffer's invisible characters are shown, in every view
+    /// onto it, as they're a layer of its styles.
+    invisibles: Invisibles,
+    file_info: FileInfo,
+    /// The buffer info last sent to the views, if any.
+    sent_buffer_info: Option<BufferInfo>,
+    /// Words added to this buffer's dictionary, which aren't spell-checked.
+    extra_words: BTreeSet<String>,
 
     /// A collection of non-primary views attached to this buffer.
     views: BTreeMap<ViewIdentifier, View>,
@@ -78,15 +165,96 @@ pub struct Editor {
     cur_undo: usize, // index to live_undos, ones after this are undone
     undos: BTreeSet<usize>, // undo groups that are undone
     gc_undos: BTreeSet<usize>, // undo groups that are no longer live and should be gc'ed
+    /// The most undo groups kept in memory.
+    max_undos: usize,
+    /// The undo groups which fell out of the window, if they are spilled.
+    undo_spill: Option<UndoSpill>,
+    /// If true, the spilled history was lost, and groups are no longer
+    /// spilled.
+    undo_spill_failed: bool,
+    /// The edits which redo the groups undone past the window, most
+    /// recently undone last, with whether their undos are still spilled.
+    spilled_redos: Vec<(SpilledGroup, bool)>,
+    /// The group of the last edit which undid or redid a spilled group,
+    /// which is collected once it is no longer the newest revision.
+    spilled_edit_group: Option<usize>,
+    /// Set if this is a scratch buffer, which keeps no undo history.
+    scratch: Option<Scratch>,
+    /// Whether an edit was refused during the current command, as the
+    /// buffer is a read-only scratch buffer.
+    edit_refused: bool,
 
     this_edit_type: EditType,
     last_edit_type: EditType,
 
     scroll_to: Option<usize>,
+    /// Whether `scroll_to` is scrolled into the middle of the view, as after
+    /// a jump, rather than just into view.
+    scroll_centered: bool,
 
     styles: Scopes,
+    /// Cached per-line information, such as indentation.
+    line_metadata: LineMetadata,
+    /// Word boundaries within runs of CJK text.
+    segmentation: Segmentation,
+    /// The plugin providing `segmentation`'s segmenter, if any.
+    segmenter_plugin: Option<PluginPid>,
+    metrics: BufferMetrics,
     doc_ctx: DocumentCtx,
     revs_in_flight: usize,
+    /// An undo or redo which is being applied to the buffer.
+    chunked_undo: Option<ChunkedUndo>,
+    /// The region spanning the plugins' changes to the styles which the
+    /// views haven't been told of, so that they're rendered once for a
+    /// stream of small updates.
+    pending_styles: Option<Interval>,
+    /// The regions of plugins' layers, with the revision they're in, whose
+    /// spans were dropped to stay within the span budget and are now in
+    /// view, for the plugins to be asked for them again.
+    span_requests: Vec<(PluginPid, Interval, u64)>,
+    /// The bulk edit being made, if any.
+    bulk_edit: Option<BulkEdit>,
+    /// The edits not yet sent to plugins, while no view of the buffer is
+    /// visible.
+    deferred_update: Option<DeferredUpdate>,
+    /// If true, styles must be recomputed for a new theme.
+    theme_pending: bool,
+    /// The number of times styles were recomputed for a new theme.
+    theme_recomputes: usize,
+    /// When the buffer is next due to be saved automatically.
+    auto_save: AutoSaveTimer,
+    /// The revision at which the auto-save timer was last started.
+    auto_save_rev: RevId,
+    /// The plugins' responses to updates, keyed by the revision of the
+    /// update, which are applied once the plugins ahead of them have
+    /// responded.
+    plugin_edit_windows: BTreeMap<u64, PluginEditWindow>,
+    /// The plugin edits which were rejected, which are yet to be reported
+    /// to their plugins.
+    plugin_edit_conflicts: Vec<ConflictingEdit>,
+    /// The undo groups plugins have opened, by plugin.
+    plugin_undo_groups: BTreeMap<PluginPid, PluginUndoGroup>,
+    /// The pids of crashed plugins, by name, whose layers are kept for them
+    /// to adopt once they are restarted.
+    detached_layers: BTreeMap<String, PluginPid>,
+    next_undo_group_token: u64,
+    /// The replacements most recently previewed, if any.
+    replace_preview: Option<ReplacePreview>,
+    /// A paste of text with suspicious characters, while the user is
+    /// asked what to do with them.
+    pending_paste: Option<(String, Option<BlockClipboard>)>,
+    next_replace_token: u64,
+    /// The head revision right after the buffer was loaded, which must be
+    /// the same in any history merged into the buffer's.
+    initial_rev: RevToken,
+    /// Whether the buffer's history was exported or merged with another,
+    /// after which it is never collected, as peers may depend on it.
+    history_shared: bool,
+    /// The deltas most recently applied to the buffer, recorded with
+    /// incidents.
+    recent_deltas: VecDeque<DeltaRecord>,
+    /// The names of the plugins running for the buffer.
+    active_plugins: BTreeSet<String>,
 
     /// Used only on Fuchsia for syncing
     #[allow(dead_code)]
@@ -95,6 +263,146 @@ pub struct Editor {
     last_synced_rev: RevId,
 }
 
+/// The edits sent by plugins in response to the update for one revision.
+struct PluginEditWindow {
+    /// The plugins yet to respond.
+    outstanding: Vec<PluginEditOrder>,
+    undo_group: usize,
+    /// The edits which wait for a plugin ahead of them to respond.
+    queued: Vec<QueuedPluginEdit>,
+    /// The edits which were applied, which later edits must not overlap.
+    applied: Vec<QueuedPluginEdit>,
+    /// When the update was sent.
+    opened: Instant,
+}
+
+/// The place of a plugin's edit in the order in which the edits of one
+/// revision are applied: by decreasing priority, then by name.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct PluginEditOrder {
+    priority: Reverse<u16>,
+    name: String,
+    plugin: PluginPid,
+}
+
+/// An undo group opened by a plugin with `begin_undo_group`, so that its
+/// edits are undone together. It is closed by `end_undo_group`, after a
+/// timeout, or once another undo group follows it or it is undone, so that
+/// the user's edits are never merged with the plugin's.
+struct PluginUndoGroup {
+    token: u64,
+    /// The undo group of the plugin's edits, once it has made one.
+    undo_group: Option<usize>,
+    deadline: Instant,
+}
+
+struct QueuedPluginEdit {
+    order: PluginEditOrder,
+    edit: PluginEdit,
+}
+
+/// A plugin edit which was rejected because it overlaps another plugin's
+/// edit of the same revision.
+pub struct ConflictingEdit {
+    pub plugin: PluginPid,
+    pub edit: PluginEdit,
+    /// The name of the plugin whose edit was applied.
+    pub conflicts_with: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EditOverlap {
+    None,
+    Conflict,
+    Duplicate,
+}
+
+/// Returns `block` with each of its segments sanitized.
+fn sanitize_block(block: &BlockClipboard, nbsp_to_space: bool) -> BlockClipboard {
+    BlockClipboard {
+        column: block.column,
+        segments: block.segments.iter()
+            .map(|segment| sanitize::sanitize(segment, nbsp_to_space).0.into_owned())
+            .collect(),
+    }
+}
+
+/// Returns `true` if an operation committing `n_deltas` deltas, which
+/// replace `size` bytes, should be made as a bulk edit.
+fn is_bulk(n_deltas: usize, size: usize) -> bool {
+    n_deltas > BULK_EDIT_DELTAS || size > BULK_EDIT_BYTES
+}
+
+/// Determines how two plugin edits of the same revision overlap. Edits
+/// which touch without sharing any text are independent, unless both
+/// insert at the same offset.
+fn plugin_edits_overlap(a: &PluginEdit, b: &PluginEdit) -> EditOverlap {
+    if a.start == b.start && a.end == b.end && a.text == b.text {
+        return EditOverlap::Duplicate;
+    }
+    let shares_text = max(a.start, b.start) < min(a.end, b.end);
+    let inserts_within = |x: &PluginEdit, y: &PluginEdit| {
+        x.start == x.end && y.start <= x.start && x.start < y.end
+    };
+    if shares_text || a.start == b.start || inserts_within(a, b) || inserts_within(b, a) {
+        EditOverlap::Conflict
+    } else {
+        EditOverlap::None
+    }
+}
+
+/// An undo or redo which is being applied to the buffer a chunk at a time.
+///
+/// The engine's head already reflects the whole undo, so that it remains a
+/// single step in the undo history; only `Editor::text` and the state derived
+/// from it lag behind. Once `n` chunks are applied, the text is the new text
+/// up to `cuts[n].1`, followed by the old text from `cuts[n].0`.
+struct ChunkedUndo {
+    /// The complete delta from the old text to the new text.
+    delta: Delta<RopeInfo>,
+    target: Rope,
+    /// The selection before the undo, which is updated once it is complete.
+    selection: Selection,
+    /// (old offset, new offset) pairs at which the delta is split.
+    cuts: Vec<(usize, usize)>,
+    n_applied: usize,
+    edit_type: EditType,
+    /// Notifications received while the undo is applied, which are handled
+    /// once it is complete.
+    queued: Vec<(ViewIdentifier, rpc::EditNotification)>,
+}
+
+/// Edits made as one operation, such as a replace-all, between
+/// `begin_bulk_edit` and `end_bulk_edit`. Until it ends, plugins aren't
+/// updated, styles aren't adjusted for the edits, and views aren't
+/// rendered; then plugins receive a single update, of the composed delta.
+struct BulkEdit {
+    /// The number of bulk edits begun and not yet ended, as they nest.
+    depth: usize,
+    /// The revision plugins were last updated to.
+    start_rev: RevId,
+    /// The number of deltas committed.
+    n_edits: usize,
+}
+
+/// The edits made to a buffer with no visible views, which plugins are
+/// sent as one update, at most `HIDDEN_UPDATE_INTERVAL` after the first.
+struct DeferredUpdate {
+    /// The revision plugins were last updated to.
+    start_rev: RevId,
+    n_edits: usize,
+    /// When the update is due, once it has been scheduled.
+    due: Option<Instant>,
+}
+
+/// The replacements found by `preview_replace`, which `replace_all`
+/// applies if the buffer hasn't changed since.
+struct ReplacePreview {
+    token: u64,
+    rev: RevId,
+    replacements: Vec<Replacement>,
+}
+
 #[derive(PartialEq, Eq, Clone, Copy)]
 enum EditType {
     Other,
@@ -127,15 +435,28 @@ impl Editor {
     pub fn with_text(doc_ctx: DocumentCtx, buffer_id: BufferIdentifier,
                      initial_view_id: &ViewIdentifier, text: String) -> Editor {
 
-        let engine = Engine::new(Rope::from(text));
+        let mut engine = Engine::new(Rope::from(text));
+        engine.start_session(history::new_session_id());
         let buffer = engine.get_head().clone();
         let last_rev_id = engine.get_head_rev_id();
+        let tab_size = doc_ctx.get_config().indent_style().tab_size;
+        let line_metadata = LineMetadata::new(&buffer, tab_size);
+        let metrics = BufferMetrics::new(&buffer);
+        let styles = Scopes::new(buffer.len());
 
         let editor = Editor {
             text: buffer,
             buffer_id: buffer_id,
             path: None,
             syntax: SyntaxDefinition::default(),
+            explicit_syntax: false,
+            syntax_edited: false,
+            syntax_detection: AutoSaveTimer::default(),
+            builtin_syntax: BuiltinSyntax::new(),
+            invisibles: Invisibles::new(),
+            file_info: FileInfo::default(),
+            sent_buffer_info: None,
+            extra_words: BTreeSet::new(),
             views: BTreeMap::new(),
             view: View::new(initial_view_id),
             engine: engine,
@@ -149,12 +470,45 @@ impl Editor {
             cur_undo: 1,
             undos: BTreeSet::new(),
             gc_undos: BTreeSet::new(),
+            max_undos: MAX_UNDOS,
+            undo_spill: None,
+            undo_spill_failed: false,
+            spilled_redos: Vec::new(),
+            spilled_edit_group: None,
+            scratch: None,
+            edit_refused: false,
             last_edit_type: EditType::Other,
             this_edit_type: EditType::Other,
             scroll_to: Some(0),
-            styles: Scopes::default(),
+            scroll_centered: false,
+            styles: styles,
+            line_metadata: line_metadata,
+            segmentation: Segmentation::new(),
+            segmenter_plugin: None,
+            metrics: metrics,
             doc_ctx: doc_ctx,
             revs_in_flight: 0,
+            chunked_undo: None,
+            pending_styles: None,
+            span_requests: Vec::new(),
+            bulk_edit: None,
+            deferred_update: None,
+            theme_pending: false,
+            theme_recomputes: 0,
+            auto_save: AutoSaveTimer::default(),
+            auto_save_rev: last_rev_id,
+            plugin_edit_windows: BTreeMap::new(),
+            plugin_edit_conflicts: Vec::new(),
+            plugin_undo_groups: BTreeMap::new(),
+            detached_layers: BTreeMap::new(),
+            next_undo_group_token: 1,
+            replace_preview: None,
+            pending_paste: None,
+            next_replace_token: 1,
+            initial_rev: last_rev_id.token(),
+            history_shared: false,
+            recent_deltas: VecDeque::new(),
+            active_plugins: BTreeSet::new(),
             sync_store: None,
             last_synced_rev: last_rev_id,
         };
@@ -162,31 +516,34 @@ impl Editor {
     }
 
 
-    #[allow(unreachable_code, unused_variables)]
+    /// Adds another view onto this editor's buffer.
     pub fn add_view(&mut self, view_id: &ViewIdentifier) {
-        panic!("multi-view support is not currently implemented");
-        assert!(!self.views.contains_key(view_id), "view_id already exists");
-        self.views.insert(view_id.to_owned(), View::new(view_id));
+        assert!(self.view.view_id != *view_id && !self.views.contains_key(view_id),
+                "view_id already exists");
+        let mut view = View::new(view_id);
+        if !self.is_pristine() {
+            view.set_modified();
+        }
+        self.views.insert(view_id.to_owned(), view);
+        self.doc_ctx.buffer_info(view_id, &self.buffer_info());
+        self.catch_up();
     }
 
     /// Removes a view from this editor's stack, if this editor has multiple views.
     ///
     /// If the editor only has a single view this is a no-op. After removing a view the caller must
     /// always call Editor::has_views() to determine whether or not the editor should be cleaned up.
-    #[allow(unreachable_code)]
     pub fn remove_view(&mut self, view_id: &ViewIdentifier) {
         if self.view.view_id == *view_id {
             if self.views.len() > 0 {
-                panic!("multi-view support is not currently implemented");
                 //set some other view as active. This will be reset on the next EditCommand
                 let tempkey = self.views.keys().nth(0).unwrap().clone();
-                let mut temp = self.views.remove(&tempkey).unwrap();
-                mem::swap(&mut temp, &mut self.view);
-                self.views.insert(temp.view_id.clone(), temp);
+                self.view = self.views.remove(&tempkey).unwrap();
             }
         } else {
             self.views.remove(view_id).expect("attempt to remove missing view");
         }
+        self.catch_up();
     }
 
     /// Returns true if this editor has additional attached views.
@@ -198,9 +555,81 @@ impl Editor {
     #[doc(hidden)]
     pub fn _set_path<P: AsRef<Path>>(&mut self, path: P) {
         let path = path.as_ref();
-        //TODO: if the user sets syntax, we shouldn't overwrite here
-        self.syntax = SyntaxDefinition::new(path.to_str());
         self.path = Some(path.to_owned());
+        if !self.explicit_syntax {
+            self.syntax = self.detected_syntax();
+        }
+        self.file_info = FileInfo::read(path);
+    }
+
+    /// Returns the syntax given by the buffer's path, or else by its
+    /// content.
+    fn detected_syntax(&self) -> SyntaxDefinition {
+        match self.path_syntax() {
+            SyntaxDefinition::Plaintext => SyntaxDefinition::from_content(&self.text)
+                .unwrap_or_default(),
+            syntax => syntax,
+        }
+    }
+
+    fn path_syntax(&self) -> SyntaxDefinition {
+        SyntaxDefinition::new(self.path.as_ref().and_then(|path| path.to_str()))
+    }
+
+    /// Returns the status of the buffer and its file.
+    pub fn buffer_info(&self) -> BufferInfo {
+        let mut info = BufferInfo::new(self.get_path(), self.syntax.identifier(), &self.text,
+                                       &self.file_info, self.is_pristine());
+        if let Some(ref scratch) = self.scratch {
+            info.display_name = scratch.title.clone();
+            info.read_only = scratch.read_only;
+            info.scratch = true;
+        }
+        info
+    }
+
+    /// Makes this a scratch buffer, which is never saved, and keeps no
+    /// undo history.
+    pub fn make_scratch(&mut self, scratch: Scratch) {
+        self.gc_undos.extend(self.live_undos.drain(..));
+        self.cur_undo = 0;
+        self.scratch = Some(scratch);
+    }
+
+    pub fn is_scratch(&self) -> bool {
+        self.scratch.is_some()
+    }
+
+    /// Appends `text` to a scratch buffer, with `styles`, whose offsets
+    /// are within `text`.
+    pub fn scratch_append(&mut self, text: &str, styles: &[ScratchStyle])
+                          -> Result<(), String> {
+        let len = self.text.len();
+        self.scratch_replace(Interval::new_closed_open(len, len), text, styles)
+    }
+
+    /// Replaces the text of a scratch buffer with `text`, with `styles`.
+    pub fn scratch_set_content(&mut self, text: &str, styles: &[ScratchStyle])
+                               -> Result<(), String> {
+        let len = self.text.len();
+        self.scratch_replace(Interval::new_closed_open(0, len), text, styles)
+    }
+
+    fn scratch_replace(&mut self, iv: Interval, text: &str, styles: &[ScratchStyle])
+                       -> Result<(), String> {
+        if self.scratch.is_none() {
+            return Err("not a scratch buffer".to_owned());
+        }
+        let spans = scratch::style_spans(styles, text.len())?;
+        self.finish_chunked_undo();
+        let delta = Delta::simple_edit(iv, Rope::from(text), self.text.len());
+        self.add_scratch_delta(delta);
+        self.commit_delta(None);
+        let styled = Interval::new_closed_open(iv.start(), iv.start() + text.len());
+        self.styles.update_direct(styled, spans);
+        self.styles_changed(styled);
+        self.render();
+        Ok(())
     }
 
     /// If this `Editor`'s buffer has been saved, Returns its path.
@@ -241,11 +670,100 @@ impl Editor {
             views.push(v.to_owned());
         }
 
+        // plugins started while updates are deferred start from the
+        // revision the others were last updated to
+        if let Some(ref deferred) = self.deferred_update {
+            let rev = deferred.start_rev.token();
+            if let Some(text) = self.engine.get_rev(rev) {
+                return PluginBufferInfo::new(self.buffer_id, &views, rev, text.len(),
+                                             text.measure::<LinesMetric>() + 1,
+                                             self.path.clone(), self.syntax.clone());
+            }
+        }
         PluginBufferInfo::new(self.buffer_id, &views,
                               self.engine.get_head_rev_id().token(), self.text.len(),
                               nb_lines, self.path.clone(), self.syntax.clone())
     }
 
+    /// Returns true if none of the buffer's views is visible.
+    fn is_hidden(&self) -> bool {
+        !self.view.is_visible() && self.views.values().all(|view| !view.is_visible())
+    }
+
+    /// Shows or hides one of the buffer's views. A buffer whose views are
+    /// all hidden sends plugins its edits in batches, and postpones
+    /// recomputing its styles and wraps, which all catch up as soon as
+    /// one of its views is shown again, before it is rendered.
+    pub fn set_view_visible(&mut self, view_id: &ViewIdentifier, visible: bool) {
+        if self.view.view_id == *view_id {
+            self.view.set_visible(&self.text, visible);
+        } else if let Some(view) = self.views.get_mut(view_id) {
+            view.set_visible(&self.text, visible);
+        }
+        self.catch_up();
+        self.render();
+    }
+
+    /// Does the work postponed while the buffer was hidden, if it no
+    /// longer is.
+    fn catch_up(&mut self) {
+        if self.is_hidden() {
+            return;
+        }
+        self.send_deferred_update();
+        if self.theme_pending {
+            self.recompute_theme_styles();
+        }
+    }
+
+    /// Notes that plugins weren't yet sent the edits since `start_rev`,
+    /// `n_edits` of them, as the buffer is hidden.
+    fn defer_update(&mut self, start_rev: RevId, n_edits: usize) {
+        if self.deferred_update.is_none() {
+            // the revision is kept until the update is sent
+            self.increment_revs_in_flight();
+            self.deferred_update = Some(DeferredUpdate {
+                start_rev: start_rev,
+                n_edits: 0,
+                due: None,
+            });
+        }
+        self.deferred_update.as_mut().unwrap().n_edits += n_edits;
+        self.last_rev_id = self.engine.get_head_rev_id();
+        self.sync_state_changed();
+    }
+
+    /// Starts the timer for the update of deferred edits, if it isn't
+    /// running. Returns the time at which the update is due, if started.
+    pub fn schedule_deferred_update(&mut self, now: Instant) -> Option<Instant> {
+        match self.deferred_update {
+            Some(ref mut deferred) if deferred.due.is_none() => {
+                let due = now + Duration::from_millis(HIDDEN_UPDATE_INTERVAL);
+                deferred.due = Some(due);
+                Some(due)
+            }
+            _ => None,
+        }
+    }
+
+    /// Sends plugins the deferred edits, if their update is due at `now`.
+    pub fn send_deferred_update_if_due(&mut self, now: Instant) {
+        let due = self.deferred_update.as_ref().and_then(|deferred| deferred.due);
+        if due.map_or(false, |due| due <= now) {
+            self.send_deferred_update();
+        }
+    }
+
+    /// Sends plugins a single update, as for a bulk edit, of the edits
+    /// deferred while the buffer was hidden.
+    fn send_deferred_update(&mut self) {
+        if let Some(deferred) = self.deferred_update.take() {
+            let delta = self.engine.delta_rev_head(deferred.start_rev.token());
+            self.notify_revision(None, &delta, Some(deferred.n_edits));
+            self.dec_revs_in_flight();
+        }
+    }
+
     fn insert(&mut self, s: &str) {
         let rope = Rope::from(s);
         let mut builder = delta::Builder::new(self.text.len());
@@ -288,6 +806,14 @@ impl Editor {
     /// breaks are to be considered invalid after this method, until the
     /// `commit_delta` call.
     fn add_delta(&mut self, delta: Delta<RopeInfo>) {
+        if let Some(read_only) = self.scratch.as_ref().map(|scratch| scratch.read_only) {
+            if read_only {
+                self.edit_refused = true;
+            } else {
+                self.add_scratch_delta(delta);
+            }
+            return;
+        }
         let head_rev_id = self.engine.get_head_rev_id();
         let undo_group;
 
@@ -300,12 +826,17 @@ impl Editor {
             undo_group = self.undo_group_id;
             self.gc_undos.extend(&self.live_undos[self.cur_undo..]);
             self.live_undos.truncate(self.cur_undo);
-            self.live_undos.push(undo_group);
-            if self.live_undos.len() <= MAX_UNDOS {
+            // the spilled groups which were undone can no longer be redone
+            self.spilled_redos.clear();
+            self.gc_undos.extend(self.spilled_edit_group.take());
+            if self.live_undos.len() < self.max_undos {
                 self.cur_undo += 1;
             } else {
-                self.gc_undos.insert(self.live_undos.remove(0));
+                let oldest = self.live_undos.remove(0);
+                self.spill_undo_group(oldest);
+                self.gc_undos.insert(oldest);
             }
+            self.live_undos.push(undo_group);
             self.undo_group_id += 1;
         }
         self.last_edit_type = self.this_edit_type;
@@ -314,16 +845,149 @@ impl Editor {
         self.text = self.engine.get_head().clone();
     }
 
+    /// Applies `delta` to a scratch buffer. As it keeps no undo history,
+    /// its edits share an undo group, which is always collected.
+    fn add_scratch_delta(&mut self, delta: Delta<RopeInfo>) {
+        let undo_group = self.undo_group_id;
+        self.gc_undos.insert(undo_group);
+        let head_rev_id = self.engine.get_head_rev_id();
+        self.engine.edit_rev(0x10000, undo_group, head_rev_id.token(), delta);
+        self.text = self.engine.get_head().clone();
+    }
+
     /// Commits the current delta, updating views, plugins, and other invariants as needed.
     fn commit_delta(&mut self, author: Option<&str>) {
-        if self.engine.get_head_rev_id() != self.last_rev_id {
+        // a chunked undo commits itself once it is complete
+        if self.chunked_undo.is_none() && self.engine.get_head_rev_id() != self.last_rev_id {
             self.update_after_revision(author);
         }
     }
 
+    /// Notes that `plugin` is about to be sent the update for revision
+    /// `rev`, so that its response is awaited.
+    pub fn expect_plugin_response(&mut self, rev: u64, plugin: PluginPid, name: &str,
+                                  priority: u16, undo_group: usize, now: Instant) {
+        self.plugin_edit_windows.entry(rev)
+            .or_insert_with(|| PluginEditWindow {
+                outstanding: Vec::new(),
+                undo_group: undo_group,
+                queued: Vec::new(),
+                applied: Vec::new(),
+                opened: now,
+            })
+            .outstanding.push(PluginEditOrder {
+                priority: Reverse(priority),
+                name: name.to_owned(),
+                plugin: plugin,
+            });
+        self.expire_plugin_edit_windows(now);
+        self.apply_ready_plugin_edits();
+    }
+
+    /// Records a plugin's response to the update for revision `rev`, which
+    /// may include an edit, and applies the edits which are ready. Returns
+    /// the edits which were rejected since this was last called.
+    ///
+    /// Edits are applied in decreasing order of plugin priority, and then
+    /// of name, so that the result doesn't depend on the order in which
+    /// the responses arrive; an edit is applied as soon as the plugins
+    /// ahead of it have responded. An edit is rejected if it overlaps an
+    /// edit which was applied before it, or inserts at the same offset; an
+    /// edit identical to one already applied is dropped.
+    pub fn plugin_responded(&mut self, rev: u64, plugin: PluginPid, edit: Option<PluginEdit>,
+                            now: Instant) -> Vec<ConflictingEdit> {
+        self.expire_plugin_edit_windows(now);
+        let expected = match self.plugin_edit_windows.get_mut(&rev) {
+            Some(window) => match window.outstanding.iter().position(|o| o.plugin == plugin) {
+                Some(pos) => {
+                    let order = window.outstanding.remove(pos);
+                    if let Some(edit) = edit {
+                        window.queued.push(QueuedPluginEdit { order: order, edit: edit });
+                    }
+                    true
+                }
+                None => false,
+            },
+            None => false,
+        };
+        if !expected {
+            print_err!("dropping late or unexpected response from {:?} to rev {}", plugin, rev);
+        }
+        self.apply_ready_plugin_edits();
+        self.take_plugin_edit_conflicts()
+    }
+
+    /// Returns the plugin edits which were rejected, which are yet to be
+    /// reported to their plugins.
+    pub fn take_plugin_edit_conflicts(&mut self) -> Vec<ConflictingEdit> {
+        mem::replace(&mut self.plugin_edit_conflicts, Vec::new())
+    }
+
+    /// Stops awaiting the responses of plugins which have taken longer than
+    /// `PLUGIN_EDIT_WINDOW_TIMEOUT`, or which are more than
+    /// `PLUGIN_EDIT_WINDOW_REVS` revisions behind, so that a hung plugin
+    /// doesn't hold back the others' edits for good.
+    fn expire_plugin_edit_windows(&mut self, now: Instant) {
+        let timeout = Duration::from_millis(PLUGIN_EDIT_WINDOW_TIMEOUT);
+        let n_old = self.plugin_edit_windows.len().saturating_sub(PLUGIN_EDIT_WINDOW_REVS);
+        for (i, (rev, window)) in self.plugin_edit_windows.iter_mut().enumerate() {
+            if !window.outstanding.is_empty() && (i < n_old || now >= window.opened + timeout) {
+                for order in window.outstanding.drain(..) {
+                    print_err!("plugin {} didn't respond to rev {} in time", order.name, rev);
+                }
+            }
+        }
+    }
+
+    /// Applies the edits which no longer wait for a plugin ahead of them,
+    /// and closes the windows whose plugins have all responded.
+    fn apply_ready_plugin_edits(&mut self) {
+        let revs = self.plugin_edit_windows.keys().cloned().collect::<Vec<_>>();
+        for rev in revs {
+            let mut window = self.plugin_edit_windows.remove(&rev).unwrap();
+            window.queued.sort_by(|a, b| a.order.cmp(&b.order));
+            let n_ready = match window.outstanding.iter().min() {
+                Some(next) => window.queued.iter().take_while(|q| q.order < *next).count(),
+                None => window.queued.len(),
+            };
+            let ready = window.queued.drain(..n_ready).collect::<Vec<_>>();
+            if !ready.is_empty() {
+                let undo_group = window.undo_group;
+                let applied = &mut window.applied;
+                let conflicts = self.bulk_edit_if(is_bulk(ready.len(), 0), |ed| {
+                    let mut conflicts = Vec::new();
+                    for queued in ready {
+                        let conflict = applied.iter()
+                            .filter(|prev| prev.edit.rev == queued.edit.rev)
+                            .map(|prev| (prev, plugin_edits_overlap(&prev.edit, &queued.edit)))
+                            .find(|&(_, overlap)| overlap != EditOverlap::None);
+                        match conflict {
+                            Some((_, EditOverlap::Duplicate)) => continue,
+                            Some((prev, _)) => conflicts.push(ConflictingEdit {
+                                plugin: queued.order.plugin,
+                                edit: queued.edit,
+                                conflicts_with: prev.order.name.clone(),
+                            }),
+                            None => {
+                                ed.apply_plugin_edit(&queued.edit, Some(undo_group));
+                                applied.push(queued);
+                            }
+                        }
+                    }
+                    conflicts
+                });
+                self.plugin_edit_conflicts.extend(conflicts);
+            }
+            if !window.outstanding.is_empty() {
+                self.plugin_edit_windows.insert(rev, window);
+            }
+        }
+    }
+
     //TODO: plugin edits should be represented by real Deltas.
     /// generates a delta from a plugin's response and applies it to the buffer.
     pub fn apply_plugin_edit(&mut self, edit: &PluginEdit, undo_group: Option<usize>) {
+        self.finish_chunked_undo();
         let interval = Interval::new_closed_open(edit.start as usize, edit.end as usize);
         let text = Rope::from(&edit.text);
         let rev_len = self.engine.get_rev(edit.rev).unwrap().len();
@@ -345,21 +1009,243 @@ impl Editor {
 
     fn update_undos(&mut self) {
         self.engine.undo(self.undos.clone());
-        self.text = self.engine.get_head().clone();
-        self.update_after_revision(None);
+        self.update_after_undo();
+    }
+
+    /// Updates the text and derived state after the engine's head was
+    /// changed by an undo or a redo.
+    fn update_after_undo(&mut self) {
+        let delta = self.engine.delta_rev_head(self.last_rev_id.token());
+        let (iv, new_len) = delta.summary();
+        if iv.size() + new_len > CHUNKED_UNDO_THRESHOLD && self.bulk_edit.is_none() {
+            self.start_chunked_undo(delta);
+        } else {
+            self.text = self.engine.get_head().clone();
+            self.update_after_revision(None);
+        }
     }
 
     fn update_after_revision(&mut self, author: Option<&str>) {
         let delta = self.engine.delta_rev_head(self.last_rev_id.token());
         let is_pristine = self.is_pristine();
-        self.scroll_to = self.view.after_edit(&self.text, &delta, is_pristine);
+        self.scroll_to = self.view.after_edit(&self.text, &self.segmentation, &delta,
+                                             is_pristine);
+        // the other views' selections are transformed, but they aren't scrolled
+        for view in self.views.values_mut() {
+            view.after_edit(&self.text, &self.segmentation, &delta, is_pristine);
+        }
+        self.update_derived_state(&delta);
+        let hidden = self.is_hidden();
+        match self.bulk_edit.as_mut() {
+            // plugins are updated once the bulk edit ends
+            Some(bulk) => bulk.n_edits += 1,
+            None if hidden => {
+                let start_rev = self.last_rev_id;
+                self.defer_update(start_rev, 1);
+            }
+            None => {
+                self.notify_revision(author, &delta, None);
+                return;
+            }
+        }
+        self.last_rev_id = self.engine.get_head_rev_id();
+    }
+
+    /// Begins a bulk edit, which ends at the matching `end_bulk_edit`. Bulk
+    /// edits may nest.
+    pub fn begin_bulk_edit(&mut self) {
+        // the edits of an undo would otherwise be composed half-applied
+        self.finish_chunked_undo();
+        self.commit_delta(None);
+        match self.bulk_edit {
+            Some(ref mut bulk) => bulk.depth += 1,
+            None => {
+                self.bulk_edit = Some(BulkEdit {
+                    depth: 1,
+                    start_rev: self.last_rev_id,
+                    n_edits: 0,
+                });
+            }
+        }
+    }
+
+    /// Ends a bulk edit. If it is the outermost, plugins are sent a
+    /// `bulk_edit` notification and a single update, of the composition of
+    /// its deltas, and views are rendered.
+    pub fn end_bulk_edit(&mut self) {
+        match self.bulk_edit {
+            Some(ref mut bulk) if bulk.depth > 1 => {
+                bulk.depth -= 1;
+                return;
+            }
+            Some(_) => (),
+            None => {
+                print_err!("end_bulk_edit without a bulk edit");
+                return;
+            }
+        }
+        self.commit_delta(None);
+        let bulk = self.bulk_edit.take().unwrap();
+        if bulk.n_edits > 0 {
+            let delta = self.engine.delta_rev_head(bulk.start_rev.token());
+            let (iv, new_len) = delta.summary();
+            self.styles.apply_edit(&delta);
+            self.mark_invisibles(iv, new_len);
+            if self.is_hidden() {
+                self.defer_update(bulk.start_rev, bulk.n_edits);
+            } else {
+                self.notify_revision(None, &delta, Some(bulk.n_edits));
+            }
+        }
+        self.render();
+    }
+
+    /// Makes the edits of `f` as one bulk edit if `bulk` is true. The bulk
+    /// edit ends however `f` returns, so that an operation which fails
+    /// midway still updates plugins and views with the edits it made.
+    fn bulk_edit_if<F, T>(&mut self, bulk: bool, f: F) -> T
+        where F: FnOnce(&mut Editor) -> T
+    {
+        if !bulk {
+            return f(self);
+        }
+        self.begin_bulk_edit();
+        let result = f(self);
+        self.end_bulk_edit();
+        result
+    }
+
+    /// Updates the state computed from the text, after `delta` is applied.
+    fn update_derived_state(&mut self, delta: &Delta<RopeInfo>) {
         let (iv, new_len) = delta.summary();
 
-        // TODO: maybe more precise editing based on actual delta rather than summary.
-        // TODO: perhaps use different semantics for spans that enclose the edited region.
-        // Currently it breaks any such span in half and applies no spans to the inserted
-        // text. That's ok for syntax highlighting but not ideal for rich text.
-        self.styles.update_all(iv, new_len);
+        // Spans around an insertion grow to cover it, so that the inserted text
+        // keeps its highlighting until plugins send new spans.
+        // During a bulk edit, styles are updated once, for the composed delta.
+        if self.bulk_edit.is_none() {
+            self.styles.apply_edit(delta);
+            self.mark_invisibles(iv, new_len);
+        }
+        // the plugins' pending styles are told to the views before the
+        // edit is rendered, so that they never lag behind the text
+        if let Some(pending) = self.pending_styles.take() {
+            let shift = |offset| if offset <= iv.start() {
+                offset
+            } else if offset >= iv.end() {
+                offset - iv.size() + new_len
+            } else {
+                iv.start()
+            };
+            self.styles_changed(Interval::new_closed_open(shift(pending.start()),
+                                                          shift(pending.end())));
+        }
+        let inserted = self.text.slice_to_string(iv.start(), iv.start() + new_len)
+            .chars().take(incidents::MAX_DELTA_TEXT).collect();
+        self.recent_deltas.push_back(DeltaRecord {
+            start: iv.start(),
+            end: iv.end(),
+            new_len: new_len,
+            inserted: inserted,
+        });
+        if self.recent_deltas.len() > incidents::RECENT_DELTAS {
+            self.recent_deltas.pop_front();
+        }
+        self.line_metadata.after_edit(&self.text, delta);
+        self.metrics.after_edit(&self.text, delta);
+        self.builtin_syntax.after_edit(&self.text, iv, new_len);
+        self.note_syntax_edit(iv.start(), new_len);
+    }
+
+    /// Notes whether an edit replacing text at `start` with `new_len` bytes
+    /// changed the first or last lines, if the syntax may be detected from
+    /// them.
+    fn note_syntax_edit(&mut self, start: usize, new_len: usize) {
+        // the creator of a scratch buffer sets its styles
+        if self.explicit_syntax || self.scratch.is_some() ||
+            self.path_syntax() != SyntaxDefinition::Plaintext {
+            return;
+        }
+        let n_lines = self.text.measure::<LinesMetric>() + 1;
+        let head_end = self.text.offset_of_line(MODELINE_LINES);
+        let tail_start = self.text.offset_of_line(n_lines.saturating_sub(MODELINE_LINES));
+        if start <= head_end || start + new_len >= tail_start {
+            self.syntax_edited = true;
+        }
+    }
+
+    /// Starts or postpones syntax detection, if lines which may determine
+    /// the syntax have been edited. Returns the time at which it is due.
+    pub fn schedule_syntax_detection(&mut self, now: Instant) -> Option<Instant> {
+        if !mem::replace(&mut self.syntax_edited, false) {
+            return None;
+        }
+        Some(self.syntax_detection.edited(now, Duration::from_millis(SYNTAX_DETECTION_DELAY)))
+    }
+
+    /// Detects the syntax, if detection is due at `now`. Returns the active
+    /// view, if the syntax changed.
+    pub fn detect_syntax_if_due(&mut self, now: Instant) -> Option<ViewIdentifier> {
+        if !self.syntax_detection.take_due(now) || self.explicit_syntax {
+            return None;
+        }
+        let syntax = self.detected_syntax();
+        if syntax == self.syntax {
+            return None;
+        }
+        self.syntax = syntax;
+        self.render();
+        Some(self.view.view_id.clone())
+    }
+
+    /// Sets the syntax, as chosen by the user, so that it is no longer
+    /// detected.
+    fn toggle_show_invisibles(&mut self) {
+        let shown = !self.invisibles.is_shown();
+        let mark_cr = self.doc_ctx.get_config().show_invisible_cr;
+        let changed = self.invisibles.set_shown(shown, &self.text, mark_cr, &mut self.styles,
+                                                &self.doc_ctx);
+        if let Some(iv) = changed {
+            self.styles_changed(iv);
+        }
+    }
+
+    /// Marks again the invisible characters of the lines touched by the
+    /// edit which replaced `iv` with `new_len` bytes, if they're shown.
+    fn mark_invisibles(&mut self, iv: Interval, new_len: usize) {
+        let mark_cr = self.doc_ctx.get_config().show_invisible_cr;
+        if let Some(lines) = self.invisibles.after_edit(&self.text, iv, new_len, mark_cr,
+                                                        &mut self.styles) {
+            self.styles_changed(lines);
+        }
+    }
+
+    fn set_language(&mut self, language_id: &str) {
+        match SyntaxDefinition::from_name(language_id) {
+            Some(syntax) => {
+                self.explicit_syntax = true;
+                self.syntax_detection.cancel();
+                self.syntax = syntax;
+            }
+            None => self.doc_ctx.alert(&Message::new(ids::unknown_language)
+                                       .param("language_id", language_id)),
+        }
+    }
+
+    /// Notifies plugins of the new head revision, which differs from the
+    /// last revision by `delta`.
+    /// `bulk_edits` is the number of edits composed, if `delta` is the
+    /// composition of a bulk edit.
+    fn notify_revision(&mut self, author: Option<&str>, delta: &Delta<RopeInfo>,
+                       bulk_edits: Option<usize>) {
+        // plugins aren't told of scratch buffers, whose edits are collected
+        // right away
+        if self.scratch.is_some() {
+            self.last_rev_id = self.engine.get_head_rev_id();
+            self.sync_state_changed();
+            self.gc_undos();
+            return;
+        }
+        let (iv, new_len) = delta.summary();
 
         // We increment revs in flight once here, and we decrement once
         // after sending plugin updates, regardless of whether or not any actual
@@ -373,12 +1259,21 @@ impl Editor {
                 false => None
             };
 
-            let update = PluginUpdate::new(
+            let mut update = PluginUpdate::new(
                 self.view.view_id.clone(),
                 iv.start(), iv.end(), new_len,
                 self.engine.get_head_rev_id().token(), text,
                 self.this_edit_type.json_string().to_owned(),
                 author.to_owned());
+            if let Some(n_edits) = bulk_edits {
+                update.set_bulk_edits(n_edits);
+            }
+            let invalidated = self.styles.take_invalidated_intervals();
+            if !invalidated.is_empty() {
+                update.set_invalidated(invalidated.iter()
+                    .map(|iv| (iv.start(), iv.end()))
+                    .collect());
+            }
 
             let undo_group = *self.live_undos.last().unwrap_or(&0);
             let view_id = self.view.view_id.clone();
@@ -390,60 +1285,459 @@ impl Editor {
         self.sync_state_changed();
     }
 
-    #[cfg(not(target_os = "fuchsia"))]
-    fn gc_undos(&mut self) {
-        if self.revs_in_flight == 0 && !self.gc_undos.is_empty() {
-            self.engine.gc(&self.gc_undos);
-            self.undos = &self.undos - &self.gc_undos;
-            self.gc_undos.clear();
+    /// Begins applying `delta`, the result of an undo, a redo or a large
+    /// reindent, to the text.
+    fn start_chunked_undo(&mut self, delta: Delta<RopeInfo>) {
+        let (iv, new_len) = delta.summary();
+        let mut cuts = vec![(iv.start(), iv.start())];
+        {
+            let mut transformer = Transformer::new(&delta);
+            let mut line = self.text.line_of_offset(iv.start()) + UNDO_CHUNK_LINES;
+            while line <= self.text.measure::<LinesMetric>() {
+                let offset = self.text.offset_of_line(line);
+                if offset >= iv.end() {
+                    break;
+                }
+                cuts.push((offset, transformer.transform(offset, true)));
+                line += UNDO_CHUNK_LINES;
+            }
         }
-    }
+        cuts.push((iv.end(), iv.start() + new_len));
 
-    #[cfg(target_os = "fuchsia")]
-    fn gc_undos(&mut self) {
-        // Never run GC on Fuchsia so that peers don't invalidate our
-        // last_rev_id and so that merge will work.
+        let mut selection = Selection::new();
+        for region in self.view.sel_regions() {
+            selection.add_region(region.clone());
+        }
+        self.chunked_undo = Some(ChunkedUndo {
+            delta: delta,
+            target: self.engine.get_head().clone(),
+            selection: selection,
+            cuts: cuts,
+            n_applied: 0,
+            edit_type: self.this_edit_type,
+            queued: Vec::new(),
+        });
+        self.apply_undo_chunks(1);
     }
 
-    fn is_pristine(&self) -> bool {
-        self.engine.is_equivalent_revision(self.pristine_rev_id, self.engine.get_head_rev_id())
+    /// Returns `true` if there is work to be done in the background, through
+    /// `do_background_work`.
+    pub fn has_background_work(&self) -> bool {
+        ((self.theme_pending || self.styles.is_restyling() ||
+          self.builtin_syntax.has_work(&self.builtin_syntax_wanted())) && !self.is_hidden())
+            || self.chunked_undo.is_some()
+            || (self.metrics.is_recounting() && self.bulk_edit.is_none())
     }
 
-    // render if needed, sending to ui
-    pub fn render(&mut self) {
-        self.view.render_if_dirty(&self.text, &self.doc_ctx, self.styles.get_merged());
-        if let Some(scrollto) = self.scroll_to {
-            let (line, col) = self.view.offset_to_line_col(&self.text, scrollto);
-            self.doc_ctx.scroll_to(&self.view.view_id, line, col);
-            self.scroll_to = None;
+    /// Does a bounded amount of background work, and renders the result.
+    pub fn do_background_work(&mut self) {
+        if self.theme_pending {
+            self.recompute_theme_styles();
+        } else if self.styles.is_restyling() && !self.is_hidden() {
+            self.restyle_next_chunk();
+        } else if self.chunked_undo.is_some() {
+            self.apply_undo_chunks(1);
+            self.render();
+        } else if self.metrics.is_recounting() {
+            // each render takes a step of the recount
+            self.render();
+        } else if !self.is_hidden() {
+            let syntax = self.builtin_syntax_wanted();
+            let parsed = self.builtin_syntax.parse_chunk(&syntax, &self.text, &mut self.styles,
+                                                         &self.doc_ctx);
+            if let Some(iv) = parsed {
+                self.styles_changed(iv);
+                self.render();
+            }
         }
     }
 
-    pub fn merge_new_state(&mut self, new_engine: Engine) {
-        self.engine.merge(&new_engine);
-        self.text = self.engine.get_head().clone();
-        // TODO: better undo semantics. This only implements separate undo histories for low concurrency.
-        self.undo_group_id = self.engine.max_undo_group_id() + 1;
-        self.last_synced_rev = self.engine.get_head_rev_id();
-        self.commit_delta(None);
-        self.render();
+    /// The syntax core highlights the buffer with itself: its syntax, unless
+    /// that is disabled, or the creator of a scratch buffer sets its styles.
+    fn builtin_syntax_wanted(&self) -> SyntaxDefinition {
+        if self.doc_ctx.get_config().builtin_syntax && self.scratch.is_none() {
+            self.syntax.clone()
+        } else {
+            SyntaxDefinition::Plaintext
+        }
     }
 
-    /// See `Engine::set_session_id` only useful when using Fuchsia sync functionality.
-    pub fn set_session_id(&mut self, session: (u64,u32)) {
-        self.engine.set_session_id(session);
+    /// Applies all the remaining chunks of an in-progress chunked undo.
+    fn finish_chunked_undo(&mut self) {
+        if self.chunked_undo.is_some() {
+            self.apply_undo_chunks(usize::max_value());
+        }
     }
 
-    #[cfg(target_os = "fuchsia")]
-    pub fn set_sync_store(&mut self, sync_store: SyncStore) {
-        self.sync_store = Some(sync_store);
-    }
+    /// Applies up to `n` chunks of the in-progress chunked undo, completing
+    /// it if none remain.
+    fn apply_undo_chunks(&mut self, n: usize) {
+        let (delta, done) = {
+            let undo = self.chunked_undo.as_mut().unwrap();
+            let (old_start, new_start) = undo.cuts[undo.n_applied];
+            undo.n_applied = min(undo.n_applied.saturating_add(n), undo.cuts.len() - 1);
+            let (old_end, new_end) = undo.cuts[undo.n_applied];
+            let iv = Interval::new_closed_open(new_start, new_start + old_end - old_start);
+            let new_text = undo.target.subseq(Interval::new_closed_open(new_start, new_end));
+            (Delta::simple_edit(iv, new_text, self.text.len()),
+             undo.n_applied == undo.cuts.len() - 1)
+        };
+        self.text = delta.apply(&self.text);
+        let is_pristine = self.is_pristine();
+        self.view.after_edit(&self.text, &self.segmentation, &delta, is_pristine);
+        self.update_derived_state(&delta);
 
-    #[cfg(not(target_os = "fuchsia"))]
-    pub fn sync_state_changed(&mut self) {
+        if done {
+            let undo = self.chunked_undo.take().unwrap();
+            self.text = self.engine.get_head().clone();
+            let selection = undo.selection.apply_delta(&undo.delta, true);
+            self.scroll_to = self.view.set_selection(&self.text, selection);
+            self.this_edit_type = undo.edit_type;
+            if self.is_hidden() {
+                let start_rev = self.last_rev_id;
+                self.defer_update(start_rev, 1);
+            } else {
+                self.notify_revision(None, &undo.delta, None);
+            }
+            for (view_id, cmd) in undo.queued {
+                self.handle_notification(&view_id, cmd);
+            }
+        }
     }
 
-    #[cfg(target_os = "fuchsia")]
+    /// Spills `group`, the oldest undo group, which falls out of the window,
+    /// if undo history is spilled.
+    fn spill_undo_group(&mut self, group: usize) {
+        if self.undo_spill.is_none() {
+            if self.undo_spill_failed
+                || self.doc_ctx.get_config().undo_history != UndoHistory::Spill {
+                return;
+            }
+            let path = undo_spill::spill_path(&self.buffer_id.to_string());
+            self.undo_spill = Some(UndoSpill::new(path));
+        }
+        // the text right after the group, and before it, with every later
+        // group undone
+        let mut after_undone = self.undos.clone();
+        after_undone.extend(&self.live_undos);
+        let mut before_undone = after_undone.clone();
+        before_undone.insert(group);
+        let after = self.engine.delta_between_undos(&self.undos, &after_undone)
+            .apply(self.engine.get_head());
+        let undo = self.engine.delta_between_undos(&after_undone, &before_undone);
+        let (iv, new_len) = undo.summary();
+        let before = undo.apply(&after);
+        let spilled = SpilledGroup {
+            start: iv.start(),
+            end: iv.end(),
+            text: before.slice_to_string(iv.start(), iv.start() + new_len),
+            base_len: after.len(),
+        };
+        let result = self.undo_spill.as_mut().unwrap().push(spilled);
+        if let Err(error) = result {
+            self.lose_spilled_history(error);
+        }
+    }
+
+    /// Forgets the spilled undo history, which can't be used, as when its
+    /// file is corrupted, and stops spilling.
+    fn lose_spilled_history(&mut self, error: String) {
+        self.undo_spill = None;
+        self.undo_spill_failed = true;
+        self.doc_ctx.alert(&Message::new(ids::undo_history_lost).param("error", error));
+    }
+
+    /// Undoes the newest spilled undo group which is applied, returning
+    /// false if there is none.
+    fn undo_spilled_group(&mut self) -> bool {
+        let result = match self.undo_spill.as_mut() {
+            Some(spill) => spill.pop(),
+            None => return false,
+        };
+        let spilled = match result {
+            Ok(Some(ref spilled)) if spilled.base_len != self.text.len() => {
+                Err("it doesn't match the buffer".to_owned())
+            }
+            Ok(spilled) => Ok(spilled),
+            Err(error) => Err(error),
+        };
+        match spilled {
+            Ok(Some(spilled)) => {
+                self.spill_live_undos();
+                let redo = spilled.inverse(&self.text);
+                self.make_spilled_edit(spilled);
+                self.spilled_redos.push((redo, true));
+                true
+            }
+            Ok(None) => false,
+            Err(error) => {
+                self.lose_spilled_history(error);
+                false
+            }
+        }
+    }
+
+    /// Replaces the groups in the window, which are all undone, with the
+    /// edits which redo them, so that they are collected before the edits
+    /// which undo spilled groups are made, as the engine can only collect
+    /// those once they are the oldest in its history.
+    fn spill_live_undos(&mut self) {
+        let mut undone = self.undos.clone();
+        let mut text = self.engine.get_head().clone();
+        let mut redos = Vec::new();
+        for &group in &self.live_undos {
+            let mut redone = undone.clone();
+            redone.remove(&group);
+            let redo = self.engine.delta_between_undos(&undone, &redone);
+            let (iv, new_len) = redo.summary();
+            let base_len = text.len();
+            text = redo.apply(&text);
+            redos.push(SpilledGroup {
+                start: iv.start(),
+                end: iv.end(),
+                text: text.slice_to_string(iv.start(), iv.start() + new_len),
+                base_len: base_len,
+            });
+            undone = redone;
+        }
+        self.spilled_redos.extend(redos.into_iter().rev().map(|redo| (redo, false)));
+        self.gc_undos.extend(self.live_undos.drain(..));
+        self.cur_undo = 0;
+    }
+
+    /// Makes an edit which undoes or redoes a spilled group. It is collected
+    /// as soon as it can be, as the spilled group is not in the engine's
+    /// history.
+    fn make_spilled_edit(&mut self, spilled: SpilledGroup) {
+        let undo_group = self.undo_group_id;
+        self.undo_group_id += 1;
+        let iv = Interval::new_closed_open(spilled.start, spilled.end);
+        let delta = Delta::simple_edit(iv, Rope::from(spilled.text), self.text.len());
+        let head_rev_id = self.engine.get_head_rev_id();
+        self.engine.edit_rev(0x10000, undo_group, head_rev_id.token(), delta);
+        if let Some(last) = mem::replace(&mut self.spilled_edit_group, Some(undo_group)) {
+            self.gc_undos.insert(last);
+        }
+    }
+
+    #[cfg(not(target_os = "fuchsia"))]
+    fn gc_undos(&mut self) {
+        if self.revs_in_flight == 0 && !self.gc_undos.is_empty() && !self.history_shared {
+            self.engine.gc(&self.gc_undos);
+            self.undos = &self.undos - &self.gc_undos;
+            self.gc_undos.clear();
+        }
+    }
+
+    #[cfg(target_os = "fuchsia")]
+    fn gc_undos(&mut self) {
+        // Never run GC on Fuchsia so that peers don't invalidate our
+        // last_rev_id and so that merge will work.
+    }
+
+    /// Marks every view onto the buffer as needing to be rendered.
+    fn set_dirty(&mut self) {
+        self.view.set_dirty();
+        for view in self.views.values_mut() {
+            view.set_dirty();
+        }
+    }
+
+    /// Notes that a plugin changed the styles within `iv`, to tell the views
+    /// at the next render, or right away if the pending region is large.
+    fn plugin_styles_changed(&mut self, iv: Interval) {
+        let pending = self.pending_styles.map_or(iv, |pending| pending.union(iv));
+        self.pending_styles = Some(pending);
+        if pending.size() > PENDING_STYLES_MAX {
+            self.render();
+        }
+    }
+
+    /// Renders the plugins' pending changes to the styles, if there are
+    /// any, as once a plugin has no more updates queued.
+    pub fn flush_plugin_styles(&mut self) {
+        if self.pending_styles.is_some() {
+            self.enforce_span_budget();
+            self.render();
+        }
+    }
+
+    /// The regions around each view's scroll, within which the plugins'
+    /// spans are kept whatever the span budget: the lines in view, and as
+    /// many bytes again on either side.
+    fn span_windows(&self) -> Vec<Interval> {
+        let len = self.text.len();
+        Some(&self.view).into_iter().chain(self.views.values()).map(|view| {
+            let iv = view.scroll_interval(&self.text);
+            Interval::new_closed_open(iv.start().saturating_sub(iv.size()),
+                                      min(iv.end() + iv.size(), len))
+        }).collect()
+    }
+
+    /// Drops the spans of plugins' layers away from the views' scroll, if
+    /// they have more than the `span_budget` setting allows.
+    fn enforce_span_budget(&mut self) {
+        let budget = self.doc_ctx.get_config().span_budget;
+        if budget == 0 {
+            return;
+        }
+        let windows = self.span_windows();
+        for iv in self.styles.enforce_span_budget(budget, &windows) {
+            self.styles_changed(iv);
+        }
+    }
+
+    /// Notes the regions whose spans were dropped for the span budget
+    /// which are now in view, to ask their plugins for them again.
+    fn request_evicted_spans(&mut self) {
+        let windows = self.span_windows();
+        let rev = self.engine.get_head_rev_id().token();
+        let evicted = self.styles.take_evicted(&windows);
+        self.span_requests.extend(evicted.into_iter().map(|(plugin, iv)| (plugin, iv, rev)));
+    }
+
+    /// Returns the regions of plugins' layers to ask the plugins for, with
+    /// the revision each is in, since last taken.
+    pub fn take_span_requests(&mut self) -> Vec<(PluginPid, Interval, u64)> {
+        mem::replace(&mut self.span_requests, Vec::new())
+    }
+
+    /// Marks every view as needing to be rendered after the styles within
+    /// `iv` have changed.
+    fn styles_changed(&mut self, iv: Interval) {
+        self.view.styles_changed(&self.text, iv);
+        for view in self.views.values_mut() {
+            view.styles_changed(&self.text, iv);
+        }
+    }
+
+    fn is_pristine(&self) -> bool {
+        self.engine.is_equivalent_revision(self.pristine_rev_id, self.engine.get_head_rev_id())
+    }
+
+    /// Tells the views which revision their text is, to send with their
+    /// lines. There is none while an undo is applied in chunks.
+    fn update_view_revs(&mut self) {
+        let rev = match self.chunked_undo {
+            Some(_) => None,
+            None => Some(self.engine.get_head_rev_id().token()),
+        };
+        self.view.set_rev(rev);
+        for view in self.views.values_mut() {
+            view.set_rev(rev);
+        }
+    }
+
+    /// Takes the next step of a recount of the longest line, if there is
+    /// one. Once it is done, the views are updated with the exact length.
+    fn recount_step(&mut self) {
+        if !self.metrics.is_recounting() {
+            return;
+        }
+        self.metrics.recount_step(&self.text);
+        if !self.metrics.is_recounting() {
+            self.view.set_dirty();
+            for view in self.views.values_mut() {
+                view.set_dirty();
+            }
+        }
+    }
+
+    // render if needed, sending to ui
+    pub fn render(&mut self) {
+        if let Some(pending) = self.pending_styles.take() {
+            self.styles_changed(pending);
+        }
+        // styles are resolved first, so that the incidents of resolving
+        // them are recorded with this render
+        self.styles.get_merged();
+        self.record_incidents();
+        self.update_view_revs();
+        // views are rendered once a bulk edit ends
+        if self.bulk_edit.is_some() {
+            return;
+        }
+        // exact recounts of the longest line are spread over renders
+        self.recount_step();
+        self.update_pending_cleanup();
+        // hidden views are rendered once they are shown again
+        if self.view.is_visible() {
+            self.view.render_if_dirty(&self.text, &self.doc_ctx, self.styles.get_merged(),
+                                      &self.segmentation, &self.metrics,
+                                      &mut self.line_metadata);
+        }
+        for view in self.views.values_mut().filter(|view| view.is_visible()) {
+            view.render_if_dirty(&self.text, &self.doc_ctx, self.styles.get_merged(),
+                                 &self.segmentation, &self.metrics, &mut self.line_metadata);
+        }
+        if let Some(scrollto) = self.scroll_to {
+            let position = self.view.scroll_position(&self.text, scrollto);
+            let center = mem::replace(&mut self.scroll_centered, false);
+            self.doc_ctx.scroll_to(&self.view.view_id, &position, center);
+            self.scroll_to = None;
+        }
+        let buffer_info = self.buffer_info();
+        if self.sent_buffer_info.as_ref() != Some(&buffer_info) {
+            self.doc_ctx.buffer_info(&self.view.view_id, &buffer_info);
+            for view_id in self.views.keys() {
+                self.doc_ctx.buffer_info(view_id, &buffer_info);
+            }
+            self.sent_buffer_info = Some(buffer_info);
+        }
+    }
+
+    pub fn merge_new_state(&mut self, new_engine: Engine) {
+        self.finish_chunked_undo();
+        self.engine.merge(&new_engine);
+        self.text = self.engine.get_head().clone();
+        // TODO: better undo semantics. This only implements separate undo histories for low concurrency.
+        self.undo_group_id = self.engine.max_undo_group_id() + 1;
+        self.last_synced_rev = self.engine.get_head_rev_id();
+        self.commit_delta(None);
+        self.render();
+    }
+
+    fn export_history(&mut self, since_rev: Option<RevToken>) -> Value {
+        self.history_shared = true;
+        history::export(&self.engine, since_rev)
+    }
+
+    /// Merges a history exported by another core, which must have loaded
+    /// the same text, and returns the new head revision.
+    fn merge_history(&mut self, payload: &Value) -> Result<Value, RemoteError> {
+        let refuse = |msg: &str| RemoteError::custom(history::MERGE_REFUSED, msg, None);
+        let other = match history::import(payload) {
+            Ok(Some(engine)) => engine,
+            Ok(None) => return Ok(json!({"rev": self.engine.get_head_rev_id().token()})),
+            Err(msg) => return Err(RemoteError::custom(3, msg, None)),
+        };
+        if self.engine.has_undos() || other.has_undos() {
+            return Err(refuse("histories with undos can't be merged yet"));
+        }
+        let initial = self.engine.get_rev(self.initial_rev).map(String::from);
+        if initial.is_none() || other.get_rev(self.initial_rev).map(String::from) != initial {
+            return Err(refuse("the history doesn't start from the buffer's initial text"));
+        }
+        self.history_shared = true;
+        self.merge_new_state(other);
+        Ok(json!({"rev": self.engine.get_head_rev_id().token()}))
+    }
+
+    /// See `Engine::set_session_id` only useful when using Fuchsia sync functionality.
+    pub fn set_session_id(&mut self, session: (u64,u32)) {
+        self.engine.set_session_id(session);
+    }
+
+    #[cfg(target_os = "fuchsia")]
+    pub fn set_sync_store(&mut self, sync_store: SyncStore) {
+        self.sync_store = Some(sync_store);
+    }
+
+    #[cfg(not(target_os = "fuchsia"))]
+    pub fn sync_state_changed(&mut self) {
+    }
+
+    #[cfg(target_os = "fuchsia")]
     pub fn sync_state_changed(&mut self) {
         if let Some(sync_store) = self.sync_store.as_mut() {
             // we don't want to sync right after recieving a new merge
@@ -512,7 +1806,8 @@ impl Editor {
         let mut deletions = Selection::new();
         for r in self.view.sel_regions() {
             if r.is_caret() {
-                let new_region = region_movement(movement, r, &self.view, &self.text, true);
+                let new_region = region_movement(movement, r, &self.view, &self.text,
+                                                 &self.segmentation, true);
                 deletions.add_region(new_region);
             } else {
                 deletions.add_region(r.clone());
@@ -561,16 +1856,101 @@ impl Editor {
 
     fn insert_newline(&mut self) {
         self.this_edit_type = EditType::InsertChars;
-        self.insert("\n");
+        let config = self.doc_ctx.get_config();
+        if !config.autoindent {
+            self.insert("\n");
+            return;
+        }
+        let rules = config.indent_rules(&self.syntax);
+        let style = config.indent_style();
+        let mut builder = delta::Builder::new(self.text.len());
+        for region in self.view.sel_regions() {
+            let line = self.text.line_of_offset(region.min());
+            let line_start = self.text.offset_of_line(line);
+            let prefix = self.text.slice_to_string(line_start, region.min());
+            let indent = if prefix.trim().is_empty() {
+                // the caret is inside the line's leading whitespace
+                style.width(&prefix)
+            } else {
+                self.line_metadata.indent(&self.text, line)
+            };
+            let bracket_delta = self.line_metadata.get(&self.text, line, BRACKET_DELTA)
+                .unwrap_or(0);
+            let indent = rules.next_line_indent(&prefix, indent, bracket_delta, style.tab_size);
+            let iv = Interval::new_closed_open(region.min(), region.max());
+            builder.replace(iv, Rope::from(format!("\n{}", style.whitespace(indent))));
+        }
+        self.add_delta(builder.build());
+    }
+
+    /// If inserting `chars` would complete a line which the current syntax's
+    /// indent rules say should be dedented (such as a closing brace), returns
+    /// a delta which both inserts the text and fixes the indent.
+    fn electric_insert_delta(&mut self, chars: &str) -> Option<Delta<RopeInfo>> {
+        let config = self.doc_ctx.get_config();
+        if !config.autoindent || self.view.sel_regions().iter().any(|r| !r.is_caret()) {
+            return None;
+        }
+        let rules = config.indent_rules(&self.syntax);
+        let style = config.indent_style();
+        let mut builder = delta::Builder::new(self.text.len());
+        let mut changed_indent = false;
+        for region in self.view.sel_regions() {
+            let line = self.text.line_of_offset(region.end);
+            let line_start = self.text.offset_of_line(line);
+            let line_end = self.text.offset_of_line(line + 1);
+            let prefix = self.text.slice_to_string(line_start, region.end);
+            let suffix = self.text.slice_to_string(region.end, line_end);
+            let ws_len = prefix.len() - prefix.trim_start().len();
+
+            let mut target = None;
+            if suffix.trim().is_empty() && line > 0 &&
+                rules.is_electric(&format!("{}{}", prefix, chars)) {
+                // find the closest preceding non-blank line
+                let mut prev = line - 1;
+                loop {
+                    let prev_start = self.text.offset_of_line(prev);
+                    let prev_text = self.text.slice_to_string(prev_start,
+                                                              self.text.offset_of_line(prev + 1));
+                    if !prev_text.trim().is_empty() || prev == 0 {
+                        let prev_indent = self.line_metadata.indent(&self.text, prev);
+                        target = Some(rules.electric_indent(&prev_text, prev_indent,
+                                                            style.tab_size));
+                        break;
+                    }
+                    prev -= 1;
+                }
+            }
+            match target {
+                Some(target) if target < self.line_metadata.indent(&self.text, line) => {
+                    let ws_iv = Interval::new_closed_open(line_start, line_start + ws_len);
+                    builder.replace(ws_iv, Rope::from(style.whitespace(target)));
+                    changed_indent = true;
+                }
+                _ => (),
+            }
+            let iv = Interval::new_closed_open(region.end, region.end);
+            builder.replace(iv, Rope::from(chars));
+        }
+        if changed_indent {
+            Some(builder.build())
+        } else {
+            None
+        }
     }
 
     fn insert_tab(&mut self) {
+        let style = self.doc_ctx.get_config().indent_style();
         let mut builder = delta::Builder::new(self.text.len());
         for region in self.view.sel_regions() {
             let iv = Interval::new_closed_open(region.min(), region.max());
+            if !style.use_spaces {
+                builder.replace(iv, Rope::from("\t"));
+                continue;
+            }
             let (_, col) = self.view.offset_to_line_col(&self.text, region.start);
-            let n = TAB_SIZE - (col % TAB_SIZE);
-            builder.replace(iv, Rope::from(n_spaces(n)));
+            let n = style.tab_size - (col % style.tab_size);
+            builder.replace(iv, Rope::from(" ".repeat(n)));
         }
         self.this_edit_type = EditType::InsertChars;
         self.add_delta(builder.build());
@@ -594,13 +1974,87 @@ impl Editor {
         */
     }
 
+    /// Rewrites the leading whitespace of the lines in `scope` in the
+    /// current indent style, as one undo group.
+    fn do_reindent(&mut self, scope: rpc::ReindentScope, snap: bool, skip_strings: bool) {
+        let style = self.doc_ctx.get_config().indent_style();
+        let last_line = self.text.line_of_offset(self.text.len());
+        // ranges of lines, inclusive
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        match scope {
+            rpc::ReindentScope::Document => ranges.push((0, last_line)),
+            rpc::ReindentScope::Selection => for region in self.view.sel_regions() {
+                let first = self.text.line_of_offset(region.min());
+                let mut last = self.text.line_of_offset(region.max());
+                if last > first && self.text.offset_of_line(last) == region.max() {
+                    last -= 1;
+                }
+                match ranges.last_mut() {
+                    Some(&mut (_, ref mut prev_last)) if first <= *prev_last + 1 => {
+                        *prev_last = max(*prev_last, last);
+                        continue;
+                    }
+                    _ => (),
+                }
+                ranges.push((first, last));
+            },
+        }
+        let strings = if skip_strings {
+            let iv = Interval::new_closed_open(0, self.text.len());
+            self.styles.intervals_with_scope(iv, &["string"])
+        } else {
+            Vec::new()
+        };
+
+        let mut builder = delta::Builder::new(self.text.len());
+        for (first, last) in ranges {
+            let end = if last == last_line { self.text.len() } else {
+                self.text.offset_of_line(last + 1)
+            };
+            let mut offset = self.text.offset_of_line(first);
+            for line in self.text.lines_raw(offset, end) {
+                let line_start = offset;
+                offset += line.len();
+                if strings.iter().any(|iv| iv.start() < line_start && line_start < iv.end()) {
+                    continue;
+                }
+                let ws_len = line.len() - line.trim_start_matches(|c| c == ' ' || c == '\t').len();
+                let ws = &line[..ws_len];
+                let new_ws = style.reindent(ws, snap);
+                if new_ws != ws {
+                    let iv = Interval::new_closed_open(line_start, line_start + ws.len());
+                    builder.replace(iv, Rope::from(new_ws));
+                }
+            }
+        }
+        if builder.is_empty() {
+            return;
+        }
+        let delta = builder.build();
+        let (iv, new_len) = delta.summary();
+        self.this_edit_type = EditType::Other;
+        if iv.size() + new_len > CHUNKED_UNDO_THRESHOLD && self.bulk_edit.is_none() {
+            // like a large undo, the text is replaced in the background
+            let text = self.text.clone();
+            self.add_delta(delta.clone());
+            self.text = text;
+            self.start_chunked_undo(delta);
+        } else {
+            let bulk = is_bulk(1, iv.size() + new_len);
+            self.bulk_edit_if(bulk, |ed| {
+                ed.add_delta(delta);
+                ed.commit_delta(None);
+            });
+        }
+    }
+
     /// Apply a movement, also setting the scroll to the point requested by
     /// the movement.
     ///
     /// The type of the `flags` parameter is a convenience to old-style
     /// movement methods.
     fn do_move(&mut self, movement: Movement, flags: u64) {
-        self.scroll_to = self.view.do_move(&self.text, movement,
+        self.scroll_to = self.view.do_move(&self.text, &self.segmentation, movement,
             (flags & FLAG_SELECT) != 0);
     }
 
@@ -613,7 +2067,7 @@ impl Editor {
     }
 
     fn move_left(&mut self, flags: u64) {
-        self.do_move(Movement::Left, flags);
+        self.move_horizontally(false, flags);
     }
 
     fn move_word_left(&mut self, flags: u64) {
@@ -625,7 +2079,62 @@ impl Editor {
     }
 
     fn move_right(&mut self, flags: u64) {
-        self.do_move(Movement::Right, flags);
+        self.move_horizontally(true, flags);
+    }
+
+    /// Moves the selection one character left or right, as displayed if
+    /// the `caret_movement` setting is visual, otherwise in the order the
+    /// text is stored.
+    fn move_horizontally(&mut self, right: bool, flags: u64) {
+        if self.doc_ctx.get_config().caret_movement != CaretMovement::Visual {
+            let movement = if right { Movement::Right } else { Movement::Left };
+            return self.do_move(movement, flags);
+        }
+        let modify = (flags & FLAG_SELECT) != 0;
+        let mut sel = Selection::new();
+        for region in self.view.sel_regions().to_vec() {
+            let offset = if region.is_caret() || modify {
+                self.visual_move_offset(region.end, right)
+            } else {
+                None
+            };
+            let new_region = match offset {
+                Some(offset) => SelRegion {
+                    start: if modify { region.start } else { offset },
+                    end: offset,
+                    horiz: None,
+                    affinity: Affinity::default(),
+                },
+                None => {
+                    let movement = if right { Movement::Right } else { Movement::Left };
+                    region_movement(movement, &region, &self.view, &self.text,
+                                    &self.segmentation, modify)
+                }
+            };
+            sel.add_region(new_region);
+        }
+        self.scroll_to = self.view.set_selection(&self.text, sel);
+    }
+
+    /// Returns the offset one character left or right of `offset` as
+    /// displayed, or `None` if its line has no right-to-left text. At the
+    /// edge of a line, the caret moves to the next or previous line.
+    fn visual_move_offset(&mut self, offset: usize, right: bool) -> Option<usize> {
+        let line = self.text.line_of_offset(offset);
+        let bidi = self.line_metadata.bidi(&self.text, line)?;
+        let line_start = self.text.offset_of_line(line);
+        let line_end = self.text.offset_of_line(line + 1);
+        // the part of the line displayed with the caret, if it's wrapped
+        let visual_line = self.view.line_of_offset(&self.text, offset);
+        let start = max(self.view.offset_of_line(&self.text, visual_line), line_start);
+        let end = min(self.view.offset_of_line(&self.text, visual_line + 1), line_end);
+        let line_text = self.text.slice_to_string(line_start, line_end);
+        match bidi::move_visually(&line_text, &bidi, start - line_start, end - line_start,
+                                  offset - line_start, right) {
+            Some(moved) => Some(line_start + moved),
+            None if right == (bidi.level % 2 == 0) => Some(end),
+            None => Some(self.text.prev_grapheme_offset(start).unwrap_or(0)),
+        }
     }
 
     fn move_word_right(&mut self, flags: u64) {
@@ -668,7 +2177,8 @@ impl Editor {
         let mut sel = Selection::new();
         for region in self.view.sel_regions() {
             sel.add_region(region.clone());
-            let new_region = region_movement(movement, region, &self.view, &self.text, false);
+            let new_region = region_movement(movement, region, &self.view, &self.text,
+                                             &self.segmentation, false);
             sel.add_region(new_region);
         }
         self.scroll_to = self.view.set_selection(&self.text, sel);
@@ -678,10 +2188,87 @@ impl Editor {
     // but paste should.
     fn do_insert(&mut self, chars: &str) {
         self.this_edit_type = EditType::InsertChars;
-        self.insert(chars);
+        match self.electric_insert_delta(chars) {
+            Some(delta) => self.add_delta(delta),
+            None => {
+                let echo_offset = self.echo_offset(chars);
+                self.insert(chars);
+                if let Some(offset) = echo_offset {
+                    self.echo_insert(offset, chars.len());
+                }
+            }
+        }
+    }
+
+    /// If inserting `chars` is eligible for the typing fast path, returns
+    /// the offset of the insertion. This is the case for a single grapheme
+    /// inserted at a single caret, leaving line breaks unchanged.
+    fn echo_offset(&self, chars: &str) -> Option<usize> {
+        let regions = self.view.sel_regions();
+        // hidden views are updated once they are shown again
+        if !self.view.is_visible() || regions.len() != 1 || !regions[0].is_caret() ||
+            chars.contains('\n') || Rope::from(chars).next_grapheme_offset(0) != Some(chars.len()) {
+            return None;
+        }
+        let offset = regions[0].end;
+        if self.view.can_echo_insert(&self.text, offset, chars.len()) {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    /// Sends the line containing an insertion to the front-end immediately,
+    /// with the existing styles shifted to account for the insertion. The
+    /// usual update follows once the edit has been fully processed.
+    fn echo_insert(&mut self, offset: usize, len: usize) {
+        let mut styles = self.styles.get_merged().clone();
+        styles.edit(Interval::new_closed_open(offset, offset), SpansBuilder::new(len).build());
+        self.update_view_revs();
+        self.view.send_echo_update(&self.text, &self.doc_ctx, &styles, &self.metrics,
+                                   offset, len);
     }
 
     pub fn do_save<P: AsRef<Path>>(&mut self, path: P) {
+        self.save(path, false);
+    }
+
+    /// Returns `true` if the buffer has a file, and unsaved changes.
+    pub fn needs_auto_save(&self) -> bool {
+        self.path.is_some() && self.scratch.is_none() && !self.is_pristine()
+    }
+
+    /// Starts or postpones the auto-save timer, if the buffer has changed
+    /// since it was last started. Returns the time at which it will expire.
+    pub fn schedule_auto_save(&mut self, now: Instant, delay: Duration) -> Option<Instant> {
+        if self.last_rev_id == self.auto_save_rev || !self.needs_auto_save() {
+            return None;
+        }
+        self.auto_save_rev = self.last_rev_id;
+        Some(self.auto_save.edited(now, delay))
+    }
+
+    /// Returns `true` if the auto-save timer has expired at `now`.
+    pub fn auto_save_due(&mut self, now: Instant) -> bool {
+        self.auto_save.take_due(now)
+    }
+
+    /// Saves the buffer to its file, if it has unsaved changes. Unlike an
+    /// explicit save, the cleanup of lines the user is editing is left for
+    /// later. Returns the active view and the path, if the buffer was saved.
+    pub fn do_auto_save(&mut self) -> Option<(ViewIdentifier, PathBuf)> {
+        if !self.needs_auto_save() {
+            return None;
+        }
+        let path = self.path.clone().unwrap();
+        self.save(&path, true);
+        Some((self.view.view_id.clone(), path))
+    }
+
+    fn save<P: AsRef<Path>>(&mut self, path: P, skip_carets: bool) {
+        self.finish_chunked_undo();
+        let config = self.doc_ctx.get_config();
+        self.apply_save_cleanup(&config, skip_carets);
         match File::create(&path) {
             Ok(mut f) => {
                 for chunk in self.text.iter_chunks(0, self.text.len()) {
@@ -693,18 +2280,115 @@ impl Editor {
             }
             Err(e) => print_err!("create error {}", e),
         }
+        self.file_info = FileInfo::read(path.as_ref());
 
         self.pristine_rev_id = self.last_rev_id;
+        self.auto_save_rev = self.last_rev_id;
+        self.auto_save.cancel();
         self.view.set_pristine();
-        self.view.set_dirty();
+        for view in self.views.values_mut() {
+            view.set_pristine();
+        }
+        self.set_dirty();
         self.render();
     }
 
+    /// Returns the regions of trailing whitespace in `lines` which will be
+    /// removed on save. If `skip_carets` is set, lines containing a caret
+    /// are excluded, so that whitespace being typed isn't flagged.
+    fn trailing_whitespace_regions(&mut self, lines: (usize, usize),
+                                   skip_carets: bool) -> Vec<Interval> {
+        let n_lines = self.text.measure::<LinesMetric>() + 1;
+        let mut regions = Vec::new();
+        for line in lines.0..min(lines.1, n_lines) {
+            let n_trailing = self.line_metadata.trailing_whitespace(&self.text, line);
+            if n_trailing == 0 {
+                continue;
+            }
+            let start = self.text.offset_of_line(line);
+            let end = start + metrics::line_len(&self.text, line);
+            if skip_carets && self.view.sel_regions().iter()
+                .any(|r| r.end >= start && r.end <= end) {
+                continue;
+            }
+            regions.push(Interval::new_closed_open(end - n_trailing, end));
+        }
+        regions
+    }
+
+    /// Returns `true` if a newline will be added to the buffer on save.
+    fn needs_final_newline(&self, config: &Config) -> bool {
+        config.final_newline_on_save && self.text.len() > 0 &&
+            self.text.byte_at(self.text.len() - 1) != b'\n'
+    }
+
+    /// Returns the number of cleanups (lines with trailing whitespace, and
+    /// a missing final newline) which will be made on save.
+    fn count_pending_cleanup(&mut self) -> usize {
+        let config = self.doc_ctx.get_config();
+        let mut count = 0;
+        if config.trim_whitespace_on_save {
+            let n_lines = self.text.measure::<LinesMetric>() + 1;
+            count += self.trailing_whitespace_regions((0, n_lines), false).len();
+        }
+        if self.needs_final_newline(&config) {
+            count += 1;
+        }
+        count
+    }
+
+    /// Updates the visible pending cleanup annotations, if they are enabled.
+    fn update_pending_cleanup(&mut self) {
+        let config = self.doc_ctx.get_config();
+        let mut regions = Selection::new();
+        if config.show_pending_cleanup && config.trim_whitespace_on_save {
+            let lines = self.view.visible_lines(&self.text);
+            for iv in self.trailing_whitespace_regions(lines, true) {
+                regions.add_region(SelRegion {
+                    start: iv.start(),
+                    end: iv.end(),
+                    horiz: None,
+                    affinity: Affinity::default(),
+                });
+            }
+        }
+        self.view.set_pending_cleanup(regions);
+    }
+
+    /// Before saving, removes trailing whitespace and adds a final newline,
+    /// as requested by the user's config. If `skip_carets` is set, lines
+    /// containing a caret are left alone.
+    fn apply_save_cleanup(&mut self, config: &Config, skip_carets: bool) {
+        let mut builder = delta::Builder::new(self.text.len());
+        if config.trim_whitespace_on_save {
+            let n_lines = self.text.measure::<LinesMetric>() + 1;
+            for iv in self.trailing_whitespace_regions((0, n_lines), skip_carets) {
+                builder.delete(iv);
+            }
+        }
+        let caret_at_end = self.view.sel_regions().iter().any(|r| r.end == self.text.len());
+        if self.needs_final_newline(config) && !(skip_carets && caret_at_end) {
+            let end = self.text.len();
+            builder.replace(Interval::new_closed_open(end, end), Rope::from("\n"));
+        }
+        if !builder.is_empty() {
+            self.this_edit_type = EditType::Other;
+            self.add_delta(builder.build());
+            self.commit_delta(None);
+        }
+    }
+
     fn do_scroll(&mut self, first: i64, last: i64) {
         let first = max(first, 0) as usize;
         let last = last as usize;
         self.view.set_scroll(first, last);
-        self.view.send_update_for_scroll(&self.text, &self.doc_ctx, self.styles.get_merged(), first, last);
+        self.request_evicted_spans();
+        self.enforce_span_budget();
+        self.update_pending_cleanup();
+        self.update_view_revs();
+        self.view.send_update_for_scroll(&self.text, &self.doc_ctx, self.styles.get_merged(),
+                                         &self.segmentation, &self.metrics,
+                                         &mut self.line_metadata, first, last);
     }
 
     /// Sets the cursor and scrolls to the beginning of the given line.
@@ -714,38 +2398,54 @@ impl Editor {
     }
 
     fn do_request_lines(&mut self, first: i64, last: i64) {
-        self.view.send_update(&self.text, &self.doc_ctx, self.styles.get_merged(), first as usize, last as usize);
+        self.update_view_revs();
+        self.view.send_update(&self.text, &self.doc_ctx, self.styles.get_merged(),
+                              &self.segmentation, &self.metrics, &mut self.line_metadata,
+                              first as usize, last as usize);
+    }
+
+    /// Returns the offset of a gesture at `line` and `col` of the lines of
+    /// revision `rev`, as the front-end had them, moved through the edits
+    /// made since. If the lines of `rev` are no longer known, the
+    /// front-end is told the gesture was stale, and `None` is returned.
+    fn gesture_offset(&self, line: u64, col: u64, rev: Option<RevToken>) -> Option<usize> {
+        let (line, col) = (line as usize, col as usize);
+        let head = self.engine.get_head_rev_id().token();
+        let rev = match rev {
+            Some(rev) if rev != head => rev,
+            _ => return Some(self.view.line_col_to_offset(&self.text, line, col)),
+        };
+        // the wrapping of older revisions isn't kept
+        let old_text = if self.view.is_wrapped() { None } else { self.engine.get_rev(rev) };
+        match old_text {
+            Some(old_text) => {
+                let offset = self.view.line_col_to_offset(&old_text, line, col);
+                let delta = self.engine.delta_rev_head(rev);
+                Some(Transformer::new(&delta).transform(offset, true))
+            }
+            None => {
+                self.doc_ctx.stale_gesture(&self.view.view_id, head);
+                None
+            }
+        }
     }
 
-    fn do_click(&mut self, line: u64, col: u64, flags: u64, click_count: u64) {
+    fn do_click(&mut self, line: u64, col: u64, flags: u64, click_count: u64,
+                rev: Option<RevToken>) {
         // TODO: calculate affinity
-        let offset = self.view.line_col_to_offset(&self.text, line as usize, col as usize);
+        let offset = match self.gesture_offset(line, col, rev) {
+            Some(offset) => offset,
+            None => return,
+        };
+        // a triple click selects the line the click is on now
+        let line = self.view.line_of_offset(&self.text, offset);
         if (flags & FLAG_SELECT) != 0 {
-            if !self.view.is_point_in_selection(offset) {
-                let sel = {
-                    let (last, rest) = self.view.sel_regions().split_last().unwrap();
-                    let mut sel = Selection::new();
-                    for region in rest {
-                        sel.add_region(region.clone());
-                    }
-                    // TODO: small nit, merged region should be backward if end < start.
-                    // This could be done by explicitly overriding, or by tweaking the
-                    // merge logic.
-                    sel.add_region(SelRegion {
-                        start: last.start,
-                        end: offset,
-                        horiz: None,
-                        affinity: Affinity::default(),
-                    });
-                    sel
-                };
-                self.view.start_drag(offset, offset, offset);
-                self.scroll_to = self.view.set_selection(&self.text, sel);
-                return;
-            }
+            self.scroll_to = self.view.select_extend(&self.text, &self.segmentation, offset);
+            return;
         } else if click_count == 2 {
             let (start, end) = {
-                let mut word_cursor = WordCursor::new(&self.text, offset);
+                let mut word_cursor = WordCursor::with_segmentation(&self.text, offset,
+                                                                    &self.segmentation);
                 word_cursor.select_word()
             };
             self.set_sel_single_region(SelRegion{
@@ -754,33 +2454,49 @@ impl Editor {
                 horiz: None,
                 affinity: Affinity::default(),
             });
-            self.view.start_drag(offset, start, end);
+            self.view.start_drag(offset, start, end, Granularity::Word);
             return;
         } else if click_count == 3 {
-            let start = self.view.line_col_to_offset(&self.text, line as usize, 0);
-            let end = self.view.line_col_to_offset(&self.text, line as usize + 1, 0);
+            let start = self.view.line_col_to_offset(&self.text, line, 0);
+            let end = self.view.line_col_to_offset(&self.text, line + 1, 0);
             self.set_sel_single_region(SelRegion{
                 start: start,
                 end: end,
                 horiz: None,
                 affinity: Affinity::default(),
             });
-            self.view.start_drag(offset, start, end);
+            self.view.start_drag(offset, start, end, Granularity::Line);
             return;
         }
-        self.view.start_drag(offset, offset, offset);
         self.set_cursor(offset);
+        self.view.start_drag(offset, offset, offset, Granularity::Point);
     }
 
-    fn do_drag(&mut self, line: u64, col: u64, _flags: u64) {
-        let offset = self.view.line_col_to_offset(&self.text, line as usize, col as usize);
-        self.scroll_to = self.view.do_drag(&self.text, offset, Affinity::default());
+    fn do_drag(&mut self, line: u64, col: u64, _flags: u64, rev: Option<RevToken>) {
+        let offset = match self.gesture_offset(line, col, rev) {
+            Some(offset) => offset,
+            None => return,
+        };
+        self.scroll_to = self.view.do_drag(&self.text, &self.segmentation, offset,
+                                           Affinity::default());
     }
 
-    fn do_gesture(&mut self, line: u64, col: u64, ty: GestureType) {
-        let offset = self.view.line_col_to_offset(&self.text, line as usize, col as usize);
+    fn do_gesture(&mut self, line: u64, col: u64, ty: GestureType, rev: Option<RevToken>) {
+        let offset = match self.gesture_offset(line, col, rev) {
+            Some(offset) => offset,
+            None => return,
+        };
         match ty {
-            GestureType::ToggleSel => self.view.toggle_sel(offset),
+            GestureType::SelectExtend => {
+                self.scroll_to = self.view.select_extend(&self.text, &self.segmentation,
+                                                         offset);
+            }
+            GestureType::SelectAddRegion => {
+                self.scroll_to = self.view.select_add_region(&self.text, offset);
+            }
+            GestureType::SelectToggleRegion | GestureType::ToggleSel => {
+                self.view.toggle_sel(offset)
+            }
         }
     }
 
@@ -796,33 +2512,278 @@ impl Editor {
         self.styles.debug_print_spans(iv);
     }
 
-    fn do_cut(&mut self) -> Value {
-        let result = self.do_copy();
-        // This copy is just to make the borrow checker happy, could be optimized.
+    /// Returns the spans of each layer within `start..end`, or the last
+    /// selection region, or the character after the caret.
+    fn debug_styles(&self, start: Option<usize>, end: Option<usize>) -> Value {
+        let (sel_start, sel_end) = {
+            let last_sel = self.view.sel_regions().last().unwrap();
+            (last_sel.min(), last_sel.max())
+        };
+        let len = self.text.len();
+        let start = min(start.unwrap_or(sel_start), len);
+        let end = match end {
+            Some(end) => min(end, len),
+            None if sel_start == sel_end => self.text.next_grapheme_offset(start).unwrap_or(len),
+            None => sel_end,
+        };
+        let end = max(start, end);
+        let layers = self.styles.layer_spans(Interval::new_closed_open(start, end));
+        json!({
+            "start": start,
+            "end": end,
+            "layers": layers,
+        })
+    }
+
+    /// Renders the buffer as HTML, with the theme's colors.
+    fn export_html(&mut self) -> String {
+        let lines = self.view_lines(0, usize::max_value());
+        let styles = self.styles.merged_by_line(&lines);
+        let theme = self.doc_ctx.get_style_map().lock().unwrap().get_theme();
+        html::render_html(&self.text, &lines, &styles,
+                          Style::default_for_theme(&theme).fg_color,
+                          Style::background_color_for_theme(&theme))
+    }
+
+    fn debug_get_stats(&mut self) -> Value {
+        let layers = self.styles.all_layer_stats().into_iter().map(|(plugin, stats)| {
+            let mut stats = serde_json::to_value(stats).unwrap();
+            stats["plugin"] = json!(plugin);
+            stats
+        }).collect::<Vec<_>>();
+        json!({
+            "layers": layers,
+            "styles": self.styles.stats(),
+            "line_metadata": self.line_metadata.stats(),
+            "pending_cleanup": self.count_pending_cleanup(),
+            "theme_recomputes": self.theme_recomputes,
+            "revisions": self.engine.revision_count(),
+        })
+    }
+
+    fn do_cut(&mut self) -> Value {
+        let result = self.do_copy();
+        // This copy is just to make the borrow checker happy, could be optimized.
         let deletions = self.view.sel_regions().to_vec();
         self.delete_sel_regions(&deletions);
         result
     }
 
     fn do_copy(&self) -> Value {
-        if let Some(val) = self.extract_sel_regions(self.view.sel_regions()) {
-            Value::String(val)
-        } else {
-            Value::Null
+        let text = match self.extract_sel_regions(self.view.sel_regions()) {
+            Some(text) => text,
+            None => return Value::Null,
+        };
+        match self.sel_block() {
+            Some(ref block) if self.doc_ctx.get_capabilities().block_clipboard => {
+                json!({"text": text, "block": block})
+            }
+            _ => Value::String(text),
+        }
+    }
+
+    /// Returns the selection as a block, if it has a region within each of
+    /// two or more adjacent lines.
+    fn sel_block(&self) -> Option<BlockClipboard> {
+        let regions = self.view.sel_regions();
+        if regions.len() < 2 || regions.iter().all(|region| region.is_caret()) {
+            return None;
+        }
+        let style = self.doc_ctx.get_config().indent_style();
+        let first_line = self.text.line_of_offset(regions[0].min());
+        let mut column = usize::max_value();
+        let mut segments = Vec::with_capacity(regions.len());
+        for (i, region) in regions.iter().enumerate() {
+            let line = self.text.line_of_offset(region.min());
+            if line != first_line + i || self.text.line_of_offset(region.max()) != line {
+                return None;
+            }
+            let prefix = self.text.slice_to_string(self.text.offset_of_line(line), region.min());
+            column = min(column, style.width(&prefix));
+            segments.push(self.text.slice_to_string(region.min(), region.max()));
+        }
+        Some(BlockClipboard { column: column, segments: segments })
+    }
+
+    /// Pastes `chars`, or the segments of `block` if the selection is a
+    /// single caret, or has a region for each of them.
+    /// Pastes `chars`, or `block`, sanitized according to the config.
+    /// Whatever the config, the user is warned of bidi control characters.
+    fn do_paste(&mut self, chars: &str, block: Option<&BlockClipboard>) {
+        let config = self.doc_ctx.get_config();
+        let (clean, found) = sanitize::sanitize(chars, config.paste_nbsp_to_space);
+        let warning = match config.paste_sanitize {
+            PasteSanitize::Off => {
+                let found = found.bidi_only();
+                found.describe().map(|text| (found, text))
+            }
+            PasteSanitize::Warn => found.describe().map(|text| (found, text)),
+            PasteSanitize::Strip => {
+                let block = block.map(|block| sanitize_block(block, config.paste_nbsp_to_space));
+                return self.paste(&clean, block.as_ref());
+            }
+            PasteSanitize::Ask if !found.is_empty() => {
+                let text = format!("{} Strip them before pasting?", found.describe().unwrap());
+                let msg = Message::new(ids::paste_strip)
+                    .param("found", serde_json::to_value(found).unwrap())
+                    .with_fallback_text(text);
+                self.doc_ctx.alert_with_actions(&self.view.view_id, &msg,
+                                                &["strip", "verbatim", "cancel"]);
+                self.pending_paste = Some((chars.to_owned(), block.cloned()));
+                return;
+            }
+            PasteSanitize::Ask => None,
+        };
+        if let Some((found, text)) = warning {
+            self.doc_ctx.alert(&Message::new(ids::paste_suspicious)
+                               .param("found", serde_json::to_value(found).unwrap())
+                               .with_fallback_text(text));
+        }
+        self.paste(chars, block)
+    }
+
+    /// Completes the paste the user was asked about, if any.
+    fn do_resolve_paste(&mut self, action: PasteAction) {
+        let (chars, block) = match self.pending_paste.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        match action {
+            PasteAction::Strip => {
+                let nbsp_to_space = self.doc_ctx.get_config().paste_nbsp_to_space;
+                let clean = sanitize::sanitize(&chars, nbsp_to_space).0.into_owned();
+                let block = block.map(|block| sanitize_block(&block, nbsp_to_space));
+                self.paste(&clean, block.as_ref());
+            }
+            PasteAction::Verbatim => self.paste(&chars, block.as_ref()),
+            PasteAction::Cancel => (),
         }
     }
 
+    fn paste(&mut self, chars: &str, block: Option<&BlockClipboard>) {
+        self.this_edit_type = EditType::Other;
+        let regions = self.view.sel_regions().to_vec();
+        let bulk = is_bulk(1, regions.len() * chars.len());
+        self.bulk_edit_if(bulk, |ed| match block {
+            Some(block) if regions.len() == block.segments.len() => {
+                ed.paste_segments(&regions, &block.segments)
+            }
+            Some(block) if regions.len() == 1 && regions[0].is_caret() => {
+                ed.paste_block(regions[0].end, &block.segments)
+            }
+            _ => ed.insert(chars),
+        })
+    }
+
+    /// Replaces each of `regions` with the corresponding segment.
+    fn paste_segments(&mut self, regions: &[SelRegion], segments: &[String]) {
+        let mut builder = delta::Builder::new(self.text.len());
+        let mut sel = Selection::new();
+        let (mut removed, mut inserted) = (0, 0);
+        for (region, segment) in regions.iter().zip(segments) {
+            builder.replace(Interval::new_closed_open(region.min(), region.max()),
+                            Rope::from(segment.as_str()));
+            let end = region.min() - removed + inserted + segment.len();
+            sel.add_region(SelRegion { start: end, end: end, horiz: None,
+                                       affinity: Affinity::default() });
+            removed += region.max() - region.min();
+            inserted += segment.len();
+        }
+        self.add_delta(builder.build());
+        self.commit_delta(None);
+        self.scroll_to = self.view.set_selection(&self.text, sel);
+    }
+
+    /// Inserts `segments` on successive lines at the column of `caret`,
+    /// padding short lines with spaces, and adding lines at the end of the
+    /// buffer as needed. A segment at a column within a tab is inserted,
+    /// with padding, before the tab.
+    fn paste_block(&mut self, caret: usize, segments: &[String]) {
+        let style = self.doc_ctx.get_config().indent_style();
+        let first_line = self.text.line_of_offset(caret);
+        let prefix = self.text.slice_to_string(self.text.offset_of_line(first_line), caret);
+        let column = style.width(&prefix);
+        let n_lines = self.text.line_of_offset(self.text.len()) + 1;
+
+        let mut inserts: Vec<(usize, String)> = Vec::new();
+        for (i, segment) in segments.iter().enumerate() {
+            let line = first_line + i;
+            let (offset, mut text) = if line < n_lines {
+                let (offset, col) = self.offset_of_column(line, column, style);
+                (offset, " ".repeat(column - col))
+            } else {
+                (self.text.len(), format!("\n{}", " ".repeat(column)))
+            };
+            text.push_str(segment);
+            // lines added at the end are all inserted at its offset
+            if inserts.last().map(|&(last, _)| last) == Some(offset) {
+                inserts.last_mut().unwrap().1.push_str(&text);
+            } else {
+                inserts.push((offset, text));
+            }
+        }
+
+        let mut builder = delta::Builder::new(self.text.len());
+        let mut end = caret;
+        let mut inserted = 0;
+        for (offset, text) in inserts {
+            inserted += text.len();
+            end = offset + inserted;
+            builder.replace(Interval::new_closed_open(offset, offset), Rope::from(text));
+        }
+        self.add_delta(builder.build());
+        self.commit_delta(None);
+        self.set_cursor(end);
+    }
+
+    /// Returns the offset of visual `column` in `line`, or of the end of the
+    /// line or the character spanning the column, and the column of that
+    /// offset.
+    fn offset_of_column(&self, line: usize, column: usize, style: IndentStyle)
+                        -> (usize, usize) {
+        let start = self.text.offset_of_line(line);
+        let text = self.text.lines_raw(start, self.text.len()).next().unwrap_or_default();
+        let (mut offset, mut col) = (start, 0);
+        for c in text.trim_end_matches(|c| c == '\n' || c == '\r').chars() {
+            let next = style.advance(col, c);
+            if next > column {
+                break;
+            }
+            offset += c.len_utf8();
+            col = next;
+        }
+        (offset, col)
+    }
+
     fn do_undo(&mut self) {
         if self.cur_undo > 0 {
             self.cur_undo -= 1;
             assert!(self.undos.insert(self.live_undos[self.cur_undo]));
             self.this_edit_type = EditType::Undo;
             self.update_undos();
+        } else if self.undo_spilled_group() {
+            self.this_edit_type = EditType::Undo;
+            self.update_after_undo();
         }
     }
 
     fn do_redo(&mut self) {
-        if self.cur_undo < self.live_undos.len() {
+        if let Some((redo, is_spilled)) = self.spilled_redos.pop() {
+            if redo.base_len == self.text.len() {
+                let undo = redo.inverse(&self.text);
+                self.make_spilled_edit(redo);
+                let result = match self.undo_spill.as_mut() {
+                    Some(ref mut spill) if is_spilled => Ok(spill.restore()),
+                    Some(spill) => spill.push(undo),
+                    None => Ok(()),
+                };
+                if let Err(error) = result {
+                    self.lose_spilled_history(error);
+                }
+                self.this_edit_type = EditType::Redo;
+                self.update_after_undo();
+            }
+        } else if self.cur_undo < self.live_undos.len() {
             assert!(self.undos.remove(&self.live_undos[self.cur_undo]));
             self.cur_undo += 1;
             self.this_edit_type = EditType::Redo;
@@ -857,6 +2818,270 @@ impl Editor {
         }
     }
 
+    fn do_surround(&mut self, open: &str, close: Option<&str>) {
+        let delims = match Delimiters::new(open, close) {
+            Ok(delims) => delims,
+            Err(reason) => return self.doc_ctx.alert(&Message::new(ids::surround_failed)
+                                                     .param("reason", reason)),
+        };
+        let mut ranges: Vec<(usize, usize, bool)> = Vec::new();
+        for region in self.view.sel_regions() {
+            let (start, end) = if region.is_caret() {
+                let mut word_cursor = WordCursor::with_segmentation(&self.text, region.end,
+                                                                    &self.segmentation);
+                word_cursor.select_word()
+            } else {
+                (region.min(), region.max())
+            };
+            // carets extended to words can overlap other regions
+            if ranges.last().map(|&(_, last_end, _)| start < last_end).unwrap_or(false) {
+                continue;
+            }
+            ranges.push((start, end, region.start > region.end));
+        }
+        if ranges.is_empty() {
+            return;
+        }
+
+        let mut builder = delta::Builder::new(self.text.len());
+        let mut sel = Selection::new();
+        let mut shift = 0;
+        for (start, end, reversed) in ranges {
+            builder.replace(Interval::new_closed_open(start, start),
+                            Rope::from(delims.open.as_str()));
+            builder.replace(Interval::new_closed_open(end, end),
+                            Rope::from(delims.close.as_str()));
+            let new_start = start + shift + delims.open.len();
+            let new_end = end + shift + delims.open.len();
+            let (start, end) = if reversed { (new_end, new_start) } else { (new_start, new_end) };
+            sel.add_region(SelRegion { start: start, end: end, horiz: None,
+                                       affinity: Affinity::default() });
+            shift += delims.open.len() + delims.close.len();
+        }
+        self.add_delta(builder.build());
+        self.commit_delta(None);
+        self.scroll_to = self.view.set_selection(&self.text, sel);
+    }
+
+    fn do_unsurround(&mut self) {
+        let edits = self.enclosing_delimiter_edits(None, |_| String::new());
+        self.apply_delimiter_edits(edits);
+    }
+
+    fn do_change_surround(&mut self, from: &str, to: &str) {
+        let delims = Delimiters::new(from, None).and_then(|from| {
+            Delimiters::new(to, None).map(|to| (from, to))
+        });
+        let (from, to) = match delims {
+            Ok(delims) => delims,
+            Err(reason) => return self.doc_ctx.alert(&Message::new(ids::change_surround_failed)
+                                                     .param("reason", reason)),
+        };
+        let edits = self.enclosing_delimiter_edits(Some(&from), |is_open| if is_open {
+            to.open.clone()
+        } else {
+            to.close.clone()
+        });
+        self.apply_delimiter_edits(edits);
+    }
+
+    /// Finds the pairs of delimiters enclosing the selection regions, and
+    /// returns the edits replacing each delimiter with `replacement(is_open)`,
+    /// sorted and without overlaps.
+    fn enclosing_delimiter_edits<F>(&self, only: Option<&Delimiters>, replacement: F)
+                                    -> Vec<(Interval, String)>
+        where F: Fn(bool) -> String
+    {
+        let mut pairs = self.view.sel_regions().iter()
+            .filter_map(|region| surround::find_enclosing(&self.text, region.min(),
+                                                          region.max(), only))
+            .collect::<Vec<_>>();
+        pairs.sort_by_key(|&(open, close)| (open.start(), close.start()));
+        pairs.dedup();
+
+        let mut edits = Vec::new();
+        for (open, close) in pairs {
+            edits.push((open, replacement(true)));
+            edits.push((close, replacement(false)));
+        }
+        edits.sort_by_key(|&(iv, _)| iv.start());
+        let mut last = 0;
+        edits.retain(|&(iv, _)| {
+            let keep = iv.start() >= last;
+            if keep {
+                last = iv.end();
+            }
+            keep
+        });
+        edits
+    }
+
+    /// Applies edits to the delimiters around the selection regions, as a
+    /// single undo group, keeping the text between them selected.
+    fn apply_delimiter_edits(&mut self, edits: Vec<(Interval, String)>) {
+        if edits.is_empty() {
+            return;
+        }
+        let map_offset = |offset: usize| {
+            let mut shift = 0isize;
+            for &(iv, ref text) in edits.iter() {
+                if iv.start() < offset && offset < iv.end() {
+                    return (iv.start() as isize + shift) as usize;
+                }
+                if iv.start() < offset && iv.end() <= offset {
+                    shift += text.len() as isize - iv.size() as isize;
+                }
+            }
+            (offset as isize + shift) as usize
+        };
+        let mut sel = Selection::new();
+        for region in self.view.sel_regions() {
+            sel.add_region(SelRegion {
+                start: map_offset(region.start),
+                end: map_offset(region.end),
+                horiz: None,
+                affinity: region.affinity,
+            });
+        }
+
+        let mut builder = delta::Builder::new(self.text.len());
+        for (iv, text) in edits.into_iter() {
+            builder.replace(iv, Rope::from(text));
+        }
+        self.add_delta(builder.build());
+        self.commit_delta(None);
+        self.scroll_to = self.view.set_selection(&self.text, sel);
+    }
+
+    /// Matches the brackets around the selection, skipping strings and
+    /// comments, or alerts if they are unbalanced.
+    fn bracket_pairs(&self) -> Option<BracketPairs> {
+        let (start, end) = {
+            let regions = self.view.sel_regions();
+            match (regions.first(), regions.last()) {
+                (Some(first), Some(last)) => (first.min(), last.max()),
+                _ => return None,
+            }
+        };
+        let literals = if self.styles.has_scopes() {
+            let window = brackets::scan_window(&self.text, start, end);
+            Some(self.styles.intervals_with_scope(window, &["string", "comment"]))
+        } else {
+            None
+        };
+        match BracketPairs::new(&self.text, start, end, literals.as_ref().map(|l| l.as_slice())) {
+            Ok(pairs) => Some(pairs),
+            Err(reason) => {
+                self.doc_ctx.alert(&Message::new(ids::brackets_unmatched).param("reason", reason));
+                None
+            }
+        }
+    }
+
+    /// Moves each caret to the start of the innermost bracketed group
+    /// enclosing it, or else past its end.
+    fn do_move_to_enclosing_bracket(&mut self, to_end: bool) {
+        let pairs = match self.bracket_pairs() {
+            Some(pairs) => pairs,
+            None => return,
+        };
+        let mut sel = Selection::new();
+        for region in self.view.sel_regions() {
+            let offset = match pairs.enclosing(region.min(), region.max()) {
+                Some((_, close)) if to_end => close + 1,
+                Some((open, _)) => open,
+                None => region.end,
+            };
+            sel.add_region(SelRegion { start: offset, end: offset, horiz: None,
+                                       affinity: Affinity::default() });
+        }
+        self.scroll_to = self.view.set_selection(&self.text, sel);
+    }
+
+    /// Selects the innermost bracketed group enclosing each region, or the
+    /// next one out if the region already is that group.
+    fn do_select_enclosing_brackets(&mut self, include_delimiters: bool) {
+        let pairs = match self.bracket_pairs() {
+            Some(pairs) => pairs,
+            None => return,
+        };
+        let range = |(open, close): (usize, usize)| if include_delimiters {
+            (open, close + 1)
+        } else {
+            (open + 1, close)
+        };
+        let mut sel = Selection::new();
+        for region in self.view.sel_regions() {
+            let (min, max) = (region.min(), region.max());
+            let pair = match pairs.enclosing(min, max) {
+                Some(pair) if range(pair) == (min, max) => pairs.enclosing(pair.0, pair.1 + 1),
+                pair => pair,
+            };
+            let (start, end) = pair.map(&range).unwrap_or((region.start, region.end));
+            sel.add_region(SelRegion { start: start, end: end, horiz: None,
+                                       affinity: Affinity::default() });
+        }
+        self.scroll_to = self.view.set_selection(&self.text, sel);
+    }
+
+    /// Selects each span, in any layer, whose scopes match `selector`, such
+    /// as all the strings of the buffer. If none do, the selection is kept.
+    fn do_select_scope(&mut self, selector: &str) {
+        let iv = Interval::new_closed_open(0, self.text.len());
+        let spans = match self.styles.find_by_selector(selector, iv) {
+            Ok(spans) => spans,
+            Err(msg) => {
+                print_err!("select_scope: {}", msg);
+                return;
+            }
+        };
+        if spans.is_empty() {
+            return;
+        }
+        let mut sel = Selection::new();
+        for (iv, _) in spans {
+            sel.add_region(SelRegion { start: iv.start(), end: iv.end(), horiz: None,
+                                       affinity: Affinity::default() });
+        }
+        self.scroll_to = self.view.set_selection(&self.text, sel);
+    }
+
+    /// Swaps the bracketed groups before and after each caret, leaving the
+    /// caret after both.
+    fn do_transpose_bracket_groups(&mut self) {
+        let pairs = match self.bracket_pairs() {
+            Some(pairs) => pairs,
+            None => return,
+        };
+        let mut builder = delta::Builder::new(self.text.len());
+        let mut sel = Selection::new();
+        let mut last = 0;
+        for region in self.view.sel_regions() {
+            let groups = pairs.group_before(region.end)
+                .and_then(|before| pairs.group_after(region.end).map(|after| (before, after)));
+            match groups {
+                Some((before, after)) if before.0 >= last => {
+                    let before = Interval::new_closed_open(before.0, before.1 + 1);
+                    let after = Interval::new_closed_open(after.0, after.1 + 1);
+                    builder.replace(before, self.text.subseq(after));
+                    builder.replace(after, self.text.subseq(before));
+                    // the swap doesn't change the length of the text
+                    last = after.end();
+                    sel.add_region(SelRegion { start: last, end: last, horiz: None,
+                                               affinity: Affinity::default() });
+                }
+                _ => sel.add_region(region.clone()),
+            }
+        }
+        if builder.is_empty() {
+            return;
+        }
+        self.this_edit_type = EditType::Other;
+        self.add_delta(builder.build());
+        self.commit_delta(None);
+        self.scroll_to = self.view.set_selection(&self.text, sel);
+    }
+
     fn delete_to_end_of_paragraph(&mut self) {
         self.delete_by_movement(Movement::EndOfParagraphKill, true);
     }
@@ -869,7 +3094,8 @@ impl Editor {
         self.insert(&*String::from(kill_ring_string));
     }
 
-    pub fn do_find(&mut self, chars: Option<String>, case_sensitive: bool) -> Value {
+    pub fn do_find(&mut self, chars: Option<String>, case_sensitive: bool, whole_words: bool)
+                   -> Value {
         let mut from_sel = false;
         let search_string = if chars.is_some() {
             chars
@@ -895,17 +3121,295 @@ impl Editor {
             return Value::Null;
         }
 
-        self.view.set_find(&search_string, case_sensitive);
+        self.view.set_find(&search_string, case_sensitive, whole_words);
 
         Value::String(search_string.to_string())
     }
 
+    /// Finds the replacements of `query`, returning up to `max_results` of
+    /// them, and a token with which `replace_all` applies all of them.
+    fn do_preview_replace(&mut self, query: &str, replacement: &str, options: &ReplaceOptions,
+                          max_results: Option<usize>) -> Value {
+        let replacements = replace::find_replacements(&self.text, query, replacement, options);
+        let max_results = max_results.unwrap_or(replacements.len());
+        let matches = replacements.iter().take(max_results).map(|r| {
+            let line = self.text.line_of_offset(r.iv.start());
+            let line_start = self.text.offset_of_line(line);
+            let line_end = self.text.offset_of_line(line + 1);
+            let context_line = self.text.slice_to_string(line_start, line_end);
+            json!({
+                "line": line,
+                "col": r.iv.start() - line_start,
+                "original": self.text.slice_to_string(r.iv.start(), r.iv.end()),
+                "replacement": r.text,
+                "context_line": context_line.trim_end_matches(|c| c == '\n' || c == '\r'),
+            })
+        }).collect::<Vec<_>>();
+
+        let token = self.next_replace_token;
+        self.next_replace_token += 1;
+        let total = replacements.len();
+        self.replace_preview = Some(ReplacePreview {
+            token: token,
+            rev: self.engine.get_head_rev_id(),
+            replacements: replacements,
+        });
+        json!({
+            "token": token,
+            "total": total,
+            "matches": matches,
+        })
+    }
+
+    /// Replaces every occurrence of `query`, or, given the `token` of a
+    /// preview, exactly the replacements previewed.
+    fn do_replace_all(&mut self, token: Option<u64>, query: Option<String>,
+                      replacement: Option<String>, options: ReplaceOptions)
+                      -> Result<Value, RemoteError> {
+        let replacements = match (token, query) {
+            (Some(token), _) => {
+                let stale = match self.replace_preview {
+                    Some(ref preview) if preview.token == token =>
+                        preview.rev != self.engine.get_head_rev_id(),
+                    _ => return Err(Message::new(ids::replace_token_unknown)
+                                    .param("token", token).into_error(3, None)),
+                };
+                if stale {
+                    return Err(Message::new(ids::replace_preview_stale).into_error(4, None));
+                }
+                self.replace_preview.take().unwrap().replacements
+            }
+            (None, Some(query)) => {
+                let replacement = replacement.unwrap_or_default();
+                replace::find_replacements(&self.text, &query, &replacement, &options)
+            }
+            (None, None) => {
+                return Err(Message::new(ids::replace_needs_query).into_error(3, None));
+            }
+        };
+
+        if !replacements.is_empty() {
+            let delta = replace::replacement_delta(&self.text, &replacements);
+            let (iv, new_len) = delta.summary();
+            self.this_edit_type = EditType::Other;
+            self.bulk_edit_if(is_bulk(1, iv.size() + new_len), |ed| ed.add_delta(delta));
+        }
+        Ok(json!({ "replaced": replacements.len() }))
+    }
+
+    /// Replaces the text of each selected region with the value of `expr`
+    /// for it. Regions `expr` fails on are left as they are, or, if
+    /// `on_error` is `Abort`, every region is.
+    fn transform_selection_with_expr(&mut self, expr: &str, on_error: OnError)
+                                     -> Result<Value, RemoteError> {
+        let expr = Expr::parse(expr).map_err(|err| {
+            Message::new(ids::expr_invalid)
+                .param("position", err.position)
+                .param("reason", err.reason)
+                .into_error(3, Some(json!({"position": err.position})))
+        })?;
+        let mut new_texts = Vec::new();
+        let mut errors = Vec::new();
+        for (i, region) in self.view.sel_regions().iter().enumerate() {
+            let text = self.text.slice_to_string(region.min(), region.max());
+            match expr.eval(&text, i) {
+                Ok(new_text) => new_texts.push(Some(new_text)),
+                Err(reason) => {
+                    errors.push(json!({"region": i, "error": reason}));
+                    new_texts.push(None);
+                }
+            }
+        }
+        if !errors.is_empty() && on_error == OnError::Abort {
+            return Ok(json!({"transformed": 0, "errors": errors}));
+        }
+
+        let mut builder = delta::Builder::new(self.text.len());
+        let mut sel = Selection::new();
+        let mut shift = 0isize;
+        let mut size = 0;
+        for (region, new_text) in self.view.sel_regions().iter().zip(new_texts.iter()) {
+            let (start, end) = (region.min(), region.max());
+            let new_start = (start as isize + shift) as usize;
+            let new_len = match *new_text {
+                Some(ref new_text) => {
+                    builder.replace(Interval::new_closed_open(start, end),
+                                    Rope::from(new_text.as_str()));
+                    size += end - start + new_text.len();
+                    new_text.len()
+                }
+                None => end - start,
+            };
+            shift += new_len as isize - (end - start) as isize;
+            let (start, end) = if region.start > region.end {
+                (new_start + new_len, new_start)
+            } else {
+                (new_start, new_start + new_len)
+            };
+            sel.add_region(SelRegion { start: start, end: end, horiz: None,
+                                       affinity: Affinity::default() });
+        }
+        let transformed = new_texts.iter().filter(|text| text.is_some()).count();
+        if transformed > 0 {
+            self.this_edit_type = EditType::Other;
+            let delta = builder.build();
+            self.bulk_edit_if(is_bulk(transformed, size), |ed| {
+                ed.add_delta(delta);
+                ed.commit_delta(None);
+            });
+            self.scroll_to = self.view.set_selection(&self.text, sel);
+        }
+        Ok(json!({"transformed": transformed, "errors": errors}))
+    }
+
+    /// Returns `true` if `cmd` moves the caret to a position which the
+    /// user may want to jump back from.
+    fn is_jump(cmd: &rpc::EditNotification) -> bool {
+        use rpc::EditNotification::*;
+        match *cmd {
+            GotoLine { .. } | Click(..) | Gesture { .. } | FindNext { .. } | FindPrevious { .. } |
+            MoveToBeginningOfDocument | MoveToEndOfDocument => true,
+            _ => false,
+        }
+    }
+
+    /// Adds the context of the buffer to the incidents recorded by
+    /// `incident!` since this was last called, and records them.
+    fn record_incidents(&mut self) {
+        let sites = incidents::take_pending();
+        if sites.is_empty() {
+            return;
+        }
+        let config = self.doc_ctx.get_config();
+        let redact = |text: String| if config.redact_incidents {
+            incidents::redact(&text)
+        } else {
+            text
+        };
+        let carets = self.view.sel_regions().iter().take(incidents::MAX_CARETS)
+            .map(|region| {
+                let offset = region.end;
+                let mut start = offset;
+                let mut end = offset;
+                for _ in 0..incidents::CONTEXT_CHARS {
+                    start = self.text.prev_codepoint_offset(start).unwrap_or(start);
+                    end = self.text.next_codepoint_offset(end).unwrap_or(end);
+                }
+                CaretContext {
+                    offset: offset,
+                    before: redact(self.text.slice_to_string(start, offset)),
+                    after: redact(self.text.slice_to_string(offset, end)),
+                }
+            })
+            .collect::<Vec<_>>();
+        let recent_deltas = self.recent_deltas.iter()
+            .map(|delta| DeltaRecord { inserted: redact(delta.inserted.clone()), ..*delta })
+            .collect::<Vec<_>>();
+        for site in sites {
+            self.doc_ctx.record_incident(Incident {
+                id: 0,
+                location: site.location,
+                message: site.message,
+                carets: carets.clone(),
+                recent_deltas: recent_deltas.clone(),
+                plugins: self.active_plugins.iter().cloned().collect(),
+                config_hash: incidents::config_hash(&config),
+                redacted: config.redact_incidents,
+            });
+        }
+    }
+
+    fn caret(&self) -> usize {
+        self.view.sel_regions().last().unwrap().end
+    }
+
+    /// Records a jump from `offset` in the jump list, if the caret has
+    /// moved far enough from it.
+    fn note_jump(&mut self, offset: usize) {
+        let from = self.text.line_of_offset(offset);
+        let to = self.text.line_of_offset(self.caret());
+        if max(from, to) - min(from, to) > JUMP_LINES {
+            self.view.jump_list_mut().push(offset);
+        }
+    }
+
+    fn do_jump(&mut self, forward: bool) {
+        let caret = self.caret();
+        let target = if forward {
+            self.view.jump_list_mut().forward()
+        } else {
+            self.view.jump_list_mut().back(caret)
+        };
+        if let Some(offset) = target {
+            self.set_cursor(offset);
+            self.view.scroll_to_center(&self.text, offset);
+            self.scroll_centered = true;
+        }
+    }
+
+    /// The intervals of the view's lines `first_line..last_line`, or of
+    /// as many of them as there are.
+    fn view_lines(&self, first_line: usize, last_line: usize) -> Vec<Interval> {
+        let height = self.view.line_of_offset(&self.text, self.text.len()) + 1;
+        let last_line = min(last_line, height);
+        let first_line = min(first_line, last_line);
+        (first_line..last_line).map(|line| {
+            let start = self.view.offset_of_line(&self.text, line);
+            let end = if line + 1 == height {
+                self.text.len()
+            } else {
+                self.view.offset_of_line(&self.text, line + 1)
+            };
+            Interval::new_closed_open(start, end)
+        }).collect()
+    }
+
+    /// Returns the styles of the lines `first_line..last_line`, or of those
+    /// before the end of the buffer, in the format of an `update`.
+    fn get_styles(&mut self, first_line: usize, last_line: usize) -> Value {
+        let height = self.view.line_of_offset(&self.text, self.text.len()) + 1;
+        let first_line = min(first_line, min(last_line, height));
+        let lines = self.view_lines(first_line, last_line);
+        let by_line = self.styles.merged_by_line(&lines);
+        // new styles are defined before their ids are returned
+        let styles = by_line.iter().flat_map(|spans| spans.iter().map(|&(_, ref style)| style))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut ids = self.doc_ctx.get_style_ids(&styles).into_iter();
+        let lines = by_line.iter().map(|spans| {
+            let mut ix = 0;
+            let mut rendered = Vec::new();
+            for &(iv, _) in spans {
+                rendered.push(iv.start() as isize - ix);
+                rendered.push(iv.size() as isize);
+                rendered.push(ids.next().unwrap() as isize);
+                ix = iv.end() as isize;
+            }
+            rendered
+        }).collect::<Vec<_>>();
+        json!({"first_line": first_line, "lines": lines})
+    }
+
+    fn get_jump_list(&self) -> Value {
+        let jumps = self.view.jump_list().entries().iter().map(|&offset| {
+            let offset = min(offset, self.text.len());
+            let (line, col) = self.view.offset_to_line_col(&self.text, offset);
+            json!({"line": line, "col": col})
+        }).collect::<Vec<_>>();
+        json!({
+            "jumps": jumps,
+            "current": self.view.jump_list().current(),
+        })
+    }
+
     fn do_find_next(&mut self, reverse: bool, wrap_around: bool, allow_same: bool) {
-        self.scroll_to = self.view.select_next_occurrence(&self.text, reverse, false, true, allow_same);
+        self.scroll_to = self.view.select_next_occurrence(&self.text, &self.segmentation, reverse,
+                                                           false, true, allow_same);
 
         if self.scroll_to.is_none() && wrap_around {
             // nothing found, search past end of file
-            self.scroll_to = self.view.select_next_occurrence(&self.text, reverse, true, true, allow_same);
+            self.scroll_to = self.view.select_next_occurrence(&self.text, &self.segmentation,
+                                                               reverse, true, true, allow_same);
         }
     }
 
@@ -925,13 +3429,21 @@ impl Editor {
         self.commit_delta(None);
         self.render();
         self.last_edit_type = self.this_edit_type;
+        if mem::replace(&mut self.edit_refused, false) {
+            self.doc_ctx.alert(&Message::new(ids::view_cant_be_edited));
+        }
     }
 
     pub fn handle_notification(&mut self, view_id: &ViewIdentifier,
                                cmd: rpc::EditNotification) {
         use rpc::EditNotification::*;
         use rpc::{LineRange, MouseAction};
+        if self.chunked_undo.is_some() && !Editor::runs_during_undo(&cmd) {
+            self.chunked_undo.as_mut().unwrap().queued.push((view_id.clone(), cmd));
+            return;
+        }
         self.cmd_prelude(view_id);
+        let jump_from = if Editor::is_jump(&cmd) { Some(self.caret()) } else { None };
 
         match cmd {
             Insert { chars } => self.do_insert(&chars),
@@ -978,53 +3490,239 @@ impl Editor {
             GotoLine { line } => self.do_goto_line(line),
             RequestLines(LineRange { first, last }) => self.do_request_lines(first, last),
             Yank => self.yank(),
+            Paste { chars, block } => self.do_paste(&chars, block.as_ref()),
+            ResolvePaste { action } => self.do_resolve_paste(action),
             Transpose => self.do_transpose(),
-            Click(MouseAction {line, column, flags, click_count} ) => {
-                self.do_click(line, column, flags, click_count.unwrap())
+            Surround { open, close } => self.do_surround(&open, close.as_ref().map(|s| s.as_str())),
+            Unsurround => self.do_unsurround(),
+            ChangeSurround { from, to } => self.do_change_surround(&from, &to),
+            MoveToEnclosingBracketStart => self.do_move_to_enclosing_bracket(false),
+            MoveToEnclosingBracketEnd => self.do_move_to_enclosing_bracket(true),
+            SelectEnclosingBrackets { include_delimiters } => {
+                self.do_select_enclosing_brackets(include_delimiters)
+            }
+            TransposeBracketGroups => self.do_transpose_bracket_groups(),
+            SelectScope { selector } => self.do_select_scope(&selector),
+            Reindent { scope, snap, skip_strings } => {
+                self.do_reindent(scope, snap, skip_strings.unwrap_or(true))
             }
-            Drag (MouseAction {line, column, flags, ..}) => {
-                self.do_drag(line, column, flags);
+            Click(MouseAction {line, column, flags, click_count, rev} ) => {
+                self.do_click(line, column, flags, click_count.unwrap(), rev)
             }
-            Gesture { line, col, ty } => self.do_gesture(line, col, ty),
+            Drag (MouseAction {line, column, flags, rev, ..}) => {
+                self.do_drag(line, column, flags, rev);
+            }
+            Gesture { line, col, ty, rev } => self.do_gesture(line, col, ty, rev),
             Undo => self.do_undo(),
             Redo => self.do_redo(),
+            CancelOperation => self.finish_chunked_undo(),
             FindNext { wrap_around, allow_same } => self.do_find_next(false, wrap_around.unwrap_or(false), allow_same.unwrap_or(false)),
             FindPrevious { wrap_around } => self.do_find_next(true, wrap_around.unwrap_or(false), true),
+            JumpBack => self.do_jump(false),
+            JumpForward => self.do_jump(true),
             DebugRewrap => self.debug_rewrap(),
             DebugPrintSpans => self.debug_print_spans(),
+            SetLanguage { language_id } => self.set_language(&language_id),
+            ToggleShowInvisibles => self.toggle_show_invisibles(),
         };
 
+        if let Some(offset) = jump_from {
+            self.note_jump(offset);
+        }
         self.cmd_postlude();
     }
 
+    /// Returns `true` if `cmd` can be handled while a chunked undo is being
+    /// applied. Other notifications are queued until the undo is complete.
+    fn runs_during_undo(cmd: &rpc::EditNotification) -> bool {
+        use rpc::EditNotification::*;
+        match *cmd {
+            Scroll(..) | RequestLines(..) | CancelOperation => true,
+            _ => false,
+        }
+    }
+
     pub fn handle_request(&mut self, view_id: &ViewIdentifier,
                           cmd: rpc::EditRequest) -> Result<Value, RemoteError> {
         use rpc::EditRequest::*;
         self.cmd_prelude(&view_id);
 
         let result = match cmd {
-            Cut => self.do_cut(),
-            Copy => self.do_copy(),
-            Find { chars, case_sensitive } => self.do_find(chars, case_sensitive),
-        };
-
-        self.cmd_postlude();
-        Ok(result)
-    }
-
-    pub fn theme_changed(&mut self) {
-        self.styles.theme_changed(&self.doc_ctx);
-        self.view.set_dirty();
-        self.render();
-    }
-
-    // Note: the following are placeholders for prototyping, and are not intended to
-    // deal with asynchrony or be efficient.
+            Cut => {
+                self.finish_chunked_undo();
+                Ok(self.do_cut())
+            }
+            Copy => Ok(self.do_copy()),
+            Find { chars, case_sensitive, whole_words } => {
+                Ok(self.do_find(chars, case_sensitive, whole_words))
+            }
+            PreviewReplace { query, replacement, options, max_results } => {
+                self.finish_chunked_undo();
+                Ok(self.do_preview_replace(&query, &replacement, &options.unwrap_or_default(),
+                                           max_results))
+            }
+            ReplaceAll { token, query, replacement, options } => {
+                self.finish_chunked_undo();
+                self.do_replace_all(token, query, replacement, options.unwrap_or_default())
+            }
+            TransformSelectionWithExpr { expr, on_error } => {
+                self.finish_chunked_undo();
+                self.transform_selection_with_expr(&expr, on_error.unwrap_or_default())
+            }
+            GetJumpList => Ok(self.get_jump_list()),
+            GetBufferInfo => Ok(serde_json::to_value(self.buffer_info()).unwrap()),
+            GetMinimap { lines_per_bucket, first_bucket, last_bucket } => {
+                Ok(self.view.get_minimap(&self.text, &self.doc_ctx, self.styles.get_merged(),
+                                         lines_per_bucket, first_bucket.unwrap_or(0),
+                                         last_bucket.unwrap_or(usize::max_value())))
+            }
+            GetStyles { first_line, last_line } => Ok(self.get_styles(first_line, last_line)),
+            PromoteToEditable => Ok(Value::Null),
+            AddWordToDictionary { word, scope } => {
+                self.add_word_to_dictionary(&word, scope)
+                    .map(|_| Value::Null)
+                    .map_err(|msg| msg.into_error(3, None))
+            }
+            GetAccessibilityLine { visual_line } => {
+                self.view.accessibility_line(&self.text, visual_line).ok_or_else(|| {
+                    RemoteError::custom(3, format!("no visual line {}", visual_line), None)
+                })
+            }
+            AccessibilityDelta { since_token } => {
+                Ok(self.view.accessibility_delta(&self.text, since_token))
+            }
+            ExportHistory { .. } | MergeHistory { .. } if self.scratch.is_some() => {
+                Err(RemoteError::custom(3, "scratch buffers have no history", None))
+            }
+            ExportHistory { since_rev } => Ok(self.export_history(since_rev)),
+            MergeHistory { payload } => {
+                self.finish_chunked_undo();
+                self.merge_history(&payload)
+            }
+            ExportHtml { path } => {
+                let html = self.export_html();
+                match path {
+                    Some(path) => fs::write(&path, html).map(|_| Value::Null).map_err(|e| {
+                        RemoteError::custom(3, format!("couldn't write {}: {}", path, e), None)
+                    }),
+                    None => Ok(Value::String(html)),
+                }
+            }
+            DebugGetStats => Ok(self.debug_get_stats()),
+            DebugStyles { start, end } => Ok(self.debug_styles(start, end)),
+        };
 
-    /// Applies an async edit from a plugin.
-    pub fn plugin_edit(&mut self, edit: &PluginEdit) {
+        self.cmd_postlude();
+        result
+    }
+
+    /// Notes that the config has changed, updating the indents of lines,
+    /// which are measured in its tab size.
+    pub fn config_changed(&mut self) {
+        let tab_size = self.doc_ctx.get_config().indent_style().tab_size;
+        self.line_metadata.set_tab_size(tab_size);
+    }
+
+    /// Notes that the theme has changed. Styles are recomputed as
+    /// background work, so that a burst of changes, as when previewing
+    /// themes, only recomputes them once.
+    pub fn theme_changed(&mut self) {
+        self.theme_pending = true;
+    }
+
+    fn recompute_theme_styles(&mut self) {
+        self.theme_pending = false;
+        self.theme_recomputes += 1;
+        self.styles.theme_diff_update(&self.doc_ctx);
+        self.restyle_next_chunk();
+    }
+
+    /// Restyles the next chunk of the text for a new theme, that in view
+    /// first, and renders it; the rest keeps its old styles meanwhile.
+    fn restyle_next_chunk(&mut self) {
+        let visible = self.view.scroll_interval(&self.text);
+        let iv = match self.styles.restyle_next(visible, RESTYLE_CHUNK) {
+            Some(iv) => iv,
+            None => return,
+        };
+        // define the chunk's styles together, rather than one at a time as
+        // they are rendered
+        let restyled = self.styles.get_merged().subseq(iv);
+        let mut seen = HashSet::new();
+        let styles = restyled.iter()
+            .filter(|&(_, style)| seen.insert(style))
+            .map(|(_, style)| style.clone())
+            .collect::<Vec<_>>();
+        self.doc_ctx.get_style_ids(&styles);
+        self.styles_changed(iv);
+        self.render();
+    }
+
+    // Note: the following are placeholders for prototyping, and are not intended to
+    // deal with asynchrony or be efficient.
+
+    /// Applies an edit `plugin` sent of its own accord, in the plugin's
+    /// open undo group if it has one.
+    pub fn plugin_edit(&mut self, plugin: PluginPid, edit: &PluginEdit, now: Instant) {
         self.this_edit_type = EditType::Other;
-        self.apply_plugin_edit(edit, None)
+        self.close_plugin_undo_groups(now);
+        let undo_group = self.plugin_undo_groups.get(&plugin).and_then(|group| group.undo_group);
+        self.apply_plugin_edit(edit, undo_group);
+        let latest = self.latest_undo_group();
+        if let Some(group) = self.plugin_undo_groups.get_mut(&plugin) {
+            group.undo_group = latest;
+            group.deadline = now + Duration::from_millis(PLUGIN_UNDO_GROUP_TIMEOUT);
+        }
+    }
+
+    /// Opens an undo group for `plugin`, returning its token, unless the
+    /// plugin already has one open.
+    pub fn plugin_begin_undo_group(&mut self, plugin: PluginPid, now: Instant) -> Option<u64> {
+        self.close_plugin_undo_groups(now);
+        if self.plugin_undo_groups.contains_key(&plugin) {
+            return None;
+        }
+        let token = self.next_undo_group_token;
+        self.next_undo_group_token += 1;
+        self.plugin_undo_groups.insert(plugin, PluginUndoGroup {
+            token: token,
+            undo_group: None,
+            deadline: now + Duration::from_millis(PLUGIN_UNDO_GROUP_TIMEOUT),
+        });
+        Some(token)
+    }
+
+    /// Closes `plugin`'s undo group with `token`. Returns `false` if it had
+    /// already been closed.
+    pub fn plugin_end_undo_group(&mut self, plugin: PluginPid, token: u64, now: Instant)
+                                 -> bool {
+        self.close_plugin_undo_groups(now);
+        match self.plugin_undo_groups.get(&plugin) {
+            Some(group) if group.token == token => (),
+            _ => return false,
+        }
+        self.plugin_undo_groups.remove(&plugin);
+        true
+    }
+
+    /// Returns the undo group which the next undo would undo, if any.
+    fn latest_undo_group(&self) -> Option<usize> {
+        self.live_undos[..self.cur_undo].last().cloned()
+    }
+
+    /// Closes the plugins' undo groups which have timed out, or which are
+    /// no longer the latest, as another edit or an undo followed them.
+    fn close_plugin_undo_groups(&mut self, now: Instant) {
+        let latest = self.latest_undo_group();
+        let closed = self.plugin_undo_groups.iter()
+            .filter(|&(_, group)| {
+                now >= group.deadline || group.undo_group.map_or(false, |g| Some(g) != latest)
+            })
+            .map(|(&plugin, _)| plugin)
+            .collect::<Vec<_>>();
+        for plugin in closed {
+            self.plugin_undo_groups.remove(&plugin);
+        }
     }
 
     pub fn plugin_n_lines(&self) -> usize {
@@ -1033,22 +3731,270 @@ impl Editor {
 
     //TODO: plugins should optionally be able to provide a layer id
     // so a single plugin can maintain multiple layers
-    pub fn plugin_add_scopes(&mut self, plugin: PluginPid, scopes: Vec<Vec<String>>) {
-        self.styles.add_scopes(plugin, scopes, &self.doc_ctx);
+    pub fn plugin_add_scopes(&mut self, plugin: PluginPid, scopes: Vec<Vec<String>>)
+                             -> Result<(), String> {
+        self.plugin_add_scopes_with_modifiers(plugin, scopes, Vec::new())
     }
 
-    pub fn plugin_update_spans(&mut self, plugin: PluginPid, start: usize, len: usize,
-                               spans: Vec<ScopeSpan>, rev: RevToken) {
-        // TODO: more protection against invalid input
-        let mut start = start;
-        let mut end_offset = start + len;
-        let mut sb = SpansBuilder::new(len);
+    /// Adds `scopes` to the plugin's layer, with the modifiers of each, as
+    /// `Scopes::add_scopes_with_modifiers` does.
+    pub fn plugin_add_scopes_with_modifiers(&mut self, plugin: PluginPid,
+                                            scopes: Vec<Vec<String>>, modifiers: Vec<u32>)
+                                            -> Result<(), String> {
+        self.displace_builtin_syntax(plugin);
+        self.styles.add_scopes_with_modifiers(plugin, scopes, modifiers, &self.doc_ctx)
+    }
+
+    /// Removes the layer of core's syntax highlighting, now that `plugin`
+    /// highlights the buffer.
+    fn displace_builtin_syntax(&mut self, plugin: PluginPid) {
+        if let Some(iv) = self.builtin_syntax.displace(plugin, &self.text, &mut self.styles) {
+            self.styles_changed(iv);
+        }
+    }
+
+    /// Returns the number of scopes and spans in the plugin's layer.
+    pub fn plugin_layer_usage(&self, plugin: PluginPid) -> (usize, usize) {
+        self.styles.layer_usage(plugin)
+    }
+
+    /// Returns the number of spans the plugin's layer would have, if those
+    /// from `start` to `start + len` were replaced by `n_spans` spans.
+    pub fn plugin_spans_after_update(&self, plugin: PluginPid, start: usize, len: usize,
+                                     n_spans: usize) -> usize {
+        let end = min(start + len, self.text.len());
+        let iv = Interval::new_closed_open(min(start, end), end);
+        let (_, total) = self.styles.layer_usage(plugin);
+        total.saturating_sub(self.styles.layer_spans_within(plugin, iv)) + n_spans
+    }
+
+    /// Returns the number of spans the plugin's layer would have after the
+    /// spans of the regions `ops` skip or insert are replaced.
+    pub fn plugin_spans_after_edit(&self, plugin: PluginPid, ops: &[SpanOp]) -> usize {
+        let (_, total) = self.styles.layer_usage(plugin);
+        let mut offset = 0;
+        let mut replaced = 0;
+        let mut inserted = 0;
+        for op in ops {
+            let (len, n_spans) = match *op {
+                SpanOp::Retain(n) => {
+                    offset += n;
+                    continue;
+                }
+                SpanOp::Skip(n) => (n, 0),
+                SpanOp::Insert { len, ref spans } => (len, spans.len()),
+            };
+            let end = min(offset.saturating_add(len), self.text.len());
+            let iv = Interval::new_closed_open(min(offset, end), end);
+            replaced += self.styles.layer_spans_within(plugin, iv);
+            inserted += n_spans;
+            offset = offset.saturating_add(len);
+        }
+        total.saturating_sub(replaced) + inserted
+    }
+
+    /// Returns the number of spans the plugin's layer would have after the
+    /// spans of the regions of `updates`, which don't overlap, are replaced.
+    pub fn plugin_spans_after_batch(&self, plugin: PluginPid, updates: &[SpanUpdate]) -> usize {
+        let (_, total) = self.styles.layer_usage(plugin);
+        let (replaced, inserted) = updates.iter().fold((0, 0), |(replaced, inserted), update| {
+            let end = min(update.start.saturating_add(update.len), self.text.len());
+            let iv = Interval::new_closed_open(min(update.start, end), end);
+            (replaced + self.styles.layer_spans_within(plugin, iv), inserted + update.spans.len())
+        });
+        total.saturating_sub(replaced) + inserted
+    }
+
+    /// Checks that `spans`, of a region of `len` bytes, are in order and
+    /// within it.
+    fn plugin_check_spans(spans: &[ScopeSpan], len: usize) -> Result<(), String> {
+        let mut prev_start = 0;
         for span in spans {
+            if span.start < prev_start || span.start > span.end || span.end > len {
+                return Err(format!("invalid span {}..{} of a region of {} bytes",
+                                   span.start, span.end, len));
+            }
+            prev_start = span.start;
+        }
+        Ok(())
+    }
+
+    /// Builds the spans of a region of `len` bytes of a layer with
+    /// `n_scopes` scopes. Spans with scopes which were never added, as when
+    /// they were dropped for exceeding the plugin's limits, are ignored.
+    fn plugin_scope_spans(n_scopes: usize, len: usize, spans: Vec<ScopeSpan>) -> Spans<u32> {
+        let mut sb = SpansBuilder::new(len);
+        for span in spans.into_iter().filter(|span| (span.scope_id as usize) < n_scopes) {
             sb.add_span(Interval::new_open_open(span.start, span.end), span.scope_id);
         }
-        let mut spans = sb.build();
+        sb.build()
+    }
+
+    /// Replaces the spans of the plugin's layer from `start` to `start +
+    /// len` as of `rev`. Fails, changing nothing, if the spans are out of
+    /// order or out of bounds. The new styles are rendered once the plugin
+    /// has no more updates queued, with `flush_plugin_styles`.
+    pub fn plugin_update_spans(&mut self, plugin: PluginPid, start: usize, len: usize,
+                               spans: Vec<ScopeSpan>, rev: RevToken) -> Result<(), String> {
+        self.finish_chunked_undo();
+        Self::plugin_check_spans(&spans, len)?;
+        let (n_scopes, _) = self.styles.layer_usage(plugin);
+        let spans = Self::plugin_scope_spans(n_scopes, len, spans);
+        let iv = self.plugin_update_layer(plugin, start, spans, rev)?;
+        self.plugin_styles_changed(iv);
+        Ok(())
+    }
+
+    /// Adds `scopes`, with their `modifiers`, to the plugin's layer and
+    /// updates several regions of its spans, as `plugin_add_scopes_with_modifiers`
+    /// and `plugin_update_spans` would, but resolving the styles and telling
+    /// the views once. The valid updates are applied even if others aren't;
+    /// the first error is returned.
+    pub fn plugin_apply_batch(&mut self, plugin: PluginPid, scopes: Vec<Vec<String>>,
+                              modifiers: Vec<u32>, updates: Vec<SpanUpdate>, rev: RevToken)
+                              -> Result<(), String> {
+        self.finish_chunked_undo();
+        self.displace_builtin_syntax(plugin);
+        let n_scopes = self.styles.layer_usage(plugin).0 + scopes.len();
+        let mut result = Ok(());
+        let mut checked = Vec::with_capacity(updates.len());
+        for update in updates {
+            let transformed = Self::plugin_check_spans(&update.spans, update.len).and_then(|()| {
+                let spans = Self::plugin_scope_spans(n_scopes, update.len, update.spans);
+                self.plugin_transform_spans(update.start, spans, rev)
+            });
+            match transformed {
+                Ok(update) => checked.push(update),
+                Err(err) => result = result.and(Err(err)),
+            }
+        }
+        let updates = checked;
+        let touched = updates.iter()
+            .fold(None, |touched: Option<Interval>, &(iv, _)| {
+                Some(touched.map_or(iv, |touched| touched.union(iv)))
+            });
+        let applied = self.styles.apply_batch(plugin, scopes, modifiers, updates,
+                                              &self.doc_ctx);
+        // the end of a batch renders it, with any updates pending before it
+        if let Some(iv) = touched {
+            self.plugin_styles_changed(iv);
+        }
+        self.render();
+        result.and(applied)
+    }
+
+    /// Updates the spans of the plugin's style layer, for a plugin which
+    /// sends styles rather than scopes. Their priority is that of styles
+    /// resolved from scopes, so that the order of layers decides between
+    /// them.
+    pub fn plugin_update_style_spans(&mut self, plugin: PluginPid, start: usize, len: usize,
+                                     spans: Vec<StyleSpan>, rev: RevToken)
+                                     -> Result<(), String> {
+        self.finish_chunked_undo();
+        let mut sb = SpansBuilder::new(len);
+        for span in spans.into_iter().filter(|span| span.start < span.end && span.end <= len) {
+            let style = Style { priority: SYNTAX_PRIORITY_DEFAULT, ..span.style };
+            sb.add_span(Interval::new_open_open(span.start, span.end), style);
+        }
+        let (iv, spans) = self.plugin_transform_spans(start, sb.build(), rev)?;
+        self.styles.update_style_layer(plugin, iv, spans)?;
+        self.plugin_styles_changed(iv);
+        Ok(())
+    }
+
+    /// Updates the spans of the plugin's layer with `ops`, which cover the
+    /// buffer as of `rev`. Fails if they don't, or if the spans they
+    /// insert are out of order or out of bounds.
+    pub fn plugin_edit_spans(&mut self, plugin: PluginPid, ops: Vec<SpanOp>, rev: RevToken)
+                             -> Result<(), String> {
+        self.finish_chunked_undo();
+        let rev_len = if rev == self.engine.get_head_rev_id().token() {
+            self.text.len()
+        } else {
+            self.engine.get_rev(rev).ok_or_else(|| format!("unknown revision {}", rev))?.len()
+        };
+        let (n_scopes, _) = self.styles.layer_usage(plugin);
+        let mut regions = Vec::new();
+        let mut offset = 0;
+        for op in ops {
+            match op {
+                SpanOp::Retain(n) => offset += n,
+                SpanOp::Skip(n) => {
+                    regions.push((offset, SpansBuilder::new(n).build()));
+                    offset += n;
+                }
+                SpanOp::Insert { len, spans } => {
+                    Self::plugin_check_spans(&spans, len)?;
+                    regions.push((offset, Self::plugin_scope_spans(n_scopes, len, spans)));
+                    offset += len;
+                }
+            }
+        }
+        if offset != rev_len {
+            return Err(format!("span ops cover {} bytes of {}", offset, rev_len));
+        }
+        let changed = match (regions.first(), regions.last()) {
+            (Some(first), Some(last)) => (first.0, last.0 + last.1.len()),
+            _ => return Ok(()),
+        };
+        if rev == self.engine.get_head_rev_id().token() {
+            let mut ops = Vec::with_capacity(regions.len() * 2 + 1);
+            let mut offset = 0;
+            for (start, spans) in regions {
+                ops.push(LayerOp::Retain(start - offset));
+                offset = start + spans.len();
+                ops.push(LayerOp::Insert(spans));
+            }
+            ops.push(LayerOp::Retain(rev_len - offset));
+            self.styles.edit_layer(plugin, ops)?;
+            self.styles_changed(Interval::new_closed_open(changed.0, changed.1));
+        } else {
+            // each region is rebased onto the head revision on its own
+            self.styles.begin_update();
+            let mut ivs = Vec::with_capacity(regions.len());
+            let mut result = Ok(());
+            for (start, spans) in regions {
+                match self.plugin_update_layer(plugin, start, spans, rev) {
+                    Ok(iv) => ivs.push(iv),
+                    Err(err) => result = result.and(Err(err)),
+                }
+            }
+            self.styles.commit_update();
+            for iv in ivs {
+                self.styles_changed(iv);
+            }
+            self.render();
+            return result;
+        }
+        self.render();
+        Ok(())
+    }
+
+    /// Replaces the spans of the plugin's layer starting at `start` as of
+    /// `rev` with `spans`, returning the interval replaced, as of the head
+    /// revision. Fails if `rev` is unknown, or the interval is past the end
+    /// of the buffer.
+    fn plugin_update_layer(&mut self, plugin: PluginPid, start: usize, spans: Spans<u32>,
+                           rev: RevToken) -> Result<Interval, String> {
+        let (iv, spans) = self.plugin_transform_spans(start, spans, rev)?;
+        self.styles.update_layer(plugin, iv, spans, None, None)?;
+        Ok(iv)
+    }
+
+    /// Transforms `spans`, starting at `start` in the buffer as of `rev`,
+    /// through the edits since, returning them with the interval they
+    /// replace now. Spans within text deleted since are dropped. Fails if
+    /// `rev` is unknown, as when it was garbage collected, in which case
+    /// the plugin should compute its spans again.
+    fn plugin_transform_spans<T: Clone + Default>(&self, start: usize, mut spans: Spans<T>,
+                                                  rev: RevToken)
+                                                  -> Result<(Interval, Spans<T>), String> {
+        let mut start = start;
+        let mut end_offset = start.saturating_add(spans.len());
         if rev != self.engine.get_head_rev_id().token() {
-            let delta = self.engine.delta_rev_head(rev);
+            let delta = self.engine.try_delta_rev_head(rev).ok_or_else(|| {
+                format!("unknown revision {}; the spans should be computed again", rev)
+            })?;
             let mut transformer = Transformer::new(&delta);
             let new_start = transformer.transform(start, false);
             if !transformer.interval_untouched(
@@ -1058,15 +4004,12 @@ impl Editor {
             start = new_start;
             end_offset = transformer.transform(end_offset, true);
         }
-        let iv = Interval::new_closed_closed(start, end_offset);
-        self.styles.update_layer(plugin, iv, spans);
-        self.view.set_dirty();
-        self.render();
+        Ok((Interval::new_closed_closed(start, end_offset), spans))
     }
 
     pub fn plugin_get_data(&self, offset: usize, max_size: usize, rev: RevToken) -> Option<String> {
         let text_cow = if rev == self.engine.get_head_rev_id().token() {
-            Cow::Borrowed(&self.text)
+            Cow::Borrowed(self.engine.get_head())
         } else {
             match self.engine.get_rev(rev) {
                 None => return None,
@@ -1087,6 +4030,36 @@ impl Editor {
         Some(text.slice_to_string(offset, end_off))
     }
 
+    /// Sets the priority of the plugin's layer, in the order the styles of
+    /// layers are merged.
+    pub fn plugin_set_layer_priority(&mut self, plugin: PluginPid, priority: u32) {
+        if self.styles.set_layer_priority(plugin, priority) {
+            let iv = Interval::new_closed_open(0, self.text.len());
+            self.styles_changed(iv);
+            self.render();
+        }
+    }
+
+    /// Sets how the spans of the plugin's layers are moved by edits.
+    pub fn plugin_set_edit_policy(&mut self, plugin: PluginPid, edit_policy: EditPolicy) {
+        self.styles.set_edit_policy(plugin, edit_policy);
+    }
+
+    /// Hides or shows the styles of the plugin's layer, keeping its spans.
+    pub fn set_layer_enabled(&mut self, plugin: PluginPid, enabled: bool) {
+        if self.styles.set_layer_enabled(plugin, enabled) {
+            let iv = Interval::new_closed_open(0, self.text.len());
+            self.styles_changed(iv);
+            self.render();
+        }
+    }
+
+    /// Sets the segmenter used to find words in CJK text, provided by `plugin`.
+    pub fn plugin_set_segmenter(&mut self, plugin: PluginPid, segmenter: Box<Segmenter>) {
+        self.segmenter_plugin = Some(plugin);
+        self.segmentation.set_segmenter(Some(segmenter));
+    }
+
     pub fn plugin_get_selections(&self, view_id: &ViewIdentifier) -> Value {
         //TODO: multiview support
         assert_eq!(view_id, &self.view.view_id);
@@ -1098,9 +4071,103 @@ impl Editor {
         json!({"selections": sels})
     }
 
+    /// Returns the spans from `start` to `end` whose scopes match
+    /// `selector`, with the plugin whose layer each is in.
+    pub fn plugin_find_scopes(&self, selector: &str, start: usize, end: usize)
+                              -> Result<Value, String> {
+        let end = min(end, self.text.len());
+        let iv = Interval::new_closed_open(min(start, end), end);
+        let spans = self.styles.find_by_selector(selector, iv)?.into_iter()
+            .map(|(iv, plugin)| json!({"start": iv.start(), "end": iv.end(), "plugin": plugin}))
+            .collect::<Vec<_>>();
+        Ok(json!(spans))
+    }
+
+    /// Returns the scopes at `offset`, those of each layer in turn.
+    pub fn plugin_get_scope_at(&self, offset: usize) -> Value {
+        let scopes = self.styles.scopes_at(offset).into_iter()
+            .flat_map(|(_, names)| names.iter().cloned())
+            .collect::<Vec<_>>();
+        json!(scopes)
+    }
+
+    /// Returns the words from `start` to `end` which a spell-checking
+    /// plugin should check, as the user's config and dictionaries say.
+    pub fn plugin_get_words(&self, start: usize, end: usize) -> Value {
+        let config = self.doc_ctx.get_config().spellcheck;
+        let words = self.words_to_check(&config, start, end).into_iter()
+            .map(|(start, end, word)| json!({"start": start, "end": end, "word": word}))
+            .collect::<Vec<_>>();
+        json!({"language": config.language, "words": words})
+    }
+
+    fn words_to_check(&self, config: &SpellcheckConfig, start: usize, end: usize)
+                      -> Vec<(usize, usize, String)> {
+        let end = min(end, self.text.len());
+        let start = min(start, end);
+        let iv = Interval::new_closed_open(start, end);
+        let scopes = |names: &[String]| {
+            let names = names.iter().map(String::as_str).collect::<Vec<_>>();
+            self.styles.intervals_with_scope(iv, &names)
+        };
+        let included = if config.included_scopes.is_empty() || !self.styles.has_scopes() {
+            None
+        } else {
+            Some(scopes(&config.included_scopes))
+        };
+        let excluded = scopes(&config.excluded_scopes);
+
+        let mut known = self.extra_words.clone();
+        if let Some(path) = self.path.as_ref().and_then(|p| spellcheck::project_words_file(p)) {
+            known.extend(spellcheck::read_words(&path));
+        }
+        if let Some(ref path) = config.extra_words_file {
+            known.extend(spellcheck::read_words(path));
+        }
+
+        let text = self.text.slice_to_string(start, end);
+        spellcheck::find_words(&text).into_iter()
+            .map(|(word_start, word_end)| (start + word_start, start + word_end))
+            .filter(|&(word_start, _)| {
+                included.as_ref().map_or(true, |ivs| spellcheck::in_any(ivs, word_start)) &&
+                    !spellcheck::in_any(&excluded, word_start)
+            })
+            .map(|(word_start, word_end)| {
+                (word_start, word_end, text[word_start - start..word_end - start].to_owned())
+            })
+            .filter(|&(_, _, ref word)| !known.contains(word))
+            .collect()
+    }
+
+    /// Adds `word` to a dictionary, so that it isn't spell-checked.
+    fn add_word_to_dictionary(&mut self, word: &str, scope: DictionaryScope)
+                              -> Result<(), Message> {
+        if word.is_empty() || word.contains(char::is_whitespace) {
+            return Err(Message::new(ids::not_a_word).param("word", word));
+        }
+        let path = match scope {
+            DictionaryScope::Buffer => {
+                self.extra_words.insert(word.to_owned());
+                return Ok(());
+            }
+            DictionaryScope::Project => {
+                self.path.as_ref().and_then(|p| spellcheck::project_words_file(p))
+                    .ok_or_else(|| Message::new(ids::no_project))?
+            }
+            DictionaryScope::User => {
+                self.doc_ctx.get_config().spellcheck.extra_words_file
+                    .ok_or_else(|| Message::new(ids::no_extra_words_file))?
+            }
+        };
+        spellcheck::append_word(&path, word)
+            .map_err(|err| Message::new(ids::dictionary_write_failed)
+                     .param("path", path.display().to_string())
+                     .param("error", err.to_string()))
+    }
+
     // Note: currently we route up through Editor to DocumentCtx, but perhaps the plugin
     // should have its own reference.
-    pub fn plugin_alert(&self, msg: &str) {
+    pub fn plugin_alert(&self, msg: &Message) {
         self.doc_ctx.alert(msg);
     }
 
@@ -1116,23 +4183,66 @@ impl Editor {
     /// for a particular view; plugins are active at the editor/buffer level.
     /// Some `view_id` is needed, however, to route to the correct client view.
     //TODO: revisit this after implementing multiview
-    pub fn plugin_started<'a, T>(&'a self, view_id: T, plugin: &str,
+    pub fn plugin_started<'a, T>(&'a mut self, view_id: T, plugin: &str,
                                  cmds: &[Command])
         where T: Into<Option<&'a ViewIdentifier>> {
+        self.active_plugins.insert(plugin.to_owned());
         let view_id = view_id.into().unwrap_or(&self.view.view_id);
         self.doc_ctx.plugin_started(view_id, plugin);
         self.doc_ctx.update_cmds(view_id, plugin, cmds);
     }
 
+    /// Keeps the styles of the layers of a plugin which crashed, once it is
+    /// stopped, for it to adopt when it is restarted.
+    pub fn plugin_detach_layer(&mut self, plugin: &str, plugin_id: PluginPid) {
+        if self.styles.detach_layer(plugin_id) {
+            self.detached_layers.insert(plugin.to_owned(), plugin_id);
+        }
+    }
+
+    /// Gives the detached layers of the named plugin, if it has any, to the
+    /// restarted plugin `plugin_id`.
+    pub fn plugin_adopt_layer(&mut self, plugin: &str, plugin_id: PluginPid) {
+        let old = match self.detached_layers.remove(plugin) {
+            Some(old) => old,
+            None => return,
+        };
+        if self.styles.adopt_layer(old, plugin_id) {
+            let iv = Interval::new_closed_open(0, self.text.len());
+            self.styles_changed(iv);
+            self.render();
+        }
+    }
+
     /// Notifies client that the named plugin has stopped.
     ///
     /// `code` is reserved for future use.
     pub fn plugin_stopped<'a, T>(&'a mut self, view_id: T, plugin: &str,
                                  plugin_id: PluginPid, code: i32)
         where T: Into<Option<&'a ViewIdentifier>> {
-        {
+        if self.segmenter_plugin == Some(plugin_id) {
+            self.segmenter_plugin = None;
+            self.segmentation.set_segmenter(None);
+        }
+        self.plugin_undo_groups.remove(&plugin_id);
+        // the edits of a revision no longer wait for the plugin's response
+        for window in self.plugin_edit_windows.values_mut() {
+            window.outstanding.retain(|order| order.plugin != plugin_id);
+        }
+        self.apply_ready_plugin_edits();
+        self.active_plugins.remove(plugin);
+        if self.doc_ctx.get_config().log_plugin_stats {
+            if let Some(stats) = self.styles.stats().layers.into_iter()
+                .find(|stats| stats.plugin.plugin() == Some(plugin_id)) {
+                print_err!("plugin {} stopped with styles {}", plugin,
+                           serde_json::to_string(&stats).unwrap());
+            }
+        }
+        if self.detached_layers.get(plugin) != Some(&plugin_id) {
             self.styles.remove_layer(plugin_id);
-            self.view.set_dirty();
+            self.builtin_syntax.plugin_stopped(plugin_id);
+            let iv = Interval::new_closed_open(0, self.text.len());
+            self.styles_changed(iv);
             self.render();
         }
         let view_id = view_id.into().unwrap_or(&self.view.view_id);
@@ -1141,8 +4251,2111 @@ impl Editor {
     }
 }
 
-fn n_spaces(n: usize) -> &'static str {
-    let spaces = "                                ";
-    assert!(n <= spaces.len());
-    &spaces[..n]
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::*;
+    use tabs::{mock_doc_ctx, mock_doc_ctx_with_updates};
+    use rpc::{EditNotification, EditRequest};
+
+    const N_LINES: usize = 200_000;
+
+    /// Returns an editor whose last undo group replaces every line.
+    fn replaced_editor(view_id: &ViewIdentifier) -> Editor {
+        let text = "foo\n".repeat(N_LINES);
+        let mut editor = Editor::with_text(mock_doc_ctx(view_id.as_str()),
+                                           BufferIdentifier::new(1), view_id, text);
+        let mut builder = delta::Builder::new(editor.text.len());
+        for line in 0..N_LINES {
+            let iv = Interval::new_closed_open(line * 4, line * 4 + 3);
+            builder.replace(iv, Rope::from("barbaz"));
+        }
+        editor.add_delta(builder.build());
+        editor.commit_delta(None);
+        editor
+    }
+
+    #[test]
+    fn chunked_undo() {
+        let view_id = ViewIdentifier::from("view-id-chunked-undo");
+        let mut editor = replaced_editor(&view_id);
+        let replaced = String::from(&editor.text);
+
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        assert!(editor.has_background_work());
+        let mut n_steps = 0;
+        while editor.has_background_work() {
+            // non-mutating requests are answered between chunks
+            let start = Instant::now();
+            editor.handle_request(&view_id, EditRequest::Copy).unwrap();
+            assert!(start.elapsed() < Duration::from_millis(200));
+            editor.do_background_work();
+            n_steps += 1;
+        }
+        assert!(n_steps > 1);
+        assert_eq!(String::from(&editor.text), "foo\n".repeat(N_LINES));
+        assert_eq!(editor.metrics.longest_line(), 3);
+
+        // the whole group is redone in one step
+        editor.handle_notification(&view_id, EditNotification::Redo);
+        editor.finish_chunked_undo();
+        assert_eq!(String::from(&editor.text), replaced);
+        assert_eq!(String::from(editor.engine.get_head()), replaced);
+    }
+
+    #[test]
+    fn longest_line_recount() {
+        let view_id = ViewIdentifier::from("view-id-longest-line-recount");
+        let text = format!("{}\n", "x".repeat(50)).repeat(9) + &"a\n".repeat(30_000);
+        let mut editor = Editor::with_text(mock_doc_ctx(view_id.as_str()),
+                                           BufferIdentifier::new(1), &view_id, text);
+        editor.render();
+        // deleting all the longest lines known leaves the longest unknown
+        let iv = Interval::new_closed_open(0, 51 * 9);
+        editor.add_delta(Delta::simple_edit(iv, Rope::from(""), editor.text.len()));
+        editor.commit_delta(None);
+        editor.render();
+        assert!(editor.has_background_work());
+        let updates = frontend_updates(&view_id);
+        while editor.has_background_work() {
+            editor.do_background_work();
+        }
+        assert_eq!(editor.metrics.longest_line(), 1);
+
+        // the recount's result is sent once it is done
+        assert_eq!(frontend_updates(&view_id), updates + 1);
+        let sent = ::std::fs::read_to_string(::std::env::temp_dir().join(view_id.as_str()))
+            .unwrap();
+        let update = sent.lines()
+            .filter(|line| line.contains("\"method\":\"update\""))
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .last()
+            .unwrap();
+        assert_eq!(update["params"]["update"]["metrics"]["longest_line"], json!(1));
+    }
+
+    #[test]
+    fn line_number_digits() {
+        let view_id = ViewIdentifier::from("view-id-line-number-digits");
+        let text = format!("{}a", "a\n".repeat(998));
+        let mut editor = Editor::with_text(mock_doc_ctx(view_id.as_str()),
+                                           BufferIdentifier::new(1), &view_id, text);
+        let digits = |editor: &Editor| editor.view.layout_hints().unwrap().line_number_digits;
+        editor.render();
+        assert_eq!(digits(&editor), 3);
+        editor.handle_notification(&view_id, EditNotification::MoveToEndOfDocument);
+        editor.handle_notification(&view_id, EditNotification::InsertNewline);
+        assert_eq!(digits(&editor), 4);
+        // the gutter doesn't narrow just under the boundary
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        assert_eq!(editor.text.measure::<LinesMetric>() + 1, 999);
+        assert_eq!(digits(&editor), 4);
+        editor.handle_notification(&view_id, EditNotification::Redo);
+        assert_eq!(digits(&editor), 4);
+        editor.handle_notification(&view_id, EditNotification::SelectAll);
+        editor.handle_notification(&view_id, EditNotification::DeleteBackward);
+        assert_eq!(digits(&editor), 1);
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        assert_eq!(digits(&editor), 4);
+        assert!(!editor.view.layout_hints().unwrap().foldable);
+    }
+
+    #[test]
+    fn autoindent_newlines() {
+        let view_id = ViewIdentifier::from("view-id-autoindent-newlines");
+        let newline = |text: &str, offset: usize, syntax: SyntaxDefinition| {
+            let mut editor = surround_editor(&view_id, text, &[(offset, offset)]);
+            editor.syntax = syntax;
+            editor.handle_notification(&view_id, EditNotification::InsertNewline);
+            String::from(&editor.text)
+        };
+        // a tab counts as tab_size columns, whether the caret is within the
+        // leading whitespace or after it; the default config indents with
+        // spaces
+        assert_eq!(newline("\tfoo\n", 1, SyntaxDefinition::Plaintext), "\t\n    foo\n");
+        assert_eq!(newline("\tfoo\n", 4, SyntaxDefinition::Plaintext), "\tfoo\n    \n");
+        assert_eq!(newline("  foo\n", 2, SyntaxDefinition::Plaintext), "  \n  foo\n");
+        assert_eq!(newline("  foo\n", 5, SyntaxDefinition::Plaintext), "  foo\n  \n");
+        assert_eq!(newline("\tfn main() {\n", 12, SyntaxDefinition::Rust),
+                   "\tfn main() {\n        \n");
+        assert_eq!(newline("  x = [1,\n", 9, SyntaxDefinition::Python), "  x = [1,\n      \n");
+    }
+
+    #[test]
+    fn autoindent_tab_size() {
+        let view_id = ViewIdentifier::from("view-id-autoindent-tab-size");
+        let mut editor = surround_editor(&view_id, "\tfoo\n", &[(4, 4)]);
+        editor.doc_ctx.set_config(Config { tab_size: 8, ..Config::default() });
+        editor.config_changed();
+        editor.handle_notification(&view_id, EditNotification::InsertNewline);
+        assert_eq!(String::from(&editor.text), "\tfoo\n        \n");
+
+        // as is the tab size of a new editor
+        let doc_ctx = mock_doc_ctx(view_id.as_str());
+        doc_ctx.set_config(Config { tab_size: 2, ..Config::default() });
+        let mut editor = Editor::with_text(doc_ctx, BufferIdentifier::new(1), &view_id,
+                                           "\tfoo\n".to_owned());
+        editor.set_cursor(4);
+        editor.handle_notification(&view_id, EditNotification::InsertNewline);
+        assert_eq!(String::from(&editor.text), "\tfoo\n  \n");
+    }
+
+    #[test]
+    fn autoindent_electric_dedent() {
+        let view_id = ViewIdentifier::from("view-id-autoindent-electric");
+        let close = |text: &str| {
+            let mut editor = surround_editor(&view_id, text, &[(text.len(), text.len())]);
+            editor.syntax = SyntaxDefinition::Rust;
+            editor.handle_notification(&view_id, EditNotification::Insert {
+                chars: "}".to_owned(),
+            });
+            String::from(&editor.text)
+        };
+        assert_eq!(close("fn main() {\n    foo();\n    "), "fn main() {\n    foo();\n}");
+        assert_eq!(close("\tif x {\n\t\tfoo();\n\t\t"), "\tif x {\n\t\tfoo();\n    }");
+        // a closing brace right after the opening one keeps its indent
+        assert_eq!(close("  {\n  "), "  {\n  }");
+        // nor is a brace dedented if the line has other text
+        assert_eq!(close("fn main() {\n    foo();\n    x"),
+                   "fn main() {\n    foo();\n    x}");
+    }
+
+    fn carets(editor: &Editor) -> Vec<usize> {
+        editor.view.sel_regions().iter().map(|region| region.end).collect()
+    }
+
+    #[test]
+    fn copy_block() {
+        let view_id = ViewIdentifier::from("view-id-copy-block");
+        let text = "one two\nthree four\nfive\n";
+        let editor = surround_editor(&view_id, text, &[(4, 7), (12, 15), (23, 23)]);
+        assert_eq!(editor.sel_block(), Some(BlockClipboard {
+            column: 4,
+            segments: vec!["two".to_owned(), "e f".to_owned(), "".to_owned()],
+        }));
+        // without the capability, the text is returned
+        assert_eq!(editor.do_copy(), json!("two\ne f"));
+        let editor = surround_editor(&view_id, text, &[(4, 7), (23, 23)]);
+        assert_eq!(editor.sel_block(), None);
+    }
+
+    #[test]
+    fn paste_block_with_tabs() {
+        let view_id = ViewIdentifier::from("view-id-paste-block-tabs");
+        let text = "abcd\na\tb\nx\n";
+        let mut editor = surround_editor(&view_id, text, &[(2, 2)]);
+        let block = BlockClipboard {
+            column: 0,
+            segments: vec!["1".to_owned(), "2".to_owned(), "3".to_owned()],
+        };
+        editor.handle_notification(&view_id, EditNotification::Paste {
+            chars: "1\n2\n3".to_owned(),
+            block: Some(block.clone()),
+        });
+        // the second segment is padded to column 2, before the tab
+        let pasted = "ab1cd\na 2\tb\nx 3\n";
+        assert_eq!(String::from(&editor.text), pasted);
+        assert_eq!(carets(&editor), vec![pasted.find('3').unwrap() + 1]);
+        // the paste is one undo group
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        assert_eq!(String::from(&editor.text), text);
+
+        // a selection of as many regions is replaced segment for segment
+        let mut editor = surround_editor(&view_id, text, &[(0, 2), (5, 5), (9, 10)]);
+        editor.handle_notification(&view_id, EditNotification::Paste {
+            chars: "1\n2\n3".to_owned(),
+            block: Some(block),
+        });
+        assert_eq!(String::from(&editor.text), "1cd\n2a\tb\n3\n");
+        assert_eq!(carets(&editor), vec![1, 5, 10]);
+    }
+
+    #[test]
+    fn paste_block_at_end() {
+        let view_id = ViewIdentifier::from("view-id-paste-block-end");
+        let mut editor = surround_editor(&view_id, "ab\ncd", &[(5, 5)]);
+        editor.handle_notification(&view_id, EditNotification::Paste {
+            chars: "1\n2\n3".to_owned(),
+            block: Some(BlockClipboard {
+                column: 2,
+                segments: vec!["1".to_owned(), "2".to_owned(), "3".to_owned()],
+            }),
+        });
+        assert_eq!(String::from(&editor.text), "ab\ncd1\n  2\n  3");
+        assert_eq!(carets(&editor), vec![editor.text.len()]);
+
+        // without a block, the text is inserted
+        editor.handle_notification(&view_id, EditNotification::Paste {
+            chars: "\n4".to_owned(),
+            block: None,
+        });
+        assert_eq!(String::from(&editor.text), "ab\ncd1\n  2\n  3\n4");
+    }
+
+    #[test]
+    fn theme_changes_coalesce() {
+        let view_id = ViewIdentifier::from("view-id-theme-changes");
+        let mut editor = surround_editor(&view_id, "fn main() {}\n", &[(0, 0)]);
+        for _ in 0..5 {
+            editor.theme_changed();
+        }
+        while editor.has_background_work() {
+            editor.do_background_work();
+        }
+        assert_eq!(editor.theme_recomputes, 1);
+    }
+
+    #[test]
+    fn builtin_syntax() {
+        use layers::{CoreLayer, LayerId};
+        let syntax = LayerId::Core(CoreLayer::Syntax);
+        let view_id = ViewIdentifier::from("view-id-builtin-syntax");
+        let mut editor = surround_editor(&view_id, "fn main() {}\n", &[(0, 0)]);
+        assert!(!editor.has_background_work());
+        editor._set_path("main.rs");
+        assert!(editor.has_background_work());
+        while editor.has_background_work() {
+            editor.do_background_work();
+        }
+
+        #[cfg(not(feature = "builtin-syntax"))]
+        assert_eq!(editor.styles.layer_usage(syntax), (0, 0));
+        #[cfg(feature = "builtin-syntax")]
+        {
+            let (n_scopes, n_spans) = editor.styles.layer_usage(syntax);
+            assert!(n_scopes > 0 && n_spans > 0);
+            assert!(editor.styles.style_at(0).is_some());
+            let keyword = editor.styles.style_at(0);
+
+            // an edit is parsed again, up to where the parse converges
+            editor.handle_notification(&view_id, EditNotification::Insert {
+                chars: "// x\n".to_owned(),
+            });
+            assert!(editor.has_background_work());
+            while editor.has_background_work() {
+                editor.do_background_work();
+            }
+            assert_ne!(editor.styles.style_at(0), keyword);
+            assert_eq!(editor.styles.style_at(5), keyword);
+
+            // a plugin's scopes replace the layer, until the plugin stops
+            let plugin = PluginPid::new(1);
+            editor.plugin_add_scopes(plugin, vec![vec!["source.rust".to_owned()]]).unwrap();
+            assert_eq!(editor.styles.layer_usage(syntax), (0, 0));
+            assert!(!editor.has_background_work());
+            editor.plugin_stopped(None, "syntect", plugin, 0);
+            assert!(editor.has_background_work());
+        }
+    }
+
+    #[test]
+    fn theme_change_in_chunks() {
+        let view_id = ViewIdentifier::from("view-id-theme-chunks");
+        let text = "fn main() {}\n".repeat(RESTYLE_CHUNK / 4);
+        let mut editor = surround_editor(&view_id, "", &[(0, 0)]);
+        editor.handle_notification(&view_id, EditNotification::Insert { chars: text.clone() });
+        let plugin = PluginPid::new(1);
+        let mut sb = SpansBuilder::new(text.len());
+        sb.add_span(Interval::new_closed_open(0, text.len()), 0);
+        editor.styles.add_scopes(plugin, vec![vec!["comment.line".to_owned()]], &editor.doc_ctx)
+            .unwrap();
+        editor.styles.update_layer(plugin, Interval::new_closed_open(0, text.len()), sb.build(),
+                                   None, None).unwrap();
+        editor.doc_ctx.get_style_map().lock().unwrap().set_theme("Solarized (dark)").unwrap();
+        editor.theme_changed();
+        // each step of background work restyles a chunk, and renders it,
+        // the lines in view first
+        let visible = editor.view.scroll_interval(&editor.text).size();
+        assert!(visible > 0 && visible < RESTYLE_CHUNK);
+        let mut steps = 0;
+        while editor.has_background_work() {
+            let sent_before = frontend_updates(&view_id);
+            editor.do_background_work();
+            assert_eq!(frontend_updates(&view_id), sent_before + 1);
+            steps += 1;
+        }
+        assert_eq!(steps, 1 + (text.len() - visible + RESTYLE_CHUNK - 1) / RESTYLE_CHUNK);
+        assert_eq!(editor.theme_recomputes, 1);
+    }
+
+    /// Applies the ops of `update` to the `lines` of a front-end's cache,
+    /// as the front-end would, returning its new lines.
+    fn apply_update_ops(lines: &[Option<Value>], update: &Value) -> Vec<Option<Value>> {
+        let mut new_lines = Vec::new();
+        let mut old_ix = 0;
+        for op in update["ops"].as_array().unwrap() {
+            let n = op["n"].as_u64().unwrap() as usize;
+            match op["op"].as_str().unwrap() {
+                "copy" => {
+                    new_lines.extend_from_slice(&lines[old_ix..old_ix + n]);
+                    old_ix += n;
+                }
+                "skip" => old_ix += n,
+                "invalidate" => new_lines.extend((0..n).map(|_| None)),
+                "ins" => new_lines.extend(op["lines"].as_array().unwrap().iter().cloned()
+                                          .map(Some)),
+                "update_styles" => for edit in op["lines"].as_array().unwrap() {
+                    let mut line = lines[old_ix].clone().unwrap();
+                    let start = edit["start"].as_u64().unwrap() as usize * 3;
+                    let end = start + edit["remove"].as_u64().unwrap() as usize * 3;
+                    let mut styles = line["styles"].as_array().unwrap().clone();
+                    styles.splice(start..end, edit["styles"].as_array().unwrap().iter().cloned());
+                    line["styles"] = json!(styles);
+                    new_lines.push(Some(line));
+                    old_ix += 1;
+                },
+                op => panic!("unexpected op {}", op),
+            }
+        }
+        new_lines
+    }
+
+    /// Restyles one long line several times with a plugin's spans, returning
+    /// the sizes of the updates sent, and the lines of a front-end which
+    /// applied them.
+    fn restyle_long_line(view_id: &ViewIdentifier, style_diffs: bool)
+                         -> (Vec<usize>, Vec<Option<Value>>) {
+        use tabs::ClientCapabilities;
+
+        let text = "let x = 1; ".repeat(100);
+        let mut editor = surround_editor(view_id, &text, &[(0, 0)]);
+        editor.doc_ctx.set_capabilities(ClientCapabilities {
+            style_diffs: style_diffs,
+            ..ClientCapabilities::default()
+        });
+        editor.render();
+        let plugin = PluginPid::new(1);
+        editor.plugin_add_scopes(plugin, vec![vec!["keyword.other".to_owned()],
+                                              vec!["constant.numeric".to_owned()]]).unwrap();
+        let rev = editor.engine.get_head_rev_id().token();
+        for step in 0..5 {
+            // each step marks the numbers of the next ten statements
+            let mut spans = Vec::new();
+            for i in 0..100 {
+                spans.push(ScopeSpan { start: 11 * i, end: 11 * i + 3, scope_id: 0 });
+                if i / 10 == step {
+                    spans.push(ScopeSpan { start: 11 * i + 8, end: 11 * i + 9, scope_id: 1 });
+                }
+            }
+            editor.plugin_update_spans(plugin, 0, text.len(), spans, rev).unwrap();
+            editor.flush_plugin_styles();
+        }
+
+        let sent = ::std::fs::read_to_string(::std::env::temp_dir().join(view_id.as_str()));
+        let mut lines = Vec::new();
+        let mut sizes = Vec::new();
+        for msg in sent.unwrap().lines().map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .filter(|msg| msg["method"] == json!("update"))
+        {
+            let update = &msg["params"]["update"];
+            sizes.push(update.to_string().len());
+            lines = apply_update_ops(&lines, update);
+        }
+        (sizes, lines)
+    }
+
+    #[test]
+    fn style_diff_updates() {
+        let (full_sizes, full_lines) =
+            restyle_long_line(&ViewIdentifier::from("view-id-restyle-full"), false);
+        let (diff_sizes, diff_lines) =
+            restyle_long_line(&ViewIdentifier::from("view-id-restyle-diffs"), true);
+        assert_eq!(diff_lines, full_lines);
+        assert_eq!(full_lines.len(), 1);
+        assert_eq!(full_lines[0].as_ref().unwrap()["styles"].as_array().unwrap().len(),
+                   3 * 110);
+        // the line is sent in full once, then only its changed spans: all
+        // of them the first time it is restyled, and a few after that
+        assert_eq!(diff_sizes.len(), 6);
+        assert_eq!(full_sizes.len(), 6);
+        assert_eq!(diff_sizes[0], full_sizes[0]);
+        assert!(diff_sizes[1] < full_sizes[1]);
+        for (diff_size, full_size) in diff_sizes.iter().zip(&full_sizes).skip(2) {
+            assert!(diff_size * 4 < *full_size);
+        }
+    }
+
+    #[test]
+    fn evicted_spans_requested_in_view() {
+        use rpc::LineRange;
+
+        let view_id = ViewIdentifier::from("view-id-evicted-spans");
+        let text = "let x = 1;\n".repeat(1000);
+        let mut editor = surround_editor(&view_id, "", &[(0, 0)]);
+        editor.handle_notification(&view_id, EditNotification::Insert { chars: text.clone() });
+        let plugin = PluginPid::new(1);
+        let mut sb = SpansBuilder::new(text.len());
+        for line in 0..1000 {
+            sb.add_span(Interval::new_closed_open(11 * line, 11 * line + 3), 0);
+        }
+        editor.styles.add_scopes(plugin, vec![vec!["keyword.other".to_owned()]], &editor.doc_ctx)
+            .unwrap();
+        editor.styles.update_layer(plugin, Interval::new_closed_open(0, text.len()), sb.build(),
+                                   None, None).unwrap();
+        editor.handle_notification(&view_id, EditNotification::Scroll(LineRange {
+            first: 0, last: 10 }));
+        assert!(editor.take_span_requests().is_empty());
+        let windows = editor.span_windows();
+        assert_eq!(windows, vec![Interval::new_closed_open(0, 220)]);
+        editor.styles.enforce_span_budget(100, &windows);
+        assert!(editor.styles.retained_spans() <= 100);
+
+        // scrolling to the dropped spans asks the plugin for those in view,
+        // and those around them
+        editor.handle_notification(&view_id, EditNotification::Scroll(LineRange {
+            first: 500, last: 510 }));
+        let rev = editor.engine.get_head_rev_id().token();
+        assert_eq!(editor.take_span_requests(),
+                   vec![(plugin, Interval::new_closed_open(5390, 5720), rev)]);
+        assert!(editor.take_span_requests().is_empty());
+    }
+
+    #[test]
+    fn edits_during_chunked_undo() {
+        let view_id = ViewIdentifier::from("view-id-chunked-undo-edits");
+        let mut editor = replaced_editor(&view_id);
+
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        editor.handle_notification(&view_id, EditNotification::MoveToBeginningOfDocument);
+        editor.handle_notification(&view_id, EditNotification::Insert { chars: "x".into() });
+        assert!(editor.has_background_work());
+        // the undo is rolled forward, and then the queued edits are applied
+        editor.handle_notification(&view_id, EditNotification::CancelOperation);
+        assert!(editor.chunked_undo.is_none());
+        assert_eq!(String::from(&editor.text), format!("x{}", "foo\n".repeat(N_LINES)));
+        assert_eq!(editor.line_metadata.trailing_whitespace(&editor.text, 0), 0);
+    }
+
+    fn surround_editor(view_id: &ViewIdentifier, text: &str, regions: &[(usize, usize)])
+                       -> Editor {
+        let mut editor = Editor::with_text(mock_doc_ctx(view_id.as_str()),
+                                           BufferIdentifier::new(1), view_id, text.to_owned());
+        let mut sel = Selection::new();
+        for &(start, end) in regions {
+            sel.add_region(SelRegion { start: start, end: end, horiz: None,
+                                       affinity: Affinity::default() });
+        }
+        editor.view.set_selection(&editor.text, sel);
+        editor
+    }
+
+    fn selected(editor: &Editor) -> Vec<String> {
+        editor.view.sel_regions().iter()
+            .map(|r| editor.text.slice_to_string(r.min(), r.max()))
+            .collect()
+    }
+
+    #[test]
+    fn find_whole_words() {
+        use word_boundaries::DictionarySegmenter;
+        let view_id = ViewIdentifier::from("view-id-find-words");
+        let find = |chars: &str, whole_words| EditRequest::Find {
+            chars: Some(chars.to_owned()), case_sensitive: false, whole_words: whole_words,
+        };
+        let find_next = || EditNotification::FindNext { wrap_around: None, allow_same: None };
+        let selected = |editor: &Editor| {
+            editor.view.sel_regions().iter().map(|r| (r.start, r.end)).collect::<Vec<_>>()
+        };
+        let text = "xian xi 北京大学在北京";
+        let mut editor = surround_editor(&view_id, text, &[(0, 0)]);
+        editor.plugin_set_segmenter(PluginPid::new(1),
+                                    Box::new(DictionarySegmenter::new(&["北京大学", "北京"])));
+
+        editor.handle_request(&view_id, find("xi", true)).unwrap();
+        editor.handle_notification(&view_id, find_next());
+        assert_eq!(selected(&editor), vec![(5, 7)]);
+
+        // the first 北京 is part of the word 北京大学
+        editor.handle_request(&view_id, find("北京", false)).unwrap();
+        editor.handle_notification(&view_id, find_next());
+        assert_eq!(selected(&editor), vec![(8, 14)]);
+        editor.set_cursor(0);
+        editor.handle_request(&view_id, find("北京", true)).unwrap();
+        editor.handle_notification(&view_id, find_next());
+        assert_eq!(selected(&editor), vec![(23, 29)]);
+        editor.set_cursor(0);
+        editor.handle_request(&view_id, find("北京大学", true)).unwrap();
+        editor.handle_notification(&view_id, find_next());
+        assert_eq!(selected(&editor), vec![(8, 20)]);
+    }
+
+    #[test]
+    fn surround() {
+        let view_id = ViewIdentifier::from("view-id-surround");
+        // a selection, and a caret in a word
+        let mut editor = surround_editor(&view_id, "one two three", &[(0, 3), (9, 9)]);
+        let surround = |open: &str, close: Option<&str>| EditNotification::Surround {
+            open: open.into(), close: close.map(|s| s.into()) };
+        editor.handle_notification(&view_id, surround("(", None));
+        assert_eq!(String::from(&editor.text), "(one) two (three)");
+        assert_eq!(selected(&editor), vec!["one", "three"]);
+
+        // regions adjacent to the existing delimiters
+        editor.handle_notification(&view_id, surround("\"", None));
+        assert_eq!(String::from(&editor.text), "(\"one\") two (\"three\")");
+        editor.handle_notification(&view_id, surround("<b>", Some("</b>")));
+        assert_eq!(String::from(&editor.text), "(\"<b>one</b>\") two (\"<b>three</b>\")");
+        assert_eq!(selected(&editor), vec!["one", "three"]);
+
+        // multi-grapheme opens need a close
+        editor.handle_notification(&view_id, surround("<b>", None));
+        assert_eq!(String::from(&editor.text), "(\"<b>one</b>\") two (\"<b>three</b>\")");
+
+        // the whole command is a single undo group
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        assert_eq!(String::from(&editor.text), "(\"one\") two (\"three\")");
+    }
+
+    #[test]
+    fn unsurround() {
+        let view_id = ViewIdentifier::from("view-id-unsurround");
+        // nested quotes, with carets inside the inner and outer pairs
+        let text = r#"say("it's 'fine'", "ok")"#;
+        let mut editor = surround_editor(&view_id, text, &[(12, 12), (20, 20)]);
+        editor.handle_notification(&view_id, EditNotification::Unsurround);
+        assert_eq!(String::from(&editor.text), r#"say("it's fine", ok)"#);
+        editor.handle_notification(&view_id, EditNotification::Unsurround);
+        assert_eq!(String::from(&editor.text), "sayit's fine, ok");
+
+        // a selection which is exactly the contents of a pair, or includes it
+        let mut editor = surround_editor(&view_id, "[(a)] [(b)]", &[(2, 3), (6, 11)]);
+        editor.handle_notification(&view_id, EditNotification::Unsurround);
+        assert_eq!(String::from(&editor.text), "[a] (b)");
+        assert_eq!(selected(&editor), vec!["a", "(b)"]);
+    }
+
+    #[test]
+    fn change_surround() {
+        let view_id = ViewIdentifier::from("view-id-change-surround");
+        let mut editor = surround_editor(&view_id, "f(a, (b)) g('c')", &[(3, 3), (13, 13)]);
+        let change = |from: &str, to: &str| EditNotification::ChangeSurround {
+            from: from.into(), to: to.into() };
+        editor.handle_notification(&view_id, change("(", "["));
+        assert_eq!(String::from(&editor.text), "f[a, (b)] g['c']");
+        editor.handle_notification(&view_id, change("'", "\""));
+        assert_eq!(String::from(&editor.text), "f[a, (b)] g[\"c\"]");
+        assert_eq!(editor.view.sel_regions().len(), 2);
+    }
+
+    #[test]
+    fn enclosing_brackets() {
+        let view_id = ViewIdentifier::from("view-id-brackets");
+        let text = r#"{"a": [1, {"b": "(]"}], "c": [[], [2]]}"#;
+        let caret = text.find('2').unwrap();
+        let select = |include_delimiters| EditNotification::SelectEnclosingBrackets {
+            include_delimiters: include_delimiters };
+        let mut editor = surround_editor(&view_id, text, &[(7, 7), (caret, caret)]);
+        editor.handle_notification(&view_id, select(false));
+        assert_eq!(selected(&editor), vec![r#"1, {"b": "(]"}"#, "2"]);
+        editor.handle_notification(&view_id, select(false));
+        assert_eq!(selected(&editor), vec![&text[1..text.len() - 1]]);
+
+        let mut editor = surround_editor(&view_id, text, &[(caret, caret)]);
+        editor.handle_notification(&view_id, select(false));
+        editor.handle_notification(&view_id, select(false));
+        assert_eq!(selected(&editor), vec!["[], [2]"]);
+        editor.handle_notification(&view_id, select(true));
+        assert_eq!(selected(&editor), vec!["[[], [2]]"]);
+
+        let mut editor = surround_editor(&view_id, text, &[(2, 2), (caret, caret)]);
+        let carets = |editor: &Editor| editor.view.sel_regions().iter()
+            .map(|r| r.end).collect::<Vec<_>>();
+        editor.handle_notification(&view_id, EditNotification::MoveToEnclosingBracketStart);
+        assert_eq!(carets(&editor), vec![0, caret - 1]);
+        editor.handle_notification(&view_id, EditNotification::MoveToEnclosingBracketEnd);
+        assert_eq!(carets(&editor), vec![0, text.len() - 1]);
+    }
+
+    #[test]
+    fn transpose_bracket_groups() {
+        let view_id = ViewIdentifier::from("view-id-transpose-brackets");
+        let mut editor = surround_editor(&view_id, "(a (b) (c d))\n[x] {y}", &[(6, 6), (17, 17)]);
+        editor.handle_notification(&view_id, EditNotification::TransposeBracketGroups);
+        assert_eq!(String::from(&editor.text), "(a (c d) (b))\n{y} [x]");
+        assert_eq!(editor.view.sel_regions().iter().map(|r| r.end).collect::<Vec<_>>(),
+                   vec![12, 21]);
+
+        // unbalanced brackets leave the text and selection alone
+        let mut editor = surround_editor(&view_id, "(a [b) c]", &[(3, 3)]);
+        editor.handle_notification(&view_id, EditNotification::TransposeBracketGroups);
+        editor.handle_notification(&view_id, EditNotification::MoveToEnclosingBracketStart);
+        assert_eq!(String::from(&editor.text), "(a [b) c]");
+        assert_eq!(editor.view.sel_regions()[0].end, 3);
+    }
+
+    #[test]
+    fn reindent() {
+        let view_id = ViewIdentifier::from("view-id-reindent");
+        let text = "a\n\tb\n  \tc\n\t  d\n e\n";
+        let reindent = |scope, snap| EditNotification::Reindent {
+            scope: scope, snap: snap, skip_strings: None,
+        };
+        let mut editor = surround_editor(&view_id, text, &[(0, 0)]);
+        editor.handle_notification(&view_id, reindent(rpc::ReindentScope::Document, false));
+        assert_eq!(String::from(&editor.text), "a\n    b\n    c\n      d\n e\n");
+        // the caret is moved along by the edit
+        let mut editor = surround_editor(&view_id, text, &[(8, 8)]);
+        editor.handle_notification(&view_id, reindent(rpc::ReindentScope::Document, true));
+        assert_eq!(String::from(&editor.text), "a\n    b\n    c\n        d\n    e\n");
+        assert_eq!(editor.view.sel_regions()[0].end, 12);
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        assert_eq!(String::from(&editor.text), text);
+
+        // the lines touched by the selection, but not the line after it
+        let mut editor = surround_editor(&view_id, text, &[(3, 3), (10, 14)]);
+        editor.handle_notification(&view_id, reindent(rpc::ReindentScope::Selection, false));
+        assert_eq!(String::from(&editor.text), "a\n    b\n  \tc\n      d\n e\n");
+
+        // lines starting inside strings are skipped
+        let text = "s = \"x\n\ty\"\n\tz\n";
+        let mut editor = surround_editor(&view_id, text, &[(0, 0)]);
+        let plugin = PluginPid::new(1);
+        editor.plugin_add_scopes(plugin, vec![vec!["source".to_owned()],
+                                              vec!["string.quoted".to_owned()]]).unwrap();
+        let spans = vec![ScopeSpan { start: 4, end: 11, scope_id: 1 }];
+        let rev = editor.engine.get_head_rev_id().token();
+        editor.plugin_update_spans(plugin, 0, text.len(), spans, rev).unwrap();
+        editor.handle_notification(&view_id, reindent(rpc::ReindentScope::Document, false));
+        assert_eq!(String::from(&editor.text), "s = \"x\n\ty\"\n    z\n");
+    }
+
+    #[test]
+    fn batched_spans() {
+        let text = "fn one() {}\nfn two() {}\n";
+        let plugin = PluginPid::new(1);
+        let scopes = || vec![vec!["storage.type".to_owned()],
+                             vec!["entity.name.function".to_owned()]];
+        let updates = || (0..2).map(|line| SpanUpdate {
+            start: line * 12,
+            len: 12,
+            spans: vec![ScopeSpan { start: 0, end: 2, scope_id: 0 },
+                        ScopeSpan { start: 3, end: 6, scope_id: 1 }],
+        }).collect::<Vec<_>>();
+        let styles = |editor: &mut Editor| editor.styles.get_merged().iter()
+            .map(|(iv, style)| (iv, style.clone()))
+            .collect::<Vec<_>>();
+        let editor_for = |view_id: &ViewIdentifier| {
+            let mut editor = surround_editor(view_id, "", &[(0, 0)]);
+            editor.handle_notification(view_id, EditNotification::Insert { chars: text.into() });
+            editor
+        };
+
+        let view_id = ViewIdentifier::from("view-id-unbatched-spans");
+        let mut editor = editor_for(&view_id);
+        let rev = editor.engine.get_head_rev_id().token();
+        let sent_before = frontend_updates(&view_id);
+        editor.plugin_add_scopes(plugin, scopes()).unwrap();
+        for update in updates() {
+            editor.plugin_update_spans(plugin, update.start, update.len, update.spans, rev)
+                .unwrap();
+        }
+        // separate updates are rendered together, once they're flushed
+        assert_eq!(frontend_updates(&view_id), sent_before);
+        editor.flush_plugin_styles();
+        assert_eq!(frontend_updates(&view_id), sent_before + 1);
+        let unbatched = styles(&mut editor);
+        assert_eq!(unbatched.len(), 4);
+
+        let view_id = ViewIdentifier::from("view-id-batched-spans");
+        let mut editor = editor_for(&view_id);
+        let rev = editor.engine.get_head_rev_id().token();
+        let sent_before = frontend_updates(&view_id);
+        editor.plugin_apply_batch(plugin, scopes(), Vec::new(), updates(), rev).unwrap();
+        assert_eq!(frontend_updates(&view_id), sent_before + 1);
+        assert_eq!(styles(&mut editor), unbatched);
+    }
+
+    #[test]
+    fn stale_plugin_updates() {
+        let view_id = ViewIdentifier::from("view-id-stale-plugin-updates");
+        let text = "let x = 1;\n";
+        let mut editor = surround_editor(&view_id, text, &[(0, 0)]);
+        let plugin = PluginPid::new(1);
+        editor.plugin_add_scopes(plugin, vec![vec!["keyword".to_owned()]]).unwrap();
+        let rev = editor.engine.get_head_rev_id().token();
+
+        // "ab " is inserted and "let " deleted before the spans arrive
+        editor.handle_notification(&view_id, EditNotification::Insert { chars: "ab ".into() });
+        for _ in 0..4 {
+            editor.handle_notification(&view_id, EditNotification::DeleteForward);
+        }
+        assert_eq!(String::from(&editor.text), "ab x = 1;\n");
+        let spans = vec![ScopeSpan { start: 0, end: 3, scope_id: 0 },
+                         ScopeSpan { start: 4, end: 5, scope_id: 0 },
+                         ScopeSpan { start: 8, end: 9, scope_id: 0 }];
+        editor.plugin_update_spans(plugin, 0, text.len(), spans, rev).unwrap();
+        let styled = editor.styles.scope_spans_in(plugin, Interval::new_closed_open(0, 10)).unwrap()
+            .map(|(iv, _)| editor.text.slice_to_string(iv.start(), iv.end()))
+            .collect::<Vec<_>>();
+        assert_eq!(styled, vec!["x", "1"]);
+
+        // spans of a revision which is no longer known are rejected
+        let err = editor.plugin_update_spans(plugin, 0, 3, Vec::new(), rev + 1000).unwrap_err();
+        assert!(err.contains("computed again"));
+        assert_eq!(editor.plugin_layer_usage(plugin), (1, 2));
+    }
+
+    #[test]
+    fn plugin_layer_usage() {
+        let view_id = ViewIdentifier::from("view-id-plugin-layer-usage");
+        let text = "one two three\n";
+        let mut editor = surround_editor(&view_id, text, &[(0, 0)]);
+        let plugin = PluginPid::new(1);
+        editor.plugin_add_scopes(plugin, vec![vec!["source".to_owned()],
+                                              vec!["keyword".to_owned()]]).unwrap();
+        let rev = editor.engine.get_head_rev_id().token();
+        let spans = vec![ScopeSpan { start: 0, end: 3, scope_id: 1 },
+                         ScopeSpan { start: 4, end: 7, scope_id: 1 },
+                         ScopeSpan { start: 8, end: 13, scope_id: 0 }];
+        editor.plugin_update_spans(plugin, 0, text.len(), spans, rev).unwrap();
+        assert_eq!(editor.plugin_layer_usage(plugin), (2, 3));
+        // replacing the spans of "two" and "three" with one
+        assert_eq!(editor.plugin_spans_after_update(plugin, 4, 9, 1), 2);
+        assert_eq!(editor.plugin_spans_after_update(plugin, 0, 100, 0), 0);
+
+        // spans with scopes which weren't added are ignored
+        let spans = vec![ScopeSpan { start: 0, end: 3, scope_id: 2 }];
+        editor.plugin_update_spans(plugin, 0, 4, spans, rev).unwrap();
+        assert_eq!(editor.plugin_layer_usage(plugin), (2, 2));
+        assert_eq!(editor.plugin_layer_usage(PluginPid::new(2)), (0, 0));
+
+        let stats = editor.handle_request(&view_id, EditRequest::DebugGetStats).unwrap();
+        assert_eq!(stats["layers"][0]["covered_bytes"], json!(8));
+        assert_eq!(stats["styles"]["layers"][0]["scope_spans"], json!(2));
+        assert_eq!(stats["styles"]["layers"][0]["names"], json!(2));
+    }
+
+    #[test]
+    fn export_html() {
+        let view_id = ViewIdentifier::from("view-id-export-html");
+        let text = "fn <a>\nb\n";
+        let mut editor = surround_editor(&view_id, text, &[(0, 0)]);
+        let export = |editor: &mut Editor| {
+            let html = editor.handle_request(&view_id, EditRequest::ExportHtml { path: None });
+            html.unwrap().as_str().unwrap().to_owned()
+        };
+        let plain = export(&mut editor);
+        assert!(plain.starts_with("<pre style=\"color: #"));
+        assert!(plain.ends_with("\">fn &lt;a&gt;\nb\n</pre>"));
+
+        // the layers overlap in "<a>", whose style is merged from both
+        let (syntax, semantic) = (PluginPid::new(1), PluginPid::new(2));
+        let rev = editor.engine.get_head_rev_id().token();
+        editor.plugin_add_scopes(syntax, vec![vec!["keyword".to_owned()]]).unwrap();
+        let spans = vec![ScopeSpan { start: 0, end: 6, scope_id: 0 }];
+        editor.plugin_update_spans(syntax, 0, text.len(), spans, rev).unwrap();
+        editor.plugin_add_scopes(semantic, vec![vec!["string.quoted".to_owned()]]).unwrap();
+        let spans = vec![ScopeSpan { start: 3, end: 8, scope_id: 0 }];
+        editor.plugin_update_spans(semantic, 0, text.len(), spans, rev).unwrap();
+        let html = export(&mut editor);
+        let body = &html[html.find('>').unwrap() + 1..];
+        assert_eq!(body.matches("<span").count(), 3);
+        assert_eq!(body.matches("</span>").count(), 3);
+        // the span crossing the line break is split, and the break is left out of it
+        assert!(body.starts_with("<span style=\"color: #"));
+        assert!(body.contains("\">fn </span><span style=\""));
+        assert!(body.contains("\">&lt;a&gt;</span>\n<span style=\""));
+        assert!(body.ends_with("\">b</span>\n</pre>"));
+
+        let path = ::std::env::temp_dir().join("xi-export-html-test.html");
+        let written = EditRequest::ExportHtml { path: Some(path.to_str().unwrap().to_owned()) };
+        assert_eq!(editor.handle_request(&view_id, written).unwrap(), Value::Null);
+        assert_eq!(fs::read_to_string(&path).unwrap(), html);
+        fs::remove_file(&path).unwrap();
+        let missing = EditRequest::ExportHtml { path: Some("/nonexistent/dir/x.html".to_owned()) };
+        assert!(editor.handle_request(&view_id, missing).is_err());
+    }
+
+    #[test]
+    fn malformed_plugin_updates() {
+        let view_id = ViewIdentifier::from("view-id-malformed-plugin-updates");
+        let text = "one two three\n";
+        let mut editor = surround_editor(&view_id, text, &[(0, 0)]);
+        let plugin = PluginPid::new(1);
+        editor.plugin_add_scopes(plugin, vec![vec!["keyword".to_owned()]]).unwrap();
+        let rev = editor.engine.get_head_rev_id().token();
+        let spans = vec![ScopeSpan { start: 0, end: 3, scope_id: 0 }];
+        editor.plugin_update_spans(plugin, 0, text.len(), spans, rev).unwrap();
+        let styles = editor.styles.get_merged().iter()
+            .map(|(iv, style)| (iv, style.clone()))
+            .collect::<Vec<_>>();
+        let unchanged = |editor: &mut Editor| editor.styles.get_merged().iter()
+            .map(|(iv, style)| (iv, style.clone()))
+            .eq(styles.iter().cloned());
+
+        // spans out of order or out of bounds are rejected
+        let spans = vec![ScopeSpan { start: 4, end: 7, scope_id: 0 },
+                         ScopeSpan { start: 0, end: 3, scope_id: 0 }];
+        assert!(editor.plugin_update_spans(plugin, 0, text.len(), spans, rev).is_err());
+        let spans = vec![ScopeSpan { start: 4, end: 20, scope_id: 0 }];
+        assert!(editor.plugin_update_spans(plugin, 0, text.len(), spans, rev).is_err());
+        let spans = vec![ScopeSpan { start: 0, end: 3, scope_id: 0 }];
+        assert!(editor.plugin_update_spans(plugin, 12, 10, spans, rev).is_err());
+        assert!(unchanged(&mut editor));
+
+        // the valid updates of a batch are applied
+        let update = |start, len, span_start, span_end| SpanUpdate {
+            start: start,
+            len: len,
+            spans: vec![ScopeSpan { start: span_start, end: span_end, scope_id: 0 }],
+        };
+        let updates = vec![update(4, 3, 0, 3), update(8, 5, 2, 9)];
+        let err = editor.plugin_apply_batch(plugin, Vec::new(), Vec::new(), updates, rev);
+        assert_eq!(err, Err("invalid span 2..9 of a region of 5 bytes".to_owned()));
+        assert_eq!(editor.plugin_layer_usage(plugin), (1, 2));
+    }
+
+    #[test]
+    fn plugin_span_ops() {
+        let view_id = ViewIdentifier::from("view-id-plugin-span-ops");
+        let text = "one two three\n";
+        let mut editor = surround_editor(&view_id, "", &[(0, 0)]);
+        editor.handle_notification(&view_id, EditNotification::Insert { chars: text.to_owned() });
+        let plugin = PluginPid::new(1);
+        editor.plugin_add_scopes(plugin, vec![vec!["source".to_owned()],
+                                              vec!["keyword".to_owned()]]).unwrap();
+        let rev = editor.engine.get_head_rev_id().token();
+        let spans = vec![ScopeSpan { start: 0, end: 3, scope_id: 1 },
+                         ScopeSpan { start: 4, end: 7, scope_id: 1 },
+                         ScopeSpan { start: 8, end: 13, scope_id: 1 }];
+        editor.plugin_update_spans(plugin, 0, text.len(), spans, rev).unwrap();
+        let keyword_at = |editor: &Editor, offset: usize| {
+            editor.styles.scope_names_at(plugin, offset) == Some(&["keyword".to_owned()][..])
+        };
+
+        // "two" is cleared, and "three" replaced, keeping "one"
+        let ops = vec![SpanOp::Retain(4), SpanOp::Skip(3), SpanOp::Retain(1),
+                       SpanOp::Insert { len: 5, spans: vec![ScopeSpan {
+                           start: 0, end: 2, scope_id: 0,
+                       }] }, SpanOp::Retain(1)];
+        assert_eq!(editor.plugin_spans_after_edit(plugin, &ops), 2);
+        editor.plugin_edit_spans(plugin, ops, rev).unwrap();
+        assert!(keyword_at(&editor, 1));
+        assert!(!keyword_at(&editor, 5));
+        assert_eq!(editor.styles.scope_names_at(plugin, 8), Some(&["source".to_owned()][..]));
+        assert_eq!(editor.styles.scope_names_at(plugin, 11), None);
+
+        // ops as of an earlier revision are rebased
+        editor.handle_notification(&view_id, EditNotification::MoveToBeginningOfDocument);
+        editor.handle_notification(&view_id, EditNotification::Insert { chars: "a ".to_owned() });
+        let ops = vec![SpanOp::Retain(4), SpanOp::Insert { len: 3, spans: vec![ScopeSpan {
+            start: 0, end: 3, scope_id: 1,
+        }] }, SpanOp::Retain(7)];
+        editor.plugin_edit_spans(plugin, ops, rev).unwrap();
+        assert!(keyword_at(&editor, 3) && keyword_at(&editor, 7) && !keyword_at(&editor, 9));
+
+        // invalid ops are rejected
+        let rev = editor.engine.get_head_rev_id().token();
+        let err = editor.plugin_edit_spans(plugin, vec![SpanOp::Skip(4)], rev);
+        assert_eq!(err, Err("span ops cover 4 bytes of 16".to_owned()));
+        let spans = vec![ScopeSpan { start: 2, end: 3, scope_id: 0 },
+                         ScopeSpan { start: 0, end: 1, scope_id: 0 }];
+        let err = editor.plugin_edit_spans(plugin, vec![SpanOp::Insert { len: 16, spans }], rev);
+        assert!(err.unwrap_err().contains("invalid span"));
+        assert!(keyword_at(&editor, 3));
+    }
+
+    #[test]
+    fn plugin_layer_priority() {
+        let view_id = ViewIdentifier::from("view-id-plugin-layer-priority");
+        let text = "if x\n";
+        let mut editor = surround_editor(&view_id, "", &[(0, 0)]);
+        editor.handle_notification(&view_id, EditNotification::Insert { chars: text.to_owned() });
+        let (semantic, syntax) = (PluginPid::new(1), PluginPid::new(2));
+        let rev = editor.engine.get_head_rev_id().token();
+        let fg_at_start = |editor: &mut Editor| {
+            editor.styles.get_merged().iter().next().and_then(|(_, style)| style.fg_color)
+        };
+        let mut fgs = Vec::new();
+        // with the same priority, the layer of the later plugin wins
+        for &(plugin, scope) in &[(semantic, "string.quoted"), (syntax, "keyword.control")] {
+            editor.plugin_add_scopes(plugin, vec![vec![scope.to_owned()]]).unwrap();
+            let spans = vec![ScopeSpan { start: 0, end: 2, scope_id: 0 }];
+            editor.plugin_update_spans(plugin, 0, text.len(), spans, rev).unwrap();
+            fgs.push(fg_at_start(&mut editor));
+        }
+        let (semantic_fg, syntax_fg) = (fgs[0], fgs[1]);
+        assert!(semantic_fg.is_some() && syntax_fg.is_some() && semantic_fg != syntax_fg);
+
+        editor.plugin_set_layer_priority(semantic, 1);
+        assert_eq!(fg_at_start(&mut editor), semantic_fg);
+        editor.plugin_set_layer_priority(syntax, 2);
+        assert_eq!(fg_at_start(&mut editor), syntax_fg);
+        // the order is kept as the layers are updated
+        let spans = vec![ScopeSpan { start: 0, end: 2, scope_id: 0 }];
+        editor.plugin_update_spans(semantic, 0, text.len(), spans, rev).unwrap();
+        assert_eq!(fg_at_start(&mut editor), syntax_fg);
+
+        // the character after the caret is inspected by default
+        editor.handle_notification(&view_id, EditNotification::MoveToBeginningOfDocument);
+        let styles = editor.handle_request(&view_id, EditRequest::DebugStyles {
+            start: None, end: None,
+        }).unwrap();
+        assert_eq!((&styles["start"], &styles["end"]), (&json!(0), &json!(1)));
+        let layers = styles["layers"].as_array().unwrap();
+        assert_eq!(layers.iter().map(|layer| layer["plugin"].clone()).collect::<Vec<_>>(),
+                   vec![json!(1), json!(2)]);
+        assert_eq!(layers[1]["spans"][0]["scopes"], json!(["keyword.control"]));
+        let styles = editor.handle_request(&view_id, EditRequest::DebugStyles {
+            start: Some(3), end: Some(100),
+        }).unwrap();
+        assert_eq!(styles["end"], json!(text.len()));
+        assert_eq!(styles["layers"], json!([]));
+    }
+
+    #[test]
+    fn plugin_updates_coalesced() {
+        let view_id = ViewIdentifier::from("view-id-plugin-updates-coalesced");
+        let text = "foo\n".repeat(50);
+        let mut editor = Editor::with_text(mock_doc_ctx(view_id.as_str()),
+                                           BufferIdentifier::new(1), &view_id, text.clone());
+        editor.render();
+        let plugin = PluginPid::new(1);
+        editor.plugin_add_scopes(plugin, vec![vec!["string.quoted".to_owned()]]).unwrap();
+        let rev = editor.engine.get_head_rev_id().token();
+        let sent_before = frontend_updates(&view_id);
+        for line in 0..50 {
+            let spans = vec![ScopeSpan { start: 0, end: 3, scope_id: 0 }];
+            editor.plugin_update_spans(plugin, line * 4, 4, spans, rev).unwrap();
+        }
+        assert_eq!(frontend_updates(&view_id), sent_before);
+        editor.flush_plugin_styles();
+        assert_eq!(frontend_updates(&view_id), sent_before + 1);
+        editor.flush_plugin_styles();
+        assert_eq!(frontend_updates(&view_id), sent_before + 1);
+        let styled = editor.styles.get_merged().iter()
+            .map(|(iv, _)| (iv.start(), iv.end()))
+            .collect::<Vec<_>>();
+        assert_eq!(styled.len(), 50);
+        assert_eq!(styled[49], (196, 199));
+
+        // an edit renders the pending styles with it
+        let spans = vec![ScopeSpan { start: 0, end: 4, scope_id: 0 }];
+        editor.plugin_update_spans(plugin, 0, 4, spans, rev).unwrap();
+        editor.handle_notification(&view_id, EditNotification::Insert { chars: "x".into() });
+        assert!(editor.pending_styles.is_none());
+        assert!(frontend_updates(&view_id) > sent_before + 1);
+    }
+
+    #[test]
+    fn show_invisibles() {
+        use layers::{CoreLayer, LayerId};
+        let invisibles = LayerId::Core(CoreLayer::Invisibles);
+        let view_id = ViewIdentifier::from("view-id-show-invisibles");
+        let mut editor = surround_editor(&view_id, "\tfoo  bar\n", &[(6, 6)]);
+        let marked = |editor: &Editor| editor.styles
+            .scope_spans_in(invisibles, Interval::new_closed_open(0, editor.text.len()))
+            .map_or(Vec::new(), |spans| {
+                spans.map(|(iv, scopes)| (iv.start(), iv.end(), scopes[0].clone())).collect()
+            });
+        editor.handle_notification(&view_id, EditNotification::ToggleShowInvisibles);
+        assert_eq!(marked(&editor), vec![(0, 1, "invisible.tab".to_owned())]);
+        let tab_style = editor.styles.style_at(0).unwrap();
+        assert!(tab_style.fg_color.is_some());
+
+        // the spaces before an inserted newline now trail
+        editor.handle_notification(&view_id, EditNotification::Insert { chars: "\n".into() });
+        assert_eq!(String::from(&editor.text), "\tfoo  \nbar\n");
+        assert_eq!(marked(&editor), vec![(0, 1, "invisible.tab".to_owned()),
+                                         (4, 6, "invisible.trailing-space".to_owned())]);
+        editor.handle_notification(&view_id, EditNotification::DeleteBackward);
+        assert_eq!(marked(&editor), vec![(0, 1, "invisible.tab".to_owned())]);
+
+        editor.handle_notification(&view_id, EditNotification::ToggleShowInvisibles);
+        assert!(editor.styles.scope_spans_in(invisibles, Interval::new_closed_open(0, 1))
+                .is_none());
+        assert_eq!(editor.styles.style_at(0), None);
+    }
+
+    #[test]
+    fn select_scope() {
+        let view_id = ViewIdentifier::from("view-id-select-scope");
+        let text = "let s = \"a\"; // \"b\"\n";
+        let mut editor = surround_editor(&view_id, "", &[(0, 0)]);
+        editor.handle_notification(&view_id, EditNotification::Insert { chars: text.to_owned() });
+        let rev = editor.engine.get_head_rev_id().token();
+        let (syntax, semantic) = (PluginPid::new(1), PluginPid::new(2));
+        editor.plugin_add_scopes(syntax, vec![
+            vec!["source.rust".to_owned(), "string.quoted.double".to_owned()],
+            vec!["source.rust".to_owned(), "comment.line".to_owned()],
+        ]).unwrap();
+        let spans = vec![ScopeSpan { start: 8, end: 11, scope_id: 0 },
+                         ScopeSpan { start: 13, end: 20, scope_id: 1 }];
+        editor.plugin_update_spans(syntax, 0, text.len(), spans, rev).unwrap();
+        editor.plugin_add_scopes(semantic, vec![vec!["string.quoted".to_owned()]]).unwrap();
+        let spans = vec![ScopeSpan { start: 16, end: 19, scope_id: 0 }];
+        editor.plugin_update_spans(semantic, 0, text.len(), spans, rev).unwrap();
+
+        let selected = |editor: &Editor| editor.view.sel_regions().iter()
+            .map(|region| (region.min(), region.max()))
+            .collect::<Vec<_>>();
+        let select = |selector: &str| EditNotification::SelectScope { selector: selector.into() };
+        editor.handle_notification(&view_id, select("string"));
+        assert_eq!(selected(&editor), vec![(8, 11), (16, 19)]);
+        // the selection is kept if nothing matches, or the selector is invalid
+        editor.handle_notification(&view_id, select("string - string.quoted"));
+        editor.handle_notification(&view_id, select("a.b.c.d.e.f.g.h.i"));
+        assert_eq!(selected(&editor), vec![(8, 11), (16, 19)]);
+        editor.handle_notification(&view_id, select("comment, string.quoted.double"));
+        assert_eq!(selected(&editor), vec![(8, 11), (13, 20)]);
+
+        assert_eq!(editor.plugin_find_scopes("string", 10, 100).unwrap(), json!([
+            {"start": 10, "end": 11, "plugin": 1},
+            {"start": 16, "end": 19, "plugin": 2},
+        ]));
+        assert!(editor.plugin_find_scopes("a.b.c.d.e.f.g.h.i", 0, 100).is_err());
+    }
+
+    #[test]
+    fn chunked_reindent() {
+        let view_id = ViewIdentifier::from("view-id-chunked-reindent");
+        let text = "\tx\n".repeat(N_LINES);
+        let mut editor = Editor::with_text(mock_doc_ctx(view_id.as_str()),
+                                           BufferIdentifier::new(1), &view_id, text);
+        editor.handle_notification(&view_id, EditNotification::Reindent {
+            scope: rpc::ReindentScope::Document, snap: false, skip_strings: None,
+        });
+        assert!(editor.has_background_work());
+        editor.do_background_work();
+        editor.handle_notification(&view_id, EditNotification::CancelOperation);
+        assert!(!editor.has_background_work());
+        assert_eq!(String::from(&editor.text), "    x\n".repeat(N_LINES));
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        editor.finish_chunked_undo();
+        assert_eq!(String::from(&editor.text), "\tx\n".repeat(N_LINES));
+    }
+
+    #[test]
+    fn spellcheck_scopes() {
+        let view_id = ViewIdentifier::from("view-id-spellcheck");
+        let text = "// helo wrld\nlet s = \"speling 42\";\n";
+        let mut editor = surround_editor(&view_id, text, &[(0, 0)]);
+        let words = |editor: &Editor, config: &SpellcheckConfig| {
+            editor.words_to_check(config, 0, text.len()).into_iter()
+                .map(|(_, _, word)| word).collect::<Vec<_>>()
+        };
+        let mut config = SpellcheckConfig::default();
+        config.included_scopes = vec!["comment".to_owned(), "string".to_owned()];
+        // without scopes, every word is checked
+        assert_eq!(words(&editor, &config), vec!["helo", "wrld", "let", "s", "speling"]);
+
+        let plugin = PluginPid::new(1);
+        let scopes = vec![vec!["source.rust"], vec!["source.rust", "comment.line"],
+                          vec!["source.rust", "string.quoted.double"],
+                          vec!["source.rust", "string.quoted.double", "constant.numeric"]];
+        editor.plugin_add_scopes(plugin, scopes.into_iter()
+                                 .map(|s| s.into_iter().map(str::to_owned).collect()).collect())
+                                     .unwrap();
+        let span = |start: usize, end: usize, scope_id: u32| {
+            ScopeSpan { start: start, end: end, scope_id: scope_id }
+        };
+        let string = text.find('"').unwrap();
+        let spans = vec![span(0, 12, 1), span(12, string, 0), span(string, string + 9, 2),
+                         span(string + 9, string + 11, 3), span(string + 11, text.len(), 0)];
+        let rev = editor.engine.get_head_rev_id().token();
+        editor.plugin_update_spans(plugin, 0, text.len(), spans, rev).unwrap();
+        assert_eq!(words(&editor, &config), vec!["helo", "wrld", "speling"]);
+
+        // the config and the dictionaries are consulted for each request
+        config.excluded_scopes = vec!["comment.line".to_owned()];
+        assert_eq!(words(&editor, &config), vec!["speling"]);
+        editor.add_word_to_dictionary("speling", DictionaryScope::Buffer).unwrap();
+        assert!(words(&editor, &config).is_empty());
+        assert!(editor.add_word_to_dictionary("two words", DictionaryScope::Buffer).is_err());
+        assert!(editor.add_word_to_dictionary("xi", DictionaryScope::Project).is_err());
+
+        let result = editor.plugin_get_words(0, 12);
+        assert_eq!(result["language"], json!("en_US"));
+        assert_eq!(result["words"][1], json!({"start": 8, "end": 12, "word": "wrld"}));
+    }
+
+    fn plugin_edit(rev: u64, start: u64, end: u64, text: &str, author: &str) -> PluginEdit {
+        PluginEdit { start: start, end: end, rev: rev, text: text.to_owned(),
+                     priority: 0, after_cursor: false, author: author.to_owned() }
+    }
+
+    /// Sends a formatter's and a fixer's responses to the same revision,
+    /// in the given order, returning the text and the rejected edits.
+    fn concurrent_plugin_edits(fixer_first: bool, fixer_edits: &[(u64, u64, &str)])
+                               -> (String, Vec<(PluginPid, String)>) {
+        let view_id = ViewIdentifier::from("view-id-plugin-edits");
+        let mut editor = surround_editor(&view_id, "let x=1;\nlet y=2;\n", &[(0, 0)]);
+        let rev = editor.engine.get_head_rev_id().token();
+        let (formatter, fixer) = (PluginPid::new(1), PluginPid::new(2));
+        let now = Instant::now();
+        editor.expect_plugin_response(rev, formatter, "formatter", 10, 1, now);
+        editor.expect_plugin_response(rev, fixer, "fixer", 0, 1, now);
+        let format = vec![plugin_edit(rev, 0, 8, "let x = 1;", "formatter")];
+        let fix = fixer_edits.iter()
+            .map(|&(start, end, text)| plugin_edit(rev, start, end, text, "fixer"))
+            .collect::<Vec<_>>();
+        let mut responses = vec![(formatter, "formatter", 10, format), (fixer, "fixer", 0, fix)];
+        if fixer_first {
+            responses.reverse();
+        }
+        let mut conflicts = Vec::new();
+        for (pid, name, priority, edits) in responses {
+            // a response carries a single edit; send the rest as extra responses
+            for edit in edits.iter().skip(1) {
+                editor.expect_plugin_response(rev, pid, name, priority, 1, now);
+                conflicts.extend(editor.plugin_responded(rev, pid, Some(edit.clone()), now));
+            }
+            conflicts.extend(editor.plugin_responded(rev, pid, edits.into_iter().next(), now));
+        }
+        let conflicts = conflicts.into_iter()
+            .map(|c| (c.plugin, c.conflicts_with))
+            .collect();
+        (String::from(&editor.text), conflicts)
+    }
+
+    #[test]
+    fn concurrent_plugin_edits_are_ordered() {
+        // the fixer's edit of the same line is rejected, whichever arrives first
+        for &fixer_first in [false, true].iter() {
+            let (text, conflicts) = concurrent_plugin_edits(fixer_first, &[(4, 5, "z")]);
+            assert_eq!(text, "let x = 1;\nlet y=2;\n");
+            assert_eq!(conflicts, vec![(PluginPid::new(2), "formatter".to_owned())]);
+        }
+        // edits of other lines are both applied
+        for &fixer_first in [false, true].iter() {
+            let (text, conflicts) = concurrent_plugin_edits(fixer_first, &[(15, 16, "2;")]);
+            assert_eq!(text, "let x = 1;\nlet y=2;;\n");
+            assert!(conflicts.is_empty());
+        }
+        // an identical edit is applied once, and insertions at the same
+        // offset conflict
+        for &fixer_first in [false, true].iter() {
+            let (text, conflicts) = concurrent_plugin_edits(
+                fixer_first, &[(0, 8, "let x = 1;"), (0, 0, "// ")]);
+            assert_eq!(text, "let x = 1;\nlet y=2;\n");
+            assert_eq!(conflicts.len(), 1);
+        }
+    }
+
+    #[test]
+    fn hung_plugins_dont_hold_back_edits() {
+        let view_id = ViewIdentifier::from("view-id-hung-plugins");
+        let mut editor = surround_editor(&view_id, "let x=1;\nlet y=2;\n", &[(0, 0)]);
+        let (formatter, fixer) = (PluginPid::new(1), PluginPid::new(2));
+        let now = Instant::now();
+
+        // an edit is applied once the plugins ahead of it have responded
+        let rev = editor.engine.get_head_rev_id().token();
+        editor.expect_plugin_response(rev, formatter, "formatter", 10, 1, now);
+        editor.expect_plugin_response(rev, fixer, "fixer", 0, 1, now);
+        let fix = plugin_edit(rev, 15, 16, "2;", "fixer");
+        assert!(editor.plugin_responded(rev, fixer, Some(fix), now).is_empty());
+        assert_eq!(String::from(&editor.text), "let x=1;\nlet y=2;\n");
+        // or once they have stopped
+        editor.plugin_stopped(None, "formatter", formatter, 1);
+        assert_eq!(String::from(&editor.text), "let x=1;\nlet y=2;;\n");
+        assert!(editor.plugin_edit_windows.is_empty());
+
+        // the plugins behind a hung plugin don't wait for it
+        let rev = editor.engine.get_head_rev_id().token();
+        editor.expect_plugin_response(rev, formatter, "formatter", 10, 2, now);
+        editor.expect_plugin_response(rev, fixer, "fixer", 0, 2, now);
+        let format = plugin_edit(rev, 0, 8, "let x = 1;", "formatter");
+        editor.plugin_responded(rev, formatter, Some(format), now);
+        assert_eq!(String::from(&editor.text), "let x = 1;\nlet y=2;;\n");
+
+        // the plugins ahead of it wait until it times out, after which its
+        // response is dropped
+        let rev = editor.engine.get_head_rev_id().token();
+        editor.expect_plugin_response(rev, formatter, "formatter", 10, 3, now);
+        editor.expect_plugin_response(rev, fixer, "fixer", 0, 3, now);
+        let fix = plugin_edit(rev, 0, 0, "// ", "fixer");
+        editor.plugin_responded(rev, fixer, Some(fix), now);
+        assert_eq!(String::from(&editor.text), "let x = 1;\nlet y=2;;\n");
+        let later = now + Duration::from_millis(PLUGIN_EDIT_WINDOW_TIMEOUT);
+        let format = plugin_edit(rev, 0, 0, "/* */", "formatter");
+        editor.plugin_responded(rev, formatter, Some(format), later);
+        assert_eq!(String::from(&editor.text), "// let x = 1;\nlet y=2;;\n");
+        assert!(editor.plugin_edit_windows.is_empty());
+
+        // as do the responses to revisions which fall too far behind
+        let first = editor.engine.get_head_rev_id().token();
+        let fix = plugin_edit(first, 0, 0, "/", "fixer");
+        for i in 0..PLUGIN_EDIT_WINDOW_REVS as u64 {
+            editor.expect_plugin_response(first + i, formatter, "formatter", 10, 4, later);
+            editor.expect_plugin_response(first + i, fixer, "fixer", 0, 4, later);
+        }
+        editor.plugin_responded(first, fixer, Some(fix), later);
+        assert_eq!(String::from(&editor.text), "// let x = 1;\nlet y=2;;\n");
+        let rev = first + PLUGIN_EDIT_WINDOW_REVS as u64;
+        editor.expect_plugin_response(rev, fixer, "fixer", 0, 4, later);
+        assert_eq!(String::from(&editor.text), "/// let x = 1;\nlet y=2;;\n");
+        assert_eq!(editor.plugin_edit_windows.len(), PLUGIN_EDIT_WINDOW_REVS);
+    }
+
+    fn preview_replace(editor: &mut Editor, view_id: &ViewIdentifier, query: &str,
+                       replacement: &str, max_results: Option<usize>) -> Value {
+        let req = EditRequest::PreviewReplace {
+            query: query.into(), replacement: replacement.into(),
+            options: None, max_results: max_results,
+        };
+        editor.handle_request(view_id, req).unwrap()
+    }
+
+    fn replace_all(editor: &mut Editor, view_id: &ViewIdentifier, token: Option<u64>,
+                   query: Option<&str>, replacement: &str) -> Result<Value, RemoteError> {
+        let req = EditRequest::ReplaceAll {
+            token: token, query: query.map(|q| q.into()),
+            replacement: Some(replacement.into()), options: None,
+        };
+        editor.handle_request(view_id, req)
+    }
+
+    #[test]
+    fn replace_preview_matches_replace_all() {
+        let view_id = ViewIdentifier::from("view-id-replace");
+        let text = "Foo bar\nbaz foo foo\n";
+        let mut editor = surround_editor(&view_id, text, &[(0, 0)]);
+        let preview = preview_replace(&mut editor, &view_id, "foo", "[$0]", Some(2));
+        assert_eq!(preview["total"], json!(3));
+        assert_eq!(preview["matches"], json!([
+            {"line": 0, "col": 0, "original": "Foo", "replacement": "[Foo]",
+             "context_line": "Foo bar"},
+            {"line": 1, "col": 4, "original": "foo", "replacement": "[foo]",
+             "context_line": "baz foo foo"},
+        ]));
+
+        // the token applies every previewed replacement, despite max_results
+        let token = preview["token"].as_u64();
+        let result = replace_all(&mut editor, &view_id, token, None, "").unwrap();
+        assert_eq!(result, json!({"replaced": 3}));
+        let previewed = String::from(&editor.text);
+        assert_eq!(previewed, "[Foo] bar\nbaz [foo] [foo]\n");
+
+        let mut editor = surround_editor(&view_id, text, &[(0, 0)]);
+        replace_all(&mut editor, &view_id, None, Some("foo"), "[$0]").unwrap();
+        assert_eq!(String::from(&editor.text), previewed);
+        // tokens are used once
+        assert!(replace_all(&mut editor, &view_id, token, None, "").is_err());
+    }
+
+    #[test]
+    fn stale_replace_preview() {
+        let view_id = ViewIdentifier::from("view-id-replace");
+        let mut editor = surround_editor(&view_id, "foo foo", &[(0, 0)]);
+        let preview = preview_replace(&mut editor, &view_id, "foo", "bar", None);
+        let token = preview["token"].as_u64();
+        editor.handle_notification(&view_id, EditNotification::Insert { chars: "x".into() });
+        assert_eq!(String::from(&editor.text), "xfoo foo");
+        assert!(replace_all(&mut editor, &view_id, token, None, "").is_err());
+        assert_eq!(String::from(&editor.text), "xfoo foo");
+
+        // a new preview can be applied
+        let preview = preview_replace(&mut editor, &view_id, "foo", "bar", None);
+        let token = preview["token"].as_u64();
+        assert!(replace_all(&mut editor, &view_id, Some(12345), None, "").is_err());
+        replace_all(&mut editor, &view_id, token, None, "").unwrap();
+        assert_eq!(String::from(&editor.text), "xbar bar");
+    }
+
+    fn transform(editor: &mut Editor, view_id: &ViewIdentifier, expr: &str,
+                 on_error: Option<OnError>) -> Result<Value, RemoteError> {
+        let req = EditRequest::TransformSelectionWithExpr {
+            expr: expr.into(), on_error: on_error,
+        };
+        editor.handle_request(view_id, req)
+    }
+
+    #[test]
+    fn transform_selection_enumerates() {
+        let view_id = ViewIdentifier::from("view-id-transform");
+        let text = "5 5 5 5 5 5 5 5 5 5";
+        let regions = (0..10).map(|i| (i * 2, i * 2 + 1)).collect::<Vec<_>>();
+        let mut editor = surround_editor(&view_id, text, &regions);
+        let result = transform(&mut editor, &view_id, "n*2", None).unwrap();
+        assert_eq!(result, json!({"transformed": 10, "errors": []}));
+        assert_eq!(String::from(&editor.text), "10 10 10 10 10 10 10 10 10 10");
+        assert_eq!(selected(&editor), vec!["10"; 10]);
+
+        // carets are numbered, and the whole transform is undone at once
+        let carets = (0..10).map(|i| (i * 3, i * 3)).collect::<Vec<_>>();
+        editor.view.set_selection(&editor.text, {
+            let mut sel = Selection::new();
+            for (start, end) in carets {
+                sel.add_region(SelRegion { start: start, end: end, horiz: None,
+                                           affinity: Affinity::default() });
+            }
+            sel
+        });
+        transform(&mut editor, &view_id, "pad(i + 1, 2, '0') + ':'", None).unwrap();
+        assert!(String::from(&editor.text).starts_with("01:10 02:10 03:10"));
+        assert!(String::from(&editor.text).ends_with("10:10"));
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        assert_eq!(String::from(&editor.text), "10 10 10 10 10 10 10 10 10 10");
+    }
+
+    #[test]
+    fn transform_selection_errors() {
+        let view_id = ViewIdentifier::from("view-id-transform-errors");
+        let text = "1 two 3 four";
+        let regions = [(0, 1), (2, 5), (6, 7), (8, 12)];
+        let mut editor = surround_editor(&view_id, text, &regions);
+        let result = transform(&mut editor, &view_id, "n + 100", None).unwrap();
+        assert_eq!(result["transformed"], json!(2));
+        assert_eq!(result["errors"].as_array().unwrap().iter()
+                   .map(|err| err["region"].as_u64().unwrap()).collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(result["errors"][0]["error"], json!("\"two\" isn't a number"));
+        assert_eq!(String::from(&editor.text), "101 two 103 four");
+        // the regions which failed are still selected
+        assert_eq!(selected(&editor), vec!["101", "two", "103", "four"]);
+
+        let result = transform(&mut editor, &view_id, "upper(n)", Some(OnError::Abort));
+        assert_eq!(result.unwrap()["transformed"], json!(0));
+        assert_eq!(String::from(&editor.text), "101 two 103 four");
+
+        let err = transform(&mut editor, &view_id, "upper(s) +", None).unwrap_err();
+        match err {
+            RemoteError::Custom { code: 3, data: Some(ref data), .. } => {
+                assert_eq!(data["position"], json!(10));
+                assert_eq!(data["message"]["id"], json!("expr_invalid"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn jumps_across_edits() {
+        let view_id = ViewIdentifier::from("view-id-jumps");
+        let text = (0..100).map(|i| format!("line {}\n", i)).collect::<String>();
+        let mut editor = surround_editor(&view_id, &text, &[(7, 7)]);
+        let line_start = |editor: &Editor, line| editor.text.offset_of_line(line);
+        editor.handle_notification(&view_id, EditNotification::GotoLine { line: 50 });
+        // small moves aren't jumps
+        editor.handle_notification(&view_id, EditNotification::GotoLine { line: 55 });
+        editor.handle_notification(&view_id, EditNotification::MoveDown);
+        assert_eq!(editor.view.jump_list().entries(), &[7]);
+
+        // inserting lines above both positions, and deleting the line
+        // jumped from
+        let mut builder = delta::Builder::new(editor.text.len());
+        builder.replace(Interval::new_closed_open(0, 0), Rope::from("new\nnew\n"));
+        builder.delete(Interval::new_closed_open(0, 7));
+        editor.add_delta(builder.build());
+        editor.commit_delta(None);
+        assert_eq!(editor.caret(), line_start(&editor, 57));
+
+        editor.handle_notification(&view_id, EditNotification::JumpBack);
+        assert_eq!(editor.caret(), 8);
+        let jumps = editor.handle_request(&view_id, EditRequest::GetJumpList).unwrap();
+        assert_eq!(jumps, json!({
+            "jumps": [{"line": 2, "col": 0}, {"line": 57, "col": 0}],
+            "current": 0,
+        }));
+        editor.handle_notification(&view_id, EditNotification::JumpForward);
+        assert_eq!(editor.caret(), line_start(&editor, 57));
+        editor.handle_notification(&view_id, EditNotification::JumpForward);
+        assert_eq!(editor.caret(), line_start(&editor, 57));
+
+        // jumping from the middle of the list discards the rest
+        editor.handle_notification(&view_id, EditNotification::JumpBack);
+        editor.handle_notification(&view_id, EditNotification::MoveToEndOfDocument);
+        assert_eq!(editor.view.jump_list().entries(), &[8]);
+        editor.handle_notification(&view_id, EditNotification::JumpBack);
+        assert_eq!(editor.caret(), 8);
+        editor.handle_notification(&view_id, EditNotification::JumpForward);
+        assert_eq!(editor.caret(), editor.text.len());
+    }
+
+    #[test]
+    fn jumps_scroll_to_center() {
+        let view_id = ViewIdentifier::from("view-id-jumps-center");
+        let text = (0..100).map(|i| format!("line {}\n", i)).collect::<String>();
+        let mut editor = surround_editor(&view_id, &text, &[(0, 0)]);
+        editor.view.set_scroll(0, 20);
+        editor.handle_notification(&view_id, EditNotification::GotoLine { line: 80 });
+        editor.handle_notification(&view_id, EditNotification::GotoLine { line: 40 });
+        editor.handle_notification(&view_id, EditNotification::JumpBack);
+        assert_eq!(editor.caret(), editor.text.offset_of_line(80));
+        let visible = editor.view.scroll_interval(&editor.text);
+        assert_eq!(editor.text.line_of_offset(visible.start()), 70);
+
+        let sent = ::std::fs::read_to_string(::std::env::temp_dir().join(view_id.as_str()))
+            .unwrap();
+        let scrolls = sent.lines()
+            .filter(|line| line.contains("\"method\":\"scroll_to\""))
+            .map(|line| line.contains("\"center\":true"))
+            .collect::<Vec<_>>();
+        // only the jump is centered, not the moves jumped between
+        assert_eq!(scrolls.last(), Some(&true));
+        assert_eq!(scrolls.iter().filter(|&&center| center).count(), 1);
+    }
+
+    /// Returns the number of updates sent to the front-end by an editor with
+    /// a mock `DocumentCtx` for `view_id`.
+    fn frontend_updates(view_id: &ViewIdentifier) -> usize {
+        let sent = ::std::fs::read_to_string(::std::env::temp_dir().join(view_id.as_str()));
+        sent.unwrap().lines().filter(|line| line.contains("\"method\":\"update\"")).count()
+    }
+
+    /// Replaces the "a" at the start of each of `lines` with "b", as
+    /// separate deltas.
+    fn replace_line_starts(editor: &mut Editor, lines: ::std::ops::Range<usize>) {
+        for line in lines {
+            let offset = editor.text.offset_of_line(line);
+            let iv = Interval::new_closed_open(offset, offset + 1);
+            editor.add_delta(Delta::simple_edit(iv, Rope::from("b"), editor.text.len()));
+            editor.commit_delta(None);
+        }
+    }
+
+    #[test]
+    fn bulk_edits() {
+        let view_id = ViewIdentifier::from("view-id-bulk-edits");
+        let (doc_ctx, updates) = mock_doc_ctx_with_updates(view_id.as_str());
+        let mut editor = Editor::with_text(doc_ctx, BufferIdentifier::new(1), &view_id,
+                                           "a\n".repeat(100));
+        replace_line_starts(&mut editor, 0..50);
+        assert_eq!(updates.try_iter().count(), 50);
+
+        editor.begin_bulk_edit();
+        replace_line_starts(&mut editor, 50..99);
+        // bulk edits nest
+        editor.bulk_edit_if(true, |ed| replace_line_starts(ed, 99..100));
+        editor.render();
+        assert_eq!(updates.try_iter().count(), 0);
+        assert_eq!(frontend_updates(&view_id), 0);
+        editor.end_bulk_edit();
+        let sent = updates.try_iter().collect::<Vec<_>>();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1.bulk_edit_params().unwrap(), json!({
+            "view_id": "view-id-bulk-edits",
+            "rev": editor.engine.get_head_rev_id().token(),
+            "composed_delta": { "start": 100, "end": 199, "new_len": 99 },
+            "n_edits": 50,
+        }));
+        assert_eq!(frontend_updates(&view_id), 1);
+        assert_eq!(String::from(&editor.text), "b\n".repeat(100));
+
+        // an operation failing midway still ends its bulk edit
+        let result: Result<(), ()> = editor.bulk_edit_if(true, |ed| {
+            ed.add_delta(Delta::simple_edit(Interval::new_closed_open(0, 1), Rope::from("c"),
+                                            ed.text.len()));
+            Err(())
+        });
+        assert!(result.is_err());
+        assert!(editor.bulk_edit.is_none());
+        let sent = updates.try_iter().collect::<Vec<_>>();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1.bulk_edit_params().unwrap()["n_edits"], json!(1));
+        // and an empty bulk edit sends nothing
+        editor.bulk_edit_if(true, |_| ());
+        assert_eq!(updates.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn scratch_buffer() {
+        let view_id = ViewIdentifier::from("view-id-scratch");
+        let mut editor = surround_editor(&view_id, "", &[(0, 0)]);
+        editor.make_scratch(Scratch { title: "results".to_owned(), read_only: true });
+        let style = ScratchStyle {
+            start: 0, end: 4, fg_color: Some(0xffc0_0000), bg_color: None,
+            weight: Some(700), underline: None, italic: None,
+        };
+        // 100k lines, appended as output would be, ten at a time
+        for i in 0..10_000 {
+            let lines = (0..10).map(|j| format!("line {}\n", i * 10 + j)).collect::<String>();
+            let styles = (0..10).map(|j| ScratchStyle {
+                start: lines.lines().take(j).map(|line| line.len() + 1).sum(),
+                end: lines.lines().take(j).map(|line| line.len() + 1).sum::<usize>() + 4,
+                ..style.clone()
+            }).collect::<Vec<_>>();
+            editor.scratch_append(&lines, &styles).unwrap();
+        }
+        assert_eq!(editor.text.measure::<LinesMetric>(), 100_000);
+        // the history doesn't grow with the appends
+        assert!(editor.engine.revision_count() <= 2);
+        assert!(editor.live_undos.is_empty() && editor.gc_undos.len() <= 1);
+        let styles = editor.styles.get_merged();
+        assert_eq!(styles.count(), 100_000);
+        let (iv, first) = styles.iter().next().unwrap();
+        assert_eq!((iv.start(), iv.end(), first.clone()), (0, 4, style.style()));
+
+        // the user can't edit or undo it
+        editor.handle_notification(&view_id, EditNotification::Insert { chars: "x".into() });
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        assert_eq!(editor.text.measure::<LinesMetric>(), 100_000);
+        assert!(!editor.needs_auto_save());
+        let info = editor.buffer_info();
+        assert_eq!((info.display_name.as_str(), info.read_only, info.scratch),
+                   ("results", true, true));
+        let result = editor.handle_request(&view_id, EditRequest::ExportHistory {
+            since_rev: None,
+        });
+        assert!(result.is_err());
+
+        // its content is replaced, styles and all
+        editor.scratch_set_content("done", &[]).unwrap();
+        assert_eq!(String::from(&editor.text), "done");
+        assert_eq!(editor.styles.get_merged().count(), 0);
+        assert!(editor.engine.revision_count() <= 2);
+        assert!(editor.scratch_append("x", &[ScratchStyle { end: 2, ..style }]).is_err());
+
+        // unless it is read-only, the user can edit it too, without undo
+        let mut editor = surround_editor(&view_id, "", &[(0, 0)]);
+        editor.make_scratch(Scratch { title: "output".to_owned(), read_only: false });
+        editor.scratch_append("ab", &[]).unwrap();
+        editor.handle_notification(&view_id, EditNotification::Insert { chars: "x".into() });
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        assert_eq!(String::from(&editor.text), "abx");
+        assert!(editor.engine.revision_count() <= 2);
+    }
+
+    #[test]
+    fn view_notification_seqs() {
+        use std::thread;
+
+        let view_id = ViewIdentifier::from("view-id-notification-seqs");
+        let mut editor = surround_editor(&view_id, "a\n", &[(0, 0)]);
+        // plugins, and the buffer info sent after config changes, notify
+        // the front-end from other threads while the buffer is edited
+        let plugins = {
+            let (doc_ctx, view_id) = (editor.doc_ctx.clone(), view_id.clone());
+            thread::spawn(move || for _ in 0..200 {
+                doc_ctx.plugin_started(&view_id, "syntect");
+                doc_ctx.update_cmds(&view_id, "syntect", &[]);
+            })
+        };
+        let config = {
+            let (doc_ctx, view_id) = (editor.doc_ctx.clone(), view_id.clone());
+            let info = editor.buffer_info();
+            thread::spawn(move || for _ in 0..200 {
+                doc_ctx.buffer_info(&view_id, &info);
+            })
+        };
+        for _ in 0..200 {
+            editor.handle_notification(&view_id, EditNotification::Insert { chars: "x".into() });
+        }
+        plugins.join().unwrap();
+        config.join().unwrap();
+
+        let sent = ::std::fs::read_to_string(::std::env::temp_dir().join(view_id.as_str()));
+        let seqs = sent.unwrap().lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .filter(|msg| msg["params"]["view_id"] == json!(view_id))
+            .map(|msg| msg["params"]["seq"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert!(seqs.len() > 600);
+        // the notifications are received in the order they are stamped
+        assert!(seqs.iter().enumerate().all(|(i, &seq)| seq == i as u64 + 1));
+    }
+
+    #[test]
+    fn bulk_replace_all() {
+        let view_id = ViewIdentifier::from("view-id-bulk-replace-all");
+        let (doc_ctx, updates) = mock_doc_ctx_with_updates(view_id.as_str());
+        let mut editor = Editor::with_text(doc_ctx, BufferIdentifier::new(1), &view_id,
+                                           "a\n".repeat(100_000));
+        let result = replace_all(&mut editor, &view_id, None, Some("a"), "bb").unwrap();
+        assert_eq!(result, json!({ "replaced": 100_000 }));
+        let sent = updates.try_iter().collect::<Vec<_>>();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].1.bulk_edit_params().is_some());
+        assert_eq!(frontend_updates(&view_id), 1);
+
+        // small replacements aren't bulk edits
+        let view_id = ViewIdentifier::from("view-id-small-replace-all");
+        let (doc_ctx, updates) = mock_doc_ctx_with_updates(view_id.as_str());
+        let mut editor = Editor::with_text(doc_ctx, BufferIdentifier::new(1), &view_id,
+                                           "a\n".repeat(10));
+        replace_all(&mut editor, &view_id, None, Some("a"), "bb").unwrap();
+        let sent = updates.try_iter().collect::<Vec<_>>();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].1.bulk_edit_params().is_none());
+    }
+
+    #[test]
+    fn hidden_buffers() {
+        let view_id = ViewIdentifier::from("view-id-hidden-buffers");
+        let second = ViewIdentifier::from("view-id-hidden-buffers-2");
+        let (doc_ctx, updates) = mock_doc_ctx_with_updates(view_id.as_str());
+        let mut editor = Editor::with_text(doc_ctx, BufferIdentifier::new(1), &view_id,
+                                           "abc\n".repeat(100));
+        editor.add_view(&second);
+        editor.view.rewrap(&editor.text, 2);
+        let insert = |ed: &mut Editor| {
+            ed.handle_notification(&second, EditNotification::Insert { chars: "x".into() });
+        };
+        insert(&mut editor);
+        assert_eq!(updates.try_iter().count(), 1);
+
+        // a buffer is hidden once all its views are
+        editor.set_view_visible(&view_id, false);
+        insert(&mut editor);
+        assert_eq!(updates.try_iter().count(), 1);
+        editor.set_view_visible(&second, false);
+        let start_rev = editor.engine.get_head_rev_id().token();
+        let sent_before = frontend_updates(&view_id);
+        let in_flight = editor.revs_in_flight;
+        for _ in 0..10 {
+            insert(&mut editor);
+        }
+        editor.theme_changed();
+        assert!(!editor.has_background_work());
+        assert_eq!(updates.try_iter().count(), 0);
+        assert_eq!(frontend_updates(&view_id), sent_before);
+        assert!(!editor.views[&view_id].is_wrapped());
+        // plugins started meanwhile start where the others are
+        let info = editor.plugin_init_info();
+        assert_eq!((info.rev, info.buf_size), (start_rev, 402));
+
+        // the edits are sent together, once due
+        let now = Instant::now();
+        let due = editor.schedule_deferred_update(now).unwrap();
+        insert(&mut editor);
+        assert_eq!(editor.schedule_deferred_update(now), None);
+        editor.send_deferred_update_if_due(now);
+        assert_eq!(updates.try_iter().count(), 0);
+        editor.send_deferred_update_if_due(due);
+        let sent = updates.try_iter().collect::<Vec<_>>();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1.bulk_edit_params().unwrap()["n_edits"], json!(11));
+        // the revision was only kept until then
+        assert_eq!(editor.revs_in_flight, in_flight + 1);
+
+        // and what's left catches up when a view is shown again
+        insert(&mut editor);
+        insert(&mut editor);
+        assert_eq!(updates.try_iter().count(), 0);
+        editor.set_view_visible(&view_id, true);
+        let sent = updates.try_iter().collect::<Vec<_>>();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1.bulk_edit_params().unwrap()["n_edits"], json!(2));
+        assert_eq!(editor.theme_recomputes, 1);
+        assert!(editor.views[&view_id].is_wrapped());
+        assert_eq!(editor.plugin_init_info().rev, editor.engine.get_head_rev_id().token());
+        insert(&mut editor);
+        assert_eq!(updates.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn bulk_plugin_edits() {
+        let view_id = ViewIdentifier::from("view-id-bulk-plugin-edits");
+        let (doc_ctx, updates) = mock_doc_ctx_with_updates(view_id.as_str());
+        let mut editor = Editor::with_text(doc_ctx, BufferIdentifier::new(1), &view_id,
+                                           "a\n".repeat(100));
+        // the responses of many plugins to one revision are applied as a bulk edit
+        let rev = editor.engine.get_head_rev_id().token();
+        let n_plugins = BULK_EDIT_DELTAS + 1;
+        let now = Instant::now();
+        for i in 0..n_plugins {
+            editor.expect_plugin_response(rev, PluginPid::new(i), "fixer", 0, 0, now);
+        }
+        // the edits wait for the first plugin's response, and are then
+        // applied together
+        for i in (0..n_plugins).rev() {
+            let offset = i as u64 * 2;
+            let edit = plugin_edit(rev, offset, offset + 1, "b", "fixer");
+            editor.plugin_responded(rev, PluginPid::new(i), Some(edit), now);
+        }
+        let sent = updates.try_iter().collect::<Vec<_>>();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1.bulk_edit_params().unwrap()["n_edits"], json!(n_plugins));
+        assert!(String::from(&editor.text).starts_with(&"b\n".repeat(n_plugins)));
+    }
+
+    /// Has `plugin` append `text` to the editor's text.
+    fn plugin_append(editor: &mut Editor, plugin: PluginPid, text: &str, now: Instant) {
+        let rev = editor.engine.get_head_rev_id().token();
+        let end = editor.text.len() as u64;
+        editor.plugin_edit(plugin, &plugin_edit(rev, end, end, text, "fixer"), now);
+    }
+
+    #[test]
+    fn plugin_undo_groups() {
+        let view_id = ViewIdentifier::from("view-id-plugin-undo-groups");
+        let mut editor = surround_editor(&view_id, "a", &[(0, 0)]);
+        let (fixer, other) = (PluginPid::new(1), PluginPid::new(2));
+        let now = Instant::now();
+        let token = editor.plugin_begin_undo_group(fixer, now).unwrap();
+        assert!(editor.plugin_begin_undo_group(fixer, now).is_none());
+        assert!(editor.plugin_begin_undo_group(other, now).is_some());
+        plugin_append(&mut editor, fixer, "b", now);
+        plugin_append(&mut editor, fixer, "c", now);
+        assert!(editor.plugin_end_undo_group(fixer, token, now));
+        assert!(!editor.plugin_end_undo_group(fixer, token, now));
+        plugin_append(&mut editor, fixer, "d", now);
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        assert_eq!(String::from(&editor.text), "abc");
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        assert_eq!(String::from(&editor.text), "a");
+
+        // a group the plugin abandons is closed after a while
+        let token = editor.plugin_begin_undo_group(fixer, now).unwrap();
+        plugin_append(&mut editor, fixer, "e", now);
+        let later = now + Duration::from_millis(PLUGIN_UNDO_GROUP_TIMEOUT);
+        plugin_append(&mut editor, fixer, "f", later);
+        assert!(!editor.plugin_end_undo_group(fixer, token, later));
+        assert!(editor.plugin_begin_undo_group(fixer, later).is_some());
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        assert_eq!(String::from(&editor.text), "ae");
+    }
+
+    #[test]
+    fn user_edits_close_plugin_undo_groups() {
+        let view_id = ViewIdentifier::from("view-id-user-edits-close-undo-groups");
+        let mut editor = surround_editor(&view_id, "a", &[(0, 0)]);
+        let fixer = PluginPid::new(1);
+        let now = Instant::now();
+        let token = editor.plugin_begin_undo_group(fixer, now).unwrap();
+        plugin_append(&mut editor, fixer, "b", now);
+        editor.handle_notification(&view_id, EditNotification::Insert { chars: "x".into() });
+        plugin_append(&mut editor, fixer, "c", now);
+        assert!(!editor.plugin_end_undo_group(fixer, token, now));
+        assert_eq!(String::from(&editor.text), "xabc");
+        // the user's edit is never undone with the plugin's
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        assert_eq!(String::from(&editor.text), "xab");
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        assert_eq!(String::from(&editor.text), "ab");
+
+        // nor is an undo of the plugin's edits extended by its later ones
+        let token = editor.plugin_begin_undo_group(fixer, now).unwrap();
+        plugin_append(&mut editor, fixer, "c", now);
+        editor.handle_notification(&view_id, EditNotification::Undo);
+        plugin_append(&mut editor, fixer, "d", now);
+        assert!(!editor.plugin_end_undo_group(fixer, token, now));
+        assert_eq!(String::from(&editor.text), "abd");
+    }
+
+    #[test]
+    fn accessibility_polling() {
+        let view_id = ViewIdentifier::from("view-id-accessibility");
+        let mut editor = surround_editor(&view_id, "one\ntwo\nthree\n", &[(5, 5)]);
+        let line = |editor: &mut Editor, visual_line: usize| {
+            editor.handle_request(&view_id, EditRequest::GetAccessibilityLine {
+                visual_line: visual_line,
+            })
+        };
+        let poll = |editor: &mut Editor, token: &Value| {
+            editor.handle_request(&view_id, EditRequest::AccessibilityDelta {
+                since_token: token.as_u64().unwrap(),
+            }).unwrap()
+        };
+        let first = line(&mut editor, 1).unwrap();
+        assert_eq!(first["text"], json!("two"));
+        assert_eq!(first["caret"], json!(1));
+        assert_eq!(line(&mut editor, 0).unwrap()["caret"], Value::Null);
+        assert!(line(&mut editor, 4).is_err());
+
+        // nothing changes until the user types
+        let mut token = first["token"].clone();
+        let delta = poll(&mut editor, &token);
+        assert_eq!(delta["lines"], json!([]));
+        assert_eq!(delta["selection_changed"], json!(false));
+        for (i, c) in "ab".chars().enumerate() {
+            editor.handle_notification(&view_id, EditNotification::Insert {
+                chars: c.to_string(),
+            });
+            let delta = poll(&mut editor, &token);
+            let text = format!("t{}wo", &"ab"[..i + 1]);
+            assert_eq!(delta["lines"], json!([
+                {"visual_line": 1, "logical_line": 1, "col": 0, "text": text},
+            ]));
+            assert_eq!(delta["selection_changed"], json!(true));
+            assert_eq!(delta["caret_line"], json!(1));
+            token = delta["token"].clone();
+        }
+        editor.handle_notification(&view_id, EditNotification::InsertNewline);
+        let delta = poll(&mut editor, &token);
+        assert_eq!(delta["n_visual_lines"], json!(5));
+        assert_eq!(delta["lines"].as_array().unwrap().len(), 2);
+        assert_eq!(delta["caret_line"], json!(2));
+        token = delta["token"].clone();
+
+        // a selection is described within each line it covers
+        editor.handle_notification(&view_id, EditNotification::MoveUpAndModifySelection);
+        assert_eq!(line(&mut editor, 1).unwrap()["selections"], json!([[0, 3]]));
+        assert_eq!(line(&mut editor, 2).unwrap()["selections"], json!([]));
+        assert_eq!(line(&mut editor, 1).unwrap()["caret"], json!(0));
+        let delta = poll(&mut editor, &token);
+        assert!(delta["lines"].as_array().unwrap().is_empty());
+        assert_eq!(delta["selection_changed"], json!(true));
+
+        // wrapped lines have both visual and logical coordinates
+        editor.handle_notification(&view_id, EditNotification::SelectAll);
+        editor.handle_notification(&view_id, EditNotification::Insert {
+            chars: "a fairly long line of words".to_owned(),
+        });
+        token = poll(&mut editor, &token)["token"].clone();
+        editor.view.rewrap(&editor.text, 10);
+        let delta = poll(&mut editor, &token);
+        assert_eq!(delta["wrap_changed"], json!(true));
+        let wrapped = line(&mut editor, 1).unwrap();
+        assert_eq!(wrapped["logical_line"], json!(0));
+        assert_eq!(wrapped["text"], json!("long line "));
+        assert_eq!(wrapped["col"], json!(9));
+        assert_eq!(line(&mut editor, 2).unwrap()["caret"], json!(8));
+        assert_eq!(poll(&mut editor, &json!(1_000_000))["reset"], json!(true));
+    }
+
+    /// Makes `n` random edits to the editor's buffer, which is only ever
+    /// ASCII, using the xorshift generator `seed`.
+    fn random_edits(editor: &mut Editor, seed: &mut u64, n: usize) {
+        let mut next = |bound: usize| {
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 7;
+            *seed ^= *seed << 17;
+            (*seed % bound as u64) as usize
+        };
+        for _ in 0..n {
+            let len = editor.text.len();
+            let start = next(len + 1);
+            let end = if next(3) == 0 { start + next(len - start + 1) } else { start };
+            let text = (0..next(4)).map(|_| (b'a' + next(26) as u8) as char).collect::<String>();
+            let iv = Interval::new_closed_open(start, end);
+            editor.add_delta(Delta::simple_edit(iv, Rope::from(text), len));
+            editor.commit_delta(None);
+        }
+    }
+
+    fn merge_history(editor: &mut Editor, view_id: &ViewIdentifier, payload: Value)
+                     -> Result<Value, RemoteError> {
+        editor.handle_request(view_id, EditRequest::MergeHistory { payload: payload })
+    }
+
+    #[test]
+    fn divergent_histories_converge() {
+        let (a_id, b_id) = (ViewIdentifier::from("view-id-a"), ViewIdentifier::from("view-id-b"));
+        for seed in 1..40u64 {
+            let mut a = surround_editor(&a_id, "the quick brown fox\n", &[(0, 0)]);
+            let mut b = surround_editor(&b_id, "the quick brown fox\n", &[(0, 0)]);
+            let mut rng = seed * 0x9E37_79B9;
+            // a second round merges histories which already share revisions
+            for _ in 0..2 {
+                random_edits(&mut a, &mut rng, 1 + seed as usize % 5);
+                random_edits(&mut b, &mut rng, 1 + seed as usize % 7);
+                let from_a = a.export_history(None);
+                let from_b = b.export_history(None);
+                merge_history(&mut a, &a_id, from_b).unwrap();
+                merge_history(&mut b, &b_id, from_a).unwrap();
+                assert_eq!(String::from(&a.text), String::from(&b.text), "seed {}", seed);
+            }
+        }
+    }
+
+    fn error_code(result: Result<Value, RemoteError>) -> i64 {
+        match result {
+            Err(RemoteError::Custom { code, .. }) => code,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refused_history_merges() {
+        let view_id = ViewIdentifier::from("view-id-refused-merges");
+        let mut editor = surround_editor(&view_id, "abc", &[(0, 0)]);
+        let mut other = surround_editor(&view_id, "abc", &[(0, 0)]);
+        let payload = other.export_history(None);
+        let head = json!({"rev": editor.engine.get_head_rev_id().token()});
+        assert_eq!(merge_history(&mut editor, &view_id, payload.clone()).unwrap(),
+                   json!({"rev": editor.engine.get_head_rev_id().token()}));
+        // an unchanged history isn't sent again
+        let since = payload["head_rev"].as_u64();
+        assert_eq!(other.export_history(since)["engine"], Value::Null);
+        assert_eq!(merge_history(&mut editor, &view_id, other.export_history(since)).unwrap(),
+                   head);
+
+        let mut future = payload.clone();
+        future["version"] = json!(history::HISTORY_VERSION + 1);
+        assert_eq!(error_code(merge_history(&mut editor, &view_id, future)), 3);
+        let mut different = surround_editor(&view_id, "xyz", &[(0, 0)]);
+        let payload = different.export_history(None);
+        assert_eq!(error_code(merge_history(&mut editor, &view_id, payload)),
+                   history::MERGE_REFUSED);
+        other.handle_notification(&view_id, EditNotification::Insert { chars: "d".into() });
+        other.handle_notification(&view_id, EditNotification::Undo);
+        let payload = other.export_history(None);
+        assert_eq!(error_code(merge_history(&mut editor, &view_id, payload)),
+                   history::MERGE_REFUSED);
+    }
+
+    #[test]
+    fn gestures_on_stale_lines() {
+        let view_id = ViewIdentifier::from("view-id-stale-gestures");
+        let mut editor = surround_editor(&view_id, "one two three\nfour\n", &[(0, 0)]);
+        let rendered = editor.engine.get_head_rev_id().token();
+        // another edit lands after the front-end rendered its lines
+        let iv = Interval::new_closed_open(0, 0);
+        editor.add_delta(Delta::simple_edit(iv, Rope::from("zero "), editor.text.len()));
+        editor.commit_delta(None);
+        let click = |editor: &mut Editor, line, column, click_count, rev| {
+            editor.handle_notification(&view_id, EditNotification::Click(rpc::MouseAction {
+                line: line, column: column, flags: 0, click_count: Some(click_count), rev: rev,
+            }));
+            let region = &editor.view.sel_regions()[0];
+            editor.text.slice_to_string(region.min(), region.max())
+        };
+        // the double click meant "two", not what is there now
+        assert_eq!(click(&mut editor, 0, 5, 2, None), "one");
+        assert_eq!(click(&mut editor, 0, 5, 2, Some(rendered)), "two");
+        let head = editor.engine.get_head_rev_id().token();
+        assert_eq!(click(&mut editor, 0, 10, 2, Some(head)), "two");
+        assert_eq!(click(&mut editor, 1, 1, 3, Some(rendered)), "four\n");
+
+        editor.handle_notification(&view_id, EditNotification::Gesture {
+            line: 0, col: 1, ty: GestureType::SelectAddRegion, rev: Some(rendered),
+        });
+        assert_eq!(editor.view.sel_regions().len(), 2);
+        assert_eq!(editor.view.sel_regions()[0].end, 6);
+        // gestures on lines which are no longer known are dropped
+        assert_eq!(click(&mut editor, 0, 0, 1, Some(rendered + 1)), "");
+        assert_eq!(editor.view.sel_regions().len(), 2);
+        editor.view.rewrap(&editor.text, 8);
+        assert_eq!(click(&mut editor, 0, 0, 1, Some(rendered)), "");
+        assert_eq!(editor.view.sel_regions().len(), 2);
+    }
+
+    #[test]
+    fn incidents_have_context() {
+        let view_id = ViewIdentifier::from("view-id-incidents");
+        let mut editor = Editor::new(mock_doc_ctx(view_id.as_str()), BufferIdentifier::new(1),
+                                     &view_id);
+        editor.handle_notification(&view_id, EditNotification::Insert {
+            chars: "let Secret = 42;\n".into(),
+        });
+        editor.handle_notification(&view_id, EditNotification::Insert { chars: "x".into() });
+        editor.plugin_started(None, "syntect", &[]);
+        // one layer of styles becomes shorter than the buffer
+        let len = editor.text.len();
+        let iv = Interval::new_closed_open(0, len);
+        let doc_ctx = editor.doc_ctx.clone();
+        editor.styles.add_scopes(PluginPid::new(1), vec![vec!["source".into()]], &doc_ctx).unwrap();
+        let mut sb = SpansBuilder::new(len);
+        sb.add_span(Interval::new_closed_open(0, 3), 0);
+        editor.styles.update_layer(PluginPid::new(1), iv, sb.build(), None, None).unwrap();
+        let empty = SpansBuilder::new(len - 3).build();
+        editor.styles.update_style_layer(PluginPid::new(2), iv, empty).unwrap();
+        editor.render();
+
+        let incidents = editor.doc_ctx.get_incidents()["incidents"].clone();
+        assert_eq!(incidents.as_array().unwrap().len(), 1);
+        let incident = &incidents[0];
+        assert!(incident["location"].as_str().unwrap().contains("layers.rs:"));
+        assert_eq!(incident["message"], "a layer's styles are 15 long, not 18");
+        // the text is redacted by default
+        assert_eq!(incident["redacted"], json!(true));
+        assert_eq!(incident["carets"], json!([{
+            "offset": 18, "before": "xxx Xxxxxx = 00;\nx", "after": "",
+        }]));
+        assert_eq!(incident["recent_deltas"], json!([
+            { "start": 0, "end": 0, "new_len": 17, "inserted": "xxx Xxxxxx = 00;\n" },
+            { "start": 17, "end": 17, "new_len": 1, "inserted": "x" },
+        ]));
+        assert_eq!(incident["plugins"], json!(["syntect"]));
+        assert_eq!(incident["config_hash"], incidents::config_hash(&Config::default()));
+
+        // styles are resolved again once the plugin stops, without it
+        editor.plugin_stopped(None, "syntect", PluginPid::new(1), 0);
+        let incidents = editor.doc_ctx.get_incidents()["incidents"].clone();
+        assert_eq!(incidents.as_array().unwrap().len(), 2);
+        assert_eq!(incidents[1]["plugins"], json!([]));
+    }
+
+    #[test]
+    fn spilled_undo_history() {
+        let view_id = ViewIdentifier::from("view-id-undo-spill");
+        let mut editor = Editor::new(mock_doc_ctx(view_id.as_str()), BufferIdentifier::new(1),
+                                     &view_id);
+        let path = ::std::env::temp_dir().join(format!("xi-undo-{}", view_id));
+        editor.undo_spill = Some(UndoSpill::new(path.clone()));
+        editor.max_undos = 3;
+        let edit = |editor: &mut Editor, i: usize| {
+            let len = editor.text.len();
+            let delta = if i % 5 == 4 {
+                // delete a character in the middle
+                Delta::simple_edit(Interval::new_closed_open(len / 2, len / 2 + 1),
+                                   Rope::from(""), len)
+            } else {
+                let text = if i % 7 == 6 { "\n" } else { "ab" };
+                Delta::simple_edit(Interval::new_closed_open(len / 3, len / 3), Rope::from(text),
+                                   len)
+            };
+            editor.this_edit_type = EditType::Other;
+            editor.add_delta(delta);
+            editor.commit_delta(None);
+            editor.last_edit_type = editor.this_edit_type;
+            editor.dec_revs_in_flight();
+        };
+        let n = 50_000;
+        for i in 0..n {
+            edit(&mut editor, i);
+        }
+        let final_text = String::from(&editor.text);
+        assert!(path.exists());
+        assert!(editor.undo_spill.as_ref().unwrap().len() > n - 10);
+
+        let undo = |editor: &mut Editor| {
+            editor.do_undo();
+            if editor.revs_in_flight > 0 {
+                editor.dec_revs_in_flight();
+            }
+        };
+        let redo = |editor: &mut Editor| {
+            editor.do_redo();
+            if editor.revs_in_flight > 0 {
+                editor.dec_revs_in_flight();
+            }
+        };
+        for _ in 0..n + 1 {
+            undo(&mut editor);
+        }
+        assert_eq!(String::from(&editor.text), "");
+        for _ in 0..n + 1 {
+            redo(&mut editor);
+        }
+        assert_eq!(String::from(&editor.text), final_text);
+
+        // an edit made after undoing past the window replaces what was undone
+        for _ in 0..200 {
+            undo(&mut editor);
+        }
+        let undone_text = String::from(&editor.text);
+        edit(&mut editor, 0);
+        undo(&mut editor);
+        assert_eq!(String::from(&editor.text), undone_text);
+        for _ in 0..n {
+            undo(&mut editor);
+        }
+        assert_eq!(String::from(&editor.text), "");
+        // the first group, which loaded the empty buffer, changes nothing
+        redo(&mut editor);
+        redo(&mut editor);
+        assert_eq!(editor.text.len(), 2);
+
+        // a corrupted spill file loses the history, which is no longer spilled
+        for i in 0..200 {
+            edit(&mut editor, i);
+        }
+        ::std::fs::write(&path, "").unwrap();
+        for _ in 0..100 {
+            undo(&mut editor);
+        }
+        assert!(editor.undo_spill.is_none());
+        assert!(!path.exists());
+        assert!(editor.text.len() > 0);
+        edit(&mut editor, 0);
+        assert!(editor.spilled_redos.is_empty());
+    }
+
+    #[test]
+    fn visual_caret_movement() {
+        let view_id = ViewIdentifier::from("view-id-bidi");
+        let mut editor = Editor::new(mock_doc_ctx(view_id.as_str()), BufferIdentifier::new(1),
+                                     &view_id);
+        editor.handle_notification(&view_id, EditNotification::Insert {
+            chars: "\u{5d0}\u{5d1}\nx".into(),
+        });
+        // in a right-to-left line, moving left moves forward, to the next line
+        assert_eq!(editor.visual_move_offset(0, false), Some(2));
+        assert_eq!(editor.visual_move_offset(2, false), Some(4));
+        assert_eq!(editor.visual_move_offset(4, false), Some(5));
+        assert_eq!(editor.visual_move_offset(0, true), Some(0));
+        // lines with only left-to-right text move logically
+        assert_eq!(editor.visual_move_offset(5, false), None);
+    }
 }
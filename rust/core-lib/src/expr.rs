@@ -0,0 +1,534 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The small expression language of `transform_selection_with_expr`,
+//! whose expressions compute the new text of each selected region.
+//!
+//! An expression has the variables `s`, the text of the region, `n`, that
+//! text as a number, and `i`, the index of the region. There are number
+//! and string literals, the arithmetic operators, of which `+` joins
+//! strings, and the functions in `FUNCTIONS`. Expressions can't loop or
+//! reach outside the text they are given.
+
+use pattern::Regex;
+
+/// The most deeply nested an expression may be.
+const MAX_NESTING: usize = 64;
+
+/// What is done with the regions whose text an expression fails on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnError {
+    /// The regions are left as they are, and the others are transformed.
+    Skip,
+    /// No region is transformed.
+    Abort,
+}
+
+impl Default for OnError {
+    fn default() -> Self {
+        OnError::Skip
+    }
+}
+
+/// An expression which couldn't be parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// The char offset in the expression at which the error was found.
+    pub position: usize,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Func {
+    Upper,
+    Lower,
+    Title,
+    Trim,
+    Len,
+    Num,
+    Str,
+    Round,
+    Floor,
+    Ceil,
+    Abs,
+    Pad,
+    Replace,
+}
+
+/// Each function, its name, and the fewest and most arguments it takes.
+const FUNCTIONS: &[(Func, &str, usize, usize)] = &[
+    (Func::Upper, "upper", 1, 1),
+    (Func::Lower, "lower", 1, 1),
+    (Func::Title, "title", 1, 1),
+    (Func::Trim, "trim", 1, 1),
+    (Func::Len, "len", 1, 1),
+    (Func::Num, "num", 1, 1),
+    (Func::Str, "str", 1, 1),
+    (Func::Round, "round", 1, 1),
+    (Func::Floor, "floor", 1, 1),
+    (Func::Ceil, "ceil", 1, 1),
+    (Func::Abs, "abs", 1, 1),
+    // pads on the left to a width, with spaces or the given string
+    (Func::Pad, "pad", 2, 3),
+    // replaces the matches of a pattern, as by `pattern::Regex::replace_all`
+    (Func::Replace, "replace", 3, 3),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Var {
+    Text,
+    Number,
+    Index,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Num(f64),
+    Str(String),
+    Var(Var),
+    Neg(Box<Node>),
+    Binary(char, Box<Node>, Box<Node>),
+    Call(Func, Vec<Node>),
+}
+
+/// A value computed by an expression.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Num(f64),
+    Str(String),
+}
+
+impl Value {
+    fn into_string(self) -> String {
+        match self {
+            Value::Str(s) => s,
+            Value::Num(n) => format_number(n),
+        }
+    }
+
+    fn as_number(&self, what: &str) -> Result<f64, String> {
+        match *self {
+            Value::Num(n) => Ok(n),
+            Value::Str(ref s) => Err(format!("{} needs a number, not the text \"{}\"; \
+                                              use num() to convert it", what, s)),
+        }
+    }
+}
+
+/// Formats numbers which are integers without a fraction.
+fn format_number(n: f64) -> String {
+    if n.fract() == 0.0 && n.abs() < 1e15 {
+        format!("{}", n as i64)
+    } else {
+        format!("{}", n)
+    }
+}
+
+/// Parses text as a number, ignoring surrounding whitespace.
+fn parse_number(text: &str) -> Result<f64, String> {
+    let trimmed = text.trim();
+    let is_numeric = trimmed.chars()
+        .all(|c| c.is_ascii_digit() || "+-.eE".contains(c));
+    match trimmed.parse::<f64>() {
+        Ok(n) if is_numeric && n.is_finite() => Ok(n),
+        _ => Err(format!("\"{}\" isn't a number", text)),
+    }
+}
+
+fn title_case(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut at_word_start = true;
+    for c in text.chars() {
+        if at_word_start {
+            result.extend(c.to_uppercase());
+        } else {
+            result.extend(c.to_lowercase());
+        }
+        at_word_start = !c.is_alphanumeric();
+    }
+    result
+}
+
+/// A parsed expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    root: Node,
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, ParseError> {
+        let mut parser = Parser { chars: source.chars().collect(), pos: 0, nesting: 0 };
+        parser.skip_whitespace();
+        let root = parser.sum()?;
+        if parser.pos < parser.chars.len() {
+            return parser.error("expected an operator");
+        }
+        Ok(Expr { root: root })
+    }
+
+    /// Evaluates the expression for the region with index `index` and
+    /// text `text`, returning its new text, or why it can't be computed.
+    pub fn eval(&self, text: &str, index: usize) -> Result<String, String> {
+        let env = Env { text: text, index: index };
+        env.eval(&self.root).map(Value::into_string)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    nesting: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, reason: &str) -> Result<T, ParseError> {
+        Err(ParseError { position: self.pos, reason: reason.to_owned() })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map_or(false, char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// Consumes `c`, and any whitespace after it, if it is next.
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() != Some(c) {
+            return false;
+        }
+        self.pos += 1;
+        self.skip_whitespace();
+        true
+    }
+
+    fn sum(&mut self) -> Result<Node, ParseError> {
+        let mut node = self.product()?;
+        while let Some(op) = self.peek().filter(|&c| c == '+' || c == '-') {
+            self.eat(op);
+            node = Node::Binary(op, Box::new(node), Box::new(self.product()?));
+        }
+        Ok(node)
+    }
+
+    fn product(&mut self) -> Result<Node, ParseError> {
+        let mut node = self.unary()?;
+        while let Some(op) = self.peek().filter(|&c| c == '*' || c == '/' || c == '%') {
+            self.eat(op);
+            node = Node::Binary(op, Box::new(node), Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, ParseError> {
+        self.nesting += 1;
+        if self.nesting > MAX_NESTING {
+            return self.error("the expression is nested too deeply");
+        }
+        let node = if self.eat('-') {
+            Node::Neg(Box::new(self.unary()?))
+        } else {
+            self.primary()?
+        };
+        self.nesting -= 1;
+        Ok(node)
+    }
+
+    fn primary(&mut self) -> Result<Node, ParseError> {
+        let node = match self.peek() {
+            None => return self.error("unexpected end of the expression"),
+            Some('(') => {
+                self.eat('(');
+                let node = self.sum()?;
+                if self.peek() != Some(')') {
+                    return self.error("expected ')'");
+                }
+                self.pos += 1;
+                node
+            }
+            Some('"') | Some('\'') => Node::Str(self.string()?),
+            Some(c) if c.is_ascii_digit() || c == '.' => Node::Num(self.number()?),
+            Some(c) if c.is_alphabetic() || c == '_' => self.name()?,
+            Some(_) => return self.error("expected a value"),
+        };
+        self.skip_whitespace();
+        Ok(node)
+    }
+
+    fn number(&mut self) -> Result<f64, ParseError> {
+        let start = self.pos;
+        while self.peek().map_or(false, |c| c.is_ascii_digit() || c == '.') {
+            self.pos += 1;
+        }
+        // an exponent, as in 1e-3
+        if let Some('e') | Some('E') = self.peek() {
+            let sign = match self.chars.get(self.pos + 1) {
+                Some(&'+') | Some(&'-') => 1,
+                _ => 0,
+            };
+            if self.chars.get(self.pos + 1 + sign).map_or(false, |c| c.is_ascii_digit()) {
+                self.pos += 1 + sign;
+                while self.peek().map_or(false, |c| c.is_ascii_digit()) {
+                    self.pos += 1;
+                }
+            }
+        }
+        let digits = self.chars[start..self.pos].iter().collect::<String>();
+        digits.parse::<f64>().or_else(|_| {
+            self.pos = start;
+            self.error("invalid number")
+        })
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        let quote = self.chars[self.pos];
+        let start = self.pos;
+        self.pos += 1;
+        let mut result = String::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => {
+                    self.pos = start;
+                    return self.error("unterminated string");
+                }
+            };
+            self.pos += 1;
+            match c {
+                c if c == quote => return Ok(result),
+                '\\' => {
+                    let escaped = match self.peek() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some(c @ '\\') | Some(c @ '"') | Some(c @ '\'') => c,
+                        _ => return self.error("unknown escape"),
+                    };
+                    self.pos += 1;
+                    result.push(escaped);
+                }
+                c => result.push(c),
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<Node, ParseError> {
+        let start = self.pos;
+        while self.peek().map_or(false, |c| c.is_alphanumeric() || c == '_') {
+            self.pos += 1;
+        }
+        let name = self.chars[start..self.pos].iter().collect::<String>();
+        self.skip_whitespace();
+        if self.peek() != Some('(') {
+            return Ok(Node::Var(match name.as_str() {
+                "s" => Var::Text,
+                "n" => Var::Number,
+                "i" => Var::Index,
+                _ => {
+                    self.pos = start;
+                    return self.error(&format!("unknown variable '{}'", name));
+                }
+            }));
+        }
+        let (func, min_args, max_args) = match FUNCTIONS.iter().find(|f| f.1 == name) {
+            Some(&(func, _, min, max)) => (func, min, max),
+            None => {
+                self.pos = start;
+                return self.error(&format!("unknown function '{}'", name));
+            }
+        };
+        self.eat('(');
+        let mut args = Vec::new();
+        let mut arg_positions = Vec::new();
+        if !self.eat(')') {
+            loop {
+                arg_positions.push(self.pos);
+                args.push(self.sum()?);
+                if self.eat(')') {
+                    break;
+                }
+                if !self.eat(',') {
+                    return self.error("expected ',' or ')'");
+                }
+            }
+        }
+        if args.len() < min_args || args.len() > max_args {
+            self.pos = start;
+            let expected = match (min_args, max_args) {
+                (1, 1) => "1 argument".to_owned(),
+                (min, max) if min == max => format!("{} arguments", min),
+                (min, max) => format!("{} to {} arguments", min, max),
+            };
+            return self.error(&format!("{}() takes {}, not {}", name, expected, args.len()));
+        }
+        // patterns which are literals are checked as the expression is
+        if let (Func::Replace, Some(&Node::Str(ref pattern))) = (func, args.get(1)) {
+            if let Err((offset, reason)) = Regex::new(pattern) {
+                // the offset is in the pattern, after the opening quote
+                self.pos = arg_positions[1] + 1 + offset;
+                return self.error(&format!("invalid pattern: {}", reason));
+            }
+        }
+        Ok(Node::Call(func, args))
+    }
+}
+
+/// The variables of an evaluation.
+struct Env<'a> {
+    text: &'a str,
+    index: usize,
+}
+
+impl<'a> Env<'a> {
+    fn eval(&self, node: &Node) -> Result<Value, String> {
+        Ok(match *node {
+            Node::Num(n) => Value::Num(n),
+            Node::Str(ref s) => Value::Str(s.clone()),
+            Node::Var(Var::Text) => Value::Str(self.text.to_owned()),
+            Node::Var(Var::Number) => Value::Num(parse_number(self.text)?),
+            Node::Var(Var::Index) => Value::Num(self.index as f64),
+            Node::Neg(ref inner) => Value::Num(-self.eval(inner)?.as_number("'-'")?),
+            Node::Binary(op, ref lhs, ref rhs) => {
+                let (lhs, rhs) = (self.eval(lhs)?, self.eval(rhs)?);
+                if let ('+', &Value::Str(_), _) | ('+', _, &Value::Str(_)) = (op, &lhs, &rhs) {
+                    return Ok(Value::Str(lhs.into_string() + &rhs.into_string()));
+                }
+                let what = format!("'{}'", op);
+                let (a, b) = (lhs.as_number(&what)?, rhs.as_number(&what)?);
+                if (op == '/' || op == '%') && b == 0.0 {
+                    return Err("division by zero".to_owned());
+                }
+                Value::Num(match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    '/' => a / b,
+                    _ => a % b,
+                })
+            }
+            Node::Call(func, ref args) => {
+                let args = args.iter().map(|arg| self.eval(arg)).collect::<Result<Vec<_>, _>>()?;
+                call(func, args)?
+            }
+        })
+    }
+}
+
+fn call(func: Func, args: Vec<Value>) -> Result<Value, String> {
+    let name = FUNCTIONS.iter().find(|f| f.0 == func).unwrap().1;
+    let number = |value: &Value| value.as_number(&format!("{}()", name));
+    let mut args = args.into_iter();
+    let first = args.next().unwrap();
+    Ok(match func {
+        Func::Upper => Value::Str(first.into_string().to_uppercase()),
+        Func::Lower => Value::Str(first.into_string().to_lowercase()),
+        Func::Title => Value::Str(title_case(&first.into_string())),
+        Func::Trim => Value::Str(first.into_string().trim().to_owned()),
+        Func::Len => Value::Num(first.into_string().chars().count() as f64),
+        Func::Num => match first {
+            Value::Str(s) => Value::Num(parse_number(&s)?),
+            num => num,
+        },
+        Func::Str => Value::Str(first.into_string()),
+        Func::Round => Value::Num(number(&first)?.round()),
+        Func::Floor => Value::Num(number(&first)?.floor()),
+        Func::Ceil => Value::Num(number(&first)?.ceil()),
+        Func::Abs => Value::Num(number(&first)?.abs()),
+        Func::Pad => {
+            let width = number(&args.next().unwrap())?;
+            let fill = args.next().map_or(" ".to_owned(), Value::into_string);
+            if fill.is_empty() {
+                return Err("pad() needs a string to pad with".to_owned());
+            }
+            let text = first.into_string();
+            let missing = (width.max(0.0) as usize).saturating_sub(text.chars().count());
+            if missing > 1 << 20 {
+                return Err("pad() can't pad to that width".to_owned());
+            }
+            let padding = fill.chars().cycle().take(missing).collect::<String>();
+            Value::Str(padding + &text)
+        }
+        Func::Replace => {
+            let pattern = args.next().unwrap().into_string();
+            let replacement = args.next().unwrap().into_string();
+            let regex = Regex::new(&pattern)
+                .map_err(|(_, reason)| format!("invalid pattern: {}", reason))?;
+            Value::Str(regex.replace_all(&first.into_string(), &replacement)?)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str, text: &str) -> Result<String, String> {
+        Expr::parse(expr).unwrap().eval(text, 3)
+    }
+
+    fn parse_error(expr: &str) -> (usize, String) {
+        let err = Expr::parse(expr).unwrap_err();
+        (err.position, err.reason)
+    }
+
+    #[test]
+    fn evaluating() {
+        assert_eq!(eval("n*2", "5"), Ok("10".to_owned()));
+        assert_eq!(eval("n / 4", " 10 "), Ok("2.5".to_owned()));
+        assert_eq!(eval("2.5e3 + 1E-1", ""), Ok("2500.1".to_owned()));
+        assert_eq!(eval("-(n - 1) % 3", "6"), Ok("-2".to_owned()));
+        assert_eq!(eval("'item ' + (i + 1)", ""), Ok("item 4".to_owned()));
+        assert_eq!(eval("s + s", "ab"), Ok("abab".to_owned()));
+        assert_eq!(eval("upper(s) + lower(\"X\") + title('the end')", "a"),
+                   Ok("AxThe End".to_owned()));
+        assert_eq!(eval("trim(s)", "  a b \t"), Ok("a b".to_owned()));
+        assert_eq!(eval("len(s) * 10 + num('1e2')", "héllo"), Ok("150".to_owned()));
+        assert_eq!(eval("round(n) + floor(1.5) + ceil(1.5) + abs(-1)", "2.6"),
+                   Ok("7".to_owned()));
+        assert_eq!(eval("pad(i, 3, '0') + pad('x', 2)", ""), Ok("003 x".to_owned()));
+        assert_eq!(eval("replace(s, '(\\\\w+)=(\\\\d+)', '$1: $2')", "a=1, b=22"),
+                   Ok("a: 1, b: 22".to_owned()));
+        assert_eq!(eval("replace(s, \"[aeiou]\", \"\")", "education"), Ok("dctn".to_owned()));
+    }
+
+    #[test]
+    fn evaluation_errors() {
+        assert!(eval("n * 2", "five").unwrap_err().contains("\"five\" isn't a number"));
+        assert!(eval("n", "inf").is_err());
+        assert!(eval("s * 2", "5").unwrap_err().contains("use num()"));
+        assert_eq!(eval("1 / (n - 1)", "1"), Err("division by zero".to_owned()));
+        assert!(eval("replace(s, '(' + s, '')", "x").unwrap_err().contains("invalid pattern"));
+        assert!(eval("pad(s, 1e9)", "").is_err());
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse_error("n * "), (4, "unexpected end of the expression".to_owned()));
+        assert_eq!(parse_error("n 2"), (2, "expected an operator".to_owned()));
+        assert_eq!(parse_error("x + 1"), (0, "unknown variable 'x'".to_owned()));
+        assert_eq!(parse_error("1 + shout(s)"), (4, "unknown function 'shout'".to_owned()));
+        assert_eq!(parse_error("upper(s, s)").1, "upper() takes 1 argument, not 2");
+        assert_eq!(parse_error("pad(s)").1, "pad() takes 2 to 3 arguments, not 1");
+        assert_eq!(parse_error("(n + 1"), (6, "expected ')'".to_owned()));
+        assert_eq!(parse_error("'abc"), (0, "unterminated string".to_owned()));
+        assert_eq!(parse_error("1..2"), (0, "invalid number".to_owned()));
+        // errors in literal patterns are found at their position
+        assert_eq!(parse_error("replace(s, 'a(b', '')"),
+                   (15, "invalid pattern: unclosed group".to_owned()));
+        assert!(parse_error(&"-".repeat(1000)).1.contains("nested too deeply"));
+    }
+}
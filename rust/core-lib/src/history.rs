@@ -0,0 +1,104 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! The export of a buffer's history, and the merging of one exported by
+//! another core editing the same document, each in its own session.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use serde_json::{self, Value};
+
+use xi_rope::engine::{Engine, RevToken};
+
+/// The version of the format of exported histories.
+pub const HISTORY_VERSION: u64 = 1;
+
+/// The error code of histories which can't be merged.
+pub const MERGE_REFUSED: i64 = 7;
+
+static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns a session ID for the revisions of a new buffer, which is very
+/// unlikely to be used by another core. The sessions which are used before
+/// any are started, (0, 0) and (1, 0), are never returned.
+pub fn new_session_id() -> (u64, u32) {
+    let mut hasher = RandomState::new().build_hasher();
+    SystemTime::now().hash(&mut hasher);
+    process::id().hash(&mut hasher);
+    SESSIONS.fetch_add(1, Ordering::Relaxed).hash(&mut hasher);
+    let session1 = hasher.finish().max(2);
+    session1.hash(&mut hasher);
+    (session1, hasher.finish() as u32)
+}
+
+/// Returns the exported history of `engine`, or only its version and
+/// session if its head is `since_rev`, as when a peer already has it.
+pub fn export(engine: &Engine, since_rev: Option<RevToken>) -> Value {
+    let head = engine.get_head_rev_id().token();
+    let revisions = if since_rev == Some(head) {
+        Value::Null
+    } else {
+        serde_json::to_value(engine).expect("engine serializes")
+    };
+    json!({
+        "version": HISTORY_VERSION,
+        "session_id": engine.session_id(),
+        "head_rev": head,
+        "engine": revisions,
+    })
+}
+
+/// Returns the engine in an exported history, or `None` if it was left
+/// out because it hadn't changed.
+pub fn import(payload: &Value) -> Result<Option<Engine>, String> {
+    match payload.get("version").and_then(Value::as_u64) {
+        Some(HISTORY_VERSION) => (),
+        Some(version) => return Err(format!("unsupported history version {}", version)),
+        None => return Err("history has no version".to_owned()),
+    }
+    match payload.get("engine") {
+        None | Some(&Value::Null) => Ok(None),
+        Some(engine) => serde_json::from_value(engine.clone())
+            .map(Some)
+            .map_err(|e| format!("invalid history: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xi_rope::rope::Rope;
+
+    #[test]
+    fn sessions_and_versions() {
+        let (a, b) = (new_session_id(), new_session_id());
+        assert!(a != b && a.0 > 1 && b.0 > 1);
+
+        let mut engine = Engine::new(Rope::from("text"));
+        engine.start_session(a);
+        let payload = export(&engine, None);
+        assert_eq!(payload["session_id"], json!([a.0, a.1]));
+        let imported = import(&payload).unwrap().unwrap();
+        assert_eq!(String::from(imported.get_head()), "text");
+        let unchanged = export(&engine, Some(engine.get_head_rev_id().token()));
+        assert!(import(&unchanged).unwrap().is_none());
+        assert!(import(&json!({"engine": null})).is_err());
+        assert!(import(&json!({"version": 2, "engine": null})).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Exporting a document as HTML, with its resolved styles inlined, as for
+//! "copy as HTML".
+
+use xi_rope::interval::Interval;
+use xi_rope::rope::Rope;
+
+use styles::{Style, UnderlineStyle};
+
+/// Renders the `lines` of `text` as a `pre` element with the theme's
+/// colors, `fg` and `bg`. The styles of each line are those of
+/// `Scopes::merged_by_line`, relative to its start; each is a `span`
+/// with its style inline, and the text between them is left plain.
+pub fn render_html(text: &Rope, lines: &[Interval], styles: &[Vec<(Interval, Style)>],
+                   fg: Option<u32>, bg: Option<u32>) -> String {
+    let mut css = Vec::new();
+    if let Some(fg) = fg {
+        css.push(format!("color: {}", css_color(fg)));
+    }
+    if let Some(bg) = bg {
+        css.push(format!("background-color: {}", css_color(bg)));
+    }
+    let mut html = if css.is_empty() {
+        "<pre>".to_owned()
+    } else {
+        format!("<pre style=\"{}\">", css.join("; "))
+    };
+    for (i, line) in lines.iter().enumerate() {
+        if i > 0 {
+            html.push('\n');
+        }
+        let line_text = text.slice_to_string(line.start(), line.end());
+        let line_text = line_text.trim_end_matches(|c| c == '\n' || c == '\r');
+        let mut ix = 0;
+        for &(iv, ref style) in styles.get(i).map_or(&[][..], |spans| spans.as_slice()) {
+            let (start, end) = (iv.start().min(line_text.len()), iv.end().min(line_text.len()));
+            if start >= end {
+                continue;
+            }
+            push_escaped(&mut html, &line_text[ix..start]);
+            html.push_str(&format!("<span style=\"{}\">", style_css(style)));
+            push_escaped(&mut html, &line_text[start..end]);
+            html.push_str("</span>");
+            ix = end;
+        }
+        push_escaped(&mut html, &line_text[ix..]);
+    }
+    html.push_str("</pre>");
+    html
+}
+
+/// The inline CSS of `style`.
+fn style_css(style: &Style) -> String {
+    let mut css = Vec::new();
+    if let Some(fg) = style.fg_color {
+        css.push(format!("color: {}", css_color(fg)));
+    }
+    if let Some(bg) = style.bg_color {
+        css.push(format!("background-color: {}", css_color(bg)));
+    }
+    if let Some(weight) = style.weight {
+        css.push(format!("font-weight: {}", weight));
+    }
+    if style.italic == Some(true) {
+        css.push("font-style: italic".to_owned());
+    }
+    let mut decorations = Vec::new();
+    if style.underline == Some(true) {
+        decorations.push("underline");
+        if style.underline_style == Some(UnderlineStyle::Squiggle) {
+            decorations.push("wavy");
+        }
+    }
+    if style.strikethrough == Some(true) {
+        decorations.push("line-through");
+    }
+    if !decorations.is_empty() {
+        css.push(format!("text-decoration: {}", decorations.join(" ")));
+        if let (Some(color), Some(true)) = (style.underline_color, style.underline) {
+            css.push(format!("text-decoration-color: {}", css_color(color)));
+        }
+    }
+    css.join("; ")
+}
+
+/// The CSS of an ARGB color: `#rrggbb` if it is opaque.
+fn css_color(argb: u32) -> String {
+    let alpha = argb >> 24;
+    let rgb = argb & 0xFF_FFFF;
+    if alpha == 0xFF {
+        format!("#{:06x}", rgb)
+    } else {
+        format!("rgba({}, {}, {}, {:.3})", rgb >> 16, (rgb >> 8) & 0xFF, rgb & 0xFF,
+                alpha as f64 / 255.0)
+    }
+}
+
+fn push_escaped(html: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '&' => html.push_str("&amp;"),
+            '"' => html.push_str("&quot;"),
+            c => html.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn style(fg: u32) -> Style {
+        Style { fg_color: Some(fg), ..Style::default() }
+    }
+
+    #[test]
+    fn plain_text_is_escaped() {
+        let text = Rope::from("a <b> & \"c\"\nd");
+        let lines = vec![Interval::new_closed_open(0, 12), Interval::new_closed_open(12, 13)];
+        assert_eq!(render_html(&text, &lines, &[], None, None),
+                   "<pre>a &lt;b&gt; &amp; &quot;c&quot;\nd</pre>");
+    }
+
+    #[test]
+    fn styled_lines() {
+        let text = Rope::from("let x;\r\nfoo\n");
+        let lines = vec![Interval::new_closed_open(0, 8), Interval::new_closed_open(8, 12),
+                         Interval::new_closed_open(12, 12)];
+        let bold = Style { weight: Some(700), italic: Some(true), ..style(0xFF00_00FF) };
+        let styles = vec![
+            vec![(Interval::new_closed_open(0, 3), bold),
+                 (Interval::new_closed_open(4, 8), style(0x8000_FF00))],
+            vec![(Interval::new_closed_open(0, 4), style(0xFF00_FF00))],
+            Vec::new(),
+        ];
+        let html = render_html(&text, &lines, &styles, Some(0xFFFF_FFFF), Some(0xFF00_0000));
+        // the newlines within a span are outside it
+        assert_eq!(html, "<pre style=\"color: #ffffff; background-color: #000000\">\
+                          <span style=\"color: #0000ff; font-weight: 700; font-style: italic\">\
+                          let</span> <span style=\"color: rgba(0, 255, 0, 0.502)\">x;</span>\n\
+                          <span style=\"color: #00ff00\">foo</span>\n</pre>");
+    }
+
+    #[test]
+    fn decorations() {
+        let squiggle = Style {
+            underline: Some(true),
+            underline_style: Some(UnderlineStyle::Squiggle),
+            underline_color: Some(0xFFFF_0000),
+            strikethrough: Some(true),
+            ..Style::default()
+        };
+        assert_eq!(style_css(&squiggle), "text-decoration: underline wavy line-through; \
+                                          text-decoration-color: #ff0000");
+        assert_eq!(style_css(&Style::default()), "");
+    }
+}
@@ -0,0 +1,223 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Incidents, internal errors which core recovers from, recorded with
+//! enough of the context they happened in to reproduce them.
+//!
+//! A suspicious code site records an incident with `incident!`, which
+//! doesn't know which buffer it is editing. The editor handling the
+//! current command takes the incidents recorded since, adds the text
+//! around its carets, its recent deltas, its plugins and a hash of the
+//! config, and adds them to the `IncidentLog` shared by all buffers.
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem;
+
+use serde_json::{self, Value};
+
+use config::Config;
+
+/// The most incidents remembered. Older incidents are forgotten.
+pub const MAX_INCIDENTS: usize = 32;
+/// The number of characters recorded on each side of a caret.
+pub const CONTEXT_CHARS: usize = 32;
+/// The most carets whose context is recorded.
+pub const MAX_CARETS: usize = 16;
+/// The number of deltas recorded, the most recent applied to the buffer.
+pub const RECENT_DELTAS: usize = 5;
+/// The most characters of the text inserted by a delta which are
+/// recorded.
+pub const MAX_DELTA_TEXT: usize = 2 * CONTEXT_CHARS;
+
+/// Where an incident happened, and what went wrong, before the context
+/// is added to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Site {
+    pub location: String,
+    pub message: String,
+}
+
+thread_local! {
+    static PENDING: RefCell<Vec<Site>> = RefCell::new(Vec::new());
+}
+
+/// Records an incident at `location`, which the editor handling the
+/// current command adds its context to. Use `incident!` rather than
+/// calling this.
+pub fn record(location: String, message: String) {
+    print_err!("incident at {}: {}", location, message);
+    PENDING.with(|pending| pending.borrow_mut().push(Site {
+        location: location,
+        message: message,
+    }));
+}
+
+/// Returns the incidents recorded on this thread since this was last
+/// called.
+pub fn take_pending() -> Vec<Site> {
+    PENDING.with(|pending| mem::replace(&mut *pending.borrow_mut(), Vec::new()))
+}
+
+/// The text around a caret when an incident happened.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CaretContext {
+    pub offset: usize,
+    pub before: String,
+    pub after: String,
+}
+
+/// A delta recently applied to the buffer, as its summary.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DeltaRecord {
+    /// The interval of the old text which was replaced.
+    pub start: usize,
+    pub end: usize,
+    /// The length of the text which replaced it.
+    pub new_len: usize,
+    /// The start of the text which replaced it, at most `MAX_DELTA_TEXT`
+    /// characters.
+    pub inserted: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Incident {
+    /// Assigned when the incident is added to the log.
+    pub id: u64,
+    /// The source file and line of the site which recorded the incident.
+    pub location: String,
+    pub message: String,
+    pub carets: Vec<CaretContext>,
+    /// The most recent deltas applied to the buffer, oldest first.
+    pub recent_deltas: Vec<DeltaRecord>,
+    /// The names of the plugins running for the buffer.
+    pub plugins: Vec<String>,
+    pub config_hash: String,
+    /// If true, the text in `carets` and `recent_deltas` is redacted.
+    pub redacted: bool,
+}
+
+/// The incidents most recently recorded by all buffers.
+#[derive(Debug, Default)]
+pub struct IncidentLog {
+    next_id: u64,
+    incidents: VecDeque<Incident>,
+    /// The locations the user has been alerted of incidents at.
+    alerted: BTreeSet<String>,
+}
+
+impl IncidentLog {
+    /// Adds `incident` to the log, giving it an id, and forgetting the
+    /// oldest incidents beyond `MAX_INCIDENTS`. Returns the incident if
+    /// it is the first at its location, which the user should be alerted
+    /// of.
+    pub fn push(&mut self, mut incident: Incident) -> Option<&Incident> {
+        self.next_id += 1;
+        incident.id = self.next_id;
+        let is_first = self.alerted.insert(incident.location.clone());
+        self.incidents.push_back(incident);
+        while self.incidents.len() > MAX_INCIDENTS {
+            self.incidents.pop_front();
+        }
+        if is_first { self.incidents.back() } else { None }
+    }
+
+    pub fn incidents(&self) -> &VecDeque<Incident> {
+        &self.incidents
+    }
+
+    /// Returns the incidents, oldest first, as returned by `get_incidents`.
+    pub fn to_json(&self) -> Value {
+        json!({ "incidents": self.incidents })
+    }
+}
+
+/// Returns `text` with its letters replaced by `x` or `X`, its digits by
+/// `0` and its other characters by `?`, keeping whitespace and ASCII
+/// punctuation, so that the shape of the text is kept but not what it
+/// says.
+pub fn redact(text: &str) -> String {
+    text.chars().map(|c| if c.is_alphabetic() {
+        if c.is_uppercase() { 'X' } else { 'x' }
+    } else if c.is_numeric() {
+        '0'
+    } else if c.is_whitespace() || c.is_ascii_punctuation() {
+        c
+    } else {
+        '?'
+    }).collect()
+}
+
+/// Returns a hash of `config`, which tells whether incidents happened
+/// with the same settings without recording them.
+pub fn config_hash(config: &Config) -> String {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(config).unwrap().hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn incident(location: &str) -> Incident {
+        Incident {
+            id: 0,
+            location: location.to_owned(),
+            message: "lengths differ".to_owned(),
+            carets: Vec::new(),
+            recent_deltas: Vec::new(),
+            plugins: Vec::new(),
+            config_hash: config_hash(&Config::default()),
+            redacted: true,
+        }
+    }
+
+    #[test]
+    fn redacting() {
+        assert_eq!(redact("let Ünï = foo(42);\n\t// 😀"), "xxx Xxx = xxx(00);\n\t// ?");
+    }
+
+    #[test]
+    fn incident_log() {
+        let mut log = IncidentLog::default();
+        assert_eq!(log.push(incident("layers.rs:1")).map(|i| i.id), Some(1));
+        // the user is alerted once for each location
+        assert_eq!(log.push(incident("layers.rs:1")), None);
+        for _ in 0..MAX_INCIDENTS {
+            log.push(incident("editor.rs:2"));
+        }
+        assert_eq!(log.incidents().len(), MAX_INCIDENTS);
+        assert_eq!(log.incidents().front().unwrap().id, 3);
+        assert_eq!(log.to_json()["incidents"][0]["location"], "editor.rs:2");
+
+        let mut config = Config::default();
+        assert_eq!(config_hash(&config), config_hash(&Config::default()));
+        config.tab_size = 2;
+        assert!(config_hash(&config) != config_hash(&Config::default()));
+    }
+
+    #[test]
+    fn pending_sites() {
+        take_pending();
+        incident!("{} != {}", 1, 2);
+        let pending = take_pending();
+        assert_eq!(pending.len(), 1);
+        assert!(pending[0].location.contains("incidents.rs:"));
+        assert_eq!(pending[0].message, "1 != 2");
+        assert!(take_pending().is_empty());
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Language-specific rules for automatic indentation.
+
+use syntax::SyntaxDefinition;
+
+/// A declarative description of how a language is indented.
+///
+/// Triggers are simple patterns, matched against a line with its leading and
+/// trailing whitespace removed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct IndentRules {
+    /// If a line ends with one of these, the following line is indented.
+    pub increase_after: Vec<String>,
+    /// If a line begins with one of these words, the following
+    /// line is dedented.
+    pub decrease_after: Vec<String>,
+    /// If a line consists entirely of one of these, it is dedented. This
+    /// is checked as each character is typed.
+    pub decrease_this: Vec<String>,
+    /// If true, a line following one with unclosed brackets is indented.
+    pub hanging_indent: bool,
+}
+
+impl IndentRules {
+    /// Returns the builtin rules for a given syntax.
+    pub fn for_syntax(syntax: &SyntaxDefinition) -> Self {
+        use syntax::SyntaxDefinition::*;
+        match *syntax {
+            Rust | C | Cpp | Objc | Go | Dart | Swift | Java | Javascript | Php | Json => {
+                IndentRules {
+                    increase_after: strings(&["{", "(", "["]),
+                    decrease_after: Vec::new(),
+                    decrease_this: strings(&["}", ")", "]", "};", "),", "});"]),
+                    hanging_indent: true,
+                }
+            }
+            Python => IndentRules {
+                increase_after: strings(&[":"]),
+                decrease_after: strings(&["return", "pass", "break", "continue", "raise"]),
+                decrease_this: strings(&["else:", "finally:"]),
+                hanging_indent: true,
+            },
+            Yaml => IndentRules {
+                increase_after: strings(&[":"]),
+                decrease_after: Vec::new(),
+                decrease_this: Vec::new(),
+                hanging_indent: false,
+            },
+            _ => IndentRules::default(),
+        }
+    }
+
+    /// Returns the indent, in columns, of a line inserted after `prefix`,
+    /// which is the text of the current line preceding the caret, and which
+    /// has an indent of `indent` columns. `bracket_delta` is the number of
+    /// brackets the current line leaves open, as its `LineMetadata` counts
+    /// them.
+    pub fn next_line_indent(&self, prefix: &str, indent: usize, bracket_delta: i64,
+                            tab_size: usize) -> usize {
+        let line = prefix.trim();
+        if self.increase_after.iter().any(|t| line.ends_with(t.as_str())) {
+            indent + tab_size
+        } else if self.decrease_after.iter().any(|t| starts_with_word(line, t)) {
+            indent.saturating_sub(tab_size)
+        } else if self.hanging_indent && bracket_delta > 0 {
+            indent + tab_size
+        } else {
+            indent
+        }
+    }
+
+    /// Returns `true` if a line consisting of `line` should be dedented.
+    pub fn is_electric(&self, line: &str) -> bool {
+        let line = line.trim();
+        self.decrease_this.iter().any(|t| t == line)
+    }
+
+    /// Returns the indent an electric line should have, given the text
+    /// and indent of the closest preceding non-blank line.
+    pub fn electric_indent(&self, prev_line: &str, prev_indent: usize,
+                           tab_size: usize) -> usize {
+        let prev_line = prev_line.trim();
+        if self.increase_after.iter().any(|t| prev_line.ends_with(t.as_str())) {
+            prev_indent
+        } else {
+            prev_indent.saturating_sub(tab_size)
+        }
+    }
+}
+
+/// How indentation is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndentStyle {
+    /// The number of columns between tab stops, which is also the width
+    /// of one level of indentation.
+    pub tab_size: usize,
+    /// If true, indentation is written with spaces, rather than with tabs
+    /// and then spaces.
+    pub use_spaces: bool,
+}
+
+impl IndentStyle {
+    /// Returns the number of columns spanned by `text`, as whitespace at
+    /// the start of a line.
+    pub fn width(&self, text: &str) -> usize {
+        text.chars().fold(0, |col, c| self.advance(col, c))
+    }
+
+    /// Returns the column following `c`, if it is at column `col`.
+    pub fn advance(&self, col: usize, c: char) -> usize {
+        match c {
+            '\t' => col + self.tab_size - col % self.tab_size,
+            _ => col + 1,
+        }
+    }
+
+    /// Returns whitespace spanning `width` columns.
+    pub fn whitespace(&self, width: usize) -> String {
+        if self.use_spaces {
+            " ".repeat(width)
+        } else {
+            format!("{}{}", "\t".repeat(width / self.tab_size),
+                    " ".repeat(width % self.tab_size))
+        }
+    }
+
+    /// Returns `ws`, which is whitespace at the start of a line, written in
+    /// this style with the same width. If `snap` is true, the width is
+    /// rounded to the nearest whole level of indentation, but not to zero.
+    pub fn reindent(&self, ws: &str, snap: bool) -> String {
+        let mut width = self.width(ws);
+        if snap && width > 0 {
+            let levels = (width + self.tab_size / 2) / self.tab_size;
+            width = levels.max(1) * self.tab_size;
+        }
+        self.whitespace(width)
+    }
+}
+
+fn strings(items: &[&str]) -> Vec<String> {
+    items.iter().map(|s| s.to_string()).collect()
+}
+
+/// Returns `true` if `line` starts with `word`, followed by a non-word
+/// character or the end of the line.
+fn starts_with_word(line: &str, word: &str) -> bool {
+    line.starts_with(word) && match line[word.len()..].chars().next() {
+        Some(c) => !(c.is_alphanumeric() || c == '_'),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use line_metadata::{BracketDeltaAnalyzer, LineAnalyzer};
+    use syntax::SyntaxDefinition;
+
+    // (prefix, current indent, expected indent of next line)
+    const RUST_CASES: &[(&str, usize, usize)] = &[
+        ("fn main() {", 0, 4),
+        ("    let x = 5;", 4, 4),
+        ("    let v = vec![", 4, 8),
+        ("    foo(a,", 4, 8),
+        ("    foo(a, b);", 4, 4),
+        ("}", 0, 0),
+    ];
+
+    const PYTHON_CASES: &[(&str, usize, usize)] = &[
+        ("def main():", 0, 4),
+        ("    x = 5", 4, 4),
+        ("    return x", 4, 0),
+        ("    returned = 1", 4, 4),
+        ("    pass", 4, 0),
+        ("    foo(a,", 4, 8),
+    ];
+
+    const YAML_CASES: &[(&str, usize, usize)] = &[
+        ("key:", 0, 4),
+        ("- item", 2, 2),
+        ("  name: value", 2, 2),
+    ];
+
+    const PLAINTEXT_CASES: &[(&str, usize, usize)] = &[
+        ("    some text:", 4, 4),
+        ("(", 0, 0),
+    ];
+
+    fn check(syntax: SyntaxDefinition, cases: &[(&str, usize, usize)]) {
+        let rules = IndentRules::for_syntax(&syntax);
+        for &(prefix, indent, expected) in cases {
+            let bracket_delta = BracketDeltaAnalyzer.analyze(prefix);
+            assert_eq!(rules.next_line_indent(prefix, indent, bracket_delta, 4), expected,
+                       "{:?}: {:?}", syntax, prefix);
+        }
+    }
+
+    #[test]
+    fn next_line_indent() {
+        check(SyntaxDefinition::Rust, RUST_CASES);
+        check(SyntaxDefinition::Python, PYTHON_CASES);
+        check(SyntaxDefinition::Yaml, YAML_CASES);
+        check(SyntaxDefinition::Plaintext, PLAINTEXT_CASES);
+    }
+
+    #[test]
+    fn electric() {
+        let rust = IndentRules::for_syntax(&SyntaxDefinition::Rust);
+        assert!(rust.is_electric("    }"));
+        assert!(!rust.is_electric("    } else {"));
+        assert_eq!(rust.electric_indent("    foo();", 4, 4), 0);
+        assert_eq!(rust.electric_indent("fn main() {", 0, 4), 0);
+
+        let python = IndentRules::for_syntax(&SyntaxDefinition::Python);
+        assert!(python.is_electric("    else:"));
+        assert!(!python.is_electric("    elsewhere:"));
+        assert_eq!(python.electric_indent("        x = 1", 8, 4), 4);
+    }
+
+    #[test]
+    fn reindent() {
+        let tabs = IndentStyle { tab_size: 4, use_spaces: false };
+        let spaces = IndentStyle { use_spaces: true, ..tabs };
+        // tab stops, rather than tab widths, are kept
+        for &(ws, width) in &[("\t", 4), ("  \t", 4), ("\t  ", 6), (" \t \t", 8), ("     ", 5)] {
+            assert_eq!(tabs.width(ws), width, "{:?}", ws);
+            assert_eq!(spaces.reindent(ws, false), " ".repeat(width));
+            assert_eq!(tabs.width(&tabs.reindent(ws, false)), width);
+        }
+        assert_eq!(tabs.reindent("  \t  \t ", false), "\t\t ");
+        assert_eq!(tabs.reindent("      ", false), "\t  ");
+        assert_eq!(IndentStyle { tab_size: 8, ..tabs }.width("  \t"), 8);
+
+        assert_eq!(spaces.reindent("      ", true), " ".repeat(8));
+        assert_eq!(spaces.reindent("     ", true), " ".repeat(4));
+        assert_eq!(spaces.reindent(" ", true), " ".repeat(4));
+        assert_eq!(tabs.reindent("", true), "");
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Showing the invisible characters of a buffer: a layer of scopes, kept
+//! by the core, marking its tabs, trailing whitespace and control
+//! characters, which the theme colors as it does any other scopes.
+//!
+//! The whole buffer is marked when they're shown; after that, only the
+//! lines an edit touched are marked again.
+
+use xi_rope::interval::Interval;
+use xi_rope::rope::{LinesMetric, Rope};
+use xi_rope::spans::{Spans, SpansBuilder};
+
+use layers::{CoreLayer, LayerId, Scopes};
+use tabs::DocumentCtx;
+
+/// The layer marking invisible characters, while they're shown.
+const LAYER: LayerId = LayerId::Core(CoreLayer::Invisibles);
+
+/// The scopes of the layer, by their ids in its spans.
+const SCOPES: [&str; 3] = ["invisible.tab", "invisible.trailing-space", "invisible.control"];
+const TAB: u32 = 0;
+const TRAILING_SPACE: u32 = 1;
+const CONTROL: u32 = 2;
+
+/// The layer's priority, above those of plugins, so that the invisible
+/// characters are marked whatever their syntax.
+const PRIORITY: u32 = u32::max_value();
+
+/// Whether a buffer's invisible characters are shown.
+#[derive(Debug, Default)]
+pub struct Invisibles {
+    shown: bool,
+}
+
+impl Invisibles {
+    pub fn new() -> Self {
+        Invisibles::default()
+    }
+
+    pub fn is_shown(&self) -> bool {
+        self.shown
+    }
+
+    /// Shows or hides the invisible characters of `text`, adding its layer
+    /// to `styles` or removing it. If `mark_cr`, the carriage returns of
+    /// CRLF line endings are marked as trailing whitespace. Returns the
+    /// region whose styles changed.
+    pub fn set_shown(&mut self, shown: bool, text: &Rope, mark_cr: bool, styles: &mut Scopes,
+                     doc_ctx: &DocumentCtx) -> Option<Interval> {
+        if shown == self.shown {
+            return None;
+        }
+        self.shown = shown;
+        let all = Interval::new_closed_open(0, text.len());
+        if !shown {
+            return styles.remove_layer(LAYER).map(|_| all);
+        }
+        let stacks = SCOPES.iter().map(|&scope| vec![scope.to_owned()]).collect();
+        if let Err(err) = styles.add_scopes(LAYER, stacks, doc_ctx) {
+            print_err!("failed to add the scopes of invisibles: {}", err);
+        }
+        let spans = mark_lines(text, all, mark_cr);
+        match styles.update_layer(LAYER, all, spans, Some(PRIORITY), None) {
+            Ok(()) => Some(all),
+            Err(err) => {
+                print_err!("failed to mark invisibles: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Marks again the lines of `text` touched by the edit which replaced
+    /// `iv` with `new_len` bytes, as inserting a newline changes whether
+    /// the whitespace before it trails. Returns the region whose styles
+    /// changed.
+    pub fn after_edit(&self, text: &Rope, iv: Interval, new_len: usize, mark_cr: bool,
+                      styles: &mut Scopes) -> Option<Interval> {
+        if !self.shown {
+            return None;
+        }
+        let first = text.line_of_offset(iv.start());
+        let last = text.line_of_offset(iv.start() + new_len);
+        let end = if last < text.measure::<LinesMetric>() {
+            text.offset_of_line(last + 1)
+        } else {
+            text.len()
+        };
+        let lines = Interval::new_closed_open(text.offset_of_line(first), end);
+        let spans = mark_lines(text, lines, mark_cr);
+        match styles.update_layer(LAYER, lines, spans, None, None) {
+            Ok(()) => Some(lines),
+            Err(err) => {
+                print_err!("failed to mark invisibles: {}", err);
+                None
+            }
+        }
+    }
+}
+
+/// Returns the spans marking the invisible characters of `lines`, whole
+/// lines of `text`, relative to their start.
+fn mark_lines(text: &Rope, lines: Interval, mark_cr: bool) -> Spans<u32> {
+    let mut sb = SpansBuilder::new(lines.size());
+    let mut offset = 0;
+    for line in text.lines_raw(lines.start(), lines.end()) {
+        let ending = if line.ends_with("\r\n") {
+            2
+        } else if line.ends_with('\n') {
+            1
+        } else {
+            0
+        };
+        let content = &line[..line.len() - ending];
+        let trailing = content.trim_end_matches(|c| c == ' ' || c == '\t').len();
+        for (i, c) in content[..trailing].char_indices() {
+            let scope = match c {
+                '\t' => TAB,
+                c if c.is_control() => CONTROL,
+                _ => continue,
+            };
+            let start = offset + i;
+            sb.add_span(Interval::new_closed_open(start, start + c.len_utf8()), scope);
+        }
+        let trailing_end = if mark_cr && ending == 2 { content.len() + 1 } else { content.len() };
+        if trailing < trailing_end {
+            let iv = Interval::new_closed_open(offset + trailing, offset + trailing_end);
+            sb.add_span(iv, TRAILING_SPACE);
+        }
+        offset += line.len();
+    }
+    sb.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marked(text: &str, mark_cr: bool) -> Vec<(usize, usize, u32)> {
+        let rope = Rope::from(text);
+        mark_lines(&rope, Interval::new_closed_open(0, text.len()), mark_cr).iter()
+            .map(|(iv, &scope)| (iv.start(), iv.end(), scope))
+            .collect()
+    }
+
+    #[test]
+    fn marks() {
+        assert_eq!(marked("\tfoo \t\nbar\u{1b}  \n  \nbaz \t", false), vec![
+            (0, 1, TAB), (4, 6, TRAILING_SPACE), (10, 11, CONTROL), (11, 13, TRAILING_SPACE),
+            (14, 16, TRAILING_SPACE), (20, 22, TRAILING_SPACE),
+        ]);
+        assert!(marked("foo\nbar\n", false).is_empty());
+    }
+
+    #[test]
+    fn crlf() {
+        // the carriage return of a line ending is only marked if asked,
+        // but one elsewhere is a control character
+        assert_eq!(marked("foo \r\nbar\r\n", false), vec![(3, 4, TRAILING_SPACE)]);
+        assert_eq!(marked("foo \r\nbar\r\n", true),
+                   vec![(3, 5, TRAILING_SPACE), (9, 10, TRAILING_SPACE)]);
+        assert_eq!(marked("a\rb\n", true), vec![(1, 2, CONTROL)]);
+    }
+}
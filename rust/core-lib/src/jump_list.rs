@@ -0,0 +1,179 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The history of the positions a view has jumped from, for navigating
+//! back and forward.
+
+use xi_rope::rope::RopeInfo;
+use xi_rope::delta::{Delta, Transformer};
+
+/// The maximum number of positions remembered.
+const MAX_JUMPS: usize = 100;
+
+#[derive(Debug, Default)]
+pub struct JumpList {
+    entries: Vec<usize>,
+    /// The index of the entry for the current position, or `entries.len()`
+    /// if the view hasn't jumped back.
+    current: usize,
+}
+
+impl JumpList {
+    /// Notes a jump from `offset`, discarding the positions which were
+    /// jumped back from.
+    pub fn push(&mut self, offset: usize) {
+        self.entries.truncate(self.current);
+        if self.entries.last() != Some(&offset) {
+            self.entries.push(offset);
+        }
+        if self.entries.len() > MAX_JUMPS {
+            self.entries.remove(0);
+        }
+        self.current = self.entries.len();
+    }
+
+    /// Returns the position to jump back to from `offset`, if any. If the
+    /// view hasn't jumped back yet, `offset` is remembered, so that it can
+    /// jump forward to it again.
+    pub fn back(&mut self, offset: usize) -> Option<usize> {
+        if self.current == self.entries.len() {
+            if self.entries.last() == Some(&offset) {
+                self.current -= 1;
+            } else {
+                self.entries.push(offset);
+                if self.entries.len() > MAX_JUMPS {
+                    self.entries.remove(0);
+                }
+                self.current = self.entries.len() - 1;
+            }
+        }
+        if self.current == 0 {
+            return None;
+        }
+        self.current -= 1;
+        Some(self.entries[self.current])
+    }
+
+    /// Returns the position to jump forward to, if the view has jumped back.
+    pub fn forward(&mut self) -> Option<usize> {
+        if self.current + 1 >= self.entries.len() {
+            return None;
+        }
+        self.current += 1;
+        Some(self.entries[self.current])
+    }
+
+    pub fn entries(&self) -> &[usize] {
+        &self.entries
+    }
+
+    /// The index in `entries` of the current position, which is
+    /// `entries().len()` unless the view has jumped back.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Updates the positions after `delta` has been applied. Positions in
+    /// deleted text move to the start of the deletion; positions which
+    /// become equal to their neighbours are merged.
+    pub fn after_edit(&mut self, delta: &Delta<RopeInfo>) {
+        let mut transformer = Transformer::new(delta);
+        let mut entries: Vec<usize> = Vec::with_capacity(self.entries.len());
+        let mut current = self.current;
+        for (i, &offset) in self.entries.iter().enumerate() {
+            let offset = transformer.transform(offset, true);
+            if entries.last() == Some(&offset) {
+                if i <= self.current {
+                    current -= 1;
+                }
+                continue;
+            }
+            entries.push(offset);
+        }
+        self.entries = entries;
+        self.current = current;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xi_rope::rope::Rope;
+    use xi_rope::interval::Interval;
+
+    #[test]
+    fn back_and_forward() {
+        let mut jumps = JumpList::default();
+        assert_eq!(jumps.back(10), None);
+        jumps.push(10);
+        jumps.push(20);
+        // at 30, after jumping from 20
+        assert_eq!(jumps.back(30), Some(20));
+        assert_eq!(jumps.back(20), Some(10));
+        assert_eq!(jumps.back(10), None);
+        assert_eq!(jumps.forward(), Some(20));
+        assert_eq!(jumps.forward(), Some(30));
+        assert_eq!(jumps.forward(), None);
+        assert_eq!(jumps.entries(), &[10, 20, 30]);
+
+        // a new jump truncates the forward history
+        assert_eq!(jumps.back(30), Some(20));
+        assert_eq!(jumps.back(20), Some(10));
+        jumps.push(10);
+        assert_eq!(jumps.entries(), &[10]);
+        assert_eq!(jumps.forward(), None);
+        assert_eq!(jumps.back(40), Some(10));
+    }
+
+    #[test]
+    fn bounded() {
+        let mut jumps = JumpList::default();
+        for i in 0..MAX_JUMPS + 10 {
+            jumps.push(i);
+        }
+        assert_eq!(jumps.entries().len(), MAX_JUMPS);
+        assert_eq!(jumps.entries()[0], 10);
+        assert_eq!(jumps.back(1000), Some(MAX_JUMPS + 9));
+        assert_eq!(jumps.entries().len(), MAX_JUMPS);
+    }
+
+    #[test]
+    fn edits() {
+        let text = Rope::from("0123456789".repeat(5));
+        let mut jumps = JumpList::default();
+        jumps.push(5);
+        jumps.push(25);
+        jumps.push(27);
+        jumps.push(45);
+        // insert before the second
+        let delta = Delta::simple_edit(Interval::new_closed_open(20, 20),
+                                       Rope::from("abc"), text.len());
+        jumps.after_edit(&delta);
+        assert_eq!(jumps.entries(), &[5, 28, 30, 48]);
+
+        // jumping back from the end, then deleting the text around 28 and 30
+        assert_eq!(jumps.back(50), Some(48));
+        assert_eq!(jumps.back(48), Some(30));
+        assert_eq!(jumps.current(), 2);
+        let text = delta.apply(&text);
+        let delta = Delta::simple_edit(Interval::new_closed_open(26, 32),
+                                       Rope::from(""), text.len());
+        jumps.after_edit(&delta);
+        assert_eq!(jumps.entries(), &[5, 26, 42, 44]);
+        assert_eq!(jumps.current(), 1);
+        assert_eq!(jumps.back(26), Some(5));
+        assert_eq!(jumps.forward(), Some(26));
+        assert_eq!(jumps.forward(), Some(42));
+    }
+}
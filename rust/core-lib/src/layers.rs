@@ -18,120 +18,1766 @@
 //! Scope information originating from any number of plugins can be resolved
 //! into styles using a theme, augmented with additional style definitions.
 
-use std::collections::BTreeMap;
+use std::cmp::{max, min, Reverse};
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::str::FromStr;
+use serde_json;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use syntect::parsing::Scope;
+use syntect::highlighting::{Highlighter, ScopeSelectors, Theme};
 
 use xi_rope::interval::Interval;
+use xi_rope::rope::{Rope, RopeDelta};
 use xi_rope::spans::{Spans, SpansBuilder};
 
 use tabs::DocumentCtx;
-use styles::Style;
+use styles::{Style, StyleDelta, UnderlineStyle, SYNTAX_PRIORITY_DEFAULT};
 use plugins::PluginPid;
 
+/// The entry of a scope id whose stack was dropped by compacting its
+/// layer.
+const RETIRED_SCOPE: u32 = u32::max_value();
+
+/// The style priority of hints, above that of any scope's style; those
+/// of more severe diagnostics are higher still.
+const DIAGNOSTIC_PRIORITY: u16 = 900;
+
+/// The most edits a detached layer is kept for, waiting to be adopted.
+const MAX_DETACHED_EDITS: usize = 100;
+
+/// The priority of the styles of find highlights, which are merged over
+/// those of every layer and diagnostic.
+const FIND_HIGHLIGHT_PRIORITY: u16 = 950;
+
+/// How much of the selection color is blended into the colors of selected
+/// text, out of 255, where the theme's selection color is opaque.
+const SELECTION_ALPHA: u8 = 64;
+
 /// A collection of layers containing scope information.
 #[derive(Default)]
 //TODO: rename. Probably to `Layers`
 pub struct Scopes {
-    layers: BTreeMap<PluginPid, ScopeLayer>,
+    layers: BTreeMap<LayerId, ScopeLayer>,
+    /// Styles set directly rather than resolved from scopes, as on the
+    /// text of a scratch buffer.
+    direct: Option<Spans<Style>>,
     merged: Spans<Style>,
+    /// While an update is begun, the intervals whose styles are yet to be
+    /// resolved.
+    pending: Option<Vec<Interval>>,
+    /// The interval spanning those whose styles changed since the merged
+    /// styles were last read, which are resolved once they are.
+    dirty: Option<Interval>,
+    /// The regions, as a map from start to end, whose spans were cleared
+    /// or guessed since they were last taken, so that plugins can be asked
+    /// to highlight just those again. They don't overlap or touch.
+    invalidated: BTreeMap<usize, usize>,
+    /// The diagnostics of each plugin, such as a language server's errors,
+    /// whose styles are merged above those of every layer.
+    diagnostics: BTreeMap<PluginPid, Spans<DiagnosticSpan>>,
+    /// Counts the changes to the merged styles and the diagnostics.
+    version: u64,
+    /// The annotations of each plugin, by offset.
+    annotations: BTreeMap<PluginPid, BTreeMap<usize, Vec<Annotation>>>,
+    /// The layers of plugins which send styles rather than scopes.
+    style_layers: BTreeMap<LayerId, StyleLayer>,
+    /// The layers of plugins which stopped, with the number of edits since;
+    /// they're kept for the plugin to adopt once it is restarted.
+    detached: BTreeMap<PluginPid, usize>,
+    /// The intervals, in order, whose scopes are yet to be restyled for a
+    /// new theme; they keep the old theme's styles until they are.
+    restyling: Vec<Interval>,
+    /// The user's changes to the theme's styles of the scopes matching each
+    /// selector, in the order they were added, so that the later wins.
+    overrides: Vec<(ScopeSelectors, StyleDelta)>,
+    /// The highlights of the document's search results, whose style is
+    /// merged over those of every layer and the diagnostics.
+    search_layer: Option<Spans<Style>>,
+}
+
+/// A layer whose styles are merged: a plugin's, or one the core keeps
+/// itself. It is serialized as the plugin's pid, or the name of the core
+/// layer.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(untagged)]
+pub enum LayerId {
+    Plugin(PluginPid),
+    Core(CoreLayer),
+}
+
+impl LayerId {
+    /// Returns the plugin whose layer this is, or `None` if it is core's.
+    pub fn plugin(self) -> Option<PluginPid> {
+        match self {
+            LayerId::Plugin(pid) => Some(pid),
+            LayerId::Core(_) => None,
+        }
+    }
+}
+
+impl From<PluginPid> for LayerId {
+    fn from(pid: PluginPid) -> LayerId {
+        LayerId::Plugin(pid)
+    }
+}
+
+/// The layers the core keeps itself. Those of a view, its find highlights
+/// and selection, are kept in its `Overlay` rather than with the document's
+/// layers, as its views have their own selections and searches. They're
+/// merged over the document's styles as a view's lines are rendered, above
+/// every plugin's, and in this order.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum CoreLayer {
+    /// Core's built-in syntax highlighting, a layer of the document.
+    Syntax,
+    /// The invisible characters, while they're shown, a layer of the
+    /// document.
+    Invisibles,
+    FindHighlights,
+    Selection,
+}
+
+/// Whether selected text is in the primary selection, that of the caret,
+/// or in another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionKind {
+    Primary,
+    Secondary,
+}
+
+impl Default for SelectionKind {
+    fn default() -> Self {
+        SelectionKind::Primary
+    }
+}
+
+/// The styles of the core layers, from the theme.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoreStyles {
+    find_highlight: Style,
+    selection_color: Option<u32>,
+}
+
+impl CoreStyles {
+    pub fn for_theme(theme: &Theme) -> CoreStyles {
+        CoreStyles {
+            find_highlight: Style::find_highlight_for_theme(theme, FIND_HIGHLIGHT_PRIORITY),
+            selection_color: Style::selection_color_for_theme(theme),
+        }
+    }
+}
+
+/// The spans of a view's core layers within some region of the text, such
+/// as a line being rendered.
+#[derive(Debug, Default)]
+pub struct Overlay {
+    layers: BTreeMap<CoreLayer, Vec<(Interval, SelectionKind)>>,
+}
+
+impl Overlay {
+    /// Adds a span to a core layer, after those added to it before. The
+    /// kind only matters to the selection.
+    pub fn add_span(&mut self, layer: CoreLayer, iv: Interval, kind: SelectionKind) {
+        self.layers.entry(layer).or_insert_with(Vec::new).push((iv, kind));
+    }
+
+    /// Sets the selected text of the region to `spans`, replacing any
+    /// selection added before.
+    pub fn set_selection_spans(&mut self, spans: Spans<SelectionKind>) {
+        let spans = spans.iter().map(|(iv, &kind)| (iv, kind)).collect();
+        self.layers.insert(CoreLayer::Selection, spans);
+    }
+
+    /// Merges the core layers over `styles`, which are of the same region.
+    /// Find highlights are merged as styles; selected text has the
+    /// selection color as its background, and its colors tinted with it,
+    /// less so if it is secondary.
+    pub fn apply(&self, mut styles: Spans<Style>, core_styles: &CoreStyles) -> Spans<Style> {
+        for (&layer, spans) in &self.layers {
+            match layer {
+                // the document's layers are already merged into `styles`
+                CoreLayer::Syntax | CoreLayer::Invisibles => (),
+                CoreLayer::FindHighlights => {
+                    let mut sb = SpansBuilder::new(styles.len());
+                    for &(iv, _) in spans {
+                        sb.add_span(iv, core_styles.find_highlight.clone());
+                    }
+                    styles = styles.merge(&sb.build(), |a: &Style, b: Option<&Style>| match b {
+                        Some(b) => a.merge(b),
+                        None => a.to_owned(),
+                    });
+                }
+                CoreLayer::Selection => {
+                    let color = match core_styles.selection_color {
+                        Some(color) => color,
+                        None => continue,
+                    };
+                    let alpha = match (color >> 24) as u8 {
+                        0xFF => SELECTION_ALPHA,
+                        alpha => alpha,
+                    };
+                    // unstyled text is given just the background
+                    let selected = Style { bg_color: Some(color), ..Style::default() };
+                    for &(sel_iv, kind) in spans {
+                        let alpha = match kind {
+                            SelectionKind::Primary => alpha,
+                            SelectionKind::Secondary => alpha / 2,
+                        };
+                        let mut sb = SpansBuilder::new(sel_iv.size());
+                        let mut ix = 0;
+                        for (span_iv, style) in styles.subseq(sel_iv).iter() {
+                            if span_iv.start() > ix {
+                                sb.add_span(Interval::new_closed_open(ix, span_iv.start()),
+                                            selected.clone());
+                            }
+                            sb.add_span(span_iv, Style { bg_color: Some(color),
+                                                         ..style.blend_fg(color, alpha) });
+                            ix = span_iv.end();
+                        }
+                        if ix < sel_iv.size() {
+                            sb.add_span(Interval::new_closed_open(ix, sel_iv.size()),
+                                        selected.clone());
+                        }
+                        styles.edit(sel_iv, sb.build());
+                    }
+                }
+            }
+        }
+        styles
+    }
+}
+
+/// Text shown at an offset in the buffer without being in it, such as a
+/// type hint after the name of a variable.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Annotation {
+    pub offset: usize,
+    pub text: String,
+    pub style: Style,
+}
+
+/// The merged styles and the diagnostics of `Scopes` as of a version,
+/// which can be read, as for rendering, while the layers change.
+#[derive(Clone)]
+pub struct ScopesSnapshot {
+    merged: Spans<Style>,
+    diagnostics: BTreeMap<PluginPid, Spans<DiagnosticSpan>>,
+    version: u64,
+}
+
+impl ScopesSnapshot {
+    pub fn get_merged(&self) -> &Spans<Style> {
+        &self.merged
+    }
+
+    /// Returns the most severe diagnostic at `offset`, as for
+    /// `Scopes::diagnostic_at`.
+    pub fn diagnostic_at(&self, offset: usize) -> Option<&DiagnosticSpan> {
+        diagnostic_at(&self.diagnostics, offset)
+    }
+
+    /// The version of the styles this is a snapshot of; it is current while
+    /// that of the `Scopes` is the same.
+    pub fn current_version(&self) -> u64 {
+        self.version
+    }
+}
+
+/// How severe a diagnostic is, most severe first.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum DiagnosticSeverity {
+    Error,
+    Warning,
+    Information,
+    Hint,
+}
+
+impl Default for DiagnosticSeverity {
+    fn default() -> Self {
+        DiagnosticSeverity::Error
+    }
+}
+
+impl DiagnosticSeverity {
+    /// Returns the style of text with a diagnostic of this severity: a red
+    /// squiggle under errors, yellow under warnings, blue under information,
+    /// and grey text for hints. Squiggles keep the text's own colors.
+    pub fn style(self) -> Style {
+        use self::DiagnosticSeverity::*;
+        let priority = DIAGNOSTIC_PRIORITY + 3 - self as u16;
+        let squiggle = |color| Style {
+            priority: priority,
+            underline: Some(true),
+            underline_style: Some(UnderlineStyle::Squiggle),
+            underline_color: Some(color),
+            ..Style::default()
+        };
+        match self {
+            Error => squiggle(0xFFD7_3A49),
+            Warning => squiggle(0xFFB0_8800),
+            Information => squiggle(0xFF03_66D6),
+            Hint => Style::new(priority, Some(0xFF95_9DA5), None, None, None, None),
+        }
+    }
+}
+
+/// A diagnostic of a span of text, as reported by a linter or language
+/// server.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiagnosticSpan {
+    pub severity: DiagnosticSeverity,
+    pub message: String,
+}
+
+/// The kind of an edit to the text, which determines what becomes of the
+/// spans around it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditType {
+    /// Text is inserted without any being deleted. The span around the
+    /// insertion, or ending at it, grows to cover the inserted text, as a
+    /// plugin would most likely extend it.
+    Insert,
+    /// Text is deleted, along with the spans within it; those around it
+    /// shrink.
+    Delete,
+    /// Text is replaced. The inserted text has no spans until they are
+    /// sent again.
+    Replace,
+}
+
+impl EditType {
+    /// Returns the kind of the edit replacing `iv` with `new_len` units.
+    pub fn of(iv: Interval, new_len: usize) -> EditType {
+        if iv.is_empty() {
+            EditType::Insert
+        } else if new_len == 0 {
+            EditType::Delete
+        } else {
+            EditType::Replace
+        }
+    }
+}
+
+/// How the spans of a layer are moved by edits to the text, as chosen by
+/// its plugin.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EditPolicy {
+    /// The spans within the edit are cleared, for the plugin to send them
+    /// again; those around it are moved as `EditType` describes.
+    Clear,
+    /// The spans are moved with the text, as breakpoints or bookmarks
+    /// would be: those overlapping the edit are cut at it, and the
+    /// inserted text has none, even within a span.
+    Shift,
+    /// The span overlapping or touching the edit grows to cover the
+    /// inserted text, like a mark; the one before it is chosen over the one
+    /// after.
+    Extend,
+}
+
+impl Default for EditPolicy {
+    fn default() -> Self {
+        EditPolicy::Clear
+    }
+}
+
+impl EditPolicy {
+    /// Updates `spans` for the edit replacing `iv` with `len` units.
+    fn apply<T: Clone + Default>(self, spans: &mut Spans<T>, iv: Interval, len: usize) {
+        match (self, EditType::of(iv, len)) {
+            (EditPolicy::Clear, EditType::Insert) => insert_into_spans(spans, iv.start(), len),
+            (EditPolicy::Extend, _) => extend_over_edit(spans, iv, len),
+            _ => spans.edit(iv, SpansBuilder::new(len).build()),
+        }
+    }
+}
+
+/// How the styles of a layer are merged with those of the layers below.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CompositeMode {
+    /// The attributes the layer's style sets are merged over those below,
+    /// as decided by their priorities.
+    Overlay,
+    /// The layer's style replaces those below, within its spans.
+    Replace,
+    /// The foreground color of the layer's style is multiplied with that
+    /// below, as for dimming text; the other attributes are left as they
+    /// are. It is used as it is where nothing is below.
+    Tint,
+}
+
+impl Default for CompositeMode {
+    fn default() -> Self {
+        CompositeMode::Overlay
+    }
+}
+
+impl CompositeMode {
+    /// Merges `style`, a layer's, with `below`, the styles of the layers
+    /// below it.
+    fn blend(self, below: &Style, style: &Style) -> Style {
+        match self {
+            CompositeMode::Overlay => below.merge(style),
+            CompositeMode::Replace => style.to_owned(),
+            CompositeMode::Tint => {
+                let fg_color = match (below.fg_color, style.fg_color) {
+                    (Some(fg), Some(tint)) => Some(multiply_colors(fg, tint)),
+                    (fg, tint) => fg.or(tint),
+                };
+                Style { fg_color: fg_color, ..below.to_owned() }
+            }
+        }
+    }
+}
+
+/// Multiplies each channel of two ARGB colors.
+fn multiply_colors(a: u32, b: u32) -> u32 {
+    (0..4).map(|i| i * 8).fold(0, |color, shift| {
+        let channel = ((a >> shift) & 0xFF) * ((b >> shift) & 0xFF) / 0xFF;
+        color | channel << shift
+    })
+}
+
+/// An operation on the spans of a layer, applied in order from the start
+/// of the buffer.
+#[derive(Debug, Clone)]
+pub enum LayerOp {
+    /// Keeps the spans of the next `n` units.
+    Retain(usize),
+    /// Clears the spans of the next `n` units.
+    Skip(usize),
+    /// Replaces the spans of the next units with these, which are as long.
+    Insert(Spans<u32>),
+}
+
+/// The spans of a layer within an interval, for inspecting how styles
+/// are resolved.
+#[derive(Serialize, Debug)]
+pub struct LayerSpans {
+    pub plugin: LayerId,
+    pub priority: u32,
+    pub enabled: bool,
+    pub spans: Vec<LayerSpan>,
+}
+
+/// How much of the document a layer's scopes cover, for noticing a
+/// plugin which leaves much of it unstyled.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayerStats {
+    /// The bytes within the layer's spans.
+    pub covered_bytes: usize,
+    pub total_bytes: usize,
+    pub span_count: usize,
+    /// The number of spans of the styles the scopes resolve to, adjacent
+    /// spans with the same style being counted once.
+    pub style_count: usize,
+}
+
+/// The spans and lookup tables of a plugin's layer, and an estimate of
+/// the memory they use.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ScopeLayerStats {
+    pub plugin: LayerId,
+    pub scope_spans: usize,
+    pub style_spans: usize,
+    /// The entries in each of the layer's lookup tables.
+    pub stacks: usize,
+    pub styles: usize,
+    pub names: usize,
+    /// The scope ids the plugin added, which share the entries of equal
+    /// stacks, and the entries its spans refer to. Many fewer of them than
+    /// stacks hint that compacting the layer would free the others.
+    pub ids: usize,
+    pub referenced_stacks: usize,
+    pub estimated_bytes: usize,
+}
+
+/// The statistics of every layer, with the merged spans of a document of
+/// `len` bytes.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ScopesStats {
+    pub len: usize,
+    pub merged_spans: usize,
+    pub layers: Vec<ScopeLayerStats>,
+    /// The estimated bytes of all the layers and the merged spans.
+    pub estimated_bytes: usize,
+}
+
+/// A span of a layer, with its scope stack and the style it resolves to.
+/// Offsets are relative to the interval it was found in.
+#[derive(Serialize, Debug)]
+pub struct LayerSpan {
+    pub start: usize,
+    pub end: usize,
+    pub scopes: Vec<String>,
+    pub style: Style,
 }
 
 /// A collection of scope spans from a single source.
 pub struct ScopeLayer {
+    /// The order in which the layer's styles are merged with those of
+    /// other layers: the higher priority wins a tie.
+    priority: u32,
+    /// Whether the layer's styles are merged; a disabled layer keeps its
+    /// spans, so that it can be enabled again right away.
+    enabled: bool,
+    /// How the layer's styles are merged with those of the layers below.
+    composite_mode: CompositeMode,
+    /// How the layer's spans are moved by edits.
+    edit_policy: EditPolicy,
     stack_lookup: Vec<Vec<Scope>>,
+    /// The modifiers of each stack, as `MODIFIER_DEPRECATED`, whose styles
+    /// apply on top of its scopes'.
+    modifier_lookup: Vec<u32>,
     style_lookup: Vec<Style>,
     /// Human readable scope names, for debugging
     name_lookup: Vec<Vec<String>>,
+    /// The entry of the lookup tables of each scope id, as the plugin
+    /// numbers the stacks it adds, in order. A stack added again shares the
+    /// entry of the first; the spans refer to entries rather than ids.
+    scope_ids: Vec<u32>,
+    /// The entries by the hash of their names and modifiers, to find that
+    /// of a stack which is added again.
+    entries_by_hash: HashMap<u64, Vec<u32>>,
     scope_spans: Spans<u32>,
     style_spans: Spans<Style>,
+    /// Whether the layer has styles which weren't resolved from its scopes,
+    /// having been restored, or kept as its scopes were forgotten; they're
+    /// restyled away by a new theme.
+    unscoped_styles: bool,
+    /// The regions, in order and apart, whose spans were dropped to keep
+    /// the layers within their budget, and are yet to be asked of the
+    /// plugin again.
+    evicted: Vec<Interval>,
+}
+
+/// A collection of style spans from a single source, which sends styles
+/// directly rather than scopes to resolve with the theme.
+pub struct StyleLayer {
+    /// The order in which the layer's styles are merged, as with those of
+    /// a `ScopeLayer`.
+    priority: u32,
+    enabled: bool,
+    /// How the layer's spans are moved by edits, as with those of a
+    /// `ScopeLayer`.
+    edit_policy: EditPolicy,
+    style_spans: Spans<Style>,
 }
 
 impl Scopes {
 
-    pub fn get_merged(&self) -> &Spans<Style> {
+    /// Returns the styles of a document of `len` bytes, with no layers.
+    pub fn new(len: usize) -> Self {
+        Scopes {
+            merged: SpansBuilder::new(len).build(),
+            ..Scopes::default()
+        }
+    }
+
+    /// Returns the merged styles, resolving first those which changed since
+    /// they were last read. While an update is begun, they are left to
+    /// resolve once it is committed.
+    pub fn get_merged(&mut self) -> &Spans<Style> {
+        if self.pending.is_none() {
+            if let Some(dirty) = self.dirty.take() {
+                self.resolve_styles(dirty);
+            }
+        }
         &self.merged
     }
 
-    /// Adds the provided scopes to the layer's lookup table.
-    pub fn add_scopes(&mut self, layer: PluginPid, scopes: Vec<Vec<String>>,
-                                doc_ctx: &DocumentCtx) {
+    /// Returns a snapshot of the merged styles and the diagnostics. It is
+    /// cheap, as the spans' trees are shared rather than copied.
+    pub fn snapshot(&mut self) -> ScopesSnapshot {
+        self.get_merged();
+        ScopesSnapshot {
+            merged: self.merged.clone(),
+            diagnostics: self.diagnostics.clone(),
+            version: self.version,
+        }
+    }
+
+    /// The version of the merged styles and the diagnostics, which changes
+    /// whenever either does.
+    pub fn current_version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns the resolved style at `offset`, or `None` if it is unstyled.
+    pub fn style_at(&mut self, offset: usize) -> Option<Style> {
+        self.get_merged().span_at(offset).map(|(_, style)| style.to_owned())
+    }
+
+    /// Returns the merged styles within each of `lines`, with offsets
+    /// relative to the start of its line. A span which crosses the bounds
+    /// of lines is split between them, and lines past the end are empty.
+    pub fn merged_by_line(&mut self, lines: &[Interval]) -> Vec<Vec<(Interval, Style)>> {
+        let merged = self.get_merged();
+        let all = Interval::new_closed_open(0, merged.len());
+        lines.iter().map(|line| {
+            let line = line.intersect(all);
+            if line.is_empty() {
+                return Vec::new();
+            }
+            merged.subseq(line).iter().map(|(iv, style)| (iv, style.to_owned())).collect()
+        }).collect()
+    }
+
+    /// Returns the most severe diagnostic at `offset`, of any plugin, or
+    /// `None` if there is none; of those as severe, the first plugin's.
+    pub fn diagnostic_at(&self, offset: usize) -> Option<&DiagnosticSpan> {
+        diagnostic_at(&self.diagnostics, offset)
+    }
+
+    /// Returns the names of the scope stack of the layer at `offset`, or
+    /// `None` if the layer has no scope there.
+    pub fn scope_names_at<L: Into<LayerId>>(&self, layer: L, offset: usize) -> Option<&[String]> {
+        let layer = layer.into();
+        let layer = match self.layers.get(&layer) {
+            Some(layer) => layer,
+            None => return None,
+        };
+        layer.scope_spans.span_at(offset)
+            .and_then(|(_, &ix)| layer.scope_name_for_index(ix))
+    }
+
+    /// Returns the names of the scope stack of the layer's entry
+    /// `span_index`, the value of its spans which have it, or `None` if
+    /// there is no such layer or entry.
+    pub fn scope_names_at_layer<L: Into<LayerId>>(&self, layer: L, span_index: u32)
+                                          -> Option<&[String]> {
+        let layer = layer.into();
+        self.layers.get(&layer).and_then(|layer| layer.scope_name_for_index(span_index))
+    }
+
+    /// Returns the scope stack of each layer at `offset`, in the order
+    /// their styles are merged; at a boundary between spans, it is that of
+    /// the span starting at `offset`. Layers with no span there are left
+    /// out, and past the end of the document the result is empty.
+    pub fn scopes_at(&self, offset: usize) -> Vec<(LayerId, &[String])> {
+        if offset >= self.merged.len() {
+            return Vec::new();
+        }
+        self.layer_order().into_iter()
+            .filter(|id| offset < self.layers[id].scope_spans.len())
+            .filter_map(|id| self.scope_names_at(id, offset).map(|names| (id, names)))
+            .collect()
+    }
+
+    /// Returns the spans of the layer within `iv`, with the names of their
+    /// scope stacks, or `None` if there is no such layer. See
+    /// `ScopeLayer::scope_spans_in`.
+    pub fn scope_spans_in<'a, L>(&'a self, layer: L, iv: Interval)
+                                 -> Option<impl Iterator<Item=(Interval, &'a [String])> + 'a>
+        where L: Into<LayerId>
+    {
+        let layer = layer.into();
+        self.layers.get(&layer).map(|layer| layer.scope_spans_in(iv))
+    }
+
+    /// Returns the regions of `text` which could be folded, according to
+    /// the scopes of the layer: those covered by a `meta.` scope, such as
+    /// `meta.block.rust`, which span at least `min_lines` lines. They are
+    /// in order of start, the outer of nested regions first.
+    pub fn fold_ranges<L: Into<LayerId>>(&self, layer: L, text: &Rope, min_lines: usize)
+                                         -> Vec<Interval> {
+        let layer = layer.into();
+        let mut ranges = match self.layers.get(&layer) {
+            Some(layer) => layer.meta_regions(),
+            None => return Vec::new(),
+        };
+        ranges.retain(|iv| {
+            let last_line = text.line_of_offset(iv.end() - 1);
+            last_line - text.line_of_offset(iv.start()) + 1 >= min_lines
+        });
+        ranges.sort_by_key(|iv| (iv.start(), Reverse(iv.end())));
+        ranges.dedup();
+        ranges
+    }
+
+    /// Adds the provided scopes to the layer's lookup table. Fails if some
+    /// of their names can't be parsed, though the stacks are added without
+    /// them, so that the indices of those which follow are unchanged.
+    pub fn add_scopes<L: Into<LayerId>>(&mut self, layer: L, scopes: Vec<Vec<String>>,
+                                doc_ctx: &DocumentCtx) -> Result<(), String> {
+        self.add_scopes_with_modifiers(layer, scopes, Vec::new(), doc_ctx)
+    }
+
+    /// Adds the provided scopes, as `add_scopes` does, with the modifiers
+    /// of each stack, such as `MODIFIER_DEPRECATED`. Those past the end of
+    /// `modifiers` have none; it fails if there are more modifiers than
+    /// stacks, though the stacks are added.
+    pub fn add_scopes_with_modifiers<L: Into<LayerId>>(&mut self, layer: L,
+                                                       scopes: Vec<Vec<String>>,
+                                                       modifiers: Vec<u32>,
+                                                       doc_ctx: &DocumentCtx)
+                                                       -> Result<(), String> {
+        let layer = layer.into();
         self.create_if_missing(layer);
-        self.layers.get_mut(&layer).unwrap().add_scopes(scopes, doc_ctx);
+        self.layers.get_mut(&layer).unwrap()
+            .add_scopes(scopes, modifiers, doc_ctx, &self.overrides)
+    }
+
+    /// Changes the theme's style of the scopes matching `selector`, such as
+    /// "comment.line.double-slash.rust", by `style_delta`, on top of the
+    /// theme and any earlier overrides. Like a new theme, the spans whose
+    /// style changes are left to be restyled, keeping their old styles
+    /// until then.
+    pub fn add_user_override(&mut self, selector: &str, style_delta: StyleDelta,
+                             doc_ctx: &DocumentCtx) -> Result<(), String> {
+        let selectors = ScopeSelectors::from_str(selector)
+            .map_err(|e| format!("invalid scope selector {:?}: {:?}", selector, e))?;
+        self.overrides.push((selectors, style_delta));
+        self.theme_diff_update(doc_ctx);
+        Ok(())
+    }
+
+    /// Adds `new_scopes`, with their `modifiers`, to a given layer and
+    /// updates its spans in each of `updates`, as a begun update, so that
+    /// the styles of all of them are resolved once. The updates which are
+    /// valid are applied even if others, or the scopes, are not; the first
+    /// error is returned.
+    pub fn apply_batch<L: Into<LayerId>>(&mut self, layer: L, new_scopes: Vec<Vec<String>>,
+                                 modifiers: Vec<u32>, updates: Vec<(Interval, Spans<u32>)>,
+                                 doc_ctx: &DocumentCtx) -> Result<(), String> {
+        let layer = layer.into();
+        let updating = self.pending.is_some();
+        self.begin_update();
+        let mut result = self.add_scopes_with_modifiers(layer, new_scopes, modifiers,
+                                                        doc_ctx);
+        for (iv, spans) in updates {
+            let updated = self.update_layer(layer, iv, spans, None, None);
+            result = result.and(updated);
+        }
+        if !updating {
+            self.commit_update();
+        }
+        result
     }
 
-    /// Inserts empty spans at the given interval for all layers.
+    /// Begins an update of several layers, whose styles are resolved
+    /// together once it is committed, rather than after each change.
+    pub fn begin_update(&mut self) {
+        if self.pending.is_none() {
+            self.pending = Some(Vec::new());
+        }
+    }
+
+    /// Commits an update begun with `begin_update`, leaving the styles of
+    /// the intervals changed by it to resolve once they are read.
+    pub fn commit_update(&mut self) {
+        if let Some(ivs) = self.pending.take() {
+            self.resolve_intervals(ivs);
+        }
+    }
+
+    /// Updates the spans of all layers for the edit replacing `iv` with
+    /// `len` units, shifting the spans around it; see `EditPolicy` for what
+    /// becomes of those it touches.
     ///
-    /// This is useful for clearing spans, and for updating spans
-    /// as edits occur.
+    /// The styles left to resolve, including those of an update which is
+    /// begun, are moved by the edit.
     pub fn update_all(&mut self, iv: Interval, len: usize) {
+        self.apply_edits(&[(iv, len)]);
+    }
+
+    /// Updates the spans of all layers for the edits of `delta`, each as
+    /// `update_all` would. The spans between its edits are kept, where
+    /// those of the interval of its summary would be cleared.
+    pub fn apply_edit(&mut self, delta: &RopeDelta) {
+        let edits = delta.edits();
+        if !edits.is_empty() {
+            self.apply_edits(&edits);
+        }
+    }
+
+    /// Applies `edits`, in order and replacing intervals of the text before
+    /// all of them; see `update_all`.
+    fn apply_edits(&mut self, edits: &[(Interval, usize)]) {
+        let updating = self.pending.is_some();
+        self.commit_update();
+        // from the last edit, so that the offsets of those before it stay
+        for &(iv, len) in edits.iter().rev() {
+            self.apply_one_edit(iv, len);
+        }
+        self.version += 1;
+        let mut moved = 0isize;
+        let mut inserted = Vec::with_capacity(edits.len());
+        for &(iv, len) in edits {
+            let start = (iv.start() as isize + moved) as usize;
+            inserted.push(Interval::new_closed_open(start, start + len));
+            moved += len as isize - iv.size() as isize;
+        }
+        self.shift_invalidated(edits, &inserted);
+        self.resolve_intervals(inserted);
+        for edits in self.detached.values_mut() {
+            *edits += 1;
+        }
+        let expired = self.detached.iter()
+            .filter(|&(_, &edits)| edits > MAX_DETACHED_EDITS)
+            .map(|(&pid, _)| pid)
+            .collect::<Vec<_>>();
+        for pid in expired {
+            self.remove_layer(pid);
+        }
+        if updating {
+            self.begin_update();
+        }
+    }
+
+    fn apply_one_edit(&mut self, iv: Interval, len: usize) {
+        let shift = |offset| if offset <= iv.start() {
+            offset
+        } else if offset >= iv.end() {
+            offset - iv.size() + len
+        } else {
+            iv.start()
+        };
+        self.dirty = self.dirty.map(|dirty| {
+            Interval::new_closed_open(shift(dirty.start()), shift(dirty.end()))
+        });
+        self.restyling = self.restyling.iter()
+            .map(|stale| Interval::new_closed_open(shift(stale.start()), shift(stale.end())))
+            .filter(|stale| !stale.is_empty())
+            .collect();
         self.merged.edit(iv, SpansBuilder::new(len).build());
-        let empty_spans = SpansBuilder::new(len).build();
         for layer in self.layers.values_mut() {
-            layer.update_scopes(iv, &empty_spans);
+            layer.apply_edit(iv, len);
+        }
+        for layer in self.style_layers.values_mut() {
+            layer.apply_edit(iv, len);
+        }
+        // direct styles are set again by whoever made the edit, and
+        // diagnostics by their plugin
+        if let Some(ref mut direct) = self.direct {
+            direct.edit(iv, SpansBuilder::new(len).build());
         }
-        self.resolve_styles(iv);
+        // as are search results, by searching again
+        if let Some(ref mut search) = self.search_layer {
+            search.edit(iv, SpansBuilder::new(len).build());
+        }
+        for diagnostics in self.diagnostics.values_mut() {
+            diagnostics.edit(iv, SpansBuilder::new(len).build());
+        }
+        for by_offset in self.annotations.values_mut() {
+            shift_annotations(by_offset, iv, len);
+        }
+    }
+
+    /// Replaces the plugin's diagnostics within `iv` with `spans`, resolving
+    /// the styles of both the old and the new ones.
+    pub fn update_diagnostic_layer(&mut self, plugin: PluginPid, iv: Interval,
+                                   spans: Spans<DiagnosticSpan>) {
+        let len = self.merged.len();
+        let new_iv = Interval::new_closed_open(iv.start(), iv.start() + spans.len());
+        self.diagnostics.entry(plugin).or_insert_with(|| SpansBuilder::new(len).build())
+            .edit(iv, spans);
+        self.version += 1;
+        self.resolve_intervals(vec![new_iv]);
+    }
+
+    /// Replaces all of the plugin's annotations with `annotations`.
+    pub fn update_annotation_layer(&mut self, plugin: PluginPid, annotations: Vec<Annotation>) {
+        let mut by_offset = BTreeMap::new();
+        for annotation in annotations {
+            by_offset.entry(annotation.offset).or_insert_with(Vec::new).push(annotation);
+        }
+        self.annotations.insert(plugin, by_offset);
     }
 
-    /// Updates the scope spans for a given layer.
-    pub fn update_layer(&mut self, layer: PluginPid, iv: Interval, spans: Spans<u32>) {
+    /// Returns the annotations of every plugin at offsets within `iv`, in
+    /// order of offset; those at the same offset are in the order of their
+    /// plugins, and then as they were given.
+    pub fn annotations_in<'a>(&'a self, iv: Interval) -> impl Iterator<Item=&'a Annotation> + 'a {
+        let mut annotations = self.annotations.values()
+            .flat_map(|by_offset| by_offset.range(iv.start()..iv.end()))
+            .flat_map(|(_, annotations)| annotations.iter())
+            .collect::<Vec<_>>();
+        annotations.sort_by_key(|annotation| annotation.offset);
+        annotations.into_iter()
+    }
+
+    /// Sets the styles within `iv` directly, to `spans`.
+    pub fn update_direct(&mut self, iv: Interval, spans: Spans<Style>) {
+        let len = self.merged.len();
+        self.direct.get_or_insert_with(|| SpansBuilder::new(len).build()).edit(iv, spans);
+        self.resolve_intervals(vec![iv]);
+    }
+
+    /// Highlights each of `intervals`, as the results of a search, with
+    /// `style`, replacing the previous highlights; no intervals clear them.
+    /// These belong to the document, and are shown in every view apart from
+    /// the highlights of each view's find. Their style is given rather than
+    /// the theme's, so they're kept as the theme changes. The intervals past
+    /// the end of the document are dropped.
+    pub fn set_search_highlights(&mut self, mut intervals: Vec<Interval>, style: Style) {
+        let len = self.merged.len();
+        let mut changed = self.search_layer.take()
+            .map_or(Vec::new(), |search| styled_intervals(&search, len));
+        intervals.retain(|iv| !iv.is_empty() && iv.end() <= len);
+        if intervals.is_empty() {
+            self.resolve_intervals(changed);
+            return;
+        }
+        intervals.sort_by_key(|iv| (iv.start(), iv.end()));
+        let mut sb = SpansBuilder::new(len);
+        let mut highlighted = intervals[0];
+        // overlapping results are highlighted as one
+        for &iv in &intervals[1..] {
+            if iv.start() < highlighted.end() {
+                highlighted = highlighted.union(iv);
+            } else {
+                sb.add_span(highlighted, style.clone());
+                highlighted = iv;
+            }
+        }
+        sb.add_span(highlighted, style);
+        let search = sb.build();
+        changed.extend(styled_intervals(&search, len));
+        self.search_layer = Some(search);
+        self.resolve_intervals(changed);
+    }
+
+    /// Updates the scope spans for a given layer, and sets its priority and
+    /// its `CompositeMode`, if they are given. Styles are only resolved
+    /// again where those of the layer changed, or everywhere it has any if
+    /// its mode did, and not at all if it is disabled.
+    ///
+    /// Fails, leaving the layer as it was, if `iv` is past the end of the
+    /// document or `spans` are not as long as `iv`. Spans with scopes which
+    /// were never added are dropped.
+    pub fn update_layer<L: Into<LayerId>>(&mut self, layer: L, iv: Interval, spans: Spans<u32>,
+                                  priority: Option<u32>, composite_mode: Option<CompositeMode>)
+                                  -> Result<(), String> {
+        let layer = layer.into();
+        if iv.end() > self.merged.len() {
+            return Err(format!("spans {}..{} are past the end of the document, {}",
+                               iv.start(), iv.end(), self.merged.len()));
+        }
+        if spans.len() != iv.size() {
+            return Err(format!("spans for {}..{} are {} long, not {}",
+                               iv.start(), iv.end(), spans.len(), iv.size()));
+        }
         self.create_if_missing(layer);
+        // the spans' scope ids are mapped to the entries of their stacks
+        let spans = {
+            let scope_ids = &self.layers[&layer].scope_ids;
+            let mut unknown = 0;
+            let mut sb = SpansBuilder::new(spans.len());
+            for (span_iv, &scope) in spans.iter() {
+                match scope_ids.get(scope as usize) {
+                    Some(&entry) if entry != RETIRED_SCOPE => sb.add_span(span_iv, entry),
+                    _ => unknown += 1,
+                }
+            }
+            if unknown > 0 {
+                print_err!("dropping {} spans of {:?} with unknown scopes", unknown, layer);
+            }
+            sb.build()
+        };
+        let prev_styles = self.layers[&layer].style_spans.subseq(iv);
         self.layers.get_mut(&layer).unwrap().update_scopes(iv, &spans);
-        self.resolve_styles(iv);
+        let mut restyled = vec![iv];
+        match composite_mode {
+            Some(mode) if mode != self.layers[&layer].composite_mode => {
+                self.layers.get_mut(&layer).unwrap().composite_mode = mode;
+                restyled.extend(styled_intervals(&self.layers[&layer].style_spans,
+                                                 self.merged.len()));
+            }
+            _ => (),
+        }
+        match priority {
+            Some(priority) if priority != self.layers[&layer].priority => {
+                if !self.set_layer_priority(layer, priority) {
+                    self.resolve_intervals(restyled);
+                }
+            }
+            _ if !self.layers[&layer].enabled => (),
+            _ if restyled.len() > 1 => self.resolve_intervals(restyled),
+            _ => {
+                let styles = self.layers[&layer].style_spans.subseq(iv);
+                let changed = changed_intervals(&prev_styles, &styles).into_iter()
+                    .map(|changed| changed.translate(iv.start()))
+                    .collect();
+                self.resolve_intervals(changed);
+            }
+        }
+        Ok(())
     }
 
-    /// Removes a given layer. This will remove all styles derived from
-    /// that layer's scopes.
-    pub fn remove_layer(&mut self, layer: PluginPid) -> Option<ScopeLayer> {
-        let layer = self.layers.remove(&layer);
-        if layer.is_some() {
+    /// Clears the spans of a given layer in `iv`, as an update with no spans
+    /// would, leaving those of other layers alone. Styles are resolved again
+    /// only where the layer had any; it does nothing if there is no such
+    /// layer, and fails if `iv` is past the end of the document.
+    pub fn remove_spans_for_range<L: Into<LayerId>>(&mut self, layer: L, iv: Interval)
+                                            -> Result<(), String> {
+        let layer = layer.into();
+        if !self.layers.contains_key(&layer) && iv.end() <= self.merged.len() {
+            return Ok(());
+        }
+        self.update_layer(layer, iv, SpansBuilder::new(iv.size()).build(), None, None)
+    }
+
+    /// Compacts the lookup tables of a given layer, dropping the stacks its
+    /// spans don't refer to; its styles don't change. The plugin's scope ids
+    /// for those stacks can't be used again, so it's for idle time, as for
+    /// a plugin which sends all its stacks with each update. Returns the
+    /// number of stacks dropped.
+    pub fn compact_layer<L: Into<LayerId>>(&mut self, layer: L) -> usize {
+        self.layers.get_mut(&layer.into()).map_or(0, |layer| layer.compact())
+    }
+
+    /// Merges the scope layers of `other`, the styles of a document which
+    /// starts `offset` bytes into this one, as that of another workspace
+    /// root. A layer of a plugin which has none here is added as it is;
+    /// otherwise `other`'s spans replace this layer's where it has any,
+    /// and its gaps leave them alone. Their styles are kept as `other`
+    /// resolved them, which should be with the same theme. Fails if `other`
+    /// runs past the end of the document.
+    pub fn merge_foreign(&mut self, other: &Scopes, offset: usize) -> Result<(), String> {
+        let iv = Interval::new_closed_open(offset, offset + other.merged.len());
+        if iv.end() > self.merged.len() {
+            return Err(format!("spans {}..{} are past the end of the document, {}",
+                               iv.start(), iv.end(), self.merged.len()));
+        }
+        let mut merged_any = false;
+        for (&pid, foreign) in other.layers.iter() {
+            if foreign.scope_spans.len() != iv.size() || foreign.style_spans.len() != iv.size() {
+                print_err!("not merging layer {:?}, which is out of step with its document",
+                           pid);
+                continue;
+            }
+            if !self.layers.contains_key(&pid) {
+                self.layers.insert(pid, ScopeLayer {
+                    priority: foreign.priority,
+                    enabled: foreign.enabled,
+                    composite_mode: foreign.composite_mode,
+                    edit_policy: foreign.edit_policy,
+                    ..ScopeLayer::new(self.merged.len())
+                });
+            }
+            let layer = self.layers.get_mut(&pid).unwrap();
+            layer.merge_foreign(foreign, iv);
+            merged_any |= layer.enabled;
+        }
+        if merged_any {
+            self.resolve_intervals(vec![iv]);
+        }
+        Ok(())
+    }
+
+    /// Updates the style spans for a given style layer. As with scope
+    /// layers, styles are only resolved again where the layer changed, and
+    /// the update fails if `iv` is past the end of the document.
+    pub fn update_style_layer(&mut self, layer: PluginPid, iv: Interval, spans: Spans<Style>)
+                              -> Result<(), String> {
+        let len = self.merged.len();
+        if iv.end() > len {
+            return Err(format!("spans {}..{} are past the end of the document, {}",
+                               iv.start(), iv.end(), len));
+        }
+        // a new style layer is merged next to the plugin's scope layer, and
+        // its spans are moved alike
+        let layer = LayerId::from(layer);
+        let (priority, edit_policy) = self.layers.get(&layer)
+            .map_or((0, EditPolicy::default()), |scope_layer| {
+                (scope_layer.priority, scope_layer.edit_policy)
+            });
+        let style_layer = self.style_layers.entry(layer).or_insert_with(|| StyleLayer {
+            priority: priority,
+            edit_policy: edit_policy,
+            ..StyleLayer::new(len)
+        });
+        let prev_styles = style_layer.style_spans.subseq(iv);
+        let same_len = spans.len() == iv.size();
+        style_layer.style_spans.edit(iv, spans);
+        if !style_layer.enabled {
+            return Ok(());
+        }
+        let changed = if same_len {
+            changed_intervals(&prev_styles, &style_layer.style_spans.subseq(iv)).into_iter()
+                .map(|changed| changed.translate(iv.start()))
+                .collect()
+        } else {
+            vec![iv]
+        };
+        self.resolve_intervals(changed);
+        Ok(())
+    }
+
+    /// Updates the scope spans for a given layer with `ops`, which must
+    /// cover the whole buffer. Styles are resolved once, and only where
+    /// the layer changed.
+    pub fn edit_layer<L: Into<LayerId>>(&mut self, layer: L, ops: Vec<LayerOp>)
+                                        -> Result<(), String> {
+        let layer = layer.into();
+        let covered = ops.iter().map(|op| match *op {
+            LayerOp::Retain(n) | LayerOp::Skip(n) => n,
+            LayerOp::Insert(ref spans) => spans.len(),
+        }).sum::<usize>();
+        if covered != self.merged.len() {
+            return Err(format!("span ops cover {} bytes of {}", covered, self.merged.len()));
+        }
+        let updating = self.pending.is_some();
+        self.begin_update();
+        let mut offset = 0;
+        for op in ops {
+            let spans = match op {
+                LayerOp::Retain(n) => {
+                    offset += n;
+                    continue;
+                }
+                LayerOp::Skip(n) => SpansBuilder::new(n).build(),
+                LayerOp::Insert(spans) => spans,
+            };
+            let iv = Interval::new_closed_open(offset, offset + spans.len());
+            offset += spans.len();
+            self.update_layer(layer, iv, spans, None, None)?;
+        }
+        if !updating {
+            self.commit_update();
+        }
+        Ok(())
+    }
+
+    /// Sets the priority of a given layer, and of the plugin's style layer
+    /// if it has one, resolving the styles of the whole document again if
+    /// that changes the order of the layers. Returns `true` if it did.
+    pub fn set_layer_priority<L: Into<LayerId>>(&mut self, layer: L, priority: u32) -> bool {
+        let layer = layer.into();
+        let order = self.merge_order();
+        match self.style_layers.get_mut(&layer) {
+            Some(style_layer) => style_layer.priority = priority,
+            None => self.create_if_missing(layer),
+        }
+        if let Some(scope_layer) = self.layers.get_mut(&layer) {
+            scope_layer.priority = priority;
+        }
+        if self.merge_order() == order {
+            return false;
+        }
+        let iv_all = Interval::new_closed_closed(0, self.merged.len());
+        self.resolve_intervals(vec![iv_all]);
+        true
+    }
+
+    /// Sets how the spans of a given layer, and of its style layer, are
+    /// moved by the edits which follow.
+    pub fn set_edit_policy<L: Into<LayerId>>(&mut self, layer: L, edit_policy: EditPolicy) {
+        let layer = layer.into();
+        self.create_if_missing(layer);
+        self.layers.get_mut(&layer).unwrap().edit_policy = edit_policy;
+        if let Some(style_layer) = self.style_layers.get_mut(&layer) {
+            style_layer.edit_policy = edit_policy;
+        }
+    }
+
+    /// Enables or disables a given layer, resolving the styles again where
+    /// it has any. Returns `true` if that changed whether it is enabled.
+    pub fn set_layer_enabled<L: Into<LayerId>>(&mut self, layer: L, enabled: bool) -> bool {
+        let layer = layer.into();
+        let len = self.merged.len();
+        let mut styled = Vec::new();
+        match self.layers.get_mut(&layer) {
+            Some(layer) if layer.enabled != enabled => {
+                layer.enabled = enabled;
+                styled.extend(styled_intervals(&layer.style_spans, len));
+            }
+            _ => (),
+        }
+        match self.style_layers.get_mut(&layer) {
+            Some(layer) if layer.enabled != enabled => {
+                layer.enabled = enabled;
+                styled.extend(styled_intervals(&layer.style_spans, len));
+            }
+            _ => (),
+        }
+        if styled.is_empty() {
+            return false;
+        }
+        self.resolve_intervals(styled);
+        true
+    }
+
+    /// Returns the regions whose spans were cleared or guessed, as by edits
+    /// or a layer being removed, since they were last taken, in order.
+    pub fn take_invalidated_intervals(&mut self) -> Vec<Interval> {
+        let invalidated = mem::replace(&mut self.invalidated, BTreeMap::new());
+        invalidated.into_iter()
+            .map(|(start, end)| Interval::new_closed_open(start, end))
+            .collect()
+    }
+
+    /// Adds `start..end` to the invalidated regions, joining it with those
+    /// it overlaps or touches.
+    fn invalidate(&mut self, start: usize, end: usize) {
+        let (mut start, mut end) = (start, end);
+        let joined = self.invalidated.range(..end + 1).rev()
+            .take_while(|&(_, &e)| e >= start)
+            .map(|(&s, &e)| (s, e))
+            .collect::<Vec<_>>();
+        for (s, e) in joined {
+            self.invalidated.remove(&s);
+            start = min(start, s);
+            end = max(end, e);
+        }
+        self.invalidated.insert(start, end);
+    }
+
+    /// Moves the invalidated regions for `edits`, and invalidates the
+    /// `edited` regions, where they are after all of them. Regions the
+    /// edits touch are joined with theirs.
+    fn shift_invalidated(&mut self, edits: &[(Interval, usize)], edited: &[Interval]) {
+        let invalidated = mem::replace(&mut self.invalidated, BTreeMap::new());
+        // the regions are in order and apart, so their ends are moved by
+        // one pass over the edits
+        let (mut ix, mut moved) = (0, 0isize);
+        let mut shift = |offset: usize, is_end: bool| {
+            while ix < edits.len() && edits[ix].0.end() < offset {
+                moved += edits[ix].1 as isize - edits[ix].0.size() as isize;
+                ix += 1;
+            }
+            let start = match edits.get(ix) {
+                Some(&(iv, len)) if iv.start() <= offset => {
+                    iv.start() + if is_end { len } else { 0 }
+                }
+                _ => offset,
+            };
+            (start as isize + moved) as usize
+        };
+        let mut regions = invalidated.into_iter()
+            .map(|(s, e)| (shift(s, false), shift(e, true)))
+            .chain(edited.iter().map(|iv| (iv.start(), iv.end())))
+            .collect::<Vec<_>>();
+        regions.sort();
+        for (s, e) in regions {
+            match self.invalidated.iter_mut().next_back() {
+                Some((_, end)) if *end >= s => *end = max(*end, e),
+                _ => {
+                    self.invalidated.insert(s, e);
+                }
+            }
+        }
+    }
+
+    /// Returns the layers in the order their styles are merged: by
+    /// priority, then by id, the plugins' before core's.
+    fn layer_order(&self) -> Vec<LayerId> {
+        let mut order = self.layers.iter()
+            .map(|(&id, layer)| (layer.priority, id))
+            .collect::<Vec<_>>();
+        order.sort();
+        order.into_iter().map(|(_, id)| id).collect()
+    }
+
+    /// Returns every layer, of scopes or of styles, in the order their
+    /// styles are merged: by priority, then by id, with a plugin's scope
+    /// layer before its style layer. Style layers are marked `true`.
+    fn merge_order(&self) -> Vec<(LayerId, bool)> {
+        let scope_layers = self.layers.iter()
+            .map(|(&id, layer)| (layer.priority, id, false));
+        let style_layers = self.style_layers.iter()
+            .map(|(&id, layer)| (layer.priority, id, true));
+        let mut order = scope_layers.chain(style_layers).collect::<Vec<_>>();
+        order.sort();
+        order.into_iter().map(|(_, id, is_style_layer)| (id, is_style_layer)).collect()
+    }
+
+    /// Detaches the layer and style layer of a plugin which stopped, such
+    /// as by crashing, removing its diagnostics and annotations. The layers'
+    /// styles are kept, and moved by edits, until they are adopted by the
+    /// restarted plugin with `adopt_layer`, or dropped after a number of
+    /// edits. Returns `false` if the plugin has no layers.
+    pub fn detach_layer(&mut self, layer: PluginPid) -> bool {
+        let id = LayerId::from(layer);
+        if !self.layers.contains_key(&id) && !self.style_layers.contains_key(&id) {
+            return false;
+        }
+        self.annotations.remove(&layer);
+        if let Some(diagnostics) = self.diagnostics.remove(&layer) {
+            self.version += 1;
+            let ivs = diagnostics.iter().map(|(iv, _)| iv).collect();
+            self.resolve_intervals(ivs);
+        }
+        self.detached.insert(layer, 0);
+        true
+    }
+
+    /// Gives the detached layers of the plugin `old` to `new`, as a plugin
+    /// restarted with a new pid. The layer's styles are kept until the new
+    /// plugin replaces them, but not its scopes, which the new plugin adds
+    /// again. Returns `false` if `old` has no detached layers, or if `new`
+    /// already has layers of its own, in which case those of `old` are
+    /// removed.
+    pub fn adopt_layer(&mut self, old: PluginPid, new: PluginPid) -> bool {
+        if self.detached.remove(&old).is_none() {
+            return false;
+        }
+        let (old, new) = (LayerId::from(old), LayerId::from(new));
+        if self.layers.contains_key(&new) || self.style_layers.contains_key(&new) {
+            self.remove_layer(old);
+            return false;
+        }
+        let order = self.merge_order().into_iter()
+            .map(|(id, is_style_layer)| (if id == old { new } else { id }, is_style_layer))
+            .collect::<Vec<_>>();
+        if let Some(mut layer) = self.layers.remove(&old) {
+            layer.forget_scopes();
+            self.layers.insert(new, layer);
+        }
+        if let Some(style_layer) = self.style_layers.remove(&old) {
+            self.style_layers.insert(new, style_layer);
+        }
+        if self.merge_order() != order {
             let iv_all = Interval::new_closed_closed(0, self.merged.len());
-            //TODO: should Spans<T> have a clear() method?
-            self.merged = SpansBuilder::new(self.merged.len()).build();
-            self.resolve_styles(iv_all);
+            self.resolve_intervals(vec![iv_all]);
+        }
+        true
+    }
+
+    /// Returns the styles of a given layer, serialized so that they can be
+    /// restored in a later session, or `None` if there is no such layer.
+    pub fn serialize_layer<L: Into<LayerId>>(&self, layer: L) -> Option<Vec<u8>> {
+        self.layers.get(&layer.into())
+            .map(|layer| serde_json::to_vec(&without_gaps(&layer.style_spans)).unwrap())
+    }
+
+    /// Restores styles serialized by `serialize_layer` to a given layer,
+    /// creating it if missing. They are shown until the plugin updates the
+    /// layer's spans over them, or the theme changes; whether they are
+    /// still current is for the caller to know. Fails if they run past the
+    /// end of the document.
+    pub fn restore_layer<L: Into<LayerId>>(&mut self, layer: L, bytes: &[u8])
+                                           -> Result<(), String> {
+        let layer = layer.into();
+        let restored: Spans<Style> = serde_json::from_slice(bytes).map_err(|e| e.to_string())?;
+        let len = self.merged.len();
+        if restored.len() > len {
+            return Err(format!("the styles run to {}, past the end of the document, {}",
+                               restored.len(), len));
+        }
+        // the priority isn't serialized, and is that of any resolved scope
+        let mut sb = SpansBuilder::new(len);
+        for (iv, style) in restored.iter() {
+            sb.add_span(iv, Style { priority: SYNTAX_PRIORITY_DEFAULT, ..style.to_owned() });
+        }
+        self.create_if_missing(layer);
+        let scope_layer = self.layers.get_mut(&layer).unwrap();
+        let prev_styles = mem::replace(&mut scope_layer.style_spans, sb.build());
+        scope_layer.unscoped_styles = true;
+        if scope_layer.enabled {
+            let mut changed = styled_intervals(&prev_styles, len);
+            changed.extend(styled_intervals(&scope_layer.style_spans, len));
+            self.resolve_intervals(changed);
+        }
+        Ok(())
+    }
+
+    /// Removes a given layer, and the plugin's style layer, diagnostics and
+    /// annotations. This will remove all styles derived from that layer's
+    /// scopes, resolving them again only where it had styles.
+    pub fn remove_layer<L: Into<LayerId>>(&mut self, layer: L) -> Option<ScopeLayer> {
+        let layer = layer.into();
+        if let Some(pid) = layer.plugin() {
+            self.detached.remove(&pid);
+            self.annotations.remove(&pid);
+            if let Some(diagnostics) = self.diagnostics.remove(&pid) {
+                self.version += 1;
+                let ivs = diagnostics.iter().map(|(iv, _)| iv).collect();
+                self.resolve_intervals(ivs);
+            }
+        }
+        if let Some(style_layer) = self.style_layers.remove(&layer) {
+            let styled = styled_intervals(&style_layer.style_spans, self.merged.len());
+            self.resolve_intervals(styled);
+        }
+        let layer = self.layers.remove(&layer);
+        if let Some(ref layer) = layer {
+            let styled = styled_intervals(&layer.style_spans, self.merged.len());
+            if layer.style_spans.len() == self.merged.len() {
+                for iv in &styled {
+                    self.invalidate(iv.start(), iv.end());
+                }
+            }
+            self.resolve_intervals(styled);
         }
         layer
     }
 
-    pub fn theme_changed(&mut self, doc_ctx: &DocumentCtx) {
+    /// Notes the document's new theme, with which the styles of scope
+    /// layers are resolved again, a chunk at a time, by `restyle_next`;
+    /// only the spans whose scopes' style changed are. Until then the text
+    /// keeps its old styles, rather than being left unstyled. Those of
+    /// style layers are kept, as they aren't the theme's. The user's
+    /// overrides apply on top of the new theme.
+    pub fn theme_diff_update(&mut self, doc_ctx: &DocumentCtx) {
+        let theme = doc_ctx.get_theme();
+        let overrides = &self.overrides;
+        // the restyling for an earlier theme may not be done, and the spans
+        // of different layers overlap
+        let mut stale = mem::replace(&mut self.restyling, Vec::new());
+        stale.extend(self.layers.values_mut()
+            .flat_map(|layer| layer.theme_changed(&theme, overrides)));
+        stale.sort_by_key(|iv| iv.start());
+        for iv in stale.into_iter().filter(|iv| !iv.is_empty()) {
+            match self.restyling.last_mut() {
+                Some(last) if last.end() >= iv.start() => *last = last.union(iv),
+                _ => self.restyling.push(iv),
+            }
+        }
+    }
+
+    /// Returns `true` if some of the text is yet to be restyled for a new
+    /// theme.
+    pub fn is_restyling(&self) -> bool {
+        !self.restyling.is_empty()
+    }
+
+    /// Restyles the next chunk, of at most `max_len`, of the text left to
+    /// restyle for a new theme, and returns the interval spanning what was
+    /// restyled; the chunk is within `visible` while any of that is left.
+    /// Returns `None` if none is.
+    pub fn restyle_next(&mut self, visible: Interval, max_len: usize) -> Option<Interval> {
+        let in_view = self.restyling.iter()
+            .map(|iv| iv.intersect(visible))
+            .find(|iv| !iv.is_empty());
+        let chunk = match in_view.or_else(|| self.restyling.first().cloned()) {
+            Some(next) => {
+                let end = min(next.start().saturating_add(max(max_len, 1)), self.merged.len());
+                Interval::new_closed_open(next.start(), end)
+            }
+            None => return None,
+        };
+        let chunk = if in_view.is_some() { chunk.intersect(visible) } else { chunk };
+        let restyled = self.restyling.iter()
+            .map(|iv| iv.intersect(chunk))
+            .filter(|iv| !iv.is_empty())
+            .collect::<Vec<_>>();
+        self.restyling = self.restyling.iter()
+            .flat_map(|iv| vec![iv.prefix(chunk), iv.suffix(chunk)])
+            .filter(|iv| !iv.is_empty())
+            .collect();
         for layer in self.layers.values_mut() {
-            layer.theme_changed(doc_ctx);
+            for &iv in &restyled {
+                layer.restyle(iv);
+            }
+        }
+        let spanned = Interval::new_closed_open(restyled[0].start(),
+                                                restyled[restyled.len() - 1].end());
+        self.resolve_intervals(restyled);
+        Some(spanned)
+    }
+
+    /// Marks the styles of each of `ivs` to be resolved from all layers
+    /// once the merged styles are read, and an update which is begun is
+    /// committed. The interval spanning them all is resolved at once.
+    fn resolve_intervals(&mut self, mut ivs: Vec<Interval>) {
+        if let Some(ref mut pending) = self.pending {
+            pending.append(&mut ivs);
+            return;
+        }
+        for iv in ivs.into_iter().filter(|iv| !iv.is_empty()) {
+            self.dirty = Some(self.dirty.map_or(iv, |dirty| dirty.union(iv)));
         }
-        self.merged = SpansBuilder::new(self.merged.len()).build();
-        let iv_all = Interval::new_closed_closed(0, self.merged.len());
-        self.resolve_styles(iv_all);
     }
 
     /// Resolves styles from all layers for the given interval, updating
-    /// the master style spans.
+    /// the master style spans. Layers are merged from lowest to highest
+    /// priority, so that where styles of equal priority conflict, the
+    /// layer of higher priority wins. The styles of diagnostics are merged
+    /// next, and then the search highlights.
     fn resolve_styles(&mut self, iv: Interval) {
-        if self.layers.is_empty() {
-            return
-        }
-        let mut layer_iter = self.layers.values();
-        let mut resolved = layer_iter.next().unwrap().style_spans.subseq(iv);
-
-        for other in layer_iter {
-            let spans = other.style_spans.subseq(iv);
-            assert_eq!(resolved.len(), spans.len());
-            resolved = resolved.merge(&spans, |a, b| {
-                match b {
-                    Some(b) => a.merge(b),
-                    None => a.to_owned(),
+        let len = self.merged.len();
+        let order = self.merge_order();
+        // the styles of a layer which is out of step with the buffer are
+        // left out, rather than misaligned
+        let layers = order.iter()
+            .filter_map(|&(pid, is_style_layer)| if is_style_layer {
+                let layer = &self.style_layers[&pid];
+                let mode = CompositeMode::Overlay;
+                if layer.enabled { Some((&layer.style_spans, mode)) } else { None }
+            } else {
+                let layer = &self.layers[&pid];
+                let mode = layer.composite_mode;
+                if layer.enabled { Some((&layer.style_spans, mode)) } else { None }
+            })
+            .chain(self.direct.iter().map(|spans| (spans, CompositeMode::Overlay)))
+            .filter(|&(style_spans, _)| {
+                let layer_len = style_spans.len();
+                if layer_len != len {
+                    incident!("a layer's styles are {} long, not {}", layer_len, len);
                 }
-            });
+                layer_len == len
+            })
+            .collect::<Vec<_>>();
+        // taking each layer's subseq is independent of the others, and is
+        // the bulk of the work when there are many layers; merging is serial
+        #[cfg(feature = "parallel")]
+        let subseqs = layers.par_iter().map(|&(spans, mode)| (spans.subseq(iv), mode))
+            .collect::<Vec<_>>();
+        #[cfg(not(feature = "parallel"))]
+        let subseqs = layers.iter().map(|&(spans, mode)| (spans.subseq(iv), mode))
+            .collect::<Vec<_>>();
+        let mut subseqs = subseqs.into_iter();
+        let mut resolved = match subseqs.next() {
+            Some((spans, _)) => spans,
+            None => SpansBuilder::new(iv.size()).build(),
+        };
+
+        // a layer's gaps have the default style, which gives way to any
+        // other, whatever the order of the layers; where only one side has
+        // a span, `merge` passes that side's style alone
+        let merge = |mode: CompositeMode| move |a: &Style, b: Option<&Style>| match b {
+            Some(b) if b.is_default => a.to_owned(),
+            Some(b) if a.is_default => b.to_owned(),
+            Some(b) => mode.blend(a, b),
+            None => a.to_owned(),
+        };
+        for (spans, mode) in subseqs {
+            resolved = resolved.merge(&spans, merge(mode));
+        }
+        for diagnostics in self.diagnostics.values().filter(|spans| spans.len() == len) {
+            let mut sb = SpansBuilder::new(iv.size());
+            for (span_iv, diagnostic) in diagnostics.subseq(iv).iter() {
+                sb.add_span(span_iv, diagnostic.severity.style());
+            }
+            resolved = resolved.merge(&sb.build(), merge(CompositeMode::Overlay));
+        }
+        if let Some(ref search) = self.search_layer {
+            resolved = resolved.merge(&search.subseq(iv), merge(CompositeMode::Overlay));
+        }
+        // the text only gaps cover is left without a span
+        self.merged.edit(iv, without_gaps(&resolved));
+        self.version += 1;
+    }
+
+    /// Returns the number of entries in the layer's scope lookup, and of
+    /// spans in the layer and the plugin's style layer.
+    pub fn layer_usage<L: Into<LayerId>>(&self, layer: L) -> (usize, usize) {
+        let layer = layer.into();
+        let style_spans = self.style_layers.get(&layer)
+            .map_or(0, |style_layer| style_layer.style_spans.count());
+        self.layers.get(&layer)
+            .map(|layer| (layer.scope_ids.len(), layer.scope_spans.count() + style_spans))
+            .unwrap_or((0, style_spans))
+    }
+
+    /// Returns the number of spans in the layer and the plugin's style
+    /// layer within `iv`.
+    pub fn layer_spans_within<L: Into<LayerId>>(&self, layer: L, iv: Interval) -> usize {
+        let layer = layer.into();
+        let style_spans = self.style_layers.get(&layer)
+            .map_or(0, |style_layer| style_layer.style_spans.subseq(iv).count());
+        self.layers.get(&layer)
+            .map(|layer| layer.scope_spans.subseq(iv).count())
+            .unwrap_or(0) + style_spans
+    }
+
+    /// Returns the number of spans the plugins' layers keep, which
+    /// `enforce_span_budget` bounds.
+    pub fn retained_spans(&self) -> usize {
+        self.layers.iter()
+            .filter(|&(&pid, _)| self.is_evictable(pid))
+            .map(|(_, layer)| layer.scope_spans.count() + count_styles(&layer.style_spans))
+            .sum()
+    }
+
+    /// Drops the spans of the plugins' layers outside `windows`, the
+    /// regions around those in view, if they keep more than `budget` spans
+    /// in all, so that a huge document's spans are kept only where it is
+    /// read. Spans within the windows are kept even if they alone are over
+    /// the budget. Returns the regions whose styles changed.
+    ///
+    /// The dropped regions are asked of the plugins again, once they are in
+    /// a window, through `take_evicted`.
+    pub fn enforce_span_budget(&mut self, budget: usize, windows: &[Interval]) -> Vec<Interval> {
+        if self.retained_spans() <= budget {
+            return Vec::new();
+        }
+        let windows = join_windows(windows, self.merged.len());
+        let mut changed = Vec::new();
+        let evictable = self.layers.keys().cloned()
+            .filter(|&pid| self.is_evictable(pid))
+            .collect::<Vec<_>>();
+        for pid in evictable {
+            let layer = self.layers.get_mut(&pid).unwrap();
+            let evicted = layer.evict_outside(&windows);
+            if layer.enabled {
+                changed.extend(evicted);
+            }
+        }
+        if !changed.is_empty() {
+            self.resolve_intervals(changed.clone());
+        }
+        changed
+    }
+
+    /// Returns the regions within `windows` whose spans were dropped by
+    /// `enforce_span_budget`, with the layer of each, for its plugin to be
+    /// asked to send them again. They aren't returned again, unless they
+    /// are dropped again.
+    pub fn take_evicted(&mut self, windows: &[Interval]) -> Vec<(PluginPid, Interval)> {
+        let windows = join_windows(windows, self.merged.len());
+        let detached = &self.detached;
+        self.layers.iter_mut()
+            .filter_map(|(id, layer)| id.plugin().map(|pid| (pid, layer)))
+            .filter(|&(pid, _)| !detached.contains_key(&pid))
+            .flat_map(|(pid, layer)| {
+                layer.take_evicted(&windows).into_iter().map(move |region| (pid, region))
+            })
+            .collect()
+    }
+
+    /// Returns `true` if the spans of a given layer can be dropped to stay
+    /// within a budget: those of a running plugin, which can be asked for
+    /// them again, rather than core's or a detached layer.
+    fn is_evictable(&self, layer: LayerId) -> bool {
+        match layer {
+            LayerId::Plugin(pid) => !self.detached.contains_key(&pid),
+            LayerId::Core(_) => false,
+        }
+    }
+
+    /// Returns statistics about the spans of a given layer, or `None` if
+    /// there is no such layer.
+    pub fn layer_stats<L: Into<LayerId>>(&self, layer: L) -> Option<LayerStats> {
+        self.layers.get(&layer.into()).map(|layer| LayerStats {
+            covered_bytes: layer.scope_spans.iter().map(|(iv, _)| iv.size()).sum(),
+            total_bytes: layer.scope_spans.len(),
+            span_count: layer.scope_spans.count(),
+            style_count: count_styles(&layer.style_spans),
+        })
+    }
+
+    /// Returns the statistics of every layer, in the order of their ids.
+    pub fn all_layer_stats(&self) -> Vec<(LayerId, LayerStats)> {
+        self.layers.keys().filter_map(|&id| self.layer_stats(id).map(|stats| (id, stats)))
+            .collect()
+    }
+
+    /// Returns the fraction of the document covered by a given layer's
+    /// spans: 0 if there is no such layer, and 1 if the document is empty.
+    pub fn coverage_fraction<L: Into<LayerId>>(&self, layer: L) -> f64 {
+        match self.layer_stats(layer) {
+            Some(LayerStats { total_bytes: 0, .. }) => 1.0,
+            Some(stats) => stats.covered_bytes as f64 / stats.total_bytes as f64,
+            None => 0.0,
+        }
+    }
+
+    /// Returns the sizes of the spans and lookup tables of every layer,
+    /// in the order of their ids, and of the merged spans.
+    pub fn stats(&self) -> ScopesStats {
+        let layers = self.layers.iter()
+            .map(|(&plugin, layer)| layer.stats(plugin))
+            .collect::<Vec<_>>();
+        let merged_spans = self.merged.count();
+        let estimated_bytes = layers.iter().map(|layer| layer.estimated_bytes).sum::<usize>()
+            + merged_spans * mem::size_of::<(Interval, Style)>();
+        ScopesStats {
+            len: self.merged.len(),
+            merged_spans: merged_spans,
+            layers: layers,
+            estimated_bytes: estimated_bytes,
+        }
+    }
+
+    /// Returns `true` if any layer has scope information.
+    pub fn has_scopes(&self) -> bool {
+        !self.layers.is_empty()
+    }
+
+    /// Returns the intervals within `iv` which, in any layer, have a scope
+    /// named `kind` or beginning `kind.`, such as "string" or "comment".
+    /// Intervals may overlap, and are in no particular order.
+    pub fn intervals_with_scope(&self, iv: Interval, kinds: &[&str]) -> Vec<Interval> {
+        let is_kind = |name: &String| kinds.iter().any(|kind| {
+            name.starts_with(kind) && name[kind.len()..].chars().next().map_or(true, |c| c == '.')
+        });
+        let mut result = Vec::new();
+        for layer in self.layers.values() {
+            let spans = layer.scope_spans.subseq(iv);
+            for (span_iv, &val) in spans.iter() {
+                let matches = layer.name_lookup.get(val as usize)
+                    .map(|stack| stack.iter().any(&is_kind))
+                    .unwrap_or(false);
+                if matches {
+                    result.push(Interval::new_closed_open(iv.start() + span_iv.start(),
+                                                          iv.start() + span_iv.end()));
+                }
+            }
+        }
+        result
+    }
+
+    /// Returns the spans within `iv` whose scope stack matches `selector`,
+    /// such as "comment.block" or "string - string.quoted.double", with the
+    /// layer each is in. They're ordered by start, and then by the order
+    /// their styles are merged; spans of different layers may overlap.
+    pub fn find_by_selector(&self, selector: &str, iv: Interval)
+                            -> Result<Vec<(Interval, LayerId)>, String> {
+        let selectors = ScopeSelectors::from_str(selector)
+            .map_err(|e| format!("invalid scope selector {:?}: {:?}", selector, e))?;
+        let mut result = Vec::new();
+        for pid in self.layer_order() {
+            let layer = &self.layers[&pid];
+            for (span_iv, &ix) in layer.scope_spans.subseq(iv).iter() {
+                let matches = layer.stack_lookup.get(ix as usize)
+                    .map_or(false, |stack| selectors.does_match(stack).is_some());
+                if matches {
+                    let span_iv = Interval::new_closed_open(iv.start() + span_iv.start(),
+                                                            iv.start() + span_iv.end());
+                    result.push((span_iv, pid));
+                }
+            }
         }
-        self.merged.edit(iv, resolved);
+        result.sort_by_key(|&(span_iv, _)| span_iv.start());
+        Ok(result)
+    }
+
+    /// Returns the spans of each layer within `iv`, in the order their
+    /// styles are merged. Layers with no spans in `iv` are left out.
+    pub fn layer_spans(&self, iv: Interval) -> Vec<LayerSpans> {
+        self.layer_order().into_iter().filter_map(|pid| {
+            let layer = &self.layers[&pid];
+            let spans = layer.scope_spans.subseq(iv).iter()
+                .map(|(span_iv, &ix)| LayerSpan {
+                    start: span_iv.start(),
+                    end: span_iv.end(),
+                    scopes: layer.name_lookup.get(ix as usize).cloned().unwrap_or_default(),
+                    style: layer.style_lookup.get(ix as usize).cloned().unwrap_or_default(),
+                })
+                .collect::<Vec<_>>();
+            if spans.is_empty() {
+                None
+            } else {
+                Some(LayerSpans {
+                    plugin: pid,
+                    priority: layer.priority,
+                    enabled: layer.enabled,
+                    spans: spans,
+                })
+            }
+        }).collect()
     }
 
     /// Prints scopes and style information for the given `Interval`.
     pub fn debug_print_spans(&self, iv: Interval) {
-        for (id, layer) in self.layers.iter() {
+        for (&id, layer) in self.layers.iter() {
             let spans = layer.scope_spans.subseq(iv);
             let styles = layer.style_spans.subseq(iv);
             if spans.iter().next().is_some() {
                 print_err!("scopes for layer {:?}:", id);
-                for (iv, val) in spans.iter() {
-                    print_err!("{}: {:?}", iv, layer.name_lookup[*val as usize]);
+                for (iv, &val) in spans.iter() {
+                    print_err!("{}: {:?}", iv, self.scope_names_at_layer(id, val));
                 }
                 print_err!("styles:");
                 for (iv, val) in styles.iter() {
@@ -142,88 +1788,635 @@ impl Scopes {
     }
 
 
-    fn create_if_missing(&mut self, layer_id: PluginPid) {
-        if !self.layers.contains_key(&layer_id) {
-            self.layers.insert(layer_id, ScopeLayer::new(self.merged.len()));
+    fn create_if_missing(&mut self, layer_id: LayerId) {
+        if !self.layers.contains_key(&layer_id) {
+            self.layers.insert(layer_id, ScopeLayer::new(self.merged.len()));
+        }
+    }
+}
+
+/// Returns the intervals where `style_spans` has styles other than the
+/// default of its gaps, or the whole document if they are out of step with
+/// its length `len`.
+fn styled_intervals(style_spans: &Spans<Style>, len: usize) -> Vec<Interval> {
+    if style_spans.len() != len {
+        return vec![Interval::new_closed_closed(0, len)];
+    }
+    let mut styled: Vec<Interval> = Vec::new();
+    for (iv, _) in style_spans.iter().filter(|&(_, style)| !style.is_default) {
+        match styled.last_mut() {
+            Some(last) if last.end() == iv.start() => *last = last.union(iv),
+            _ => styled.push(iv),
+        }
+    }
+    styled
+}
+
+/// Returns `style_spans` without the spans of its gaps.
+fn without_gaps(style_spans: &Spans<Style>) -> Spans<Style> {
+    let mut sb = SpansBuilder::new(style_spans.len());
+    for (iv, style) in style_spans.iter().filter(|&(_, style)| !style.is_default) {
+        sb.add_span(iv, style.to_owned());
+    }
+    sb.build()
+}
+
+/// Returns the number of spans in `style_spans`, not counting those of
+/// its gaps.
+fn count_styles(style_spans: &Spans<Style>) -> usize {
+    style_spans.iter().filter(|&(_, style)| !style.is_default).count()
+}
+
+/// The hash of the names and modifiers of a scope stack, by which its
+/// entry in a layer's lookup tables is found.
+fn entry_hash(names: &[String], modifiers: u32) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (names, modifiers).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Keeps the items of `lookup` whose entries are `referenced`.
+fn retain_referenced<T>(lookup: &mut Vec<T>, referenced: &[bool]) {
+    let mut entry = 0;
+    lookup.retain(|_| {
+        entry += 1;
+        referenced[entry - 1]
+    });
+}
+
+/// Returns the spans of `above` where it has any, and those of `below`
+/// elsewhere.
+fn overlay_spans<T: Clone + Default>(below: &Spans<T>, above: &Spans<T>) -> Spans<T> {
+    // where the two overlap, `merge` passes the span of `above` second
+    below.merge(above, |a, b| b.unwrap_or(a).to_owned())
+}
+
+/// Moves `annotations` for the edit replacing `iv` with `len` units. Those
+/// within the replaced text are dropped; those at its start stay before
+/// the new text.
+fn shift_annotations(annotations: &mut BTreeMap<usize, Vec<Annotation>>, iv: Interval,
+                     len: usize) {
+    let mut after = annotations.split_off(&iv.start());
+    if let Some(at_start) = after.remove(&iv.start()) {
+        annotations.insert(iv.start(), at_start);
+    }
+    let after = after.split_off(&iv.end());
+    for (offset, mut moved) in after {
+        let offset = offset - iv.size() + len;
+        for annotation in moved.iter_mut() {
+            annotation.offset = offset;
+        }
+        annotations.entry(offset).or_insert_with(Vec::new).append(&mut moved);
+    }
+}
+
+/// Returns `windows` clipped to a document of `len` bytes, in order and
+/// joined where they overlap or touch.
+fn join_windows(windows: &[Interval], len: usize) -> Vec<Interval> {
+    let mut joined = Vec::with_capacity(windows.len());
+    let all = Interval::new_closed_open(0, len);
+    let clipped = windows.iter().map(|window| window.intersect(all))
+        .filter(|window| !window.is_empty());
+    for window in clipped {
+        add_region(&mut joined, window);
+    }
+    joined
+}
+
+/// Returns `regions`, which are in order and apart, moved by the edit
+/// replacing `iv` with `len` units. A region the edit is within covers the
+/// new text; those which end up empty are dropped.
+fn shift_regions(regions: &[Interval], iv: Interval, len: usize) -> Vec<Interval> {
+    let shift = |offset: usize, is_end: bool| if offset <= iv.start() {
+        offset
+    } else if offset >= iv.end() {
+        offset - iv.size() + len
+    } else if is_end {
+        iv.start() + len
+    } else {
+        iv.start()
+    };
+    regions.iter()
+        .map(|region| Interval::new_closed_open(shift(region.start(), false),
+                                                shift(region.end(), true)))
+        .filter(|region| !region.is_empty())
+        .collect()
+}
+
+/// Adds `iv` to `regions`, which are in order and apart, joining it with
+/// those it overlaps or touches.
+fn add_region(regions: &mut Vec<Interval>, iv: Interval) {
+    let first = regions.iter().position(|region| region.end() >= iv.start())
+        .unwrap_or(regions.len());
+    let last = regions.iter().rposition(|region| region.start() <= iv.end())
+        .map_or(first, |ix| max(ix + 1, first));
+    let joined = regions[first..last].iter().fold(iv, |joined, &region| joined.union(region));
+    regions.splice(first..last, Some(joined));
+}
+
+/// Removes `iv` from `regions`, which are in order and apart, cutting
+/// those it overlaps.
+fn remove_region(regions: &mut Vec<Interval>, iv: Interval) {
+    *regions = regions.iter()
+        .flat_map(|region| vec![region.prefix(iv), region.suffix(iv)])
+        .filter(|region| !region.is_empty())
+        .collect();
+}
+
+/// Returns the most severe of `diagnostics` at `offset`; of those as
+/// severe, the first plugin's.
+fn diagnostic_at(diagnostics: &BTreeMap<PluginPid, Spans<DiagnosticSpan>>, offset: usize)
+                 -> Option<&DiagnosticSpan> {
+    diagnostics.values()
+        .filter_map(|spans| spans.span_at(offset).map(|(_, diagnostic)| diagnostic))
+        .min_by_key(|diagnostic| diagnostic.severity)
+}
+
+/// Returns the intervals where the values of `old` and `new`, which have
+/// the same length, differ. They are in order, and adjacent intervals are
+/// joined.
+fn changed_intervals<T>(old: &Spans<T>, new: &Spans<T>) -> Vec<Interval>
+    where T: Clone + Default + PartialEq
+{
+    let old = old.iter().map(|(iv, style)| (iv.start(), iv.end(), style)).collect::<Vec<_>>();
+    let new = new.iter().map(|(iv, style)| (iv.start(), iv.end(), style)).collect::<Vec<_>>();
+    let mut points = old.iter().chain(new.iter())
+        .flat_map(|&(start, end, _)| vec![start, end])
+        .collect::<Vec<_>>();
+    points.sort();
+    points.dedup();
+
+    // the value of `spans` at `point`, advancing `ix` past the spans which
+    // end before it
+    fn value_from<'a, T>(spans: &[(usize, usize, &'a T)], ix: &mut usize, point: usize)
+                         -> Option<&'a T> {
+        while *ix < spans.len() && spans[*ix].1 <= point {
+            *ix += 1;
+        }
+        match spans.get(*ix) {
+            Some(&(start, _, style)) if start <= point => Some(style),
+            _ => None,
+        }
+    }
+
+    let mut changed: Vec<Interval> = Vec::new();
+    let (mut old_ix, mut new_ix) = (0, 0);
+    for pair in points.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+        if value_from(&old, &mut old_ix, start) == value_from(&new, &mut new_ix, start) {
+            continue;
+        }
+        if let Some(last) = changed.last_mut() {
+            if last.end() == start {
+                *last = Interval::new_closed_open(last.start(), end);
+                continue;
+            }
+        }
+        changed.push(Interval::new_closed_open(start, end));
+    }
+    changed
+}
+
+/// Inserts `len` units into `spans` at `offset`, growing the span which
+/// covers the unit before it to cover them.
+fn insert_into_spans<T: Clone + Default>(spans: &mut Spans<T>, offset: usize, len: usize) {
+    let before = match offset {
+        0 => None,
+        _ => spans.span_at(offset - 1).map(|(iv, val)| (iv, val.to_owned())),
+    };
+    match before {
+        Some((span_iv, val)) => {
+            let (start, end) = (span_iv.start(), span_iv.end());
+            let mut sb = SpansBuilder::new(end - start + len);
+            sb.add_span(Interval::new_closed_open(0, end - start + len), val);
+            spans.edit(Interval::new_closed_open(start, end), sb.build());
+        }
+        None => {
+            let point = Interval::new_closed_open(offset, offset);
+            spans.edit(point, SpansBuilder::new(len).build());
+        }
+    }
+}
+
+/// Replaces `iv` in `spans` with `len` units, which the span overlapping or
+/// touching it covers, if there's one; that before it wins over that after.
+fn extend_over_edit<T: Clone + Default>(spans: &mut Spans<T>, iv: Interval, len: usize) {
+    let around = Interval::new_closed_open(iv.start().saturating_sub(1),
+                                           min(iv.end() + 1, spans.len()));
+    let first = spans.subseq(around).iter().next()
+        .map(|(span_iv, _)| span_iv.start() + around.start());
+    let span = first.and_then(|offset| spans.span_at(offset))
+        .map(|(span_iv, val)| (span_iv, val.to_owned()));
+    match span {
+        Some((span_iv, val)) => {
+            let (start, end) = (min(span_iv.start(), iv.start()), max(span_iv.end(), iv.end()));
+            let new_len = end - start - iv.size() + len;
+            let mut sb = SpansBuilder::new(new_len);
+            if new_len > 0 {
+                sb.add_span(Interval::new_closed_open(0, new_len), val);
+            }
+            spans.edit(Interval::new_closed_open(start, end), sb.build());
+        }
+        None => spans.edit(iv, SpansBuilder::new(len).build()),
+    }
+}
+
+impl Default for ScopeLayer {
+    fn default() -> Self {
+        ScopeLayer {
+            priority: 0,
+            enabled: true,
+            composite_mode: CompositeMode::default(),
+            edit_policy: EditPolicy::default(),
+            stack_lookup: Vec::new(),
+            modifier_lookup: Vec::new(),
+            style_lookup: Vec::new(),
+            name_lookup: Vec::new(),
+            scope_ids: Vec::new(),
+            entries_by_hash: HashMap::new(),
+            scope_spans: Spans::default(),
+            style_spans: Spans::default(),
+            unscoped_styles: false,
+            evicted: Vec::new(),
+        }
+    }
+}
+
+impl StyleLayer {
+
+    pub fn new(len: usize) -> Self {
+        StyleLayer {
+            priority: 0,
+            enabled: true,
+            edit_policy: EditPolicy::default(),
+            style_spans: SpansBuilder::new(len).build(),
+        }
+    }
+
+    fn apply_edit(&mut self, iv: Interval, len: usize) {
+        self.edit_policy.apply(&mut self.style_spans, iv, len);
+    }
+}
+
+impl ScopeLayer {
+
+    pub fn new(len: usize) -> Self {
+        ScopeLayer {
+            priority: 0,
+            enabled: true,
+            composite_mode: CompositeMode::default(),
+            edit_policy: EditPolicy::default(),
+            stack_lookup: Vec::new(),
+            modifier_lookup: Vec::new(),
+            style_lookup: Vec::new(),
+            name_lookup: Vec::new(),
+            scope_ids: Vec::new(),
+            entries_by_hash: HashMap::new(),
+            scope_spans: SpansBuilder::new(len).build(),
+            style_spans: SpansBuilder::new(len).build(),
+            unscoped_styles: false,
+            evicted: Vec::new(),
+        }
+    }
+
+    /// Returns the names of the scope stack of the entry `idx`, the value
+    /// of the spans which have it, or `None` if there is no such entry.
+    pub fn scope_name_for_index(&self, idx: u32) -> Option<&[String]> {
+        self.name_lookup.get(idx as usize).map(|names| names.as_slice())
+    }
+
+    /// Returns the spans of the layer within `iv`, clipped to it, with the
+    /// names of their scope stacks. Offsets are in the document; the names
+    /// are borrowed from the layer, not copied.
+    pub fn scope_spans_in<'a>(&'a self, iv: Interval)
+                              -> impl Iterator<Item=(Interval, &'a [String])> + 'a {
+        let spans = self.scope_spans.subseq(iv).iter()
+            .map(|(span_iv, &ix)| (span_iv.translate(iv.start()), ix))
+            .collect::<Vec<_>>();
+        spans.into_iter().filter_map(move |(span_iv, ix)| {
+            self.name_lookup.get(ix as usize).map(|names| (span_iv, names.as_slice()))
+        })
+    }
+
+    /// Returns the regions covered by each `meta.` scope of the layer, at
+    /// whatever depth of the stacks, where its spans are contiguous.
+    fn meta_regions(&self) -> Vec<Interval> {
+        let mut regions = Vec::new();
+        // the region of the scope at each depth which is still open
+        let mut open: Vec<Option<(&str, Interval)>> = Vec::new();
+        for (iv, &ix) in self.scope_spans.iter() {
+            let names = self.name_lookup.get(ix as usize).map_or(&[][..], |names| &names[..]);
+            if open.len() < names.len() {
+                open.resize(names.len(), None);
+            }
+            for (depth, region) in open.iter_mut().enumerate() {
+                let name = names.get(depth).map(|name| name.as_str())
+                    .filter(|name| name.starts_with("meta."));
+                *region = match (region.take(), name) {
+                    (Some((open_name, open_iv)), Some(name))
+                        if open_name == name && open_iv.end() == iv.start() => {
+                        Some((name, open_iv.union(iv)))
+                    }
+                    (closed, name) => {
+                        regions.extend(closed.map(|(_, closed_iv)| closed_iv));
+                        name.map(|name| (name, iv))
+                    }
+                };
+            }
+        }
+        regions.extend(open.into_iter().filter_map(|region| region.map(|(_, iv)| iv)));
+        regions
+    }
+
+    /// Resolves the styles of the layer's scopes with a new theme, and
+    /// returns the intervals of the spans whose scopes' style changed. The
+    /// spans keep their old styles until they are restyled.
+    fn theme_changed(&mut self, theme: &Theme, overrides: &[(ScopeSelectors, StyleDelta)])
+                     -> Vec<Interval> {
+        let style_lookup = Self::styles_for_stacks(&self.stack_lookup, &self.modifier_lookup,
+                                                   theme, overrides);
+        let changed = style_lookup.iter().zip(self.style_lookup.iter())
+            .map(|(new, old)| new != old)
+            .collect::<Vec<_>>();
+        self.style_lookup = style_lookup;
+        if mem::replace(&mut self.unscoped_styles, false) {
+            return vec![Interval::new_closed_open(0, self.style_spans.len())];
+        }
+        if !changed.contains(&true) {
+            return Vec::new();
+        }
+        let mut ivs: Vec<Interval> = Vec::new();
+        for (iv, _) in self.scope_spans.iter().filter(|&(_, &ix)| changed[ix as usize]) {
+            match ivs.last_mut() {
+                Some(last) if last.end() == iv.start() => *last = last.union(iv),
+                _ => ivs.push(iv),
+            }
+        }
+        ivs
+    }
+
+    /// Resolves the styles of the spans within `iv` again, as for a new
+    /// theme.
+    fn restyle(&mut self, iv: Interval) {
+        let styles = Self::styles_for_spans(&self.style_lookup, &self.scope_spans.subseq(iv));
+        self.style_spans.edit(iv, styles);
+    }
+
+    /// Returns the sizes of the layer's spans and lookup tables. The bytes
+    /// are estimated from the sizes of their elements, not counting the
+    /// nodes of the spans' trees.
+    fn stats(&self, plugin: LayerId) -> ScopeLayerStats {
+        let scope_spans = self.scope_spans.count();
+        let style_spans = count_styles(&self.style_spans);
+        let stacks_bytes = self.stack_lookup.iter()
+            .map(|stack| stack.len() * mem::size_of::<Scope>())
+            .sum::<usize>();
+        let names_bytes = self.name_lookup.iter()
+            .flat_map(|stack| stack.iter().map(|name| name.len() + mem::size_of::<String>()))
+            .sum::<usize>();
+        let referenced_stacks = self.referenced_entries().iter().filter(|&&r| r).count();
+        let estimated_bytes = scope_spans * mem::size_of::<(Interval, u32)>()
+            + style_spans * mem::size_of::<(Interval, Style)>()
+            + stacks_bytes + self.stack_lookup.len() * mem::size_of::<Vec<Scope>>()
+            + self.style_lookup.len() * mem::size_of::<Style>()
+            + names_bytes + self.name_lookup.len() * mem::size_of::<Vec<String>>()
+            + self.scope_ids.len() * mem::size_of::<u32>();
+        ScopeLayerStats {
+            plugin: plugin,
+            scope_spans: scope_spans,
+            style_spans: style_spans,
+            stacks: self.stack_lookup.len(),
+            styles: self.style_lookup.len(),
+            names: self.name_lookup.len(),
+            ids: self.scope_ids.len(),
+            referenced_stacks: referenced_stacks,
+            estimated_bytes: estimated_bytes,
         }
     }
-}
 
-impl Default for ScopeLayer {
-    fn default() -> Self {
-        ScopeLayer {
-            stack_lookup: Vec::new(),
-            style_lookup: Vec::new(),
-            name_lookup: Vec::new(),
-            scope_spans: Spans::default(),
-            style_spans: Spans::default(),
+    /// Returns whether the spans refer to each entry of the lookup tables.
+    fn referenced_entries(&self) -> Vec<bool> {
+        let mut referenced = vec![false; self.style_lookup.len()];
+        for (_, &entry) in self.scope_spans.iter() {
+            referenced[entry as usize] = true;
         }
+        referenced
     }
-}
-
-impl ScopeLayer {
 
-    pub fn new(len: usize) -> Self {
-        ScopeLayer {
-            stack_lookup: Vec::new(),
-            style_lookup: Vec::new(),
-            name_lookup: Vec::new(),
-            scope_spans: SpansBuilder::new(len).build(),
-            style_spans: SpansBuilder::new(len).build(),
+    /// Drops the entries of the lookup tables which no span refers to, as
+    /// those of stacks a plugin sent again, renumbering the others. Their
+    /// scope ids are retired: spans with them are dropped, as though their
+    /// scopes were unknown. Returns the number of entries dropped.
+    fn compact(&mut self) -> usize {
+        let referenced = self.referenced_entries();
+        let mut next = 0;
+        let renumbered = referenced.iter().map(|&is_referenced| if is_referenced {
+            next += 1;
+            next - 1
+        } else {
+            RETIRED_SCOPE
+        }).collect::<Vec<_>>();
+        let dropped = referenced.len() - next as usize;
+        if dropped == 0 {
+            return 0;
         }
+        retain_referenced(&mut self.stack_lookup, &referenced);
+        retain_referenced(&mut self.modifier_lookup, &referenced);
+        retain_referenced(&mut self.style_lookup, &referenced);
+        retain_referenced(&mut self.name_lookup, &referenced);
+        for entry in self.scope_ids.iter_mut().filter(|entry| **entry != RETIRED_SCOPE) {
+            *entry = renumbered[*entry as usize];
+        }
+        let mut sb = SpansBuilder::new(self.scope_spans.len());
+        for (iv, &entry) in self.scope_spans.iter() {
+            sb.add_span(iv, renumbered[entry as usize]);
+        }
+        self.scope_spans = sb.build();
+        self.entries_by_hash.clear();
+        for entry in 0..self.name_lookup.len() {
+            let hash = entry_hash(&self.name_lookup[entry], self.modifier_lookup[entry]);
+            self.entries_by_hash.entry(hash).or_insert_with(Vec::new).push(entry as u32);
+        }
+        dropped
     }
 
-    fn theme_changed(&mut self, doc_ctx: &DocumentCtx) {
-        // recompute styles with the new theme
-        self.style_lookup = self.styles_for_stacks(self.stack_lookup.as_slice(), doc_ctx);
-        let iv_all = Interval::new_closed_closed(0, self.style_spans.len());
-        self.style_spans = SpansBuilder::new(self.style_spans.len()).build();
-        // this feels unnecessary but we can't pass in a reference to self
-        // and I don't want to get fancy unless there's an actual perf problem
-        let scopes = self.scope_spans.clone();
-        self.update_styles(iv_all, &scopes)
+    /// Clears the layer's scopes and their spans, keeping its styles.
+    fn forget_scopes(&mut self) {
+        self.stack_lookup.clear();
+        self.modifier_lookup.clear();
+        self.style_lookup.clear();
+        self.name_lookup.clear();
+        self.scope_ids.clear();
+        self.entries_by_hash.clear();
+        self.scope_spans = SpansBuilder::new(self.scope_spans.len()).build();
+        self.unscoped_styles = true;
     }
 
-    fn add_scopes(&mut self, scopes: Vec<Vec<String>>,
-                                doc_ctx: &DocumentCtx) {
+    fn add_scopes(&mut self, scopes: Vec<Vec<String>>, mut modifiers: Vec<u32>,
+                  doc_ctx: &DocumentCtx, overrides: &[(ScopeSelectors, StyleDelta)])
+                  -> Result<(), String> {
+        let n_stacks = scopes.len();
+        let n_modifiers = modifiers.len();
+        modifiers.resize(n_stacks, 0);
         let mut stacks = Vec::with_capacity(scopes.len());
-        for stack in scopes {
+        let mut new_modifiers = Vec::with_capacity(scopes.len());
+        let mut failed = Vec::new();
+        for (stack, modifiers) in scopes.into_iter().zip(modifiers) {
+            let hash = entry_hash(&stack, modifiers);
+            let existing = self.entries_by_hash.get(&hash).and_then(|entries| {
+                entries.iter().cloned().find(|&entry| {
+                    self.name_lookup[entry as usize] == stack
+                        && self.modifier_lookup[entry as usize] == modifiers
+                })
+            });
+            if let Some(entry) = existing {
+                self.scope_ids.push(entry);
+                continue;
+            }
+            let entry = self.name_lookup.len() as u32;
+            self.scope_ids.push(entry);
+            self.entries_by_hash.entry(hash).or_insert_with(Vec::new).push(entry);
             let scopes = stack.iter().map(|s| Scope::new(&s))
-                .filter(|result| match *result {
+                .zip(stack.iter())
+                .filter(|&(ref result, name)| match *result {
                     Err(ref err) => {
                         print_err!("failed to resolve scope {}\nErr: {:?}",
                                    &stack.join(" "),
                                    err);
+                        failed.push(name.to_owned());
                         false
                     }
                     _ => true
                 })
-                .map(|s| s.unwrap())
+                .map(|(s, _)| s.unwrap())
                 .collect::<Vec<_>>();
             stacks.push(scopes);
             self.name_lookup.push(stack);
+            // pushed right away, so that the stacks added again later in
+            // `scopes` find their entry
+            self.modifier_lookup.push(modifiers);
+            new_modifiers.push(modifiers);
         }
 
-        let mut new_styles = self.styles_for_stacks(stacks.as_slice(), doc_ctx);
+        let theme = doc_ctx.get_theme();
+        let mut new_styles = Self::styles_for_stacks(&stacks, &new_modifiers, &theme,
+                                                     overrides);
         self.stack_lookup.append(&mut stacks);
         self.style_lookup.append(&mut new_styles);
+        if !failed.is_empty() {
+            Err(format!("failed to resolve scopes {}", failed.join(", ")))
+        } else if n_modifiers > n_stacks {
+            Err(format!("{} modifiers for {} stacks", n_modifiers, n_stacks))
+        } else {
+            Ok(())
+        }
     }
 
-    fn styles_for_stacks(&self, stacks: &[Vec<Scope>],
-                         doc_ctx: &DocumentCtx) -> Vec<Style> {
-        let style_map = doc_ctx.get_style_map().lock().unwrap();
-        let highlighter = style_map.get_highlighter();
+    /// Computes the styles of `stacks` with `theme`, a snapshot taken from
+    /// the style map, so that its lock isn't held while they are computed,
+    /// and then the `overrides` whose selectors match, in order.
+    fn styles_for_stacks(stacks: &[Vec<Scope>], modifiers: &[u32], theme: &Theme,
+                         overrides: &[(ScopeSelectors, StyleDelta)]) -> Vec<Style> {
+        let highlighter = Highlighter::new(theme);
+        let invisible = Scope::new("invisible").unwrap();
 
         let mut new_styles = Vec::new();
-        for stack in stacks {
+        for (stack, &modifiers) in stacks.iter().zip(modifiers) {
             let style = highlighter.style_mod_for_stack(stack);
-            let style = Style::from_syntect_style_mod(&style);
+            let mut style = Style::from_syntect_style_mod(&style)
+                .apply_delta(&StyleDelta::for_modifiers(modifiers));
+            // invisible characters the theme doesn't color are dimmed
+            if style.fg_color.is_none() && stack.iter().any(|s| invisible.is_prefix_of(*s)) {
+                style.fg_color = Some(Style::invisibles_color_for_theme(theme));
+            }
+            for &(ref selectors, ref delta) in overrides {
+                if selectors.does_match(stack).is_some() {
+                    style = style.apply_delta(delta);
+                }
+            }
             new_styles.push(style);
         }
         new_styles
     }
 
+    /// Updates the spans for the edit replacing `iv` with `len` units, as
+    /// its `EditPolicy` moves them.
+    fn apply_edit(&mut self, iv: Interval, len: usize) {
+        self.edit_policy.apply(&mut self.scope_spans, iv, len);
+        self.edit_policy.apply(&mut self.style_spans, iv, len);
+        if !self.evicted.is_empty() {
+            self.evicted = shift_regions(&self.evicted, iv, len);
+        }
+    }
+
+    /// Drops the spans outside `windows`, which are in order and apart,
+    /// marking the regions where the layer had any as evicted. Returns
+    /// those regions.
+    fn evict_outside(&mut self, windows: &[Interval]) -> Vec<Interval> {
+        let len = self.scope_spans.len();
+        let mut evicted = Vec::new();
+        let mut start = 0;
+        let bounds = windows.iter().map(|w| (w.start(), w.end())).chain(Some((len, len)));
+        for (window_start, window_end) in bounds {
+            let gap = Interval::new_closed_open(start, max(start, window_start));
+            start = max(start, window_end);
+            if gap.is_empty() || self.scope_spans.subseq(gap).count() == 0
+                && count_styles(&self.style_spans.subseq(gap)) == 0 {
+                continue;
+            }
+            self.scope_spans.edit(gap, SpansBuilder::new(gap.size()).build());
+            self.style_spans.edit(gap, SpansBuilder::new(gap.size()).build());
+            add_region(&mut self.evicted, gap);
+            evicted.push(gap);
+        }
+        evicted
+    }
+
+    /// Removes and returns the evicted regions within `windows`.
+    fn take_evicted(&mut self, windows: &[Interval]) -> Vec<Interval> {
+        let taken = windows.iter()
+            .flat_map(|&window| self.evicted.iter().map(move |region| region.intersect(window)))
+            .filter(|region| !region.is_empty())
+            .collect::<Vec<_>>();
+        for &region in &taken {
+            remove_region(&mut self.evicted, region);
+        }
+        taken
+    }
+
+    /// Sets the spans in `iv` to those of `foreign`, a layer of the text in
+    /// it, where it has any, adding its scopes after this layer's.
+    fn merge_foreign(&mut self, foreign: &ScopeLayer, iv: Interval) {
+        let first_scope = self.style_lookup.len() as u32;
+        self.stack_lookup.extend(foreign.stack_lookup.iter().cloned());
+        self.modifier_lookup.extend(foreign.modifier_lookup.iter().cloned());
+        self.style_lookup.extend(foreign.style_lookup.iter().cloned());
+        self.name_lookup.extend(foreign.name_lookup.iter().cloned());
+        // the plugin's scope ids are this layer's, but its stacks added
+        // again can share the foreign entries
+        for (i, names) in foreign.name_lookup.iter().enumerate() {
+            let hash = entry_hash(names, foreign.modifier_lookup[i]);
+            self.entries_by_hash.entry(hash).or_insert_with(Vec::new).push(first_scope + i as u32);
+        }
+        let mut sb = SpansBuilder::new(iv.size());
+        for (span_iv, &scope) in foreign.scope_spans.iter() {
+            sb.add_span(span_iv, scope + first_scope);
+        }
+        let scope_spans = overlay_spans(&self.scope_spans.subseq(iv), &sb.build());
+        let style_spans = self.style_spans.subseq(iv).merge(&foreign.style_spans, |a, b| match b {
+            Some(b) if !b.is_default => b.to_owned(),
+            _ => a.to_owned(),
+        });
+        self.scope_spans.edit(iv, scope_spans);
+        self.style_spans.edit(iv, style_spans);
+        self.unscoped_styles |= foreign.unscoped_styles;
+    }
+
     fn update_scopes(&mut self, iv: Interval, spans: &Spans<u32>) {
+        // the plugin's spans are kept again, whether or not it was asked
+        if !self.evicted.is_empty() {
+            remove_region(&mut self.evicted, iv);
+        }
         self.scope_spans.edit(iv, spans.to_owned());
         self.update_styles(iv, spans);
     }
@@ -231,7 +2424,13 @@ impl ScopeLayer {
     /// Updates `self.style_spans`, mapping scopes to styles and combining
     /// adjacent and equal spans.
     fn update_styles(&mut self, iv: Interval, spans: &Spans<u32>) {
+        let styles = Self::styles_for_spans(&self.style_lookup, spans);
+        self.style_spans.edit(iv, styles);
+    }
 
+    /// Maps the scopes of `spans` to their styles in `style_lookup`. It
+    /// borrows just the lookup, so that a layer's own spans can be passed.
+    fn styles_for_spans(style_lookup: &[Style], spans: &Spans<u32>) -> Spans<Style> {
         // NOTE: This is a tradeoff. Keeping both u32 and Style spans for each
         // layer makes debugging simpler and reduces the total number of spans
         // on the wire (because we combine spans that resolve to the same style)
@@ -239,11 +2438,13 @@ impl ScopeLayer {
         let mut sb = SpansBuilder::new(spans.len());
         let mut spans_iter = spans.iter();
         let mut prev = spans_iter.next();
-        {
+        // the gaps between spans get the default style, so that the layers
+        // below show through them when the styles are resolved
+        let mut end = 0;
         // distinct adjacent scopes can often resolve to the same style,
         // so we combine them when building the styles.
         let style_eq = |i1: &u32, i2: &u32| {
-            self.style_lookup[*i1 as usize] == self.style_lookup[*i2 as usize]
+            style_lookup[*i1 as usize] == style_lookup[*i2 as usize]
         };
 
         while let Some((p_iv, p_val)) = prev {
@@ -252,12 +2453,1528 @@ impl ScopeLayer {
                     prev = Some((p_iv.union(n_iv), p_val));
                 }
                 other => {
-                    sb.add_span(p_iv, self.style_lookup[*p_val as usize].to_owned());
+                    if p_iv.start() > end {
+                        sb.add_span(Interval::new_closed_open(end, p_iv.start()), Style::gap());
+                    }
+                    sb.add_span(p_iv, style_lookup[*p_val as usize].to_owned());
+                    end = p_iv.end();
                     prev = other;
                 }
             }
         }
+        if spans.len() > end {
+            sb.add_span(Interval::new_closed_open(end, spans.len()), Style::gap());
+        }
+        sb.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use serde_json;
+
+    use super::*;
+    use tabs::mock_doc_ctx;
+
+    const SCOPES: &[&str] = &["keyword.control", "entity.name.function", "string.quoted",
+        "comment.line", "constant.numeric", "storage.type", "variable.parameter"];
+
+    /// Returns scopes with `n_stacks` stacks, each of which styles a span.
+    fn scopes_with_stacks(n_stacks: usize, doc_ctx: &DocumentCtx) -> Scopes {
+        let plugin = PluginPid::new(1);
+        let stacks = (0..n_stacks).map(|i| vec![
+            "source.rust".to_owned(),
+            format!("meta.block.{}", i),
+            SCOPES[i % SCOPES.len()].to_owned(),
+        ]).collect();
+        let mut scopes = Scopes::default();
+        scopes.add_scopes(plugin, stacks, doc_ctx).unwrap();
+        let len = 2 * n_stacks;
+        scopes.update_all(Interval::new_closed_open(0, 0), len);
+        let mut sb = SpansBuilder::new(len);
+        for i in 0..n_stacks {
+            sb.add_span(Interval::new_closed_open(2 * i, 2 * i + 1), i as u32);
+        }
+        scopes.update_layer(plugin, Interval::new_closed_open(0, len), sb.build(), None, None)
+            .unwrap();
+        scopes
+    }
+
+    /// Sets the layer of `plugin` to style all of a text of length `len`
+    /// with `scope`.
+    fn scope_layer<L: Into<LayerId>>(scopes: &mut Scopes, layer: L, scope: &str, len: usize,
+                                     priority: Option<u32>, doc_ctx: &DocumentCtx) {
+        let layer = layer.into();
+        scopes.add_scopes(layer, vec![vec![scope.to_owned()]], doc_ctx).unwrap();
+        let mut sb = SpansBuilder::new(len);
+        sb.add_span(Interval::new_closed_open(0, len), 0);
+        scopes.update_layer(layer, Interval::new_closed_open(0, len), sb.build(), priority, None)
+            .unwrap();
+    }
+
+    fn fg_colors(scopes: &mut Scopes) -> Vec<Option<u32>> {
+        scopes.get_merged().iter().map(|(_, style)| style.fg_color).collect()
+    }
+
+    #[test]
+    fn point_queries() {
+        let doc_ctx = mock_doc_ctx("layers_point_queries");
+        let mut scopes = scopes_with_stacks(1000, &doc_ctx);
+        let plugin = PluginPid::new(1);
+        let styles = scopes.layers[&LayerId::from(plugin)].style_lookup.clone();
+        for i in (0..1000).step_by(7) {
+            assert_eq!(scopes.style_at(2 * i), Some(styles[i].clone()));
+            assert_eq!(scopes.style_at(2 * i + 1), None);
+            let names = scopes.scope_names_at(plugin, 2 * i).unwrap();
+            assert_eq!(names[1], format!("meta.block.{}", i));
+            assert_eq!(scopes.scope_names_at(plugin, 2 * i + 1), None);
+        }
+        assert_eq!(scopes.style_at(2000), None);
+        assert_eq!(scopes.scope_names_at(PluginPid::new(2), 0), None);
+
+        let spans = scopes.scope_spans_in(plugin, Interval::new_closed_open(9, 14)).unwrap()
+            .map(|(iv, names)| (iv.start(), iv.end(), names[1].as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(spans, vec![(10, 11, "meta.block.5"), (12, 13, "meta.block.6")]);
+        assert!(scopes.scope_spans_in(PluginPid::new(2), Interval::new_closed_open(0, 4))
+            .is_none());
+    }
+
+    #[test]
+    fn layer_stats() {
+        let doc_ctx = mock_doc_ctx("layers_layer_stats");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let plugin = PluginPid::new(1);
+        let stats = scopes.layer_stats(plugin).unwrap();
+        assert_eq!(stats, LayerStats {
+            covered_bytes: 10, total_bytes: 20, span_count: 10, style_count: 10,
+        });
+        assert_eq!(scopes.coverage_fraction(plugin), 0.5);
+        assert_eq!(scopes.layer_stats(PluginPid::new(2)), None);
+        assert_eq!(scopes.coverage_fraction(PluginPid::new(2)), 0.0);
+
+        // adjacent spans resolving to the same style are counted once
+        let mut sb = SpansBuilder::new(4);
+        sb.add_span(Interval::new_closed_open(0, 2), 0);
+        sb.add_span(Interval::new_closed_open(2, 4), 0);
+        scopes.update_layer(plugin, Interval::new_closed_open(0, 4), sb.build(), None, None)
+            .unwrap();
+        let stats = scopes.layer_stats(plugin).unwrap();
+        assert_eq!((stats.covered_bytes, stats.span_count, stats.style_count), (12, 10, 9));
+        assert_eq!(scopes.all_layer_stats(), vec![(plugin.into(), stats)]);
+        assert_eq!(serde_json::to_value(stats).unwrap()["covered_bytes"], json!(12));
+    }
+
+    #[test]
+    fn scopes_stats() {
+        let doc_ctx = mock_doc_ctx("layers_scopes_stats");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let (plugin, other) = (PluginPid::new(1), PluginPid::new(2));
+        scope_layer(&mut scopes, other, "comment.line", 20, None, &doc_ctx);
+        check_resolved(&mut scopes);
+        let stats = scopes.stats();
+        assert_eq!((stats.len, stats.merged_spans), (20, 20));
+        let layer = stats.layers[0].clone();
+        assert_eq!((layer.plugin, layer.scope_spans, layer.style_spans), (plugin.into(), 10, 10));
+        assert_eq!((layer.stacks, layer.styles, layer.names), (10, 10, 10));
+        assert!(layer.estimated_bytes > stats.layers[1].estimated_bytes);
+        assert_eq!(stats.estimated_bytes, layer.estimated_bytes +
+                   stats.layers[1].estimated_bytes + 20 * mem::size_of::<(Interval, Style)>());
+
+        // inserting moves the spans without adding to them
+        scopes.update_all(Interval::new_closed_open(3, 3), 5);
+        check_resolved(&mut scopes);
+        let inserted = scopes.stats();
+        assert_eq!(inserted.len, 25);
+        assert_eq!(inserted.layers, stats.layers);
+
+        // a layer adopted by a restarted plugin keeps only its styles
+        let restarted = PluginPid::new(3);
+        scopes.detach_layer(plugin);
+        scopes.adopt_layer(plugin, restarted);
+        let adopted = scopes.stats();
+        let layer = adopted.layers.iter().find(|layer| layer.plugin == restarted.into()).unwrap();
+        assert_eq!((layer.scope_spans, layer.style_spans, layer.stacks, layer.names),
+                   (0, 10, 0, 0));
+        assert!(adopted.estimated_bytes < inserted.estimated_bytes);
+
+        scopes.remove_layer(restarted);
+        scopes.remove_layer(other);
+        let removed = scopes.stats();
+        assert!(removed.layers.is_empty());
+        assert_eq!(removed.estimated_bytes,
+                   removed.merged_spans * mem::size_of::<(Interval, Style)>());
+    }
+
+    #[test]
+    fn malformed_updates() {
+        let doc_ctx = mock_doc_ctx("layers_malformed_updates");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let styles = check_resolved(&mut scopes);
+        let (plugin, other) = (PluginPid::new(1), PluginPid::new(2));
+        let usage = scopes.layer_usage(plugin);
+
+        // a scope which can't be parsed is reported, but the stack added
+        let stacks = vec![vec!["a.b.c.d.e.f.g.h.i".to_owned()], vec!["comment.line".to_owned()]];
+        let err = scopes.add_scopes(other, stacks, &doc_ctx).unwrap_err();
+        assert!(err.contains("a.b.c.d.e.f.g.h.i"));
+        assert_eq!(scopes.layer_usage(other), (2, 0));
+
+        // spans past the end of the document are rejected
+        let mut sb = SpansBuilder::new(10);
+        sb.add_span(Interval::new_closed_open(0, 10), 1);
+        let past_end = Interval::new_closed_open(15, 25);
+        let err = scopes.update_layer(other, past_end, sb.build(), None, None);
+        assert_eq!(err, Err("spans 15..25 are past the end of the document, 20".to_owned()));
+        assert_eq!(check_resolved(&mut scopes), styles);
+
+        // as are spans which are not as long as the interval they replace
+        let mut sb = SpansBuilder::new(10);
+        sb.add_span(Interval::new_closed_open(0, 10), 1);
+        let short = Interval::new_closed_open(0, 12);
+        let err = scopes.update_layer(other, short, sb.build(), None, None);
+        assert_eq!(err, Err("spans for 0..12 are 10 long, not 12".to_owned()));
+        assert_eq!(check_resolved(&mut scopes), styles);
+        assert_eq!(scopes.layer_usage(other), (2, 0));
+
+        // spans with unknown scopes are dropped, and the others applied
+        let mut sb = SpansBuilder::new(4);
+        sb.add_span(Interval::new_closed_open(0, 2), 1);
+        sb.add_span(Interval::new_closed_open(2, 4), 7);
+        scopes.update_layer(other, Interval::new_closed_open(0, 4), sb.build(), None, None)
+            .unwrap();
+        assert_eq!(scopes.layer_usage(other), (2, 1));
+        assert_eq!(scopes.scope_names_at(other, 1), Some(&["comment.line".to_owned()][..]));
+        assert_eq!(scopes.scope_names_at(other, 2), None);
+        assert_eq!(&check_resolved(&mut scopes)[2..], &styles[2..]);
+        assert_eq!(scopes.layer_usage(plugin), usage);
+    }
+
+    #[test]
+    fn restored_layers() {
+        let doc_ctx = mock_doc_ctx("layers_restored_layers");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let plugin = PluginPid::new(1);
+        let styles = check_resolved(&mut scopes);
+        let bytes = scopes.serialize_layer(plugin).unwrap();
+        assert!(scopes.serialize_layer(PluginPid::new(2)).is_none());
+
+        // a new session shows the styles before the plugin sends scopes
+        let mut restored = Scopes::default();
+        restored.update_all(Interval::new_closed_open(0, 0), 20);
+        assert!(restored.restore_layer(plugin, &bytes).is_ok());
+        assert_eq!(check_resolved(&mut restored), styles);
+        // until it replaces them
+        let sb = SpansBuilder::new(4);
+        restored.update_layer(plugin, Interval::new_closed_open(0, 4), sb.build(), None, None)
+            .unwrap();
+        let updated = check_resolved(&mut restored);
+        assert_eq!(updated[0], None);
+        assert_eq!(&updated[4..], &styles[4..]);
+
+        let mut shorter = Scopes::default();
+        shorter.update_all(Interval::new_closed_open(0, 0), 10);
+        assert_eq!(shorter.restore_layer(plugin, &bytes),
+                   Err("the styles run to 19, past the end of the document, 10".to_owned()));
+        assert!(shorter.restore_layer(plugin, b"[1, 2").is_err());
+        assert!(shorter.layers.is_empty());
+    }
+
+    #[test]
+    fn fold_ranges() {
+        let doc_ctx = mock_doc_ctx("layers_fold_ranges");
+        let text = Rope::from("fn a() {\n    b\n}\nfn c() { d }\n");
+        let stack = |names: &[&str]| {
+            ["source.rust"].iter().chain(names).map(|&name| name.to_owned()).collect()
+        };
+        let mut scopes = Scopes::default();
+        let plugin = PluginPid::new(1);
+        scopes.add_scopes(plugin, vec![stack(&["meta.function"]),
+                                       stack(&["meta.function", "meta.block"]),
+                                       stack(&["meta.function", "meta.block", "variable"]),
+                                       stack(&["keyword"])], &doc_ctx).unwrap();
+        scopes.update_all(Interval::new_closed_open(0, 0), text.len());
+        let mut sb = SpansBuilder::new(text.len());
+        for &(start, end, ix) in &[(0, 2, 3), (2, 7, 0), (7, 14, 1), (14, 15, 2), (15, 17, 1),
+                                   (17, 19, 3), (19, 24, 0), (24, 26, 1), (26, 27, 2),
+                                   (27, 29, 1)] {
+            sb.add_span(Interval::new_closed_open(start, end), ix);
+        }
+        let iv = Interval::new_closed_open(0, text.len());
+        scopes.update_layer(plugin, iv, sb.build(), None, None)
+            .unwrap();
+
+        let ranges = |min_lines| scopes.fold_ranges(plugin, &text, min_lines).iter()
+            .map(|iv| (iv.start(), iv.end()))
+            .collect::<Vec<_>>();
+        assert_eq!(ranges(2), vec![(2, 17), (7, 17)]);
+        assert_eq!(ranges(1), vec![(2, 17), (7, 17), (19, 29), (24, 29)]);
+        assert_eq!(ranges(4), vec![]);
+        assert!(scopes.fold_ranges(PluginPid::new(2), &text, 1).is_empty());
+    }
+
+    #[test]
+    fn merged_by_line() {
+        let doc_ctx = mock_doc_ctx("layers_merged_by_line");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let red = Style::new(200, Some(0xFFFF_0000), None, None, None, None);
+        let mut sb = SpansBuilder::new(20);
+        sb.add_span(Interval::new_closed_open(5, 13), red.clone());
+        scopes.update_style_layer(PluginPid::new(2), Interval::new_closed_open(0, 20), sb.build())
+            .unwrap();
+        let lines = [(0, 4), (4, 10), (10, 20), (20, 24)].iter()
+            .map(|&(start, end)| Interval::new_closed_open(start, end))
+            .collect::<Vec<_>>();
+        let by_line = scopes.merged_by_line(&lines);
+        let bounds = by_line.iter()
+            .map(|spans| spans.iter().map(|&(iv, _)| (iv.start(), iv.end())).collect())
+            .collect::<Vec<Vec<_>>>();
+        assert_eq!(bounds, vec![vec![(0, 1), (2, 3)],
+                                vec![(0, 1), (1, 2), (2, 3), (3, 4), (4, 5), (5, 6)],
+                                vec![(0, 1), (1, 2), (2, 3), (4, 5), (6, 7), (8, 9)],
+                                vec![]]);
+        // the red span is split between the second and third lines
+        assert_eq!(by_line[1][1].1.fg_color, Some(0xFFFF_0000));
+        assert_eq!(by_line[2][2].1.fg_color, Some(0xFFFF_0000));
+        assert_eq!(by_line[2][3].1, scopes.style_at(14).unwrap());
+    }
+
+    fn diagnostics(len: usize, spans: &[(usize, usize, DiagnosticSeverity, &str)])
+                   -> Spans<DiagnosticSpan> {
+        let mut sb = SpansBuilder::new(len);
+        for &(start, end, severity, message) in spans {
+            let diagnostic = DiagnosticSpan { severity: severity, message: message.to_owned() };
+            sb.add_span(Interval::new_closed_open(start, end), diagnostic);
+        }
+        sb.build()
+    }
+
+    #[test]
+    fn diagnostic_layers() {
+        use super::DiagnosticSeverity::*;
+        let doc_ctx = mock_doc_ctx("layers_diagnostic_layers");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let (linter, server) = (PluginPid::new(2), PluginPid::new(3));
+        let all = Interval::new_closed_open(0, 20);
+        scopes.update_diagnostic_layer(linter, all,
+                                       diagnostics(20, &[(2, 5, Warning, "unused")]));
+        scopes.update_diagnostic_layer(server, all,
+            diagnostics(20, &[(0, 3, Error, "mismatched"), (10, 12, Hint, "elided")]));
+        let fg = |scopes: &mut Scopes, offset| scopes.style_at(offset).and_then(|s| s.fg_color);
+        let underline = |scopes: &mut Scopes, offset| scopes.style_at(offset)
+            .and_then(|style| style.underline_color);
+        let message = |scopes: &Scopes, offset| scopes.diagnostic_at(offset)
+            .map(|diagnostic| diagnostic.message.clone());
+
+        // diagnostics are merged over the scopes' styles, and the most
+        // severe wins; squiggles keep the scopes' colors
+        let styles = check_resolved(&mut scopes);
+        let scope_styles = scopes.layers[&LayerId::from(PluginPid::new(1))].style_lookup.clone();
+        assert_eq!(styles[0], Some(scope_styles[0].merge(&Error.style())));
+        assert_eq!(styles[1], Some(Error.style()));
+        assert_eq!(fg(&mut scopes, 0), scope_styles[0].fg_color);
+        assert!(fg(&mut scopes, 0).is_some());
+        assert_eq!(styles[0].as_ref().unwrap().underline_style, Some(UnderlineStyle::Squiggle));
+        assert_eq!(underline(&mut scopes, 2), Error.style().underline_color);
+        assert_eq!(fg(&mut scopes, 2), scope_styles[1].fg_color);
+        assert_eq!(underline(&mut scopes, 4), Warning.style().underline_color);
+        assert_eq!(fg(&mut scopes, 10), Hint.style().fg_color);
+        assert_eq!(scopes.style_at(13), None);
+        assert_eq!(message(&scopes, 2), Some("mismatched".to_owned()));
+        assert_eq!(message(&scopes, 3), Some("unused".to_owned()));
+        assert_eq!(message(&scopes, 5), None);
+
+        // they move with edits, and are dropped with their plugin
+        scopes.update_all(Interval::new_closed_open(0, 0), 2);
+        assert_eq!(message(&scopes, 1), None);
+        assert_eq!(message(&scopes, 4), Some("mismatched".to_owned()));
+        scopes.update_diagnostic_layer(server, Interval::new_closed_open(0, 10),
+                                       diagnostics(10, &[]));
+        assert_eq!(message(&scopes, 4), Some("unused".to_owned()));
+        assert_eq!(message(&scopes, 12), Some("elided".to_owned()));
+        assert!(scopes.remove_layer(server).is_none());
+        assert_eq!(message(&scopes, 12), None);
+        check_resolved(&mut scopes);
+        assert_eq!(underline(&mut scopes, 4), Warning.style().underline_color);
+        let style = scopes.layers[&LayerId::from(PluginPid::new(1))].style_spans
+            .span_at(12).map(|(_, style)| style.clone());
+        assert_eq!(scopes.style_at(12), style);
+    }
+
+    #[test]
+    fn disabled_layers() {
+        let doc_ctx = mock_doc_ctx("layers_disabled_layers");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let without = check_resolved(&mut scopes);
+        let spell = PluginPid::new(2);
+        scope_layer(&mut scopes, spell, "comment.line", 20, Some(1), &doc_ctx);
+        let with = check_resolved(&mut scopes);
+        assert!(with != without);
+
+        assert!(scopes.set_layer_enabled(spell, false));
+        assert!(!scopes.set_layer_enabled(spell, false));
+        assert!(!scopes.set_layer_enabled(PluginPid::new(3), false));
+        assert_eq!(check_resolved(&mut scopes), without);
+        // a disabled layer is still updated, and restyled for a new theme
+        let mut sb = SpansBuilder::new(5);
+        sb.add_span(Interval::new_closed_open(0, 5), 0);
+        scopes.update_layer(spell, Interval::new_closed_open(0, 5), sb.build(), None, None)
+            .unwrap();
+        scopes.theme_diff_update(&doc_ctx);
+        restyle_all(&mut scopes);
+        assert_eq!(check_resolved(&mut scopes), without);
+        assert_eq!(scopes.layer_spans(Interval::new_closed_open(0, 20))[1].enabled, false);
+
+        assert!(scopes.set_layer_enabled(spell, true));
+        assert_eq!(check_resolved(&mut scopes), with);
+    }
+
+    #[test]
+    fn style_layers() {
+        let doc_ctx = mock_doc_ctx("layers_style_layers");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let without = check_resolved(&mut scopes);
+        let lint = PluginPid::new(2);
+        let red = Style::new(200, Some(0xFFFF_0000), None, None, None, None);
+        let mut sb = SpansBuilder::new(20);
+        sb.add_span(Interval::new_closed_open(0, 4), red.clone());
+        scopes.update_style_layer(lint, Interval::new_closed_open(0, 20), sb.build()).unwrap();
+        let with = check_resolved(&mut scopes);
+        assert_eq!(with[0].as_ref().unwrap().fg_color, Some(0xFFFF_0000));
+        assert_eq!(&with[4..], &without[4..]);
+
+        // it's merged in order of priority, edited, and kept for a new theme
+        assert!(scopes.set_layer_priority(PluginPid::new(1), 1));
+        let raised = check_resolved(&mut scopes);
+        assert_eq!(raised[0], without[0]);
+        assert_eq!(raised[1], Some(red.clone()));
+        assert!(scopes.set_layer_priority(lint, 2));
+        scopes.update_all(Interval::new_closed_open(0, 0), 2);
+        scopes.theme_diff_update(&doc_ctx);
+        restyle_all(&mut scopes);
+        let styles = check_resolved(&mut scopes);
+        assert_eq!(styles[2].as_ref().unwrap().fg_color, Some(0xFFFF_0000));
+        assert_eq!(styles[6], without[4]);
+
+        assert!(scopes.set_layer_enabled(lint, false));
+        assert!(scopes.set_layer_enabled(lint, true));
+        assert_eq!(check_resolved(&mut scopes), styles);
+        assert!(scopes.remove_layer(lint).is_none());
+        assert_eq!(check_resolved(&mut scopes)[2], without[0]);
+    }
+
+    #[test]
+    fn detached_layers() {
+        let doc_ctx = mock_doc_ctx("layers_detached_layers");
+        let (old, new) = (PluginPid::new(1), PluginPid::new(2));
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let all = Interval::new_closed_open(0, 20);
+        let errors = diagnostics(20, &[(0, 3, DiagnosticSeverity::Error, "mismatched")]);
+        scopes.update_diagnostic_layer(old, all, errors);
+        let styled = check_resolved(&mut scopes);
+        assert!(!scopes.detach_layer(new));
+        assert!(scopes.detach_layer(old));
+        // the styles are kept, and moved by edits, but not the diagnostics
+        assert!(scopes.diagnostic_at(0).is_none());
+        scopes.update_all(Interval::new_closed_open(0, 0), 2);
+        let detached = check_resolved(&mut scopes);
+        assert_eq!(&detached[6..], &styled[4..]);
+
+        // the restarted plugin adds its scopes again, replacing the styles
+        assert!(!scopes.adopt_layer(new, old));
+        assert!(scopes.adopt_layer(old, new));
+        assert!(scopes.layers.get(&LayerId::from(old)).is_none());
+        assert_eq!(check_resolved(&mut scopes), detached);
+        assert_eq!(scopes.layer_usage(new), (0, 0));
+        scope_layer(&mut scopes, new, "comment.line", 22, None, &doc_ctx);
+        assert_eq!(scopes.layer_usage(new), (1, 1));
+        let replaced = check_resolved(&mut scopes);
+        assert!(replaced.iter().all(|style| *style == replaced[0]));
+
+        // a layer which isn't adopted is dropped after enough edits
+        assert!(scopes.detach_layer(new));
+        for _ in 0..MAX_DETACHED_EDITS {
+            scopes.update_all(Interval::new_closed_open(0, 0), 1);
+        }
+        assert!(scopes.layers.contains_key(&LayerId::from(new)));
+        scopes.update_all(Interval::new_closed_open(0, 0), 1);
+        assert!(scopes.layers.is_empty());
+        assert!(!scopes.adopt_layer(new, old));
+        assert!(check_resolved(&mut scopes).iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn core_layers() {
+        let doc_ctx = mock_doc_ctx("layers_core_layers");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let plain = check_resolved(&mut scopes);
+        let theme = doc_ctx.get_theme();
+        let core_styles = CoreStyles::for_theme(&theme);
+        let color = core_styles.selection_color.unwrap();
+        let alpha = if color >> 24 == 0xFF { SELECTION_ALPHA } else { (color >> 24) as u8 };
+        let tinted = |style: Option<Style>, alpha| {
+            let style = style.unwrap_or_default();
+            Some(Style { bg_color: Some(color), ..style.blend_fg(color, alpha) })
+        };
+        let find_highlight = Style::find_highlight_for_theme(&theme, FIND_HIGHLIGHT_PRIORITY);
+
+        let mut overlay = Overlay::default();
+        let mut sb = SpansBuilder::new(20);
+        sb.add_span(Interval::new_closed_open(2, 5), SelectionKind::Secondary);
+        sb.add_span(Interval::new_closed_open(8, 9), SelectionKind::Primary);
+        overlay.set_selection_spans(sb.build());
+        overlay.add_span(CoreLayer::FindHighlights, Interval::new_closed_open(8, 12),
+                         SelectionKind::Primary);
+        let styles = overlay.apply(scopes.get_merged().clone(), &core_styles);
+        let styles = (0..20)
+            .map(|offset| styles.span_at(offset).map(|(_, style)| style.to_owned()))
+            .collect::<Vec<_>>();
+        assert_eq!(styles[2], tinted(plain[2].clone(), alpha / 2));
+        // unstyled text which is selected is given the selection background
+        assert_eq!(plain[3], None);
+        assert_eq!(styles[3], tinted(None, alpha / 2));
+        assert_eq!(styles[0], plain[0]);
+        assert_eq!(styles[6], plain[6]);
+        // selected text is tinted over the find highlights, which are also
+        // merged where the text is unstyled
+        let highlighted = plain[8].as_ref().unwrap().merge(&find_highlight);
+        assert_eq!(styles[8], tinted(Some(highlighted), alpha));
+        assert_eq!(styles[9], Some(find_highlight));
+        assert_eq!(styles[12], plain[12]);
+        // while the document's styles are left as they were
+        assert_eq!(check_resolved(&mut scopes), plain);
+        assert!(LayerId::Plugin(PluginPid::new(1)) < LayerId::Core(CoreLayer::FindHighlights));
+        assert!(CoreLayer::FindHighlights < CoreLayer::Selection);
+        // the ids of layers are serialized as a plugin's pid, or core's name
+        assert_eq!(json!(LayerId::from(PluginPid::new(1))), json!(1));
+        assert_eq!(json!(LayerId::Core(CoreLayer::FindHighlights)), json!("find_highlights"));
+    }
+
+    fn annotation(offset: usize, text: &str) -> Annotation {
+        Annotation { offset: offset, text: text.to_owned(), style: Style::default() }
+    }
+
+    #[test]
+    fn annotation_layers() {
+        let doc_ctx = mock_doc_ctx("layers_annotation_layers");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let (types, params) = (PluginPid::new(2), PluginPid::new(3));
+        scopes.update_annotation_layer(params, vec![annotation(5, "n:")]);
+        scopes.update_annotation_layer(types, vec![
+            annotation(12, ": u8"), annotation(2, ": i32"), annotation(5, ": bool"),
+        ]);
+        let texts = |scopes: &Scopes, start: usize, end: usize| {
+            scopes.annotations_in(Interval::new_closed_open(start, end))
+                .map(|annotation| (annotation.offset, annotation.text.clone()))
+                .collect::<Vec<_>>()
+        };
+        let at = |offset: usize, text: &str| (offset, text.to_owned());
+        assert_eq!(texts(&scopes, 0, 20),
+                   vec![at(2, ": i32"), at(5, ": bool"), at(5, "n:"), at(12, ": u8")]);
+        assert_eq!(texts(&scopes, 3, 12), vec![at(5, ": bool"), at(5, "n:")]);
+
+        // those at an insertion stay before it, and those deleted are dropped
+        scopes.update_all(Interval::new_closed_open(5, 5), 3);
+        assert_eq!(texts(&scopes, 0, 20),
+                   vec![at(2, ": i32"), at(5, ": bool"), at(5, "n:"), at(15, ": u8")]);
+        scopes.update_all(Interval::new_closed_open(1, 5), 0);
+        assert_eq!(texts(&scopes, 0, 20), vec![at(1, ": bool"), at(1, "n:"), at(11, ": u8")]);
+        scopes.update_all(Interval::new_closed_open(0, 2), 1);
+        assert_eq!(texts(&scopes, 0, 20), vec![at(10, ": u8")]);
+
+        scopes.update_annotation_layer(types, Vec::new());
+        assert!(texts(&scopes, 0, 20).is_empty());
+        scopes.update_annotation_layer(params, vec![annotation(0, "x:")]);
+        scopes.remove_layer(params);
+        assert!(texts(&scopes, 0, 20).is_empty());
+    }
+
+    #[test]
+    fn snapshots() {
+        let doc_ctx = mock_doc_ctx("layers_snapshots");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let snapshot = scopes.snapshot();
+        assert_eq!(snapshot.current_version(), scopes.current_version());
+        assert_eq!(snapshot.get_merged().len(), 20);
+
+        // the snapshot is kept as the styles change
+        let all = Interval::new_closed_open(0, 20);
+        let errors = diagnostics(20, &[(0, 3, DiagnosticSeverity::Error, "mismatched")]);
+        scopes.update_diagnostic_layer(PluginPid::new(2), all, errors);
+        scopes.update_all(Interval::new_closed_open(0, 0), 2);
+        assert!(scopes.current_version() > snapshot.current_version());
+        assert_eq!(snapshot.get_merged().len(), 20);
+        assert!(snapshot.diagnostic_at(0).is_none());
+        let snapshot = scopes.snapshot();
+        assert_eq!(snapshot.get_merged().len(), 22);
+        assert_eq!(snapshot.diagnostic_at(2), scopes.diagnostic_at(2));
+        assert!(snapshot.diagnostic_at(2).is_some());
+
+        // nothing changes the version which doesn't change the styles
+        scopes.begin_update();
+        scopes.commit_update();
+        scopes.layer_spans(Interval::new_closed_open(0, 22));
+        assert_eq!(snapshot.current_version(), scopes.current_version());
+    }
+
+    #[test]
+    fn scope_selectors() {
+        let doc_ctx = mock_doc_ctx("layers_scope_selectors");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let comments = PluginPid::new(2);
+        scope_layer(&mut scopes, comments, "comment.block", 20, None, &doc_ctx);
+        let find = |selector: &str, start: usize, end: usize| {
+            scopes.find_by_selector(selector, Interval::new_closed_open(start, end)).unwrap()
+                .into_iter()
+                .map(|(iv, id)| (iv.start(), iv.end(), id.plugin().unwrap()))
+                .collect::<Vec<_>>()
+        };
+        let (rust, meta_3) = (PluginPid::new(1), (6, 7, PluginPid::new(1)));
+        assert_eq!(find("meta.block.3", 0, 20), vec![meta_3]);
+        assert_eq!(find("source.rust meta.block.3", 0, 20), vec![meta_3]);
+        assert_eq!(find("comment", 0, 20), vec![(0, 20, comments), meta_3]);
+        assert_eq!(find("comment - comment.block", 0, 20), vec![meta_3]);
+        assert_eq!(find("meta.block.3, comment.block", 5, 10),
+                   vec![(5, 10, comments), meta_3]);
+        assert_eq!(find("source - meta.block.3", 0, 20).len(), 9);
+        assert_eq!(find("source, comment", 4, 8),
+                   vec![(4, 5, rust), (4, 8, comments), (6, 7, rust)]);
+        assert_eq!(find("string", 0, 20), vec![(4, 5, rust), (18, 19, rust)]);
+        assert!(find("markup", 0, 20).is_empty());
+        let all = Interval::new_closed_open(0, 20);
+        assert!(scopes.find_by_selector("a.b.c.d.e.f.g.h.i", all).is_err());
+    }
+
+    #[test]
+    fn scopes_at() {
+        let doc_ctx = mock_doc_ctx("layers_scopes_at");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let (rust, markup) = (PluginPid::new(1), PluginPid::new(2));
+        let stacks = vec![vec!["markup.bold".to_owned()], vec!["markup.italic".to_owned()]];
+        scopes.add_scopes(markup, stacks, &doc_ctx).unwrap();
+        let mut sb = SpansBuilder::new(20);
+        sb.add_span(Interval::new_closed_open(0, 6), 0);
+        sb.add_span(Interval::new_closed_open(6, 20), 1);
+        scopes.update_layer(markup, Interval::new_closed_open(0, 20), sb.build(),
+                            Some(u32::max_value()), None).unwrap();
+        fn at(scopes: &Scopes, offset: usize) -> Vec<(PluginPid, String)> {
+            scopes.scopes_at(offset).into_iter()
+                .map(|(id, names)| (id.plugin().unwrap(), names.last().unwrap().to_owned()))
+                .collect()
+        }
+        // the span starting at a boundary is the one there
+        assert_eq!(at(&scopes, 6), vec![(rust, "comment.line".to_owned()),
+                               (markup, "markup.italic".to_owned())]);
+        assert_eq!(at(&scopes, 5), vec![(markup, "markup.bold".to_owned())]);
+
+        // the layers are in order of priority
+        scopes.set_layer_priority(markup, 0);
+        scopes.set_layer_priority(rust, 1);
+        assert_eq!(at(&scopes, 6)[0], (markup, "markup.italic".to_owned()));
+        assert!(scopes.scopes_at(20).is_empty());
+        assert!(scopes.scopes_at(usize::max_value()).is_empty());
+    }
+
+    /// Returns the style of each offset, once the styles of all of `scopes`
+    /// have been resolved again from scratch, checking they're the same as
+    /// those resolved as it was updated.
+    /// Restyles all of the text left to restyle for a new theme at once.
+    fn restyle_all(scopes: &mut Scopes) {
+        while scopes.restyle_next(Interval::new_closed_open(0, 0), usize::max_value()).is_some() {
+        }
+    }
+
+    fn check_resolved(scopes: &mut Scopes) -> Vec<Option<Style>> {
+        let len = scopes.merged.len();
+        let styles = (0..len).map(|offset| scopes.style_at(offset)).collect::<Vec<_>>();
+        scopes.merged = SpansBuilder::new(len).build();
+        scopes.resolve_styles(Interval::new_closed_open(0, len));
+        assert_eq!(styles, (0..len).map(|offset| scopes.style_at(offset)).collect::<Vec<_>>());
+        styles
+    }
+
+    #[test]
+    fn delta_edits() {
+        use xi_rope::delta::Builder;
+
+        let doc_ctx = mock_doc_ctx("layers_delta_edits");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let mut builder = Builder::new(20);
+        builder.replace(Interval::new_closed_open(2, 3), Rope::from("ab"));
+        builder.delete(Interval::new_closed_open(10, 12));
+        builder.replace(Interval::new_closed_open(17, 17), Rope::from("c"));
+        scopes.apply_edit(&builder.build());
+        let plugin = PluginPid::new(1);
+        let blocks = (0..20)
+            .map(|offset| scopes.scope_names_at(plugin, offset).map(|names| names[1].clone()))
+            .collect::<Vec<_>>();
+        let block = |i: usize| Some(format!("meta.block.{}", i));
+        // the replaced and deleted spans are dropped, and the one before the
+        // insertion grows; those between the edits are kept
+        let mut expected = vec![None; 20];
+        for &(offset, i) in &[(0, 0), (5, 2), (7, 3), (9, 4), (11, 6), (13, 7), (15, 8), (18, 9)] {
+            expected[offset] = block(i);
+        }
+        expected[16] = block(8);
+        assert_eq!(blocks, expected);
+        let styles = check_resolved(&mut scopes);
+        assert!(styles[7].is_some() && styles[10].is_none());
+
+        // an edit which changes nothing leaves the spans as they are
+        let version = scopes.current_version();
+        scopes.apply_edit(&Builder::new(20).build());
+        assert_eq!(scopes.current_version(), version);
+    }
+
+    #[test]
+    fn incremental_resolve() {
+        let doc_ctx = mock_doc_ctx("layers_incremental_resolve");
+        let mut scopes = scopes_with_stacks(50, &doc_ctx);
+        let (plugin, other) = (PluginPid::new(1), PluginPid::new(2));
+        scope_layer(&mut scopes, other, "comment.line", 100, None, &doc_ctx);
+        let mut sb = SpansBuilder::new(100);
+        sb.add_span(Interval::new_closed_open(30, 60), 0);
+        scopes.update_layer(other, Interval::new_closed_open(0, 100), sb.build(), None, None)
+            .unwrap();
+        check_resolved(&mut scopes);
+
+        // a region is updated among spans which stay the same
+        let mut sb = SpansBuilder::new(40);
+        for i in 10..30 {
+            let stack = if i == 20 { 3 } else { i as u32 };
+            sb.add_span(Interval::new_closed_open(2 * i - 20, 2 * i - 19), stack);
+        }
+        scopes.update_layer(plugin, Interval::new_closed_open(20, 60), sb.build(), None, None)
+            .unwrap();
+        check_resolved(&mut scopes);
+
+        scopes.remove_layer(other);
+        let styles = check_resolved(&mut scopes);
+        assert_eq!(styles[1], None);
+        assert!(styles[40].is_some());
+        scopes.remove_layer(plugin);
+        assert!(check_resolved(&mut scopes).iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn changed_intervals_of_large_update() {
+        // a plugin sends the spans of the whole of a 1MB document again,
+        // with a 10 byte region changed
+        let len = 1 << 20;
+        let style = |priority| Style::new(priority, Some(priority as u32), None, None, None, None);
+        let spans = |changed: usize| {
+            let mut sb = SpansBuilder::new(len);
+            for i in 0..len / 10 {
+                let priority = if i == changed { 2 } else { 1 };
+                sb.add_span(Interval::new_closed_open(i * 10, i * 10 + 5), style(priority));
+            }
+            sb.build()
+        };
+        let changed = changed_intervals(&spans(len), &spans(5000));
+        assert_eq!(changed, vec![Interval::new_closed_open(50000, 50005)]);
+        assert!(changed_intervals(&spans(len), &spans(len)).is_empty());
+
+        let mut sb = SpansBuilder::new(20);
+        sb.add_span(Interval::new_closed_open(2, 4), style(1));
+        sb.add_span(Interval::new_closed_open(4, 8), style(2));
+        let old = sb.build();
+        let mut sb = SpansBuilder::new(20);
+        sb.add_span(Interval::new_closed_open(3, 8), style(2));
+        sb.add_span(Interval::new_closed_open(10, 12), style(1));
+        assert_eq!(changed_intervals(&old, &sb.build()),
+                   vec![Interval::new_closed_open(2, 4), Interval::new_closed_open(10, 12)]);
+    }
+
+    #[test]
+    fn lazy_resolve() {
+        let doc_ctx = mock_doc_ctx("layers_lazy_resolve");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let before = check_resolved(&mut scopes);
+        let version = scopes.current_version();
+        let lint = PluginPid::new(2);
+        scope_layer(&mut scopes, lint, "comment.line", 20, Some(1), &doc_ctx);
+        let mut sb = SpansBuilder::new(4);
+        sb.add_span(Interval::new_closed_open(0, 4), 0);
+        scopes.update_layer(lint, Interval::new_closed_open(12, 16), sb.build(), None, None)
+            .unwrap();
+        // nothing is resolved until the merged styles are read
+        assert_eq!(scopes.current_version(), version);
+        assert_eq!(scopes.dirty, Some(Interval::new_closed_open(0, 20)));
+        assert_eq!(scopes.merged.subseq(Interval::new_closed_open(0, 20)).iter().count(),
+                   before.iter().filter(|style| style.is_some()).count());
+
+        check_resolved(&mut scopes);
+        assert!(scopes.dirty.is_none());
+        assert!(scopes.current_version() > version);
+
+        // an edit moves what is left to resolve
+        let mut sb = SpansBuilder::new(4);
+        sb.add_span(Interval::new_closed_open(0, 4), Style::default());
+        scopes.update_direct(Interval::new_closed_open(12, 16), sb.build());
+        scopes.update_all(Interval::new_closed_open(2, 4), 5);
+        assert_eq!(scopes.dirty, Some(Interval::new_closed_open(2, 19)));
+        scopes.update_all(Interval::new_closed_open(10, 15), 0);
+        assert_eq!(scopes.dirty, Some(Interval::new_closed_open(2, 14)));
+        check_resolved(&mut scopes);
+    }
+
+    #[test]
+    fn batched_update() {
+        let doc_ctx = mock_doc_ctx("layers_batched_update");
+        let plugins = (1..6).map(PluginPid::new).collect::<Vec<_>>();
+        let scope_names = ["keyword.control", "string.quoted", "comment.line",
+                           "constant.numeric", "storage.type"];
+        let mut scopes = Scopes::default();
+        scopes.update_all(Interval::new_closed_open(0, 0), 100);
+        scopes.begin_update();
+        for (i, (&plugin, scope)) in plugins.iter().zip(scope_names.iter()).enumerate() {
+            scopes.add_scopes(plugin, vec![vec![scope.to_string()]], &doc_ctx).unwrap();
+            let mut sb = SpansBuilder::new(100);
+            sb.add_span(Interval::new_closed_open(i * 10, i * 10 + 20), 0);
+            scopes.update_layer(plugin, Interval::new_closed_open(0, 100), sb.build(), None, None)
+                .unwrap();
+        }
+        scopes.set_layer_priority(plugins[0], 10);
+        // nothing is resolved until the update is committed
+        assert_eq!(scopes.get_merged().iter().count(), 0);
+        assert_eq!(scopes.pending.as_ref().map(Vec::len), Some(6));
+        scopes.commit_update();
+        assert!(scopes.pending.is_none());
+        let styles = check_resolved(&mut scopes);
+        let first = &scopes.layers[&LayerId::from(plugins[0])];
+        assert_eq!(styles[5], first.style_lookup.get(0).cloned());
+        assert!(styles[55].is_some() && styles[60].is_none());
+
+        // edits within an update resolve what was left to resolve before it
+        scopes.begin_update();
+        scopes.remove_layer(plugins[4]);
+        scopes.update_all(Interval::new_closed_open(0, 10), 0);
+        scopes.commit_update();
+        assert!(check_resolved(&mut scopes)[40..].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn inspect_layer_spans() {
+        let doc_ctx = mock_doc_ctx("layers_inspect");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let (plugin, other) = (PluginPid::new(1), PluginPid::new(2));
+        scopes.add_scopes(other, vec![vec!["comment.line".to_owned()]], &doc_ctx).unwrap();
+        let mut sb = SpansBuilder::new(20);
+        sb.add_span(Interval::new_closed_open(15, 20), 0);
+        scopes.update_layer(other, Interval::new_closed_open(0, 20), sb.build(), None, None)
+            .unwrap();
+
+        let layers = scopes.layer_spans(Interval::new_closed_open(3, 7));
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].plugin, plugin.into());
+        let spans = layers[0].spans.iter()
+            .map(|span| (span.start, span.end, span.scopes[1].as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(spans, vec![(1, 2, "meta.block.2"), (3, 4, "meta.block.3")]);
+        let layer = &scopes.layers[&LayerId::from(plugin)];
+        assert_eq!(layers[0].spans[0].style, layer.style_lookup[2]);
+
+        scopes.set_layer_priority(plugin, 1);
+        let layers = serde_json::to_value(scopes.layer_spans(Interval::new_closed_open(14, 17)))
+            .unwrap();
+        assert_eq!(layers[0]["plugin"], json!(2));
+        assert_eq!(layers[0]["spans"][0]["scopes"], json!(["comment.line"]));
+        assert_eq!(layers[0]["spans"][0]["start"], json!(1));
+        assert_eq!(layers[1]["plugin"], json!(1));
+        assert_eq!(layers[1]["priority"], json!(1));
+    }
+
+    #[test]
+    fn edits_shift_spans() {
+        let doc_ctx = mock_doc_ctx("layers_edits_shift_spans");
+        // the stack i styles 2 * i
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let plugin = PluginPid::new(1);
+        let stack_at = |scopes: &Scopes, offset: usize| {
+            let layer = &scopes.layers[&LayerId::from(plugin)];
+            layer.scope_spans.span_at(offset).map(|(_, &stack)| stack)
+        };
+        assert_eq!(EditType::of(Interval::new_closed_open(3, 3), 2), EditType::Insert);
+
+        // inserting after a styled unit extends its span
+        scopes.update_all(Interval::new_closed_open(1, 1), 2);
+        assert_eq!((0..4).map(|i| stack_at(&scopes, i)).collect::<Vec<_>>(),
+                   vec![Some(0), Some(0), Some(0), None]);
+        assert_eq!(scopes.style_at(2), scopes.style_at(0));
+        assert!(scopes.style_at(2).is_some());
+        assert_eq!(scopes.layers[&LayerId::from(plugin)].scope_spans.span_at(0).unwrap().0,
+                   Interval::new_closed_open(0, 3));
+        // but not after an unstyled one
+        scopes.update_all(Interval::new_closed_open(4, 4), 1);
+        assert_eq!((3..7).map(|i| stack_at(&scopes, i)).collect::<Vec<_>>(),
+                   vec![None, None, Some(1), None]);
+        assert_eq!(scopes.style_at(4), None);
+
+        // deleting shrinks spans, and replacing leaves the new text unstyled
+        scopes.update_all(Interval::new_closed_open(1, 5), 0);
+        assert_eq!((0..3).map(|i| stack_at(&scopes, i)).collect::<Vec<_>>(),
+                   vec![Some(0), Some(1), None]);
+        scopes.update_all(Interval::new_closed_open(2, 4), 3);
+        assert_eq!((1..7).map(|i| stack_at(&scopes, i)).collect::<Vec<_>>(),
+                   vec![Some(1), None, None, None, None, Some(3)]);
+        assert_eq!(scopes.merged.len(), 20);
+        check_resolved(&mut scopes);
+    }
+
+    #[test]
+    fn edit_policies() {
+        let doc_ctx = mock_doc_ctx("layers_edit_policies");
+        let plugin = PluginPid::new(1);
+        // the layer has a span from 4 to 8, of 12 units
+        let spans_after_edit = |policy: EditPolicy, iv: Interval, len: usize| {
+            let mut scopes = Scopes::new(12);
+            scopes.set_edit_policy(plugin, policy);
+            scopes.add_scopes(plugin, vec![vec!["comment.line".to_owned()]], &doc_ctx).unwrap();
+            let mut sb = SpansBuilder::new(12);
+            sb.add_span(Interval::new_closed_open(4, 8), 0);
+            scopes.update_layer(plugin, Interval::new_closed_open(0, 12), sb.build(), None, None)
+                .unwrap();
+            scopes.update_all(iv, len);
+            check_resolved(&mut scopes);
+            assert_eq!(count_styles(&scopes.layers[&LayerId::from(plugin)].style_spans),
+                       scopes.layers[&LayerId::from(plugin)].scope_spans.iter().count());
+            scopes.layers[&LayerId::from(plugin)].scope_spans.iter()
+                .map(|(span_iv, _)| (span_iv.start(), span_iv.end()))
+                .collect::<Vec<_>>()
+        };
+        let insert = |policy: EditPolicy, offset: usize| {
+            spans_after_edit(policy, Interval::new_closed_open(offset, offset), 2)
+        };
+
+        for &policy in &[EditPolicy::Clear, EditPolicy::Shift, EditPolicy::Extend] {
+            assert_eq!(insert(policy, 2), vec![(6, 10)]);
+            assert_eq!(insert(policy, 10), vec![(4, 8)]);
+        }
+        // inside the span
+        assert_eq!(insert(EditPolicy::Clear, 6), vec![(4, 10)]);
+        assert_eq!(insert(EditPolicy::Shift, 6), vec![(4, 6), (8, 10)]);
+        assert_eq!(insert(EditPolicy::Extend, 6), vec![(4, 10)]);
+        // at its ends
+        assert_eq!(insert(EditPolicy::Clear, 4), vec![(6, 10)]);
+        assert_eq!(insert(EditPolicy::Shift, 4), vec![(6, 10)]);
+        assert_eq!(insert(EditPolicy::Extend, 4), vec![(4, 10)]);
+        assert_eq!(insert(EditPolicy::Clear, 8), vec![(4, 10)]);
+        assert_eq!(insert(EditPolicy::Shift, 8), vec![(4, 8)]);
+        assert_eq!(insert(EditPolicy::Extend, 8), vec![(4, 10)]);
+        // replacing its end
+        let replaced = Interval::new_closed_open(6, 9);
+        assert_eq!(spans_after_edit(EditPolicy::Clear, replaced, 1), vec![(4, 6)]);
+        assert_eq!(spans_after_edit(EditPolicy::Shift, replaced, 1), vec![(4, 6)]);
+        assert_eq!(spans_after_edit(EditPolicy::Extend, replaced, 1), vec![(4, 7)]);
+        assert_eq!(spans_after_edit(EditPolicy::Extend, replaced, 0), vec![(4, 6)]);
+    }
+
+    #[test]
+    fn span_budget() {
+        let doc_ctx = mock_doc_ctx("layers_span_budget");
+        // the stack i styles 2 * i, of 100 bytes
+        let mut scopes = scopes_with_stacks(50, &doc_ctx);
+        let plugin = PluginPid::new(1);
+        let styled = check_resolved(&mut scopes);
+        assert_eq!(scopes.retained_spans(), 100);
+        let window = Interval::new_closed_open(40, 60);
+        assert!(scopes.enforce_span_budget(100, &[window]).is_empty());
+
+        // the spans in the window are kept, and the rest dropped
+        let changed = scopes.enforce_span_budget(40, &[window]);
+        assert_eq!(changed, vec![Interval::new_closed_open(0, 40),
+                                 Interval::new_closed_open(60, 100)]);
+        assert!(scopes.retained_spans() <= 40);
+        let styles = check_resolved(&mut scopes);
+        assert_eq!(&styles[40..60], &styled[40..60]);
+        assert!(styles[..40].iter().chain(&styles[60..]).all(|style| style.is_none()));
+        // nor are core's layers' spans dropped
+        let syntax = LayerId::Core(CoreLayer::Syntax);
+        scope_layer(&mut scopes, syntax, "source.rust", 100, None, &doc_ctx);
+        assert!(scopes.enforce_span_budget(10, &[window]).is_empty());
+        scopes.remove_layer(syntax);
+
+        // the dropped regions move with edits, growing over text inserted
+        // at their ends, and are asked for again once they're in a window,
+        // which may be past the end of the document
+        scopes.update_all(Interval::new_closed_open(0, 0), 10);
+        let window = Interval::new_closed_open(0, 30);
+        assert_eq!(scopes.take_evicted(&[window, Interval::new_closed_open(90, 200)]),
+                   vec![(plugin, Interval::new_closed_open(0, 30)),
+                        (plugin, Interval::new_closed_open(90, 110))]);
+        assert!(scopes.take_evicted(&[window]).is_empty());
+        let mut sb = SpansBuilder::new(20);
+        for i in 0..10 {
+            sb.add_span(Interval::new_closed_open(2 * i, 2 * i + 1), i as u32);
+        }
+        scopes.update_layer(plugin, Interval::new_closed_open(10, 30), sb.build(), None, None)
+            .unwrap();
+        assert_eq!(&check_resolved(&mut scopes)[10..30], &styled[..20]);
+        // those the plugin sends spans for unasked aren't asked for
+        scopes.update_layer(plugin, Interval::new_closed_open(30, 50),
+                            SpansBuilder::new(20).build(), None, None).unwrap();
+        assert_eq!(scopes.take_evicted(&[Interval::new_closed_open(0, 110)]),
+                   vec![(plugin, Interval::new_closed_open(70, 90))]);
+    }
+
+    #[test]
+    fn layer_ops() {
+        let doc_ctx = mock_doc_ctx("layers_ops");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let plugin = PluginPid::new(1);
+        let stacks = |scopes: &Scopes| (0..20)
+            .map(|i| {
+                let layer = &scopes.layers[&LayerId::from(plugin)];
+                layer.scope_spans.span_at(i).map(|(_, &stack)| stack)
+            })
+            .collect::<Vec<_>>();
+        let before = stacks(&scopes);
+        let mut sb = SpansBuilder::new(3);
+        sb.add_span(Interval::new_closed_open(1, 3), 7);
+        let ops = vec![LayerOp::Retain(2), LayerOp::Skip(4), LayerOp::Retain(10),
+                       LayerOp::Insert(sb.build()), LayerOp::Retain(1)];
+        scopes.edit_layer(plugin, ops).unwrap();
+        let after = stacks(&scopes);
+        assert_eq!(&after[..2], &before[..2]);
+        assert!(after[2..6].iter().all(Option::is_none));
+        assert_eq!(&after[6..16], &before[6..16]);
+        assert_eq!(&after[16..19], &[None, Some(7), Some(7)]);
+        assert_eq!(after[19], before[19]);
+        check_resolved(&mut scopes);
+
+        // ops which don't cover the buffer are rejected, leaving it as it was
+        let err = scopes.edit_layer(plugin, vec![LayerOp::Skip(4), LayerOp::Retain(15)]);
+        assert_eq!(err, Err("span ops cover 19 bytes of 20".to_owned()));
+        assert_eq!(stacks(&scopes), after);
+    }
+
+    #[test]
+    fn invalidated_intervals() {
+        let doc_ctx = mock_doc_ctx("layers_invalidated");
+        let mut scopes = scopes_with_stacks(10, &doc_ctx);
+        let regions = |scopes: &mut Scopes| scopes.take_invalidated_intervals().iter()
+            .map(|iv| (iv.start(), iv.end()))
+            .collect::<Vec<_>>();
+        // the initial insertion of the text
+        assert_eq!(regions(&mut scopes), vec![(0, 20)]);
+
+        scopes.update_all(Interval::new_closed_open(2, 2), 3);
+        scopes.update_all(Interval::new_closed_open(12, 14), 0);
+        scopes.update_all(Interval::new_closed_open(16, 17), 1);
+        assert_eq!(regions(&mut scopes), vec![(2, 5), (12, 12), (16, 17)]);
+        assert!(regions(&mut scopes).is_empty());
+
+        // regions move with later edits, and are joined as they meet
+        scopes.update_all(Interval::new_closed_open(10, 11), 2);
+        scopes.update_all(Interval::new_closed_open(2, 4), 0);
+        scopes.update_all(Interval::new_closed_open(2, 2), 1);
+        assert_eq!(regions(&mut scopes), vec![(2, 3), (9, 11)]);
+        scopes.update_all(Interval::new_closed_open(4, 5), 0);
+        scopes.update_all(Interval::new_closed_open(6, 7), 2);
+        scopes.update_all(Interval::new_closed_open(3, 9), 5);
+        assert_eq!(regions(&mut scopes), vec![(3, 8)]);
+
+        // a removed layer's styles are invalidated
+        scopes.remove_layer(PluginPid::new(1));
+        assert_eq!(regions(&mut scopes),
+                   vec![(0, 1), (10, 11), (12, 13), (14, 15), (16, 17), (18, 19)]);
+    }
+
+    #[test]
+    fn layer_priority() {
+        let doc_ctx = mock_doc_ctx("layers_priority");
+        let (keyword, string) = (PluginPid::new(1), PluginPid::new(2));
+        let mut scopes = Scopes::default();
+        scopes.update_all(Interval::new_closed_open(0, 0), 10);
+        scope_layer(&mut scopes, keyword, "keyword.control", 10, None, &doc_ctx);
+        scope_layer(&mut scopes, string, "string.quoted", 10, None, &doc_ctx);
+        let keyword_fg = scopes.layers[&LayerId::from(keyword)].style_lookup[0].fg_color;
+        let string_fg = scopes.layers[&LayerId::from(string)].style_lookup[0].fg_color;
+        assert!(keyword_fg.is_some() && string_fg.is_some() && keyword_fg != string_fg);
+        // with equal priorities, the later plugin wins
+        assert_eq!(fg_colors(&mut scopes), vec![string_fg]);
+
+        scopes.set_layer_priority(keyword, 10);
+        assert_eq!(fg_colors(&mut scopes), vec![keyword_fg]);
+        // a hint with the update changes the order too
+        scope_layer(&mut scopes, string, "string.quoted", 10, Some(20), &doc_ctx);
+        assert_eq!(fg_colors(&mut scopes), vec![string_fg]);
+        scopes.set_layer_priority(string, 0);
+        assert_eq!(fg_colors(&mut scopes), vec![keyword_fg]);
+
+        // the order holds as layers are updated, and once they're removed
+        scopes.update_all(Interval::new_closed_open(5, 6), 5);
+        let mut sb = SpansBuilder::new(5);
+        sb.add_span(Interval::new_closed_open(0, 5), 0);
+        scopes.update_layer(string, Interval::new_closed_open(5, 10), sb.build(), None, None)
+            .unwrap();
+        assert_eq!(fg_colors(&mut scopes), vec![keyword_fg, string_fg, keyword_fg]);
+        scopes.remove_layer(keyword);
+        assert!(fg_colors(&mut scopes).iter().all(|&fg| fg == string_fg));
+    }
+
+    #[test]
+    fn theme_diffs() {
+        let doc_ctx = mock_doc_ctx("layers_theme_diffs");
+        let mut scopes = scopes_with_stacks(100, &doc_ctx);
+        let styles = check_resolved(&mut scopes);
+        // nothing is restyled for the same theme
+        scopes.theme_diff_update(&doc_ctx);
+        assert!(!scopes.is_restyling());
+
+        // but the spans of the stacks whose style changed are
+        {
+            let layer = scopes.layers.get_mut(&LayerId::from(PluginPid::new(1))).unwrap();
+            layer.style_lookup[3] = Style::default();
+            layer.style_lookup[4] = Style::default();
+            layer.style_lookup[50] = Style::default();
+        }
+        scopes.theme_diff_update(&doc_ctx);
+        assert_eq!(scopes.restyling, vec![Interval::new_closed_open(6, 7),
+                                          Interval::new_closed_open(8, 9),
+                                          Interval::new_closed_open(100, 101)]);
+        // several of them at a time, as they fit in a chunk
+        assert_eq!(scopes.restyle_next(Interval::new_closed_open(0, 0), 50),
+                   Some(Interval::new_closed_open(6, 9)));
+        assert_eq!(scopes.restyle_next(Interval::new_closed_open(0, 0), 50),
+                   Some(Interval::new_closed_open(100, 101)));
+        assert_eq!(check_resolved(&mut scopes), styles);
+
+        // and all of those of a layer whose styles weren't its scopes'
+        let bytes = scopes.serialize_layer(PluginPid::new(1)).unwrap();
+        assert!(scopes.restore_layer(PluginPid::new(2), &bytes).is_ok());
+        scopes.theme_diff_update(&doc_ctx);
+        assert_eq!(scopes.restyling, vec![Interval::new_closed_open(0, 200)]);
+        restyle_all(&mut scopes);
+        assert_eq!(count_styles(&scopes.layers[&LayerId::from(PluginPid::new(2))].style_spans), 0);
+    }
+
+    #[test]
+    fn gaps_between_spans() {
+        let doc_ctx = mock_doc_ctx("layers_gaps_between_spans");
+        let mut scopes = Scopes::new(30);
+        let (first, second) = (PluginPid::new(1), PluginPid::new(2));
+        let layer = |scopes: &mut Scopes, plugin, scope: &str, ivs: &[(usize, usize)]| {
+            scopes.add_scopes(plugin, vec![vec![scope.to_owned()]], &doc_ctx).unwrap();
+            let mut sb = SpansBuilder::new(30);
+            for &(start, end) in ivs {
+                sb.add_span(Interval::new_closed_open(start, end), 0);
+            }
+            scopes.update_layer(plugin, Interval::new_closed_open(0, 30), sb.build(), None, None)
+                .unwrap();
+        };
+        layer(&mut scopes, first, "comment.line", &[(0, 10)]);
+        layer(&mut scopes, second, "string.quoted", &[(5, 20)]);
+        let comment = scopes.layers[&LayerId::from(first)].style_lookup[0].clone();
+        let string = scopes.layers[&LayerId::from(second)].style_lookup[0].clone();
+        assert_ne!(comment.fg_color, string.fg_color);
+
+        // the first layer's style doesn't bleed into its gaps, where only
+        // the second has a span, and the text neither covers is unstyled
+        let styles = check_resolved(&mut scopes);
+        assert_eq!(styles[0], Some(comment.clone()));
+        assert_eq!(styles[7], Some(comment.merge(&string)));
+        assert_eq!(styles[15], Some(string.clone()));
+        assert_eq!(styles[25], None);
+
+        // the gaps are filled with the default style, which gives way to
+        // any other whatever the order of the layers
+        let gaps = scopes.layers[&LayerId::from(first)].style_spans.iter()
+            .filter(|&(_, style)| style.is_default)
+            .map(|(iv, _)| iv)
+            .collect::<Vec<_>>();
+        assert_eq!(gaps, vec![Interval::new_closed_open(10, 30)]);
+        assert!(scopes.set_layer_priority(first, 1000));
+        let reordered = check_resolved(&mut scopes);
+        assert_eq!(reordered[15], Some(string));
+        assert_eq!(reordered[25], None);
+    }
+
+    #[test]
+    fn user_overrides() {
+        let doc_ctx = mock_doc_ctx("layers_user_overrides");
+        let mut scopes = Scopes::new(20);
+        let (comment, string) = (PluginPid::new(1), PluginPid::new(2));
+        scope_layer(&mut scopes, comment, "comment.line.double-slash.rust", 10, None, &doc_ctx);
+        let mut sb = SpansBuilder::new(10);
+        sb.add_span(Interval::new_closed_open(0, 10), 0);
+        scopes.add_scopes(string, vec![vec!["string.quoted".to_owned()]], &doc_ctx).unwrap();
+        scopes.update_layer(string, Interval::new_closed_open(10, 20), sb.build(), None, None)
+            .unwrap();
+        let styles = check_resolved(&mut scopes);
+
+        let struck = StyleDelta { strikethrough: Some(true), ..StyleDelta::default() };
+        scopes.add_user_override("comment.line", struck, &doc_ctx).unwrap();
+        assert_eq!(scopes.restyling, vec![Interval::new_closed_open(0, 10)]);
+        restyle_all(&mut scopes);
+        let overridden = check_resolved(&mut scopes);
+        let comment_style = overridden[0].clone().unwrap();
+        assert_eq!(comment_style.strikethrough, Some(true));
+        assert_eq!(comment_style.fg_color, styles[0].as_ref().unwrap().fg_color);
+        assert_eq!(&overridden[10..], &styles[10..]);
+
+        // later overrides win, also for scopes added after them
+        let red = StyleDelta { fg_color: Some(0xFFFF_0000), ..StyleDelta::default() };
+        scopes.add_user_override("comment, string", red, &doc_ctx).unwrap();
+        restyle_all(&mut scopes);
+        scope_layer(&mut scopes, PluginPid::new(3), "comment.block", 20, Some(1), &doc_ctx);
+        let styles = check_resolved(&mut scopes);
+        assert_eq!(styles[15].as_ref().unwrap().fg_color, Some(0xFFFF_0000));
+        assert_eq!(styles[15].as_ref().unwrap().strikethrough, None);
+        let none = StyleDelta::default();
+        assert!(scopes.add_user_override("a.b.c.d.e.f.g.h.i", none, &doc_ctx).is_err());
+
+        // and are applied on top of a new theme
+        doc_ctx.get_style_map().lock().unwrap().set_theme("Solarized (dark)").unwrap();
+        scopes.theme_diff_update(&doc_ctx);
+        restyle_all(&mut scopes);
+        let styles = check_resolved(&mut scopes);
+        assert!(styles.iter().all(|style| style.as_ref().unwrap().fg_color == Some(0xFFFF_0000)));
+    }
+
+    #[test]
+    fn remove_spans_for_range() {
+        let doc_ctx = mock_doc_ctx("layers_remove_spans_for_range");
+        let mut scopes = Scopes::new(20);
+        let (syntax, semantic) = (PluginPid::new(1), PluginPid::new(2));
+        scope_layer(&mut scopes, syntax, "comment.line", 20, None, &doc_ctx);
+        scope_layer(&mut scopes, semantic, "string.quoted", 20, Some(1), &doc_ctx);
+        let comment = scopes.layers[&LayerId::from(syntax)].style_lookup[0].clone();
+        let merged = check_resolved(&mut scopes)[0].clone();
+
+        scopes.remove_spans_for_range(semantic, Interval::new_closed_open(5, 10)).unwrap();
+        restyle_all(&mut scopes);
+        let styles = check_resolved(&mut scopes);
+        assert_eq!(styles[4], merged);
+        assert_eq!(styles[5], Some(comment));
+        assert_eq!(styles[10], merged);
+        assert_eq!(scopes.layers[&LayerId::from(syntax)].scope_spans.iter().count(), 1);
+        assert_eq!(scopes.layers[&LayerId::from(semantic)].scope_spans.iter().count(), 2);
+
+        // clearing it again, or a layer which doesn't exist, changes nothing
+        scopes.remove_spans_for_range(semantic, Interval::new_closed_open(5, 10)).unwrap();
+        scopes.remove_spans_for_range(PluginPid::new(3), Interval::new_closed_open(0, 20))
+            .unwrap();
+        restyle_all(&mut scopes);
+        assert_eq!(check_resolved(&mut scopes), styles);
+        assert!(!scopes.layers.contains_key(&LayerId::from(PluginPid::new(3))));
+        let past_end = Interval::new_closed_open(15, 25);
+        assert!(scopes.remove_spans_for_range(semantic, past_end).is_err());
+    }
+
+    #[test]
+    fn compact_layer() {
+        let doc_ctx = mock_doc_ctx("layers_compact_layer");
+        let mut scopes = Scopes::new(20);
+        let plugin = PluginPid::new(1);
+        let table = || vec![vec!["comment.line".to_owned()], vec!["string.quoted".to_owned()],
+                            vec!["keyword.control".to_owned()]];
+        let update = |scopes: &mut Scopes, start: usize, end: usize, scope: u32| {
+            let mut sb = SpansBuilder::new(end - start);
+            sb.add_span(Interval::new_closed_open(0, end - start), scope);
+            let iv = Interval::new_closed_open(start, end);
+            scopes.update_layer(plugin, iv, sb.build(), None, None).unwrap();
+        };
+        // a table sent again shares the entries of the first
+        for _ in 0..3 {
+            scopes.add_scopes(plugin, table(), &doc_ctx).unwrap();
+        }
+        update(&mut scopes, 0, 10, 6);
+        update(&mut scopes, 10, 20, 7);
+        let stats = scopes.layers[&LayerId::from(plugin)].stats(plugin.into());
+        assert_eq!((stats.stacks, stats.ids, stats.referenced_stacks), (3, 9, 2));
+        assert_eq!(scopes.layer_usage(plugin).0, 9);
+        let styles = check_resolved(&mut scopes);
+        assert!(styles[0] != styles[10]);
+
+        assert_eq!(scopes.compact_layer(plugin), 1);
+        assert_eq!(scopes.compact_layer(plugin), 0);
+        assert_eq!(scopes.layers[&LayerId::from(plugin)].stack_lookup.len(), 2);
+        assert_eq!(check_resolved(&mut scopes), styles);
+        // the ids of the dropped stack are retired, and the others kept
+        update(&mut scopes, 0, 5, 8);
+        update(&mut scopes, 5, 10, 1);
+        let compacted = check_resolved(&mut scopes);
+        assert_eq!(compacted[0], None);
+        assert_eq!(compacted[5], styles[10]);
+
+        scopes.add_scopes(plugin, table(), &doc_ctx).unwrap();
+        assert_eq!(scopes.layers[&LayerId::from(plugin)].scope_ids[9..], [0, 1, 2]);
+        update(&mut scopes, 0, 5, 11);
+        assert!(check_resolved(&mut scopes)[0].is_some());
+        assert_eq!(scopes.compact_layer(PluginPid::new(2)), 0);
+    }
+
+    #[test]
+    fn scope_names_for_index() {
+        let doc_ctx = mock_doc_ctx("layers_scope_names_for_index");
+        let mut scopes = Scopes::new(10);
+        let plugin = PluginPid::new(1);
+        let table = || vec![vec!["source.rust".to_owned(), "comment.line".to_owned()],
+                            vec!["source.rust".to_owned(), "string.quoted".to_owned()]];
+        scopes.add_scopes(plugin, table(), &doc_ctx).unwrap();
+        scopes.add_scopes(plugin, table(), &doc_ctx).unwrap();
+        // the stacks added again share the entries of the first
+        assert_eq!(scopes.layers[&LayerId::from(plugin)].name_lookup.len(), 2);
+        let mut sb = SpansBuilder::new(10);
+        sb.add_span(Interval::new_closed_open(2, 6), 3);
+        scopes.update_layer(plugin, Interval::new_closed_open(0, 10), sb.build(), None, None)
+            .unwrap();
+        let (_, &entry) = scopes.layers[&LayerId::from(plugin)].scope_spans.span_at(2).unwrap();
+        assert_eq!(entry, 1);
+        assert_eq!(scopes.scope_names_at_layer(plugin, entry),
+                   Some(&["source.rust".to_owned(), "string.quoted".to_owned()][..]));
+        assert_eq!(scopes.scope_names_at_layer(plugin, entry), scopes.scope_names_at(plugin, 2));
+        assert_eq!(scopes.layers[&LayerId::from(plugin)].scope_name_for_index(2), None);
+        assert_eq!(scopes.scope_names_at_layer(PluginPid::new(2), 0), None);
+    }
+
+    #[test]
+    fn merge_foreign() {
+        let doc_ctx = mock_doc_ctx("layers_merge_foreign");
+        let mut scopes = Scopes::new(30);
+        let (syntax, semantic) = (PluginPid::new(1), PluginPid::new(2));
+        scope_layer(&mut scopes, syntax, "comment.line", 30, None, &doc_ctx);
+        let comment = scopes.layers[&LayerId::from(syntax)].style_lookup[0].clone();
+
+        let mut other = Scopes::new(10);
+        other.add_scopes(syntax, vec![vec!["string.quoted".to_owned()]], &doc_ctx).unwrap();
+        let mut sb = SpansBuilder::new(10);
+        sb.add_span(Interval::new_closed_open(2, 5), 0);
+        other.update_layer(syntax, Interval::new_closed_open(0, 10), sb.build(), None, None)
+            .unwrap();
+        scope_layer(&mut other, semantic, "keyword.control", 10, Some(1), &doc_ctx);
+        let string = other.layers[&LayerId::from(syntax)].style_lookup[0].clone();
+        let keyword = other.layers[&LayerId::from(semantic)].style_lookup[0].clone();
+
+        scopes.merge_foreign(&other, 10).unwrap();
+        let styles = check_resolved(&mut scopes);
+        // the gaps of the foreign layer keep the spans of this one
+        assert_eq!(styles[9], Some(comment.clone()));
+        assert_eq!(styles[10], Some(comment.merge(&keyword)));
+        assert_eq!(styles[12], Some(string.merge(&keyword)));
+        assert_eq!(styles[20], Some(comment.clone()));
+        assert_eq!(scopes.layers[&LayerId::from(semantic)].priority, 1);
+        let names = scopes.scope_spans_in(syntax, Interval::new_closed_open(12, 13)).unwrap()
+            .map(|(_, stack)| stack.to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![vec!["string.quoted".to_owned()]]);
+
+        assert!(scopes.merge_foreign(&other, 25).is_err());
+        assert_eq!(check_resolved(&mut scopes), styles);
+    }
+
+    #[test]
+    fn search_highlights() {
+        let doc_ctx = mock_doc_ctx("layers_search_highlights");
+        let mut scopes = Scopes::new(20);
+        scope_layer(&mut scopes, PluginPid::new(1), "comment.line", 20, None, &doc_ctx);
+        let comment = check_resolved(&mut scopes)[0].clone().unwrap();
+        let found = Style { bg_color: Some(0xFFFF_FF00), ..Style::default() };
+        let iv = |start, end| Interval::new_closed_open(start, end);
+        scopes.set_search_highlights(vec![iv(10, 14), iv(2, 4), iv(12, 16), iv(18, 30)],
+                                     found.clone());
+        let styles = check_resolved(&mut scopes);
+        let highlighted = comment.merge(&found);
+        assert_eq!(styles[1], Some(comment.clone()));
+        assert_eq!(styles[3], Some(highlighted.clone()));
+        assert_eq!(styles[15], Some(highlighted.clone()));
+        assert_eq!(styles[16], Some(comment.clone()));
+        // the result past the end is dropped, and overlapping ones merged
+        assert_eq!(styles[19], Some(comment.clone()));
+        assert_eq!(scopes.search_layer.as_ref().unwrap().iter().count(), 2);
+
+        scopes.set_search_highlights(Vec::new(), found);
+        assert!(scopes.search_layer.is_none());
+        let styles = check_resolved(&mut scopes);
+        assert_eq!(styles[3], styles[0]);
+    }
+
+    #[test]
+    fn search_highlights_theme_change() {
+        let doc_ctx = mock_doc_ctx("layers_search_highlights_theme_change");
+        let mut scopes = Scopes::new(20);
+        scope_layer(&mut scopes, PluginPid::new(1), "comment.line", 20, None, &doc_ctx);
+        let found = Style { bg_color: Some(0xFFFF_FF00), ..Style::default() };
+        let iv = |start, end| Interval::new_closed_open(start, end);
+        scopes.set_search_highlights(vec![iv(2, 4), iv(10, 16)], found.clone());
+        check_resolved(&mut scopes);
+
+        // the highlights keep their style through a change of theme, and
+        // are moved by edits
+        doc_ctx.get_style_map().lock().unwrap().set_theme("Solarized (dark)").unwrap();
+        scopes.theme_diff_update(&doc_ctx);
+        restyle_all(&mut scopes);
+        scopes.update_all(iv(0, 1), 0);
+        let styles = check_resolved(&mut scopes);
+        assert_eq!(styles[2].as_ref().unwrap().bg_color, found.bg_color);
+        assert_eq!(styles[10].as_ref().unwrap().bg_color, found.bg_color);
+        assert!(styles[15].as_ref().unwrap().bg_color != found.bg_color);
+    }
+
+    #[test]
+    fn scope_modifiers() {
+        use styles::{MODIFIER_DEPRECATED, MODIFIER_READONLY};
+        let doc_ctx = mock_doc_ctx("layers_scope_modifiers");
+        let mut scopes = Scopes::new(20);
+        let plugin = PluginPid::new(1);
+        let stack = || vec!["variable.other".to_owned()];
+        // the second stack has only a bit which doesn't change its style
+        scopes.add_scopes_with_modifiers(plugin, vec![stack(), stack(), stack(), stack()],
+                                         vec![0, 8, MODIFIER_DEPRECATED, MODIFIER_READONLY],
+                                         &doc_ctx).unwrap();
+        let mut sb = SpansBuilder::new(20);
+        for i in 0..4 {
+            sb.add_span(Interval::new_closed_open(i * 5, i * 5 + 5), i as u32);
+        }
+        scopes.update_layer(plugin, Interval::new_closed_open(0, 20), sb.build(), None, None)
+            .unwrap();
+        let styles = check_resolved(&mut scopes);
+        let plain = styles[0].clone().unwrap();
+        assert_eq!(styles[5], Some(plain.clone()));
+        assert_eq!(styles[10], Some(Style { strikethrough: Some(true), ..plain.clone() }));
+        assert_eq!(styles[15], Some(Style { italic: Some(true), ..plain.clone() }));
+        // spans whose modifiers resolve to the same style make one style span
+        assert_eq!(scopes.layers[&LayerId::from(plugin)].style_spans.iter().count(), 3);
+
+        // the modifiers are kept through a change of theme
+        doc_ctx.get_style_map().lock().unwrap().set_theme("Solarized (dark)").unwrap();
+        scopes.theme_diff_update(&doc_ctx);
+        restyle_all(&mut scopes);
+        let styles = check_resolved(&mut scopes);
+        assert_eq!(styles[10].as_ref().unwrap().strikethrough, Some(true));
+        assert_eq!(styles[15].as_ref().unwrap().italic, Some(true));
+
+        // stacks past the end of the modifiers have none, but more
+        // modifiers than stacks are an error
+        scopes.add_scopes(plugin, vec![stack()], &doc_ctx).unwrap();
+        assert_eq!(scopes.layers[&LayerId::from(plugin)].scope_ids[4], 0);
+        let err = scopes.add_scopes_with_modifiers(plugin, vec![stack()], vec![1, 2], &doc_ctx);
+        assert_eq!(err, Err("2 modifiers for 1 stacks".to_owned()));
+        assert_eq!(scopes.layers[&LayerId::from(plugin)].scope_ids[5], 2);
+        assert_eq!(scopes.layers[&LayerId::from(plugin)].stack_lookup.len(), 4);
+    }
+
+    #[test]
+    fn composite_modes() {
+        let doc_ctx = mock_doc_ctx("layers_composite_modes");
+        let mut scopes = Scopes::new(20);
+        let (syntax, overlay) = (PluginPid::new(1), PluginPid::new(2));
+        scope_layer(&mut scopes, syntax, "keyword.control", 20, None, &doc_ctx);
+        scopes.add_scopes(overlay, vec![vec!["string.quoted".to_owned()]], &doc_ctx).unwrap();
+        let mut sb = SpansBuilder::new(20);
+        sb.add_span(Interval::new_closed_open(0, 10), 0);
+        let iv = Interval::new_closed_open(0, 20);
+        scopes.update_layer(overlay, iv, sb.build(), Some(1), None).unwrap();
+        let below = scopes.layers[&LayerId::from(syntax)].style_lookup[0].clone();
+        let style = scopes.layers[&LayerId::from(overlay)].style_lookup[0].clone();
+        assert_eq!(check_resolved(&mut scopes)[0], Some(below.merge(&style)));
+
+        // changing only the mode resolves the layer's styles again, and its
+        // gaps keep the styles below
+        let empty = SpansBuilder::new(0).build();
+        let at_end = Interval::new_closed_open(20, 20);
+        scopes.update_layer(overlay, at_end, empty.clone(), None, Some(CompositeMode::Replace))
+            .unwrap();
+        let styles = check_resolved(&mut scopes);
+        assert_eq!(styles[0], Some(style.clone()));
+        assert_eq!(styles[10], Some(below.clone()));
+
+        scopes.update_layer(overlay, at_end, empty, None, Some(CompositeMode::Tint)).unwrap();
+        let styles = check_resolved(&mut scopes);
+        let fg_color = match (below.fg_color, style.fg_color) {
+            (Some(fg), Some(tint)) => Some(multiply_colors(fg, tint)),
+            (fg, tint) => fg.or(tint),
+        };
+        assert_eq!(styles[0], Some(Style { fg_color: fg_color, ..below.clone() }));
+        assert_eq!(styles[10], Some(below));
+        assert_eq!(multiply_colors(0xFFFF_FFFF, 0xFF80_8080), 0xFF80_8080);
+        assert_eq!(multiply_colors(0x80FF_0000, 0xFF80_FF00), 0x8080_0000);
+    }
+
+    #[test]
+    fn restyling_in_chunks() {
+        let doc_ctx = mock_doc_ctx("layers_restyling_in_chunks");
+        let mut scopes = scopes_with_stacks(5000, &doc_ctx);
+        scope_layer(&mut scopes, PluginPid::new(2), "comment.line", 10_000, Some(1), &doc_ctx);
+        assert!(scopes.set_layer_enabled(PluginPid::new(2), false));
+        let old = check_resolved(&mut scopes);
+
+        doc_ctx.get_style_map().lock().unwrap().set_theme("Solarized (dark)").unwrap();
+        scopes.theme_diff_update(&doc_ctx);
+        assert!(scopes.is_restyling());
+        // the old styles are kept until they're restyled, the visible first
+        assert_eq!(check_resolved(&mut scopes), old);
+        let visible = Interval::new_closed_open(4000, 4100);
+        assert_eq!(scopes.restyle_next(visible, 1000), Some(visible));
+        let styles = check_resolved(&mut scopes);
+        assert!(styles[4000] != old[4000]);
+        assert_eq!(styles[4100], old[4100]);
+
+        // what's left is moved by edits made meanwhile
+        scopes.update_all(Interval::new_closed_open(100, 104), 3);
+        let mut chunks = Vec::new();
+        while let Some(chunk) = scopes.restyle_next(Interval::new_closed_open(0, 0), 1000) {
+            chunks.push(chunk);
         }
-        self.style_spans.edit(iv, sb.build());
+        assert!(!scopes.is_restyling());
+        assert_eq!(chunks.len(), 10);
+        assert_eq!(chunks[3], Interval::new_closed_open(3000, 3999));
+        assert_eq!(chunks[4], Interval::new_closed_open(4099, 5099));
+        let restyled = check_resolved(&mut scopes);
+
+        // and the result is that of resolving the styles all at once
+        let mut reference = scopes_with_stacks(5000, &doc_ctx);
+        scope_layer(&mut reference, PluginPid::new(2), "comment.line", 10_000, Some(1), &doc_ctx);
+        assert!(reference.set_layer_enabled(PluginPid::new(2), false));
+        reference.update_all(Interval::new_closed_open(100, 104), 3);
+        assert_eq!(restyled, check_resolved(&mut reference));
+        assert!(scopes.set_layer_enabled(PluginPid::new(2), true));
+        assert!(reference.set_layer_enabled(PluginPid::new(2), true));
+        assert_eq!(check_resolved(&mut scopes), check_resolved(&mut reference));
+    }
+
+    #[test]
+    fn theme_change_doesnt_block_other_documents() {
+        let doc_ctx = mock_doc_ctx("layers_theme_change");
+        let mut large = scopes_with_stacks(20_000, &doc_ctx);
+        let mut small = scopes_with_stacks(1, &doc_ctx);
+
+        let (started_tx, started_rx) = mpsc::channel();
+        let large_ctx = doc_ctx.clone();
+        let theme_change = thread::spawn(move || {
+            large_ctx.get_style_map().lock().unwrap().set_theme("Solarized (dark)").unwrap();
+            started_tx.send(()).unwrap();
+            let start = Instant::now();
+            large.theme_diff_update(&large_ctx);
+            restyle_all(&mut large);
+            (start.elapsed(), Instant::now())
+        });
+
+        started_rx.recv().unwrap();
+        // let the theme change get into computing styles
+        thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        small.add_scopes(PluginPid::new(1), vec![vec!["string.quoted".to_owned()]], &doc_ctx)
+            .unwrap();
+        let mut sb = SpansBuilder::new(2);
+        sb.add_span(Interval::new_closed_open(0, 1), 1);
+        small.update_layer(PluginPid::new(1), Interval::new_closed_open(0, 2), sb.build(), None,
+                           None)
+            .unwrap();
+        let update_time = start.elapsed();
+        let updated_at = Instant::now();
+
+        let (theme_change_time, changed_at) = theme_change.join().unwrap();
+        // the plugin update was made while the theme change was computing
+        // styles, rather than waiting for it to release the style map
+        assert!(updated_at < changed_at);
+        assert!(update_time * 10 < theme_change_time,
+                "update took {:?}, theme change {:?}", update_time, theme_change_time);
+        assert_eq!(small.layers[&LayerId::from(PluginPid::new(1))].style_lookup.len(), 2);
     }
 }
@@ -0,0 +1,99 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hints for laying out a view's gutters, derived from its content.
+
+/// What a front-end needs to know to size its gutters. This is sent in an
+/// update whenever it changes.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct LayoutHints {
+    /// The number of digits to leave room for in line numbers.
+    pub line_number_digits: usize,
+    /// Whether any line in the viewport has a find match or pending
+    /// cleanup, which may be marked in the gutter.
+    pub has_annotations: bool,
+    /// Whether any line in the viewport is indented more than the line
+    /// before it, so that it can be folded by indentation.
+    pub foldable: bool,
+    /// The most levels of indentation of any line in the viewport, for
+    /// sizing indent guides.
+    pub max_indent_depth: usize,
+}
+
+/// Returns the number of digits to leave room for in the numbers of
+/// `n_lines` lines, given the number previously left room for.
+///
+/// This grows as soon as the number of lines needs another digit, but only
+/// shrinks once the number of lines is a tenth under the boundary, so that
+/// the gutter doesn't change width while lines come and go around it, as
+/// when an edit that crosses it is undone and redone.
+pub fn line_number_digits(n_lines: usize, prev_digits: Option<usize>) -> usize {
+    let digits = n_lines.to_string().len();
+    match prev_digits {
+        Some(prev) if prev > digits => {
+            let boundary = 10usize.pow(prev as u32 - 1);
+            if n_lines < boundary - boundary / 10 {
+                line_number_digits(n_lines, Some(prev - 1))
+            } else {
+                prev
+            }
+        }
+        _ => digits,
+    }
+}
+
+/// Returns whether some line is more indented than the non-blank line
+/// before it, and the levels of indentation of the most indented line,
+/// given the indent of each line in columns, or `None` if it is blank.
+pub fn indentation<I>(widths: I, tab_size: usize) -> (bool, usize)
+    where I: IntoIterator<Item=Option<usize>>
+{
+    let mut foldable = false;
+    let mut max_width = 0;
+    let mut prev_width = None;
+    // blank lines don't have an indentation of their own
+    for width in widths.into_iter().filter_map(|width| width) {
+        foldable |= prev_width.map_or(false, |prev| width > prev);
+        max_width = max_width.max(width);
+        prev_width = Some(width);
+    }
+    (foldable, max_width / tab_size.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digits_hysteresis() {
+        assert_eq!(line_number_digits(1, None), 1);
+        assert_eq!(line_number_digits(999, None), 3);
+        assert_eq!(line_number_digits(1000, Some(3)), 4);
+        // shrinking waits until well under the boundary
+        assert_eq!(line_number_digits(999, Some(4)), 4);
+        assert_eq!(line_number_digits(900, Some(4)), 4);
+        assert_eq!(line_number_digits(899, Some(4)), 3);
+        assert_eq!(line_number_digits(5, Some(4)), 1);
+        assert_eq!(line_number_digits(9, Some(2)), 2);
+        assert_eq!(line_number_digits(8, Some(2)), 1);
+    }
+
+    #[test]
+    fn indentation_of_lines() {
+        assert_eq!(indentation(vec![Some(0), Some(2), Some(0)], 4), (true, 0));
+        assert_eq!(indentation(vec![Some(8), None, Some(8), Some(4)], 4), (false, 2));
+        assert_eq!(indentation(vec![Some(0), None, Some(4)], 4), (true, 1));
+        assert_eq!(indentation(Vec::new(), 4), (false, 0));
+    }
+}
@@ -21,6 +21,11 @@ extern crate serde_json;
 extern crate serde_derive;
 extern crate time;
 extern crate syntect;
+#[cfg(feature = "parallel")]
+extern crate rayon;
+
+#[cfg(unix)]
+extern crate libc;
 
 #[cfg(target_os = "fuchsia")]
 extern crate magenta;
@@ -64,6 +69,37 @@ pub mod internal {
     pub mod movement;
     pub mod syntax;
     pub mod layers;
+    pub mod line_metadata;
+    pub mod config;
+    pub mod indent;
+    pub mod metrics;
+    pub mod surround;
+    pub mod line_ids;
+    pub mod auto_save;
+    pub mod replace;
+    pub mod jump_list;
+    pub mod minimap;
+    pub mod brackets;
+    pub mod buffer_info;
+    pub mod mapped_file;
+    pub mod mapped_view;
+    pub mod spellcheck;
+    pub mod suggest;
+    pub mod layout_hints;
+    pub mod accessibility;
+    pub mod sanitize;
+    pub mod history;
+    pub mod messages;
+    pub mod pattern;
+    pub mod expr;
+    pub mod incidents;
+    pub mod undo_spill;
+    pub mod bidi;
+    pub mod scratch;
+    pub mod html;
+    pub mod builtin_syntax;
+    pub mod invisibles;
+    pub mod style_cache;
 }
 
 use internal::tabs;
@@ -78,6 +114,37 @@ use internal::selection;
 use internal::movement;
 use internal::syntax;
 use internal::layers;
+use internal::line_metadata;
+use internal::config;
+use internal::indent;
+use internal::metrics;
+use internal::surround;
+use internal::line_ids;
+use internal::auto_save;
+use internal::replace;
+use internal::jump_list;
+use internal::minimap;
+use internal::brackets;
+use internal::buffer_info;
+use internal::mapped_file;
+use internal::mapped_view;
+use internal::spellcheck;
+use internal::suggest;
+use internal::layout_hints;
+use internal::accessibility;
+use internal::sanitize;
+use internal::history;
+use internal::messages;
+use internal::pattern;
+use internal::expr;
+use internal::incidents;
+use internal::undo_spill;
+use internal::bidi;
+use internal::scratch;
+use internal::html;
+use internal::builtin_syntax;
+use internal::invisibles;
+use internal::style_cache;
 #[cfg(target_os = "fuchsia")]
 use internal::fuchsia;
 
@@ -101,8 +168,14 @@ pub struct MainState {
 
 impl MainState {
     pub fn new() -> Self {
+        MainState::with_safe_mode(false)
+    }
+
+    /// Creates the state for a session without the user's configuration or
+    /// any plugins if `safe_mode` is true, for troubleshooting.
+    pub fn with_safe_mode(safe_mode: bool) -> Self {
         MainState {
-            tabs: Documents::new(),
+            tabs: Documents::with_safe_mode(safe_mode),
         }
     }
 
@@ -133,7 +206,11 @@ impl Handler for MainState {
         self.tabs.handle_request(rpc, &mut ctx)
     }
 
-    fn idle(&mut self, _ctx: RpcCtx, _token: usize) {
-        self.tabs.handle_idle();
+    fn invalid_request(&mut self, err: RemoteError) -> RemoteError {
+        suggest::suggest_methods(err)
+    }
+
+    fn idle(&mut self, mut ctx: RpcCtx, token: usize) {
+        self.tabs.handle_idle(&mut ctx, token);
     }
 }
@@ -0,0 +1,191 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Stable identities for logical lines, so that front-ends can tell a line
+//! which has moved from one with new contents.
+//!
+//! Each line is identified by a marker at its start, which is transformed
+//! through edits. After an edit, each affected line takes the id of the
+//! first marker within it; if several markers land on the same offset,
+//! the last of them wins, as it marked the contents which follow. Lines
+//! without a marker get a new id, and markers which lose are retired.
+//!
+//! In practice this means that when a line is split, the first part keeps
+//! the id, unless the split is at the start of the line, in which case the
+//! moved contents keep it. When lines are joined, the first keeps its id,
+//! unless the join deletes the whole of the first, in which case the last
+//! keeps it. Ids are never reused.
+
+use xi_rope::rope::{LinesMetric, Rope, RopeInfo};
+use xi_rope::delta::{Delta, Transformer};
+use xi_rope::tree::Cursor;
+
+pub struct LineIds {
+    /// (start offset, id) for each line, in order.
+    lines: Vec<(usize, u64)>,
+    next_id: u64,
+}
+
+impl LineIds {
+    pub fn new(text: &Rope) -> Self {
+        let n_lines = text.measure::<LinesMetric>() + 1;
+        let mut lines = vec![(0, 0)];
+        let mut cursor = Cursor::new(text, 0);
+        while lines.len() < n_lines {
+            let offset = cursor.next::<LinesMetric>().unwrap();
+            let id = lines.len() as u64;
+            lines.push((offset, id));
+        }
+        let next_id = lines.len() as u64;
+        LineIds { lines: lines, next_id: next_id }
+    }
+
+    /// Returns the id of logical line `line`.
+    pub fn get(&self, line: usize) -> u64 {
+        self.lines[line].1
+    }
+
+    /// Updates the ids after `delta` has been applied, producing `text`.
+    pub fn after_edit(&mut self, text: &Rope, delta: &Delta<RopeInfo>) {
+        let (iv, new_len) = delta.summary();
+        let first_line = text.line_of_offset(iv.start());
+        let last_line = text.line_of_offset(iv.start() + new_len);
+        // the start of the first line is before the edit, and unchanged
+        let first_start = text.offset_of_line(first_line);
+        let lo = match self.lines.binary_search_by_key(&first_start, |&(offset, _)| offset) {
+            Ok(ix) | Err(ix) => ix,
+        };
+        let hi = match self.lines.binary_search_by_key(&iv.end(), |&(offset, _)| offset) {
+            Ok(ix) => ix + 1,
+            Err(ix) => ix,
+        };
+
+        let mut transformer = Transformer::new(delta);
+        let markers = self.lines[lo..hi].iter()
+            .map(|&(offset, id)| (transformer.transform(offset, true), id))
+            .collect::<Vec<_>>();
+        let mut new_lines = Vec::with_capacity(last_line + 1 - first_line);
+        let mut markers = markers.iter().peekable();
+        for line in first_line..last_line + 1 {
+            let end = if line == last_line { usize::max_value() } else {
+                text.offset_of_line(line + 1)
+            };
+            let mut chosen: Option<(usize, u64)> = None;
+            while let Some(&&(offset, id)) = markers.peek() {
+                if offset >= end {
+                    break;
+                }
+                if chosen.map(|(first, _)| first == offset).unwrap_or(true) {
+                    chosen = Some((offset, id));
+                }
+                markers.next();
+            }
+            let id = match chosen {
+                Some((_, id)) => id,
+                None => {
+                    self.next_id += 1;
+                    self.next_id - 1
+                }
+            };
+            new_lines.push((text.offset_of_line(line), id));
+        }
+
+        let shift = new_len as isize - iv.size() as isize;
+        for entry in self.lines[hi..].iter_mut() {
+            entry.0 = (entry.0 as isize + shift) as usize;
+        }
+        self.lines.splice(lo..hi, new_lines);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xi_rope::interval::Interval;
+
+    fn edit(text: &mut Rope, ids: &mut LineIds, start: usize, end: usize, new: &str) {
+        let iv = Interval::new_closed_open(start, end);
+        let delta = Delta::simple_edit(iv, Rope::from(new), text.len());
+        *text = delta.apply(text);
+        ids.after_edit(text, &delta);
+    }
+
+    fn all(ids: &LineIds) -> Vec<u64> {
+        ids.lines.iter().map(|&(_, id)| id).collect()
+    }
+
+    #[test]
+    fn edits_above() {
+        let mut text = Rope::from("a\nb\nc");
+        let mut ids = LineIds::new(&text);
+        assert_eq!(all(&ids), vec![0, 1, 2]);
+        edit(&mut text, &mut ids, 0, 0, "x\ny\n");
+        assert_eq!(all(&ids), vec![3, 4, 0, 1, 2]);
+        edit(&mut text, &mut ids, 0, 4, "");
+        assert_eq!(all(&ids), vec![0, 1, 2]);
+        // typing at the start of a line doesn't change it
+        edit(&mut text, &mut ids, 2, 2, "zz");
+        assert_eq!(all(&ids), vec![0, 1, 2]);
+        assert_eq!(ids.lines[2].0, 6);
+    }
+
+    #[test]
+    fn split() {
+        let mut text = Rope::from("foo\nbar\n");
+        let mut ids = LineIds::new(&text);
+        // in the middle, the first part keeps the id
+        edit(&mut text, &mut ids, 1, 1, "\n");
+        assert_eq!(all(&ids), vec![0, 3, 1, 2]);
+        // at the start, the moved contents keep it
+        edit(&mut text, &mut ids, 5, 5, "\n");
+        assert_eq!(String::from(&text), "f\noo\n\nbar\n");
+        assert_eq!(all(&ids), vec![0, 3, 4, 1, 2]);
+        // and at the end, the line keeps it
+        edit(&mut text, &mut ids, 9, 9, "\n");
+        assert_eq!(all(&ids), vec![0, 3, 4, 1, 5, 2]);
+    }
+
+    #[test]
+    fn join() {
+        let mut text = Rope::from("foo\nbar\nbaz\nqux");
+        let mut ids = LineIds::new(&text);
+        // deleting a newline, the first line keeps its id
+        edit(&mut text, &mut ids, 3, 4, "");
+        assert_eq!(String::from(&text), "foobar\nbaz\nqux");
+        assert_eq!(all(&ids), vec![0, 2, 3]);
+        // deleting a whole line, the following line keeps its id
+        edit(&mut text, &mut ids, 0, 7, "");
+        assert_eq!(all(&ids), vec![2, 3]);
+        // deleting from the start of a line into the next, the last keeps it
+        edit(&mut text, &mut ids, 0, 5, "");
+        assert_eq!(String::from(&text), "ux");
+        assert_eq!(all(&ids), vec![3]);
+    }
+
+    #[test]
+    fn multiple_regions() {
+        let mut text = Rope::from("a\nb\nc\nd");
+        let mut ids = LineIds::new(&text);
+        let before = text.clone();
+        let mut builder = ::xi_rope::delta::Builder::new(text.len());
+        builder.replace(Interval::new_closed_open(0, 0), Rope::from("x\n"));
+        builder.replace(Interval::new_closed_open(6, 6), Rope::from("y\n"));
+        let delta = builder.build();
+        text = delta.apply(&before);
+        ids.after_edit(&text, &delta);
+        assert_eq!(String::from(&text), "x\na\nb\nc\ny\nd");
+        // the lines between the edits keep their ids
+        assert_eq!(all(&ids), vec![4, 0, 1, 2, 5, 3]);
+    }
+}
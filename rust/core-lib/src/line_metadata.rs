@@ -0,0 +1,488 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A memoized cache of per-line metadata.
+//!
+//! A number of features need to know some property of each line: its indent,
+//! its trailing whitespace, how it changes bracket nesting. Rather than having
+//! each of them rescan the text, they register a `LineAnalyzer` with the
+//! buffer's `LineMetadata`, which computes values on demand and keeps them
+//! until an edit touches the line.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use serde_json::Value;
+
+use xi_rope::rope::{LinesMetric, Rope, RopeInfo};
+use xi_rope::delta::Delta;
+
+use bidi::{self, LineBidi};
+
+/// The identifier of the builtin indent analyzer.
+pub const INDENT: &'static str = "indent";
+/// The identifier of the builtin trailing whitespace analyzer.
+pub const TRAILING_WHITESPACE: &'static str = "trailing_whitespace";
+/// The identifier of the builtin bracket depth analyzer.
+pub const BRACKET_DELTA: &'static str = "bracket_delta";
+/// The identifier of the builtin word count analyzer.
+pub const WORD_COUNT: &'static str = "word_count";
+
+/// Computes a single value from the contents of a line.
+pub trait LineAnalyzer {
+    /// A unique identifier for this analyzer.
+    fn id(&self) -> &'static str;
+
+    /// Computes the value for `line`. The line includes its trailing
+    /// newline, if it has one.
+    fn analyze(&self, line: &str) -> i64;
+}
+
+/// The width, in columns, of a line's leading whitespace.
+pub struct IndentAnalyzer {
+    tab_size: usize,
+}
+
+/// The number of bytes of whitespace preceding the line's newline.
+pub struct TrailingWhitespaceAnalyzer;
+
+/// The number of brackets opened on a line, less the number closed.
+pub struct BracketDeltaAnalyzer;
+
+/// The number of whitespace separated words on a line.
+pub struct WordCountAnalyzer;
+
+struct CachedLine {
+    /// A hash of the line's text, used to revalidate entries after an edit.
+    hash: u64,
+    values: Vec<Option<i64>>,
+    /// The line's bidi levels, once resolved, which are `None` if it has
+    /// no right-to-left text.
+    bidi: Option<Option<LineBidi>>,
+}
+
+/// A per-buffer cache of values computed by `LineAnalyzer`s.
+///
+/// Entries are keyed by line number and the hash of the line's text. When
+/// the buffer changes, `after_edit` must be called with the applied delta;
+/// only entries for lines touched by the delta are discarded.
+pub struct LineMetadata {
+    analyzers: Vec<Box<LineAnalyzer + Send>>,
+    lines: Vec<Option<CachedLine>>,
+    /// The bracket depth at the start of each line, as far as it has been
+    /// summed; the depth of line `n + 1` is that of line `n` plus its delta.
+    depths: Vec<i64>,
+    tab_size: usize,
+    hits: u64,
+    misses: u64,
+    invocations: Vec<u64>,
+}
+
+impl LineMetadata {
+    /// Creates a new cache for `text`, with the builtin analyzers registered.
+    pub fn new(text: &Rope, tab_size: usize) -> Self {
+        let n_lines = text.measure::<LinesMetric>() + 1;
+        let mut cache = LineMetadata {
+            analyzers: Vec::new(),
+            lines: (0..n_lines).map(|_| None).collect(),
+            depths: vec![0],
+            tab_size: tab_size,
+            hits: 0,
+            misses: 0,
+            invocations: Vec::new(),
+        };
+        cache.register(Box::new(IndentAnalyzer { tab_size: tab_size }));
+        cache.register(Box::new(TrailingWhitespaceAnalyzer));
+        cache.register(Box::new(BracketDeltaAnalyzer));
+        cache.register(Box::new(WordCountAnalyzer));
+        cache
+    }
+
+    /// Registers a new analyzer. If an analyzer with the same id already
+    /// exists, it is replaced, and its cached values are discarded.
+    pub fn register(&mut self, analyzer: Box<LineAnalyzer + Send>) {
+        match self.analyzer_idx(analyzer.id()) {
+            Some(idx) => {
+                for line in self.lines.iter_mut() {
+                    if let Some(ref mut line) = *line {
+                        line.values[idx] = None;
+                    }
+                }
+                if analyzer.id() == BRACKET_DELTA {
+                    self.depths.truncate(1);
+                }
+                self.analyzers[idx] = analyzer;
+                self.invocations[idx] = 0;
+            }
+            None => {
+                for line in self.lines.iter_mut() {
+                    if let Some(ref mut line) = *line {
+                        line.values.push(None);
+                    }
+                }
+                self.analyzers.push(analyzer);
+                self.invocations.push(0);
+            }
+        }
+    }
+
+    /// Returns the value computed by the analyzer `id` for `line`, computing
+    /// it if necessary. Returns `None` if no such analyzer is registered or
+    /// if `line` is out of bounds.
+    pub fn get(&mut self, text: &Rope, line: usize, id: &str) -> Option<i64> {
+        match self.analyzer_idx(id) {
+            Some(idx) if line < self.lines.len() => Some(self.value(text, line, idx)),
+            _ => None,
+        }
+    }
+
+    /// Returns the indent of `line`, in columns.
+    pub fn indent(&mut self, text: &Rope, line: usize) -> usize {
+        self.get(text, line, INDENT).unwrap_or(0) as usize
+    }
+
+    /// Returns the number of bytes of trailing whitespace on `line`.
+    pub fn trailing_whitespace(&mut self, text: &Rope, line: usize) -> usize {
+        self.get(text, line, TRAILING_WHITESPACE).unwrap_or(0) as usize
+    }
+
+    /// Returns whether `line` has nothing but whitespace.
+    pub fn is_blank(&mut self, text: &Rope, line: usize) -> bool {
+        self.get(text, line, WORD_COUNT).unwrap_or(0) == 0
+    }
+
+    /// Returns the bracket nesting depth at the start of `line`.
+    ///
+    /// The depths are kept as running sums of the lines' deltas, so only
+    /// the lines past the last one summed are visited.
+    pub fn bracket_depth(&mut self, text: &Rope, line: usize) -> usize {
+        let line = line.min(self.lines.len());
+        while self.depths.len() <= line {
+            let prev = self.depths.len() - 1;
+            let depth = self.depths[prev] + self.get(text, prev, BRACKET_DELTA).unwrap_or(0);
+            self.depths.push(depth);
+        }
+        self.depths[line].max(0) as usize
+    }
+
+    /// Sets the tab size used to measure indents, discarding the indents
+    /// measured with a different one.
+    pub fn set_tab_size(&mut self, tab_size: usize) {
+        if tab_size != self.tab_size {
+            self.tab_size = tab_size;
+            self.register(Box::new(IndentAnalyzer { tab_size: tab_size }));
+        }
+    }
+
+    /// Updates the cache after `delta` has been applied, producing `text`.
+    pub fn after_edit(&mut self, text: &Rope, delta: &Delta<RopeInfo>) {
+        let (iv, new_len) = delta.summary();
+        let n_lines = text.measure::<LinesMetric>() + 1;
+        let first = text.line_of_offset(iv.start());
+        let new_last = text.line_of_offset(iv.start() + new_len);
+        // lines following the edit are unchanged, so we can work out how
+        // many lines the edit replaced without having the old text.
+        let n_tail = n_lines - new_last - 1;
+        if self.lines.len() < n_tail + 1 || self.lines.len() - n_tail - 1 < first {
+            // we've lost track of the text; start over.
+            self.lines = (0..n_lines).map(|_| None).collect();
+            self.depths.truncate(1);
+            return;
+        }
+        let old_last = self.lines.len() - n_tail - 1;
+        // the depth at the start of `first` only depends on the lines before it
+        self.depths.truncate(first + 1);
+
+        let replaced = self.lines.splice(first..old_last + 1, (first..new_last + 1).map(|_| None))
+            .collect::<Vec<_>>();
+
+        // if the number of lines didn't change, entries whose text is
+        // unchanged (such as lines touched by a no-op replacement, or
+        // restored by an undo) can be kept.
+        if replaced.len() == new_last + 1 - first {
+            for (i, entry) in replaced.into_iter().enumerate() {
+                if let Some(entry) = entry {
+                    if entry.hash == hash_line(&line_text(text, first + i)) {
+                        self.lines[first + i] = Some(entry);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns cache statistics, for debugging.
+    pub fn stats(&self) -> Value {
+        let mut invocations = json!({});
+        for (analyzer, count) in self.analyzers.iter().zip(self.invocations.iter()) {
+            invocations[analyzer.id()] = json!(count);
+        }
+        let lookups = self.hits + self.misses;
+        let hit_rate = if lookups == 0 { 0.0 } else { self.hits as f64 / lookups as f64 };
+        json!({
+            "hits": self.hits,
+            "misses": self.misses,
+            "hit_rate": hit_rate,
+            "cached_lines": self.lines.iter().filter(|l| l.is_some()).count(),
+            "invocations": invocations,
+        })
+    }
+
+    fn analyzer_idx(&self, id: &str) -> Option<usize> {
+        self.analyzers.iter().position(|a| a.id() == id)
+    }
+
+    fn value(&mut self, text: &Rope, line: usize, idx: usize) -> i64 {
+        if let Some(ref entry) = self.lines[line] {
+            if let Some(value) = entry.values[idx] {
+                self.hits += 1;
+                return value;
+            }
+        }
+        self.misses += 1;
+        let line_str = line_text(text, line);
+        let value = self.analyzers[idx].analyze(&line_str);
+        self.invocations[idx] += 1;
+
+        let n_analyzers = self.analyzers.len();
+        let entry = self.lines[line].get_or_insert_with(|| {
+            CachedLine { hash: hash_line(&line_str), values: vec![None; n_analyzers], bidi: None }
+        });
+        entry.values[idx] = Some(value);
+        value
+    }
+
+    /// Returns the bidi levels of `line`, resolving them if necessary.
+    /// Returns `None` if the line has no right-to-left text, or if it is
+    /// out of bounds.
+    pub fn bidi(&mut self, text: &Rope, line: usize) -> Option<LineBidi> {
+        if line >= self.lines.len() {
+            return None;
+        }
+        if let Some(CachedLine { bidi: Some(ref bidi), .. }) = self.lines[line] {
+            self.hits += 1;
+            return bidi.clone();
+        }
+        self.misses += 1;
+        let line_str = line_text(text, line);
+        let bidi = bidi::resolve(&line_str);
+        let n_analyzers = self.analyzers.len();
+        let entry = self.lines[line].get_or_insert_with(|| {
+            CachedLine { hash: hash_line(&line_str), values: vec![None; n_analyzers], bidi: None }
+        });
+        entry.bidi = Some(bidi.clone());
+        bidi
+    }
+}
+
+fn line_text(text: &Rope, line: usize) -> String {
+    let start = text.offset_of_line(line);
+    let end = text.offset_of_line(line + 1);
+    text.slice_to_string(start, end)
+}
+
+fn hash_line(line: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    line.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl LineAnalyzer for IndentAnalyzer {
+    fn id(&self) -> &'static str { INDENT }
+
+    fn analyze(&self, line: &str) -> i64 {
+        let mut col = 0;
+        for c in line.chars() {
+            match c {
+                ' ' => col += 1,
+                '\t' => col += self.tab_size - (col % self.tab_size),
+                _ => break,
+            }
+        }
+        col as i64
+    }
+}
+
+impl LineAnalyzer for TrailingWhitespaceAnalyzer {
+    fn id(&self) -> &'static str { TRAILING_WHITESPACE }
+
+    fn analyze(&self, line: &str) -> i64 {
+        let line = line.trim_end_matches(|c| c == '\n' || c == '\r');
+        (line.len() - line.trim_end().len()) as i64
+    }
+}
+
+impl LineAnalyzer for BracketDeltaAnalyzer {
+    fn id(&self) -> &'static str { BRACKET_DELTA }
+
+    fn analyze(&self, line: &str) -> i64 {
+        line.chars().fold(0, |depth, c| match c {
+            '(' | '[' | '{' => depth + 1,
+            ')' | ']' | '}' => depth - 1,
+            _ => depth,
+        })
+    }
+}
+
+impl LineAnalyzer for WordCountAnalyzer {
+    fn id(&self) -> &'static str { WORD_COUNT }
+
+    fn analyze(&self, line: &str) -> i64 {
+        line.split_whitespace().count() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xi_rope::interval::Interval;
+
+    fn edit(text: &mut Rope, start: usize, end: usize, new: &str) -> Delta<RopeInfo> {
+        let iv = Interval::new_closed_open(start, end);
+        let delta = Delta::simple_edit(iv, Rope::from(new), text.len());
+        *text = delta.apply(text);
+        delta
+    }
+
+    fn invocations(cache: &LineMetadata) -> u64 {
+        cache.invocations.iter().sum()
+    }
+
+    #[test]
+    fn builtin_analyzers() {
+        let text = Rope::from("fn main() {\n\t  let a = [1, 2];  \n}\n");
+        let mut cache = LineMetadata::new(&text, 4);
+        assert_eq!(cache.indent(&text, 0), 0);
+        assert_eq!(cache.indent(&text, 1), 6);
+        assert_eq!(cache.trailing_whitespace(&text, 1), 2);
+        assert_eq!(cache.trailing_whitespace(&text, 0), 0);
+        assert_eq!(cache.get(&text, 0, WORD_COUNT), Some(3));
+        assert_eq!(cache.bracket_depth(&text, 1), 1);
+        assert_eq!(cache.bracket_depth(&text, 3), 0);
+        assert_eq!(cache.get(&text, 4, INDENT), None);
+        assert_eq!(cache.get(&text, 0, "no_such_analyzer"), None);
+    }
+
+    #[test]
+    fn memoized() {
+        let text = Rope::from("a\n  b\n");
+        let mut cache = LineMetadata::new(&text, 4);
+        assert_eq!(cache.indent(&text, 1), 2);
+        assert_eq!(cache.indent(&text, 1), 2);
+        assert_eq!(cache.hits, 1);
+        assert_eq!(cache.misses, 1);
+        assert_eq!(invocations(&cache), 1);
+    }
+
+    #[test]
+    fn invalidation() {
+        let mut text = Rope::from("zero\none\n  two\nthree\n");
+        let mut cache = LineMetadata::new(&text, 4);
+        for line in 0..4 {
+            cache.indent(&text, line);
+        }
+        // indent `one`
+        let delta = edit(&mut text, 5, 5, "    ");
+        cache.after_edit(&text, &delta);
+        assert_eq!(cache.indent(&text, 1), 4);
+        assert_eq!(invocations(&cache), 5);
+        // split a line; following lines shift down, but remain cached
+        let delta = edit(&mut text, 2, 2, "\n");
+        cache.after_edit(&text, &delta);
+        assert_eq!(cache.indent(&text, 3), 2);
+        assert_eq!(cache.indent(&text, 4), 0);
+        assert_eq!(invocations(&cache), 5);
+        // join them again
+        let delta = edit(&mut text, 2, 3, "");
+        cache.after_edit(&text, &delta);
+        assert_eq!(cache.indent(&text, 2), 2);
+        assert_eq!(cache.indent(&text, 0), 0);
+        assert_eq!(invocations(&cache), 6);
+    }
+
+    #[test]
+    fn bracket_depths_summed_once() {
+        let mut text = Rope::from("a {\n  b (\n  c\n  )\n}\n");
+        let mut cache = LineMetadata::new(&text, 4);
+        assert_eq!(cache.bracket_depth(&text, 3), 2);
+        assert_eq!(cache.bracket_depth(&text, 5), 0);
+        assert_eq!(cache.bracket_depth(&text, 2), 2);
+        assert_eq!(invocations(&cache), 5);
+        // an edit only resums the lines from the one it touches
+        let delta = edit(&mut text, 8, 8, "[");
+        cache.after_edit(&text, &delta);
+        assert_eq!(cache.bracket_depth(&text, 1), 1);
+        assert_eq!(cache.bracket_depth(&text, 4), 2);
+        assert_eq!(cache.bracket_depth(&text, 5), 1);
+        assert_eq!(invocations(&cache), 6);
+    }
+
+    #[test]
+    fn tab_size_change() {
+        let text = Rope::from("\tx\n");
+        let mut cache = LineMetadata::new(&text, 4);
+        assert_eq!(cache.indent(&text, 0), 4);
+        cache.set_tab_size(4);
+        assert_eq!(invocations(&cache), 1);
+        cache.set_tab_size(8);
+        assert_eq!(cache.indent(&text, 0), 8);
+    }
+
+    #[test]
+    fn bidi_levels() {
+        let mut text = Rope::from("abc\nאבג abc\n");
+        let mut cache = LineMetadata::new(&text, 4);
+        assert_eq!(cache.bidi(&text, 0), None);
+        assert_eq!(cache.bidi(&text, 1).unwrap().level, 1);
+        assert_eq!(cache.bidi(&text, 1).unwrap().runs.len(), 3);
+        assert_eq!((cache.hits, cache.misses), (1, 2));
+        // right-to-left text typed into a line is resolved again
+        let delta = edit(&mut text, 3, 3, " ד");
+        cache.after_edit(&text, &delta);
+        assert_eq!(cache.bidi(&text, 0).unwrap().level, 0);
+        assert_eq!(cache.bidi(&text, 3), None);
+    }
+
+    #[test]
+    fn unchanged_lines_revalidated() {
+        let mut text = Rope::from("  a\n  b\n");
+        let mut cache = LineMetadata::new(&text, 4);
+        cache.indent(&text, 0);
+        let delta = edit(&mut text, 2, 3, "a");
+        cache.after_edit(&text, &delta);
+        cache.indent(&text, 0);
+        assert_eq!(invocations(&cache), 1);
+    }
+
+    #[test]
+    fn one_edit_in_large_buffer() {
+        let n_lines = 500_000;
+        let mut s = String::new();
+        for i in 0..n_lines {
+            s.push_str(if i % 3 == 0 { "    indented\n" } else { "line\n" });
+        }
+        let mut text = Rope::from(s);
+        let mut cache = LineMetadata::new(&text, 4);
+        for line in (0..n_lines).step_by(1000) {
+            cache.indent(&text, line);
+        }
+        let before = invocations(&cache);
+        let offset = text.offset_of_line(250_000);
+        let delta = edit(&mut text, offset, offset, "  ");
+        cache.after_edit(&text, &delta);
+        for line in (0..n_lines).step_by(1000) {
+            cache.indent(&text, line);
+        }
+        assert_eq!(invocations(&cache), before + 1);
+        assert_eq!(cache.indent(&text, 250_000), 2);
+    }
+}
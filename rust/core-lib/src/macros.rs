@@ -34,3 +34,12 @@ macro_rules! print_err {
         }
     )
 }
+
+/// Records an incident, an internal error which core recovers from, with
+/// a message formatted as by `format!`. Unlike an assertion, it doesn't
+/// panic. See `incidents`.
+macro_rules! incident {
+    ($($arg:tt)*) => (
+        $crate::incidents::record(format!("{}:{}", file!(), line!()), format!($($arg)*))
+    )
+}
@@ -0,0 +1,377 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reading huge files without loading them, by mapping them into memory.
+//!
+//! A sparse index of line offsets is built in steps, as background work.
+//! Lines are read from the map as they are needed, and bytes which aren't
+//! UTF-8 are replaced when they are displayed.
+
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The index records the offset of every this many lines.
+const LINES_PER_CHECKPOINT: usize = 1024;
+
+/// The number of bytes indexed in each step.
+const INDEX_STEP: usize = 16 << 20;
+
+/// Lines are cut to this many bytes when displayed.
+pub const MAX_LINE_LEN: usize = 1 << 16;
+
+#[cfg(unix)]
+mod mapping {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+    use std::slice;
+
+    use libc;
+
+    /// A read-only, private mapping of a file.
+    pub struct Mapping {
+        ptr: *mut libc::c_void,
+        len: usize,
+    }
+
+    // the mapping is never written, so may be read from any thread
+    unsafe impl Send for Mapping {}
+
+    impl Mapping {
+        pub fn new(file: &File, len: usize) -> io::Result<Self> {
+            if len == 0 {
+                // mapping nothing is an error
+                return Ok(Mapping { ptr: ptr::null_mut(), len: 0 });
+            }
+            let ptr = unsafe {
+                libc::mmap(ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE,
+                           file.as_raw_fd(), 0)
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Mapping { ptr: ptr, len: len })
+        }
+
+        pub fn as_bytes(&self) -> &[u8] {
+            if self.len == 0 {
+                return &[];
+            }
+            unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+        }
+    }
+
+    impl Drop for Mapping {
+        fn drop(&mut self) {
+            if self.len > 0 {
+                unsafe { libc::munmap(self.ptr, self.len); }
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod mapping {
+    use std::fs::File;
+    use std::io::{self, Read};
+
+    /// Where files can't be mapped, they are read instead.
+    pub struct Mapping(Vec<u8>);
+
+    impl Mapping {
+        pub fn new(mut file: &File, len: usize) -> io::Result<Self> {
+            let mut bytes = Vec::with_capacity(len);
+            file.take(len as u64).read_to_end(&mut bytes)?;
+            Ok(Mapping(bytes))
+        }
+
+        pub fn as_bytes(&self) -> &[u8] {
+            &self.0
+        }
+    }
+}
+
+use self::mapping::Mapping;
+
+pub struct MappedFile {
+    path: PathBuf,
+    map: Mapping,
+    /// The offset of the start of every `LINES_PER_CHECKPOINT`th line.
+    checkpoints: Vec<usize>,
+    /// The number of bytes indexed so far.
+    indexed: usize,
+    /// The number of newlines in the indexed bytes.
+    newlines: usize,
+}
+
+impl MappedFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        Ok(MappedFile {
+            path: path.to_owned(),
+            map: Mapping::new(&file, len)?,
+            checkpoints: vec![0],
+            indexed: 0,
+            newlines: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.map.as_bytes()
+    }
+
+    /// The length of the file when it was mapped.
+    pub fn len(&self) -> usize {
+        self.bytes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if the file is now shorter than when it was mapped.
+    /// Reading the part of the map past the end of the file is a fatal
+    /// error, so this is checked before the map is used.
+    pub fn is_truncated(&self) -> bool {
+        fs::metadata(&self.path).map(|m| (m.len() as usize) < self.len()).unwrap_or(true)
+    }
+
+    /// The number of bytes indexed so far.
+    pub fn indexed(&self) -> usize {
+        self.indexed
+    }
+
+    pub fn is_indexed(&self) -> bool {
+        self.indexed == self.len()
+    }
+
+    /// Indexes the next part of the file. Returns `true` if there is more
+    /// to be indexed.
+    pub fn index_step(&mut self) -> bool {
+        let end = (self.indexed + INDEX_STEP).min(self.len());
+        let mut newlines = self.newlines;
+        let mut checkpoints = Vec::new();
+        for (ix, &b) in self.bytes()[self.indexed..end].iter().enumerate() {
+            if b == b'\n' {
+                newlines += 1;
+                if newlines % LINES_PER_CHECKPOINT == 0 {
+                    checkpoints.push(self.indexed + ix + 1);
+                }
+            }
+        }
+        self.checkpoints.extend(checkpoints);
+        self.newlines = newlines;
+        self.indexed = end;
+        !self.is_indexed()
+    }
+
+    /// Indexes the whole file.
+    pub fn index_all(&mut self) {
+        while self.index_step() {}
+    }
+
+    /// The number of lines indexed so far, which is all of them once the
+    /// file is indexed.
+    pub fn n_lines(&self) -> usize {
+        if self.is_indexed() { self.newlines + 1 } else { self.newlines }
+    }
+
+    /// Returns the offset of the start of `line`, if it has been indexed.
+    pub fn offset_of_line(&self, line: usize) -> Option<usize> {
+        if line >= self.n_lines() {
+            return None;
+        }
+        let mut offset = self.checkpoints[line / LINES_PER_CHECKPOINT];
+        for _ in 0..line % LINES_PER_CHECKPOINT {
+            offset = self.line_end(offset);
+        }
+        Some(offset)
+    }
+
+    /// Returns the line containing `offset`.
+    pub fn line_of_offset(&self, offset: usize) -> usize {
+        let offset = offset.min(self.len());
+        let ix = match self.checkpoints.binary_search(&offset) {
+            Ok(ix) => ix,
+            Err(ix) => ix - 1,
+        };
+        let start = self.checkpoints[ix];
+        let newlines = self.bytes()[start..offset].iter().filter(|&&b| b == b'\n').count();
+        ix * LINES_PER_CHECKPOINT + newlines
+    }
+
+    /// Returns the offset after the newline ending the line containing
+    /// `offset`, or the end of the file.
+    fn line_end(&self, offset: usize) -> usize {
+        match self.bytes()[offset..].iter().position(|&b| b == b'\n') {
+            Some(pos) => offset + pos + 1,
+            None => self.len(),
+        }
+    }
+
+    /// Returns the extent of `line`, including its newline, if it has been
+    /// indexed.
+    pub fn line_range(&self, line: usize) -> Option<(usize, usize)> {
+        self.offset_of_line(line).map(|start| (start, self.line_end(start)))
+    }
+
+    /// Returns the text of `line`, for display, if it has been indexed.
+    pub fn line<'a>(&'a self, line: usize) -> Option<Cow<'a, str>> {
+        self.line_range(line).map(|(start, end)| {
+            self.slice(start, end.min(start + MAX_LINE_LEN))
+        })
+    }
+
+    /// Returns the text from `start` to `end`, with any bytes which aren't
+    /// UTF-8 replaced.
+    pub fn slice<'a>(&'a self, start: usize, end: usize) -> Cow<'a, str> {
+        String::from_utf8_lossy(&self.bytes()[start..end])
+    }
+
+    /// Returns the extent of the next occurrence of `query` starting at or
+    /// after `start` if `forward`, or else the last one ending by `start`.
+    pub fn find(&self, query: &str, case_sensitive: bool, start: usize, forward: bool)
+                -> Option<(usize, usize)> {
+        let query = query.as_bytes();
+        let bytes = self.bytes();
+        if query.is_empty() || query.len() > bytes.len() {
+            return None;
+        }
+        let matches = |pos: usize| {
+            let candidate = &bytes[pos..pos + query.len()];
+            if case_sensitive {
+                candidate == query
+            } else {
+                candidate.eq_ignore_ascii_case(query)
+            }
+        };
+        let last = bytes.len() - query.len();
+        let found = if forward {
+            (start.min(last + 1)..last + 1).find(|&pos| matches(pos))
+        } else {
+            let end = start.saturating_sub(query.len()).min(last) + 1;
+            (0..end).rev().find(|&pos| matches(pos))
+        };
+        found.map(|pos| (pos, pos + query.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::{Seek, SeekFrom, Write};
+
+    fn fixture(name: &str, contents: &[u8]) -> PathBuf {
+        let mut path = env::temp_dir();
+        path.push(format!("xi-test-mapped-{}", name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn lines() {
+        let text = (0..5000).map(|i| format!("line {}\n", i)).collect::<String>();
+        let path = fixture("lines", text.as_bytes());
+        let mut file = MappedFile::open(&path).unwrap();
+        assert_eq!(file.n_lines(), 0);
+        assert_eq!(file.line(0), None);
+        file.index_all();
+        assert_eq!(file.n_lines(), 5001);
+        assert_eq!(file.line(0).unwrap(), "line 0\n");
+        assert_eq!(file.line(3000).unwrap(), "line 3000\n");
+        assert_eq!(file.line(5000).unwrap(), "");
+        assert_eq!(file.line(5001), None);
+        let offset = file.offset_of_line(2048).unwrap();
+        assert_eq!(file.line_of_offset(offset), 2048);
+        assert_eq!(file.line_of_offset(offset + 3), 2048);
+        assert_eq!(file.line_of_offset(offset - 1), 2047);
+        assert_eq!(file.line_of_offset(file.len()), 5000);
+    }
+
+    #[test]
+    fn lossy() {
+        let path = fixture("lossy", b"ok\nbad \xff\xfe\nok");
+        let mut file = MappedFile::open(&path).unwrap();
+        file.index_all();
+        assert_eq!(file.line(1).unwrap(), "bad \u{fffd}\u{fffd}\n");
+        assert_eq!(file.line(2).unwrap(), "ok");
+        let path = fixture("empty", b"");
+        let mut file = MappedFile::open(&path).unwrap();
+        file.index_all();
+        assert_eq!(file.n_lines(), 1);
+        assert_eq!(file.line(0).unwrap(), "");
+    }
+
+    #[test]
+    fn find() {
+        let path = fixture("find", b"foo bar\nFoo baz\nfoo");
+        let file = MappedFile::open(&path).unwrap();
+        assert_eq!(file.find("foo", true, 0, true), Some((0, 3)));
+        assert_eq!(file.find("foo", true, 1, true), Some((16, 19)));
+        assert_eq!(file.find("foo", false, 1, true), Some((8, 11)));
+        assert_eq!(file.find("foo", true, 16, false), Some((0, 3)));
+        assert_eq!(file.find("foo", false, 19, false), Some((16, 19)));
+        assert_eq!(file.find("qux", false, 0, true), None);
+    }
+
+    #[test]
+    fn truncation() {
+        let path = fixture("truncation", b"one\ntwo\nthree\n");
+        let file = MappedFile::open(&path).unwrap();
+        assert!(!file.is_truncated());
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(4).unwrap();
+        assert!(file.is_truncated());
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "expensive-tests"), ignore)]
+    fn huge_sparse_file() {
+        const MB: u64 = 1 << 20;
+        let mut path = env::temp_dir();
+        path.push("xi-test-mapped-huge");
+        let mut f = File::create(&path).unwrap();
+        // a short line every megabyte, with holes of NULs between them
+        for i in 0..2048 {
+            f.seek(SeekFrom::Start(i * MB)).unwrap();
+            write!(f, "\nline {}\n", i).unwrap();
+        }
+        f.set_len(2048 * MB).unwrap();
+        drop(f);
+
+        let mut file = MappedFile::open(&path).unwrap();
+        let mut steps = 0;
+        while file.index_step() {
+            steps += 1;
+        }
+        assert!(steps > 100);
+        assert_eq!(file.n_lines(), 2048 * 2 + 1);
+        assert_eq!(file.line(1).unwrap(), "line 0\n");
+        assert_eq!(file.line(2 * 2000 + 1).unwrap(), "line 2000\n");
+        // the holes are long lines, which are cut
+        assert_eq!(file.line(2).unwrap().len(), MAX_LINE_LEN);
+        let (start, _) = file.find("line 2047", true, 0, true).unwrap();
+        assert_eq!(file.line_of_offset(start), 2 * 2047 + 1);
+        let _ = fs::remove_file(&path);
+    }
+}
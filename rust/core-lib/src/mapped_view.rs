@@ -0,0 +1,302 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Views of mapped files, which can be scrolled, searched and copied
+//! from, but not edited.
+
+use std::cmp::{max, min};
+use std::path::PathBuf;
+
+use serde_json::{self, Value};
+
+use xi_rope::rope::Rope;
+use xi_rpc::RemoteError;
+
+use buffer_info::{BufferInfo, FileInfo};
+use mapped_file::{MappedFile, MAX_LINE_LEN};
+use rpc::{EditNotification, EditRequest, LineRange, MouseAction};
+use syntax::SyntaxDefinition;
+use tabs::{DocumentCtx, ViewIdentifier};
+use view::ScrollPosition;
+use messages::{ids, Message};
+
+/// The error code of commands which would edit a view-only buffer.
+const READONLY: i64 = 5;
+
+/// The number of lines sent beyond those the front-end asks for.
+const SCROLL_SLOP: usize = 2;
+
+pub struct MappedView {
+    view_id: ViewIdentifier,
+    file: MappedFile,
+    doc_ctx: DocumentCtx,
+    /// The lines the front-end is showing.
+    first_line: usize,
+    last_line: usize,
+    /// The anchor and the caret of the selection.
+    sel: (usize, usize),
+    /// The string being searched for, and whether its case matters.
+    find: Option<(String, bool)>,
+    sent_buffer_info: bool,
+}
+
+impl MappedView {
+    pub fn new(view_id: &ViewIdentifier, file: MappedFile, doc_ctx: DocumentCtx) -> Self {
+        MappedView {
+            view_id: view_id.to_owned(),
+            file: file,
+            doc_ctx: doc_ctx,
+            first_line: 0,
+            last_line: 50,
+            sel: (0, 0),
+            find: None,
+            sent_buffer_info: false,
+        }
+    }
+
+    pub fn has_background_work(&self) -> bool {
+        !self.file.is_indexed()
+    }
+
+    /// Indexes the next part of the file, reporting the progress.
+    pub fn do_background_work(&mut self) {
+        if !self.check_file() {
+            return;
+        }
+        self.file.index_step();
+        self.doc_ctx.index_progress(&self.view_id, self.file.indexed(), self.file.len());
+        self.render();
+    }
+
+    /// Remaps the file if it has been truncated, as reading past its end
+    /// would crash. Returns `false` if it can't be remapped.
+    fn check_file(&mut self) -> bool {
+        if !self.file.is_truncated() {
+            return true;
+        }
+        let path = self.file.path().to_owned();
+        match MappedFile::open(&path) {
+            Ok(file) => {
+                self.doc_ctx.alert(&Message::new(ids::file_truncated)
+                                   .param("path", path.display().to_string()));
+                let len = file.len();
+                self.file = file;
+                self.sel = (min(self.sel.0, len), min(self.sel.1, len));
+                true
+            }
+            Err(err) => {
+                self.doc_ctx.alert(&Message::new(ids::file_reload_failed)
+                                   .param("path", path.display().to_string())
+                                   .param("error", err.to_string()));
+                false
+            }
+        }
+    }
+
+    pub fn buffer_info(&self) -> BufferInfo {
+        // the first line is enough for the BOM and line ending
+        let first_line = self.file.line(0).unwrap_or_default();
+        let path = self.file.path();
+        let mut info = BufferInfo::new(Some(path), SyntaxDefinition::new(path.to_str())
+                                       .identifier(), &Rope::from(first_line.as_ref()),
+                                       &FileInfo::read(path), true);
+        info.read_only = true;
+        info
+    }
+
+    /// Returns the path and the contents of the file, for loading it into
+    /// an editable buffer.
+    pub fn into_text(mut self) -> Option<(PathBuf, String)> {
+        if !self.check_file() {
+            return None;
+        }
+        let text = self.file.slice(0, self.file.len()).into_owned();
+        Some((self.file.path().to_owned(), text))
+    }
+
+    pub fn len(&self) -> usize {
+        self.file.len()
+    }
+
+    pub fn alert(&self, msg: &Message) {
+        self.doc_ctx.alert(msg);
+    }
+
+    pub fn handle_notification(&mut self, cmd: EditNotification) {
+        use rpc::EditNotification::*;
+        if !self.check_file() {
+            return;
+        }
+        match cmd {
+            Scroll(LineRange { first, last }) | RequestLines(LineRange { first, last }) => {
+                self.first_line = max(first, 0) as usize;
+                self.last_line = max(last, 0) as usize;
+            }
+            GotoLine { line } => {
+                if let Some(offset) = self.file.offset_of_line(line as usize) {
+                    self.set_caret(offset);
+                }
+            }
+            Click(MouseAction { line, column, .. }) => {
+                if let Some(offset) = self.offset_of_line_col(line as usize, column as usize) {
+                    self.sel = (offset, offset);
+                }
+            }
+            Drag(MouseAction { line, column, .. }) => {
+                if let Some(offset) = self.offset_of_line_col(line as usize, column as usize) {
+                    self.sel.1 = offset;
+                }
+            }
+            SelectAll => self.sel = (0, self.file.len()),
+            MoveToBeginningOfDocument => self.set_caret(0),
+            MoveToEndOfDocument => {
+                let len = self.file.len();
+                self.set_caret(len);
+            }
+            FindNext { wrap_around, allow_same } => {
+                self.find_next(true, wrap_around.unwrap_or(false), allow_same.unwrap_or(false))
+            }
+            FindPrevious { wrap_around } => {
+                self.find_next(false, wrap_around.unwrap_or(false), false)
+            }
+            Insert { .. } | DeleteForward | DeleteBackward | DeleteWordForward |
+            DeleteWordBackward | DeleteToEndOfParagraph | DeleteToBeginningOfLine |
+            InsertNewline | InsertTab | Yank | Paste { .. } | Transpose | Surround { .. } |
+            Unsurround | ResolvePaste { .. } |
+            ChangeSurround { .. } | TransposeBracketGroups | Undo | Redo | Reindent { .. } => {
+                self.doc_ctx.alert(&Message::new(ids::view_cant_be_edited))
+            }
+            other => print_err!("not available in view-only mode: {:?}", other),
+        }
+        self.render();
+    }
+
+    pub fn handle_request(&mut self, cmd: EditRequest) -> Result<Value, RemoteError> {
+        use rpc::EditRequest::*;
+        if !self.check_file() {
+            return Err(Message::new(ids::file_unreadable).into_error(READONLY, None));
+        }
+        match cmd {
+            Copy => {
+                let (start, end) = (min(self.sel.0, self.sel.1), max(self.sel.0, self.sel.1));
+                if start == end {
+                    Ok(Value::Null)
+                } else {
+                    Ok(Value::String(self.file.slice(start, end).into_owned()))
+                }
+            }
+            Find { chars, case_sensitive, .. } => {
+                let (start, end) = (min(self.sel.0, self.sel.1), max(self.sel.0, self.sel.1));
+                let query = chars.or_else(|| if start == end { None } else {
+                    Some(self.file.slice(start, end).into_owned())
+                });
+                self.find = query.filter(|q| !q.is_empty()).map(|q| (q, case_sensitive));
+                Ok(self.find.as_ref().map(|&(ref q, _)| Value::String(q.clone()))
+                   .unwrap_or(Value::Null))
+            }
+            GetBufferInfo => Ok(serde_json::to_value(self.buffer_info()).unwrap()),
+            Cut | PreviewReplace { .. } | ReplaceAll { .. } |
+            TransformSelectionWithExpr { .. } => {
+                Err(Message::new(ids::view_cant_be_edited).into_error(READONLY, None))
+            }
+            other => {
+                Err(Message::new(ids::view_only_unavailable)
+                    .param("command", format!("{:?}", other))
+                    .into_error(READONLY, None))
+            }
+        }
+    }
+
+    fn set_caret(&mut self, offset: usize) {
+        self.sel = (offset, offset);
+        let line = self.file.line_of_offset(offset);
+        let col = offset - self.file.offset_of_line(line).unwrap_or(offset);
+        self.doc_ctx.scroll_to(&self.view_id, &ScrollPosition::unwrapped(line, col), false);
+    }
+
+    fn offset_of_line_col(&self, line: usize, col: usize) -> Option<usize> {
+        self.file.line_range(line).map(|(start, end)| min(start + col, end))
+    }
+
+    fn find_next(&mut self, forward: bool, wrap_around: bool, allow_same: bool) {
+        let (query, case_sensitive) = match self.find {
+            Some((ref query, case_sensitive)) => (query.clone(), case_sensitive),
+            None => return,
+        };
+        let (start, end) = (min(self.sel.0, self.sel.1), max(self.sel.0, self.sel.1));
+        let from = match (forward, allow_same) {
+            (true, true) => start,
+            (true, false) => if start == end { end } else { start + 1 },
+            (false, _) => end.saturating_sub(1).max(start),
+        };
+        let found = self.file.find(&query, case_sensitive, from, forward).or_else(|| {
+            if !wrap_around {
+                None
+            } else if forward {
+                self.file.find(&query, case_sensitive, 0, true)
+            } else {
+                self.file.find(&query, case_sensitive, self.file.len(), false)
+            }
+        });
+        if let Some((match_start, match_end)) = found {
+            self.set_caret(match_end);
+            self.sel.0 = match_start;
+        }
+    }
+
+    /// Sends the lines the front-end is showing, which are all invalidated
+    /// as the number of lines grows while the file is indexed.
+    pub fn render(&mut self) {
+        if !self.sent_buffer_info {
+            self.doc_ctx.buffer_info(&self.view_id, &self.buffer_info());
+            self.sent_buffer_info = true;
+        }
+        let height = self.file.n_lines();
+        let first_line = min(self.first_line.saturating_sub(SCROLL_SLOP), height);
+        let last_line = min(self.last_line + SCROLL_SLOP, height);
+        let (sel_start, sel_end) = (min(self.sel.0, self.sel.1), max(self.sel.0, self.sel.1));
+
+        let mut ops = Vec::new();
+        if first_line > 0 {
+            ops.push(json!({"op": "invalidate", "n": first_line}));
+        }
+        let lines = (first_line..last_line).map(|line| {
+            let (start, end) = self.file.line_range(line).unwrap();
+            let text = self.file.line(line).unwrap();
+            let mut rendered = json!({"text": text});
+            // columns are in the displayed text, which may be cut or have
+            // bytes replaced
+            let col = |offset: usize| {
+                self.file.slice(start, min(offset, start + MAX_LINE_LEN)).len()
+            };
+            if start <= self.sel.1 && self.sel.1 < end ||
+                self.sel.1 == end && end == self.file.len() {
+                rendered["cursor"] = json!([col(self.sel.1)]);
+            }
+            if sel_start < end && sel_end > start {
+                let (a, b) = (col(max(sel_start, start)), col(min(sel_end, end)));
+                rendered["styles"] = json!([a, b - a, 0]);
+            }
+            rendered
+        }).collect::<Vec<_>>();
+        ops.push(json!({"op": "ins", "n": lines.len(), "lines": lines}));
+        if last_line < height {
+            ops.push(json!({"op": "invalidate", "n": height - last_line}));
+        }
+        self.doc_ctx.update_view(&self.view_id, &json!({
+            "ops": ops,
+            "pristine": true,
+        }));
+    }
+}
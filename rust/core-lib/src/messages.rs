@@ -0,0 +1,218 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! The catalog of the messages core shows the user, each with a stable id
+//! and structured parameters, so that front-ends can translate them, and
+//! the English text of each, for front-ends which don't.
+
+use serde_json::{Map, Value};
+
+use xi_rpc::RemoteError;
+
+use suggest::did_you_mean;
+
+macro_rules! catalog {
+    ($($id:ident: $template:expr,)*) => {
+        /// The ids of the messages. Each is a constant, so that the same id
+        /// can't be given to two messages.
+        #[allow(non_upper_case_globals)]
+        pub mod ids {
+            $(pub const $id: &str = stringify!($id);)*
+        }
+
+        /// The id of every message, and the template of its English text,
+        /// in which `{name}` is replaced by the parameter `name`.
+        pub const CATALOG: &[(&str, &str)] = &[$((stringify!($id), $template)),*];
+    }
+}
+
+catalog! {
+    safe_mode: "xi-core is running in safe mode, without your config or any plugins.",
+    config_changes_invalid: "invalid config changes {changes}: {error}",
+    file_loaded_into_memory: "{path} is {megabytes} MB, all of which is now loaded into memory",
+    file_will_be_created: "{path} doesn't exist, and will be created when saved.",
+    file_open_failed: "can't open {path}: {error}.",
+    file_unreadable: "the file can't be read",
+    file_truncated: "{path} was truncated, and has been reloaded",
+    file_reload_failed: "can't reload {path}: {error}",
+    view_cant_be_saved: "readonly: this view can't be saved",
+    view_cant_be_edited: "readonly: this view can't be edited",
+    view_only_unavailable: "not available in view-only mode: {command}",
+    unknown_language: "unknown language '{language_id}'",
+    paste_suspicious: "The pasted text contains suspicious characters.",
+    paste_strip: "The pasted text contains suspicious characters. Strip them before pasting?",
+    surround_failed: "can't surround: {reason}",
+    change_surround_failed: "can't change surround: {reason}",
+    brackets_unmatched: "can't match brackets: {reason}",
+    replace_token_unknown: "unknown replace token {token}",
+    replace_preview_stale: "buffer changed since the replacements were previewed",
+    replace_needs_query: "replace_all needs a token or a query",
+    expr_invalid: "invalid expression at {position}: {reason}",
+    not_a_word: "not a word: \"{word}\"",
+    no_project: "an untitled buffer has no project",
+    no_extra_words_file: "spellcheck.extra_words_file isn't set",
+    dictionary_write_failed: "can't add to {path}: {error}",
+    undo_history_lost: "the undo history spilled to disk was lost: {error}",
+    internal_error: "xi-core recovered from an internal error at {location}, which was \
+                     recorded as incident {id}.",
+    plugin_message: "{msg}",
+    plugin_crashed: "The plugin {plugin} crashed.",
+    plugin_suspended: "The plugin {plugin} has been suspended, for using {used} {resource}. \
+                       It can be restarted with resume_plugin.",
+    plugin_command_unknown: "The plugin {plugin} has no command '{command}'.",
+    plugin_command_not_running: "The plugin {plugin} for the command '{command}' isn't running.",
+}
+
+/// A message for the user. It is sent to front-ends as its `id`, its
+/// `params`, and its `fallback_text`, the English text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    id: &'static str,
+    params: Map<String, Value>,
+    fallback_text: Option<String>,
+}
+
+impl Message {
+    /// Creates the message `id`, which must be one of `ids`.
+    pub fn new(id: &'static str) -> Message {
+        debug_assert!(template(id).is_some(), "no message {}", id);
+        Message { id: id, params: Map::new(), fallback_text: None }
+    }
+
+    /// Adds the parameter `name`. Numbers should be given as numbers, not
+    /// formatted, so that front-ends can format them for the user's locale.
+    pub fn param<V: Into<Value>>(mut self, name: &str, value: V) -> Message {
+        self.params.insert(name.to_owned(), value.into());
+        self
+    }
+
+    /// Adds paths, or names, which the user may have meant, which are
+    /// offered after the message's text.
+    pub fn suggestions<S: AsRef<str>>(self, suggestions: &[S]) -> Message {
+        let suggestions = suggestions.iter().map(|s| s.as_ref()).collect::<Vec<_>>();
+        self.param("suggestions", suggestions)
+    }
+
+    /// Sets the English text, for a message whose template can't express
+    /// it, as when it lists counts.
+    pub fn with_fallback_text(mut self, text: String) -> Message {
+        self.fallback_text = Some(text);
+        self
+    }
+
+    pub fn id(&self) -> &'static str {
+        self.id
+    }
+
+    pub fn params(&self) -> &Map<String, Value> {
+        &self.params
+    }
+
+    /// Returns the English text of the message.
+    pub fn fallback_text(&self) -> String {
+        if let Some(ref text) = self.fallback_text {
+            return text.clone();
+        }
+        let mut text = template(self.id).unwrap_or(self.id).to_owned();
+        for (name, value) in self.params.iter() {
+            let value = match *value {
+                Value::String(ref s) => s.clone(),
+                ref other => other.to_string(),
+            };
+            text = text.replace(&format!("{{{}}}", name), &value);
+        }
+        if let Some(&Value::Array(ref suggestions)) = self.params.get("suggestions") {
+            let suggestions = suggestions.iter().filter_map(Value::as_str).collect::<Vec<_>>();
+            text.push_str(&did_you_mean(&suggestions));
+        }
+        text
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "params": self.params,
+            "fallback_text": self.fallback_text(),
+        })
+    }
+
+    /// Returns an error with the message's English text, and the message
+    /// as `message` in its data, along with `data`'s fields.
+    pub fn into_error(self, code: i64, data: Option<Value>) -> RemoteError {
+        let mut data = match data {
+            Some(Value::Object(fields)) => fields,
+            _ => Map::new(),
+        };
+        data.insert("message".to_owned(), self.to_json());
+        RemoteError::custom(code, self.fallback_text(), Some(Value::Object(data)))
+    }
+}
+
+/// Returns the template of the message `id`.
+pub fn template(id: &str) -> Option<&'static str> {
+    CATALOG.iter().find(|&&(other, _)| other == id).map(|&(_, template)| template)
+}
+
+/// Returns the parameters named in `template`.
+pub fn template_params(template: &str) -> Vec<&str> {
+    template.split('{').skip(1).filter_map(|s| s.split('}').next()).collect()
+}
+
+/// Describes every message, for translators.
+pub fn list_message_ids() -> Value {
+    let messages = CATALOG.iter().map(|&(id, template)| json!({
+        "id": id,
+        "params": template_params(template),
+        "fallback_text": template,
+    })).collect::<Vec<_>>();
+    Value::Array(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates() {
+        let msg = Message::new(ids::file_loaded_into_memory)
+            .param("path", "/tmp/big.log")
+            .param("megabytes", 300);
+        assert_eq!(msg.fallback_text(),
+                   "/tmp/big.log is 300 MB, all of which is now loaded into memory");
+        assert_eq!(msg.to_json()["params"], json!({"path": "/tmp/big.log", "megabytes": 300}));
+        let msg = Message::new(ids::file_will_be_created).param("path", "a.txt")
+            .suggestions(&["b.txt"]);
+        assert_eq!(msg.fallback_text(),
+                   "a.txt doesn't exist, and will be created when saved. Did you mean 'b.txt'?");
+        assert_eq!(template_params(template(ids::plugin_suspended).unwrap()),
+                   vec!["plugin", "used", "resource"]);
+    }
+
+    #[test]
+    fn catalog_is_structured() {
+        for &(id, template) in CATALOG {
+            assert!(id.chars().all(|c| c.is_ascii_lowercase() || c == '_'),
+                    "{} isn't a plain id", id);
+            // numbers are parameters, so that they can be formatted
+            assert!(!template.chars().any(|c| c.is_ascii_digit()), "{} has a number", id);
+            for param in template_params(template) {
+                assert!(!param.is_empty() && param.chars().all(|c| c.is_ascii_lowercase() ||
+                                                              c == '_'),
+                        "{} has a bad parameter {:?}", id, param);
+            }
+        }
+        assert_eq!(list_message_ids().as_array().unwrap().len(), CATALOG.len());
+    }
+}
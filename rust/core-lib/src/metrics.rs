@@ -0,0 +1,269 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Document-wide metrics, maintained incrementally as the buffer is edited.
+
+use xi_rope::rope::{LinesMetric, Rope, RopeInfo};
+use xi_rope::delta::Delta;
+
+/// The number of longest lines we keep track of.
+const TOP_K: usize = 8;
+
+/// The number of lines examined by each step of a recount.
+const RECOUNT_CHUNK: usize = 10_000;
+
+/// Tracks the length of the longest line in a buffer.
+///
+/// We keep the `TOP_K` longest lines, so that deleting the longest line
+/// doesn't usually require rescanning the buffer. If enough of them are
+/// deleted that we can no longer be sure which line is longest, we report
+/// the longest known line (a lower bound) and rescan the buffer a chunk
+/// at a time, through `recount_step`.
+pub struct BufferMetrics {
+    /// (length, line number) pairs, longest first.
+    top: Vec<(usize, usize)>,
+    /// No line absent from `top` is longer than this.
+    floor: usize,
+    /// The number of lines in the buffer.
+    n_lines: usize,
+    /// If a recount is in progress, the next line to be examined.
+    recount: Option<usize>,
+}
+
+impl BufferMetrics {
+    pub fn new(text: &Rope) -> Self {
+        let mut metrics = BufferMetrics {
+            top: Vec::new(),
+            floor: 0,
+            n_lines: text.measure::<LinesMetric>() + 1,
+            recount: Some(0),
+        };
+        while metrics.is_recounting() {
+            metrics.recount_step(text);
+        }
+        metrics
+    }
+
+    /// Returns the length, in bytes, of the longest line, not including its
+    /// line ending. If a recount is in progress this may be an underestimate.
+    pub fn longest_line(&self) -> usize {
+        self.top.first().map(|&(len, _)| len).unwrap_or(0)
+    }
+
+    /// Returns the line number of the longest line.
+    pub fn longest_line_number(&self) -> usize {
+        self.top.first().map(|&(_, line)| line).unwrap_or(0)
+    }
+
+    /// Returns `true` if `longest_line` may currently be an underestimate.
+    pub fn is_recounting(&self) -> bool {
+        self.recount.is_some()
+    }
+
+    /// Updates the metrics after `delta` has been applied, producing `text`.
+    pub fn after_edit(&mut self, text: &Rope, delta: &Delta<RopeInfo>) {
+        let (iv, new_len) = delta.summary();
+        let n_lines = text.measure::<LinesMetric>() + 1;
+        let first = text.line_of_offset(iv.start());
+        let new_last = text.line_of_offset(iv.start() + new_len);
+        let n_tail = n_lines - new_last - 1;
+        if self.n_lines < n_tail + 1 || self.n_lines - n_tail - 1 < first {
+            *self = BufferMetrics::new(text);
+            return;
+        }
+        let old_last = self.n_lines - n_tail - 1;
+        self.n_lines = n_lines;
+
+        self.top.retain(|&(_, line)| line < first || line > old_last);
+        for entry in self.top.iter_mut() {
+            if entry.1 > old_last {
+                entry.1 = entry.1 + new_last - old_last;
+            }
+        }
+        for line in first..new_last + 1 {
+            let len = line_len(text, line);
+            self.insert(line, len);
+        }
+
+        if self.recount.is_some() {
+            // line numbers have shifted; start again.
+            self.recount = Some(0);
+        } else if self.longest_line() < self.floor {
+            self.recount = Some(0);
+        }
+    }
+
+    /// Examines the next chunk of lines of an in-progress recount.
+    pub fn recount_step(&mut self, text: &Rope) {
+        let start = match self.recount {
+            Some(start) => start,
+            None => return,
+        };
+        if start == 0 {
+            self.top.clear();
+            self.floor = 0;
+        }
+        let end = ::std::cmp::min(start + RECOUNT_CHUNK, self.n_lines);
+        for line in start..end {
+            let len = line_len(text, line);
+            self.insert(line, len);
+        }
+        self.recount = if end < self.n_lines { Some(end) } else { None };
+    }
+
+    fn insert(&mut self, line: usize, len: usize) {
+        if self.top.len() == TOP_K && len <= self.top[TOP_K - 1].0 {
+            self.floor = ::std::cmp::max(self.floor, len);
+            return;
+        }
+        let pos = self.top.iter().position(|&(l, _)| l < len).unwrap_or(self.top.len());
+        self.top.insert(pos, (len, line));
+        if self.top.len() > TOP_K {
+            let (dropped, _) = self.top.pop().unwrap();
+            self.floor = ::std::cmp::max(self.floor, dropped);
+        }
+    }
+}
+
+/// The length of `line`, not including any line ending.
+pub fn line_len(text: &Rope, line: usize) -> usize {
+    let start = text.offset_of_line(line);
+    let mut end = text.offset_of_line(line + 1);
+    if end > start && text.byte_at(end - 1) == b'\n' {
+        end -= 1;
+        if end > start && text.byte_at(end - 1) == b'\r' {
+            end -= 1;
+        }
+    }
+    end - start
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xi_rope::interval::Interval;
+
+    fn edit(text: &mut Rope, start: usize, end: usize, new: &str) -> Delta<RopeInfo> {
+        let iv = Interval::new_closed_open(start, end);
+        let delta = Delta::simple_edit(iv, Rope::from(new), text.len());
+        *text = delta.apply(text);
+        delta
+    }
+
+    fn brute_force(text: &Rope) -> usize {
+        String::from(text).lines().map(|l| l.len()).max().unwrap_or(0)
+    }
+
+    fn settle(metrics: &mut BufferMetrics, text: &Rope) {
+        while metrics.is_recounting() {
+            metrics.recount_step(text);
+        }
+    }
+
+    #[test]
+    fn initial() {
+        let text = Rope::from("a\nabcd\r\nabc\n");
+        let metrics = BufferMetrics::new(&text);
+        assert_eq!(metrics.longest_line(), 4);
+        assert_eq!(metrics.longest_line_number(), 1);
+        assert_eq!(BufferMetrics::new(&Rope::from("")).longest_line(), 0);
+    }
+
+    #[test]
+    fn grow_and_shrink() {
+        let mut text = Rope::from("ab\nabc\na\n");
+        let mut metrics = BufferMetrics::new(&text);
+        let delta = edit(&mut text, 0, 0, "xxxx");
+        metrics.after_edit(&text, &delta);
+        assert_eq!(metrics.longest_line(), 6);
+        let delta = edit(&mut text, 0, 5, "");
+        metrics.after_edit(&text, &delta);
+        assert!(!metrics.is_recounting());
+        assert_eq!(metrics.longest_line(), 3);
+        assert_eq!(metrics.longest_line_number(), 1);
+    }
+
+    #[test]
+    fn delete_longest_without_rescan() {
+        // fewer lines than TOP_K: every line is tracked.
+        let mut text = Rope::from("a\nabc\nabcdef\nab\n");
+        let mut metrics = BufferMetrics::new(&text);
+        let start = text.offset_of_line(2);
+        let end = text.offset_of_line(3);
+        let delta = edit(&mut text, start, end, "");
+        metrics.after_edit(&text, &delta);
+        assert!(!metrics.is_recounting());
+        assert_eq!(metrics.longest_line(), 3);
+    }
+
+    #[test]
+    fn adversarial_deletes() {
+        let mut s = String::new();
+        for i in 0..200 {
+            s.push_str(&"x".repeat((i * 37) % 101));
+            s.push('\n');
+        }
+        let mut text = Rope::from(s);
+        let mut metrics = BufferMetrics::new(&text);
+        for round in 0..60 {
+            // delete the current longest line
+            let line = metrics.longest_line_number();
+            let start = text.offset_of_line(line);
+            let end = text.offset_of_line(line + 1);
+            let delta = edit(&mut text, start, end, "");
+            metrics.after_edit(&text, &delta);
+            assert!(metrics.longest_line() <= brute_force(&text));
+            settle(&mut metrics, &text);
+            assert_eq!(metrics.longest_line(), brute_force(&text));
+
+            // and every few rounds, recreate a long line somewhere else
+            if round % 3 == 0 {
+                let offset = text.offset_of_line((round * 7) % 150);
+                let delta = edit(&mut text, offset, offset, &format!("{}\n", "y".repeat(100)));
+                metrics.after_edit(&text, &delta);
+                assert!(!metrics.is_recounting());
+                assert_eq!(metrics.longest_line(), brute_force(&text));
+            }
+        }
+    }
+
+    #[test]
+    fn edit_during_recount() {
+        let mut s = String::new();
+        for i in 0..(TOP_K + 1) {
+            s.push_str(&"z".repeat(100 + i));
+            s.push('\n');
+        }
+        for i in 0..(RECOUNT_CHUNK * 2) {
+            s.push_str(&"x".repeat(i % 20));
+            s.push('\n');
+        }
+        let mut text = Rope::from(s);
+        let mut metrics = BufferMetrics::new(&text);
+        assert_eq!(metrics.longest_line(), 100 + TOP_K);
+        // delete all the tracked lines, leaving the untracked line 0
+        let start = text.offset_of_line(1);
+        let end = text.offset_of_line(TOP_K + 1);
+        let delta = edit(&mut text, start, end, "");
+        metrics.after_edit(&text, &delta);
+        assert!(metrics.is_recounting());
+        assert!(metrics.longest_line() <= 100);
+        metrics.recount_step(&text);
+        let delta = edit(&mut text, 0, 0, "\n\n");
+        metrics.after_edit(&text, &delta);
+        settle(&mut metrics, &text);
+        assert_eq!(metrics.longest_line(), 100);
+        assert_eq!(metrics.longest_line_number(), 2);
+    }
+}
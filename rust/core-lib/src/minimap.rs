@@ -0,0 +1,306 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compact summaries of buckets of lines, from which front-ends draw a
+//! minimap without requesting every line.
+//!
+//! Summaries are computed when requested, and kept until an edit or a
+//! change of styles touches their bucket.
+
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use xi_rope::rope::{LinesMetric, Rope, RopeInfo};
+use xi_rope::delta::Delta;
+use xi_rope::interval::Interval;
+use xi_rope::spans::Spans;
+
+use styles::Style;
+
+/// The most buckets a minimap is divided into; more lines per bucket are
+/// used than requested if needed, which bounds the size of the summary.
+const MAX_BUCKETS: usize = 1024;
+
+/// The number of indentation levels counted separately; deeper lines are
+/// counted with the last.
+const INDENT_LEVELS: usize = 8;
+
+/// The number of columns per indentation level, and per tab.
+const INDENT_WIDTH: usize = 4;
+
+/// The number of segments of each bucket given a dominant style.
+const SEGMENTS: usize = 4;
+
+/// The summary of one bucket of lines.
+#[derive(Debug, Clone, PartialEq)]
+struct Bucket {
+    /// The number of non-blank lines at each indentation level.
+    indent: [usize; INDENT_LEVELS],
+    /// The mean number of non-whitespace characters per line.
+    density: usize,
+    /// The id of the style covering the most text in each segment, if any.
+    styles: Vec<Option<usize>>,
+}
+
+impl Bucket {
+    fn to_json(&self) -> Value {
+        json!({
+            "indent": self.indent,
+            "density": self.density,
+            "styles": self.styles,
+        })
+    }
+}
+
+pub struct Minimap {
+    /// The number of lines per bucket the front-end asked for.
+    requested: usize,
+    lines_per_bucket: usize,
+    n_lines: usize,
+    buckets: Vec<Option<Bucket>>,
+    /// The buckets invalidated since the front-end was last told.
+    changed: Option<(usize, usize)>,
+}
+
+fn n_lines(text: &Rope) -> usize {
+    text.measure::<LinesMetric>() + 1
+}
+
+impl Minimap {
+    pub fn new(text: &Rope, lines_per_bucket: usize) -> Self {
+        let n_lines = n_lines(text);
+        let min_lines_per_bucket = (n_lines + MAX_BUCKETS - 1) / MAX_BUCKETS;
+        let mut minimap = Minimap {
+            requested: lines_per_bucket,
+            lines_per_bucket: lines_per_bucket.max(min_lines_per_bucket).max(1),
+            n_lines: n_lines,
+            buckets: Vec::new(),
+            changed: None,
+        };
+        let n_buckets = minimap.n_buckets();
+        minimap.buckets.resize(n_buckets, None);
+        minimap
+    }
+
+    /// The number of lines per bucket the minimap was created with.
+    pub fn requested(&self) -> usize {
+        self.requested
+    }
+
+    pub fn n_buckets(&self) -> usize {
+        (self.n_lines + self.lines_per_bucket - 1) / self.lines_per_bucket
+    }
+
+    /// Returns the summaries of buckets `first..last`, computing those which
+    /// aren't cached. `style_id` is the id of a style, as sent to the
+    /// front-end.
+    pub fn get<F>(&mut self, text: &Rope, style_spans: &Spans<Style>, style_id: F,
+                  first: usize, last: usize) -> Value
+        where F: Fn(&Style) -> usize
+    {
+        let last = last.min(self.buckets.len());
+        let first = first.min(last);
+        let mut buckets = Vec::with_capacity(last - first);
+        for ix in first..last {
+            if self.buckets[ix].is_none() {
+                self.buckets[ix] = Some(self.summarize(text, style_spans, &style_id, ix));
+            }
+            buckets.push(self.buckets[ix].as_ref().unwrap().to_json());
+        }
+        json!({
+            "lines_per_bucket": self.lines_per_bucket,
+            "n_buckets": self.buckets.len(),
+            "first_bucket": first,
+            "buckets": buckets,
+        })
+    }
+
+    fn summarize<F>(&self, text: &Rope, style_spans: &Spans<Style>, style_id: &F,
+                    ix: usize) -> Bucket
+        where F: Fn(&Style) -> usize
+    {
+        let first_line = ix * self.lines_per_bucket;
+        let end_line = (first_line + self.lines_per_bucket).min(self.n_lines);
+        let line_offset = |line: usize| if line >= self.n_lines { text.len() } else {
+            text.offset_of_line(line)
+        };
+
+        let mut indent = [0; INDENT_LEVELS];
+        let mut non_whitespace = 0;
+        for line in text.lines_raw(line_offset(first_line), line_offset(end_line)) {
+            let mut columns = 0;
+            let mut blank = true;
+            for c in line.chars() {
+                match c {
+                    ' ' if blank => columns += 1,
+                    '\t' if blank => columns += INDENT_WIDTH,
+                    c if c.is_whitespace() => (),
+                    _ => {
+                        blank = false;
+                        non_whitespace += 1;
+                    }
+                }
+            }
+            if !blank {
+                indent[(columns / INDENT_WIDTH).min(INDENT_LEVELS - 1)] += 1;
+            }
+        }
+        let n_lines = end_line - first_line;
+
+        let n_segments = SEGMENTS.min(n_lines);
+        let styles = (0..n_segments).map(|segment| {
+            let start = line_offset(first_line + segment * n_lines / n_segments);
+            let end = line_offset(first_line + (segment + 1) * n_lines / n_segments);
+            let mut coverage = BTreeMap::new();
+            let spans = style_spans.subseq(Interval::new_closed_open(start, end));
+            for (iv, style) in spans.iter() {
+                *coverage.entry(style_id(style)).or_insert(0) += iv.size();
+            }
+            coverage.iter().max_by_key(|&(_, &size)| size).map(|(&id, _)| id)
+        }).collect();
+
+        Bucket {
+            indent: indent,
+            density: (non_whitespace + n_lines / 2) / n_lines,
+            styles: styles,
+        }
+    }
+
+    fn invalidate(&mut self, first: usize, last: usize) {
+        let last = last.min(self.buckets.len());
+        if first >= last {
+            return;
+        }
+        for bucket in self.buckets[first..last].iter_mut() {
+            *bucket = None;
+        }
+        self.changed = match self.changed {
+            Some((start, end)) => Some((start.min(first), end.max(last))),
+            None => Some((first, last)),
+        };
+    }
+
+    /// Invalidates the buckets touched by `delta`, producing `text`. If the
+    /// number of lines changes, every bucket after the edit is invalidated,
+    /// as their lines have moved.
+    pub fn after_edit(&mut self, text: &Rope, delta: &Delta<RopeInfo>) {
+        let (iv, new_len) = delta.summary();
+        let n_lines = n_lines(text);
+        let mut first = text.line_of_offset(iv.start()) / self.lines_per_bucket;
+        let last = if n_lines == self.n_lines {
+            text.line_of_offset(iv.start() + new_len) / self.lines_per_bucket + 1
+        } else {
+            self.n_lines = n_lines;
+            let n_buckets = self.n_buckets();
+            self.buckets.resize(n_buckets, None);
+            first = first.min(n_buckets - 1);
+            n_buckets
+        };
+        self.invalidate(first, last);
+    }
+
+    /// Invalidates the buckets whose styles have changed within `iv`.
+    pub fn styles_changed(&mut self, text: &Rope, iv: Interval) {
+        let first = text.line_of_offset(iv.start()) / self.lines_per_bucket;
+        let last = text.line_of_offset(iv.end().min(text.len())) / self.lines_per_bucket + 1;
+        self.invalidate(first, last);
+    }
+
+    /// Returns the buckets invalidated since this was last called, if any.
+    pub fn take_changed(&mut self) -> Option<(usize, usize)> {
+        self.changed.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xi_rope::spans::SpansBuilder;
+
+    fn text(n_lines: usize) -> Rope {
+        Rope::from((0..n_lines).map(|i| format!("{}line {}\n", " ".repeat(i % 3 * 4), i))
+            .collect::<String>())
+    }
+
+    fn cached(minimap: &Minimap) -> Vec<usize> {
+        (0..minimap.buckets.len()).filter(|&ix| minimap.buckets[ix].is_some()).collect()
+    }
+
+    fn edit(minimap: &mut Minimap, text: &mut Rope, start: usize, end: usize, new: &str) {
+        let delta = Delta::simple_edit(Interval::new_closed_open(start, end),
+                                       Rope::from(new), text.len());
+        *text = delta.apply(text);
+        minimap.after_edit(text, &delta);
+    }
+
+    #[test]
+    fn summaries() {
+        let text = text(10);
+        let mut builder = SpansBuilder::new(text.len());
+        builder.add_span(Interval::new_closed_open(0, 3), Style::default());
+        let spans = builder.build();
+        let mut minimap = Minimap::new(&text, 4);
+        let result = minimap.get(&text, &spans, |_| 7, 0, 10);
+        assert_eq!(result["n_buckets"], json!(3));
+        assert_eq!(result["buckets"][0], json!({
+            "indent": [2, 1, 1, 0, 0, 0, 0, 0],
+            "density": 5,
+            "styles": [7, null, null, null],
+        }));
+        // the last bucket has the empty last line, which is blank
+        assert_eq!(result["buckets"][2]["indent"], json!([1, 0, 1, 0, 0, 0, 0, 0]));
+        assert_eq!(result["buckets"][2]["styles"].as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn invalidation() {
+        let mut text = text(100);
+        let spans = Spans::default();
+        let mut minimap = Minimap::new(&text, 10);
+        minimap.get(&text, &spans, |_| 0, 0, 20);
+        assert_eq!(minimap.n_buckets(), 11);
+        assert_eq!(minimap.take_changed(), None);
+
+        // editing within a line invalidates its bucket
+        let offset = text.offset_of_line(35);
+        edit(&mut minimap, &mut text, offset, offset + 1, "xyz");
+        assert_eq!(minimap.take_changed(), Some((3, 4)));
+        assert_eq!(cached(&minimap), vec![0, 1, 2, 4, 5, 6, 7, 8, 9, 10]);
+
+        // as does joining two lines of the same bucket, and the buckets after
+        minimap.get(&text, &spans, |_| 0, 0, 20);
+        let offset = text.offset_of_line(56) - 1;
+        edit(&mut minimap, &mut text, offset, offset + 1, "");
+        assert_eq!(minimap.take_changed(), Some((5, 10)));
+        assert_eq!(cached(&minimap), vec![0, 1, 2, 3, 4]);
+        assert_eq!(minimap.n_buckets(), 10);
+
+        // styles touching two buckets
+        minimap.get(&text, &spans, |_| 0, 0, 20);
+        let iv = Interval::new_closed_open(text.offset_of_line(19), text.offset_of_line(20) + 1);
+        minimap.styles_changed(&text, iv);
+        assert_eq!(minimap.take_changed(), Some((1, 3)));
+    }
+
+    #[test]
+    fn bounded() {
+        let text = Rope::from("    foo();\n".repeat(1_000_000));
+        let mut minimap = Minimap::new(&text, 1);
+        let result = minimap.get(&text, &Spans::default(), |_| 0, 0, usize::max_value());
+        assert!(result["lines_per_bucket"].as_u64().unwrap() >= 977);
+        assert!(result["n_buckets"].as_u64().unwrap() <= MAX_BUCKETS as u64);
+        assert!(result.to_string().len() < 100_000);
+    }
+}
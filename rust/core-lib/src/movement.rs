@@ -18,7 +18,7 @@ use std::cmp::max;
 
 use selection::{Affinity, HorizPos, Selection, SelRegion};
 use view::View;
-use word_boundaries::WordCursor;
+use word_boundaries::{Segmentation, WordCursor};
 use xi_rope::rope::{LinesMetric, Rope};
 use xi_rope::tree::Cursor;
 
@@ -107,8 +107,10 @@ fn scroll_height(view: &View) -> isize {
 }
 
 /// Compute the result of movement on one selection region.
-pub fn region_movement(m: Movement, r: &SelRegion, view: &View, text: &Rope, modify: bool)
-    -> SelRegion
+///
+/// Word movements use `words` to find the words within runs of CJK text.
+pub fn region_movement(m: Movement, r: &SelRegion, view: &View, text: &Rope,
+                       words: &Segmentation, modify: bool) -> SelRegion
 {
     let (offset, horiz) = match m {
         Movement::Left => {
@@ -134,12 +136,12 @@ pub fn region_movement(m: Movement, r: &SelRegion, view: &View, text: &Rope, mod
             }
         }
         Movement::LeftWord => {
-            let mut word_cursor = WordCursor::new(text, r.end);
+            let mut word_cursor = WordCursor::with_segmentation(text, r.end, words);
             let offset = word_cursor.prev_boundary().unwrap_or(0);
             (offset, None)
         }
         Movement::RightWord => {
-            let mut word_cursor = WordCursor::new(text, r.end);
+            let mut word_cursor = WordCursor::with_segmentation(text, r.end, words);
             let offset = word_cursor.next_boundary().unwrap_or_else(|| text.len());
             (offset, None)
         }
@@ -221,11 +223,11 @@ pub fn region_movement(m: Movement, r: &SelRegion, view: &View, text: &Rope, mod
 /// If `modify` is `true`, the selections are modified, otherwise the results
 /// of individual region movements become carets.
 pub fn selection_movement(m: Movement, s: &Selection, view: &View, text: &Rope,
-    modify: bool) -> Selection
+    words: &Segmentation, modify: bool) -> Selection
 {
     let mut result = Selection::new();
     for r in s.iter() {
-        let new_region = region_movement(m, r, view, text, modify);
+        let new_region = region_movement(m, r, view, text, words, modify);
         result.add_region(new_region);
     }
     result
@@ -0,0 +1,565 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A small backtracking regular expression matcher, for the `replace`
+//! function of selection transforms.
+//!
+//! Patterns have literals, `.`, classes such as `[a-z]` and `[^,]`, the
+//! escapes `\d`, `\w`, `\s` and their negations, the anchors `^`, `$` and
+//! `\b`, groups, `(?:...)` groups which don't capture, alternation, and
+//! the quantifiers `*`, `+`, `?` and `{m,n}`, which are lazy if followed
+//! by `?`. Matching gives up after `MAX_STEPS` steps, so that a pattern
+//! can't take forever to fail.
+
+/// The most steps a search may take.
+const MAX_STEPS: usize = 1_000_000;
+
+/// The most nested matches a search may be in, as of groups with
+/// quantifiers, which each match by recursion.
+const MAX_DEPTH: usize = 500;
+
+/// The most a quantifier may repeat, or groups a pattern may nest.
+const MAX_COUNT: usize = 1_000;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Empty,
+    Char(char),
+    /// Any character but a newline.
+    Any,
+    Class { ranges: Vec<(char, char)>, negated: bool },
+    Start,
+    End,
+    WordBoundary,
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat { node: Box<Node>, min: usize, max: Option<usize>, greedy: bool },
+}
+
+impl Node {
+    /// Returns true if the node always matches a single character.
+    fn is_single_char(&self) -> bool {
+        match *self {
+            Node::Char(_) | Node::Any | Node::Class { .. } => true,
+            _ => false,
+        }
+    }
+
+    fn matches_char(&self, c: char) -> bool {
+        match *self {
+            Node::Char(expected) => c == expected,
+            Node::Any => c != '\n',
+            Node::Class { ref ranges, negated } => {
+                ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != negated
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A compiled pattern.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Regex {
+    root: Node,
+    n_groups: usize,
+}
+
+/// A match of a pattern, as the char offsets of the match and of each of
+/// its groups.
+type Captures = Vec<Option<(usize, usize)>>;
+
+impl Regex {
+    /// Compiles `pattern`, or returns why it is invalid and the char
+    /// offset at which that was found.
+    pub fn new(pattern: &str) -> Result<Regex, (usize, String)> {
+        let mut parser = Parser { chars: pattern.chars().collect(), pos: 0, n_groups: 0 };
+        let root = parser.alternation(0)?;
+        if parser.pos < parser.chars.len() {
+            return Err((parser.pos, "unmatched ')'".to_owned()));
+        }
+        Ok(Regex { root: root, n_groups: parser.n_groups })
+    }
+
+    /// Returns the first match starting at or after `start`, or `None`.
+    fn find_at(&self, text: &[char], start: usize) -> Result<Option<Captures>, String> {
+        let mut matcher = Matcher {
+            text: text,
+            caps: vec![None; self.n_groups + 1],
+            steps: 0,
+            depth: 0,
+        };
+        for pos in start..text.len() + 1 {
+            let mut end = None;
+            let found = matcher.node(&self.root, pos, &mut |_, p| {
+                end = Some(p);
+                true
+            });
+            if matcher.steps > MAX_STEPS || matcher.depth > MAX_DEPTH {
+                return Err("the pattern is too complex to match".to_owned());
+            }
+            if found {
+                let mut caps = matcher.caps.clone();
+                caps[0] = Some((pos, end.unwrap()));
+                return Ok(Some(caps));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Replaces every non-overlapping match in `text`. In `replacement`,
+    /// `$0` stands for the match, `$1` to `$9` for its groups, and `$$`
+    /// for a `$`.
+    pub fn replace_all(&self, text: &str, replacement: &str) -> Result<String, String> {
+        let chars = text.chars().collect::<Vec<_>>();
+        let mut result = String::with_capacity(text.len());
+        let mut copied = 0;
+        let mut pos = 0;
+        let mut last_end = None;
+        while pos <= chars.len() {
+            let caps = match self.find_at(&chars, pos)? {
+                Some(caps) => caps,
+                None => break,
+            };
+            let (start, end) = caps[0].unwrap();
+            // an empty match where the last match ended is skipped
+            if start == end && last_end == Some(start) {
+                pos = start + 1;
+                continue;
+            }
+            last_end = Some(end);
+            result.extend(&chars[copied..start]);
+            expand(replacement, &chars, &caps, &mut result);
+            copied = end;
+            pos = if end == start { end + 1 } else { end };
+        }
+        result.extend(&chars[copied..]);
+        Ok(result)
+    }
+}
+
+fn expand(replacement: &str, text: &[char], caps: &Captures, result: &mut String) {
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek().cloned()) {
+            ('$', Some('$')) => {
+                chars.next();
+                result.push('$');
+            }
+            ('$', Some(d @ '0'..='9')) => {
+                chars.next();
+                let group = d as usize - '0' as usize;
+                if let Some(&Some((start, end))) = caps.get(group) {
+                    result.extend(&text[start..end]);
+                }
+            }
+            (c, _) => result.push(c),
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    n_groups: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn error<T>(&self, reason: &str) -> Result<T, (usize, String)> {
+        Err((self.pos, reason.to_owned()))
+    }
+
+    fn alternation(&mut self, depth: usize) -> Result<Node, (usize, String)> {
+        if depth > MAX_COUNT {
+            return self.error("groups are nested too deeply");
+        }
+        let mut alternatives = vec![self.concat(depth)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alternatives.push(self.concat(depth)?);
+        }
+        Ok(if alternatives.len() == 1 { alternatives.pop().unwrap() } else {
+            Node::Alt(alternatives)
+        })
+    }
+
+    fn concat(&mut self, depth: usize) -> Result<Node, (usize, String)> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom(depth)?;
+            nodes.push(self.quantified(atom)?);
+        }
+        Ok(match nodes.len() {
+            0 => Node::Empty,
+            1 => nodes.pop().unwrap(),
+            _ => Node::Concat(nodes),
+        })
+    }
+
+    fn atom(&mut self, depth: usize) -> Result<Node, (usize, String)> {
+        let c = self.peek().unwrap();
+        self.pos += 1;
+        Ok(match c {
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '(' => {
+                let group = if self.chars[self.pos..].starts_with(&['?', ':']) {
+                    self.pos += 2;
+                    None
+                } else {
+                    self.n_groups += 1;
+                    Some(self.n_groups)
+                };
+                let inner = self.alternation(depth + 1)?;
+                if self.peek() != Some(')') {
+                    return self.error("unclosed group");
+                }
+                self.pos += 1;
+                Node::Group(Box::new(inner), group)
+            }
+            '[' => self.class()?,
+            '\\' => self.escape(false)?,
+            '*' | '+' | '?' | '{' => {
+                self.pos -= 1;
+                return self.error("nothing to repeat");
+            }
+            c => Node::Char(c),
+        })
+    }
+
+    /// Parses the escape after a `\`, which in a class can't be an anchor.
+    fn escape(&mut self, in_class: bool) -> Result<Node, (usize, String)> {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return self.error("trailing backslash"),
+        };
+        self.pos += 1;
+        let class = |ranges: &[(char, char)], negated| {
+            Node::Class { ranges: ranges.to_vec(), negated: negated }
+        };
+        const DIGIT: &[(char, char)] = &[('0', '9')];
+        const WORD: &[(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+        const SPACE: &[(char, char)] = &[('\t', '\r'), (' ', ' ')];
+        Ok(match c {
+            'd' => class(DIGIT, false),
+            'D' => class(DIGIT, true),
+            'w' => class(WORD, false),
+            'W' => class(WORD, true),
+            's' => class(SPACE, false),
+            'S' => class(SPACE, true),
+            'b' if !in_class => Node::WordBoundary,
+            'n' => Node::Char('\n'),
+            't' => Node::Char('\t'),
+            c if c.is_ascii_alphanumeric() => {
+                self.pos -= 1;
+                return self.error("unknown escape");
+            }
+            c => Node::Char(c),
+        })
+    }
+
+    fn class(&mut self) -> Result<Node, (usize, String)> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return self.error("unclosed class"),
+            };
+            self.pos += 1;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = match c {
+                '\\' => match self.escape(true)? {
+                    Node::Char(c) => c,
+                    Node::Class { ranges: escaped, negated: false } => {
+                        ranges.extend(escaped);
+                        continue;
+                    }
+                    _ => return self.error("negated escapes can't be in a class"),
+                },
+                c => c,
+            };
+            let is_range = self.peek() == Some('-') &&
+                self.chars.get(self.pos + 1).map_or(false, |&c| c != ']');
+            if !is_range {
+                ranges.push((lo, lo));
+                continue;
+            }
+            self.pos += 1;
+            let hi = self.chars[self.pos];
+            self.pos += 1;
+            let hi = if hi == '\\' {
+                match self.escape(true)? {
+                    Node::Char(c) => c,
+                    _ => return self.error("a range can't end with a class"),
+                }
+            } else {
+                hi
+            };
+            if hi < lo {
+                return self.error("the range is out of order");
+            }
+            ranges.push((lo, hi));
+        }
+        Ok(Node::Class { ranges: ranges, negated: negated })
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node, (usize, String)> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => return self.counted(atom),
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        self.repeat(atom, min, max)
+    }
+
+    /// Parses a `{m}`, `{m,}` or `{m,n}` quantifier.
+    fn counted(&mut self, atom: Node) -> Result<Node, (usize, String)> {
+        self.pos += 1;
+        let min = self.number()?;
+        let max = if self.peek() == Some(',') {
+            self.pos += 1;
+            if self.peek() == Some('}') { None } else { Some(self.number()?) }
+        } else {
+            Some(min)
+        };
+        if self.peek() != Some('}') {
+            return self.error("expected '}'");
+        }
+        self.pos += 1;
+        if max.map_or(false, |max| max < min) {
+            return self.error("the repetition is out of order");
+        }
+        self.repeat(atom, min, max)
+    }
+
+    fn number(&mut self) -> Result<usize, (usize, String)> {
+        let start = self.pos;
+        while self.peek().map_or(false, |c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits = self.chars[start..self.pos].iter().collect::<String>();
+        match digits.parse::<usize>() {
+            Ok(n) if n <= MAX_COUNT => Ok(n),
+            Ok(_) => self.error("the repetition is too large"),
+            Err(_) => self.error("expected a number"),
+        }
+    }
+
+    fn repeat(&mut self, atom: Node, min: usize, max: Option<usize>)
+              -> Result<Node, (usize, String)> {
+        match atom {
+            Node::Repeat { .. } | Node::Start | Node::End | Node::WordBoundary => {
+                return self.error("nothing to repeat");
+            }
+            _ => (),
+        }
+        let greedy = self.peek() != Some('?');
+        if !greedy {
+            self.pos += 1;
+        }
+        Ok(Node::Repeat { node: Box::new(atom), min: min, max: max, greedy: greedy })
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// The state of a search. Nodes are matched by continuation passing: once
+/// a node matches, the continuation is called with the position after
+/// it, and returns whether the rest of the pattern matched.
+struct Matcher<'a> {
+    text: &'a [char],
+    caps: Captures,
+    steps: usize,
+    depth: usize,
+}
+
+type Cont<'c, 'a> = &'c mut FnMut(&mut Matcher<'a>, usize) -> bool;
+
+impl<'a> Matcher<'a> {
+    fn node<'c>(&mut self, node: &Node, pos: usize, k: Cont<'c, 'a>) -> bool {
+        self.steps += 1;
+        if self.steps > MAX_STEPS || self.depth > MAX_DEPTH {
+            return false;
+        }
+        match *node {
+            Node::Empty => k(self, pos),
+            Node::Char(_) | Node::Any | Node::Class { .. } => {
+                match self.text.get(pos) {
+                    Some(&c) if node.matches_char(c) => k(self, pos + 1),
+                    _ => false,
+                }
+            }
+            Node::Start => pos == 0 && k(self, pos),
+            Node::End => pos == self.text.len() && k(self, pos),
+            Node::WordBoundary => {
+                let before = pos > 0 && is_word_char(self.text[pos - 1]);
+                let after = pos < self.text.len() && is_word_char(self.text[pos]);
+                before != after && k(self, pos)
+            }
+            Node::Group(ref inner, group) => {
+                self.depth += 1;
+                let matched = self.node(inner, pos, &mut |me, end| {
+                    let group = match group {
+                        Some(group) => group,
+                        None => return k(me, end),
+                    };
+                    let old = me.caps[group];
+                    me.caps[group] = Some((pos, end));
+                    k(me, end) || {
+                        me.caps[group] = old;
+                        false
+                    }
+                });
+                self.depth -= 1;
+                matched
+            }
+            Node::Concat(ref nodes) => self.concat(nodes, pos, k),
+            Node::Alt(ref alternatives) => {
+                alternatives.iter().any(|alt| self.node(alt, pos, &mut |me, p| k(me, p)))
+            }
+            Node::Repeat { ref node, min, max, greedy } if node.is_single_char() => {
+                self.repeat_char(node, min, max, greedy, pos, k)
+            }
+            Node::Repeat { ref node, min, max, greedy } => {
+                self.repeat(node, min, max, greedy, 0, pos, k)
+            }
+        }
+    }
+
+    fn concat<'c>(&mut self, nodes: &[Node], pos: usize, k: Cont<'c, 'a>) -> bool {
+        match nodes.split_first() {
+            None => k(self, pos),
+            Some((first, rest)) => {
+                self.depth += 1;
+                let matched = self.node(first, pos, &mut |me, p| me.concat(rest, p, k));
+                self.depth -= 1;
+                matched
+            }
+        }
+    }
+
+    /// Matches a repetition of a single character without recursing for
+    /// each repeat, so that long runs don't exhaust the stack.
+    fn repeat_char<'c>(&mut self, node: &Node, min: usize, max: Option<usize>, greedy: bool,
+                       pos: usize, k: Cont<'c, 'a>) -> bool {
+        let max = max.unwrap_or(usize::max_value());
+        let available = self.text[pos..].iter()
+            .take(max)
+            .take_while(|&&c| node.matches_char(c))
+            .count();
+        if available < min {
+            return false;
+        }
+        let mut counts = min..available + 1;
+        let mut try_count = |me: &mut Matcher<'a>, count: usize| {
+            me.steps += 1;
+            me.steps <= MAX_STEPS && k(me, pos + count)
+        };
+        if greedy {
+            counts.rev().any(|count| try_count(self, count))
+        } else {
+            counts.any(|count| try_count(self, count))
+        }
+    }
+
+    fn repeat<'c>(&mut self, node: &Node, min: usize, max: Option<usize>, greedy: bool,
+                  count: usize, pos: usize, k: Cont<'c, 'a>) -> bool {
+        if count >= min && !greedy && k(self, pos) {
+            return true;
+        }
+        if max.map_or(true, |max| count < max) {
+            self.depth += 1;
+            let matched = self.node(node, pos, &mut |me, p| {
+                // a repeat which matched nothing would repeat forever
+                if p == pos && count >= min {
+                    return false;
+                }
+                me.repeat(node, min, max, greedy, count + 1, p, k)
+            });
+            self.depth -= 1;
+            if matched {
+                return true;
+            }
+        }
+        count >= min && greedy && k(self, pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replace(pattern: &str, text: &str, replacement: &str) -> String {
+        Regex::new(pattern).unwrap().replace_all(text, replacement).unwrap()
+    }
+
+    #[test]
+    fn replacing() {
+        assert_eq!(replace("o", "foo boo", "0"), "f00 b00");
+        assert_eq!(replace("(\\w+)@(\\w+)", "me@host, you@there", "$2:$1"),
+                   "host:me, there:you");
+        assert_eq!(replace("[^a-c]+", "abxxcyd", "-"), "ab-c-");
+        assert_eq!(replace("^\\s+|\\s+$", "  padded \t", ""), "padded");
+        assert_eq!(replace("\\bcat\\b", "cat concat cat", "dog"), "dog concat dog");
+        assert_eq!(replace("a{2,3}", "aaaaaaa", "x"), "xxa");
+        assert_eq!(replace("<.+?>", "<a><b>", "[$0]"), "[<a>][<b>]");
+        assert_eq!(replace("(?:ab)+", "ababa", "$1."), ".a");
+        assert_eq!(replace("x*", "abc", "-"), "-a-b-c-");
+        assert_eq!(replace("é", "café", "e$$"), "cafe$");
+        assert_eq!(replace("[\\d.]+", "v1.25 and 3", "N"), "vN and N");
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert_eq!(Regex::new("ab(c").unwrap_err(), (4, "unclosed group".to_owned()));
+        assert_eq!(Regex::new("a)").unwrap_err().0, 1);
+        assert_eq!(Regex::new("*a").unwrap_err(), (0, "nothing to repeat".to_owned()));
+        assert!(Regex::new("[a-").is_err());
+        assert!(Regex::new("[z-a]").is_err());
+        assert!(Regex::new("a{3,1}").is_err());
+        assert!(Regex::new("\\q").is_err());
+        assert!(Regex::new("a**").is_err());
+    }
+
+    #[test]
+    fn pathological_patterns() {
+        // long runs are matched without recursing for each character
+        let long = "a".repeat(100_000);
+        assert_eq!(replace("a*b?$", &long, "x"), "x");
+        // and catastrophic backtracking gives up
+        let regex = Regex::new("(a*)*b").unwrap();
+        assert!(regex.replace_all(&"a".repeat(30), "").is_err());
+    }
+}
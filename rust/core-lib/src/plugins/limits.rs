@@ -0,0 +1,202 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Limits on the resources a plugin may use in core, and the accounting
+//! of its use of them.
+
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+/// A resource whose use by a plugin is limited.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Resource {
+    /// The entries in the scope lookup of one of the plugin's layers.
+    Scopes,
+    /// The spans in one of the plugin's layers.
+    Spans,
+    /// The bytes of updates sent to the plugin which it hasn't answered.
+    QueuedBytes,
+    /// The notifications and requests the plugin sent in the last second.
+    Requests,
+}
+
+impl Resource {
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Resource::Scopes => "scopes",
+            Resource::Spans => "spans",
+            Resource::QueuedBytes => "bytes of unanswered updates",
+            Resource::Requests => "requests per second",
+        }
+    }
+}
+
+/// The limits on a plugin's use of resources. Past a limit, the plugin is
+/// throttled: its scopes and spans are dropped, and it is warned. Past
+/// `hard_limit_factor` times a limit, it is suspended.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PluginLimits {
+    pub max_scopes: usize,
+    pub max_spans: usize,
+    pub max_queued_bytes: usize,
+    pub max_requests_per_sec: usize,
+    pub hard_limit_factor: usize,
+}
+
+impl Default for PluginLimits {
+    fn default() -> Self {
+        PluginLimits {
+            max_scopes: 10_000,
+            max_spans: 1_000_000,
+            max_queued_bytes: 16 << 20,
+            max_requests_per_sec: 1000,
+            hard_limit_factor: 4,
+        }
+    }
+}
+
+/// How a plugin's use of a resource compares to its limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitCheck {
+    Within,
+    /// Past the limit; the plugin is throttled.
+    Soft,
+    /// Past the hard limit; the plugin is suspended.
+    Hard,
+}
+
+impl PluginLimits {
+    pub fn limit(&self, resource: Resource) -> usize {
+        match resource {
+            Resource::Scopes => self.max_scopes,
+            Resource::Spans => self.max_spans,
+            Resource::QueuedBytes => self.max_queued_bytes,
+            Resource::Requests => self.max_requests_per_sec,
+        }
+    }
+
+    pub fn check(&self, resource: Resource, used: usize) -> LimitCheck {
+        let limit = self.limit(resource);
+        if used > limit.saturating_mul(self.hard_limit_factor.max(1)) {
+            LimitCheck::Hard
+        } else if used > limit {
+            LimitCheck::Soft
+        } else {
+            LimitCheck::Within
+        }
+    }
+}
+
+/// What is done with a plugin's message, given its use of a resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Enforcement {
+    Allow,
+    /// The message is dropped. `warn` is `true` if the plugin has just
+    /// become throttled, and should be told.
+    Throttle { warn: bool },
+    Suspend,
+}
+
+/// A running plugin's rate of requests, and the resources it is being
+/// throttled for.
+#[derive(Debug, Default)]
+pub struct PluginUsage {
+    window_start: Option<Instant>,
+    window_requests: usize,
+    throttled: BTreeSet<Resource>,
+}
+
+impl PluginUsage {
+    /// Counts a request made at `now`, returning the number made in the
+    /// current one second window.
+    pub fn count_request(&mut self, now: Instant) -> usize {
+        if self.requests_per_sec(now) == 0 {
+            self.window_start = Some(now);
+            self.window_requests = 0;
+        }
+        self.window_requests += 1;
+        self.window_requests
+    }
+
+    /// Returns the number of requests in the window including `now`.
+    pub fn requests_per_sec(&self, now: Instant) -> usize {
+        match self.window_start {
+            Some(start) if now.duration_since(start) < Duration::from_secs(1) => {
+                self.window_requests
+            }
+            _ => 0,
+        }
+    }
+
+    /// Decides what is done with a message which would bring the plugin's
+    /// use of `resource` to `used`.
+    pub fn enforce(&mut self, limits: &PluginLimits, resource: Resource, used: usize)
+                   -> Enforcement {
+        match limits.check(resource, used) {
+            LimitCheck::Within => {
+                self.throttled.remove(&resource);
+                Enforcement::Allow
+            }
+            LimitCheck::Soft => Enforcement::Throttle { warn: self.throttled.insert(resource) },
+            LimitCheck::Hard => Enforcement::Suspend,
+        }
+    }
+
+    pub fn throttled(&self) -> Vec<Resource> {
+        self.throttled.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checking_limits() {
+        let limits = PluginLimits { max_spans: 10, hard_limit_factor: 3, ..Default::default() };
+        assert_eq!(limits.check(Resource::Spans, 10), LimitCheck::Within);
+        assert_eq!(limits.check(Resource::Spans, 11), LimitCheck::Soft);
+        assert_eq!(limits.check(Resource::Spans, 30), LimitCheck::Soft);
+        assert_eq!(limits.check(Resource::Spans, 31), LimitCheck::Hard);
+        let limits = PluginLimits { hard_limit_factor: 0, ..limits };
+        assert_eq!(limits.check(Resource::Spans, 11), LimitCheck::Hard);
+    }
+
+    #[test]
+    fn accounting() {
+        let mut usage = PluginUsage::default();
+        let start = Instant::now();
+        assert_eq!(usage.count_request(start), 1);
+        assert_eq!(usage.count_request(start + Duration::from_millis(999)), 2);
+        assert_eq!(usage.requests_per_sec(start + Duration::from_millis(999)), 2);
+        assert_eq!(usage.requests_per_sec(start + Duration::from_millis(1000)), 0);
+        assert_eq!(usage.count_request(start + Duration::from_millis(1000)), 1);
+
+        // the plugin is warned once, when it's first throttled
+        let limits = PluginLimits { max_scopes: 10, ..Default::default() };
+        assert_eq!(usage.enforce(&limits, Resource::Scopes, 10), Enforcement::Allow);
+        assert_eq!(usage.enforce(&limits, Resource::Scopes, 11),
+                   Enforcement::Throttle { warn: true });
+        assert_eq!(usage.enforce(&limits, Resource::Scopes, 12),
+                   Enforcement::Throttle { warn: false });
+        assert_eq!(usage.throttled(), vec![Resource::Scopes]);
+        assert_eq!(usage.enforce(&limits, Resource::Scopes, 5), Enforcement::Allow);
+        assert!(usage.throttled().is_empty());
+        assert_eq!(usage.enforce(&limits, Resource::Scopes, 11),
+                   Enforcement::Throttle { warn: true });
+        assert_eq!(usage.enforce(&limits, Resource::Scopes, 41), Enforcement::Suspend);
+    }
+}
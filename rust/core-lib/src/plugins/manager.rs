@@ -17,6 +17,7 @@
 use std::collections::{BTreeMap, BTreeSet};
 use std::io;
 use std::sync::{Arc, Mutex, Weak, MutexGuard};
+use std::time::Instant;
 
 use std::path::Path;
 use std::fmt::Debug;
@@ -24,22 +25,77 @@ use std::fmt::Debug;
 use serde::Serialize;
 use serde_json::{self, Value};
 
+use xi_rpc::RemoteError;
+use xi_rope::interval::Interval;
+
+use config::Config;
+use editor::ConflictingEdit;
 use tabs::{BufferIdentifier, ViewIdentifier, BufferContainerRef};
+use spellcheck::DictionaryScope;
+use suggest::suggest;
+use messages::{ids, Message};
 
-use super::{PluginCatalog, PluginRef, start_plugin_process, PluginPid};
+use super::{PluginCatalog, PluginRef, PluginSegmenter, start_plugin_process, PluginPid};
 use super::rpc_types::{PluginNotification, PluginRequest, PluginUpdate, UpdateResponse, PluginBufferInfo, ClientPluginInfo};
 use super::manifest::{PluginActivation, Command};
+use super::limits::{Enforcement, PluginUsage, Resource};
+use super::services::{self, SERVICE_REFUSED};
 
 pub type PluginName = String;
 type PluginGroup = BTreeMap<PluginName, PluginRef>;
 
+/// The exit code reported for plugins stopped for exceeding their limits.
+const SUSPENDED_EXIT_CODE: i32 = 2;
+
+/// The error code of requests from plugins which have been stopped.
+const STOPPED_PLUGIN: i64 = 89;
+
+/// Which plugins may be discovered and started.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PluginMode {
+    /// All the plugins in the catalog.
+    Enabled,
+    /// Only the named plugins, as when finding out which of them causes
+    /// trouble.
+    Only(BTreeSet<PluginName>),
+    /// No plugins: none are discovered or started, as in safe mode.
+    Disabled,
+}
+
+impl PluginMode {
+    pub fn allows(&self, plugin_name: &str) -> bool {
+        match *self {
+            PluginMode::Enabled => true,
+            PluginMode::Only(ref names) => names.contains(plugin_name),
+            PluginMode::Disabled => false,
+        }
+    }
+
+    /// Returns the catalog of the plugins discovered in this mode.
+    fn discover(&self) -> PluginCatalog {
+        match *self {
+            // TODO: actually parse these from manifest files
+            PluginMode::Enabled | PluginMode::Only(_) => PluginCatalog::debug(),
+            PluginMode::Disabled => PluginCatalog::new(&[]),
+        }
+    }
+}
+
 /// Manages plugin loading, activation, lifecycle, and dispatch.
 pub struct PluginManager {
     catalog: PluginCatalog,
+    mode: PluginMode,
     /// Buffer-scoped plugins, by buffer
     buffer_plugins: BTreeMap<BufferIdentifier, PluginGroup>,
     global_plugins: PluginGroup,
     buffers: BufferContainerRef,
+    config: Arc<Mutex<Config>>,
+    /// The rates and throttling of running plugins.
+    usage: BTreeMap<PluginPid, PluginUsage>,
+    /// Plugins stopped for exceeding their limits, which aren't started
+    /// again until they are resumed.
+    suspended: BTreeSet<PluginName>,
     next_id: usize,
 }
 
@@ -62,7 +118,8 @@ impl PluginManager {
 
     /// Returns plugins available to this view.
     pub fn get_available_plugins(&self, view_id: &ViewIdentifier) -> Vec<ClientPluginInfo> {
-        self.catalog.iter_names().map(|name| {
+        let mode = &self.mode;
+        self.catalog.iter_names().filter(|name| mode.allows(name)).map(|name| {
             let running = self.plugin_is_running(view_id, &name);
             let name = name.clone();
             ClientPluginInfo { name, running }
@@ -70,33 +127,111 @@ impl PluginManager {
     }
 
     /// Handle a request from a plugin.
-    pub fn handle_plugin_notification(&self, cmd: PluginNotification, plugin_id: PluginPid) {
+    pub fn handle_plugin_notification(&mut self, cmd: PluginNotification,
+                                      plugin_id: PluginPid) {
         use self::PluginNotification::*;
+        let within_rate = match self.count_request(cmd.view_id(), plugin_id) {
+            Some(within_rate) => within_rate,
+            None => return,
+        };
         match cmd {
             //TODO: these should not be unwraps
-            AddScopes { view_id, scopes } => {
-                self.buffers.lock().editor_for_view_mut(&view_id).unwrap()
-                    .plugin_add_scopes(plugin_id, scopes);
+            AddScopes { view_id, scopes, modifiers, edit_policy } => {
+                let (n_scopes, _) = self.buffers.lock().editor_for_view(&view_id).unwrap()
+                    .plugin_layer_usage(plugin_id);
+                let used = n_scopes + scopes.len();
+                if within_rate && self.enforce_limit(&view_id, plugin_id, Resource::Scopes, used) {
+                    let result = {
+                        let mut buffers = self.buffers.lock();
+                        let editor = buffers.editor_for_view_mut(&view_id).unwrap();
+                        if let Some(edit_policy) = edit_policy {
+                            editor.plugin_set_edit_policy(plugin_id, edit_policy);
+                        }
+                        editor.plugin_add_scopes_with_modifiers(plugin_id, scopes,
+                                                                modifiers.unwrap_or_default())
+                    };
+                    self.report_invalid_update(&view_id, plugin_id, "add_scopes", result);
+                }
             }
             UpdateSpans { view_id, start, len, spans, rev } => {
-                self.buffers.lock().editor_for_view_mut(&view_id).unwrap()
-                    .plugin_update_spans(plugin_id, start, len, spans, rev);
+                let used = self.buffers.lock().editor_for_view(&view_id).unwrap()
+                    .plugin_spans_after_update(plugin_id, start, len, spans.len());
+                if within_rate && self.enforce_limit(&view_id, plugin_id, Resource::Spans, used) {
+                    let result = self.buffers.lock().editor_for_view_mut(&view_id).unwrap()
+                        .plugin_update_spans(plugin_id, start, len, spans, rev);
+                    self.report_invalid_update(&view_id, plugin_id, "update_spans", result);
+                }
+            }
+            BatchUpdate { view_id, scopes, modifiers, updates, rev } => {
+                let (used_scopes, used_spans) = {
+                    let buffers = self.buffers.lock();
+                    let editor = buffers.editor_for_view(&view_id).unwrap();
+                    (editor.plugin_layer_usage(plugin_id).0 + scopes.len(),
+                     editor.plugin_spans_after_batch(plugin_id, &updates))
+                };
+                if within_rate &&
+                    self.enforce_limit(&view_id, plugin_id, Resource::Scopes, used_scopes) &&
+                    self.enforce_limit(&view_id, plugin_id, Resource::Spans, used_spans) {
+                    let result = self.buffers.lock().editor_for_view_mut(&view_id).unwrap()
+                        .plugin_apply_batch(plugin_id, scopes, modifiers.unwrap_or_default(),
+                                            updates, rev);
+                    self.report_invalid_update(&view_id, plugin_id, "batch_update", result);
+                }
+            }
+            UpdateStyleSpans { view_id, start, len, spans, rev } => {
+                let used = self.buffers.lock().editor_for_view(&view_id).unwrap()
+                    .plugin_spans_after_update(plugin_id, start, len, spans.len());
+                if within_rate && self.enforce_limit(&view_id, plugin_id, Resource::Spans, used) {
+                    let result = self.buffers.lock().editor_for_view_mut(&view_id).unwrap()
+                        .plugin_update_style_spans(plugin_id, start, len, spans, rev);
+                    self.report_invalid_update(&view_id, plugin_id, "update_style_spans", result);
+                }
             }
             Edit { view_id, edit } => {
                 self.buffers.lock().editor_for_view_mut(&view_id).unwrap()
-                    .plugin_edit(&edit);
+                    .plugin_edit(plugin_id, &edit, Instant::now());
             }
             Alert { view_id, msg } => {
                 self.buffers.lock().editor_for_view(&view_id).unwrap()
-                .plugin_alert(&msg);
+                .plugin_alert(&Message::new(ids::plugin_message).param("msg", msg));
+            }
+            RegisterSegmenter { view_id } => {
+                match self.plugin_for_pid(&view_id, plugin_id) {
+                    Some(plugin) => {
+                        let segmenter = Box::new(PluginSegmenter::new(plugin.clone()));
+                        self.buffers.lock().editor_for_view_mut(&view_id).unwrap()
+                            .plugin_set_segmenter(plugin_id, segmenter);
+                    }
+                    None => print_err!("register_segmenter from unknown plugin {:?}", plugin_id),
+                }
+            }
+            SetLayerPriority { view_id, priority } => {
+                if within_rate {
+                    self.buffers.lock().editor_for_view_mut(&view_id).unwrap()
+                        .plugin_set_layer_priority(plugin_id, priority);
+                }
             }
         }
     }
 
+    /// Renders the styles plugins' updates changed in each buffer, which
+    /// are left pending so that a stream of updates is rendered once.
+    pub fn flush_styles(&mut self) {
+        for editor in self.buffers.lock().iter_editors_mut() {
+            editor.flush_plugin_styles();
+        }
+    }
+
     /// Handle a request from a plugin.
-    pub fn handle_plugin_request(&self, cmd: PluginRequest, _: PluginPid) -> Value {
+    pub fn handle_plugin_request(&mut self, cmd: PluginRequest, plugin_id: PluginPid)
+                                 -> Result<Value, RemoteError> {
         use self::PluginRequest::*;
-        match cmd {
+        // requests are answered while the plugin is throttled, as it would
+        // otherwise wait for them
+        if self.count_request(cmd.view_id(), plugin_id).is_none() {
+            return Err(RemoteError::custom(STOPPED_PLUGIN, "plugin has been stopped", None));
+        }
+        let result = match cmd {
             //TODO: these should not be unwraps
             LineCount { view_id } => {
                 let n_lines = self.buffers.lock().editor_for_view(&view_id).unwrap()
@@ -112,7 +247,170 @@ impl PluginManager {
                 self.buffers.lock().editor_for_view(&view_id).unwrap()
                     .plugin_get_selections(&view_id)
             }
+            GetWords { view_id, start, end } => {
+                self.buffers.lock().editor_for_view(&view_id).unwrap()
+                    .plugin_get_words(start, end)
+            }
+            FindScopes { view_id, selector, start, end } => {
+                self.buffers.lock().editor_for_view(&view_id).unwrap()
+                    .plugin_find_scopes(&selector, start, end)
+                    .map_err(|msg| RemoteError::custom(3, msg, None))?
+            }
+            GetScopeAt { view_id, offset } => {
+                self.buffers.lock().editor_for_view(&view_id).unwrap()
+                    .plugin_get_scope_at(offset)
+            }
+            BeginUndoGroup { view_id } => {
+                let token = self.buffers.lock().editor_for_view_mut(&view_id).unwrap()
+                    .plugin_begin_undo_group(plugin_id, Instant::now())
+                    .ok_or_else(|| RemoteError::custom(
+                        3, "the plugin already has an open undo group", None))?;
+                serde_json::to_value(token).unwrap()
+            }
+            EndUndoGroup { view_id, group_token } => {
+                let was_open = self.buffers.lock().editor_for_view_mut(&view_id).unwrap()
+                    .plugin_end_undo_group(plugin_id, group_token, Instant::now());
+                serde_json::to_value(was_open).unwrap()
+            }
+            EditSpans { view_id, rev, ops } => {
+                let used = self.buffers.lock().editor_for_view(&view_id).unwrap()
+                    .plugin_spans_after_edit(plugin_id, &ops);
+                if !self.enforce_limit(&view_id, plugin_id, Resource::Spans, used) {
+                    return Err(RemoteError::custom(3, "the plugin has too many spans", None));
+                }
+                self.buffers.lock().editor_for_view_mut(&view_id).unwrap()
+                    .plugin_edit_spans(plugin_id, ops, rev)
+                    .map_err(|msg| RemoteError::custom(3, msg, None))?;
+                Value::Null
+            }
+            ServiceRequest { .. } => unreachable!("service requests are routed by the ref"),
+        };
+        Ok(result)
+    }
+
+    /// Checks that a plugin's request for `service` can be routed, returning
+    /// the plugin providing it, and the name of the plugin requesting it.
+    /// Service requests are refused while the requester is throttled.
+    fn route_service_request(&mut self, view_id: &ViewIdentifier, service: &str,
+                             method: &str, plugin_id: PluginPid)
+                             -> Result<(PluginRef, PluginName), RemoteError> {
+        let refused = |msg: String| RemoteError::custom(SERVICE_REFUSED, msg, None);
+        match self.count_request(view_id, plugin_id) {
+            None => {
+                return Err(RemoteError::custom(STOPPED_PLUGIN, "plugin has been stopped", None));
+            }
+            Some(false) => return Err(refused("too many requests".to_owned())),
+            Some(true) => (),
         }
+        let locals = self.running_for_view(view_id).ok();
+        let active = locals.into_iter().flat_map(|r| r.values())
+            .chain(self.global_plugins.values())
+            .map(|plugin| (plugin.get_name_and_priority().0, plugin))
+            .collect::<Vec<_>>();
+        let consumer = active.iter()
+            .find(|&&(_, plugin)| plugin.get_identifier() == plugin_id)
+            .and_then(|&(ref name, _)| self.catalog.get_named(name))
+            .ok_or_else(|| refused("the requesting plugin isn't in the catalog".to_owned()))?;
+        let descriptions = active.iter().filter_map(|&(ref name, _)| self.catalog.get_named(name));
+        let provider = services::choose_provider(consumer, descriptions, service)
+            .map_err(|msg| {
+                print_err!("refused {} request from {}: {}", service, consumer.name, msg);
+                refused(msg)
+            })?;
+        print_err!("routing {} request {} from {} to {}", service, method, consumer.name,
+                   provider.name);
+        let plugin = active.iter().find(|&&(ref name, _)| *name == provider.name).unwrap().1;
+        Ok((plugin.clone(), consumer.name.clone()))
+    }
+
+    /// Counts a message from a plugin against its rate limit. Returns
+    /// `None` if the plugin isn't running, as when it has just been
+    /// suspended, and otherwise whether it is within its rate.
+    fn count_request(&mut self, view_id: &ViewIdentifier, plugin_id: PluginPid) -> Option<bool> {
+        if self.plugin_for_pid(view_id, plugin_id).is_none() {
+            return None;
+        }
+        let requests = self.usage.entry(plugin_id).or_insert_with(PluginUsage::default)
+            .count_request(Instant::now());
+        let within_rate = self.enforce_limit(view_id, plugin_id, Resource::Requests, requests);
+        if self.plugin_for_pid(view_id, plugin_id).is_none() {
+            return None;
+        }
+        Some(within_rate)
+    }
+
+    /// Checks a plugin's use of `resource` against its limits, warning the
+    /// plugin when it becomes throttled, and suspending it past the hard
+    /// limit. Returns `true` if the message using the resource is handled.
+    fn enforce_limit(&mut self, view_id: &ViewIdentifier, plugin_id: PluginPid,
+                     resource: Resource, used: usize) -> bool {
+        let limits = self.config.lock().unwrap().plugin_limits.clone();
+        let enforcement = self.usage.entry(plugin_id).or_insert_with(PluginUsage::default)
+            .enforce(&limits, resource, used);
+        match enforcement {
+            Enforcement::Allow => true,
+            Enforcement::Throttle { warn } => {
+                if warn {
+                    print_err!("throttling plugin {:?}: {} {}", plugin_id, used,
+                               resource.description());
+                    let params = json!({
+                        "view_id": view_id,
+                        "resource": resource,
+                        "used": used,
+                        "limit": limits.limit(resource),
+                    });
+                    if let Some(plugin) = self.plugin_for_pid(view_id, plugin_id) {
+                        plugin.rpc_notification("resource_limit_exceeded", &params);
+                    }
+                }
+                false
+            }
+            Enforcement::Suspend => {
+                self.suspend_plugin(view_id, plugin_id, resource, used);
+                false
+            }
+        }
+    }
+
+    /// Tells a plugin that core couldn't apply all of an update it sent,
+    /// with the `method` of the notification and the error.
+    fn report_invalid_update(&self, view_id: &ViewIdentifier, plugin_id: PluginPid,
+                             method: &str, result: Result<(), String>) {
+        if let Err(err) = result {
+            print_err!("invalid {} from plugin {:?}: {}", method, plugin_id, err);
+            let params = json!({
+                "view_id": view_id,
+                "method": method,
+                "error": err,
+            });
+            if let Some(plugin) = self.plugin_for_pid(view_id, plugin_id) {
+                plugin.rpc_notification("invalid_update", &params);
+            }
+        }
+    }
+
+    /// Stops a plugin which has used too much of a resource, and alerts
+    /// the user. It isn't started again until it is resumed.
+    fn suspend_plugin(&mut self, view_id: &ViewIdentifier, plugin_id: PluginPid,
+                      resource: Resource, used: usize) {
+        let plugin_name = match self.plugin_for_pid(view_id, plugin_id) {
+            Some(plugin) => plugin.get_name_and_priority().0,
+            None => return,
+        };
+        let text = format!("The plugin {} has been suspended, for using {} {}. \
+                            It can be restarted with resume_plugin.",
+                           plugin_name, used, resource.description());
+        print_err!("{}", text);
+        let msg = Message::new(ids::plugin_suspended)
+            .param("plugin", plugin_name.as_str())
+            .param("used", used)
+            .param("resource", serde_json::to_value(resource).unwrap())
+            .with_fallback_text(text);
+        if let Some(ed) = self.buffers.lock().editor_for_view(view_id) {
+            ed.plugin_alert(&msg);
+        }
+        self.suspended.insert(plugin_name.clone());
+        self.end_plugin(view_id, &plugin_name, SUSPENDED_EXIT_CODE, PluginRef::kill);
     }
 
     /// Passes an update from a buffer to all registered plugins.
@@ -122,50 +420,113 @@ impl PluginManager {
         // find all running plugins for this buffer, and send them the update
         let mut dead_plugins = Vec::new();
 
-        if let Ok(running) = self.running_for_view(view_id) {
-            for (name, plugin) in running.iter().chain(self.global_plugins.iter()) {
+        let running = self.running_for_view(view_id).ok().map(|running| {
+            running.iter().chain(self.global_plugins.iter())
+                .map(|(name, plugin)| (name.to_owned(), plugin.clone()))
+                .collect::<Vec<_>>()
+        });
+        if let Some(running) = running {
+            // plugins whose edits conflict with another's are told so
+            let recipients = running.iter()
+                .map(|&(_, ref plugin)| plugin.clone())
+                .collect::<Vec<_>>();
+            let rev = update.rev();
+            let mut updated = Vec::new();
+            for &(ref name, ref plugin) in running.iter() {
                 // check to see if plugins have crashed
                 if plugin.is_dead() {
                     dead_plugins.push((name.to_owned(), plugin.get_identifier()));
                     continue;
                 }
+                // updates aren't dropped, as the plugin would lose track
+                // of the buffer, but they aren't sent past the hard limit
+                let plugin_id = plugin.get_identifier();
+                let queued = plugin.queued_bytes();
+                if !self.enforce_limit(view_id, plugin_id, Resource::QueuedBytes, queued) &&
+                    self.plugin_for_pid(view_id, plugin_id).is_none() {
+                    continue;
+                }
+                updated.push(plugin.clone());
+            }
 
-                self.buffers.lock().editor_for_view_mut(view_id)
-                    .unwrap().increment_revs_in_flight();
+            // every response is awaited before the update is sent, so that
+            // a quick plugin's edit isn't applied ahead of a slower one's
+            // which comes before it
+            {
+                let now = Instant::now();
+                let mut buffers = self.buffers.lock();
+                let editor = buffers.editor_for_view_mut(view_id).unwrap();
+                for plugin in updated.iter() {
+                    let (name, priority) = plugin.get_name_and_priority();
+                    editor.increment_revs_in_flight();
+                    editor.expect_plugin_response(rev, plugin.get_identifier(), &name,
+                                                  priority, undo_group, now);
+                }
+            }
 
+            for plugin in updated.iter() {
                 let view_id = view_id.to_owned();
                 let buffers = self.buffers.clone().to_weak();
                 let mut plugin_ref = plugin.clone();
+                let recipients = recipients.clone();
 
+                // plugins may rescan the buffer rather than follow a bulk edit
+                if let Some(params) = update.bulk_edit_params() {
+                    plugin.rpc_notification("bulk_edit", &params);
+                }
                 plugin.update(&update, move |response| {
                     let buffers = match buffers.upgrade() {
                         Some(b) => b,
                         None => return,
                     };
 
-                    match response.map(serde_json::from_value::<UpdateResponse>) {
-                        Ok(Ok(UpdateResponse::Edit(edit))) => {
-                            buffers.lock().editor_for_view_mut(&view_id).unwrap()
-                                .apply_plugin_edit(&edit, Some(undo_group));
+                    let edit = match response.map(serde_json::from_value::<UpdateResponse>) {
+                        Ok(Ok(UpdateResponse::Edit(edit))) => Some(edit),
+                        Ok(Ok(UpdateResponse::Ack(_))) => None,
+                        Ok(Err(err)) => {
+                            print_err!("plugin response json err: {:?}", err);
+                            None
                         }
-                        Ok(Ok(UpdateResponse::Ack(_))) => (),
-                        Ok(Err(err)) => print_err!("plugin response json err: {:?}", err),
                         Err(err) => {
                             print_err!("plugin process dead? {:?}", err);
                             //TODO: do we have a retry policy?
                             plugin_ref.declare_dead();
+                            None
                         }
-                    }
-                    buffers.lock().editor_for_view_mut(&view_id)
-                        .unwrap().dec_revs_in_flight();
+                    };
+                    let conflicts = {
+                        let mut buffers = buffers.lock();
+                        let editor = buffers.editor_for_view_mut(&view_id).unwrap();
+                        let conflicts = editor.plugin_responded(
+                            rev, plugin_ref.get_identifier(), edit, Instant::now());
+                        editor.dec_revs_in_flight();
+                        conflicts
+                    };
+                    notify_conflicts(&recipients, &view_id, conflicts);
                 });
             }
         };
         self.cleanup_dead(view_id, &dead_plugins);
         self.buffers.lock().editor_for_view_mut(view_id).unwrap().dec_revs_in_flight();
+        self.send_edit_conflicts(view_id);
         Ok(())
     }
 
+    /// Tells the view's plugins of their edits which were rejected, as
+    /// they conflict with others, other than in response to an update.
+    fn send_edit_conflicts(&self, view_id: &ViewIdentifier) {
+        let conflicts = match self.buffers.lock().editor_for_view_mut(view_id) {
+            Some(ed) => ed.take_plugin_edit_conflicts(),
+            None => return,
+        };
+        let recipients = self.running_for_view(view_id).ok().into_iter()
+            .flat_map(|running| running.values())
+            .chain(self.global_plugins.values())
+            .cloned()
+            .collect::<Vec<_>>();
+        notify_conflicts(&recipients, view_id, conflicts);
+    }
+
     /// Sends a notification to groups of plugins.
     fn notify_plugins<V>(&self, view_id: &ViewIdentifier,
                          only_globals: bool, method: &str, params: &V)
@@ -188,18 +549,36 @@ impl PluginManager {
 
     fn dispatch_command(&self, view_id: &ViewIdentifier, receiver: &str,
                         method: &str, params: &Value) {
-        let plugin_ref = self.running_for_view(view_id)
-            .ok()
-            .and_then(|r| r.get(receiver));
-
-        match plugin_ref {
+        let running = self.running_for_view(view_id).ok();
+        let plugin_ref = running.and_then(|r| r.get(receiver));
+        // plugins which register no commands may take any
+        let commands = self.catalog.get_named(receiver)
+            .map(|desc| desc.commands.iter().map(|cmd| cmd.rpc_cmd.method.clone()).collect())
+            .unwrap_or_else(Vec::new);
+
+        let msg = match plugin_ref {
+            Some(_) if !commands.is_empty() && !commands.iter().any(|cmd| cmd == method) => {
+                Message::new(ids::plugin_command_unknown)
+                    .param("plugin", receiver)
+                    .param("command", method)
+                    .suggestions(&suggest(method, &commands))
+            }
             Some(plug) => {
                 let inner = json!({"method": method, "params": params});
                 plug.rpc_notification("custom_command", &inner);
+                return;
             }
             None => {
-                print_err!("missing plugin {} for command {}", receiver, method);
+                let names = running.into_iter().flat_map(|r| r.keys());
+                Message::new(ids::plugin_command_not_running)
+                    .param("plugin", receiver)
+                    .param("command", method)
+                    .suggestions(&suggest(receiver, names))
             }
+        };
+        print_err!("{}", msg.fallback_text());
+        if let Some(ed) = self.buffers.lock().editor_for_view(view_id) {
+            ed.plugin_alert(&msg);
         }
     }
 
@@ -210,11 +589,17 @@ impl PluginManager {
                     init_info: &PluginBufferInfo,
                     plugin_name: &str, ) -> Result<(), Error> {
 
+        if !self.mode.allows(plugin_name) {
+            return Err(Error::Other(format!("{} is disabled", plugin_name)));
+        }
         // verify that this view_id is valid
          let _ = self.running_for_view(view_id)?;
          if self.plugin_is_running(view_id, plugin_name) {
              return Err(Error::Other(format!("{} already running", plugin_name)));
          }
+         if self.suspended.contains(plugin_name) {
+             return Err(Error::Other(format!("{} is suspended", plugin_name)));
+         }
 
         let plugin_id = self.next_plugin_id();
         let plugin_desc = self.catalog.get_named(plugin_name)
@@ -260,9 +645,10 @@ impl PluginManager {
                               plugin_name: &str, plugin_ref: PluginRef,
                               commands: Vec<Command>) {
         // only add to our 'running' collection if the editor still exists
-        let is_running = match self.buffers.lock().editor_for_view(view_id) {
+        let is_running = match self.buffers.lock().editor_for_view_mut(view_id) {
             Some(ed) => {
                 ed.plugin_started(view_id, plugin_name, &commands);
+                ed.plugin_adopt_layer(plugin_name, plugin_ref.get_identifier());
                 true
             }
             None => false,
@@ -281,24 +667,35 @@ impl PluginManager {
     fn on_plugin_connect_global(&mut self, plugin_name: &str,
                                 plugin_ref: PluginRef, commands: Vec<Command>) {
         {
-            let buffers = self.buffers.lock();
-            for ed in buffers.iter_editors() {
+            let mut buffers = self.buffers.lock();
+            for ed in buffers.iter_editors_mut() {
                 ed.plugin_started(None, plugin_name, &commands);
+                ed.plugin_adopt_layer(plugin_name, plugin_ref.get_identifier());
             }
         }
         self.global_plugins.insert(plugin_name.to_owned(), plugin_ref);
     }
 
     fn stop_plugin(&mut self, view_id: &ViewIdentifier, plugin_name: &str) {
+        self.end_plugin(view_id, plugin_name, 0, PluginRef::shutdown);
+    }
+
+    /// Removes the named plugin, ending its process with `end`, and
+    /// reports `exit_code` to the editors.
+    fn end_plugin<F>(&mut self, view_id: &ViewIdentifier, plugin_name: &str,
+                     exit_code: i32, end: F)
+        where F: Fn(&PluginRef)
+    {
         let is_global = self.catalog.get_named(plugin_name).unwrap().is_global();
         if is_global {
             let plugin_ref = self.global_plugins.remove(plugin_name);
             if let Some(plugin_ref) = plugin_ref {
                 let plugin_id = plugin_ref.get_identifier();
-                plugin_ref.shutdown();
+                self.usage.remove(&plugin_id);
+                end(&plugin_ref);
                 let mut buffers = self.buffers.lock();
                 for ed in buffers.iter_editors_mut() {
-                    ed.plugin_stopped(None, plugin_name, plugin_id, 0);
+                    ed.plugin_stopped(None, plugin_name, plugin_id, exit_code);
                 }
             }
         }
@@ -309,13 +706,46 @@ impl PluginManager {
 
         if let Some(plugin_ref) = plugin_ref {
             let plugin_id = plugin_ref.get_identifier();
-            plugin_ref.shutdown();
+            self.usage.remove(&plugin_id);
+            end(&plugin_ref);
             //TODO: should we notify now, or wait until we know this worked?
             //can this fail? (yes.) How do we tell, and when do we kill the proc?
             if let Some(ed) = self.buffers.lock().editor_for_view_mut(view_id) {
-                ed.plugin_stopped(view_id, plugin_name, plugin_id, 0);
+                ed.plugin_stopped(view_id, plugin_name, plugin_id, exit_code);
             }
         }
+        self.send_edit_conflicts(view_id);
+    }
+
+    /// Returns the resources used by the view's plugins, for debugging.
+    fn debug_stats(&self, view_id: &ViewIdentifier) -> Value {
+        let now = Instant::now();
+        let running = self.running_for_view(view_id).ok().into_iter()
+            .flat_map(|running| running.iter())
+            .chain(self.global_plugins.iter())
+            .collect::<Vec<_>>();
+        let buffers = self.buffers.lock();
+        let editor = buffers.editor_for_view(view_id);
+        let running = running.into_iter()
+            .map(|(name, plugin)| {
+                let plugin_id = plugin.get_identifier();
+                let (scopes, spans) = editor.map(|ed| ed.plugin_layer_usage(plugin_id))
+                    .unwrap_or((0, 0));
+                let usage = self.usage.get(&plugin_id);
+                (name.to_owned(), json!({
+                    "scopes": scopes,
+                    "spans": spans,
+                    "queued_bytes": plugin.queued_bytes(),
+                    "requests_per_sec": usage.map_or(0, |u| u.requests_per_sec(now)),
+                    "throttled": usage.map(PluginUsage::throttled).unwrap_or_default(),
+                }))
+            })
+            .collect::<serde_json::Map<_, _>>();
+        json!({
+            "running": running,
+            "suspended": self.suspended,
+            "limits": self.config.lock().unwrap().plugin_limits,
+        })
     }
 
     /// Remove dead plugins, notifying editors as needed.
@@ -327,6 +757,7 @@ impl PluginManager {
         //TODO: define exit codes, put them in an enum somewhere
         let ABNORMAL_EXIT_CODE = 1;
         for &(ref name, pid) in plugins.iter() {
+            self.usage.remove(&pid);
             let is_global = self.catalog.get_named(name).unwrap().is_global();
             if is_global {
                 {
@@ -334,6 +765,7 @@ impl PluginManager {
                 }
                 let mut buffers = self.buffers.lock();
                 for ed in buffers.iter_editors_mut() {
+                    ed.plugin_detach_layer(name, pid);
                     ed.plugin_stopped(None, name, pid, ABNORMAL_EXIT_CODE);
                 }
 
@@ -341,9 +773,13 @@ impl PluginManager {
                 let _ = self.running_for_view_mut(&view_id)
                     .map(|running| running.remove(name));
                 self.buffers.lock().editor_for_view_mut(view_id).map(|ed|{
+                    ed.plugin_detach_layer(name, pid);
                     ed.plugin_stopped(view_id, name, pid, ABNORMAL_EXIT_CODE);
                 });
             }
+            if let Some(ed) = self.buffers.lock().editor_for_view(view_id) {
+                ed.plugin_alert(&Message::new(ids::plugin_crashed).param("plugin", name.as_str()));
+            }
         }
     }
 
@@ -355,6 +791,14 @@ impl PluginManager {
         self.buffers.buffer_for_view(view_id).map(|id| id.to_owned())
     }
 
+    /// Returns the plugin with identifier `pid`, if it is running for this view.
+    fn plugin_for_pid(&self, view_id: &ViewIdentifier, pid: PluginPid) -> Option<&PluginRef> {
+        let locals = self.running_for_view(view_id).ok();
+        locals.into_iter().flat_map(|r| r.values())
+            .chain(self.global_plugins.values())
+            .find(|plugin| plugin.get_identifier() == pid)
+    }
+
     fn next_plugin_id(&mut self) -> PluginPid {
         self.next_id += 1;
         PluginPid(self.next_id)
@@ -413,14 +857,18 @@ impl WeakPluginManagerRef {
 }
 
 impl PluginManagerRef {
-    pub fn new(buffers: BufferContainerRef) -> Self {
+    pub fn new(buffers: BufferContainerRef, config: Arc<Mutex<Config>>, mode: PluginMode)
+               -> Self {
         PluginManagerRef(Arc::new(Mutex::new(
             PluginManager {
-                // TODO: actually parse these from manifest files
-                catalog: PluginCatalog::debug(),
+                catalog: mode.discover(),
+                mode: mode,
                 buffer_plugins: BTreeMap::new(),
                 global_plugins: PluginGroup::new(),
                 buffers: buffers,
+                config: config,
+                usage: BTreeMap::new(),
+                suspended: BTreeSet::new(),
                 next_id: 0,
             }
         )))
@@ -435,6 +883,21 @@ impl PluginManagerRef {
         WeakPluginManagerRef(Arc::downgrade(&self.0))
     }
 
+    /// Handles a request from a plugin. A service request is answered by
+    /// the plugin providing the service without the manager locked, as the
+    /// provider may send messages of its own meanwhile.
+    pub fn handle_plugin_request(&self, cmd: PluginRequest, plugin_id: PluginPid)
+                                 -> Result<Value, RemoteError> {
+        match cmd {
+            PluginRequest::ServiceRequest { view_id, service, method, params } => {
+                let (provider, consumer) = self.lock()
+                    .route_service_request(&view_id, &service, &method, plugin_id)?;
+                provider.service_request(&view_id, &consumer, &service, &method, &params)
+            }
+            cmd => self.lock().handle_plugin_request(cmd, plugin_id),
+        }
+    }
+
 
     /// Called when a new buffer is created.
     pub fn document_new(&self, view_id: &ViewIdentifier, init_info: &PluginBufferInfo) {
@@ -446,7 +909,9 @@ impl PluginManagerRef {
                 .map(|ed| { ed.available_plugins(view_id, &available) });
         }
 
-        if self.add_running_collection(view_id).is_ok() {
+        // a view onto a buffer which is already open shares its plugins
+        let is_new_buffer = self.lock().running_for_view(view_id).is_err();
+        if is_new_buffer && self.add_running_collection(view_id).is_ok() {
             let to_start = self.activatable_plugins(view_id);
             self.start_plugins(view_id, &init_info, &to_start);
             self.lock().notify_plugins(view_id, true, "new_buffer", &json!({
@@ -463,6 +928,30 @@ impl PluginManagerRef {
         }));
     }
 
+    /// Called when a word is added to one of a buffer's dictionaries.
+    pub fn dictionary_changed(&self, view_id: &ViewIdentifier, word: &str,
+                              scope: DictionaryScope) {
+        self.lock().notify_plugins(view_id, false, "dictionary_changed", &json!({
+            "view_id": view_id,
+            "word": word,
+            "scope": scope,
+        }));
+    }
+
+    /// Asks a plugin to send its spans within `iv`, as of `rev`, again,
+    /// after core dropped them to stay within the span budget.
+    pub fn request_spans(&self, view_id: &ViewIdentifier, plugin_id: PluginPid, iv: Interval,
+                         rev: u64) {
+        if let Some(plugin) = self.lock().plugin_for_pid(view_id, plugin_id) {
+            plugin.rpc_notification("request_spans", &json!({
+                "view_id": view_id,
+                "rev": rev,
+                "start": iv.start(),
+                "end": iv.end(),
+            }));
+        }
+    }
+
     /// Called when a buffer is closed.
     pub fn document_close(&self, view_id: &ViewIdentifier) {
         let to_stop = self.lock().running_for_view(view_id)
@@ -480,6 +969,22 @@ impl PluginManagerRef {
             "view_id": view_id}));
     }
 
+    /// Called when a buffer's language is set by the user or detected from
+    /// its content. Its plugins are restarted, so that they see the new
+    /// language, and their styles are recomputed.
+    pub fn document_language_changed(&self, view_id: &ViewIdentifier,
+                                     init_info: PluginBufferInfo) {
+        let running = match self.lock().running_for_view(view_id) {
+            Ok(running) => running.keys().cloned().collect::<Vec<_>>(),
+            // the buffer's plugins haven't been started yet
+            Err(_) => return,
+        };
+        for plugin_name in running {
+            self.stop_plugin(view_id, &plugin_name);
+        }
+        self.document_syntax_changed(view_id, init_info);
+    }
+
     /// Called when a document's syntax definition has changed.
     pub fn document_syntax_changed(&self, view_id: &ViewIdentifier, init_info: PluginBufferInfo) {
         print_err!("document_syntax_changed {}", view_id);
@@ -520,6 +1025,75 @@ impl PluginManagerRef {
         self.lock().stop_plugin(view_id, plugin_name);
     }
 
+    /// Returns the id of the named plugin, if it is running for the view.
+    pub fn plugin_pid(&self, view_id: &ViewIdentifier, plugin_name: &str) -> Option<PluginPid> {
+        let inner = self.lock();
+        inner.running_for_view(view_id).ok()
+            .and_then(|locals| locals.get(plugin_name))
+            .or_else(|| inner.global_plugins.get(plugin_name))
+            .map(|plugin| plugin.get_identifier())
+    }
+
+    /// Starts a plugin which was suspended for exceeding its limits.
+    pub fn resume_plugin(&self, view_id: &ViewIdentifier, init_info: &PluginBufferInfo,
+                         plugin_name: &str) -> Result<(), Error> {
+        if !self.lock().suspended.remove(plugin_name) {
+            return Err(Error::Other(format!("{} is not suspended", plugin_name)));
+        }
+        self.start_plugin(view_id, init_info, plugin_name)
+    }
+
+    pub fn mode(&self) -> PluginMode {
+        self.lock().mode.clone()
+    }
+
+    /// Stops all running plugins, then discovers plugins again and starts
+    /// those `mode` allows on each open buffer.
+    pub fn restart_plugins(&self, mode: PluginMode) {
+        let infos = self.lock().buffers.lock().iter_editors()
+            .map(|ed| ed.plugin_init_info())
+            .collect::<Vec<_>>();
+        for info in infos.iter() {
+            let view_id = &info.views[0];
+            let running = {
+                let inner = self.lock();
+                let locals = inner.running_for_view(view_id)
+                    .map(|locals| locals.keys().cloned().collect::<Vec<_>>())
+                    .unwrap_or_default();
+                locals.into_iter().chain(inner.global_plugins.keys().cloned())
+                    .collect::<Vec<_>>()
+            };
+            for plugin_name in running {
+                self.stop_plugin(view_id, &plugin_name);
+            }
+        }
+        {
+            let mut inner = self.lock();
+            inner.catalog = mode.discover();
+            inner.mode = mode;
+        }
+        for info in infos.iter() {
+            let view_id = &info.views[0];
+            let available = self.lock().get_available_plugins(view_id);
+            {
+                let inner = self.lock();
+                let buffers = inner.buffers.lock();
+                if let Some(ed) = buffers.editor_for_view(view_id) {
+                    for view_id in info.views.iter() {
+                        ed.available_plugins(view_id, &available);
+                    }
+                }
+            }
+            let to_start = self.activatable_plugins(view_id);
+            self.start_plugins(view_id, info, &to_start);
+        }
+    }
+
+    /// Returns the resources used by the view's plugins, for debugging.
+    pub fn debug_stats(&self, view_id: &ViewIdentifier) -> Value {
+        self.lock().debug_stats(view_id)
+    }
+
     /// Forward an update from a view to registered plugins.
     pub fn update_plugins(&self, view_id: &ViewIdentifier,
                           update: PluginUpdate, undo_group: usize) -> Result<(), Error> {
@@ -565,7 +1139,7 @@ impl PluginManagerRef {
             .to_owned();
 
         inner.catalog.filter(|plug_desc|{
-            plug_desc.activations.iter().any(|act|{
+            inner.mode.allows(&plug_desc.name) && plug_desc.activations.iter().any(|act|{
                 match *act {
                     PluginActivation::Autorun => true,
                     PluginActivation::OnSyntax(ref other) if *other == syntax => true,
@@ -591,3 +1165,193 @@ impl PluginManagerRef {
         }
     }
 }
+
+/// Sends each of `conflicts` to its plugin, among `plugins`, as a
+/// `conflicting_edit` notification.
+fn notify_conflicts(plugins: &[PluginRef], view_id: &ViewIdentifier,
+                    conflicts: Vec<ConflictingEdit>) {
+    for conflict in conflicts {
+        let params = json!({
+            "view_id": view_id,
+            "rev": conflict.edit.rev,
+            "edit": conflict.edit,
+            "conflicts_with": conflict.conflicts_with,
+        });
+        plugins.iter()
+            .filter(|plugin| plugin.get_identifier() == conflict.plugin)
+            .for_each(|plugin| plugin.rpc_notification("conflicting_edit", &params));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+    use editor::Editor;
+    use rpc::EditNotification;
+    use tabs::{mock_doc_ctx, mock_doc_ctx_with_updates};
+    use super::super::PluginDescription;
+    use super::super::manifest::PluginScope;
+
+    #[test]
+    fn disabled_plugins_never_start() {
+        let view_id = ViewIdentifier::from("view-id-disabled-plugins");
+        let buffer_id = BufferIdentifier::new(1);
+        let buffers = BufferContainerRef::new();
+        let editor = Editor::new(mock_doc_ctx(view_id.as_str()), buffer_id, &view_id);
+        let info = editor.plugin_init_info();
+        buffers.add_editor(&view_id, &buffer_id, editor);
+        let config = Arc::new(Mutex::new(Config::default()));
+        let manager = PluginManagerRef::new(buffers, config, PluginMode::Disabled);
+        let shell = PluginDescription {
+            name: "shell".to_owned(),
+            version: "0.0".to_owned(),
+            scope: PluginScope::BufferLocal,
+            exec_path: PathBuf::from("/bin/sh"),
+            activations: vec![PluginActivation::Autorun],
+            commands: Vec::new(),
+            priority: 0,
+            services: Vec::new(),
+            capabilities: Vec::new(),
+        };
+        manager.lock().catalog = PluginCatalog::new(&[shell]);
+
+        manager.document_new(&view_id, &info);
+        assert!(manager.lock().get_available_plugins(&view_id).is_empty());
+        assert!(manager.start_plugin(&view_id, &info, "shell").is_err());
+        let inner = manager.lock();
+        assert!(inner.running_for_view(&view_id).unwrap().is_empty());
+        assert!(inner.global_plugins.is_empty() && inner.usage.is_empty());
+        assert_eq!(inner.next_id, 0);
+    }
+
+    #[test]
+    fn plugin_modes() {
+        let only = PluginMode::Only(vec!["syntect".to_owned()].into_iter().collect());
+        assert!(only.allows("syntect") && !only.allows("spellcheck"));
+        assert!(PluginMode::Enabled.allows("spellcheck"));
+        assert!(!PluginMode::Disabled.allows("syntect"));
+        assert_eq!(serde_json::to_value(&only).unwrap(), json!({"only": ["syntect"]}));
+        assert_eq!(serde_json::to_value(&PluginMode::Disabled).unwrap(), json!("disabled"));
+    }
+
+    /// Writes a plugin which answers each update after `delay` seconds with
+    /// an edit replacing `start..end` with `text`, and which logs the
+    /// conflicting edits it is told of to `log`.
+    fn fixture_plugin(name: &str, priority: u16, delay: &str, start: u64, end: u64,
+                      text: &str, log: &PathBuf) -> PluginDescription {
+        let exec_path = env::temp_dir().join(format!("{}.sh", name));
+        let script = format!(r#"#!/bin/sh
+while read -r line; do
+    case "$line" in
+    *'"method":"update"'*)
+        sleep {delay}
+        id=$(echo "$line" | sed 's/.*"id":\([0-9]*\).*/\1/')
+        rev=$(echo "$line" | sed 's/.*"rev":\([0-9]*\).*/\1/')
+        echo '{{"id":'$id',"result":{{"start":{start},"end":{end},"rev":'$rev',"text":"{text}","priority":0,"after_cursor":false,"author":"{name}"}}}}'
+        ;;
+    *'"method":"conflicting_edit"'*)
+        echo "$line" >> {log}
+        ;;
+    *'"method":"shutdown"'*)
+        exit 0
+        ;;
+    esac
+done
+"#, delay=delay, start=start, end=end, text=text, name=name, log=log.display());
+        File::create(&exec_path).unwrap().write_all(script.as_bytes()).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&exec_path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        PluginDescription {
+            name: name.to_owned(),
+            version: "0.0".to_owned(),
+            scope: PluginScope::BufferLocal,
+            exec_path: exec_path,
+            activations: vec![PluginActivation::Autorun],
+            commands: Vec::new(),
+            priority: priority,
+            services: Vec::new(),
+            capabilities: Vec::new(),
+        }
+    }
+
+    /// Waits up to five seconds for `done` to return true.
+    fn wait_for<F: FnMut() -> bool>(mut done: F) -> bool {
+        for _ in 0..100 {
+            if done() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    /// Has a formatter and a fixer plugin respond to the update for the same
+    /// edit, the fixer first if `fixer_first`, and returns the resulting
+    /// text and the conflicts the fixer was told of.
+    fn fixture_plugin_edits(view_id: &str, fixer_first: bool) -> (String, Vec<Value>) {
+        let view_id = ViewIdentifier::from(view_id);
+        let buffer_id = BufferIdentifier::new(1);
+        let buffers = BufferContainerRef::new();
+        let (doc_ctx, updates) = mock_doc_ctx_with_updates(view_id.as_str());
+        let editor = Editor::new(doc_ctx, buffer_id, &view_id);
+        let info = editor.plugin_init_info();
+        buffers.add_editor(&view_id, &buffer_id, editor);
+        let config = Arc::new(Mutex::new(Config::default()));
+        let manager = PluginManagerRef::new(buffers.clone(), config, PluginMode::Enabled);
+        let log = env::temp_dir().join(format!("{}-conflicts", view_id.as_str()));
+        let _ = fs::remove_file(&log);
+        let (formatter_delay, fixer_delay) = if fixer_first { ("0.3", "0") } else { ("0", "0.3") };
+        let formatter = fixture_plugin(&format!("{}-formatter", view_id.as_str()), 10,
+                                       formatter_delay, 0, 8, "let x = 1;", &log);
+        let fixer = fixture_plugin(&format!("{}-fixer", view_id.as_str()), 0,
+                                   fixer_delay, 4, 5, "z", &log);
+        let names = vec![formatter.name.clone(), fixer.name.clone()];
+        manager.lock().catalog = PluginCatalog::new(&[formatter, fixer]);
+        manager.document_new(&view_id, &info);
+        assert!(wait_for(|| names.iter().all(|name| manager.plugin_pid(&view_id, name).is_some())));
+
+        buffers.lock().editor_for_view_mut(&view_id).unwrap()
+            .handle_notification(&view_id, EditNotification::Insert { chars: "let x=1;".into() });
+        let (_, update, undo_group) = updates.recv_timeout(Duration::from_secs(5)).unwrap();
+        manager.update_plugins(&view_id, update, undo_group).unwrap();
+
+        let text = || {
+            let buffers = buffers.lock();
+            let editor = buffers.editor_for_view(&view_id).unwrap();
+            editor.plugin_get_data(0, 1024, editor.plugin_init_info().rev).unwrap()
+        };
+        let conflicts = || fs::read_to_string(&log).unwrap_or_default().lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap()["params"].clone())
+            .collect::<Vec<_>>();
+        wait_for(|| text() != "let x=1;" && !conflicts().is_empty());
+        for name in names.iter() {
+            manager.stop_plugin(&view_id, name);
+        }
+        (text(), conflicts())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn fixture_plugin_edits_are_ordered() {
+        // the formatter's edit is applied and the fixer's rejected, whichever
+        // plugin responds first
+        for &(view_id, fixer_first) in [("view-id-fixture-fixer-first", true),
+                                        ("view-id-fixture-fixer-last", false)].iter() {
+            let (text, conflicts) = fixture_plugin_edits(view_id, fixer_first);
+            assert_eq!(text, "let x = 1;");
+            assert_eq!(conflicts.len(), 1);
+            assert_eq!(conflicts[0]["edit"]["text"], json!("z"));
+            assert_eq!(conflicts[0]["conflicts_with"],
+                       json!(format!("{}-formatter", view_id)));
+        }
+    }
+}
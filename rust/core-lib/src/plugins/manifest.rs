@@ -84,6 +84,13 @@ pub struct PluginDescription {
     /// Events that cause this plugin to run
     pub activations: Vec<PluginActivation>,
     pub commands: Vec<Command>,
+    /// When several plugins edit the same revision, the edits of plugins
+    /// with higher priorities are applied first.
+    pub priority: u16,
+    /// The services other plugins may request of this one, through core.
+    pub services: Vec<String>,
+    /// The features of core this plugin may use.
+    pub capabilities: Vec<PluginCapability>,
 }
 
 /// `PluginActivation`s represent events that trigger running a plugin.
@@ -99,6 +106,13 @@ pub enum PluginActivation {
     OnCommand,
 }
 
+/// Features of core which a plugin may only use if it is granted them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PluginCapability {
+    /// Making requests of the services of other plugins, and serving them.
+    Services,
+}
+
 #[derive(Debug, Clone)]
 /// Describes the scope of events a plugin receives.
 pub enum PluginScope {
@@ -247,6 +261,9 @@ impl PluginDescription {
             exec_path: exec_path.into(),
             activations: activations,
             commands: commands,
+            priority: 0,
+            services: Vec::new(),
+            capabilities: Vec::new(),
         }
     }
 
@@ -257,6 +274,16 @@ impl PluginDescription {
             _ => false,
         }
     }
+
+    pub fn has_capability(&self, capability: PluginCapability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Returns `true` if this plugin serves requests for `service`.
+    pub fn provides(&self, service: &str) -> bool {
+        self.has_capability(PluginCapability::Services) &&
+            self.services.iter().any(|s| s == service)
+    }
 }
 
 
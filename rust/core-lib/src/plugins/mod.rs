@@ -18,9 +18,12 @@ pub mod rpc_types;
 mod manager;
 mod manifest;
 mod catalog;
+mod limits;
+mod services;
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::{Arc, Mutex, mpsc};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
 use std::process::{Child, Command as ProcCommand, Stdio};
 use std::io::{self, BufReader};
@@ -29,25 +32,40 @@ use serde_json::{self, Value};
 
 use xi_rpc::{self, RpcPeer, RpcCtx, RpcLoop, Handler, RemoteError};
 use tabs::ViewIdentifier;
+use word_boundaries::Segmenter;
 
-pub use self::manager::{PluginManagerRef, WeakPluginManagerRef};
+pub use self::manager::{PluginManagerRef, WeakPluginManagerRef, PluginMode};
 pub use self::manifest::{PluginDescription, Command, PlaceholderRpc};
+pub use self::limits::PluginLimits;
 
 use self::rpc_types::{PluginUpdate, PluginNotification, PluginRequest,
 PluginBufferInfo};
 
 use self::manager::PluginName;
 use self::catalog::PluginCatalog;
+use self::services::{SERVICE_REFUSED, SERVICE_TIMED_OUT, SERVICE_TIMEOUT};
 
 
+/// The maximum number of runs whose segmentation by a plugin is kept.
+const MAX_SEGMENTED_RUNS: usize = 4096;
+
 pub type PluginPeer = RpcPeer;
 /// A process-unique identifier for a running plugin.
 ///
 /// Note: two instances of the same executable will have different identifiers.
 /// Note: this identifier is distinct from the OS's process id.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PluginPid(usize);
 
+impl PluginPid {
+    pub const fn new(val: usize) -> Self {
+        PluginPid(val)
+    }
+}
+
+/// Idle token for rendering the styles a plugin's updates changed.
+const FLUSH_STYLES_TOKEN: usize = 0;
+
 /// A running plugin.
 pub struct Plugin {
     peer: PluginPeer,
@@ -56,6 +74,8 @@ pub struct Plugin {
     manager: WeakPluginManagerRef,
     description: PluginDescription,
     identifier: PluginPid,
+    /// The size of the updates the plugin hasn't responded to.
+    queued_bytes: Arc<AtomicUsize>,
 }
 
 /// A convenience wrapper for passing around a reference to a plugin.
@@ -74,13 +94,15 @@ impl Clone for PluginRef {
 impl Handler for PluginRef {
     type Notification = PluginNotification;
     type Request = PluginRequest;
-    fn handle_notification(&mut self, _ctx: RpcCtx, rpc: Self::Notification) {
+    fn handle_notification(&mut self, mut ctx: RpcCtx, rpc: Self::Notification) {
         let plugin_manager = {
             self.0.lock().unwrap().manager.upgrade()
         };
         if let Some(plugin_manager) = plugin_manager {
             let pid = self.get_identifier();
-            plugin_manager.lock().handle_plugin_notification(rpc, pid)
+            plugin_manager.lock().handle_plugin_notification(rpc, pid);
+            // the styles it changed are rendered once its queue is empty
+            ctx.schedule_idle(FLUSH_STYLES_TOKEN);
         }
     }
 
@@ -91,11 +113,21 @@ impl Handler for PluginRef {
         };
         if let Some(plugin_manager) = plugin_manager {
             let pid = self.get_identifier();
-            Ok(plugin_manager.lock().handle_plugin_request(rpc, pid))
+            plugin_manager.handle_plugin_request(rpc, pid)
         } else {
             Err(RemoteError::custom(88, "Plugin manager missing", None))
         }
     }
+
+    fn idle(&mut self, _ctx: RpcCtx, token: usize) {
+        let plugin_manager = {
+            self.0.lock().unwrap().manager.upgrade()
+        };
+        match (token, plugin_manager) {
+            (FLUSH_STYLES_TOKEN, Some(plugin_manager)) => plugin_manager.lock().flush_styles(),
+            _ => (),
+        }
+    }
 }
 
 
@@ -117,9 +149,16 @@ impl PluginRef {
     pub fn update<F>(&self, update: &PluginUpdate, callback: F)
             where F: FnOnce(Result<Value, xi_rpc::Error>) + Send + 'static {
         let params = serde_json::to_value(update).expect("PluginUpdate invalid");
+        let size = params.to_string().len();
         match self.0.lock() {
-            Ok(plugin) => plugin.peer.send_rpc_request_async("update", &params,
-                                                             Box::new(callback)),
+            Ok(plugin) => {
+                let queued_bytes = plugin.queued_bytes.clone();
+                queued_bytes.fetch_add(size, Ordering::SeqCst);
+                plugin.peer.send_rpc_request_async("update", &params, Box::new(move |response| {
+                    queued_bytes.fetch_sub(size, Ordering::SeqCst);
+                    callback(response)
+                }))
+            }
             Err(err) => {
                 print_err!("plugin update failed {:?}", err);
                 callback(Err(xi_rpc::Error::PeerDisconnect));
@@ -127,6 +166,51 @@ impl PluginRef {
         }
     }
 
+    /// Asks the plugin for the word boundaries within `text`, a run of CJK
+    /// characters.
+    pub fn segment_text<F>(&self, text: &str, callback: F)
+            where F: FnOnce(Result<Value, xi_rpc::Error>) + Send + 'static {
+        let params = json!({"text": text});
+        match self.0.lock() {
+            Ok(plugin) => plugin.peer.send_rpc_request_async("segment_text", &params,
+                                                             Box::new(callback)),
+            Err(err) => {
+                print_err!("plugin segment_text failed {:?}", err);
+                callback(Err(xi_rpc::Error::PeerDisconnect));
+            }
+        }
+    }
+
+    /// Asks the plugin to serve a request for `service` from the plugin
+    /// `consumer`, waiting at most `SERVICE_TIMEOUT` for its response.
+    pub fn service_request(&self, view_id: &ViewIdentifier, consumer: &str, service: &str,
+                           method: &str, params: &Value) -> Result<Value, RemoteError> {
+        let params = json!({
+            "view_id": view_id,
+            "consumer": consumer,
+            "service": service,
+            "method": method,
+            "params": params,
+        });
+        let (tx, rx) = mpsc::channel();
+        self.0.lock().unwrap().peer.send_rpc_request_async("service_request", &params,
+                                                           Box::new(move |result| {
+            let _ = tx.send(result);
+        }));
+        match rx.recv_timeout(SERVICE_TIMEOUT) {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(xi_rpc::Error::RemoteError(err))) => Err(err),
+            Ok(Err(err)) => {
+                let msg = format!("{} failed to serve {}: {:?}", service, method, err);
+                Err(RemoteError::custom(SERVICE_REFUSED, msg, None))
+            }
+            Err(_) => {
+                let msg = format!("{} didn't serve {} in time", service, method);
+                Err(RemoteError::custom(SERVICE_TIMED_OUT, msg, None))
+            }
+        }
+    }
+
     /// Termination message sent to the plugin.
     ///
     /// The plugin is expected to clean up and close the pipe.
@@ -147,6 +231,24 @@ impl PluginRef {
         }
     }
 
+    /// Kills the plugin's process, without waiting for it to clean up, as
+    /// for a plugin which is misbehaving.
+    pub fn kill(&self) {
+        match self.0.lock() {
+            Ok(mut inner) => {
+                let _ = inner.process.kill();
+                let exit_status = inner.process.wait();
+                print_err!("killed process {:?}", exit_status);
+            }
+            Err(_) => print_err!("plugin mutex poisoned"),
+        }
+    }
+
+    /// Returns the size of the updates the plugin hasn't responded to.
+    pub fn queued_bytes(&self) -> usize {
+        self.0.lock().unwrap().queued_bytes.load(Ordering::SeqCst)
+    }
+
     /// Returns `true` if this plugin has crashed.
     pub fn is_dead(&self) -> bool {
         self.1.load(Ordering::SeqCst)
@@ -161,9 +263,68 @@ impl PluginRef {
     pub fn get_identifier(&self) -> PluginPid {
         self.0.lock().unwrap().identifier
     }
+
+    /// Returns the plugin's name and priority.
+    pub fn get_name_and_priority(&self) -> (String, u16) {
+        let inner = self.0.lock().unwrap();
+        (inner.description.name.clone(), inner.description.priority)
+    }
 }
 
 
+/// A `Segmenter` which asks a plugin, through the `segment_text` request.
+///
+/// Requests are asynchronous: the boundaries within a run are unknown until
+/// the plugin responds, after which they are remembered, for as many runs as
+/// `MAX_SEGMENTED_RUNS`.
+pub struct PluginSegmenter {
+    plugin: PluginRef,
+    results: Arc<Mutex<BTreeMap<String, Vec<usize>>>>,
+    pending: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl PluginSegmenter {
+    pub fn new(plugin: PluginRef) -> Self {
+        PluginSegmenter {
+            plugin: plugin,
+            results: Arc::new(Mutex::new(BTreeMap::new())),
+            pending: Arc::new(Mutex::new(BTreeSet::new())),
+        }
+    }
+}
+
+impl Segmenter for PluginSegmenter {
+    fn segment(&self, run: &str) -> Option<Vec<usize>> {
+        if let Some(boundaries) = self.results.lock().unwrap().get(run) {
+            return Some(boundaries.clone());
+        }
+        if self.plugin.is_dead() || !self.pending.lock().unwrap().insert(run.to_owned()) {
+            return None;
+        }
+        let run = run.to_owned();
+        let results = self.results.clone();
+        let pending = self.pending.clone();
+        self.plugin.segment_text(&run.clone(), move |response| {
+            match response.map(serde_json::from_value::<Vec<usize>>) {
+                Ok(Ok(mut boundaries)) => {
+                    boundaries.retain(|&b| b > 0 && b < run.len() && run.is_char_boundary(b));
+                    boundaries.sort();
+                    boundaries.dedup();
+                    let mut results = results.lock().unwrap();
+                    if results.len() >= MAX_SEGMENTED_RUNS {
+                        results.clear();
+                    }
+                    results.insert(run.clone(), boundaries);
+                }
+                Ok(Err(err)) => print_err!("segment_text response json err: {:?}", err),
+                Err(err) => print_err!("segment_text failed: {:?}", err),
+            }
+            pending.lock().unwrap().remove(&run);
+        });
+        None
+    }
+}
+
 /// Starts a thread which collects editor updates and propagates them to plugins.
 ///
 /// In addition to updates caused by user edits, updates can be caused by
@@ -227,6 +388,7 @@ pub fn start_plugin_process<C>(manager_ref: &PluginManagerRef,
                     manager: manager_ref,
                     description: plugin_desc,
                     identifier: identifier,
+                    queued_bytes: Arc::new(AtomicUsize::new(0)),
                 };
                 let mut plugin_ref = PluginRef(
                     Arc::new(Mutex::new(plugin)),
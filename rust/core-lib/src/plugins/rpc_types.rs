@@ -16,6 +16,10 @@
 
 use std::path::PathBuf;
 
+use serde_json::Value;
+
+use layers::EditPolicy;
+use styles::Style;
 use syntax::SyntaxDefinition;
 use tabs::{BufferIdentifier, ViewIdentifier};
 
@@ -63,6 +67,14 @@ pub struct PluginUpdate {
     rev: u64,
     edit_type: String,
     author: String,
+    /// The number of edits composed into this update, if it is the update
+    /// for a bulk edit.
+    #[serde(skip_serializing, skip_deserializing)]
+    bulk_edits: Option<usize>,
+    /// The regions, as of `rev`, whose spans were cleared or guessed since
+    /// the last update, which the plugin may want to highlight again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    invalidated: Option<Vec<(usize, usize)>>,
 }
 
 /// A response to an `update` RPC sent to a plugin.
@@ -104,6 +116,24 @@ pub struct ScopeSpan {
     pub scope_id: u32,
 }
 
+/// The spans of a region of a plugin's layer, in a `batch_update`; as in
+/// an `update_spans`, they replace those from `start` to `start + len`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpanUpdate {
+    pub start: usize,
+    pub len: usize,
+    pub spans: Vec<ScopeSpan>,
+}
+
+/// A span of a style layer, whose style is sent directly rather than
+/// resolved from scopes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StyleSpan {
+    pub start: usize,
+    pub end: usize,
+    pub style: Style,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "method", content = "params")]
@@ -112,6 +142,41 @@ pub enum PluginRequest {
     GetData { view_id: ViewIdentifier, offset: usize, max_size: usize, rev: u64 },
     LineCount { view_id: ViewIdentifier },
     GetSelections { view_id: ViewIdentifier },
+    /// Returns the words from `start` to `end` which should be
+    /// spell-checked, and the language to check them in.
+    GetWords { view_id: ViewIdentifier, start: usize, end: usize },
+    /// Asks the plugin providing `service` on this view's buffer to
+    /// handle `method`, returning its response.
+    ServiceRequest { view_id: ViewIdentifier, service: String, method: String, params: Value },
+    /// Opens an undo group, in which the plugin's edits to this view's
+    /// buffer are undone together, returning its token.
+    BeginUndoGroup { view_id: ViewIdentifier },
+    /// Closes the undo group with `group_token`, returning whether it was
+    /// still open.
+    EndUndoGroup { view_id: ViewIdentifier, group_token: u64 },
+    /// Updates the spans of the plugin's layer with `ops`, which cover the
+    /// buffer as of `rev`.
+    EditSpans { view_id: ViewIdentifier, rev: u64, ops: Vec<SpanOp> },
+    /// Returns the spans from `start` to `end`, in any layer, whose scopes
+    /// match the scope selector `selector`.
+    FindScopes { view_id: ViewIdentifier, selector: String, start: usize, end: usize },
+    /// Returns the scopes at `offset`: those of each layer, in the order
+    /// their styles are merged.
+    GetScopeAt { view_id: ViewIdentifier, offset: usize },
+}
+
+/// An operation of an `edit_spans` request, on the spans of the plugin's
+/// layer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum SpanOp {
+    /// Keeps the spans of the next `n` bytes.
+    Retain(usize),
+    /// Clears the spans of the next `n` bytes.
+    Skip(usize),
+    /// Replaces the spans of the next `len` bytes with `spans`, whose
+    /// offsets are relative to the start of those bytes.
+    Insert { len: usize, spans: Vec<ScopeSpan> },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -119,10 +184,43 @@ pub enum PluginRequest {
 #[serde(tag = "method", content = "params")]
 /// RPC commands sent from plugins.
 pub enum PluginNotification {
-    AddScopes { view_id: ViewIdentifier, scopes: Vec<Vec<String>> },
+    /// Adds scopes to the plugin's layer, with the modifiers of each stack,
+    /// such as `MODIFIER_DEPRECATED`, if any, and sets how the layer's
+    /// spans are moved by edits if `edit_policy` is given.
+    AddScopes {
+        view_id: ViewIdentifier,
+        scopes: Vec<Vec<String>>,
+        modifiers: Option<Vec<u32>>,
+        edit_policy: Option<EditPolicy>,
+    },
     UpdateSpans { view_id: ViewIdentifier, start: usize, len: usize, spans: Vec<ScopeSpan>, rev: u64 },
+    /// Adds scopes to the plugin's layer and updates several regions of its
+    /// spans at once, so that the styles are resolved once.
+    BatchUpdate {
+        view_id: ViewIdentifier,
+        scopes: Vec<Vec<String>>,
+        modifiers: Option<Vec<u32>>,
+        updates: Vec<SpanUpdate>,
+        rev: u64,
+    },
+    /// Updates the spans of the plugin's style layer, for a plugin which
+    /// sends styles rather than scopes.
+    UpdateStyleSpans {
+        view_id: ViewIdentifier,
+        start: usize,
+        len: usize,
+        spans: Vec<StyleSpan>,
+        rev: u64,
+    },
     Edit { view_id: ViewIdentifier, edit: PluginEdit },
     Alert { view_id: ViewIdentifier, msg: String },
+    /// Registers the plugin as the provider of word boundaries in CJK text
+    /// for this view's buffer, through the `segment_text` request.
+    RegisterSegmenter { view_id: ViewIdentifier },
+    /// Sets the priority of the plugin's layer of spans for this view's
+    /// buffer. Where the styles of layers conflict, the higher priority
+    /// wins.
+    SetLayerPriority { view_id: ViewIdentifier, priority: u32 },
 }
 
 impl PluginBufferInfo {
@@ -148,7 +246,63 @@ impl PluginUpdate {
             text: text,
             rev: rev,
             edit_type: edit_type,
-            author: author
+            author: author,
+            bulk_edits: None,
+            invalidated: None,
+        }
+    }
+
+    pub fn rev(&self) -> u64 {
+        self.rev
+    }
+
+    /// Marks this as the update for a bulk edit of `n_edits` edits.
+    pub fn set_bulk_edits(&mut self, n_edits: usize) {
+        self.bulk_edits = Some(n_edits);
+    }
+
+    /// Sets the regions whose spans were invalidated since the last update.
+    pub fn set_invalidated(&mut self, invalidated: Vec<(usize, usize)>) {
+        self.invalidated = Some(invalidated);
+    }
+
+    /// Returns the params of the `bulk_edit` notification sent before this
+    /// update, if it is the update for a bulk edit.
+    pub fn bulk_edit_params(&self) -> Option<Value> {
+        self.bulk_edits.map(|n_edits| json!({
+            "view_id": self.view_id,
+            "rev": self.rev,
+            "composed_delta": {
+                "start": self.start,
+                "end": self.end,
+                "new_len": self.new_len,
+            },
+            "n_edits": n_edits,
+        }))
+    }
+}
+
+impl PluginRequest {
+    pub fn view_id(&self) -> &ViewIdentifier {
+        use self::PluginRequest::*;
+        match *self {
+            GetData { ref view_id, .. } | LineCount { ref view_id } |
+            GetSelections { ref view_id } | GetWords { ref view_id, .. } |
+            ServiceRequest { ref view_id, .. } | BeginUndoGroup { ref view_id } |
+            EndUndoGroup { ref view_id, .. } | EditSpans { ref view_id, .. } |
+            FindScopes { ref view_id, .. } | GetScopeAt { ref view_id, .. } => view_id,
+        }
+    }
+}
+
+impl PluginNotification {
+    pub fn view_id(&self) -> &ViewIdentifier {
+        use self::PluginNotification::*;
+        match *self {
+            AddScopes { ref view_id, .. } | UpdateSpans { ref view_id, .. } |
+            BatchUpdate { ref view_id, .. } | UpdateStyleSpans { ref view_id, .. } |
+            Edit { ref view_id, .. } | Alert { ref view_id, .. } |
+            RegisterSegmenter { ref view_id } | SetLayerPriority { ref view_id, .. } => view_id,
         }
     }
 }
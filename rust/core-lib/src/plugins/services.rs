@@ -0,0 +1,86 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The routing of requests for services from one plugin to another.
+
+use std::time::Duration;
+
+use super::PluginDescription;
+use super::manifest::PluginCapability;
+
+/// The error code of service requests which core refuses to route.
+pub const SERVICE_REFUSED: i64 = 90;
+
+/// The error code of service requests which the provider didn't answer
+/// within `SERVICE_TIMEOUT`.
+pub const SERVICE_TIMED_OUT: i64 = 91;
+
+/// How long a plugin making a service request waits for the response.
+pub const SERVICE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Chooses the plugin a request for `service` from `consumer` is routed
+/// to, among the plugins active on the consumer's buffer. Both must have
+/// the `Services` capability, and the provider must declare the service.
+/// A plugin's requests are never routed to itself, as it can't answer
+/// them while it waits.
+pub fn choose_provider<'a, I>(consumer: &PluginDescription, active: I, service: &str)
+                              -> Result<&'a PluginDescription, String>
+    where I: IntoIterator<Item=&'a PluginDescription>
+{
+    if !consumer.has_capability(PluginCapability::Services) {
+        return Err(format!("{} doesn't have the services capability", consumer.name));
+    }
+    active.into_iter()
+        .filter(|desc| desc.name != consumer.name)
+        .find(|desc| desc.provides(service))
+        .ok_or_else(|| format!("no active plugin provides the service {}", service))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use super::super::manifest::PluginScope;
+
+    fn plugin(name: &str, services: &[&str], capabilities: Vec<PluginCapability>)
+              -> PluginDescription {
+        PluginDescription {
+            name: name.to_owned(),
+            version: "0.0".to_owned(),
+            scope: PluginScope::BufferLocal,
+            exec_path: PathBuf::from(name),
+            activations: Vec::new(),
+            commands: Vec::new(),
+            priority: 0,
+            services: services.iter().map(|s| s.to_string()).collect(),
+            capabilities: capabilities,
+        }
+    }
+
+    #[test]
+    fn choosing_providers() {
+        use self::PluginCapability::Services;
+        let lsp = plugin("lsp", &[], vec![Services]);
+        let git = plugin("git", &["blame"], vec![Services]);
+        let undeclared = plugin("spy", &["blame"], Vec::new());
+        let active = vec![&undeclared, &git, &lsp];
+        assert_eq!(choose_provider(&lsp, active.clone(), "blame").unwrap().name, "git");
+        assert!(choose_provider(&lsp, active.clone(), "diff").is_err());
+        // consumers and providers both need the capability
+        assert!(choose_provider(&undeclared, active.clone(), "blame").is_err());
+        assert!(choose_provider(&lsp, vec![&undeclared], "blame").is_err());
+        // nor do plugins serve themselves
+        assert!(choose_provider(&git, active, "blame").is_err());
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Finding the replacements of every occurrence of a string, which are
+//! either previewed or applied.
+
+use xi_rope::rope::{Rope, RopeInfo};
+use xi_rope::interval::Interval;
+use xi_rope::delta::{self, Delta};
+use xi_rope::find::{find, CaseMatching};
+use xi_rope::tree::Cursor;
+
+/// The options controlling how occurrences are matched.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ReplaceOptions {
+    pub case_sensitive: bool,
+}
+
+/// The replacement of one occurrence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replacement {
+    pub iv: Interval,
+    pub text: String,
+}
+
+/// Returns the replacements of the non-overlapping occurrences of `query`
+/// in `text`, in order.
+///
+/// In `replacement`, `$0` stands for the occurrence, as it appears in the
+/// text (which may differ in case from `query`), and `$$` for a `$`.
+pub fn find_replacements(text: &Rope, query: &str, replacement: &str,
+                         options: &ReplaceOptions) -> Vec<Replacement> {
+    if query.is_empty() {
+        return Vec::new();
+    }
+    let case_matching = if options.case_sensitive {
+        CaseMatching::Exact
+    } else {
+        CaseMatching::CaseInsensitive
+    };
+    let mut result = Vec::new();
+    let mut cursor = Cursor::new(text, 0);
+    while let Some(start) = find(&mut cursor, case_matching, query) {
+        let end = cursor.pos();
+        let original = text.slice_to_string(start, end);
+        result.push(Replacement {
+            iv: Interval::new_closed_open(start, end),
+            text: expand(replacement, &original),
+        });
+        if end == text.len() {
+            break;
+        }
+    }
+    result
+}
+
+/// Substitutes `matched` into the `template` of a replacement.
+fn expand(template: &str, matched: &str) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('$', Some(&'0')) => {
+                chars.next();
+                result.push_str(matched);
+            }
+            ('$', Some(&'$')) => {
+                chars.next();
+                result.push('$');
+            }
+            (c, _) => result.push(c),
+        }
+    }
+    result
+}
+
+/// Returns the delta applying `replacements` to `text`.
+pub fn replacement_delta(text: &Rope, replacements: &[Replacement]) -> Delta<RopeInfo> {
+    let mut builder = delta::Builder::new(text.len());
+    for replacement in replacements {
+        builder.replace(replacement.iv, Rope::from(&replacement.text));
+    }
+    builder.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replaced(text: &str, query: &str, replacement: &str, case_sensitive: bool) -> String {
+        let text = Rope::from(text);
+        let options = ReplaceOptions { case_sensitive: case_sensitive };
+        let replacements = find_replacements(&text, query, replacement, &options);
+        String::from(replacement_delta(&text, &replacements).apply(&text))
+    }
+
+    #[test]
+    fn replacements() {
+        assert_eq!(replaced("foo bar foo", "foo", "baz", true), "baz bar baz");
+        assert_eq!(replaced("Foo bar foo", "foo", "baz", true), "Foo bar baz");
+        assert_eq!(replaced("Foo bar foo", "foo", "baz", false), "baz bar baz");
+        // occurrences don't overlap
+        assert_eq!(replaced("aaaa", "aa", "b", true), "bb");
+        assert_eq!(replaced("abc", "", "x", true), "abc");
+    }
+
+    #[test]
+    fn substitution() {
+        assert_eq!(replaced("Foo foo", "foo", "<$0>", false), "<Foo> <foo>");
+        assert_eq!(replaced("cost", "cost", "$$5 $1", true), "$5 $1");
+    }
+}
@@ -26,6 +26,11 @@ use serde::ser::{self, Serialize, Serializer};
 
 use tabs::ViewIdentifier;
 use plugins::PlaceholderRpc;
+use replace::ReplaceOptions;
+use spellcheck::DictionaryScope;
+use sanitize::PasteAction;
+use expr::OnError;
+use scratch::ScratchStyle;
 
 
 // =============================================================================
@@ -83,7 +88,7 @@ pub struct EmptyStruct {}
 ///
 /// let cmd: CoreNotification = serde_json::from_str(&json).unwrap();
 /// match cmd {
-///     CoreNotification::ClientStarted( .. )  => (), // expected
+///     CoreNotification::ClientStarted { .. }  => (), // expected
 ///     other => panic!("Unexpected variant"),
 /// }
 /// # }
@@ -171,6 +176,12 @@ pub enum CoreNotification {
     /// # }
     /// ```
     Plugin(PluginNotification),
+    /// Starts a plugin again for the view, after it was suspended for
+    /// exceeding its resource limits.
+    ResumePlugin { view_id: ViewIdentifier, plugin_name: String },
+    /// Hides or shows the styles of the named plugin's layer for the view's
+    /// buffer, keeping its spans.
+    SetLayerEnabled { view_id: ViewIdentifier, plugin_name: String, enabled: bool },
     /// Tells `xi-core` to close the specified view.
     CloseView { view_id: ViewIdentifier },
     /// Tells `xi-core` to save the contents of the specified view's
@@ -178,10 +189,34 @@ pub enum CoreNotification {
     Save { view_id: ViewIdentifier, file_path: String },
     /// Tells `xi-core` to set the theme.
     SetTheme { theme_name: String },
-    /// Notifies `xi-core` that the client has started.
-    //TODO: this should be a unit, but we have a minor issue with serde.
-    //see https://github.com/google/xi-editor/issues/400
-    ClientStarted(EmptyStruct),
+    /// Tells `xi-core` to show a theme without committing to it. The
+    /// previous theme is restored by `CancelThemePreview`.
+    PreviewTheme { theme_name: String },
+    /// Keeps the theme being previewed.
+    CommitTheme,
+    /// Restores the theme from before the first `PreviewTheme`.
+    CancelThemePreview,
+    /// Modifies the user's configuration. `changes` is an object whose
+    /// keys are config fields; see `Config` for the available fields.
+    ModifyUserConfig { changes: Value },
+    /// Applies the user's configuration again, after it was disabled in
+    /// safe mode.
+    ReloadUserConfig,
+    /// Stops all plugins, then discovers them again and starts them on the
+    /// open buffers. If `plugins` is given, only those plugins may start.
+    RestartPlugins { plugins: Option<Vec<String>> },
+    /// Notifies `xi-core` that the client has started. `capabilities`
+    /// lists the optional protocol features the client supports:
+    /// `"line_ids"`, `"visual_scroll_to"` and `"block_clipboard"`. If
+    /// `safe_mode` is true, the user's configuration and plugins are
+    /// disabled, as with the `--safe-mode` flag.
+    ClientStarted { capabilities: Option<Vec<String>>, safe_mode: Option<bool> },
+    /// Notifies `xi-core` that the specified view has gained or lost
+    /// focus in the client.
+    FocusChanged { view_id: ViewIdentifier, focused: bool },
+    /// Notifies `xi-core` that the specified view has been shown or
+    /// hidden in the client, as when it is in a background tab.
+    ViewVisibility { view_id: ViewIdentifier, visible: bool },
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -220,8 +255,37 @@ pub enum CoreRequest {
     /// at that location.
     ///
     /// Returns the view identifier that should be used to interact
-    /// with the newly created view.
-    NewView { file_path: Option<String> },
+    /// with the newly created view. With the `view_only_mmap` `mode`,
+    /// the file is mapped into memory rather than loaded, and can't be
+    /// edited.
+    NewView { file_path: Option<String>, mode: Option<OpenMode> },
+    /// Offers to compress large messages sent in either direction, with
+    /// one of `algorithms`, in order of preference. Returns the chosen
+    /// algorithm and threshold, or `Null` if none is supported, in which
+    /// case messages are not compressed.
+    NegotiateCompression { algorithms: Vec<String>, threshold: Option<usize> },
+    /// Returns the user's configuration, with the committed `theme`, and
+    /// the `previewed_theme`, if one is being previewed. `provenance`
+    /// tells whether core is in safe mode, and whether the user's
+    /// configuration and plugins are enabled.
+    GetConfig,
+    /// Returns the id, the parameters and the English text of every
+    /// message core may show the user, for translators.
+    ListMessageIds,
+    /// Returns the incidents most recently recorded, the internal errors
+    /// core recovered from, with the context they happened in.
+    GetIncidents,
+    /// Creates a view of a new scratch buffer, which is never saved and
+    /// keeps no undo history, and returns its identifier. Unless it is
+    /// `read_only`, which is the default, the user can also edit it.
+    NewScratchView { title: String, read_only: Option<bool> },
+    /// Appends `text` to the scratch buffer of the view, with `styles`
+    /// whose offsets are within `text`.
+    ScratchAppend { view_id: ViewIdentifier, text: String, styles: Option<Vec<ScratchStyle>> },
+    /// Replaces the text of the scratch buffer of the view with `text`,
+    /// with `styles`.
+    ScratchSetContent { view_id: ViewIdentifier, text: String,
+                        styles: Option<Vec<ScratchStyle>> },
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -259,13 +323,53 @@ pub struct EditCommand<T> {
     pub cmd: T,
 }
 
+/// How a file is opened by `new_view`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OpenMode {
+    Editable,
+    /// For viewing huge files, which are mapped into memory.
+    ViewOnlyMmap,
+}
+
+/// A copied rectangular selection, which is pasted as a rectangle.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockClipboard {
+    /// The column of the left edge of the selection.
+    pub column: usize,
+    /// The text selected on each line.
+    pub segments: Vec<String>,
+}
+
 /// An enum representing touch and mouse gestures applied to the text.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 #[serde(rename_all = "snake_case")]
 pub enum GestureType {
+    /// Extends the active region to the point, from its anchor.
+    SelectExtend,
+    /// Adds a caret at the point.
+    SelectAddRegion,
+    /// Removes the region containing the point, or else adds a caret there.
+    SelectToggleRegion,
+    /// The older name of `SelectToggleRegion`.
     ToggleSel,
 }
 
+/// The lines rewritten by `reindent`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ReindentScope {
+    Document,
+    /// The lines touched by the selection.
+    Selection,
+}
+
+impl Default for ReindentScope {
+    fn default() -> Self {
+        ReindentScope::Document
+    }
+}
+
 /// An inclusive range.
 ///
 /// # Note:
@@ -288,6 +392,9 @@ pub struct MouseAction {
     pub column: u64,
     pub flags: u64,
     pub click_count: Option<u64>,
+    /// The revision of the lines the front-end had when the event
+    /// happened, as sent in its last update.
+    pub rev: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -344,17 +451,77 @@ pub enum EditNotification {
     GotoLine { line: u64 },
     RequestLines(LineRange),
     Yank,
+    /// Pastes `chars`, or, if given and there is a single caret, the
+    /// segments of `block`, one per line from the caret's column. With as
+    /// many selection regions as segments, each region is replaced by one.
+    Paste { chars: String, block: Option<BlockClipboard> },
+    /// Answers the alert asking how to paste text with suspicious
+    /// characters, with the `ask` paste sanitization.
+    ResolvePaste { action: PasteAction },
     Transpose,
+    /// Surrounds each selection region, or the word under each caret, with
+    /// `open` and `close`. If `close` is omitted, it is the bracket
+    /// matching `open`, or `open` itself.
+    Surround { open: String, close: Option<String> },
+    /// Removes the innermost pair of brackets or quotes enclosing each
+    /// selection region.
+    Unsurround,
+    /// Replaces the innermost `from` pair enclosing each selection region
+    /// with the `to` pair.
+    ChangeSurround { from: String, to: String },
+    /// Moves each caret to the opening bracket of the innermost pair of
+    /// brackets enclosing it.
+    MoveToEnclosingBracketStart,
+    /// Moves each caret past the closing bracket of the innermost pair of
+    /// brackets enclosing it.
+    MoveToEnclosingBracketEnd,
+    /// Selects the contents of the innermost pair of brackets enclosing each
+    /// selection region, or the next pair out if they are already selected.
+    SelectEnclosingBrackets {
+        #[serde(default)]
+        include_delimiters: bool,
+    },
+    /// Swaps the bracketed groups before and after each caret.
+    TransposeBracketGroups,
+    /// Selects every span whose scopes match the scope selector `selector`,
+    /// such as "string" or "comment - comment.line".
+    SelectScope { selector: String },
+    /// Rewrites the leading whitespace of each line in the current indent
+    /// style, keeping its width, or rounding it to whole levels if `snap`
+    /// is set. Lines starting within strings are skipped, unless
+    /// `skip_strings` is false.
+    Reindent {
+        #[serde(default)]
+        scope: ReindentScope,
+        #[serde(default)]
+        snap: bool,
+        skip_strings: Option<bool>,
+    },
     Click(MouseAction),
     Drag(MouseAction),
-    Gesture { line: u64, col: u64, ty: GestureType},
+    Gesture { line: u64, col: u64, ty: GestureType, rev: Option<u64> },
     Undo,
     Redo,
+    /// Cancels a long running operation. An undo or redo which is being
+    /// applied in chunks is completed immediately.
+    CancelOperation,
     FindNext { wrap_around: Option<bool>, allow_same: Option<bool> },
     FindPrevious { wrap_around: Option<bool> },
+    /// Moves the caret back to the position it last jumped from, as by
+    /// `goto_line`, `find_next` or a click far away.
+    JumpBack,
+    /// Returns to the position `JumpBack` last jumped from.
+    JumpForward,
     DebugRewrap,
     /// Prints the style spans present in the active selection.
     DebugPrintSpans,
+    /// Sets the buffer's language, by its identifier, as in `"python3"`,
+    /// or its name. The language is then never detected from the buffer's
+    /// content.
+    SetLanguage { language_id: String },
+    /// Shows the buffer's invisible characters, as tabs and trailing
+    /// whitespace, or hides them again.
+    ToggleShowInvisibles,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -367,13 +534,97 @@ pub enum EditRequest {
     Cut,
     /// Copies the active selection, returning their contents or
     /// or `Null` if the selection was empty.
+    ///
+    /// For clients with the `block_clipboard` capability, a selection of
+    /// adjacent lines, as made by `add_selection_below`, is returned as an
+    /// object with its `text` and a `BlockClipboard` `block`.
     Copy,
     /// Searches the document for `chars`, if present, falling back on
     /// the last selection region if `chars` is `None`.
     ///
     /// If `chars` is `None` and there is an active selection, returns
     /// the string value used for the search, else returns `Null`.
-    Find { chars: Option<String>, case_sensitive: bool },
+    ///
+    /// If `whole_words` is set, only occurrences which neither start nor
+    /// end inside a word are found, with runs of CJK text divided into
+    /// words as for word movement.
+    Find {
+        chars: Option<String>,
+        case_sensitive: bool,
+        #[serde(default)]
+        whole_words: bool,
+    },
+    /// Finds the replacements of every occurrence of `query`, without
+    /// applying them. Returns up to `max_results` of them, their `total`
+    /// number, and a `token` with which `ReplaceAll` applies all of them.
+    PreviewReplace {
+        query: String,
+        replacement: String,
+        options: Option<ReplaceOptions>,
+        max_results: Option<usize>,
+    },
+    /// Replaces every occurrence of `query`, or, given the `token` of a
+    /// preview, exactly the previewed replacements. Fails if the buffer
+    /// has changed since the preview.
+    ReplaceAll {
+        token: Option<u64>,
+        query: Option<String>,
+        replacement: Option<String>,
+        options: Option<ReplaceOptions>,
+    },
+    /// Replaces the text of each selected region with the value of the
+    /// expression `expr`, as one undo group. Returns the number of
+    /// regions transformed, and the errors of any the expression failed
+    /// on, which are left as they are, or, if `on_error` is `"abort"`,
+    /// leave every region as it is.
+    TransformSelectionWithExpr { expr: String, on_error: Option<OnError> },
+    /// Returns the view's jump list, and the index in it of the current
+    /// position.
+    GetJumpList,
+    /// Returns the status of the buffer and its file, as in the
+    /// `buffer_info` notification.
+    GetBufferInfo,
+    /// Returns summaries of buckets of `lines_per_bucket` lines, or more
+    /// if the buffer is long, for drawing a minimap. Only the buckets
+    /// `first_bucket..last_bucket` are returned, if given.
+    GetMinimap {
+        lines_per_bucket: usize,
+        first_bucket: Option<usize>,
+        last_bucket: Option<usize>,
+    },
+    /// Returns the styles of the lines `first_line..last_line`, as in the
+    /// `styles` of the lines of an `update`, without the selections.
+    GetStyles { first_line: usize, last_line: usize },
+    /// Loads a view-only file into an editable buffer. Does nothing if
+    /// the view is already editable.
+    PromoteToEditable,
+    /// Adds `word` to the buffer's, the project's or the user's dictionary,
+    /// so that spell-checking plugins ignore it.
+    AddWordToDictionary { word: String, scope: DictionaryScope },
+    /// Returns the plain text of visual line `visual_line`, with its
+    /// coordinates, and the caret and selections within it, for
+    /// accessibility clients.
+    GetAccessibilityLine { visual_line: usize },
+    /// Returns the visual lines which changed since `since_token` was
+    /// returned, for accessibility clients which poll for changes.
+    AccessibilityDelta { since_token: u64 },
+    /// Returns the buffer's history, for merging into another core's copy
+    /// of the buffer. Only the version and session are returned if the
+    /// head revision is still `since_rev`.
+    ExportHistory { since_rev: Option<u64> },
+    /// Merges a history returned by `export_history` into the buffer's,
+    /// and returns the new head revision.
+    MergeHistory { payload: Value },
+    /// Exports the buffer as HTML, with the styles of its text inline and
+    /// the theme's colors, broken into the view's lines. Writes it to
+    /// `path`, if given, or else returns it.
+    ExportHtml { path: Option<String> },
+    /// Returns internal statistics about the buffer, for debugging,
+    /// including how much of it the spans of each layer of styles cover.
+    DebugGetStats,
+    /// Returns the spans of each layer of styles from `start` to `end`, or
+    /// within the last selection, with their scopes and styles.
+    DebugStyles { start: Option<usize>, end: Option<usize> },
 }
 
 
@@ -438,8 +689,17 @@ impl Serialize for MouseAction
         #[derive(Serialize)]
         struct Helper(u64, u64, u64, Option<u64>);
 
-        let as_tup = Helper(self.line, self.column, self.flags, self.click_count);
-        let v = serde_json::to_value(&as_tup).map_err(ser::Error::custom)?;
+        let v = match self.rev {
+            Some(rev) => {
+                let as_tup = (self.line, self.column, self.flags,
+                              self.click_count.unwrap_or(0), rev);
+                serde_json::to_value(&as_tup)
+            }
+            None => {
+                let as_tup = Helper(self.line, self.column, self.flags, self.click_count);
+                serde_json::to_value(&as_tup)
+            }
+        }.map_err(ser::Error::custom)?;
         v.serialize(serializer)
     }
 }
@@ -450,8 +710,10 @@ impl<'de> Deserialize<'de> for MouseAction
         where D: Deserializer<'de>
     {
         let v: Vec<u64> = Vec::deserialize(deserializer)?;
-        let click_count = if v.len() == 4 { Some(v[3]) } else { None };
-        Ok(MouseAction { line: v[0], column: v[1], flags: v[2], click_count: click_count })
+        let click_count = if v.len() >= 4 { Some(v[3]) } else { None };
+        let rev = if v.len() == 5 { Some(v[4]) } else { None };
+        Ok(MouseAction { line: v[0], column: v[1], flags: v[2], click_count: click_count,
+                         rev: rev })
     }
 }
 
@@ -0,0 +1,231 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The sanitization of pasted text, which may carry control characters
+//! and escape sequences from a terminal, or invisible characters from a
+//! document, which would silently corrupt a source file.
+
+use std::borrow::Cow;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+/// What is done with suspicious characters in pasted text.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PasteSanitize {
+    /// Text is pasted as it is, though the user is still warned of bidi
+    /// control characters.
+    Off,
+    /// Text is pasted as it is, and the user is told what it contains.
+    Warn,
+    /// Suspicious characters are removed or converted.
+    Strip,
+    /// The user is asked whether to strip them.
+    Ask,
+}
+
+impl Default for PasteSanitize {
+    fn default() -> Self {
+        PasteSanitize::Off
+    }
+}
+
+/// The user's answer when asked how to paste text with suspicious
+/// characters.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PasteAction {
+    Strip,
+    Verbatim,
+    Cancel,
+}
+
+/// The numbers of suspicious characters found in some text.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Suspicious {
+    /// C0 and C1 control characters, other than tabs and newlines, and
+    /// not part of escape sequences.
+    pub control: usize,
+    /// ANSI CSI escape sequences, as for colors in a terminal.
+    pub escapes: usize,
+    /// Carriage returns before newlines.
+    pub carriage_returns: usize,
+    pub nbsp: usize,
+    /// Bidi control characters, which can make code display differently
+    /// from how it is compiled, as in "trojan source" attacks.
+    pub bidi: usize,
+}
+
+impl Suspicious {
+    pub fn is_empty(&self) -> bool {
+        *self == Suspicious::default()
+    }
+
+    /// Only the bidi control characters of `self`.
+    pub fn bidi_only(&self) -> Suspicious {
+        Suspicious { bidi: self.bidi, ..Suspicious::default() }
+    }
+
+    /// Returns a message telling the user what pasted text contained, or
+    /// `None` if it contained nothing suspicious.
+    pub fn describe(&self) -> Option<String> {
+        let counts = [
+            (self.control, "control character"),
+            (self.escapes, "terminal escape sequence"),
+            (self.carriage_returns, "carriage return"),
+            (self.nbsp, "non-breaking space"),
+            (self.bidi, "bidi control character"),
+        ];
+        let mut parts = counts.iter()
+            .filter(|&&(n, _)| n > 0)
+            .map(|&(n, name)| format!("{} {}{}", n, name, if n == 1 { "" } else { "s" }))
+            .collect::<Vec<_>>();
+        let last = parts.pop()?;
+        let listed = if parts.is_empty() { last } else {
+            format!("{} and {}", parts.join(", "), last)
+        };
+        let mut msg = format!("The pasted text contains {}.", listed);
+        if self.bidi > 0 {
+            msg.push_str(" Bidi control characters can make code read differently \
+                          from how it runs.");
+        }
+        Some(msg)
+    }
+}
+
+pub fn is_bidi_control(c: char) -> bool {
+    match c {
+        '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' |
+        '\u{2066}'..='\u{2069}' => true,
+        _ => false,
+    }
+}
+
+fn is_control(c: char) -> bool {
+    (c < ' ' && c != '\t' && c != '\n') || ('\u{7F}'..='\u{9F}').contains(&c)
+}
+
+fn is_suspicious(c: char) -> bool {
+    is_control(c) || c == '\u{A0}' || is_bidi_control(c)
+}
+
+/// Returns `text` with its suspicious characters removed, and what they
+/// were. Escape sequences and carriage returns before newlines are
+/// removed, as are control characters, and non-breaking spaces become
+/// spaces if `nbsp_to_space` is true.
+pub fn sanitize<'a>(text: &'a str, nbsp_to_space: bool) -> (Cow<'a, str>, Suspicious) {
+    let mut found = Suspicious::default();
+    if !text.chars().any(is_suspicious) {
+        return (Cow::Borrowed(text), found);
+    }
+    let mut clean = String::with_capacity(text.len());
+    let mut chars = text.char_indices().peekable();
+    while let Some((ix, c)) = chars.next() {
+        match c {
+            '\r' if text[ix + 1..].starts_with('\n') => found.carriage_returns += 1,
+            '\u{1B}' if text[ix + 1..].starts_with('[') => {
+                chars.next();
+                skip_csi(&mut chars);
+                found.escapes += 1;
+            }
+            '\u{9B}' => {
+                skip_csi(&mut chars);
+                found.escapes += 1;
+            }
+            '\u{A0}' => {
+                found.nbsp += 1;
+                clean.push(if nbsp_to_space { ' ' } else { c });
+            }
+            c if is_bidi_control(c) => found.bidi += 1,
+            c if is_control(c) => found.control += 1,
+            c => clean.push(c),
+        }
+    }
+    (Cow::Owned(clean), found)
+}
+
+/// Skips the parameters and final byte of a CSI escape sequence. A
+/// malformed sequence ends at the first character which can't be in it.
+fn skip_csi(chars: &mut Peekable<CharIndices>) {
+    while let Some(&(_, c)) = chars.peek() {
+        match c {
+            '\u{20}'..='\u{3F}' => { chars.next(); }
+            '\u{40}'..='\u{7E}' => {
+                chars.next();
+                return;
+            }
+            _ => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip(text: &str) -> (String, Suspicious) {
+        let (clean, found) = sanitize(text, false);
+        (clean.into_owned(), found)
+    }
+
+    #[test]
+    fn control_characters() {
+        let (clean, found) = strip("a\u{7}b\tc\u{0}\n\u{85}d\re");
+        assert_eq!(clean, "ab\tc\nde");
+        assert_eq!(found, Suspicious { control: 4, ..Suspicious::default() });
+    }
+
+    #[test]
+    fn escape_sequences() {
+        // colored output from a terminal, and an unfinished sequence
+        let (clean, found) = strip("\u{1B}[1;31merror\u{1B}[0m: x\u{9B}2Ky\u{1B}[");
+        assert_eq!(clean, "error: xy");
+        assert_eq!(found, Suspicious { escapes: 4, ..Suspicious::default() });
+        // an escape which doesn't start a CSI sequence is a control character
+        let (clean, found) = strip("\u{1B}]0;title\u{7}");
+        assert_eq!(clean, "]0;title");
+        assert_eq!(found, Suspicious { control: 2, ..Suspicious::default() });
+    }
+
+    #[test]
+    fn carriage_returns_and_spaces() {
+        let (clean, found) = strip("one\r\ntwo\u{A0}three\r\n");
+        assert_eq!(clean, "one\ntwo\u{A0}three\n");
+        assert_eq!(found, Suspicious { carriage_returns: 2, nbsp: 1, ..Suspicious::default() });
+        assert_eq!(sanitize("two\u{A0}three", true).0, "two three");
+    }
+
+    #[test]
+    fn bidi_controls() {
+        // the "trojan source" comment-out, which displays as a comment
+        let payload = "/*\u{202E} } \u{2066}if (isAdmin)\u{2069} \u{2066} begin admins only */";
+        let (clean, found) = strip(payload);
+        assert_eq!(clean, "/* } if (isAdmin)  begin admins only */");
+        assert_eq!(found, Suspicious { bidi: 4, ..Suspicious::default() });
+        assert!(found.describe().unwrap().contains("4 bidi control characters"));
+    }
+
+    #[test]
+    fn clean_text_is_borrowed() {
+        match sanitize("fn main() {\n\tprintln!(\"é\");\n}", false) {
+            (Cow::Borrowed(_), found) => assert!(found.is_empty()),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(Suspicious::default().describe(), None);
+        let found = Suspicious { control: 1, escapes: 2, bidi: 1, ..Suspicious::default() };
+        assert!(found.describe().unwrap().starts_with("The pasted text contains 1 control \
+            character, 2 terminal escape sequences and 1 bidi control character."));
+        assert_eq!(found.bidi_only(), Suspicious { bidi: 1, ..Suspicious::default() });
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Scratch buffers, for output such as search results, which are never
+//! saved and keep no undo history. Their text is set by whoever created
+//! them, along with its styles.
+
+use xi_rope::interval::Interval;
+use xi_rope::spans::{Spans, SpansBuilder};
+
+use styles::Style;
+
+/// The priority of the styles of a scratch buffer, which win over those
+/// of syntax highlighting.
+pub const SCRATCH_STYLE_PRIORITY: u16 = 300;
+
+/// What makes a buffer a scratch buffer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scratch {
+    /// Shown as the buffer's name.
+    pub title: String,
+    /// If true, the buffer can only be changed by its creator.
+    pub read_only: bool,
+}
+
+/// A style set on the text of a scratch buffer, from `start` to `end` of
+/// the text it comes with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScratchStyle {
+    pub start: usize,
+    pub end: usize,
+    pub fg_color: Option<u32>,
+    pub bg_color: Option<u32>,
+    pub weight: Option<u16>,
+    pub underline: Option<bool>,
+    pub italic: Option<bool>,
+}
+
+impl ScratchStyle {
+    pub fn style(&self) -> Style {
+        Style::new(SCRATCH_STYLE_PRIORITY, self.fg_color, self.bg_color, self.weight,
+                   self.underline, self.italic)
+    }
+}
+
+/// Returns `styles` as the spans of a text of length `len`. The styles
+/// must be in order, and may not overlap.
+pub fn style_spans(styles: &[ScratchStyle], len: usize) -> Result<Spans<Style>, String> {
+    let mut sb = SpansBuilder::new(len);
+    let mut last_end = 0;
+    for style in styles {
+        if style.start < last_end || style.start > style.end || style.end > len {
+            return Err(format!("invalid style span {}..{} of a text of length {}",
+                               style.start, style.end, len));
+        }
+        if style.start < style.end {
+            sb.add_span(Interval::new_closed_open(style.start, style.end), style.style());
+        }
+        last_end = style.end;
+    }
+    Ok(sb.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start: usize, end: usize) -> ScratchStyle {
+        ScratchStyle {
+            start: start,
+            end: end,
+            fg_color: Some(0xffc0_0000),
+            bg_color: None,
+            weight: None,
+            underline: None,
+            italic: Some(true),
+        }
+    }
+
+    #[test]
+    fn scratch_style_spans() {
+        let spans = style_spans(&[span(0, 2), span(2, 2), span(4, 5)], 6).unwrap();
+        let found = spans.iter().map(|(iv, style)| (iv.start(), iv.end(), style.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(found, vec![(0, 2, span(0, 2).style()), (4, 5, span(4, 5).style())]);
+        assert_eq!(found[0].2.priority, SCRATCH_STYLE_PRIORITY);
+
+        assert!(style_spans(&[span(2, 4), span(3, 5)], 6).is_err());
+        assert!(style_spans(&[span(3, 2)], 6).is_err());
+        assert!(style_spans(&[span(4, 7)], 6).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Choosing the words a spell-checking plugin should check, and the
+//! dictionaries of extra words which are never misspelled.
+
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use xi_rope::interval::Interval;
+
+/// The name of the file of extra words shared by a project's files. The
+/// nearest one in a file's directory or its ancestors is used.
+pub const PROJECT_WORDS_FILE: &str = ".xi-words";
+
+/// Spell-checking settings, which plugins receive through `get_words`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpellcheckConfig {
+    /// If not empty, and the buffer has scopes, only words with one of
+    /// these scopes (as in `"comment"` or `"string.quoted"`) are checked.
+    pub included_scopes: Vec<String>,
+    /// Words with one of these scopes are not checked, even if they have
+    /// an included scope.
+    pub excluded_scopes: Vec<String>,
+    /// The language of the dictionary, as in `"en_US"`.
+    pub language: String,
+    /// A file of words, one per line, which are never misspelled.
+    pub extra_words_file: Option<PathBuf>,
+}
+
+impl Default for SpellcheckConfig {
+    fn default() -> Self {
+        SpellcheckConfig {
+            included_scopes: Vec::new(),
+            excluded_scopes: Vec::new(),
+            language: "en_US".to_owned(),
+            extra_words_file: None,
+        }
+    }
+}
+
+/// The dictionary a word is added to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DictionaryScope {
+    /// Only this buffer, until it is closed.
+    Buffer,
+    /// The project's `PROJECT_WORDS_FILE`.
+    Project,
+    /// The user's `extra_words_file`.
+    User,
+}
+
+/// Returns the project words file for the file at `path`: the nearest
+/// existing one, or else one in the file's directory.
+pub fn project_words_file(path: &Path) -> Option<PathBuf> {
+    let dir = path.parent()?;
+    let existing = dir.ancestors()
+        .map(|dir| dir.join(PROJECT_WORDS_FILE))
+        .find(|file| file.is_file());
+    Some(existing.unwrap_or_else(|| dir.join(PROJECT_WORDS_FILE)))
+}
+
+/// Reads a file of words, one per line. A missing file has no words.
+pub fn read_words(path: &Path) -> BTreeSet<String> {
+    let mut contents = String::new();
+    if let Ok(mut f) = File::open(path) {
+        if let Err(err) = f.read_to_string(&mut contents) {
+            print_err!("can't read words from {:?}: {}", path, err);
+        }
+    }
+    contents.lines()
+        .map(str::trim)
+        .filter(|word| !word.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Appends `word` to a file of words, creating it if needed.
+pub fn append_word(path: &Path, word: &str) -> io::Result<()> {
+    let needs_newline = read_to_end(path).map(|bytes| {
+        bytes.last().map_or(false, |&b| b != b'\n')
+    }).unwrap_or(false);
+    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+    if needs_newline {
+        f.write_all(b"\n")?;
+    }
+    writeln!(f, "{}", word)
+}
+
+fn read_to_end(path: &Path) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Returns the words in `text`, as offsets of their start and end. Words
+/// are runs of letters, possibly joined by apostrophes, as in "don't";
+/// runs containing digits, such as "x86", aren't words.
+pub fn find_words(text: &str) -> Vec<(usize, usize)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut has_digit = false;
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '\'';
+    for (ix, c) in text.char_indices().chain(Some((text.len(), ' '))) {
+        if is_word_char(c) {
+            if start.is_none() {
+                start = Some(ix);
+                has_digit = false;
+            }
+            has_digit |= c.is_numeric();
+        } else if let Some(word_start) = start.take() {
+            let word = &text[word_start..ix];
+            let trimmed = word.trim_matches('\'');
+            if !has_digit && !trimmed.is_empty() {
+                let leading = word.len() - word.trim_start_matches('\'').len();
+                let start = word_start + leading;
+                words.push((start, start + trimmed.len()));
+            }
+        }
+    }
+    words
+}
+
+/// Returns `true` if any of `intervals` contains `offset`.
+pub fn in_any(intervals: &[Interval], offset: usize) -> bool {
+    intervals.iter().any(|iv| iv.contains(offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn words(text: &str) -> Vec<&str> {
+        find_words(text).into_iter().map(|(start, end)| &text[start..end]).collect()
+    }
+
+    #[test]
+    fn finding_words() {
+        assert_eq!(words("// don't 'quote' x86 übung, 42"),
+                   vec!["don't", "quote", "übung"]);
+        assert_eq!(words("snake_case"), vec!["snake", "case"]);
+        assert!(words("'' 1 --").is_empty());
+    }
+
+    #[test]
+    fn word_files() {
+        let mut dir = env::temp_dir();
+        dir.push("xi-spellcheck-word-files");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src")).unwrap();
+        let file = dir.join("src").join("main.rs");
+        assert_eq!(project_words_file(&file), Some(dir.join("src").join(PROJECT_WORDS_FILE)));
+
+        let words_file = dir.join(PROJECT_WORDS_FILE);
+        fs::write(&words_file, "xi").unwrap();
+        assert_eq!(project_words_file(&file), Some(words_file.clone()));
+        append_word(&words_file, "rope").unwrap();
+        let words = read_words(&words_file);
+        assert_eq!(words.iter().collect::<Vec<_>>(), vec!["rope", "xi"]);
+        assert!(read_words(&dir.join("missing")).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
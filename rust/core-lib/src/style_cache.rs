@@ -0,0 +1,233 @@
+// Copyright 2018 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The lines held by a front-end which accepts `update_styles` ops, so that
+//! a line whose text is unchanged, but whose styles have changed, can be
+//! updated with only the spans which differ, rather than resent in full.
+//!
+//! Each line is keyed by its current visual line number, and remembers its
+//! index in the front-end's cache, which only changes when an update is
+//! sent. Edits drop the lines they touch, and renumber those after them.
+
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use serde_json::{self, Value};
+
+struct CachedLine {
+    /// The position of the line in the front-end's cache.
+    index: usize,
+    /// A hash of the line as rendered, without its styles.
+    hash: u64,
+    /// The styles of the line, as sent.
+    styles: Vec<isize>,
+}
+
+pub struct StyleCache {
+    /// The lines the front-end holds, by visual line number.
+    lines: BTreeMap<usize, CachedLine>,
+    /// The number of visual lines, as of the last update or edit.
+    n_lines: usize,
+}
+
+impl StyleCache {
+    pub fn new(n_lines: usize) -> StyleCache {
+        StyleCache { lines: BTreeMap::new(), n_lines: n_lines }
+    }
+
+    /// Forgets every line, as after the view is rewrapped.
+    pub fn clear(&mut self, n_lines: usize) {
+        self.lines.clear();
+        self.n_lines = n_lines;
+    }
+
+    /// Notes that the front-end has been sent `rendered` as `line`, at the
+    /// same index.
+    pub fn record(&mut self, line: usize, rendered: &Value) {
+        let (hash, styles) = summarize(rendered);
+        self.lines.insert(line, CachedLine { index: line, hash: hash, styles: styles });
+    }
+
+    /// Forgets `line`, which the front-end has been sent without its styles
+    /// being known, or which it no longer holds.
+    pub fn remove(&mut self, line: usize) {
+        self.lines.remove(&line);
+    }
+
+    /// Updates the cache after an edit, which changed the lines from `first`
+    /// up to `tail`, in new line numbers, leaving `n_lines` visual lines.
+    pub fn after_edit(&mut self, first: usize, tail: usize, n_lines: usize) {
+        let old_tail = (tail + self.n_lines).saturating_sub(n_lines);
+        let moved = self.lines.split_off(&old_tail);
+        self.lines.split_off(&first);
+        for (line, cached) in moved {
+            self.lines.insert(line + n_lines - self.n_lines, cached);
+        }
+        self.n_lines = n_lines;
+    }
+
+    /// Returns the ops of an update which sends `lines`, rendered from
+    /// `first_line`, in a view of `height` visual lines, copying or
+    /// restyling those the front-end already has. If `dirty`, the lines
+    /// outside of them are invalidated, rather than copied.
+    pub fn update_ops(&mut self, lines: Vec<Value>, first_line: usize, height: usize,
+                      dirty: bool) -> Vec<Value> {
+        let last_line = first_line + lines.len();
+        let mut ops = Vec::new();
+        let mut old_ix = 0;
+        if dirty {
+            push_op(&mut ops, "invalidate", first_line, None);
+        } else {
+            push_op(&mut ops, "copy", first_line, None);
+            old_ix = first_line;
+        }
+        let mut sent = Vec::with_capacity(lines.len());
+        for (line_num, line) in (first_line..last_line).zip(lines) {
+            let (hash, styles) = summarize(&line);
+            match self.lines.get(&line_num) {
+                Some(cached) if cached.hash == hash && cached.index >= old_ix => {
+                    push_op(&mut ops, "skip", cached.index - old_ix, None);
+                    if cached.styles == styles {
+                        push_op(&mut ops, "copy", 1, None);
+                    } else {
+                        push_op(&mut ops, "update_styles", 1,
+                                Some(diff_styles(&cached.styles, &styles)));
+                    }
+                    old_ix = cached.index + 1;
+                }
+                _ => push_op(&mut ops, "ins", 1, Some(line)),
+            }
+            sent.push((line_num, CachedLine { index: line_num, hash: hash, styles: styles }));
+        }
+        if last_line < height {
+            if dirty {
+                push_op(&mut ops, "invalidate", height - last_line, None);
+            } else {
+                push_op(&mut ops, "skip", last_line - old_ix, None);
+                push_op(&mut ops, "copy", height - last_line, None);
+            }
+        }
+        if dirty {
+            self.lines.clear();
+        }
+        self.lines.extend(sent);
+        self.n_lines = height;
+        ops
+    }
+}
+
+/// Returns a hash of `rendered` without its styles, and the styles.
+fn summarize(rendered: &Value) -> (u64, Vec<isize>) {
+    let mut rendered = rendered.clone();
+    let styles = rendered.as_object_mut()
+        .and_then(|line| line.remove("styles"))
+        .and_then(|styles| serde_json::from_value(styles).ok())
+        .unwrap_or_default();
+    let mut hasher = DefaultHasher::new();
+    rendered.to_string().hash(&mut hasher);
+    (hasher.finish(), styles)
+}
+
+/// Returns the edit which turns the styles `old` into `new`, as the index
+/// of the first span which differs, the number of spans it removes, and
+/// the spans which replace them. Spans are counted as the triples of the
+/// styles array.
+fn diff_styles(old: &[isize], new: &[isize]) -> Value {
+    let old = old.chunks(3).collect::<Vec<_>>();
+    let new = new.chunks(3).collect::<Vec<_>>();
+    let prefix = old.iter().zip(new.iter()).take_while(|&(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let styles = new[prefix..new.len() - suffix].iter()
+        .flat_map(|span| span.iter().cloned())
+        .collect::<Vec<_>>();
+    json!({
+        "start": prefix,
+        "remove": old.len() - prefix - suffix,
+        "styles": styles,
+    })
+}
+
+/// Appends an op of `n` lines to `ops`, merging it with the last if that
+/// has the same kind.
+fn push_op(ops: &mut Vec<Value>, op: &str, n: usize, line: Option<Value>) {
+    if n == 0 {
+        return;
+    }
+    if let Some(last) = ops.last_mut() {
+        if last["op"] == op {
+            last["n"] = json!(last["n"].as_u64().unwrap() as usize + n);
+            if let Some(line) = line {
+                last["lines"].as_array_mut().unwrap().push(line);
+            }
+            return;
+        }
+    }
+    let mut update = json!({
+        "op": op,
+        "n": n,
+    });
+    if let Some(line) = line {
+        update["lines"] = json!([line]);
+    }
+    ops.push(update);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(text: &str, styles: &[isize]) -> Value {
+        json!({ "text": text, "styles": styles })
+    }
+
+    #[test]
+    fn style_diffs() {
+        let old = [0, 2, 3, 1, 2, 4, 1, 2, 5];
+        let new = [0, 2, 3, 1, 3, 6, 0, 2, 5];
+        assert_eq!(diff_styles(&old, &new),
+                   json!({ "start": 1, "remove": 2, "styles": [1, 3, 6, 0, 2, 5] }));
+        assert_eq!(diff_styles(&old, &old), json!({ "start": 3, "remove": 0, "styles": [] }));
+        assert_eq!(diff_styles(&old, &[0, 2, 3, 1, 2, 5]),
+                   json!({ "start": 1, "remove": 1, "styles": [] }));
+    }
+
+    #[test]
+    fn lines_after_edit() {
+        let mut cache = StyleCache::new(10);
+        let lines = (0..10).map(|i| line(&i.to_string(), &[0, 1, 2])).collect::<Vec<_>>();
+        let ops = cache.update_ops(lines.clone(), 0, 10, true);
+        assert_eq!(ops, vec![json!({ "op": "ins", "n": 10, "lines": lines })]);
+
+        // lines 3 and 4 are replaced by three lines, moving those after them
+        cache.after_edit(3, 6, 11);
+        let mut lines = (0..3).chain(2..10).map(|i| line(&i.to_string(), &[0, 1, 2]))
+            .collect::<Vec<_>>();
+        lines[9] = line("8", &[0, 1, 3]);
+        let ops = cache.update_ops(lines[1..].to_vec(), 1, 11, true);
+        assert_eq!(ops, vec![
+            json!({ "op": "invalidate", "n": 1 }),
+            json!({ "op": "skip", "n": 1 }),
+            json!({ "op": "copy", "n": 2 }),
+            json!({ "op": "ins", "n": 3, "lines": lines[3..6].to_vec() }),
+            json!({ "op": "skip", "n": 2 }),
+            json!({ "op": "copy", "n": 3 }),
+            json!({ "op": "update_styles", "n": 1,
+                    "lines": [{ "start": 0, "remove": 1, "styles": [0, 1, 3] }] }),
+            json!({ "op": "copy", "n": 1 }),
+        ]);
+    }
+}
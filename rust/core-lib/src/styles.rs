@@ -15,13 +15,16 @@
 //! Management of styles.
 
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use serde_json::{self, Value};
 use syntect::highlighting::StyleModifier as SynStyleModifier;
 use syntect::highlighting::{Color, Theme, ThemeSet, ThemeSettings, Highlighter, BLACK};
 
-const N_RESERVED_STYLES: usize = 2;
-const SYNTAX_PRIORITY_DEFAULT: u16 = 200;
+const N_RESERVED_STYLES: usize = 3;
+/// The priority of the styles resolved from scopes, and of those sent by
+/// plugins directly.
+pub const SYNTAX_PRIORITY_DEFAULT: u16 = 200;
 const SYNTAX_PRIORITY_LOWEST: u16 = 0;
 
 #[derive(Clone, PartialEq, Eq, Default, Hash, Debug, Serialize, Deserialize)]
@@ -33,7 +36,7 @@ const SYNTAX_PRIORITY_LOWEST: u16 = 0;
 pub struct Style {
     /// The priority of this style, in the range (0, 1000). Used to resolve
     /// conflicting fields when merging styles. The higher priority wins.
-    #[serde(skip_serializing)]
+    #[serde(skip_serializing, default)]
     pub priority: u16,
     /// The foreground text color, in ARGB.
     pub fg_color: Option<u32>,
@@ -46,8 +49,65 @@ pub struct Style {
     pub weight: Option<u16>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub underline: Option<bool>,
+    /// How the text is underlined, if it is; solid if unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub underline_style: Option<UnderlineStyle>,
+    /// The color of the underline, in ARGB; that of the text if unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub underline_color: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub italic: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strikethrough: Option<bool>,
+    /// Set on the style which fills the gaps between a layer's spans, so
+    /// that text the layer leaves unstyled can be told from text no layer
+    /// covers. It gives way to any other style, and is never rendered.
+    #[serde(skip)]
+    pub is_default: bool,
+}
+
+/// Changes to some of the attributes of a style, as a user's override of
+/// the theme's style for some scopes: those which are `None` are left as
+/// they are.
+#[derive(Clone, PartialEq, Eq, Default, Debug, Serialize, Deserialize)]
+pub struct StyleDelta {
+    pub fg_color: Option<u32>,
+    pub bg_color: Option<u32>,
+    pub weight: Option<u16>,
+    pub underline: Option<bool>,
+    pub underline_style: Option<UnderlineStyle>,
+    pub underline_color: Option<u32>,
+    pub italic: Option<bool>,
+    pub strikethrough: Option<bool>,
+}
+
+/// A modifier of a scope stack, as of a semantic token, for a deprecated
+/// symbol; it is struck through.
+pub const MODIFIER_DEPRECATED: u32 = 1;
+/// A modifier for a read-only symbol, which is italic.
+pub const MODIFIER_READONLY: u32 = 1 << 1;
+
+impl StyleDelta {
+    /// Returns the changes the bits of `modifiers`, such as
+    /// `MODIFIER_DEPRECATED`, make to the style of a scope stack. Unknown
+    /// bits change nothing.
+    pub fn for_modifiers(modifiers: u32) -> StyleDelta {
+        let flag = |modifier| if modifiers & modifier != 0 { Some(true) } else { None };
+        StyleDelta {
+            strikethrough: flag(MODIFIER_DEPRECATED),
+            italic: flag(MODIFIER_READONLY),
+            ..StyleDelta::default()
+        }
+    }
+}
+
+/// The line text is underlined with.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnderlineStyle {
+    Solid,
+    /// A wavy line, as under misspelled words or errors.
+    Squiggle,
 }
 
 impl Style {
@@ -84,10 +144,19 @@ impl Style {
             bg_color: bg_color.into(),
             weight: weight.into(),
             underline: underline.into(),
+            underline_style: None,
+            underline_color: None,
             italic: italic.into(),
+            strikethrough: None,
+            is_default: false,
         }
     }
 
+    /// Returns the style of the gaps between a layer's spans.
+    pub fn gap() -> Self {
+        Style { is_default: true, ..Style::default() }
+    }
+
     /// Returns the default style for the given `Theme`.
     pub fn default_for_theme(theme: &Theme) -> Self {
         let fg = theme.settings.foreground.unwrap_or(BLACK);
@@ -100,9 +169,65 @@ impl Style {
             None)
     }
 
+    /// Returns the background color of the given `Theme`, in ARGB.
+    pub fn background_color_for_theme(theme: &Theme) -> Option<u32> {
+        theme.settings.background.map(|color| Self::rgba_from_syntect_color(&color))
+    }
+
+    /// Returns the color of invisible characters for the given `Theme`, in
+    /// ARGB: its foreground, blended halfway into its background.
+    pub fn invisibles_color_for_theme(theme: &Theme) -> u32 {
+        let fg = Self::rgba_from_syntect_color(&theme.settings.foreground.unwrap_or(BLACK));
+        Self::background_color_for_theme(theme).map_or(fg, |bg| blend(fg, bg, 0x80))
+    }
+
+    /// Returns the color of selections for the given `Theme`, in ARGB.
+    pub fn selection_color_for_theme(theme: &Theme) -> Option<u32> {
+        theme.settings.selection.map(|color| Self::rgba_from_syntect_color(&color))
+    }
+
+    /// Returns the style of find highlights for the given `Theme`, with
+    /// `priority`.
+    pub fn find_highlight_for_theme(theme: &Theme, priority: u16) -> Style {
+        let color = |color: Option<Color>| color.map(|color| Self::rgba_from_syntect_color(&color));
+        Style {
+            priority: priority,
+            fg_color: color(theme.settings.find_highlight_foreground),
+            bg_color: color(theme.settings.find_highlight),
+            ..Style::default()
+        }
+    }
+
+    /// Returns the style with `color`, in RGB, blended into its foreground
+    /// color by `alpha`, from none of it at 0 to all of it at 255.
+    pub fn blend_fg(&self, color: u32, alpha: u8) -> Style {
+        Style {
+            fg_color: self.fg_color.map(|fg| blend(fg, color, alpha)),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the style with the attributes `delta` sets replaced, keeping
+    /// its priority.
+    pub fn apply_delta(&self, delta: &StyleDelta) -> Style {
+        Style {
+            priority: self.priority,
+            fg_color: delta.fg_color.or(self.fg_color),
+            bg_color: delta.bg_color.or(self.bg_color),
+            weight: delta.weight.or(self.weight),
+            underline: delta.underline.or(self.underline),
+            underline_style: delta.underline_style.or(self.underline_style),
+            underline_color: delta.underline_color.or(self.underline_color),
+            italic: delta.italic.or(self.italic),
+            strikethrough: delta.strikethrough.or(self.strikethrough),
+            is_default: self.is_default,
+        }
+    }
+
     /// Creates a new style by combining attributes of `self` and `other`.
     /// If both styles define an attribute, the highest priority wins; `other`
-    /// wins in the case of a tie.
+    /// wins in the case of a tie. Each attribute is merged on its own, so
+    /// that a style which only underlines keeps the other's colors.
     ///
     /// Note: when merging multiple styles, apply them in increasing priority.
     pub fn merge(&self, other: &Style) -> Style {
@@ -112,15 +237,18 @@ impl Style {
             (other, self)
         };
 
-        Style::new(
-            p1.priority,
-            p1.fg_color.or(p2.fg_color),
-            //TODO: stop ignoring background color
-            None,
-            p1.weight.or(p2.weight),
-            p1.underline.or(p2.underline),
-            p1.italic.or(p2.italic),
-            )
+        Style {
+            priority: p1.priority,
+            fg_color: p1.fg_color.or(p2.fg_color),
+            bg_color: p1.bg_color.or(p2.bg_color),
+            weight: p1.weight.or(p2.weight),
+            underline: p1.underline.or(p2.underline),
+            underline_style: p1.underline_style.or(p2.underline_style),
+            underline_color: p1.underline_color.or(p2.underline_color),
+            italic: p1.italic.or(p2.italic),
+            strikethrough: p1.strikethrough.or(p2.strikethrough),
+            is_default: p1.is_default && p2.is_default,
+        }
     }
 
     /// Encode this `Style`, setting the `id` property.
@@ -138,11 +266,21 @@ impl Style {
     }
 }
 
+/// Blends the RGB channels of `over` into those of `color` by `alpha`,
+/// keeping the alpha channel of `color`.
+pub fn blend(color: u32, over: u32, alpha: u8) -> u32 {
+    let channel = |shift: u32| {
+        let (a, b) = ((color >> shift) & 0xFF, (over >> shift) & 0xFF);
+        ((a * (255 - alpha as u32) + b * alpha as u32) / 255) << shift
+    };
+    (color & 0xFF00_0000) | channel(16) | channel(8) | channel(0)
+}
+
 /// A map from styles to client identifiers for a given `Theme`.
 pub struct ThemeStyleMap {
     themes: ThemeSet,
     theme_name: String,
-    theme: Theme,
+    theme: Arc<Theme>,
     default_style: Style,
     map: HashMap<Style, usize>,
 
@@ -155,7 +293,7 @@ impl ThemeStyleMap {
     pub fn new() -> ThemeStyleMap {
         let themes = ThemeSet::load_defaults();
         let theme_name = "InspiredGitHub".to_owned();
-        let theme = themes.themes.get(&theme_name).expect("missing theme").to_owned();
+        let theme = Arc::new(themes.themes.get(&theme_name).expect("missing theme").to_owned());
         let default_style = Style::default_for_theme(&theme);
 
         ThemeStyleMap {
@@ -176,6 +314,12 @@ impl ThemeStyleMap {
         Highlighter::new(&self.theme)
     }
 
+    /// Returns the current theme, which styles can be computed from
+    /// without holding the style map's lock.
+    pub fn get_theme(&self) -> Arc<Theme> {
+        self.theme.clone()
+    }
+
     pub fn get_theme_name(&self) -> &str {
         &self.theme_name
     }
@@ -193,7 +337,7 @@ impl ThemeStyleMap {
             return Ok(())
         }
         if let Some(new_theme) = self.themes.themes.get(theme_name) {
-            self.theme = new_theme.to_owned();
+            self.theme = Arc::new(new_theme.to_owned());
             self.theme_name = theme_name.to_owned();
             self.default_style = Style::default_for_theme(&self.theme);
             self.map = HashMap::new();
@@ -219,3 +363,57 @@ impl ThemeStyleMap {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_attributes() {
+        let syntax = Style::new(SYNTAX_PRIORITY_DEFAULT, Some(0xFF11_2233), None, 700, None, None);
+        let squiggle = Style {
+            priority: 900,
+            underline: Some(true),
+            underline_style: Some(UnderlineStyle::Squiggle),
+            underline_color: Some(0xFFFF_0000),
+            ..Style::default()
+        };
+        let merged = syntax.merge(&squiggle);
+        assert_eq!(merged, Style { fg_color: Some(0xFF11_2233), weight: Some(700), ..squiggle });
+        assert_eq!(squiggle.merge(&syntax), merged);
+
+        // where both set an attribute, the higher priority wins
+        let background = Style { priority: 300, fg_color: Some(0xFF44_5566),
+                                 bg_color: Some(0xFFEE_EEEE), strikethrough: Some(false),
+                                 ..Style::default() };
+        let merged = merged.merge(&background);
+        assert_eq!(merged.fg_color, Some(0xFF11_2233));
+        assert_eq!(merged.bg_color, Some(0xFFEE_EEEE));
+        assert_eq!(merged.strikethrough, Some(false));
+        assert_eq!(merged.priority, 900);
+
+        // a delta replaces just the attributes it sets
+        let delta = StyleDelta { italic: Some(true), weight: Some(400), ..StyleDelta::default() };
+        let changed = syntax.apply_delta(&delta);
+        assert_eq!(changed, Style { italic: Some(true), weight: Some(400), ..syntax.clone() });
+        let deprecated = syntax.apply_delta(&StyleDelta::for_modifiers(MODIFIER_DEPRECATED | 8));
+        assert_eq!(deprecated, Style { strikethrough: Some(true), ..syntax.clone() });
+
+        let json = merged.to_json(3);
+        assert_eq!(json["underline_style"], json!("squiggle"));
+        assert_eq!(json["underline_color"], json!(0xFFFF_0000u32));
+        assert_eq!(json["strikethrough"], json!(false));
+        assert!(json.get("italic").is_none());
+    }
+
+    #[test]
+    fn blend_colors() {
+        assert_eq!(blend(0xFF00_80FF, 0x80FF_FF00, 0), 0xFF00_80FF);
+        assert_eq!(blend(0xFF00_80FF, 0x80FF_FF00, 255), 0xFFFF_FF00);
+        assert_eq!(blend(0xFF00_80FF, 0x80FF_FF00, 51), 0xFF33_99CC);
+        let style = Style::new(SYNTAX_PRIORITY_DEFAULT, Some(0xFF00_0000), None, 700, None, None);
+        assert_eq!(style.blend_fg(0xFFFF_FFFF, 255).fg_color, Some(0xFFFF_FFFF));
+        assert_eq!(style.blend_fg(0xFFFF_FFFF, 255).weight, Some(700));
+        assert_eq!(Style::default().blend_fg(0xFFFF_FFFF, 255), Style::default());
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! "Did you mean" suggestions of near-misses, for errors about unknown
+//! names of commands, plugins and files.
+
+use std::cmp::{max, min};
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use xi_rpc::RemoteError;
+
+/// At most this many candidates are compared with a name, so that finding
+/// suggestions for it is never slow.
+pub const MAX_CANDIDATES: usize = 1000;
+
+/// At most this many suggestions are made.
+pub const MAX_SUGGESTIONS: usize = 3;
+
+/// Returns the Damerau-Levenshtein distance between `a` and `b`, counting
+/// the swap of two adjacent characters as a single edit, or `None` if it
+/// is more than `bound`.
+pub fn distance(a: &str, b: &str, bound: usize) -> Option<usize> {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    if max(a.len(), b.len()) - min(a.len(), b.len()) > bound {
+        return None;
+    }
+    // three rows of the table of distances between prefixes
+    let mut prev2 = vec![0; b.len() + 1];
+    let mut prev = (0..b.len() + 1).collect::<Vec<_>>();
+    let mut row = vec![0; b.len() + 1];
+    for i in 1..a.len() + 1 {
+        row[0] = i;
+        for j in 1..b.len() + 1 {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            row[j] = min(min(prev[j] + 1, row[j - 1] + 1), prev[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = min(row[j], prev2[j - 2] + 1);
+            }
+        }
+        // distances only grow down the table, though a swap reaches back
+        // two rows
+        if row.iter().all(|&d| d > bound) && prev.iter().all(|&d| d >= bound) {
+            return None;
+        }
+        prev2 = mem::replace(&mut prev, row.clone());
+    }
+    Some(prev[b.len()]).filter(|&d| d <= bound)
+}
+
+/// Returns the candidates nearest to `name`, nearest first, ignoring
+/// case. A candidate is near if at most a third of its characters need
+/// changing. Only the first `MAX_CANDIDATES` are compared.
+pub fn suggest<I, S>(name: &str, candidates: I) -> Vec<String>
+    where I: IntoIterator<Item=S>,
+          S: AsRef<str>,
+{
+    let lower = name.to_lowercase();
+    let bound = max(1, name.chars().count() / 3);
+    let mut near = candidates.into_iter()
+        .take(MAX_CANDIDATES)
+        .filter(|c| c.as_ref() != name)
+        .filter_map(|c| {
+            distance(&lower, &c.as_ref().to_lowercase(), bound)
+                .map(|d| (d, c.as_ref().to_owned()))
+        })
+        .collect::<Vec<_>>();
+    near.sort();
+    near.dedup();
+    near.into_iter().take(MAX_SUGGESTIONS).map(|(_, c)| c).collect()
+}
+
+/// Returns the files near `path` in its directory, for when it doesn't
+/// exist. Only the first `MAX_CANDIDATES` entries are read.
+pub fn suggest_paths(path: &Path) -> Vec<PathBuf> {
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_string_lossy()),
+        _ => return Vec::new(),
+    };
+    let dir_or_cwd = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let names = match fs::read_dir(dir_or_cwd) {
+        Ok(entries) => entries.take(MAX_CANDIDATES)
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>(),
+        Err(_) => return Vec::new(),
+    };
+    suggest(&name, &names).into_iter().map(|name| dir.join(name)).collect()
+}
+
+/// Returns a sentence suggesting `suggestions`, to append to an error
+/// message, or an empty string if there are none.
+pub fn did_you_mean<S: AsRef<str>>(suggestions: &[S]) -> String {
+    if suggestions.is_empty() {
+        return String::new();
+    }
+    let quoted = suggestions.iter()
+        .map(|s| format!("'{}'", s.as_ref()))
+        .collect::<Vec<_>>();
+    format!(" Did you mean {}?", quoted.join(" or "))
+}
+
+/// Adds suggestions to the error for a request which couldn't be parsed
+/// because its method, or the method of an edit command, is unknown. The
+/// error's data becomes `{"message": <the original>, "suggestions": [..]}`.
+pub fn suggest_methods(err: RemoteError) -> RemoteError {
+    let suggestions = match err {
+        RemoteError::InvalidRequest(Some(Value::String(ref msg))) => {
+            unknown_variant(msg).map(|(name, candidates)| suggest(name, candidates))
+        }
+        _ => None,
+    };
+    match (err, suggestions) {
+        (RemoteError::InvalidRequest(Some(msg)), Some(ref suggestions))
+            if !suggestions.is_empty() => {
+            RemoteError::InvalidRequest(Some(json!({
+                "message": msg,
+                "suggestions": suggestions,
+            })))
+        }
+        (err, _) => err,
+    }
+}
+
+/// Returns the unknown name, and the known ones, from serde's error
+/// for an unknown variant, as in "unknown variant `a`, expected `b` or `c`".
+fn unknown_variant(msg: &str) -> Option<(&str, Vec<&str>)> {
+    if !msg.starts_with("unknown variant `") {
+        return None;
+    }
+    // the names are the quoted pieces
+    let mut names = msg.split('`').skip(1).step_by(2);
+    names.next().map(|name| (name, names.collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn distances() {
+        assert_eq!(distance("insert", "insert", 2), Some(0));
+        assert_eq!(distance("insrt", "insert", 2), Some(1));
+        assert_eq!(distance("isnert", "insert", 2), Some(1));
+        assert_eq!(distance("undo", "redo", 2), Some(2));
+        assert_eq!(distance("undo", "redo", 1), None);
+        assert_eq!(distance("", "abc", 3), Some(3));
+        assert_eq!(distance("a", "abcdef", 2), None);
+        assert_eq!(distance("übung", "ubung", 1), Some(1));
+    }
+
+    #[test]
+    fn suggestions() {
+        let commands = ["insert", "insert_newline", "insert_tab", "delete_forward"];
+        assert_eq!(suggest("insetr", &commands), vec!["insert"]);
+        assert_eq!(suggest("Insert_Tba", &commands), vec!["insert_tab"]);
+        assert!(suggest("scroll", &commands).is_empty());
+        assert!(suggest("insert", &commands).is_empty());
+        assert_eq!(did_you_mean(&["a", "b"]), " Did you mean 'a' or 'b'?");
+        assert_eq!(did_you_mean::<&str>(&[]), "");
+
+        // only the first candidates are compared, and a few suggested
+        let many = (0..MAX_CANDIDATES).map(|i| format!("command{}", i)).collect::<Vec<_>>();
+        assert_eq!(suggest("command5x", &many).len(), MAX_SUGGESTIONS);
+        assert_eq!(suggest("command5x", &many)[0], "command5");
+        let late = many.iter().map(String::as_str).chain(Some("insert"));
+        assert!(suggest("insetr", late).is_empty());
+    }
+
+    #[test]
+    fn method_suggestions() {
+        let err = RemoteError::InvalidRequest(Some(json!(
+            "unknown variant `fnid`, expected one of `find`, `copy`, `cut`")));
+        assert_eq!(suggest_methods(err), RemoteError::InvalidRequest(Some(json!({
+            "message": "unknown variant `fnid`, expected one of `find`, `copy`, `cut`",
+            "suggestions": ["find"],
+        }))));
+        let err = RemoteError::InvalidRequest(Some(json!(
+            "unknown variant `zzz`, expected `find` or `copy`")));
+        assert_eq!(suggest_methods(err.clone()), err);
+        let err = RemoteError::InvalidRequest(Some(json!("missing field `view_id`")));
+        assert_eq!(suggest_methods(err.clone()), err);
+    }
+
+    #[test]
+    fn path_suggestions() {
+        let mut dir = env::temp_dir();
+        dir.push("xi-suggest-paths");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("main.rs"), "").unwrap();
+        fs::write(dir.join("lib.rs"), "").unwrap();
+        assert_eq!(suggest_paths(&dir.join("mian.rs")), vec![dir.join("main.rs")]);
+        assert!(suggest_paths(&dir.join("other.txt")).is_empty());
+        assert!(suggest_paths(&dir.join("missing").join("main.rs")).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Finding the pairs of delimiters, such as brackets and quotes, which
+//! surround regions of text.
+
+use xi_rope::rope::Rope;
+use xi_rope::interval::Interval;
+
+/// Pairs of distinct opening and closing delimiters.
+const BRACKETS: &[(&str, &str)] = &[
+    ("(", ")"), ("[", "]"), ("{", "}"), ("<", ">"),
+    ("\u{201c}", "\u{201d}"), ("\u{2018}", "\u{2019}"), ("\u{ab}", "\u{bb}"),
+    ("\u{300c}", "\u{300d}"), ("\u{300e}", "\u{300f}"), ("\u{ff08}", "\u{ff09}"),
+    ("\u{3010}", "\u{3011}"),
+];
+
+/// Delimiters which both open and close.
+const QUOTES: &[&str] = &["\"", "'", "`"];
+
+/// The number of bytes scanned in each direction for an enclosing bracket.
+const SCAN_LIMIT: usize = 1 << 16;
+
+/// A pair of delimiters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delimiters {
+    pub open: String,
+    pub close: String,
+}
+
+impl Delimiters {
+    /// Validates a pair of delimiters given by the user. If `close` is
+    /// omitted, `open` must be a single grapheme, and it is closed by the
+    /// matching bracket, or by itself.
+    pub fn new(open: &str, close: Option<&str>) -> Result<Self, String> {
+        if open.is_empty() {
+            return Err("the opening delimiter is empty".to_owned());
+        }
+        let close = match close {
+            Some(close) if close.is_empty() => {
+                return Err("the closing delimiter is empty".to_owned());
+            }
+            Some(close) => close.to_owned(),
+            None if !is_single_grapheme(open) => {
+                return Err(format!("a closing delimiter is needed for '{}'", open));
+            }
+            None => BRACKETS.iter().find(|&&(o, c)| o == open || c == open)
+                .map(|&(_, c)| c.to_owned())
+                .unwrap_or_else(|| open.to_owned()),
+        };
+        let open = BRACKETS.iter().find(|&&(_, c)| c == open)
+            .map(|&(o, _)| o.to_owned())
+            .unwrap_or_else(|| open.to_owned());
+        Ok(Delimiters { open: open, close: close })
+    }
+
+    fn is_quote(&self) -> bool {
+        self.open == self.close
+    }
+}
+
+/// Returns the innermost pair of delimiters enclosing the region from
+/// `start` to `end`, as the intervals of the opening and closing delimiters.
+///
+/// If `only` is given, only pairs with those delimiters are considered;
+/// otherwise the brackets (except for angle brackets, which are commonly
+/// unpaired in code) and quotes are. A region which begins and ends with
+/// a pair is enclosed by it.
+pub fn find_enclosing(text: &Rope, start: usize, end: usize, only: Option<&Delimiters>)
+                      -> Option<(Interval, Interval)> {
+    let candidates = match only {
+        Some(delims) => vec![delims.clone()],
+        None => BRACKETS.iter().cloned().filter(|&(o, _)| o != "<")
+            .chain(QUOTES.iter().map(|&q| (q, q)))
+            .map(|(o, c)| Delimiters { open: o.to_owned(), close: c.to_owned() })
+            .collect(),
+    };
+
+    let region = text.slice_to_string(start, end);
+    for delims in candidates.iter() {
+        let (open, close) = (delims.open.as_str(), delims.close.as_str());
+        if region.len() >= open.len() + close.len() &&
+            region.starts_with(open) && region.ends_with(close) {
+            return Some((Interval::new_closed_open(start, start + open.len()),
+                         Interval::new_closed_open(end - close.len(), end)));
+        }
+    }
+
+    // the innermost pair has the last opening delimiter
+    candidates.iter()
+        .filter_map(|delims| if delims.is_quote() {
+            find_enclosing_quote(text, start, end, &delims.open)
+        } else {
+            find_enclosing_bracket(text, start, end, delims)
+        })
+        .max_by_key(|&(open, _)| open.start())
+}
+
+fn find_enclosing_bracket(text: &Rope, start: usize, end: usize, delims: &Delimiters)
+                          -> Option<(Interval, Interval)> {
+    let (open, close) = (delims.open.as_str(), delims.close.as_str());
+    let scan_start = prev_boundary(text, start.saturating_sub(SCAN_LIMIT));
+    let before = text.slice_to_string(scan_start, start);
+    let mut depth = 0;
+    let mut open_ix = None;
+    for (ix, _) in before.char_indices().rev() {
+        let rest = &before[ix..];
+        if rest.starts_with(close) {
+            depth += 1;
+        } else if rest.starts_with(open) {
+            if depth == 0 {
+                open_ix = Some(scan_start + ix);
+                break;
+            }
+            depth -= 1;
+        }
+    }
+    let open_ix = open_ix?;
+
+    let scan_end = prev_boundary(text, (end + SCAN_LIMIT).min(text.len()));
+    let after = text.slice_to_string(end, scan_end);
+    let mut depth = 0;
+    for (ix, _) in after.char_indices() {
+        let rest = &after[ix..];
+        if rest.starts_with(open) {
+            depth += 1;
+        } else if rest.starts_with(close) {
+            if depth == 0 {
+                let close_ix = end + ix;
+                return Some((Interval::new_closed_open(open_ix, open_ix + open.len()),
+                             Interval::new_closed_open(close_ix, close_ix + close.len())));
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+/// Quotes are paired within a line: a region is enclosed by a quote if an
+/// odd number of them precede it on its line.
+fn find_enclosing_quote(text: &Rope, start: usize, end: usize, quote: &str)
+                        -> Option<(Interval, Interval)> {
+    let line = text.line_of_offset(start);
+    if text.line_of_offset(end) != line {
+        return None;
+    }
+    let line_start = text.offset_of_line(line);
+    let line_text = text.slice_to_string(line_start, text.offset_of_line(line + 1));
+    let offsets = quote_offsets(&line_text, quote);
+    let n_before = offsets.iter().take_while(|&&ix| line_start + ix < start).count();
+    if n_before % 2 == 0 {
+        return None;
+    }
+    let open_ix = line_start + offsets[n_before - 1];
+    let close_ix = line_start + *offsets.iter().find(|&&ix| line_start + ix >= end)?;
+    Some((Interval::new_closed_open(open_ix, open_ix + quote.len()),
+          Interval::new_closed_open(close_ix, close_ix + quote.len())))
+}
+
+/// Returns the offsets of the occurrences of `quote` in `s` which aren't
+/// escaped by a backslash, or, for single quotes, apostrophes within words.
+fn quote_offsets(s: &str, quote: &str) -> Vec<usize> {
+    s.match_indices(quote)
+        .filter(|&(ix, _)| s[..ix].chars().rev().take_while(|&c| c == '\\').count() % 2 == 0)
+        .filter(|&(ix, _)| {
+            let is_alnum = |c: Option<char>| c.map(char::is_alphanumeric).unwrap_or(false);
+            quote != "'" || !(is_alnum(s[..ix].chars().next_back()) &&
+                              is_alnum(s[ix + quote.len()..].chars().next()))
+        })
+        .map(|(ix, _)| ix)
+        .collect()
+}
+
+fn prev_boundary(text: &Rope, offset: usize) -> usize {
+    if text.is_codepoint_boundary(offset) {
+        offset
+    } else {
+        text.prev_codepoint_offset(offset).unwrap_or(0)
+    }
+}
+
+fn is_single_grapheme(s: &str) -> bool {
+    Rope::from(s).next_grapheme_offset(0) == Some(s.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enclosing(s: &str, start: usize, end: usize, only: Option<&str>)
+                 -> Option<(usize, usize)> {
+        let only = only.map(|o| Delimiters::new(o, None).unwrap());
+        find_enclosing(&Rope::from(s), start, end, only.as_ref())
+            .map(|(open, close)| (open.start(), close.start()))
+    }
+
+    #[test]
+    fn delimiters() {
+        let parens = Delimiters::new("(", None).unwrap();
+        assert_eq!(parens.close, ")");
+        assert_eq!(Delimiters::new(")", None).unwrap(), parens);
+        assert_eq!(Delimiters::new("\"", None).unwrap().close, "\"");
+        assert_eq!(Delimiters::new("\u{300c}", None).unwrap().close, "\u{300d}");
+        assert!(Delimiters::new("<b>", None).is_err());
+        assert_eq!(Delimiters::new("<b>", Some("</b>")).unwrap().close, "</b>");
+        assert!(Delimiters::new("", None).is_err());
+    }
+
+    #[test]
+    fn nested_brackets() {
+        let s = "f(a, [b, (c)], d)";
+        assert_eq!(enclosing(s, 10, 11, None), Some((9, 11)));
+        assert_eq!(enclosing(s, 6, 7, None), Some((5, 12)));
+        assert_eq!(enclosing(s, 15, 15, None), Some((1, 16)));
+        assert_eq!(enclosing(s, 0, 1, None), None);
+        assert_eq!(enclosing(s, 10, 11, Some("[")), Some((5, 12)));
+    }
+
+    #[test]
+    fn nested_quotes() {
+        let s = r#"x = "a 'b' c" + 'd'"#;
+        assert_eq!(enclosing(s, 8, 9, None), Some((7, 9)));
+        assert_eq!(enclosing(s, 6, 6, None), Some((4, 12)));
+        assert_eq!(enclosing(s, 11, 11, None), Some((4, 12)));
+        assert_eq!(enclosing(s, 17, 18, None), Some((16, 18)));
+        assert_eq!(enclosing(s, 14, 14, None), None);
+        // escaped quotes don't count
+        assert_eq!(enclosing(r#""a \" b""#, 6, 6, None), Some((0, 7)));
+        // nor do apostrophes
+        assert_eq!(enclosing("'it's'", 2, 2, None), Some((0, 5)));
+    }
+
+    #[test]
+    fn adjacent_delimiters() {
+        // a region which is exactly the contents, or includes the delimiters
+        assert_eq!(enclosing("(foo)", 1, 4, None), Some((0, 4)));
+        assert_eq!(enclosing("(foo)", 0, 5, None), Some((0, 4)));
+        assert_eq!(enclosing("[(foo)]", 1, 6, None), Some((1, 5)));
+        assert_eq!(enclosing("'foo'", 0, 5, None), Some((0, 4)));
+    }
+}
@@ -14,14 +14,25 @@
 
 //! Very basic syntax detection.
 
+use std::cmp::min;
 use std::fmt;
 
+use xi_rope::rope::{LinesMetric, Rope};
+
+/// The number of lines at the start and at the end of a buffer which are
+/// searched for modelines.
+pub const MODELINE_LINES: usize = 5;
+
+/// Only the start of longer lines is searched, as minified files can have
+/// very long lines.
+const MAX_DETECTION_LINE_LEN: usize = 1024;
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum SyntaxDefinition {
     Plaintext, Markdown, Python, Rust, C, Go, Dart, Swift, Toml,
     Json, Yaml, Cpp, Objc, Shell, Ruby, Javascript, Java, Php,
-    Perl,
+    Perl, Xml,
 }
 
 impl Default for SyntaxDefinition {
@@ -55,6 +66,7 @@ impl SyntaxDefinition {
                 "java" | "jav" => Java,
                 "php" => Php,
                 "pl" => Perl,
+                "xml" => Xml,
                 _ => Plaintext,
             }
         } else {
@@ -88,9 +100,153 @@ impl SyntaxDefinition {
             Java => "java",
             Php => "php",
             Perl => "perl",
+            Xml => "xml",
             Plaintext => "plaintext",
         }
     }
+
+    /// The usual extension of files of this syntax, by which syntect finds
+    /// its grammar, or `None` for plain text.
+    pub fn extension(&self) -> Option<&'static str> {
+        use self::SyntaxDefinition::*;
+        Some(match *self {
+            Rust => "rs",
+            Markdown => "md",
+            Python => "py",
+            C => "c",
+            Go => "go",
+            Dart => "dart",
+            Swift => "swift",
+            Toml => "toml",
+            Json => "json",
+            Yaml => "yaml",
+            Cpp => "cc",
+            Objc => "m",
+            Shell => "sh",
+            Ruby => "rb",
+            Javascript => "js",
+            Java => "java",
+            Php => "php",
+            Perl => "pl",
+            Xml => "xml",
+            Plaintext => return None,
+        })
+    }
+
+    /// Returns the syntax called `name`, which may be its identifier, as in
+    /// `"python3"`, a common name, as in `"python"` or `"bash"`, or one of
+    /// its extensions.
+    pub fn from_name(name: &str) -> Option<Self> {
+        use self::SyntaxDefinition::*;
+        let syntax = match &*name.trim().to_lowercase() {
+            "plaintext" | "text" => return Some(Plaintext),
+            "markdown" => Markdown,
+            "python" | "python2" | "python3" => Python,
+            "rust" => Rust,
+            "golang" => Go,
+            "cpp" | "c++" => Cpp,
+            "objective-c" | "objc" => Objc,
+            "shellscript" | "shell" | "sh" | "bash" | "zsh" | "ksh" => Shell,
+            "ruby" => Ruby,
+            "javascript" | "node" | "nodejs" => Javascript,
+            "perl" => Perl,
+            "php" => Php,
+            other => SyntaxDefinition::new(&*format!(".{}", other)),
+        };
+        if syntax == Plaintext { None } else { Some(syntax) }
+    }
+
+    /// Detects the syntax of `text` from a Vim or Emacs modeline in its
+    /// first or last `MODELINE_LINES` lines, or else from a shebang or an
+    /// XML declaration on its first line.
+    pub fn from_content(text: &Rope) -> Option<Self> {
+        let n_lines = text.measure::<LinesMetric>() + 1;
+        let head = 0..min(n_lines, MODELINE_LINES);
+        let tail = n_lines.saturating_sub(MODELINE_LINES).max(head.end)..n_lines;
+        let lines = head.chain(tail).map(|line| line_text(text, line)).collect::<Vec<_>>();
+        lines.iter()
+            .filter_map(|line| from_emacs_modeline(line).or_else(|| from_vim_modeline(line)))
+            .next()
+            .or_else(|| from_first_line(&lines[0]))
+    }
+}
+
+/// Returns the start of the text of `line`, without its line ending.
+fn line_text(text: &Rope, line: usize) -> String {
+    let start = text.offset_of_line(line);
+    let mut end = min(text.len(), start + MAX_DETECTION_LINE_LEN);
+    while !text.is_codepoint_boundary(end) {
+        end -= 1;
+    }
+    text.lines(start, end).next().map(|line| line.into_owned()).unwrap_or_default()
+}
+
+/// Detects the syntax from a shebang, as in `#!/usr/bin/env python3`, or
+/// an XML declaration.
+fn from_first_line(line: &str) -> Option<SyntaxDefinition> {
+    let line = line.trim_start_matches('\u{feff}');
+    if line.starts_with("<?xml") {
+        return Some(SyntaxDefinition::Xml);
+    }
+    // Rust's inner attributes start with "#!["
+    if !line.starts_with("#!") || line.starts_with("#![") {
+        return None;
+    }
+    let mut words = line[2..].split_whitespace();
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        interpreter = words.find(|word| !word.starts_with('-') && !word.contains('='))?;
+    }
+    // without a version, as in "python3.6"
+    SyntaxDefinition::from_name(interpreter.trim_end_matches(|c: char| {
+        c.is_digit(10) || c == '.'
+    }))
+}
+
+/// Detects the syntax from an Emacs modeline, as in `-*- mode: python -*-`
+/// or `-*- python -*-`.
+fn from_emacs_modeline(line: &str) -> Option<SyntaxDefinition> {
+    let start = line.find("-*-")? + 3;
+    let end = start + line[start..].find("-*-")?;
+    let vars = &line[start..end];
+    if !vars.contains(':') {
+        return SyntaxDefinition::from_name(vars);
+    }
+    vars.split(';')
+        .filter_map(|var| {
+            let mut parts = var.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("mode") => {
+                    Some(value)
+                }
+                _ => None,
+            }
+        })
+        .filter_map(SyntaxDefinition::from_name)
+        .next()
+}
+
+/// Detects the syntax from a Vim modeline, as in `vim: set ft=python:` or
+/// `vi: syntax=sh`.
+fn from_vim_modeline(line: &str) -> Option<SyntaxDefinition> {
+    let start = ["vim:", "vi:", "ex:"].iter()
+        .filter_map(|marker| {
+            line.find(marker)
+                .filter(|&ix| ix == 0 || line[..ix].ends_with(char::is_whitespace))
+                .map(|ix| ix + marker.len())
+        })
+        .min()?;
+    line[start..].split(|c: char| c == ':' || c.is_whitespace())
+        .filter_map(|option| {
+            let mut parts = option.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("ft"), Some(value)) | (Some("filetype"), Some(value)) |
+                (Some("syntax"), Some(value)) | (Some("syn"), Some(value)) => Some(value),
+                _ => None,
+            }
+        })
+        .filter_map(SyntaxDefinition::from_name)
+        .next()
 }
 
 impl<S: AsRef<str>> From<S> for SyntaxDefinition {
@@ -119,4 +275,32 @@ mod tests {
         assert_eq!(SyntaxDefinition::from("build"), SyntaxDefinition::Plaintext);
         assert_eq!(SyntaxDefinition::from("build.test.sh"), SyntaxDefinition::Shell);
     }
+
+    fn detect(text: &str) -> Option<SyntaxDefinition> {
+        SyntaxDefinition::from_content(&Rope::from(text))
+    }
+
+    #[test]
+    fn test_detection() {
+        use super::SyntaxDefinition::*;
+        assert_eq!(detect("#!/usr/bin/env python\nprint('hi')\n"), Some(Python));
+        assert_eq!(detect("#!/usr/bin/env -S python3.6 -u\n"), Some(Python));
+        assert_eq!(detect("#!/bin/bash\n"), Some(Shell));
+        assert_eq!(detect("<?xml version=\"1.0\"?>\n<a/>"), Some(Xml));
+        assert_eq!(detect("#![allow(dead_code)]\n"), None);
+        assert_eq!(detect("#!/usr/bin/unknown\n"), None);
+        assert_eq!(detect("# -*- mode: ruby; coding: utf-8 -*-\n"), Some(Ruby));
+        assert_eq!(detect("/* -*- c++ -*- */\n"), Some(Cpp));
+        // modelines win over the first line, and are found near the end
+        let text = format!("#!/bin/sh\n{}# vim: set ft=perl ts=4:\n\n", "x\n".repeat(20));
+        assert_eq!(detect(&text), Some(Perl));
+        let text = format!("# vim: set ft=perl:\n{}", "x\n".repeat(20));
+        assert_eq!(detect(&text), Some(Perl));
+        let text = format!("x\n# vim: set ft=perl:\n{}", "x\n".repeat(20));
+        assert_eq!(detect(&text), Some(Perl));
+        let text = format!("{}# vim: set ft=perl:\n{}", "x\n".repeat(10), "x\n".repeat(10));
+        assert_eq!(detect(&text), None);
+        assert_eq!(detect("nevim: ft=perl\n"), None);
+        assert_eq!(detect(""), None);
+    }
 }
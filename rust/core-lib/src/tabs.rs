@@ -20,25 +20,53 @@ use std::io::{self, Read};
 use std::path::{PathBuf, Path};
 use std::fs::File;
 use std::sync::{Arc, Mutex, MutexGuard, Weak, mpsc};
+use std::time::{Duration, Instant};
 
-use serde_json::value::Value;
+use serde_json::{self, Value};
 
 use xi_rope::rope::Rope;
 use xi_rpc::{RpcCtx, RemoteError};
+use xi_rpc::compression;
+use syntect::highlighting::Theme;
 
 use editor::Editor;
 
 use rpc;
 use styles::{Style, ThemeStyleMap};
+use config::{Config, UserConfig};
+use auto_save::AutoSave;
 use MainPeer;
 
-use syntax::SyntaxDefinition;
-use plugins::{self, PluginManagerRef, Command};
+use buffer_info::BufferInfo;
+use mapped_file::MappedFile;
+use mapped_view::MappedView;
+use selection::Affinity;
+use scratch::Scratch;
+use view::ScrollPosition;
+use suggest::suggest_paths;
+use messages::{self, ids, Message};
+use incidents::{Incident, IncidentLog};
+use plugins::{self, PluginManagerRef, PluginMode, Command};
 use plugins::rpc_types::{PluginUpdate, ClientPluginInfo};
 
 #[cfg(target_os = "fuchsia")]
 use apps_ledger_services_public::{Ledger_Proxy};
 
+/// Idle token for running the closures in `Documents::idle_queue`.
+const IDLE_QUEUE_TOKEN: usize = 0;
+/// Idle token for doing a step of editors' background work.
+const BACKGROUND_WORK_TOKEN: usize = 1;
+
+/// Token for the timer which saves buffers automatically.
+const AUTO_SAVE_TOKEN: usize = 2;
+const SYNTAX_DETECTION_TOKEN: usize = 3;
+/// Token for the timer which sends plugins the edits of hidden buffers.
+const DEFERRED_UPDATE_TOKEN: usize = 4;
+
+/// Loading a view-only file larger than this into an editable buffer
+/// warns that it may use a lot of memory.
+const LARGE_FILE: usize = 256 << 20;
+
 /// ViewIdentifiers are the primary means of routing messages between xi-core and a client view.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct ViewIdentifier(String);
@@ -176,7 +204,15 @@ impl BufferContainerRef {
 
     /// Returns `true` if `file_path` is already open, else `false`.
     pub fn has_open_file<P: AsRef<Path>>(&self, file_path: P) -> bool {
-        self.lock().open_files.contains_key(file_path.as_ref())
+        self.buffer_for_file(file_path).is_some()
+    }
+
+    /// Returns the buffer which `file_path` is open in, if any. Paths are
+    /// compared once canonicalized, so that different paths to the same
+    /// file share a buffer.
+    pub fn buffer_for_file<P: AsRef<Path>>(&self, file_path: P) -> Option<BufferIdentifier> {
+        let file_path = canonical_path(file_path.as_ref());
+        self.lock().open_files.get(&file_path).map(|id| id.to_owned())
     }
 
     /// Returns a copy of the BufferIdentifier associated with a given view.
@@ -199,14 +235,8 @@ impl BufferContainerRef {
         let file_path = file_path.as_ref();
         let mut inner = self.lock();
         let buffer_id = inner.views.get(view_id).unwrap().to_owned();
-        let prev_path = inner.editor_for_view(view_id).unwrap()
-            .get_path().map(Path::to_owned);
-        if let Some(prev_path) = prev_path {
-            if prev_path != file_path {
-                inner.open_files.remove(&prev_path);
-            }
-        }
-        inner.open_files.insert(file_path.to_owned(), buffer_id);
+        inner.open_files.retain(|_, id| *id != buffer_id);
+        inner.open_files.insert(canonical_path(file_path), buffer_id);
         inner.editor_for_view_mut(view_id).unwrap()._set_path(file_path);
     }
 
@@ -222,28 +252,32 @@ impl BufferContainerRef {
     /// If this is the last view open onto the underlying buffer, also cleans up
     /// the `Editor` instance.
     pub fn close_view(&self, view_id: &ViewIdentifier) {
-        let (remove, path) = {
-            let mut inner = self.lock();
+        let mut inner = self.lock();
+        let remove = {
             let editor = inner.editor_for_view_mut(view_id).unwrap();
+            let was_last = !editor.has_views();
             editor.remove_view(view_id);
-            if !editor.has_views() {
-                (true, editor.get_path().map(PathBuf::from))
-            } else {
-                (false, None)
-            }
+            was_last
         };
 
+        let buffer_id = inner.views.remove(view_id).unwrap();
         if remove {
-            let mut inner = self.lock();
-            let buffer_id = inner.views.remove(view_id).unwrap();
-            if let Some(path) = path {
-                inner.open_files.remove(&path);
-            }
+            inner.open_files.retain(|_, id| *id != buffer_id);
             inner.editors.remove(&buffer_id);
         }
     }
 }
 
+/// Returns the canonical form of `path`, or `path` itself if it can't be
+/// canonicalized, as when the file doesn't exist yet.
+fn canonical_path(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
+
+fn display_paths(paths: &[PathBuf]) -> Vec<String> {
+    paths.iter().map(|path| path.display().to_string()).collect()
+}
+
 impl WeakBufferContainerRef {
     /// Upgrades the weak reference to an Arc, if possible.
     ///
@@ -285,11 +319,27 @@ pub struct Documents {
     id_counter: usize,
     kill_ring: Arc<Mutex<Rope>>,
     style_map: Arc<Mutex<ThemeStyleMap>>,
+    config: Arc<Mutex<Config>>,
+    /// The user's changes to `config`.
+    user_config: UserConfig,
+    /// If true, core was started without the user's config and plugins,
+    /// for troubleshooting.
+    safe_mode: bool,
+    capabilities: Arc<Mutex<ClientCapabilities>>,
+    /// The incidents recorded by all buffers.
+    incidents: Arc<Mutex<IncidentLog>>,
+    /// The sequence number of the last notification sent about each view.
+    view_seqs: Arc<Mutex<BTreeMap<ViewIdentifier, u64>>>,
     plugins: PluginManagerRef,
     /// A tx channel used to propagate plugin updates from all `Editor`s.
     update_channel: mpsc::Sender<(ViewIdentifier, PluginUpdate, usize)>,
     /// A queue of closures to be executed on the next idle runloop pass.
     idle_queue: Vec<Box<IdleProc>>,
+    /// The views of files opened in `view_only_mmap` mode, which have no
+    /// `Editor`.
+    mapped_views: BTreeMap<ViewIdentifier, MappedView>,
+    /// The committed theme, while another is being previewed.
+    theme_preview: Option<String>,
     #[allow(dead_code)]
     sync_repo: Option<SyncRepo>,
 }
@@ -300,14 +350,72 @@ pub struct DocumentCtx {
     kill_ring: Arc<Mutex<Rope>>,
     rpc_peer: MainPeer,
     style_map: Arc<Mutex<ThemeStyleMap>>,
+    config: Arc<Mutex<Config>>,
+    capabilities: Arc<Mutex<ClientCapabilities>>,
+    incidents: Arc<Mutex<IncidentLog>>,
+    view_seqs: Arc<Mutex<BTreeMap<ViewIdentifier, u64>>>,
     update_channel: mpsc::Sender<(ViewIdentifier, PluginUpdate, usize)>
 }
 
+/// Optional protocol features requested by the client in `client_started`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ClientCapabilities {
+    /// If true, each rendered line includes the stable id of its logical line.
+    pub line_ids: bool,
+    /// If true, `scroll_to` also gives the visual line and column of the
+    /// caret, and its affinity.
+    pub visual_scroll_to: bool,
+    /// If true, copies of rectangular selections are returned with their
+    /// segments, for pasting as rectangles.
+    pub block_clipboard: bool,
+    /// If true, rendered lines with right-to-left text include their bidi
+    /// levels, and the visual ranges of their selections.
+    pub bidi: bool,
+    /// If true, the styles defined together, such as after a theme change,
+    /// are sent in one `def_styles` notification, rather than a `def_style`
+    /// each.
+    pub def_styles: bool,
+    /// If true, a line whose styles have changed, but whose text hasn't, is
+    /// updated with an `update_styles` op, which gives only the spans which
+    /// differ.
+    pub style_diffs: bool,
+}
+
+impl ClientCapabilities {
+    pub fn from_names(names: &[String]) -> Self {
+        let mut capabilities = ClientCapabilities::default();
+        for name in names {
+            match name.as_str() {
+                "line_ids" => capabilities.line_ids = true,
+                "visual_scroll_to" => capabilities.visual_scroll_to = true,
+                "block_clipboard" => capabilities.block_clipboard = true,
+                "bidi" => capabilities.bidi = true,
+                "def_styles" => capabilities.def_styles = true,
+                "style_diffs" => capabilities.style_diffs = true,
+                _ => print_err!("unknown client capability '{}'", name),
+            }
+        }
+        capabilities
+    }
+}
+
 
 impl Documents {
     pub fn new() -> Documents {
+        Documents::with_safe_mode(false)
+    }
+
+    /// Creates the documents, without the user's config or any plugins
+    /// if `safe_mode` is true.
+    pub fn with_safe_mode(safe_mode: bool) -> Documents {
         let buffers = BufferContainerRef::new();
-        let plugin_manager = PluginManagerRef::new(buffers.clone());
+        let config = Arc::new(Mutex::new(Config::default()));
+        let (user_config, plugin_mode) = if safe_mode {
+            (UserConfig::disabled(), PluginMode::Disabled)
+        } else {
+            (UserConfig::default(), PluginMode::Enabled)
+        };
+        let plugin_manager = PluginManagerRef::new(buffers.clone(), config.clone(), plugin_mode);
         let (update_tx, update_rx) = mpsc::channel();
 
         plugins::start_update_thread(update_rx, &plugin_manager);
@@ -317,9 +425,17 @@ impl Documents {
             id_counter: 0,
             kill_ring: Arc::new(Mutex::new(Rope::from(""))),
             style_map: Arc::new(Mutex::new(ThemeStyleMap::new())),
+            config: config,
+            user_config: user_config,
+            safe_mode: safe_mode,
+            capabilities: Arc::new(Mutex::new(ClientCapabilities::default())),
+            incidents: Arc::new(Mutex::new(IncidentLog::default())),
+            view_seqs: Arc::new(Mutex::new(BTreeMap::new())),
             plugins: plugin_manager,
             update_channel: update_tx,
             idle_queue: Vec::new(),
+            mapped_views: BTreeMap::new(),
+            theme_preview: None,
             sync_repo: None,
         }
     }
@@ -334,6 +450,10 @@ impl Documents {
             kill_ring: self.kill_ring.clone(),
             rpc_peer: peer.clone(),
             style_map: self.style_map.clone(),
+            config: self.config.clone(),
+            capabilities: self.capabilities.clone(),
+            incidents: self.incidents.clone(),
+            view_seqs: self.view_seqs.clone(),
             update_channel: self.update_channel.clone(),
         }
     }
@@ -352,16 +472,76 @@ impl Documents {
                                rpc_ctx: &mut RpcCtx<'a>) {
         use rpc::CoreNotification::*;
         match cmd {
-            ClientStarted(..) => self.do_client_init(rpc_ctx.get_peer()),
-            SetTheme { theme_name } => self.do_set_theme(rpc_ctx.get_peer(),
-                                                         &theme_name),
+            ClientStarted { capabilities, safe_mode } => {
+                if safe_mode == Some(true) && !self.safe_mode {
+                    self.enter_safe_mode();
+                }
+                self.do_client_init(rpc_ctx.get_peer(), capabilities.unwrap_or_default())
+            }
+            SetTheme { theme_name } => {
+                self.theme_preview = None;
+                self.do_set_theme(rpc_ctx, &theme_name);
+            }
+            PreviewTheme { theme_name } => self.do_preview_theme(rpc_ctx, &theme_name),
+            CommitTheme => self.theme_preview = None,
+            CancelThemePreview => {
+                if let Some(theme_name) = self.theme_preview.take() {
+                    self.do_set_theme(rpc_ctx, &theme_name);
+                }
+            }
+            ModifyUserConfig { changes } => self.do_modify_user_config(&changes),
+            ReloadUserConfig => self.do_reload_user_config(rpc_ctx.get_peer()),
+            RestartPlugins { plugins } => {
+                let mode = match plugins {
+                    Some(names) => PluginMode::Only(names.into_iter().collect()),
+                    None => PluginMode::Enabled,
+                };
+                self.plugins.restart_plugins(mode);
+            }
             Save { view_id, file_path } => self.do_save(&view_id, file_path),
             CloseView { view_id } => self.do_close_view(&view_id),
             Edit(rpc::EditCommand { view_id, cmd }) => {
-                self.buffers.lock().editor_for_view_mut(&view_id)
-                    .map(|ed| ed.handle_notification(&view_id, cmd));
+                if let Some(view) = self.mapped_views.get_mut(&view_id) {
+                    return view.handle_notification(cmd);
+                }
+                let result = self.buffers.lock().editor_for_view_mut(&view_id)
+                    .map(|ed| {
+                        let syntax = ed.get_syntax().to_owned();
+                        ed.handle_notification(&view_id, cmd);
+                        (ed.has_background_work(), *ed.get_syntax() != syntax,
+                         ed.take_span_requests())
+                    });
+                if let Some((has_work, language_changed, span_requests)) = result {
+                    if has_work {
+                        rpc_ctx.schedule_idle(BACKGROUND_WORK_TOKEN);
+                    }
+                    if language_changed {
+                        self.language_changed(&view_id);
+                    }
+                    for (plugin, iv, rev) in span_requests {
+                        self.plugins.request_spans(&view_id, plugin, iv, rev);
+                    }
+                }
+                self.schedule_auto_save(&view_id, rpc_ctx);
+                self.schedule_syntax_detection(&view_id, rpc_ctx);
+                self.schedule_deferred_update(&view_id, rpc_ctx);
+            }
+            FocusChanged { view_id, focused } => self.do_focus_changed(&view_id, focused),
+            ViewVisibility { view_id, visible } => {
+                let has_work = self.buffers.lock().editor_for_view_mut(&view_id)
+                    .map(|ed| {
+                        ed.set_view_visible(&view_id, visible);
+                        ed.has_background_work()
+                    });
+                if has_work == Some(true) {
+                    rpc_ctx.schedule_idle(BACKGROUND_WORK_TOKEN);
                 }
+            }
             Plugin(cmd) => self.do_plugin_cmd(cmd),
+            ResumePlugin { view_id, plugin_name } => self.do_resume_plugin(&view_id, &plugin_name),
+            SetLayerEnabled { view_id, plugin_name, enabled } => {
+                self.do_set_layer_enabled(&view_id, &plugin_name, enabled)
+            }
         }
     }
 
@@ -369,16 +549,47 @@ impl Documents {
                               rpc_ctx: &mut RpcCtx<'a>) -> Result<Value, RemoteError> {
         use rpc::CoreRequest::*;
         match cmd {
-            NewView { file_path } => {
+            NewView { file_path: Some(file_path), mode: Some(rpc::OpenMode::ViewOnlyMmap) } => {
+                let result = self.do_new_mapped_view(rpc_ctx.get_peer(), &file_path)?;
+                rpc_ctx.schedule_idle(IDLE_QUEUE_TOKEN);
+                rpc_ctx.schedule_idle(BACKGROUND_WORK_TOKEN);
+                Ok(result)
+            }
+            NewView { file_path, .. } => {
                 let result = self.do_new_view(rpc_ctx.get_peer(), file_path);
                 // schedule idle handler after creating views; this is used to
                 // send cursors for empty views, and to initialize plugins.
-                rpc_ctx.schedule_idle(0);
+                rpc_ctx.schedule_idle(IDLE_QUEUE_TOKEN);
+                // and to highlight the syntax, if no plugin does
+                rpc_ctx.schedule_idle(BACKGROUND_WORK_TOKEN);
                 Ok(result)
             }
             Edit(rpc::EditCommand { view_id, cmd }) => {
-                let result = self.buffers.lock().editor_for_view_mut(&view_id)
+                if self.mapped_views.contains_key(&view_id) {
+                    return match cmd {
+                        rpc::EditRequest::PromoteToEditable => {
+                            self.do_promote_to_editable(rpc_ctx.get_peer(), &view_id)
+                        }
+                        cmd => self.mapped_views.get_mut(&view_id).unwrap().handle_request(cmd),
+                    };
+                }
+                let added_word = match cmd {
+                    rpc::EditRequest::AddWordToDictionary { ref word, scope } => {
+                        Some((word.to_owned(), scope))
+                    }
+                    _ => None,
+                };
+                let is_stats = cmd == rpc::EditRequest::DebugGetStats;
+                let mut result = self.buffers.lock().editor_for_view_mut(&view_id)
                     .map(|ed| ed.handle_request(&view_id, cmd));
+                if let (true, Some(&mut Ok(ref mut stats))) = (is_stats, result.as_mut()) {
+                    stats["plugins"] = self.plugins.debug_stats(&view_id);
+                }
+                if let (Some(&Ok(_)), Some((word, scope))) = (result.as_ref(), added_word) {
+                    self.plugins.dictionary_changed(&view_id, &word, scope);
+                }
+                self.schedule_auto_save(&view_id, rpc_ctx);
+                self.schedule_deferred_update(&view_id, rpc_ctx);
                 match result {
                     None => {
                         let msg = format!("No editor for view_id: {}", view_id);
@@ -387,6 +598,35 @@ impl Documents {
                     Some(result) => result,
                 }
             }
+            NegotiateCompression { algorithms, threshold } => {
+                let compression = compression::negotiate(&algorithms, threshold);
+                // the response is shorter than any threshold, and is
+                // never itself compressed.
+                rpc_ctx.get_peer().set_compression(compression);
+                Ok(compression.map(|c| json!({
+                    "algorithm": c.algorithm.name(),
+                    "threshold": c.threshold,
+                })).unwrap_or(Value::Null))
+            }
+            GetConfig => Ok(self.do_get_config()),
+            ListMessageIds => Ok(messages::list_message_ids()),
+            GetIncidents => Ok(self.incidents.lock().unwrap().to_json()),
+            NewScratchView { title, read_only } => {
+                let result = self.do_new_scratch_view(rpc_ctx.get_peer(), title,
+                                                      read_only.unwrap_or(true));
+                rpc_ctx.schedule_idle(IDLE_QUEUE_TOKEN);
+                Ok(result)
+            }
+            ScratchAppend { view_id, text, styles } => {
+                self.do_scratch_edit(&view_id, |ed| {
+                    ed.scratch_append(&text, &styles.unwrap_or_default())
+                })
+            }
+            ScratchSetContent { view_id, text, styles } => {
+                self.do_scratch_edit(&view_id, |ed| {
+                    ed.scratch_set_content(&text, &styles.unwrap_or_default())
+                })
+            }
         }
     }
 
@@ -406,13 +646,9 @@ impl Documents {
         // three code paths: new buffer, open file, and new view into existing buffer
         let view_id = self.next_view_id();
         if let Some(file_path) = file_path.map(PathBuf::from) {
-            // TODO: here, we should eventually be adding views to the existing editor.
-            // for the time being, we just create a new empty view.
-            if self.buffers.has_open_file(&file_path) {
-                let buffer_id = self.next_buffer_id();
-                self.new_empty_view(rpc_peer, &view_id, buffer_id);
-                // let buffer_id = self.open_files.get(&file_path).unwrap().to_owned();
-                //self.add_view(&view_id, buffer_id);
+            if let Some(buffer_id) = self.buffers.buffer_for_file(&file_path) {
+                // already open: add a view onto the existing buffer
+                self.add_view(&view_id, buffer_id);
             } else {
                 // not open: create new buffer_id and open file
                 let buffer_id = self.next_buffer_id();
@@ -442,9 +678,110 @@ impl Documents {
         json!(view_id)
     }
 
+    /// Creates a view of a new scratch buffer. Plugins aren't started for
+    /// it, as only its creator sets its text and styles.
+    fn do_new_scratch_view(&mut self, rpc_peer: &MainPeer, title: String,
+                           read_only: bool) -> Value {
+        let view_id = self.next_view_id();
+        let buffer_id = self.next_buffer_id();
+        let mut editor = Editor::new(self.new_tab_ctx(rpc_peer), buffer_id, &view_id);
+        editor.make_scratch(Scratch { title: title, read_only: read_only });
+        self.add_editor(&view_id, &buffer_id, editor, None);
+        let view_id2 = view_id.clone();
+        self.idle_queue.push(Box::new(move |self_ref: &mut Documents| {
+            if let Some(editor) = self_ref.buffers.lock().editor_for_view_mut(&view_id2) {
+                editor.render();
+            }
+        }));
+        json!(view_id)
+    }
+
+    /// Changes the text of the view's scratch buffer with `edit`.
+    fn do_scratch_edit<F>(&mut self, view_id: &ViewIdentifier, edit: F)
+                          -> Result<Value, RemoteError>
+        where F: FnOnce(&mut Editor) -> Result<(), String>
+    {
+        match self.buffers.lock().editor_for_view_mut(view_id) {
+            Some(ed) => edit(ed).map(|_| Value::Null)
+                .map_err(|msg| RemoteError::custom(3, msg, None)),
+            None => {
+                let msg = format!("No editor for view_id: {}", view_id);
+                Err(RemoteError::custom(2, msg, None))
+            }
+        }
+    }
+
+    /// Creates a view of a file which is mapped into memory rather than
+    /// loaded, and indexed in the background. It can't be edited.
+    fn do_new_mapped_view(&mut self, rpc_peer: &MainPeer, file_path: &str)
+                          -> Result<Value, RemoteError> {
+        let file = MappedFile::open(file_path).map_err(|err| {
+            let suggestions = suggest_paths(Path::new(file_path));
+            let msg = Message::new(ids::file_open_failed)
+                .param("path", file_path)
+                .param("error", err.to_string())
+                .suggestions(&display_paths(&suggestions));
+            let data = if suggestions.is_empty() { None } else {
+                Some(json!({"suggestions": suggestions}))
+            };
+            msg.into_error(6, data)
+        })?;
+        let view_id = self.next_view_id();
+        let view = MappedView::new(&view_id, file, self.new_tab_ctx(rpc_peer));
+        self.mapped_views.insert(view_id.clone(), view);
+
+        let view_id2 = view_id.clone();
+        self.idle_queue.push(Box::new(move |self_ref: &mut Documents| {
+            if let Some(view) = self_ref.mapped_views.get_mut(&view_id2) {
+                view.render();
+            }
+        }));
+        Ok(json!(view_id))
+    }
+
+    /// Loads the file of a view-only view into an editable buffer, which
+    /// replaces it. Returns the size of the file.
+    fn do_promote_to_editable(&mut self, rpc_peer: &MainPeer, view_id: &ViewIdentifier)
+                              -> Result<Value, RemoteError> {
+        let view = self.mapped_views.remove(view_id).unwrap();
+        let len = view.len();
+        let (path, text) = match view.into_text() {
+            Some(contents) => contents,
+            None => return Err(Message::new(ids::file_unreadable).into_error(6, None)),
+        };
+        let tab_ctx = self.new_tab_ctx(rpc_peer);
+        if len > LARGE_FILE {
+            tab_ctx.alert(&Message::new(ids::file_loaded_into_memory)
+                          .param("path", path.display().to_string())
+                          .param("megabytes", len >> 20));
+        }
+        let buffer_id = self.next_buffer_id();
+        let editor = Editor::with_text(tab_ctx, buffer_id, view_id, text);
+        self.add_editor(view_id, &buffer_id, editor, Some(&path));
+        let init_info = self.buffers.lock().editor_for_view(view_id)
+            .unwrap().plugin_init_info();
+        self.plugins.document_new(view_id, &init_info);
+        self.buffers.lock().editor_for_view_mut(view_id).unwrap().render();
+        Ok(json!({"size": len}))
+    }
+
     fn do_close_view(&mut self, view_id: &ViewIdentifier) {
-        self.plugins.document_close(view_id);
+        if self.mapped_views.remove(view_id).is_some() {
+            self.view_seqs.lock().unwrap().remove(view_id);
+            return;
+        }
+        if self.config.lock().unwrap().auto_save != AutoSave::Off {
+            self.do_auto_save(view_id);
+        }
+        // the buffer's plugins keep running while other views are open onto it
+        let is_last_view = self.buffers.lock().editor_for_view(view_id)
+            .map(|ed| !ed.has_views())
+            .unwrap_or(true);
+        if is_last_view {
+            self.plugins.document_close(view_id);
+        }
         self.buffers.close_view(view_id);
+        self.view_seqs.lock().unwrap().remove(view_id);
     }
 
     fn new_empty_view(&mut self, rpc_peer: &MainPeer, view_id: &ViewIdentifier,
@@ -470,6 +807,12 @@ impl Documents {
                     self.add_editor(view_id, &buffer_id, ed, None);
                 } else {
                     // if a path that doesn't exist, create a new empty buffer + set path
+                    let suggestions = suggest_paths(path);
+                    if !suggestions.is_empty() {
+                        ed.plugin_alert(&Message::new(ids::file_will_be_created)
+                                        .param("path", path.display().to_string())
+                                        .suggestions(&display_paths(&suggestions)));
+                    }
                     self.add_editor(view_id, &buffer_id, ed, Some(path));
                 }
             }
@@ -494,9 +837,7 @@ impl Documents {
     }
 
     /// Adds a new view to an existing editor instance.
-    #[allow(unreachable_code, unused_variables, dead_code)]
     fn add_view(&mut self, view_id: &ViewIdentifier, buffer_id: BufferIdentifier) {
-        panic!("add_view should not currently be accessible");
         self.buffers.add_view(view_id, &buffer_id);
     }
 
@@ -511,18 +852,27 @@ impl Documents {
                                file_path: P) {
         //TODO: handle & report errors
         let file_path = file_path.as_ref();
+        if let Some(view) = self.mapped_views.get(view_id) {
+            return view.alert(&Message::new(ids::view_cant_be_saved));
+        }
+        if let Some(ed) = self.buffers.lock().editor_for_view(view_id) {
+            if ed.is_scratch() {
+                return ed.plugin_alert(&Message::new(ids::view_cant_be_saved));
+            }
+        }
         let prev_syntax = self.buffers.lock().editor_for_view(view_id)
             .unwrap().get_syntax().to_owned();
-        // notify of syntax change before notify of file_save
-        //FIXME: this doesn't tell us if the syntax _will_ change, for instance
-        //if syntax was a user selection. (we don't handle this case right now)
 
         self.buffers.lock().editor_for_view_mut(view_id)
             .unwrap().do_save(file_path);
         self.buffers.set_path(file_path, view_id);
-        let init_info = self.buffers.lock().editor_for_view(view_id)
-            .unwrap().plugin_init_info();
-        if prev_syntax != SyntaxDefinition::new(file_path.to_str()) {
+        let (syntax, init_info) = {
+            let buffers = self.buffers.lock();
+            let ed = buffers.editor_for_view(view_id).unwrap();
+            (ed.get_syntax().to_owned(), ed.plugin_init_info())
+        };
+        // notify of syntax change before notify of file_save
+        if prev_syntax != syntax {
             self.plugins.document_syntax_changed(view_id, init_info);
         }
         self.plugins.document_did_save(&view_id, file_path);
@@ -556,7 +906,43 @@ impl Documents {
         }
     }
 
-    fn do_client_init(&self, rpc_peer: &MainPeer) {
+    /// Handles a client resume plugin RPC.
+    fn do_resume_plugin(&self, view_id: &ViewIdentifier, plugin_name: &str) {
+        let info = self.buffers.lock().editor_for_view(view_id)
+            .map(|ed| ed.plugin_init_info());
+        if let Some(info) = info {
+            if let Err(err) = self.plugins.resume_plugin(view_id, &info, plugin_name) {
+                print_err!("unable to resume plugin {}: {:?}", plugin_name, err);
+            }
+        }
+    }
+
+    fn do_set_layer_enabled(&self, view_id: &ViewIdentifier, plugin_name: &str,
+                            enabled: bool) {
+        match self.plugins.plugin_pid(view_id, plugin_name) {
+            Some(pid) => {
+                if let Some(ed) = self.buffers.lock().editor_for_view_mut(view_id) {
+                    ed.set_layer_enabled(pid, enabled);
+                }
+            }
+            None => print_err!("no plugin {} is running for {}", plugin_name, view_id),
+        }
+    }
+
+    /// Disables the user's config and stops all plugins, as when core was
+    /// started in safe mode.
+    fn enter_safe_mode(&mut self) {
+        self.safe_mode = true;
+        self.user_config.disable(&mut self.config.lock().unwrap());
+        self.plugins.restart_plugins(PluginMode::Disabled);
+    }
+
+    fn do_client_init(&self, rpc_peer: &MainPeer, capabilities: Vec<String>) {
+        *self.capabilities.lock().unwrap() = ClientCapabilities::from_names(&capabilities);
+        if self.safe_mode {
+            rpc_peer.send_rpc_notification("alert",
+                                           &alert_params(&Message::new(ids::safe_mode)));
+        }
         let params = {
             let style_map = self.style_map.lock().unwrap();
             json!({
@@ -567,8 +953,9 @@ impl Documents {
         rpc_peer.send_rpc_notification("available_themes", &params);
     }
 
-    /// Handle a client set theme RPC
-    fn do_set_theme(&self, rpc_peer: &MainPeer, theme_name: &str) {
+    /// Handle a client set theme RPC. Returns `false` if there is no
+    /// such theme.
+    fn do_set_theme<'a>(&self, rpc_ctx: &mut RpcCtx<'a>, theme_name: &str) -> bool {
         let success = self.style_map.lock().unwrap()
             .set_theme(&theme_name).is_ok();
         if success {
@@ -579,20 +966,211 @@ impl Documents {
                     "theme": style_map.get_theme_settings(),
                 })
             };
-            rpc_peer.send_rpc_notification("theme_changed", &params);
+            rpc_ctx.get_peer().send_rpc_notification("theme_changed", &params);
 
             let mut buffers = self.buffers.lock();
             for ed in buffers.iter_editors_mut() {
                 ed.theme_changed();
             }
+            // the styles are recomputed when idle, so that if another theme
+            // is previewed first, they are only recomputed for that one
+            rpc_ctx.schedule_idle(BACKGROUND_WORK_TOKEN);
         } else {
             print_err!("no theme named {}", theme_name);
         }
+        success
+    }
+
+    /// Sets the theme, keeping the committed one to restore if the preview
+    /// is cancelled.
+    fn do_preview_theme<'a>(&mut self, rpc_ctx: &mut RpcCtx<'a>, theme_name: &str) {
+        let committed = self.style_map.lock().unwrap().get_theme_name().to_owned();
+        if self.do_set_theme(rpc_ctx, theme_name) && self.theme_preview.is_none() {
+            self.theme_preview = Some(committed);
+        }
+    }
+
+    fn do_get_config(&self) -> Value {
+        let mut config = serde_json::to_value(&*self.config.lock().unwrap()).unwrap();
+        let current = self.style_map.lock().unwrap().get_theme_name().to_owned();
+        let (theme, previewed) = match self.theme_preview {
+            Some(ref committed) => (committed.to_owned(), Some(current)),
+            None => (current, None),
+        };
+        config["theme"] = json!(theme);
+        config["previewed_theme"] = json!(previewed);
+        config["provenance"] = json!({
+            "safe_mode": self.safe_mode,
+            "user_config": if self.user_config.is_disabled() { "disabled" } else { "enabled" },
+            "plugins": self.plugins.mode(),
+        });
+        config
+    }
+
+    /// Handle a client modify user config RPC
+    fn do_modify_user_config(&mut self, changes: &Value) {
+        let result = self.user_config.modify(&mut self.config.lock().unwrap(), changes);
+        if let Err(err) = result {
+            print_err!("invalid config changes {}: {}", changes, err);
+        }
+        self.config_changed();
     }
 
-    pub fn handle_idle(&mut self) {
-        while let Some(f) = self.idle_queue.pop() {
-            f.call(self);
+    /// Applies the user's config again, as after safe mode, alerting the
+    /// user to the changes which were invalid.
+    fn do_reload_user_config(&mut self, rpc_peer: &MainPeer) {
+        let errors = self.user_config.reload(&mut self.config.lock().unwrap());
+        for msg in errors {
+            print_err!("{}", msg.fallback_text());
+            rpc_peer.send_rpc_notification("alert", &alert_params(&msg));
+        }
+        self.config_changed();
+    }
+
+    /// Lets each editor update the state it keeps from the config.
+    fn config_changed(&mut self) {
+        let mut buffers = self.buffers.lock();
+        for ed in buffers.iter_editors_mut() {
+            ed.config_changed();
+        }
+    }
+
+    /// Handles the client's notification that a view's focus changed.
+    fn do_focus_changed(&mut self, view_id: &ViewIdentifier, focused: bool) {
+        if !focused && self.config.lock().unwrap().auto_save == AutoSave::OnFocusLost {
+            self.do_auto_save(view_id);
+        }
+    }
+
+    /// After an edit, postpones the automatic save of the view's buffer,
+    /// if it is saved after a delay.
+    fn schedule_auto_save<'a>(&mut self, view_id: &ViewIdentifier, rpc_ctx: &mut RpcCtx<'a>) {
+        let (auto_save, delay) = {
+            let config = self.config.lock().unwrap();
+            (config.auto_save, config.auto_save_delay)
+        };
+        if auto_save != AutoSave::AfterDelay {
+            return;
+        }
+        let deadline = self.buffers.lock().editor_for_view_mut(view_id)
+            .and_then(|ed| ed.schedule_auto_save(Instant::now(), Duration::from_millis(delay)));
+        if let Some(deadline) = deadline {
+            rpc_ctx.schedule_timer(deadline, AUTO_SAVE_TOKEN);
+        }
+    }
+
+    /// After an edit, postpones the detection of the syntax of the view's
+    /// buffer, if the lines which may determine it were edited.
+    fn schedule_syntax_detection<'a>(&mut self, view_id: &ViewIdentifier,
+                                     rpc_ctx: &mut RpcCtx<'a>) {
+        let deadline = self.buffers.lock().editor_for_view_mut(view_id)
+            .and_then(|ed| ed.schedule_syntax_detection(Instant::now()));
+        if let Some(deadline) = deadline {
+            rpc_ctx.schedule_timer(deadline, SYNTAX_DETECTION_TOKEN);
+        }
+    }
+
+    /// After an edit, starts the timer for sending plugins the edits of
+    /// the view's buffer, if its views are all hidden.
+    fn schedule_deferred_update<'a>(&mut self, view_id: &ViewIdentifier,
+                                    rpc_ctx: &mut RpcCtx<'a>) {
+        let deadline = self.buffers.lock().editor_for_view_mut(view_id)
+            .and_then(|ed| ed.schedule_deferred_update(Instant::now()));
+        if let Some(deadline) = deadline {
+            rpc_ctx.schedule_timer(deadline, DEFERRED_UPDATE_TOKEN);
+        }
+    }
+
+    /// Sends plugins the deferred edits of each hidden buffer whose update
+    /// is due.
+    fn do_deferred_updates_due(&mut self) {
+        let now = Instant::now();
+        for editor in self.buffers.lock().iter_editors_mut() {
+            editor.send_deferred_update_if_due(now);
+        }
+    }
+
+    /// Detects the syntax of each buffer whose detection is due, restarting
+    /// the plugins of those whose syntax changed.
+    fn do_syntax_detection_due(&mut self) {
+        let now = Instant::now();
+        let changed = self.buffers.lock().iter_editors_mut()
+            .filter_map(|ed| ed.detect_syntax_if_due(now))
+            .collect::<Vec<_>>();
+        for view_id in changed {
+            self.language_changed(&view_id);
+        }
+    }
+
+    /// Restarts the plugins of the view's buffer, after its language was
+    /// set or detected, so that they highlight it anew.
+    fn language_changed(&self, view_id: &ViewIdentifier) {
+        let init_info = self.buffers.lock().editor_for_view(view_id)
+            .map(|ed| ed.plugin_init_info());
+        if let Some(init_info) = init_info {
+            self.plugins.document_language_changed(view_id, init_info);
+        }
+    }
+
+    /// Saves each buffer whose auto-save timer has expired. Saves happen
+    /// synchronously, so one is never already in progress.
+    fn do_auto_save_due(&mut self) {
+        if self.config.lock().unwrap().auto_save != AutoSave::AfterDelay {
+            return;
+        }
+        let now = Instant::now();
+        let saved = self.buffers.lock().iter_editors_mut()
+            .filter_map(|ed| if ed.auto_save_due(now) { ed.do_auto_save() } else { None })
+            .collect::<Vec<_>>();
+        for (view_id, path) in saved {
+            self.plugins.document_did_save(&view_id, &path);
+        }
+    }
+
+    /// Saves the view's buffer to its file, if it has unsaved changes.
+    fn do_auto_save(&mut self, view_id: &ViewIdentifier) {
+        let saved = self.buffers.lock().editor_for_view_mut(view_id)
+            .and_then(|ed| ed.do_auto_save());
+        if let Some((view_id, path)) = saved {
+            self.plugins.document_did_save(&view_id, &path);
+        }
+    }
+
+    pub fn handle_idle<'a>(&mut self, rpc_ctx: &mut RpcCtx<'a>, token: usize) {
+        match token {
+            BACKGROUND_WORK_TOKEN => self.do_background_work(rpc_ctx),
+            AUTO_SAVE_TOKEN => self.do_auto_save_due(),
+            SYNTAX_DETECTION_TOKEN => {
+                self.do_syntax_detection_due();
+                rpc_ctx.schedule_idle(BACKGROUND_WORK_TOKEN);
+            }
+            DEFERRED_UPDATE_TOKEN => self.do_deferred_updates_due(),
+            _ => {
+                while let Some(f) = self.idle_queue.pop() {
+                    f.call(self);
+                }
+            }
+        }
+    }
+
+    /// Does a step of background work for each editor which has some,
+    /// rescheduling itself until none remains.
+    fn do_background_work<'a>(&mut self, rpc_ctx: &mut RpcCtx<'a>) {
+        let mut has_work = false;
+        for editor in self.buffers.lock().iter_editors_mut() {
+            if editor.has_background_work() {
+                editor.do_background_work();
+                has_work |= editor.has_background_work();
+            }
+        }
+        for view in self.mapped_views.values_mut() {
+            if view.has_background_work() {
+                view.do_background_work();
+                has_work |= view.has_background_work();
+            }
+        }
+        if has_work {
+            rpc_ctx.schedule_idle(BACKGROUND_WORK_TOKEN);
         }
     }
 }
@@ -609,70 +1187,145 @@ impl Drop for Documents {
 }
 
 impl DocumentCtx {
+    /// Sends the notification `method` about the view `view_id` to the
+    /// front-end, stamped with a `seq` one greater than that of the last
+    /// notification about the view. All notifications about a view go
+    /// through here, and are sent while the sequence numbers are locked,
+    /// so that they are received in the order they are stamped.
+    fn send_view_notification(&self, view_id: &ViewIdentifier, method: &str,
+                              mut params: Value) {
+        let mut view_seqs = self.view_seqs.lock().unwrap();
+        let seq = view_seqs.entry(view_id.clone()).or_insert(0);
+        *seq += 1;
+        params["seq"] = json!(*seq);
+        self.rpc_peer.send_rpc_notification(method, &params);
+    }
+
     pub fn update_view(&self, view_id: &ViewIdentifier, update: &Value) {
-        self.rpc_peer.send_rpc_notification("update",
-            &json!({
+        self.send_view_notification(view_id, "update",
+            json!({
                 "view_id": view_id,
                 "update": update,
             }));
     }
 
-    pub fn scroll_to(&self, view_id: &ViewIdentifier, line: usize, col: usize) {
-        self.rpc_peer.send_rpc_notification("scroll_to",
-            &json!({
+    /// Asks the front-end to scroll `position` into view, in the middle of
+    /// the view if `center` is set.
+    pub fn scroll_to(&self, view_id: &ViewIdentifier, position: &ScrollPosition, center: bool) {
+        let mut params = json!({
+            "view_id": view_id,
+            "line": position.line,
+            "col": position.col,
+        });
+        if center {
+            params["center"] = json!(true);
+        }
+        if self.get_capabilities().visual_scroll_to {
+            params["visual_line"] = json!(position.visual_line);
+            params["visual_col"] = json!(position.visual_col);
+            params["affinity"] = json!(match position.affinity {
+                Affinity::Downstream => "downstream",
+                Affinity::Upstream => "upstream",
+            });
+        }
+        self.send_view_notification(view_id, "scroll_to", params);
+    }
+
+    /// Notify the client that the minimap summaries of buckets
+    /// `first..last` have changed.
+    pub fn minimap_changed(&self, view_id: &ViewIdentifier, n_buckets: usize,
+                           first: usize, last: usize) {
+        self.send_view_notification(view_id, "minimap_changed",
+            json!({
+                "view_id": view_id,
+                "n_buckets": n_buckets,
+                "first_bucket": first,
+                "last_bucket": last,
+            }));
+    }
+
+    /// Notify the client of the status of a view's buffer and its file.
+    pub fn buffer_info(&self, view_id: &ViewIdentifier, info: &BufferInfo) {
+        let mut params = serde_json::to_value(info).unwrap();
+        params["view_id"] = json!(view_id);
+        self.send_view_notification(view_id, "buffer_info", params);
+    }
+
+    /// Notify the client of the progress of indexing a view-only file.
+    pub fn index_progress(&self, view_id: &ViewIdentifier, indexed: usize, total: usize) {
+        self.send_view_notification(view_id, "index_progress",
+            json!({
                 "view_id": view_id,
-                "line": line,
-                "col": col,
+                "indexed": indexed,
+                "total": total,
             }));
     }
 
     /// Notify the client that a plugin ha started.
     pub fn plugin_started(&self, view_id: &ViewIdentifier, plugin: &str) {
-        self.rpc_peer.send_rpc_notification("plugin_started",
-                                            &json!({
-                                                "view_id": view_id,
-                                                "plugin": plugin,
-                                            }));
+        self.send_view_notification(view_id, "plugin_started",
+                                    json!({
+                                        "view_id": view_id,
+                                        "plugin": plugin,
+                                    }));
     }
 
     /// Notify the client that a plugin ha stopped.
     ///
     /// `code` is not currently used.
     pub fn plugin_stopped(&self, view_id: &ViewIdentifier, plugin: &str, code: i32) {
-        self.rpc_peer.send_rpc_notification("plugin_stopped",
-                                            &json!({
-                                                "view_id": view_id,
-                                                "plugin": plugin,
-                                                "code": code,
-                                            }));
+        self.send_view_notification(view_id, "plugin_stopped",
+                                    json!({
+                                        "view_id": view_id,
+                                        "plugin": plugin,
+                                        "code": code,
+                                    }));
     }
 
     /// Notify the client of the available plugins.
     pub fn available_plugins(&self, view_id: &ViewIdentifier,
                              plugins: &[ClientPluginInfo]) {
-        self.rpc_peer.send_rpc_notification("available_plugins",
-                                            &json!({
-                                                "view_id": view_id,
-                                                "plugins": plugins }));
+        self.send_view_notification(view_id, "available_plugins",
+                                    json!({
+                                        "view_id": view_id,
+                                        "plugins": plugins }));
     }
 
     pub fn update_cmds(&self, view_id: &ViewIdentifier,
                        plugin: &str, cmds: &[Command]) {
-        self.rpc_peer.send_rpc_notification("update_cmds",
-                                            &json!({
-                                                "view_id": view_id,
-                                                "plugin": plugin,
-                                                "cmds": cmds,
-                                            }));
-    }
-
-    pub fn alert(&self, msg: &str) {
-        self.rpc_peer.send_rpc_notification("alert",
-            &json!({
-                "msg": msg,
+        self.send_view_notification(view_id, "update_cmds",
+                                    json!({
+                                        "view_id": view_id,
+                                        "plugin": plugin,
+                                        "cmds": cmds,
+                                    }));
+    }
+
+    /// Notify the client that a gesture in the view was dropped, as the
+    /// lines it was made on are too old, and that it should retry once it
+    /// has the lines of revision `rev`.
+    pub fn stale_gesture(&self, view_id: &ViewIdentifier, rev: u64) {
+        self.send_view_notification(view_id, "stale_gesture",
+            json!({
+                "view_id": view_id,
+                "rev": rev,
             }));
     }
 
+    pub fn alert(&self, msg: &Message) {
+        self.rpc_peer.send_rpc_notification("alert", &alert_params(msg));
+    }
+
+    /// Sends an alert for the view offering the user `actions`, which the
+    /// front-end answers with the command for the alert.
+    pub fn alert_with_actions(&self, view_id: &ViewIdentifier, msg: &Message,
+                              actions: &[&str]) {
+        let mut params = alert_params(msg);
+        params["view_id"] = json!(view_id);
+        params["actions"] = json!(actions);
+        self.send_view_notification(view_id, "alert", params);
+    }
+
     pub fn get_kill_ring(&self) -> Rope {
         self.kill_ring.lock().unwrap().clone()
     }
@@ -686,6 +1339,53 @@ impl DocumentCtx {
         &self.style_map
     }
 
+    /// Returns the current theme. The style map is only locked while it
+    /// is taken, so styles can be computed from it without blocking other
+    /// documents.
+    pub fn get_theme(&self) -> Arc<Theme> {
+        self.style_map.lock().unwrap().get_theme()
+    }
+
+    /// Returns a copy of the current user configuration.
+    pub fn get_config(&self) -> Config {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Replaces the config, as the user's changes to it do.
+    #[cfg(test)]
+    pub fn set_config(&self, config: Config) {
+        *self.config.lock().unwrap() = config;
+    }
+
+    pub fn get_capabilities(&self) -> ClientCapabilities {
+        self.capabilities.lock().unwrap().clone()
+    }
+
+    /// Replaces the capabilities of the client, as `client_started` does.
+    #[cfg(test)]
+    pub fn set_capabilities(&self, capabilities: ClientCapabilities) {
+        *self.capabilities.lock().unwrap() = capabilities;
+    }
+
+    /// Returns the incidents most recently recorded, as for
+    /// `get_incidents`.
+    pub fn get_incidents(&self) -> Value {
+        self.incidents.lock().unwrap().to_json()
+    }
+
+    /// Adds `incident` to the log, alerting the user if it is the first
+    /// at its location.
+    pub fn record_incident(&self, incident: Incident) {
+        let mut log = self.incidents.lock().unwrap();
+        if let Some(incident) = log.push(incident) {
+            let msg = Message::new(ids::internal_error)
+                .param("location", incident.location.as_str())
+                .param("id", incident.id)
+                .param("incident", serde_json::to_value(incident).unwrap());
+            self.alert(&msg);
+        }
+    }
+
 
     // Get the index for a given style. If the style is not in the existing
     // style map, then issues a def_style request to the front end. Intended
@@ -702,6 +1402,34 @@ impl DocumentCtx {
         ix
     }
 
+    /// Gets the indices of `styles`, as `get_style_id` does, but locks the
+    /// style map once, and defines the new styles together, with a single
+    /// `def_styles` notification if the front-end supports it.
+    pub fn get_style_ids(&self, styles: &[Style]) -> Vec<usize> {
+        let mut defs = Vec::new();
+        let ids = {
+            let mut style_map = self.style_map.lock().unwrap();
+            styles.iter().map(|style| {
+                if let Some(ix) = style_map.lookup(style) {
+                    return ix;
+                }
+                let ix = style_map.add(style);
+                defs.push(style_map.merge_with_default(style).to_json(ix));
+                ix
+            }).collect()
+        };
+        if self.get_capabilities().def_styles {
+            if !defs.is_empty() {
+                self.rpc_peer.send_rpc_notification("def_styles", &json!({"styles": defs}));
+            }
+        } else {
+            for def in &defs {
+                self.rpc_peer.send_rpc_notification("def_style", def);
+            }
+        }
+        ids
+    }
+
     /// Notify plugins of an update
     pub fn update_plugins(&self, view_id: ViewIdentifier,
                           update: PluginUpdate, undo_group: usize) {
@@ -768,31 +1496,60 @@ impl Documents {
     }
 }
 
+// a bit of gymnastics to let us instantiate an Editor instance
+/// Returns the params of an alert of `msg`, which has its English text as
+/// `msg`, for front-ends which don't translate messages.
+fn alert_params(msg: &Message) -> Value {
+    let mut params = msg.to_json();
+    params["msg"] = params["fallback_text"].clone();
+    params
+}
+
 #[cfg(test)]
-mod tests {
-    use super::*;
-    use xi_rpc::{RpcLoop};
-    use std::env;
-    use std::fs::File;
-    use serde_json;
+pub fn mock_doc_ctx(tempfile: &str) -> DocumentCtx {
+    use std::thread;
 
-    // a bit of gymnastics to let us instantiate an Editor instance
-    fn mock_doc_ctx(tempfile: &str) -> DocumentCtx {
-        let mut dir = env::temp_dir();
-        dir.push(tempfile);
-        let f = File::create(dir).unwrap();
+    let (doc_ctx, update_rx) = mock_doc_ctx_with_updates(tempfile);
+    // discard plugin updates, for editors which commit edits
+    thread::spawn(move || for _ in update_rx {});
+    doc_ctx
+}
 
-        let mock_loop = RpcLoop::new(f);
-        let mock_peer = mock_loop.get_raw_peer();
-        let (update_tx, _) = mpsc::channel();
+/// Returns a mock `DocumentCtx`, and the receiver of the plugin updates
+/// sent through it. Messages to the front-end are written to `tempfile`,
+/// in the temporary directory.
+#[cfg(test)]
+pub fn mock_doc_ctx_with_updates(tempfile: &str)
+    -> (DocumentCtx, mpsc::Receiver<(ViewIdentifier, PluginUpdate, usize)>)
+{
+    use std::env;
+    use xi_rpc::RpcLoop;
+
+    let mut dir = env::temp_dir();
+    dir.push(tempfile);
+    let f = File::create(dir).unwrap();
+
+    let mock_loop = RpcLoop::new(f);
+    let mock_peer = mock_loop.get_raw_peer();
+    let (update_tx, update_rx) = mpsc::channel();
+
+    let doc_ctx = DocumentCtx {
+        kill_ring: Arc::new(Mutex::new(Rope::from(""))),
+        rpc_peer: Box::new(mock_peer.clone()),
+        style_map: Arc::new(Mutex::new(ThemeStyleMap::new())),
+        config: Arc::new(Mutex::new(Config::default())),
+        capabilities: Arc::new(Mutex::new(ClientCapabilities::default())),
+        incidents: Arc::new(Mutex::new(IncidentLog::default())),
+        view_seqs: Arc::new(Mutex::new(BTreeMap::new())),
+        update_channel: update_tx,
+    };
+    (doc_ctx, update_rx)
+}
 
-        DocumentCtx {
-            kill_ring: Arc::new(Mutex::new(Rope::from(""))),
-            rpc_peer: Box::new(mock_peer.clone()),
-            style_map: Arc::new(Mutex::new(ThemeStyleMap::new())),
-            update_channel: update_tx,
-        }
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
 
     #[test]
     fn test_save_as() {
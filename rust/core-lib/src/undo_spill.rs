@@ -0,0 +1,332 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Undo history spilled to disk, so that undo groups the engine has
+//! collected can still be undone.
+//!
+//! When an undo group falls out of the in-memory window, the edit which
+//! undoes it is spilled: the text it replaced, relative to the text right
+//! after it, with every later group undone. The spilled groups are a stack,
+//! written to a per-buffer file a segment at a time, which is walked back
+//! by undos past the window.
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use serde_json;
+
+use xi_rope::rope::Rope;
+
+/// The version of the format of spilled segments.
+pub const SPILL_VERSION: u64 = 1;
+/// The number of spilled groups written to the file at a time.
+pub const SEGMENT_LEN: usize = 64;
+/// The most segments kept in memory once they are loaded back.
+pub const LOADED_SEGMENTS: usize = 4;
+
+/// Where undo groups go once they fall out of the in-memory window.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UndoHistory {
+    /// They are forgotten.
+    Memory,
+    /// They are spilled to disk.
+    Spill,
+}
+
+impl Default for UndoHistory {
+    fn default() -> Self {
+        UndoHistory::Memory
+    }
+}
+
+/// An edit which undoes a spilled undo group, or redoes it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SpilledGroup {
+    /// The interval of the text which is replaced.
+    pub start: usize,
+    pub end: usize,
+    /// The text which replaces it.
+    pub text: String,
+    /// The length of the text the edit applies to, which the buffer must
+    /// have for it to be made.
+    pub base_len: usize,
+}
+
+impl SpilledGroup {
+    /// Returns the edit which reverts this edit, made to `text`.
+    pub fn inverse(&self, text: &Rope) -> SpilledGroup {
+        SpilledGroup {
+            start: self.start,
+            end: self.start + self.text.len(),
+            text: text.slice_to_string(self.start, self.end),
+            base_len: self.base_len - (self.end - self.start) + self.text.len(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Segment {
+    version: u64,
+    groups: Vec<SpilledGroup>,
+}
+
+/// The spilled undo groups of a buffer, oldest first. The file is removed
+/// once they are dropped.
+pub struct UndoSpill {
+    path: PathBuf,
+    /// The offset and length in the file of each segment written.
+    segments: Vec<(u64, usize)>,
+    /// The groups after those written, which are yet to fill a segment.
+    pending: Vec<SpilledGroup>,
+    /// The number of groups which are still applied; those after them are
+    /// undone, and can be restored.
+    len: usize,
+    /// The segments loaded back from the file, most recently used last.
+    loaded: VecDeque<(usize, Vec<SpilledGroup>)>,
+}
+
+/// Returns the directory undo history is spilled to.
+pub fn spill_dir() -> PathBuf {
+    let cache = env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .unwrap_or_else(env::temp_dir);
+    cache.join("xi").join("undo")
+}
+
+/// Returns the path of the file the undo history of the buffer `buffer`
+/// is spilled to.
+pub fn spill_path(buffer: &str) -> PathBuf {
+    spill_dir().join(format!("{}-{}.jsonl", process::id(), buffer))
+}
+
+impl UndoSpill {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        UndoSpill {
+            path: path.into(),
+            segments: Vec::new(),
+            pending: Vec::new(),
+            len: 0,
+            loaded: VecDeque::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The number of spilled groups which can be undone.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of groups in the segments which were written.
+    fn written(&self) -> usize {
+        self.segments.len() * SEGMENT_LEN
+    }
+
+    /// Spills `group`, the newest applied group, forgetting the undone
+    /// groups, which can no longer be restored.
+    pub fn push(&mut self, group: SpilledGroup) -> Result<(), String> {
+        self.truncate()?;
+        self.pending.push(group);
+        self.len += 1;
+        if self.pending.len() == SEGMENT_LEN {
+            self.write_segment()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the newest applied group, which is now undone, or `None` if
+    /// there are none.
+    pub fn pop(&mut self) -> Result<Option<SpilledGroup>, String> {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.len -= 1;
+        let ix = self.len;
+        if ix >= self.written() {
+            return Ok(Some(self.pending[ix - self.written()].clone()));
+        }
+        let segment = self.load_segment(ix / SEGMENT_LEN)?;
+        Ok(Some(segment[ix % SEGMENT_LEN].clone()))
+    }
+
+    /// Marks the oldest undone group as applied again, after it is redone.
+    pub fn restore(&mut self) {
+        assert!(self.len < self.written() + self.pending.len(), "no undone group to restore");
+        self.len += 1;
+    }
+
+    /// Forgets the groups after `len`, loading the segment it ends in back
+    /// as the pending groups.
+    fn truncate(&mut self) -> Result<(), String> {
+        if self.len >= self.written() {
+            let n_pending = self.len - self.written();
+            self.pending.truncate(n_pending);
+            return Ok(());
+        }
+        let ix = self.len / SEGMENT_LEN;
+        let mut pending = self.load_segment(ix)?;
+        pending.truncate(self.len % SEGMENT_LEN);
+        let offset = self.segments[ix].0;
+        self.segments.truncate(ix);
+        self.loaded.retain(|&(loaded_ix, _)| loaded_ix < ix);
+        self.pending = pending;
+        OpenOptions::new().write(true).open(&self.path)
+            .and_then(|file| file.set_len(offset))
+            .map_err(|e| format!("can't truncate {}: {}", self.path.display(), e))
+    }
+
+    fn write_segment(&mut self) -> Result<(), String> {
+        let segment = Segment {
+            version: SPILL_VERSION,
+            groups: mem::replace(&mut self.pending, Vec::new()),
+        };
+        let mut line = serde_json::to_string(&segment).expect("segment serializes");
+        line.push('\n');
+        let path = self.path.clone();
+        let result = fs::create_dir_all(self.path.parent().unwrap_or_else(|| Path::new(".")))
+            .and_then(|_| OpenOptions::new().create(true).append(true).open(&path))
+            .and_then(|mut file| {
+                let offset = file.seek(SeekFrom::End(0))?;
+                file.write_all(line.as_bytes())?;
+                Ok(offset)
+            });
+        match result {
+            Ok(offset) => {
+                self.segments.push((offset, line.len()));
+                Ok(())
+            }
+            Err(e) => {
+                self.pending = segment.groups;
+                Err(format!("can't write {}: {}", path.display(), e))
+            }
+        }
+    }
+
+    /// Returns the groups of the segment `ix`, reading it from the file
+    /// unless it was loaded recently.
+    fn load_segment(&mut self, ix: usize) -> Result<Vec<SpilledGroup>, String> {
+        if let Some(pos) = self.loaded.iter().position(|&(loaded_ix, _)| loaded_ix == ix) {
+            let entry = self.loaded.remove(pos).unwrap();
+            let groups = entry.1.clone();
+            self.loaded.push_back(entry);
+            return Ok(groups);
+        }
+        let (offset, len) = self.segments[ix];
+        let mut buf = vec![0; len];
+        File::open(&self.path)
+            .and_then(|mut file| {
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(&mut buf)
+            })
+            .map_err(|e| format!("can't read {}: {}", self.path.display(), e))?;
+        let segment: Segment = serde_json::from_slice(&buf)
+            .map_err(|e| format!("{} is corrupted: {}", self.path.display(), e))?;
+        if segment.version != SPILL_VERSION {
+            return Err(format!("unsupported spill version {}", segment.version));
+        }
+        if segment.groups.len() != SEGMENT_LEN {
+            return Err(format!("{} is corrupted: a segment has {} groups",
+                               self.path.display(), segment.groups.len()));
+        }
+        if self.loaded.len() == LOADED_SEGMENTS {
+            self.loaded.pop_front();
+        }
+        self.loaded.push_back((ix, segment.groups.clone()));
+        Ok(segment.groups)
+    }
+}
+
+impl Drop for UndoSpill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(n: usize) -> SpilledGroup {
+        SpilledGroup { start: n, end: n + 1, text: n.to_string(), base_len: n + 1 }
+    }
+
+    fn temp_spill(name: &str) -> UndoSpill {
+        let path = env::temp_dir().join(format!("xi-undo-spill-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        UndoSpill::new(path)
+    }
+
+    #[test]
+    fn spilled_stack() {
+        let mut spill = temp_spill("stack");
+        let n = SEGMENT_LEN * (LOADED_SEGMENTS + 2) + 3;
+        for i in 0..n {
+            spill.push(group(i)).unwrap();
+        }
+        assert_eq!(spill.segments.len(), LOADED_SEGMENTS + 2);
+        for i in (n - 2 * SEGMENT_LEN..n).rev() {
+            assert_eq!(spill.pop().unwrap(), Some(group(i)));
+        }
+        // redone groups are restored without being spilled again
+        spill.restore();
+        spill.restore();
+        assert_eq!(spill.pop().unwrap(), Some(group(n - 2 * SEGMENT_LEN + 1)));
+        assert!(spill.loaded.len() <= LOADED_SEGMENTS);
+
+        // a new group replaces the undone ones
+        spill.push(group(1000)).unwrap();
+        assert_eq!(spill.len(), n - 2 * SEGMENT_LEN + 2);
+        assert_eq!(spill.pop().unwrap(), Some(group(1000)));
+        for i in (0..n - 2 * SEGMENT_LEN + 1).rev() {
+            assert_eq!(spill.pop().unwrap(), Some(group(i)));
+        }
+        assert_eq!(spill.pop().unwrap(), None);
+        let path = spill.path().to_owned();
+        assert!(path.exists());
+        drop(spill);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn corrupted_spill() {
+        let mut spill = temp_spill("corrupted");
+        for i in 0..SEGMENT_LEN + 1 {
+            spill.push(group(i)).unwrap();
+        }
+        let mut contents = fs::read(spill.path()).unwrap();
+        contents[0] = b'[';
+        fs::write(spill.path(), contents).unwrap();
+        assert_eq!(spill.pop().unwrap(), Some(group(SEGMENT_LEN)));
+        assert!(spill.pop().unwrap_err().contains("corrupted"));
+
+        let mut spill = temp_spill("missing");
+        for i in 0..SEGMENT_LEN {
+            spill.push(group(i)).unwrap();
+        }
+        fs::remove_file(spill.path()).unwrap();
+        assert!(spill.pop().unwrap_err().contains("can't read"));
+    }
+}
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::cmp::{min,max};
+use std::mem;
 
 use serde_json::value::Value;
 
@@ -21,20 +22,57 @@ use xi_rope::delta::{Delta, DeltaRegion};
 use xi_rope::tree::{Cursor, Metric};
 use xi_rope::breaks::{Breaks, BreaksInfo, BreaksMetric, BreaksBaseMetric};
 use xi_rope::interval::Interval;
-use xi_rope::spans::Spans;
+use xi_rope::spans::{Spans, SpansBuilder};
 use xi_rope::find::{find, CaseMatching};
+use xi_rope::engine::RevToken;
 
 use tabs::{ViewIdentifier, DocumentCtx};
 use styles::Style;
+use layers::{CoreLayer, CoreStyles, Overlay, SelectionKind};
 use index_set::IndexSet;
 use selection::{Affinity, Selection, SelRegion};
 use movement::{Movement, selection_movement};
+use word_boundaries::{Segmentation, WordCursor};
+use metrics::BufferMetrics;
 
 use linewrap;
+use line_ids::LineIds;
+use jump_list::JumpList;
+use minimap::Minimap;
+use style_cache::StyleCache;
+use layout_hints::{self, LayoutHints};
+use accessibility::ChangeLog;
+use line_metadata::LineMetadata;
+use bidi;
 
 const SCROLL_SLOP: usize = 2;
+
+/// The most changed lines described by `accessibility_delta`.
+const MAX_ACCESSIBILITY_LINES: usize = 100;
 const BACKWARDS_FIND_CHUNK_SIZE: usize = 32_768;
 
+/// Where a caret is, as sent to the front-end in `scroll_to`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrollPosition {
+    /// The line and column, for front-ends without the `visual_scroll_to`
+    /// capability. This ignores the caret's affinity.
+    pub line: usize,
+    pub col: usize,
+    /// The visual line, numbered as in the line cache, and the column
+    /// within it, at which the caret is drawn.
+    pub visual_line: usize,
+    pub visual_col: usize,
+    pub affinity: Affinity,
+}
+
+impl ScrollPosition {
+    /// Returns the position of a caret in a view without wrapping.
+    pub fn unwrapped(line: usize, col: usize) -> Self {
+        ScrollPosition { line: line, col: col, visual_line: line, visual_col: col,
+                         affinity: Affinity::default() }
+    }
+}
+
 pub struct View {
     pub view_id: ViewIdentifier,
 
@@ -68,13 +106,54 @@ pub struct View {
     search_string: Option<String>,
     /// The case matching setting for the currently active search
     case_matching: CaseMatching,
+    /// Whether the currently active search only matches whole words
+    whole_words: bool,
     /// The set of all known find occurrences (highlights)
     occurrences: Option<Selection>,
     /// Set of ranges that have already been searched for the currently active search string
     valid_search: IndexSet,
+    /// Whitespace which will be removed when the buffer is saved.
+    pending_cleanup: Selection,
+    /// The ids of logical lines, if the front-end has asked for them.
+    line_ids: Option<LineIds>,
+    /// The positions the caret has jumped from.
+    jump_list: JumpList,
+    /// The summaries of the lines for the front-end's minimap, once it has
+    /// asked for them.
+    minimap: Option<Minimap>,
+    /// The lines the front-end holds, with their styles, if it accepts
+    /// `update_styles` ops.
+    style_cache: Option<StyleCache>,
+    /// The layout hints last sent to the front-end.
+    layout_hints: Option<LayoutHints>,
+    /// The changes to the view, for accessibility clients.
+    accessibility: ChangeLog,
+    /// The revision of the buffer the lines sent to the front-end are of,
+    /// which it gives back in gestures, or `None` while an undo is being
+    /// applied in chunks.
+    rev: Option<RevToken>,
+    /// Whether the front-end is showing the view. Hidden views aren't
+    /// rendered, and aren't rewrapped as they are edited.
+    visible: bool,
+    /// If true, the view was wrapped when it was last visible, and is
+    /// rewrapped at `wrap_col` once it is shown again.
+    wrap_stale: bool,
+}
+
+/// The unit by which a region selected with the mouse grows, as it is
+/// dragged or extended: characters after a click, words after a double
+/// click, and lines after a triple click.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Granularity {
+    Point,
+    Word,
+    Line,
 }
 
 /// State required to resolve a drag gesture into a selection.
+///
+/// This outlives the drag itself, so that the region can be extended from
+/// its anchor, until the selection is changed other than by the mouse.
 struct DragState {
     /// All the selection regions other than the one being dragged.
     base_sel: Selection,
@@ -88,6 +167,8 @@ struct DragState {
 
     /// End of the region selected when drag was started.
     max: usize,
+
+    granularity: Granularity,
 }
 
 impl View {
@@ -114,9 +195,47 @@ impl View {
             pristine: true,
             search_string: None,
             case_matching: CaseMatching::CaseInsensitive,
+            whole_words: false,
             occurrences: None,
             valid_search: IndexSet::new(),
+            pending_cleanup: Selection::new(),
+            line_ids: None,
+            jump_list: JumpList::default(),
+            minimap: None,
+            style_cache: None,
+            layout_hints: None,
+            accessibility: ChangeLog::default(),
+            rev: None,
+            visible: true,
+            wrap_stale: false,
+        }
+    }
+
+    pub fn set_rev(&mut self, rev: Option<RevToken>) {
+        self.rev = rev;
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    /// Shows or hides the view. A view which is shown again is rewrapped,
+    /// if it was wrapped, and rendered anew.
+    pub fn set_visible(&mut self, text: &Rope, visible: bool) {
+        if visible && !self.visible {
+            if self.wrap_stale {
+                self.wrap_stale = false;
+                let wrap_col = self.wrap_col;
+                self.rewrap(text, wrap_col);
+            }
+            self.set_dirty();
         }
+        self.visible = visible;
+    }
+
+    /// Returns true if the view's lines are wrapped.
+    pub fn is_wrapped(&self) -> bool {
+        self.breaks.is_some()
     }
 
     pub fn set_scroll(&mut self, first: usize, last: usize) {
@@ -128,6 +247,18 @@ impl View {
         self.height
     }
 
+    /// Returns the region of the text within the lines scrolled into view.
+    pub fn scroll_interval(&self, text: &Rope) -> Interval {
+        let n_lines = self.line_of_offset(text, text.len()) + 1;
+        let offset_of_line = |line| if line < n_lines {
+            self.offset_of_line(text, line)
+        } else {
+            text.len()
+        };
+        Interval::new_closed_open(offset_of_line(self.first_line),
+                                  offset_of_line(self.first_line + self.height))
+    }
+
     pub fn scroll_to_cursor(&mut self, text: &Rope) {
         let end = self.sel_regions().last().unwrap().end;
         let (line, _) = self.offset_to_line_col(text, end);
@@ -138,9 +269,17 @@ impl View {
         }
     }
 
+    /// Scrolls so that the line of `offset` is in the middle of the view,
+    /// or as near it as the start of the document allows.
+    pub fn scroll_to_center(&mut self, text: &Rope, offset: usize) {
+        let (line, _) = self.offset_to_line_col(text, offset);
+        self.first_line = line.saturating_sub(self.height / 2);
+    }
+
     /// Toggles a caret at the given offset.
     pub fn toggle_sel(&mut self, offset: usize) {
         self.sel_dirty = true;
+        self.accessibility.selection_changed();
         if !self.selection.regions_in_range(offset, offset).is_empty() {
             self.selection.delete_range(offset, offset, true);
             if !self.selection.is_empty() {
@@ -153,6 +292,7 @@ impl View {
             offset: offset,
             min: offset,
             max: offset,
+            granularity: Granularity::Point,
         });
         let region = SelRegion {
             start: offset,
@@ -168,17 +308,27 @@ impl View {
     ///
     /// If `modify` is `true`, the selections are modified, otherwise the results
     /// of individual region movements become carets.
-    pub fn do_move(&mut self, text: &Rope, movement: Movement, modify: bool)
-        -> Option<usize>
+    pub fn do_move(&mut self, text: &Rope, words: &Segmentation, movement: Movement,
+                   modify: bool) -> Option<usize>
     {
         self.drag_state = None;
-        let new_sel = selection_movement(movement, &self.selection, self, text, modify);
+        let new_sel = selection_movement(movement, &self.selection, self, text, words, modify);
         self.set_selection(text, new_sel)
     }
 
     /// Set the selection to a new value. Return value is the offset of a
     /// point that should be scrolled into view.
+    ///
+    /// This ends any drag, as the dragged region may be gone.
     pub fn set_selection(&mut self, text: &Rope, sel: Selection) -> Option<usize> {
+        self.drag_state = None;
+        self.set_drag_selection(text, sel)
+    }
+
+    fn set_drag_selection(&mut self, text: &Rope, sel: Selection) -> Option<usize> {
+        if *sel != *self.selection {
+            self.accessibility.selection_changed();
+        }
         self.selection = sel;
         // We somewhat arbitrarily choose the last region for setting the old-style
         // selection state, and for scrolling it into view if needed. This choice can
@@ -202,30 +352,85 @@ impl View {
         });
         self.selection = selection;
         self.sel_dirty = true;
+        self.drag_state = None;
+        self.accessibility.selection_changed();
     }
 
-    /// Starts a drag operation.
-    pub fn start_drag(&mut self, offset: usize, min: usize, max: usize) {
+    /// Starts a drag operation, from the region between `min` and `max`
+    /// selected by a click at `offset`. The selection should already have
+    /// been set to that region alone.
+    pub fn start_drag(&mut self, offset: usize, min: usize, max: usize,
+                      granularity: Granularity) {
         let base_sel = Selection::new();
-        self.drag_state = Some(DragState { base_sel, offset, min, max });
+        self.drag_state = Some(DragState { base_sel, offset, min, max, granularity });
     }
 
     /// Does a drag gesture, setting the selection from a combination of the drag
     /// state and new offset.
-    pub fn do_drag(&mut self, text: &Rope, offset: usize, affinity: Affinity) -> Option<usize> {
+    pub fn do_drag(&mut self, text: &Rope, words: &Segmentation, offset: usize,
+                   affinity: Affinity) -> Option<usize> {
         let new_sel = self.drag_state.as_ref().map(|drag_state| {
             let mut sel = drag_state.base_sel.clone();
-            // TODO: on double or triple click, quantize offset to requested granularity.
-            let (start, end) = if offset < drag_state.offset {
-                (drag_state.max, min(offset, drag_state.min))
-            } else {
+            let forward = offset >= drag_state.offset;
+            let offset = snap_to_granularity(text, words, offset, drag_state.granularity,
+                                             forward);
+            let (start, end) = if forward {
                 (drag_state.min, max(offset, drag_state.max))
+            } else {
+                (drag_state.max, min(offset, drag_state.min))
             };
             let horiz = None;
             sel.add_region(SelRegion { start, end, horiz, affinity });
             sel
         });
-        new_sel.and_then(|new_sel| self.set_selection(text, new_sel))
+        new_sel.and_then(|new_sel| self.set_drag_selection(text, new_sel))
+    }
+
+    /// Extends the active (last) region from its anchor to `offset`, as a
+    /// shift-click does. After a drag, or a double or triple click, the
+    /// region keeps the anchor and granularity it was selected with, so
+    /// that a word selection extends by words, and dragging afterwards
+    /// extends it further. Otherwise its start is the anchor.
+    pub fn select_extend(&mut self, text: &Rope, words: &Segmentation, offset: usize)
+                         -> Option<usize> {
+        if self.drag_state.is_none() {
+            let (last, rest) = self.selection.split_last().unwrap();
+            let mut base_sel = Selection::new();
+            for region in rest {
+                base_sel.add_region(region.clone());
+            }
+            self.drag_state = Some(DragState {
+                base_sel: base_sel,
+                offset: last.start,
+                min: last.start,
+                max: last.start,
+                granularity: Granularity::Point,
+            });
+        }
+        self.do_drag(text, words, offset, Affinity::default())
+    }
+
+    /// Adds a caret at `offset` to the selection, as an alt-shift-click
+    /// does. Dragging afterwards selects from the caret, keeping the other
+    /// regions.
+    pub fn select_add_region(&mut self, text: &Rope, offset: usize) -> Option<usize> {
+        let base_sel = self.selection.clone();
+        let mut sel = base_sel.clone();
+        sel.add_region(SelRegion {
+            start: offset,
+            end: offset,
+            horiz: None,
+            affinity: Affinity::default(),
+        });
+        let scroll_to = self.set_selection(text, sel);
+        self.drag_state = Some(DragState {
+            base_sel: base_sel,
+            offset: offset,
+            min: offset,
+            max: offset,
+            granularity: Granularity::Point,
+        });
+        scroll_to
     }
 
     /// Returns the regions of the current selection.
@@ -233,16 +438,26 @@ impl View {
         &self.selection
     }
 
+    pub fn jump_list(&self) -> &JumpList {
+        &self.jump_list
+    }
+
+    pub fn jump_list_mut(&mut self) -> &mut JumpList {
+        &mut self.jump_list
+    }
+
     /// Determines whether the offset is in any selection (counting carets and
     /// selection edges).
     pub fn is_point_in_selection(&self, offset: usize) -> bool {
         !self.selection.regions_in_range(offset, offset).is_empty()
     }
 
-    // Render a single line, and advance cursors to next line.
+    // Render a single line, and advance cursors to next line. The line's
+    // bidi levels are resolved with `line_metadata`, or directly if it may
+    // not be up to date.
     fn render_line(&self, tab_ctx: &DocumentCtx, text: &Rope,
         start_of_line: &mut Cursor<RopeInfo>, soft_breaks: Option<&mut Cursor<BreaksInfo>>, style_spans: &Spans<Style>,
-        line_num: usize) -> Value
+        core_styles: &CoreStyles, line_num: usize, line_metadata: Option<&mut LineMetadata>) -> Value
     {
         let start_pos = start_of_line.pos();
         let pos = soft_breaks.map_or(start_of_line.next::<LinesMetric>(), |bc| {
@@ -255,6 +470,10 @@ impl View {
         let l_str = text.slice_to_string(start_pos, pos);
         let mut cursors = Vec::new();
         let mut selections = Vec::new();
+        // the view's selection and find highlights are merged over the styles
+        let mut overlay = Overlay::default();
+        let mut selected = SpansBuilder::new(pos - start_pos);
+        let primary = self.selection.last().map(|region| (region.min(), region.max()));
         for region in self.selection.regions_in_range(start_pos, pos) {
             // cursor
             let c = region.end;
@@ -271,21 +490,39 @@ impl View {
             let sel_end_ix = clamp(region.max(), start_pos, pos) - start_pos;
             if sel_end_ix > sel_start_ix {
                 selections.push((sel_start_ix, sel_end_ix));
+                let kind = if primary == Some((region.min(), region.max())) {
+                    SelectionKind::Primary
+                } else {
+                    SelectionKind::Secondary
+                };
+                selected.add_span(Interval::new_closed_open(sel_start_ix, sel_end_ix), kind);
             }
         }
+        overlay.set_selection_spans(selected.build());
 
-        let mut hls = Vec::new();
         if let Some(ref occurrences) = self.occurrences {
             for region in occurrences.regions_in_range(start_pos, pos) {
                 let sel_start_ix = clamp(region.min(), start_pos, pos) - start_pos;
                 let sel_end_ix = clamp(region.max(), start_pos, pos) - start_pos;
                 if sel_end_ix > sel_start_ix {
-                    hls.push((sel_start_ix, sel_end_ix));
+                    overlay.add_span(CoreLayer::FindHighlights,
+                                     Interval::new_closed_open(sel_start_ix, sel_end_ix),
+                                     SelectionKind::Primary);
                 }
             }
         }
 
-        let styles = self.render_styles(tab_ctx, start_pos, pos, &selections, &hls, style_spans);
+        let mut cleanup = Vec::new();
+        for region in self.pending_cleanup.regions_in_range(start_pos, pos) {
+            let start_ix = clamp(region.min(), start_pos, pos) - start_pos;
+            let end_ix = clamp(region.max(), start_pos, pos) - start_pos;
+            if end_ix > start_ix {
+                cleanup.push((start_ix, end_ix));
+            }
+        }
+
+        let styles = self.render_styles(tab_ctx, start_pos, pos, &cleanup, style_spans,
+                                        &overlay, core_styles);
 
         let mut result = json!({
             "text": &l_str,
@@ -295,27 +532,80 @@ impl View {
         if !cursors.is_empty() {
             result["cursor"] = json!(cursors);
         }
+        if tab_ctx.get_capabilities().bidi {
+            self.render_bidi(text, start_pos, pos, &selections, line_metadata, &mut result);
+        }
+        if let Some(ref line_ids) = self.line_ids {
+            let logical_line = text.line_of_offset(start_pos);
+            result["line_id"] = json!(line_ids.get(logical_line));
+            let wrap_index = line_num - self.line_of_offset(text, text.offset_of_line(logical_line));
+            if wrap_index > 0 {
+                result["wrap_index"] = json!(wrap_index);
+            }
+        }
         result
     }
 
+    /// Adds the bidi levels of the line rendered from `start..end` to
+    /// `rendered`, if it has right-to-left text, and the visual ranges of
+    /// its `selections`, which are relative to `start`.
+    fn render_bidi(&self, text: &Rope, start: usize, end: usize, selections: &[(usize, usize)],
+                   line_metadata: Option<&mut LineMetadata>, rendered: &mut Value) {
+        let logical_line = text.line_of_offset(start);
+        let line_start = text.offset_of_line(logical_line);
+        let bidi = match line_metadata {
+            Some(line_metadata) => line_metadata.bidi(text, logical_line),
+            None => bidi::resolve(&text.slice_to_string(line_start,
+                                                        text.offset_of_line(logical_line + 1))),
+        };
+        let bidi = match bidi {
+            Some(bidi) => bidi,
+            None => return,
+        };
+        let (start, end) = (start - line_start, end - line_start);
+        let runs = bidi::visual_runs(&bidi, start, end).iter()
+            .map(|run| json!([run.start - start, run.end - start, run.level]))
+            .collect::<Vec<_>>();
+        rendered["bidi"] = json!({ "level": bidi.level, "runs": runs });
+        if !selections.is_empty() {
+            let ranges = selections.iter()
+                .flat_map(|&(sel_start, sel_end)| {
+                    bidi::visual_ranges(&bidi, start, end, start + sel_start, start + sel_end)
+                })
+                .map(|(range_start, range_end)| json!([range_start - start, range_end - start]))
+                .collect::<Vec<_>>();
+            rendered["selection_ranges"] = json!(ranges);
+        }
+    }
+
+    /// Starts tracking line ids, if the front-end has asked for them.
+    fn init_line_ids(&mut self, text: &Rope, tab_ctx: &DocumentCtx) {
+        if self.line_ids.is_none() && tab_ctx.get_capabilities().line_ids {
+            self.line_ids = Some(LineIds::new(text));
+        }
+        if self.style_cache.is_none() && tab_ctx.get_capabilities().style_diffs {
+            self.style_cache = Some(StyleCache::new(self.n_visual_lines(text)));
+        }
+    }
+
+    /// Renders the styles of the text from `start` to `end`, with the
+    /// view's `overlay`, in `core_styles`, merged over the document's
+    /// `style_spans`. The selections and find highlights are in the overlay,
+    /// so only the pending cleanup has a reserved style.
     pub fn render_styles(&self, tab_ctx: &DocumentCtx, start: usize, end: usize,
-        sel: &[(usize, usize)], hls: &[(usize, usize)], style_spans: &Spans<Style>) -> Vec<isize>
+        cleanup: &[(usize, usize)], style_spans: &Spans<Style>, overlay: &Overlay,
+        core_styles: &CoreStyles) -> Vec<isize>
     {
         let mut rendered_styles = Vec::new();
         let style_spans = style_spans.subseq(Interval::new_closed_open(start, end));
+        let style_spans = overlay.apply(style_spans, core_styles);
 
         let mut ix = 0;
-        for &(sel_start, sel_end) in sel {
-            rendered_styles.push((sel_start as isize) - ix);
-            rendered_styles.push(sel_end as isize - sel_start as isize);
-            rendered_styles.push(0);
-            ix = sel_end as isize;
-        }
-        for &(sel_start, sel_end) in hls {
-            rendered_styles.push((sel_start as isize) - ix);
-            rendered_styles.push(sel_end as isize - sel_start as isize);
-            rendered_styles.push(1);
-            ix = sel_end as isize;
+        for &(start, end) in cleanup {
+            rendered_styles.push((start as isize) - ix);
+            rendered_styles.push(end as isize - start as isize);
+            rendered_styles.push(2);
+            ix = end as isize;
         }
         for (iv, style) in style_spans.iter() {
             let style_id = tab_ctx.get_style_id(&style);
@@ -328,8 +618,10 @@ impl View {
     }
 
     pub fn send_update(&mut self, text: &Rope, tab_ctx: &DocumentCtx, style_spans: &Spans<Style>,
+        words: &Segmentation, metrics: &BufferMetrics, line_metadata: &mut LineMetadata,
         first_line: usize, last_line: usize)
     {
+        self.init_line_ids(text, tab_ctx);
         let dirty = self.dirty || self.sel_dirty;
         if dirty {
             self.valid_lines.clear();
@@ -339,26 +631,51 @@ impl View {
 
         // update find for given region
         if self.hls_dirty {
-            self.update_find_for_lines(text, first_line, last_line);
+            self.update_find_for_lines(text, words, first_line, last_line);
         }
 
-        let mut ops = Vec::new();
-        if first_line > 0 {
-            let op = if dirty { "invalidate" } else { "copy" };
-            ops.push(self.build_update_op(op, None, first_line));
-        }
         let first_line_offset = self.offset_of_line(text, first_line);
         let mut line_cursor = Cursor::new(text, first_line_offset);
         let mut soft_breaks = self.breaks.as_ref().map(|breaks|
             Cursor::new(breaks, first_line_offset)
         );
 
+        let core_styles = CoreStyles::for_theme(&tab_ctx.get_theme());
         let mut rendered_lines = Vec::new();
         for line_num in first_line..last_line {
             rendered_lines.push(self.render_line(tab_ctx, text,
-                &mut line_cursor, soft_breaks.as_mut(), style_spans, line_num));
+                &mut line_cursor, soft_breaks.as_mut(), style_spans, &core_styles, line_num,
+                Some(&mut *line_metadata)));
+        }
+        let ops = match self.style_cache.as_mut() {
+            Some(style_cache) => style_cache.update_ops(rendered_lines, first_line, height, dirty),
+            None => self.full_update_ops(rendered_lines, first_line, height, dirty),
+        };
+        let mut params = json!({
+            "ops": ops,
+            "pristine": self.pristine,
+            "rev": self.rev,
+            "metrics": self.metrics_json(text, metrics, height),
+        });
+        if let Some(hints) = self.update_layout_hints(text, tab_ctx, line_metadata) {
+            params["layout_hints"] = json!(hints);
         }
-        ops.push(self.build_update_op("ins", Some(rendered_lines), last_line - first_line));
+        tab_ctx.update_view(&self.view_id, &params);
+        self.valid_lines.union_one_range(first_line, last_line);
+    }
+
+
+    /// Returns the ops of an update which sends all of `lines`, rendered
+    /// from `first_line`, and copies or invalidates the others.
+    fn full_update_ops(&self, lines: Vec<Value>, first_line: usize, height: usize,
+                       dirty: bool) -> Vec<Value> {
+        let last_line = first_line + lines.len();
+        let mut ops = Vec::new();
+        if first_line > 0 {
+            let op = if dirty { "invalidate" } else { "copy" };
+            ops.push(self.build_update_op(op, None, first_line));
+        }
+        ops.push(self.build_update_op("ins", Some(lines), last_line - first_line));
         if last_line < height {
             if !dirty {
                 ops.push(self.build_update_op("skip", None, last_line - first_line));
@@ -366,28 +683,25 @@ impl View {
             let op = if dirty { "invalidate" } else { "copy" };
             ops.push(self.build_update_op(op, None, height - last_line));
         }
-        let params = json!({
-            "ops": ops,
-            "pristine": self.pristine,
-        });
-        tab_ctx.update_view(&self.view_id, &params);
-        self.valid_lines.union_one_range(first_line, last_line);
+        ops
     }
 
-
     /// Send lines within given region (plus slop) that the front-end does not already
     /// have.
     pub fn send_update_for_scroll(&mut self, text: &Rope, tab_ctx: &DocumentCtx, style_spans: &Spans<Style>,
+        words: &Segmentation, metrics: &BufferMetrics, line_metadata: &mut LineMetadata,
         first_line: usize, last_line: usize)
     {
+        self.init_line_ids(text, tab_ctx);
         let first_line = max(first_line, SCROLL_SLOP) - SCROLL_SLOP;
         let last_line = last_line + SCROLL_SLOP;
         let height = self.offset_to_line_col(text, text.len()).0 + 1;
         let last_line = min(last_line, height);
 
         // update find for given region
-        self.update_find_for_lines(text, first_line, last_line);
+        self.update_find_for_lines(text, words, first_line, last_line);
 
+        let core_styles = CoreStyles::for_theme(&tab_ctx.get_theme());
         let mut ops = Vec::new();
         let mut line = 0;
         for (start, end) in self.valid_lines.minus_one_range(first_line, last_line) {
@@ -404,27 +718,184 @@ impl View {
             for line_num in start..end {
                 rendered_lines.push(self.render_line(tab_ctx, text,
                                                      &mut line_cursor, soft_breaks.as_mut(),
-                                                     style_spans, line_num));
+                                                     style_spans, &core_styles, line_num,
+                                                     Some(&mut *line_metadata)));
+            }
+            if let Some(style_cache) = self.style_cache.as_mut() {
+                // lines renumbered by an edit which hasn't been rendered yet
+                // aren't where the front-end will put them
+                for (line_num, rendered) in (start..end).zip(rendered_lines.iter()) {
+                    if self.dirty {
+                        style_cache.remove(line_num);
+                    } else {
+                        style_cache.record(line_num, rendered);
+                    }
+                }
             }
             ops.push(self.build_update_op("ins", Some(rendered_lines), end - start));
             ops.push(self.build_update_op("skip", None, end - start));
             line = end;
         }
-        if line == 0 {
+        let hints = self.update_layout_hints(text, tab_ctx, line_metadata);
+        if line == 0 && hints.is_none() {
             // Front-end already has all lines, no need to send any more.
             return;
         }
         if line < height {
             ops.push(self.build_update_op("copy", None, height - line));
         }
-        let params = json!({
+        let mut params = json!({
             "ops": ops,
             "pristine": self.pristine,
+            "rev": self.rev,
+            "metrics": self.metrics_json(text, metrics, height),
         });
+        if let Some(hints) = hints {
+            params["layout_hints"] = json!(hints);
+        }
         tab_ctx.update_view(&self.view_id, &params);
         self.valid_lines.union_one_range(first_line, last_line);
     }
 
+    /// Returns `true` if inserting `len` bytes into the line containing
+    /// `offset` can be sent to the front-end with `send_echo_update`, which
+    /// requires that the front-end has the line, and that the insertion
+    /// won't change where the line wraps.
+    pub fn can_echo_insert(&self, text: &Rope, offset: usize, len: usize) -> bool {
+        let line = self.line_of_offset(text, offset);
+        if self.dirty || self.valid_lines.minus_one_range(line, line + 1).next().is_some() {
+            return false;
+        }
+        match self.breaks {
+            Some(_) => {
+                let line_len = self.offset_of_line(text, line + 1) - self.offset_of_line(text, line);
+                line_len + len < self.wrap_col
+            }
+            None => true,
+        }
+    }
+
+    /// Sends a minimal update containing only the line containing `offset`,
+    /// which has just had `len` bytes inserted at `offset`, with the caret
+    /// placed after the insertion.
+    ///
+    /// This is sent before the rest of the edit is processed, to reduce typing
+    /// latency. It is always followed by a complete update, once the selection
+    /// and styles have been updated.
+    pub fn send_echo_update(&mut self, text: &Rope, tab_ctx: &DocumentCtx,
+                            style_spans: &Spans<Style>, metrics: &BufferMetrics,
+                            offset: usize, len: usize) {
+        self.init_line_ids(text, tab_ctx);
+        let line = self.line_of_offset(text, offset);
+        let height = self.offset_to_line_col(text, text.len()).0 + 1;
+        let line_offset = self.offset_of_line(text, line);
+        let mut line_cursor = Cursor::new(text, line_offset);
+        let mut soft_breaks = self.breaks.as_ref().map(|breaks|
+            Cursor::new(breaks, line_offset)
+        );
+        // the selection hasn't been updated for the edit, so we render
+        // without it and set the cursor directly.
+        let selection = mem::replace(&mut self.selection, Selection::new());
+        // the line's cached metadata is updated after the echo is sent
+        let core_styles = CoreStyles::for_theme(&tab_ctx.get_theme());
+        let mut rendered = self.render_line(tab_ctx, text, &mut line_cursor,
+                                            soft_breaks.as_mut(), style_spans, &core_styles,
+                                            line, None);
+        self.selection = selection;
+        rendered["cursor"] = json!([offset + len - line_offset]);
+        if let Some(style_cache) = self.style_cache.as_mut() {
+            style_cache.remove(line);
+        }
+
+        let mut ops = Vec::new();
+        if line > 0 {
+            ops.push(self.build_update_op("copy", None, line));
+        }
+        ops.push(self.build_update_op("ins", Some(vec![rendered]), 1));
+        ops.push(self.build_update_op("skip", None, 1));
+        if line + 1 < height {
+            ops.push(self.build_update_op("copy", None, height - line - 1));
+        }
+        let params = json!({
+            "ops": ops,
+            "pristine": false,
+            "rev": self.rev,
+            "metrics": self.metrics_json(text, metrics, height),
+        });
+        tab_ctx.update_view(&self.view_id, &params);
+    }
+
+    /// Sets the whitespace to be marked as pending cleanup.
+    pub fn set_pending_cleanup(&mut self, regions: Selection) {
+        if *regions != *self.pending_cleanup {
+            self.pending_cleanup = regions;
+            self.sel_dirty = true;
+        }
+    }
+
+    /// Returns the range of logical lines currently visible, including a
+    /// margin above and below.
+    pub fn visible_lines(&self, text: &Rope) -> (usize, usize) {
+        // the viewport may reach past the end of the text
+        let height = self.line_of_offset(text, text.len());
+        let first_line = min(max(self.first_line, SCROLL_SLOP) - SCROLL_SLOP, height);
+        let last_line = min(self.first_line + self.height + SCROLL_SLOP, height);
+        let first = text.line_of_offset(self.offset_of_line(text, first_line));
+        let last = text.line_of_offset(self.offset_of_line(text, last_line));
+        (first, last + 1)
+    }
+
+    /// Returns the layout hints for the viewport, if they have changed
+    /// since they were last sent. The indents of the lines are read from
+    /// `line_metadata`.
+    fn update_layout_hints(&mut self, text: &Rope, tab_ctx: &DocumentCtx,
+                           line_metadata: &mut LineMetadata) -> Option<LayoutHints> {
+        let n_lines = text.measure::<LinesMetric>() + 1;
+        let (first, last) = self.visible_lines(text);
+        let tab_size = tab_ctx.get_config().indent_style().tab_size;
+        line_metadata.set_tab_size(tab_size);
+        let widths = (first..min(last, n_lines)).map(|line| {
+            if line_metadata.is_blank(text, line) {
+                None
+            } else {
+                Some(line_metadata.indent(text, line))
+            }
+        });
+        let (foldable, max_indent_depth) = layout_hints::indentation(widths, tab_size);
+        let (start, end) = (text.offset_of_line(first), text.offset_of_line(last));
+        let has_annotations = !self.pending_cleanup.regions_in_range(start, end).is_empty() ||
+            self.occurrences.as_ref()
+                .map_or(false, |occ| !occ.regions_in_range(start, end).is_empty());
+        let prev_digits = self.layout_hints.as_ref().map(|hints| hints.line_number_digits);
+        let hints = LayoutHints {
+            line_number_digits: layout_hints::line_number_digits(n_lines, prev_digits),
+            has_annotations: has_annotations,
+            foldable: foldable,
+            max_indent_depth: max_indent_depth,
+        };
+        if self.layout_hints.as_ref() == Some(&hints) {
+            return None;
+        }
+        self.layout_hints = Some(hints.clone());
+        Some(hints)
+    }
+
+    /// Returns the layout hints last sent to the front-end.
+    pub fn layout_hints(&self) -> Option<&LayoutHints> {
+        self.layout_hints.as_ref()
+    }
+
+    /// Document-wide metrics, included in each update for the use of
+    /// scrollbars and the like. `height` is the number of visual lines.
+    fn metrics_json(&self, text: &Rope, metrics: &BufferMetrics, height: usize) -> Value {
+        json!({
+            "byte_len": text.len(),
+            "line_count": text.measure::<LinesMetric>() + 1,
+            "visual_line_count": height,
+            "longest_line": metrics.longest_line(),
+        })
+    }
+
     fn build_update_op(&self, op: &str, lines: Option<Vec<Value>>, n: usize) -> Value {
         let mut update = json!({
             "op": op,
@@ -439,11 +910,19 @@ impl View {
     }
 
     // Update front-end with any changes to view since the last time sent.
-    pub fn render_if_dirty(&mut self, text: &Rope, tab_ctx: &DocumentCtx, style_spans: &Spans<Style>) {
+    pub fn render_if_dirty(&mut self, text: &Rope, tab_ctx: &DocumentCtx, style_spans: &Spans<Style>,
+                           words: &Segmentation, metrics: &BufferMetrics,
+                           line_metadata: &mut LineMetadata) {
+        if let Some(minimap) = self.minimap.as_mut() {
+            if let Some((first, last)) = minimap.take_changed() {
+                tab_ctx.minimap_changed(&self.view_id, minimap.n_buckets(), first, last);
+            }
+        }
         if self.sel_dirty || self.hls_dirty || self.dirty {
             let first_line = max(self.first_line, SCROLL_SLOP) - SCROLL_SLOP;
             let last_line = self.first_line + self.height + SCROLL_SLOP;
-            self.send_update(text, tab_ctx, style_spans, first_line, last_line);
+            self.send_update(text, tab_ctx, style_spans, words, metrics, line_metadata,
+                             first_line, last_line);
             self.sel_dirty = false;
             self.hls_dirty = false;
             self.dirty = false;
@@ -470,6 +949,24 @@ impl View {
         (line, offset - self.offset_of_line(text, line))
     }
 
+    /// Returns the position of the caret at `offset`, for `scroll_to`. A
+    /// caret on a soft break with upstream affinity is at the end of the
+    /// visual line before it, as in `render_line`.
+    pub fn scroll_position(&self, text: &Rope, offset: usize) -> ScrollPosition {
+        let affinity = self.selection.iter().find(|region| region.end == offset)
+            .map_or(Affinity::default(), |region| region.affinity);
+        let (line, col) = self.offset_to_line_col(text, offset);
+        let on_soft_break = col == 0 && line > 0 &&
+            text.offset_of_line(text.line_of_offset(offset)) != offset;
+        let (visual_line, visual_col) = if affinity == Affinity::Upstream && on_soft_break {
+            (line - 1, offset - self.offset_of_line(text, line - 1))
+        } else {
+            (line, col)
+        };
+        ScrollPosition { line: line, col: col, visual_line: visual_line,
+                         visual_col: visual_col, affinity: affinity }
+    }
+
     pub fn line_col_to_offset(&self, text: &Rope, line: usize, col: usize) -> usize {
         let mut offset = self.offset_of_line(text, line).saturating_add(col);
         if offset >= text.len() {
@@ -517,6 +1014,11 @@ impl View {
     pub fn rewrap(&mut self, text: &Rope, wrap_col: usize) {
         self.breaks = Some(linewrap::linewrap(text, wrap_col));
         self.wrap_col = wrap_col;
+        self.accessibility.wrap_changed();
+        let n_lines = self.n_visual_lines(text);
+        if let Some(style_cache) = self.style_cache.as_mut() {
+            style_cache.clear(n_lines);
+        }
     }
 
     /// Updates the view after the text has been modified by the given `delta`.
@@ -524,13 +1026,46 @@ impl View {
     /// recomputing line wraps.
     ///
     /// Return value is a location of a point that should be scrolled into view.
-    pub fn after_edit(&mut self, text: &Rope, delta: &Delta<RopeInfo>, pristine: bool)
+    pub fn after_edit(&mut self, text: &Rope, words: &Segmentation, delta: &Delta<RopeInfo>,
+                      pristine: bool)
         -> Option<usize>
     {
+        if !self.visible && self.breaks.is_some() {
+            // until it is shown again, the view is unwrapped rather than
+            // rewrapped after each edit
+            self.breaks = None;
+            self.wrap_stale = true;
+            let n_lines = self.n_visual_lines(text);
+            if let Some(style_cache) = self.style_cache.as_mut() {
+                style_cache.clear(n_lines);
+            }
+        }
         if let Some(breaks) = self.breaks.as_mut() {
             let (iv, new_len) = delta.summary();
             linewrap::rewrap(breaks, text, iv, new_len, self.wrap_col);
         }
+        if self.style_cache.is_some() {
+            let (iv, new_len) = delta.summary();
+            // the edited logical lines are those the rewrap may have changed
+            let first = self.line_of_offset(text,
+                text.offset_of_line(text.line_of_offset(iv.start())));
+            let next = text.line_of_offset(iv.start() + new_len) + 1;
+            let n_lines = self.n_visual_lines(text);
+            let tail = if next > text.measure::<LinesMetric>() {
+                n_lines
+            } else {
+                self.line_of_offset(text, text.offset_of_line(next))
+            };
+            self.style_cache.as_mut().unwrap().after_edit(first, tail, n_lines);
+        }
+        if let Some(line_ids) = self.line_ids.as_mut() {
+            line_ids.after_edit(text, delta);
+        }
+        self.jump_list.after_edit(delta);
+        if let Some(minimap) = self.minimap.as_mut() {
+            minimap.after_edit(text, delta);
+        }
+        self.accessibility.after_edit(delta);
         self.pristine = pristine;
         self.dirty = true;
         // Any edit cancels a drag. This is good behavior for edits initiated through
@@ -560,7 +1095,7 @@ impl View {
 
             // update find for the whole delta (is going to only update invalid regions)
             let (iv, _) = delta.summary();
-            self.update_find(text, iv.start(), iv.end(), true, false);
+            self.update_find(text, words, iv.start(), iv.end(), true, false);
         }
 
         // Note: for committing plugin edits, we probably want to know the priority
@@ -569,11 +1104,136 @@ impl View {
         self.set_selection(text, new_sel)
     }
 
+    /// Returns the number of visual lines.
+    fn n_visual_lines(&self, text: &Rope) -> usize {
+        self.line_of_offset(text, text.len()) + 1
+    }
+
+    /// Returns the start and end offsets of visual line `line`, and its
+    /// plain text, with its coordinates, or `None` if there is no such line.
+    fn accessibility_text(&self, text: &Rope, line: usize) -> Option<(usize, usize, Value)> {
+        let n_lines = self.n_visual_lines(text);
+        if line >= n_lines {
+            return None;
+        }
+        let start = self.offset_of_line(text, line);
+        let end = if line + 1 < n_lines {
+            self.offset_of_line(text, line + 1)
+        } else {
+            text.len()
+        };
+        let content = text.slice_to_string(start, end);
+        let logical_line = text.line_of_offset(start);
+        let description = json!({
+            "visual_line": line,
+            "logical_line": logical_line,
+            "col": start - text.offset_of_line(logical_line),
+            "text": content.trim_end_matches(|c| c == '\n' || c == '\r'),
+        });
+        Some((start, end, description))
+    }
+
+    /// Describes visual line `line` for accessibility clients: its plain
+    /// text, its logical line and the column at which it starts there, and
+    /// the caret and the selected ranges within it. Returns `None` if
+    /// there is no such line.
+    pub fn accessibility_line(&self, text: &Rope, line: usize) -> Option<Value> {
+        let (start, end, mut description) = self.accessibility_text(text, line)?;
+        let len = description["text"].as_str().unwrap().len();
+        // a caret at the end of a wrapped line is at the start of the next
+        let last_line = line + 1 == self.n_visual_lines(text);
+        let caret = self.selection.last()
+            .map(|region| region.end)
+            .filter(|&offset| start <= offset && (offset < end || last_line))
+            .map(|offset| min(offset - start, len));
+        let selections = self.selection.iter()
+            .filter(|region| !region.is_caret() && region.min() < start + len &&
+                    region.max() > start)
+            .map(|region| {
+                (max(region.min(), start) - start, min(region.max(), start + len) - start)
+            })
+            .collect::<Vec<_>>();
+        description["caret"] = json!(caret);
+        description["selections"] = json!(selections);
+        description["token"] = json!(self.accessibility.token());
+        Some(description)
+    }
+
+    /// Describes the visual lines which changed since `since_token` was
+    /// given out, for accessibility clients, with the current token. If
+    /// the changes are no longer known, `reset` is true, and the client
+    /// should read the lines it needs again.
+    pub fn accessibility_delta(&self, text: &Rope, since_token: u64) -> Value {
+        let mut result = json!({
+            "token": self.accessibility.token(),
+            "n_visual_lines": self.n_visual_lines(text),
+            "n_logical_lines": text.measure::<LinesMetric>() + 1,
+            "caret_line": self.line_of_offset(text, self.selection.last().unwrap().end),
+        });
+        let changes = match self.accessibility.since(since_token) {
+            Some(changes) => changes,
+            None => {
+                result["reset"] = json!(true);
+                return result;
+            }
+        };
+        let mut lines = Vec::new();
+        let mut truncated = false;
+        // the first line not yet described, as ranges may share lines
+        let mut next_line = 0;
+        'ranges: for iv in changes.ranges {
+            let first = max(self.line_of_offset(text, iv.start()), next_line);
+            let last = self.line_of_offset(text, iv.end());
+            for line in first..last + 1 {
+                if lines.len() == MAX_ACCESSIBILITY_LINES {
+                    truncated = true;
+                    break 'ranges;
+                }
+                lines.extend(self.accessibility_text(text, line).map(|(_, _, desc)| desc));
+            }
+            next_line = last + 1;
+        }
+        result["reset"] = json!(false);
+        result["lines"] = json!(lines);
+        result["truncated"] = json!(truncated);
+        result["selection_changed"] = json!(changes.selection_changed);
+        result["wrap_changed"] = json!(changes.wrap_changed);
+        result
+    }
+
+    /// Returns the minimap summaries of buckets `first..last`, with
+    /// `lines_per_bucket` lines each, or more if the text is long.
+    pub fn get_minimap(&mut self, text: &Rope, tab_ctx: &DocumentCtx,
+                       style_spans: &Spans<Style>, lines_per_bucket: usize,
+                       first: usize, last: usize) -> Value {
+        if self.minimap.as_ref().map(|m| m.requested() != lines_per_bucket).unwrap_or(true) {
+            self.minimap = Some(Minimap::new(text, lines_per_bucket));
+        }
+        let minimap = self.minimap.as_mut().unwrap();
+        // the front-end is up to date once it has asked
+        minimap.take_changed();
+        minimap.get(text, style_spans, |style| tab_ctx.get_style_id(style), first, last)
+    }
+
+    /// Notes that the styles within `iv` have changed.
+    pub fn styles_changed(&mut self, text: &Rope, iv: Interval) {
+        self.dirty = true;
+        if let Some(minimap) = self.minimap.as_mut() {
+            minimap.styles_changed(text, iv);
+        }
+    }
+
     /// Call to mark view as pristine. Used after a buffer is saved.
     pub fn set_pristine(&mut self) {
         self.pristine = true;
     }
 
+    /// Call to mark view as having unsaved changes. Used when a view is
+    /// added onto a modified buffer.
+    pub fn set_modified(&mut self) {
+        self.pristine = false;
+    }
+
     /// Unsets the search and removes all highlights from the view.
     pub fn unset_find(&mut self) {
         self.search_string = None;
@@ -584,7 +1244,7 @@ impl View {
 
     /// Sets find for the view, highlights occurrences in the current viewport and selects the first
     /// occurrence relative to the last cursor.
-    pub fn set_find(&mut self, search_string: &str, case_sensitive: bool) {
+    pub fn set_find(&mut self, search_string: &str, case_sensitive: bool, whole_words: bool) {
         let case_matching = if case_sensitive {
             CaseMatching::Exact
         } else {
@@ -592,7 +1252,8 @@ impl View {
         };
 
         if let Some(ref s) = self.search_string {
-            if s == search_string && case_matching == self.case_matching {
+            if s == search_string && case_matching == self.case_matching &&
+                whole_words == self.whole_words {
                 // search parameters did not change
                 return;
             }
@@ -602,19 +1263,24 @@ impl View {
 
         self.search_string = Some(search_string.to_string());
         self.case_matching = case_matching;
+        self.whole_words = whole_words;
     }
 
-    fn update_find_for_lines(&mut self, text: &Rope, first_line: usize, last_line: usize) {
+    fn update_find_for_lines(&mut self, text: &Rope, words: &Segmentation, first_line: usize,
+                             last_line: usize) {
         if self.search_string.is_none() {
             return;
         }
         let start = self.offset_of_line(text, first_line);
         let end = self.offset_of_line(text, last_line);
-        self.update_find(text, start, end, true, false);
+        self.update_find(text, words, start, end, true, false);
     }
 
-    fn update_find(&mut self, text: &Rope, start: usize, end: usize, include_slop: bool,
-                   stop_on_found: bool)
+    /// Finds the occurrences from `start` to `end` which haven't been found
+    /// yet. If the search is for whole words, occurrences which start or end
+    /// inside a word, as divided by `words`, are skipped.
+    fn update_find(&mut self, text: &Rope, words: &Segmentation, start: usize, end: usize,
+                   include_slop: bool, stop_on_found: bool)
     {
         if self.search_string.is_none() {
             return;
@@ -636,13 +1302,20 @@ impl View {
             let from = max(start, slop) - slop;
             let to = min(end + slop, text.len());
 
-            let text = text.subseq(Interval::new_closed_open(0, to));
-            let mut cursor = Cursor::new(&text, from);
+            let searched = text.subseq(Interval::new_closed_open(0, to));
+            let mut cursor = Cursor::new(&searched, from);
 
             loop {
                 match find(&mut cursor, self.case_matching, &search_string) {
                     Some(start) => {
                         let end = start + len;
+                        if self.whole_words && !(
+                            WordCursor::with_segmentation(text, start, words).is_boundary() &&
+                            WordCursor::with_segmentation(text, end, words).is_boundary()) {
+                            cursor.set(start);
+                            cursor.next_codepoint();
+                            continue;
+                        }
 
                         let region = SelRegion {
                             start: start,
@@ -698,7 +1371,7 @@ impl View {
             }
 
             // continue with the find for the current region
-            self.update_find(text, invalidate_from, end, false, false);
+            self.update_find(text, words, invalidate_from, end, false, false);
         } else {
             self.valid_search.union_one_range(start, searched_until);
             self.hls_dirty = true;
@@ -711,8 +1384,9 @@ impl View {
     /// determines whether the search should stop at the first found occurrence (does only apply
     /// to forward search, i.e. reverse = false). If `allow_same` is set to `true` the current
     /// selection is considered a valid next occurrence.
-    pub fn select_next_occurrence(&mut self, text: &Rope, reverse: bool, wrapped: bool,
-                                  stop_on_found: bool, allow_same: bool) -> Option<usize>
+    pub fn select_next_occurrence(&mut self, text: &Rope, words: &Segmentation, reverse: bool,
+                                  wrapped: bool, stop_on_found: bool, allow_same: bool)
+                                  -> Option<usize>
     {
         if self.search_string.is_none() {
             return None;
@@ -767,7 +1441,7 @@ impl View {
                 }
 
                 if !reverse {
-                    self.update_find(text, b, e, false, stop_on_found);
+                    self.update_find(text, words, b, e, false, stop_on_found);
                 } else {
                     // when searching backward, the actual search isn't executed backwards, which is
                     // why the search is executed in chunks
@@ -776,7 +1450,7 @@ impl View {
                     } else {
                         b
                     };
-                    self.update_find(text, start, e, false, false);
+                    self.update_find(text, words, start, e, false, false);
                 }
             } else {
                 break;
@@ -803,3 +1477,147 @@ fn clamp(x: usize, min: usize, max: usize) -> usize {
         max
     }
 }
+
+/// Snaps `offset` out to the edge of the word or line containing it, so
+/// that a region dragged or extended by words or lines covers whole ones.
+fn snap_to_granularity(text: &Rope, words: &Segmentation, offset: usize,
+                       granularity: Granularity, forward: bool) -> usize {
+    match granularity {
+        Granularity::Point => offset,
+        Granularity::Word => {
+            let (start, end) = WordCursor::with_segmentation(text, offset, words)
+                .select_word();
+            if forward { end } else { start }
+        }
+        Granularity::Line => {
+            let line = text.line_of_offset(offset);
+            if !forward {
+                text.offset_of_line(line)
+            } else if line >= text.measure::<LinesMetric>() {
+                text.len()
+            } else {
+                text.offset_of_line(line + 1)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caret(text: &Rope, view: &mut View, offset: usize, affinity: Affinity) -> ScrollPosition {
+        let mut sel = Selection::new();
+        sel.add_region(SelRegion { start: offset, end: offset, horiz: None, affinity: affinity });
+        view.set_selection(text, sel);
+        view.scroll_position(text, offset)
+    }
+
+    #[test]
+    fn scroll_position_wrapped() {
+        let text = Rope::from("aaaa bbbb cccc\ndd");
+        let mut view = View::new(&ViewIdentifier::from("view-id-scroll-position"));
+        view.rewrap(&text, 5);
+        // on the soft break after "aaaa "
+        let downstream = caret(&text, &mut view, 5, Affinity::Downstream);
+        assert_eq!((downstream.visual_line, downstream.visual_col), (1, 0));
+        let upstream = caret(&text, &mut view, 5, Affinity::Upstream);
+        assert_eq!((upstream.line, upstream.col), (1, 0));
+        assert_eq!((upstream.visual_line, upstream.visual_col), (0, 5));
+        assert_eq!(upstream.affinity, Affinity::Upstream);
+
+        // a hard break has no line before it to be drawn on
+        let hard = caret(&text, &mut view, 15, Affinity::Upstream);
+        assert_eq!((hard.visual_line, hard.visual_col), (3, 0));
+        // the end of the last line
+        let last = caret(&text, &mut view, text.len(), Affinity::Downstream);
+        assert_eq!((last.visual_line, last.visual_col), (3, 2));
+    }
+
+    fn regions(view: &View) -> Vec<(usize, usize)> {
+        view.sel_regions().iter().map(|r| (r.start, r.end)).collect()
+    }
+
+    fn select(text: &Rope, view: &mut View, regions: &[(usize, usize)]) {
+        let mut sel = Selection::new();
+        for &(start, end) in regions {
+            sel.add_region(SelRegion { start, end, horiz: None, affinity: Affinity::default() });
+        }
+        view.set_selection(text, sel);
+    }
+
+    #[test]
+    fn select_extend() {
+        let text = Rope::from("one two three\nfour five\nsix");
+        let words = Segmentation::new();
+        let mut view = View::new(&ViewIdentifier::from("view-id-select-extend"));
+        select(&text, &mut view, &[(4, 4)]);
+        view.select_extend(&text, &words, 9);
+        assert_eq!(regions(&view), vec![(4, 9)]);
+        // the anchor stays put when extending back past it
+        view.select_extend(&text, &words, 2);
+        assert_eq!(regions(&view), vec![(4, 2)]);
+
+        // only the last region is extended
+        select(&text, &mut view, &[(0, 0), (8, 8)]);
+        view.select_extend(&text, &words, 12);
+        assert_eq!(regions(&view), vec![(0, 0), (8, 12)]);
+
+        // after a double click, by words, and dragging carries on from there
+        select(&text, &mut view, &[(4, 7)]);
+        view.start_drag(5, 4, 7, Granularity::Word);
+        view.select_extend(&text, &words, 9);
+        assert_eq!(regions(&view), vec![(4, 13)]);
+        view.select_extend(&text, &words, 1);
+        assert_eq!(regions(&view), vec![(7, 0)]);
+        view.do_drag(&text, &words, 15, Affinity::default());
+        assert_eq!(regions(&view), vec![(4, 18)]);
+
+        // after a triple click, by lines
+        select(&text, &mut view, &[(0, 14)]);
+        view.start_drag(2, 0, 14, Granularity::Line);
+        view.select_extend(&text, &words, 16);
+        assert_eq!(regions(&view), vec![(0, 24)]);
+        view.select_extend(&text, &words, 25);
+        assert_eq!(regions(&view), vec![(0, 27)]);
+
+        // moving the selection forgets the granularity
+        view.do_move(&text, &words, Movement::Left, false);
+        view.select_extend(&text, &words, 2);
+        assert_eq!(regions(&view), vec![(0, 2)]);
+    }
+
+    #[test]
+    fn select_add_region() {
+        let text = Rope::from("one two three\nfour five\nsix");
+        let words = Segmentation::new();
+        let mut view = View::new(&ViewIdentifier::from("view-id-select-add-region"));
+        select(&text, &mut view, &[(0, 0)]);
+        view.select_add_region(&text, 8);
+        assert_eq!(regions(&view), vec![(0, 0), (8, 8)]);
+        // dragging selects from the new caret, keeping the others
+        view.do_drag(&text, &words, 13, Affinity::default());
+        assert_eq!(regions(&view), vec![(0, 0), (8, 13)]);
+
+        select(&text, &mut view, &[(0, 3), (8, 13)]);
+        view.select_add_region(&text, 18);
+        assert_eq!(regions(&view), vec![(0, 3), (8, 13), (18, 18)]);
+        view.select_extend(&text, &words, 16);
+        assert_eq!(regions(&view), vec![(0, 3), (8, 13), (18, 16)]);
+    }
+
+    #[test]
+    fn select_toggle_region() {
+        let text = Rope::from("one two three\nfour five\nsix");
+        let mut view = View::new(&ViewIdentifier::from("view-id-select-toggle-region"));
+        select(&text, &mut view, &[(0, 0), (4, 7)]);
+        view.toggle_sel(5);
+        assert_eq!(regions(&view), vec![(0, 0)]);
+        view.toggle_sel(9);
+        assert_eq!(regions(&view), vec![(0, 0), (9, 9)]);
+        // removing the only region leaves a caret in its place
+        select(&text, &mut view, &[(4, 7)]);
+        view.toggle_sel(5);
+        assert_eq!(regions(&view), vec![(5, 5)]);
+    }
+}
@@ -14,12 +14,49 @@
 
 //! Segmentation of word boundaries. Note: this current implementation
 //! is intended to work for code. Future work is to make it Unicode aware.
+//!
+//! Chinese and Japanese text isn't separated by spaces, so runs of CJK
+//! characters are split into words by a `Segmenter`, if one is available.
 
-use xi_rope::rope::{Rope, RopeInfo};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use xi_rope::rope::{LinesMetric, Rope, RopeInfo};
 use xi_rope::tree::Cursor;
 
+/// The maximum number of lines whose segmentation is cached.
+const MAX_CACHED_LINES: usize = 4096;
+
 pub struct WordCursor<'a> {
     inner: Cursor<'a, RopeInfo>,
+    segmentation: Option<(&'a Rope, &'a Segmentation)>,
+}
+
+/// Finds the boundaries between words in runs of CJK text.
+pub trait Segmenter: Send {
+    /// Returns the offsets of the word boundaries within `run`, excluding
+    /// `0` and `run.len()`, or `None` if they aren't known.
+    fn segment(&self, run: &str) -> Option<Vec<usize>>;
+}
+
+/// Word boundaries within the runs of CJK text of a buffer, cached by
+/// line contents.
+pub struct Segmentation {
+    segmenter: Option<Box<Segmenter>>,
+    fallback: Option<DictionarySegmenter>,
+    /// Boundaries relative to the start of the line, keyed by the hash of
+    /// the line's text.
+    cache: RefCell<HashMap<u64, Vec<usize>>>,
+}
+
+/// Segments text by greedily matching the longest word in a dictionary,
+/// falling back to single characters.
+pub struct DictionarySegmenter {
+    words: HashSet<String>,
+    /// The length of the longest word, in chars.
+    max_len: usize,
 }
 
 impl<'a> WordCursor<'a> {
@@ -27,11 +64,107 @@ impl<'a> WordCursor<'a> {
         let inner = Cursor::new(text, pos);
         WordCursor {
             inner: inner,
+            segmentation: None,
+        }
+    }
+
+    /// Creates a cursor which also finds the boundaries between words in
+    /// runs of CJK text.
+    pub fn with_segmentation(text: &'a Rope, pos: usize, segmentation: &'a Segmentation)
+                             -> WordCursor<'a> {
+        WordCursor {
+            inner: Cursor::new(text, pos),
+            segmentation: Some((text, segmentation)),
+        }
+    }
+
+    /// Returns the CJK word boundaries from `start` to `end`, inclusive.
+    fn cjk_boundaries(&self, start: usize, end: usize) -> Vec<usize> {
+        match self.segmentation {
+            Some((text, segmentation)) => segmentation.boundaries(text, start, end),
+            None => Vec::new(),
         }
     }
 
+    /// Returns `true` if the boundary at `offset` starts a word, or, if
+    /// `before` is set, ends one.
+    fn is_word_edge(&self, offset: usize, before: bool) -> bool {
+        self.segmentation.map_or(false, |(text, _)| {
+            let mut cursor = Cursor::new(text, offset);
+            let c = if before { cursor.prev_codepoint() } else { cursor.next_codepoint() };
+            match c.map(get_word_property) {
+                Some(WordProperty::Other) => true,
+                _ => false,
+            }
+        })
+    }
+
     /// Get previous boundary, and set the cursor at the boundary found.
     pub fn prev_boundary(&mut self) -> Option<usize> {
+        let initial = self.inner.pos();
+        let prev = self.prev_boundary_inner();
+        if let Some(prev) = prev {
+            if let Some(&cjk) = self.cjk_boundaries(prev, initial).iter().rev()
+                .find(|&&b| b > prev && b < initial && self.is_word_edge(b, false)) {
+                self.inner.set(cjk);
+                return Some(cjk);
+            }
+        }
+        prev
+    }
+
+    /// Get next boundary, and set the cursor at the boundary found.
+    pub fn next_boundary(&mut self) -> Option<usize> {
+        let initial = self.inner.pos();
+        let next = self.next_boundary_inner();
+        if let Some(next) = next {
+            if let Some(&cjk) = self.cjk_boundaries(initial, next).iter()
+                .find(|&&b| b > initial && b < next && self.is_word_edge(b, true)) {
+                self.inner.set(cjk);
+                return Some(cjk);
+            }
+        }
+        next
+    }
+
+    /// Returns `true` if the cursor isn't inside a word: it is next to a
+    /// space or punctuation, or between two words of a run of CJK text.
+    pub fn is_boundary(&mut self) -> bool {
+        let pos = self.inner.pos();
+        let after = self.inner.next_codepoint().map(get_word_property);
+        self.inner.set(pos);
+        let before = self.inner.prev_codepoint().map(get_word_property);
+        self.inner.set(pos);
+        match (before, after) {
+            (Some(WordProperty::Other), Some(WordProperty::Other)) =>
+                self.cjk_boundaries(pos, pos).contains(&pos),
+            _ => true,
+        }
+    }
+
+    /// Return the selection for the word containing the current cursor. The
+    /// cursor is moved to the end of that selection.
+    pub fn select_word(&mut self) -> (usize, usize) {
+        let initial = self.inner.pos();
+        let (start, end) = self.select_word_inner();
+        let boundaries = self.cjk_boundaries(start, end);
+        if boundaries.is_empty() {
+            return (start, end);
+        }
+        let word_end = boundaries.iter().cloned()
+            .find(|&b| b > initial && b < end).unwrap_or(end);
+        let mut word_start = boundaries.iter().cloned().rev()
+            .find(|&b| b <= initial && b > start).unwrap_or(start);
+        if word_start == word_end {
+            // at the end of a word; select the word before
+            word_start = boundaries.iter().cloned().rev()
+                .find(|&b| b < initial && b > start).unwrap_or(start);
+        }
+        self.inner.set(word_end);
+        (word_start, word_end)
+    }
+
+    fn prev_boundary_inner(&mut self) -> Option<usize> {
         if let Some(ch) = self.inner.prev_codepoint() {
             let mut prop = get_word_property(ch);
             let mut candidate = self.inner.pos();
@@ -49,8 +182,7 @@ impl<'a> WordCursor<'a> {
         None
     }
 
-    /// Get next boundary, and set the cursor at the boundary found.
-    pub fn next_boundary(&mut self) -> Option<usize> {
+    fn next_boundary_inner(&mut self) -> Option<usize> {
         if let Some(ch) = self.inner.next_codepoint() {
             let mut prop = get_word_property(ch);
             let mut candidate = self.inner.pos();
@@ -68,9 +200,7 @@ impl<'a> WordCursor<'a> {
         None
     }
 
-    /// Return the selection for the word containing the current cursor. The
-    /// cursor is moved to the end of that selection.
-    pub fn select_word(&mut self) -> (usize, usize) {
+    fn select_word_inner(&mut self) -> (usize, usize) {
         let initial = self.inner.pos();
         let init_prop_after = self.inner.next_codepoint().map(get_word_property);
         self.inner.set(initial);
@@ -134,6 +264,149 @@ impl<'a> WordCursor<'a> {
     }
 }
 
+impl Segmentation {
+    pub fn new() -> Self {
+        Segmentation {
+            segmenter: None,
+            fallback: DictionarySegmenter::bundled(),
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the segmenter, which takes precedence over any builtin one.
+    pub fn set_segmenter(&mut self, segmenter: Option<Box<Segmenter>>) {
+        self.segmenter = segmenter;
+        self.cache.borrow_mut().clear();
+    }
+
+    /// Returns the word boundaries within and around runs of CJK text, from
+    /// `start` to `end` inclusive.
+    pub fn boundaries(&self, text: &Rope, start: usize, end: usize) -> Vec<usize> {
+        if self.segmenter.is_none() && self.fallback.is_none() {
+            return Vec::new();
+        }
+        let mut result = Vec::new();
+        for line in text.line_of_offset(start)..text.line_of_offset(end) + 1 {
+            let line_start = text.offset_of_line(line);
+            let line_end = if line < text.measure::<LinesMetric>() {
+                text.offset_of_line(line + 1)
+            } else {
+                text.len()
+            };
+            let line_text = text.slice_to_string(line_start, line_end);
+            let boundaries = self.line_boundaries(&line_text);
+            result.extend(boundaries.iter().map(|b| b + line_start)
+                          .filter(|&b| b >= start && b <= end));
+        }
+        result
+    }
+
+    fn line_boundaries(&self, line: &str) -> Vec<usize> {
+        let mut hasher = DefaultHasher::new();
+        line.hash(&mut hasher);
+        let hash = hasher.finish();
+        if let Some(boundaries) = self.cache.borrow().get(&hash) {
+            return boundaries.clone();
+        }
+
+        let mut boundaries = Vec::new();
+        let mut complete = true;
+        let mut run_start = None;
+        let ends = line.char_indices().map(|(ix, c)| (ix, Some(c)))
+            .chain(Some((line.len(), None)));
+        for (ix, c) in ends {
+            match (run_start, c.map_or(false, is_cjk)) {
+                (None, true) => run_start = Some(ix),
+                (Some(start), false) => {
+                    match self.segment(&line[start..ix]) {
+                        Some(within) => {
+                            boundaries.push(start);
+                            boundaries.extend(within.iter().map(|b| b + start));
+                            boundaries.push(ix);
+                        }
+                        None => complete = false,
+                    }
+                    run_start = None;
+                }
+                _ => (),
+            }
+        }
+
+        // results which may change, such as those awaiting a plugin, aren't cached
+        if complete {
+            let mut cache = self.cache.borrow_mut();
+            if cache.len() >= MAX_CACHED_LINES {
+                cache.clear();
+            }
+            cache.insert(hash, boundaries.clone());
+        }
+        boundaries
+    }
+
+    fn segment(&self, run: &str) -> Option<Vec<usize>> {
+        self.segmenter.as_ref().and_then(|s| s.segment(run))
+            .or_else(|| self.fallback.as_ref().and_then(|s| s.segment(run)))
+    }
+}
+
+impl DictionarySegmenter {
+    pub fn new<S: AsRef<str>>(words: &[S]) -> Self {
+        let words = words.iter()
+            .map(|w| w.as_ref().to_owned())
+            .collect::<HashSet<_>>();
+        let max_len = words.iter().map(|w| w.chars().count()).max().unwrap_or(0);
+        DictionarySegmenter { words: words, max_len: max_len }
+    }
+
+    /// Returns a segmenter using the bundled dictionary of common words.
+    #[cfg(feature = "cjk-dictionary")]
+    pub fn bundled() -> Option<Self> {
+        let dict = include_str!("../assets/cjk_words.txt");
+        let words = dict.lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .collect::<Vec<_>>();
+        Some(DictionarySegmenter::new(&words))
+    }
+
+    #[cfg(not(feature = "cjk-dictionary"))]
+    pub fn bundled() -> Option<Self> {
+        None
+    }
+}
+
+impl Segmenter for DictionarySegmenter {
+    fn segment(&self, run: &str) -> Option<Vec<usize>> {
+        let offsets = run.char_indices().map(|(ix, _)| ix)
+            .chain(Some(run.len()))
+            .collect::<Vec<_>>();
+        let mut boundaries = Vec::new();
+        let mut i = 0;
+        while i + 1 < offsets.len() {
+            let longest = (2..self.max_len + 1).rev()
+                .filter(|&n| i + n < offsets.len())
+                .find(|&n| self.words.contains(&run[offsets[i]..offsets[i + n]]))
+                .unwrap_or(1);
+            i += longest;
+            if i + 1 < offsets.len() {
+                boundaries.push(offsets[i]);
+            }
+        }
+        Some(boundaries)
+    }
+}
+
+/// Returns `true` if `c` is a Chinese or Japanese character, in text which
+/// isn't separated into words by spaces.
+fn is_cjk(c: char) -> bool {
+    let c = c as u32;
+    (c >= 0x3040 && c <= 0x30ff) ||  // hiragana, katakana
+        (c >= 0x31f0 && c <= 0x31ff) ||  // katakana phonetic extensions
+        (c >= 0x3400 && c <= 0x4dbf) ||  // CJK unified ideographs extension A
+        (c >= 0x4e00 && c <= 0x9fff) ||  // CJK unified ideographs
+        (c >= 0xf900 && c <= 0xfaff) ||  // CJK compatibility ideographs
+        (c >= 0x20000 && c <= 0x2fa1f)  // supplementary ideographs
+}
+
 #[derive(PartialEq, Eq)]
 enum WordBoundary {
     Interior,
@@ -215,4 +488,130 @@ fn get_word_property(codepoint: char) -> WordProperty {
         }
     }
     WordProperty::Other
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::*;
+
+    fn segmentation(words: &[&str]) -> Segmentation {
+        let mut segmentation = Segmentation::new();
+        segmentation.set_segmenter(Some(Box::new(DictionarySegmenter::new(words))));
+        segmentation
+    }
+
+    fn next_boundaries(text: &Rope, segmentation: &Segmentation) -> Vec<usize> {
+        let mut cursor = WordCursor::with_segmentation(text, 0, segmentation);
+        let mut result = Vec::new();
+        while let Some(b) = cursor.next_boundary() {
+            result.push(b);
+        }
+        result
+    }
+
+    fn prev_boundaries(text: &Rope, segmentation: &Segmentation) -> Vec<usize> {
+        let mut cursor = WordCursor::with_segmentation(text, text.len(), segmentation);
+        let mut result = Vec::new();
+        while let Some(b) = cursor.prev_boundary() {
+            result.push(b);
+            if b == 0 {
+                break;
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn greedy_longest_match() {
+        let segmenter = DictionarySegmenter::new(&["北京", "北京大学", "大学", "学生"]);
+        // 北京大学 is preferred to 北京
+        assert_eq!(segmenter.segment("北京大学生").unwrap(), vec![12]);
+        assert_eq!(segmenter.segment("我在北京").unwrap(), vec![3, 6]);
+        assert_eq!(segmenter.segment("我").unwrap(), Vec::<usize>::new());
+    }
+
+    #[cfg(feature = "cjk-dictionary")]
+    #[test]
+    fn bundled_dictionary() {
+        let segmenter = DictionarySegmenter::bundled().unwrap();
+        assert_eq!(segmenter.segment("我们喜欢编辑器").unwrap(), vec![6, 12]);
+    }
+
+    #[test]
+    fn mixed_movement() {
+        let text = Rope::from("hello 我爱北京天安门 world");
+        let words = segmentation(&["北京", "天安门"]);
+        assert_eq!(next_boundaries(&text, &words), vec![5, 9, 12, 18, 27, 33]);
+        assert_eq!(prev_boundaries(&text, &words), vec![28, 18, 12, 9, 6, 0]);
+
+        // words adjoining latin text
+        let text = Rope::from("用Rust写的, ok");
+        let words = segmentation(&[]);
+        assert_eq!(next_boundaries(&text, &words), vec![3, 7, 10, 13, 14, 17]);
+    }
+
+    #[test]
+    fn mixed_select_word() {
+        let text = Rope::from("xi 编辑器是用Rust写的。");
+        let words = segmentation(&["编辑器"]);
+        let select = |offset| {
+            WordCursor::with_segmentation(&text, offset, &words).select_word()
+        };
+        assert_eq!(select(1), (0, 2));
+        assert_eq!(select(6), (3, 12));
+        assert_eq!(select(12), (12, 15));
+        assert_eq!(select(20), (18, 22));
+        // CJK punctuation is a separate word
+        assert_eq!(select(28), (28, 31));
+
+        // at the end of the text, the word before is selected
+        let text = Rope::from("我爱北京");
+        let words = segmentation(&["北京"]);
+        let mut cursor = WordCursor::with_segmentation(&text, text.len(), &words);
+        assert_eq!(cursor.select_word(), (6, 12));
+    }
+
+    #[test]
+    fn word_edges() {
+        let text = Rope::from("我爱北京 hello, xi");
+        let words = segmentation(&["北京"]);
+        let edges = (0..text.len() + 1)
+            .filter(|&offset| text.is_codepoint_boundary(offset))
+            .filter(|&offset| WordCursor::with_segmentation(&text, offset, &words).is_boundary())
+            .collect::<Vec<_>>();
+        assert_eq!(edges, vec![0, 3, 6, 12, 13, 18, 19, 20, 22]);
+        // without a segmenter, the run of CJK text is one word
+        assert!(!WordCursor::new(&text, 6).is_boundary());
+    }
+
+    #[test]
+    fn fallback() {
+        let text = Rope::from("hello 我爱北京 world");
+        let mut cursor = WordCursor::new(&text, 5);
+        assert_eq!(cursor.next_boundary(), Some(18));
+        assert_eq!(WordCursor::new(&text, 9).select_word(), (6, 18));
+    }
+
+    struct CountingSegmenter(Arc<AtomicUsize>);
+
+    impl Segmenter for CountingSegmenter {
+        fn segment(&self, _run: &str) -> Option<Vec<usize>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Some(Vec::new())
+        }
+    }
+
+    #[test]
+    fn cached_by_line() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut words = Segmentation::new();
+        words.set_segmenter(Some(Box::new(CountingSegmenter(count.clone()))));
+        let text = Rope::from("我爱北京\nabc 天安门\n我爱北京\n");
+        words.boundaries(&text, 0, text.len());
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        words.boundaries(&text, 0, text.len());
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+}
@@ -18,10 +18,14 @@ extern crate serde_json;
 extern crate xi_rpc;
 extern crate xi_core_lib;
 
-use std::io;
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::time::Duration;
 
-use xi_rpc::{RpcLoop, ReadError};
-use xi_rpc::test_utils::{make_reader, test_channel};
+use serde_json::Value;
+use xi_rpc::{RpcLoop, ReadError, RemoteError};
+use xi_rpc::test_utils::{make_reader, test_channel, DummyReader};
 use xi_core_lib::MainState;
 
 #[test]
@@ -156,6 +160,872 @@ fn test_other_edit_commands() {
     assert!(rpc_looper.mainloop(|| json, &mut state).is_ok());
 }
 
+/// Applies the ops of an `update` notification to a front-end line cache.
+fn apply_update(cache: &mut Vec<Option<Value>>, update: &Value) {
+    let mut new_cache = Vec::new();
+    let mut old_ix = 0;
+    for op in update["ops"].as_array().unwrap() {
+        let n = op["n"].as_u64().unwrap() as usize;
+        match op["op"].as_str().unwrap() {
+            "copy" => {
+                for i in old_ix..old_ix + n {
+                    new_cache.push(cache.get(i).cloned().unwrap_or(None));
+                }
+                old_ix += n;
+            }
+            "skip" => old_ix += n,
+            "invalidate" => new_cache.extend((0..n).map(|_| None)),
+            "ins" => {
+                for line in op["lines"].as_array().unwrap() {
+                    new_cache.push(Some(line.clone()));
+                }
+            }
+            other => panic!("unexpected op {}", other),
+        }
+    }
+    *cache = new_cache;
+}
+
+/// Reads all pending messages, applying any updates to `cache`. Returns
+/// the number of updates received.
+fn drain_updates(rx: &mut DummyReader, cache: &mut Vec<Option<Value>>) -> usize {
+    let mut n_updates = 0;
+    while let Some(msg) = rx.next_timeout(Duration::from_millis(100)) {
+        let msg = msg.unwrap();
+        if msg.get_method() == Some("update") {
+            apply_update(cache, &msg.get_params().unwrap()["update"]);
+            n_updates += 1;
+        }
+    }
+    n_updates
+}
+
+/// Runs `rpcs` against a new view, returning the front-end's final
+/// line cache and the number of updates it received.
+fn run_edits(rpcs: &str) -> (Vec<Option<Value>>, usize) {
+    let mut state = MainState::new();
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let json = make_reader(r#"{"method":"client_started","params":{}}
+{"id":0,"method":"new_view","params":{}}
+{"method":"edit","params":{"view_id":"view-id-1","method":"insert","params":{"chars":"hello\nworld"}}}"#);
+    assert!(rpc_looper.mainloop(|| json, &mut state).is_ok());
+    let mut cache = Vec::new();
+    drain_updates(&mut rx, &mut cache);
+    let json = make_reader(rpcs);
+    assert!(rpc_looper.mainloop(|| json, &mut state).is_ok());
+    let n_updates = drain_updates(&mut rx, &mut cache);
+    (cache, n_updates)
+}
+
+#[test]
+/// Tests that the updates sent by the typing fast path converge to the
+/// same front-end state as an equivalent edit which doesn't use it.
+fn test_echo_insert_converges() {
+    let (typed, typed_updates) = run_edits(r#"{"method":"edit","params":{"view_id":"view-id-1","method":"insert","params":{"chars":"a"}}}
+{"method":"edit","params":{"view_id":"view-id-1","method":"insert","params":{"chars":"b"}}}
+{"method":"edit","params":{"view_id":"view-id-1","method":"insert","params":{"chars":"c"}}}"#);
+    let (pasted, pasted_updates) = run_edits(r#"{"method":"edit","params":{"view_id":"view-id-1","method":"insert","params":{"chars":"abc"}}}"#);
+    // each typed character gets both an echo and a complete update
+    assert_eq!(typed_updates, 6);
+    assert_eq!(pasted_updates, 1);
+    assert_eq!(typed, pasted);
+    assert_eq!(typed[1].as_ref().unwrap()["text"], json!("worldabc"));
+}
+
+/// Returns `true` if the rendered `line` has the pending cleanup style.
+fn has_cleanup_style(line: &Option<Value>) -> bool {
+    let styles = line.as_ref().unwrap()["styles"].as_array().unwrap();
+    styles.chunks(3).any(|triple| triple[2] == json!(2))
+}
+
+#[test]
+fn test_pending_cleanup() {
+    const ENABLE: &str = r#"{"method":"modify_user_config","params":{"changes":{"trim_whitespace_on_save":true,"show_pending_cleanup":true}}}
+{"method":"edit","params":{"view_id":"view-id-1","method":"insert","params":{"chars":"  \nfoo"}}}"#;
+    let (cache, _) = run_edits(ENABLE);
+    assert!(has_cleanup_style(&cache[1]));
+    assert!(!has_cleanup_style(&cache[2]));
+
+    // whitespace on the caret's line isn't marked
+    let (cache, _) = run_edits(&format!(r#"{}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"move_up","params":[]}}}}"#, ENABLE));
+    assert!(!has_cleanup_style(&cache[1]));
+
+    let (cache, _) = run_edits(&format!(r#"{}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"move_up","params":[]}}}}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"move_to_right_end_of_line","params":[]}}}}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"delete_backward","params":[]}}}}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"delete_backward","params":[]}}}}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"move_down","params":[]}}}}"#, ENABLE));
+    assert_eq!(cache[1].as_ref().unwrap()["text"], json!("world\n"));
+    assert!(!has_cleanup_style(&cache[1]));
+}
+
+#[test]
+/// Tests that `reindent` follows indent settings changed after the buffer
+/// was opened.
+fn test_reindent_with_tabs() {
+    const EDITS: &str = r#"{"method":"edit","params":{"view_id":"view-id-1","method":"insert","params":{"chars":"\n        y\n  z"}}}
+{"method":"modify_user_config","params":{"changes":{"translate_tabs_to_spaces":false}}}
+{"method":"edit","params":{"view_id":"view-id-1","method":"insert_tab","params":[]}}"#;
+    let (cache, _) = run_edits(&format!(r#"{}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"reindent","params":{{"scope":"document"}}}}}}"#, EDITS));
+    assert_eq!(line_texts(&cache), vec!["hello\n", "world\n", "\t\ty\n", "  z\t"]);
+    let (cache, _) = run_edits(&format!(r#"{}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"reindent","params":{{"snap":true}}}}}}"#, EDITS));
+    assert_eq!(line_texts(&cache)[3], "\tz\t");
+}
+
+/// Returns the `line_id` of each line in the cache.
+fn line_ids(cache: &[Option<Value>]) -> Vec<u64> {
+    cache.iter().map(|line| line.as_ref().unwrap()["line_id"].as_u64().unwrap()).collect()
+}
+
+#[test]
+/// Tests that line ids are stable across edits and rewrapping.
+fn test_line_ids() {
+    let mut state = MainState::new();
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let json = make_reader(r#"{"method":"client_started","params":{"capabilities":["line_ids"]}}
+{"id":0,"method":"new_view","params":{}}
+{"method":"edit","params":{"view_id":"view-id-1","method":"insert","params":{"chars":"hello\nworld"}}}"#);
+    assert!(rpc_looper.mainloop(|| json, &mut state).is_ok());
+    let mut cache = Vec::new();
+    drain_updates(&mut rx, &mut cache);
+    let ids = line_ids(&cache);
+    assert_eq!(ids.len(), 2);
+    assert!(ids[0] != ids[1]);
+
+    let mut run = |rpcs: &str, cache: &mut Vec<Option<Value>>| {
+        let json = make_reader(rpcs);
+        assert!(rpc_looper.mainloop(|| json, &mut state).is_ok());
+        drain_updates(&mut rx, cache);
+        line_ids(cache)
+    };
+
+    // inserting a line above doesn't change the lines below
+    let after_insert = run(r#"{"method":"edit","params":{"view_id":"view-id-1","method":"move_to_beginning_of_document","params":[]}}
+{"method":"edit","params":{"view_id":"view-id-1","method":"insert","params":{"chars":"top\n"}}}"#, &mut cache);
+    assert_eq!(&after_insert[1..], &ids[..]);
+    assert!(!ids.contains(&after_insert[0]));
+
+    // splitting "hello", the first part keeps its id; joining restores it
+    let after_split = run(r#"{"method":"edit","params":{"view_id":"view-id-1","method":"move_right","params":[]}}
+{"method":"edit","params":{"view_id":"view-id-1","method":"move_right","params":[]}}
+{"method":"edit","params":{"view_id":"view-id-1","method":"insert_newline","params":[]}}"#, &mut cache);
+    assert_eq!(cache[2].as_ref().unwrap()["text"], json!("llo\n"));
+    assert_eq!(after_split[1], ids[0]);
+    assert!(!after_insert.contains(&after_split[2]));
+    assert_eq!(after_split[3], ids[1]);
+    let after_join = run(r#"{"method":"edit","params":{"view_id":"view-id-1","method":"delete_backward","params":[]}}"#,
+                         &mut cache);
+    assert_eq!(after_join, after_insert);
+
+    // wrapped lines share the id of their logical line
+    let long_line = json!({"method": "edit", "params": {"view_id": "view-id-1",
+        "method": "insert", "params": {"chars": "lorem ipsum ".repeat(10)}}});
+    let after_wrap = run(&format!(r#"{}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"debug_rewrap","params":[]}}}}"#, long_line),
+                         &mut cache);
+    assert_eq!(cache.len(), 4);
+    assert_eq!(&after_wrap[..2], &after_join[..2]);
+    assert_eq!(after_wrap[2], after_join[1]);
+    assert_eq!(after_wrap[3], after_join[2]);
+    assert_eq!(cache[2].as_ref().unwrap()["wrap_index"], json!(1));
+    assert!(cache[1].as_ref().unwrap().get("wrap_index").is_none());
+}
+
+#[test]
+/// Tests that `scroll_to` gives the visual position of the caret to front-ends
+/// which ask for it.
+fn test_visual_scroll_to() {
+    let scroll_to = |capabilities: Value| {
+        let mut state = MainState::new();
+        let (tx, mut rx) = test_channel();
+        let mut rpc_looper = RpcLoop::new(tx);
+        let edit = |method: &str, params: Value| json!({"method": "edit",
+            "params": {"view_id": "view-id-1", "method": method, "params": params}}).to_string();
+        let input = [
+            json!({"method": "client_started", "params": {"capabilities": capabilities}})
+                .to_string(),
+            json!({"id": 0, "method": "new_view", "params": {}}).to_string(),
+            edit("insert", json!({"chars": "lorem ipsum ".repeat(10)})),
+            edit("debug_rewrap", json!([])),
+            edit("move_to_beginning_of_document", json!([])),
+            edit("move_to_end_of_document", json!([])),
+        ].join("\n");
+        let r = io::BufReader::new(input.as_bytes().chain(SlowEof(Duration::from_millis(100))));
+        assert!(rpc_looper.mainloop(|| r, &mut state).is_ok());
+        let mut positions = Vec::new();
+        while let Some(msg) = rx.next_timeout(Duration::from_millis(100)) {
+            let msg = msg.unwrap();
+            if msg.get_method() == Some("scroll_to") {
+                positions.push(msg.get_params().unwrap().clone());
+            }
+        }
+        positions.pop().unwrap()
+    };
+
+    // the second of the two wrapped lines has 48 of the 120 characters
+    let position = scroll_to(json!(["visual_scroll_to"]));
+    assert_eq!(position["line"], json!(1));
+    assert_eq!(position["col"], json!(48));
+    assert_eq!(position["visual_line"], json!(1));
+    assert_eq!(position["visual_col"], json!(48));
+    assert_eq!(position["affinity"], json!("downstream"));
+    let position = scroll_to(json!([]));
+    assert_eq!((&position["line"], &position["col"]), (&json!(1), &json!(48)));
+    assert!(position.get("visual_line").is_none());
+}
+
+#[test]
+/// Tests that clients with the `block_clipboard` capability get the segments
+/// of a rectangular selection, and can paste them as a rectangle.
+fn test_block_clipboard() {
+    let mut state = MainState::new();
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let edit = |method: &str, params: Value| json!({"method": "edit",
+        "params": {"view_id": "view-id-1", "method": method, "params": params}}).to_string();
+    let json = [
+        r#"{"method":"client_started","params":{"capabilities":["block_clipboard"]}}"#.to_owned(),
+        r#"{"id":0,"method":"new_view","params":{}}"#.to_owned(),
+        edit("insert", json!({"chars": "ab\ncd"})),
+        edit("move_to_beginning_of_document", json!([])),
+        edit("add_selection_below", json!([])),
+        edit("move_right_and_modify_selection", json!([])),
+        r#"{"id":1,"method":"edit","params":{"view_id":"view-id-1","method":"copy","params":[]}}"#
+            .to_owned(),
+    ].join("\n");
+    let json = make_reader(&json);
+    assert!(rpc_looper.mainloop(|| json, &mut state).is_ok());
+    let mut cache = Vec::new();
+    let mut responses = Vec::new();
+    while let Some(msg) = rx.next_timeout(Duration::from_millis(100)) {
+        let msg = msg.unwrap();
+        if msg.get_method() == Some("update") {
+            apply_update(&mut cache, &msg.get_params().unwrap()["update"]);
+        } else if msg.is_response() {
+            responses.push(msg.into_response().unwrap().unwrap());
+        }
+    }
+    let copied = responses.pop().unwrap();
+    assert_eq!(copied, json!({"text": "a\nc", "block": {"column": 0, "segments": ["a", "c"]}}));
+
+    let json = [
+        edit("move_to_end_of_document", json!([])),
+        edit("paste", json!({"chars": copied["text"], "block": copied["block"]})),
+    ].join("\n");
+    let json = make_reader(&json);
+    assert!(rpc_looper.mainloop(|| json, &mut state).is_ok());
+    drain_updates(&mut rx, &mut cache);
+    assert_eq!(line_texts(&cache), vec!["ab\n", "cda\n", "  c"]);
+}
+
+/// Returns the text of each line in `cache`.
+fn line_texts(cache: &[Option<Value>]) -> Vec<String> {
+    cache.iter()
+        .map(|line| line.as_ref().unwrap()["text"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+#[test]
+/// Tests that two views of the same file share its buffer, so that edits
+/// from either are seen by both, and saves include both.
+fn test_shared_buffer() {
+    let mut dir = env::temp_dir();
+    dir.push("xi-test-shared-buffer");
+    let _ = fs::create_dir(&dir);
+    let path = dir.join("shared.txt");
+    fs::write(&path, "one\ntwo\n").unwrap();
+    // a different path to the same file
+    let other_path = dir.join("..").join("xi-test-shared-buffer").join("shared.txt");
+
+    let mut state = MainState::new();
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let edit = |view_id: &str, method: &str, params: Value| json!({"method": "edit",
+        "params": {"view_id": view_id, "method": method, "params": params}}).to_string();
+    let mut rpcs = vec![
+        r#"{"method":"client_started","params":{}}"#.to_owned(),
+        json!({"id": 0, "method": "new_view", "params": {"file_path": path}}).to_string(),
+        json!({"id": 1, "method": "new_view", "params": {"file_path": other_path}}).to_string(),
+        edit("view-id-3", "move_to_end_of_document", json!([])),
+    ];
+    for _ in 0..3 {
+        rpcs.push(edit("view-id-1", "insert", json!({"chars": "a"})));
+        rpcs.push(edit("view-id-3", "insert", json!({"chars": "b"})));
+    }
+    rpcs.push(edit("view-id-1", "insert_newline", json!([])));
+    rpcs.push(json!({"method": "save",
+        "params": {"view_id": "view-id-3", "file_path": path}}).to_string());
+    // closing one view leaves the buffer open in the other
+    rpcs.push(r#"{"method":"close_view","params":{"view_id":"view-id-1"}}"#.to_owned());
+    rpcs.push(edit("view-id-3", "insert", json!({"chars": "c"})));
+    let input = rpcs.join("\n");
+    assert!(rpc_looper.mainloop(|| make_reader(&input), &mut state).is_ok());
+
+    let mut caches = vec![Vec::new(), Vec::new()];
+    let mut view_ids = Vec::new();
+    let mut responses = Vec::new();
+    while let Some(msg) = rx.next_timeout(Duration::from_millis(100)) {
+        let msg = msg.unwrap();
+        if msg.get_method() == Some("update") {
+            let params = msg.get_params().unwrap();
+            let view_id = params["view_id"].as_str().unwrap();
+            assert!(view_id == "view-id-1" || view_id == "view-id-3");
+            view_ids.push(view_id.to_owned());
+            let ix = if view_id == "view-id-1" { 0 } else { 1 };
+            apply_update(&mut caches[ix], &params["update"]);
+        } else if msg.is_response() {
+            responses.push(msg.into_response().unwrap().unwrap());
+        }
+    }
+    assert_eq!(responses, vec![json!("view-id-1"), json!("view-id-3")]);
+    assert!(view_ids.contains(&"view-id-1".to_owned()));
+    assert!(view_ids.contains(&"view-id-3".to_owned()));
+
+    assert_eq!(line_texts(&caches[0]), vec!["aaa\n", "one\n", "two\n", "bbb"]);
+    assert_eq!(line_texts(&caches[1]), vec!["aaa\n", "one\n", "two\n", "bbbc"]);
+    // each view's caret is where it typed
+    assert_eq!(caches[0][1].as_ref().unwrap()["cursor"], json!([0]));
+    assert_eq!(caches[1][3].as_ref().unwrap()["cursor"], json!([4]));
+    assert_eq!(fs::read_to_string(&path).unwrap(), "aaa\none\ntwo\nbbb");
+}
+
+#[test]
+/// Tests that the front-end is told which minimap buckets an edit changes,
+/// once it has asked for the minimap.
+fn test_minimap_changed() {
+    let mut state = MainState::new();
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let text = (0..30).map(|i| format!("line {}\n", i)).collect::<String>();
+    let input = format!(r#"{{"method":"client_started","params":{{}}}}
+{{"id":0,"method":"new_view","params":{{}}}}
+{}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"insert","params":{{"chars":"x"}}}}}}
+{{"id":1,"method":"edit","params":{{"view_id":"view-id-1","method":"get_minimap","params":{{"lines_per_bucket":10}}}}}}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"goto_line","params":{{"line":15}}}}}}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"insert","params":{{"chars":"y"}}}}}}"#,
+        json!({"method": "edit", "params": {"view_id": "view-id-1", "method": "insert",
+            "params": {"chars": text}}}));
+    assert!(rpc_looper.mainloop(|| make_reader(&input), &mut state).is_ok());
+
+    let mut changes = Vec::new();
+    let mut responses = Vec::new();
+    while let Some(msg) = rx.next_timeout(Duration::from_millis(100)) {
+        let msg = msg.unwrap();
+        if msg.get_method() == Some("minimap_changed") {
+            let mut params = msg.get_params().unwrap().clone();
+            assert!(params.as_object_mut().unwrap().remove("seq").is_some());
+            changes.push(params);
+        } else if msg.is_response() {
+            responses.push(msg.into_response().unwrap().unwrap());
+        }
+    }
+    let minimap = &responses[1];
+    assert_eq!(minimap["lines_per_bucket"], json!(10));
+    assert_eq!(minimap["n_buckets"], json!(4));
+    // the last bucket is the line "x"
+    assert_eq!(minimap["buckets"][3]["density"], json!(1));
+    // only the edited line's bucket changed, and only after the request
+    assert_eq!(changes, vec![json!({"view_id": "view-id-1", "n_buckets": 4,
+                                    "first_bucket": 1, "last_bucket": 2})]);
+}
+
+#[test]
+/// Tests that `buffer_info` is sent when a file is opened, and again only
+/// when the status it reports changes.
+fn test_buffer_info() {
+    let mut dir = env::temp_dir();
+    dir.push("xi-test-buffer-info");
+    let _ = fs::create_dir(&dir);
+    let path = dir.join("crlf.txt");
+    fs::write(&path, "one\r\ntwo\r\n").unwrap();
+
+    let mut state = MainState::new();
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let edit = |method: &str, params: Value| json!({"method": "edit",
+        "params": {"view_id": "view-id-1", "method": method, "params": params}}).to_string();
+    let mut run = |rpcs: &[String]| {
+        let input = rpcs.join("\n");
+        // waiting before EOF lets the views render
+        let r = io::BufReader::new(input.as_bytes().chain(SlowEof(Duration::from_millis(100))));
+        assert!(rpc_looper.mainloop(|| r, &mut state).is_ok());
+        let mut infos = Vec::new();
+        let mut responses = Vec::new();
+        while let Some(msg) = rx.next_timeout(Duration::from_millis(100)) {
+            let msg = msg.unwrap();
+            if msg.get_method() == Some("buffer_info") {
+                infos.push(msg.get_params().unwrap().clone());
+            } else if msg.is_response() {
+                responses.push(msg.into_response().unwrap().unwrap());
+            }
+        }
+        (infos, responses)
+    };
+
+    let (infos, _) = run(&[
+        r#"{"method":"client_started","params":{}}"#.to_owned(),
+        json!({"id": 0, "method": "new_view", "params": {"file_path": path}}).to_string(),
+    ]);
+    assert_eq!(infos.len(), 1);
+    assert_eq!(infos[0]["view_id"], json!("view-id-1"));
+    assert_eq!(infos[0]["display_name"], json!("crlf.txt"));
+    assert_eq!(infos[0]["language_id"], json!("plaintext"));
+    assert_eq!(infos[0]["encoding"], json!("utf-8"));
+    assert_eq!(infos[0]["bom"], json!(false));
+    assert_eq!(infos[0]["line_ending"], json!("crlf"));
+    assert_eq!(infos[0]["read_only"], json!(false));
+    assert_eq!(infos[0]["pristine"], json!(true));
+    assert_eq!(infos[0]["file_size"], json!(10));
+
+    // only the first edit changes the status
+    let (infos, responses) = run(&[
+        edit("insert", json!({"chars": "a"})),
+        edit("insert", json!({"chars": "b"})),
+        edit("move_left", json!([])),
+        json!({"id": 1, "method": "edit", "params": {"view_id": "view-id-1",
+            "method": "get_buffer_info", "params": []}}).to_string(),
+    ]);
+    assert_eq!(infos.len(), 1);
+    assert_eq!(infos[0]["pristine"], json!(false));
+    let mut info = infos[0].clone();
+    info.as_object_mut().unwrap().remove("view_id");
+    info.as_object_mut().unwrap().remove("seq");
+    assert_eq!(responses, vec![info]);
+
+    let (infos, _) = run(&[
+        json!({"method": "save", "params": {"view_id": "view-id-1", "file_path": path}})
+            .to_string(),
+        edit("move_right", json!([])),
+    ]);
+    assert_eq!(infos.len(), 1);
+    assert_eq!(infos[0]["pristine"], json!(true));
+    assert_eq!(infos[0]["file_size"], json!(12));
+}
+
+#[test]
+/// Tests that the language of an untitled buffer is detected once a script
+/// is pasted into it, but not once the user has chosen one.
+fn test_syntax_detection() {
+    let mut state = MainState::new();
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let edit = |method: &str, params: Value| json!({"method": "edit",
+        "params": {"view_id": "view-id-1", "method": method, "params": params}}).to_string();
+    let mut run = |rpcs: &[String]| {
+        let input = rpcs.join("\n");
+        // waiting before EOF lets the detection timer fire
+        let r = io::BufReader::new(input.as_bytes().chain(SlowEof(Duration::from_millis(500))));
+        assert!(rpc_looper.mainloop(|| r, &mut state).is_ok());
+        let mut languages = Vec::new();
+        while let Some(msg) = rx.next_timeout(Duration::from_millis(100)) {
+            let msg = msg.unwrap();
+            if msg.get_method() == Some("buffer_info") {
+                languages.push(msg.get_params().unwrap()["language_id"].clone());
+            }
+        }
+        languages
+    };
+
+    let languages = run(&[
+        r#"{"method":"client_started","params":{}}"#.to_owned(),
+        r#"{"id":0,"method":"new_view","params":{}}"#.to_owned(),
+        edit("insert", json!({"chars": "#!/usr/bin/env python\nprint('hello')\n"})),
+    ]);
+    assert_eq!(languages.first(), Some(&json!("plaintext")));
+    assert_eq!(languages.last(), Some(&json!("python3")));
+
+    let languages = run(&[
+        edit("set_language", json!({"language_id": "rust"})),
+        edit("move_to_beginning_of_document", json!([])),
+        edit("insert", json!({"chars": "#!/bin/sh\n"})),
+    ]);
+    assert_eq!(languages, vec![json!("rust")]);
+}
+
+#[test]
+/// Tests that a file opened in `view_only_mmap` mode can be viewed,
+/// searched and copied from, but not edited until it is promoted.
+fn test_view_only_mmap() {
+    let mut path = env::temp_dir();
+    path.push("xi-test-view-only.log");
+    fs::write(&path, &b"first\nsecond \xff\nthird\n"[..]).unwrap();
+
+    let mut state = MainState::new();
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let edit = |method: &str, params: Value| json!({"method": "edit",
+        "params": {"view_id": "view-id-1", "method": method, "params": params}}).to_string();
+    let request = |id: u64, method: &str, params: Value| json!({"id": id, "method": "edit",
+        "params": {"view_id": "view-id-1", "method": method, "params": params}}).to_string();
+    let mut cache = Vec::new();
+    let mut run = |rpcs: &[String], cache: &mut Vec<Option<Value>>| {
+        let input = rpcs.join("\n");
+        let r = io::BufReader::new(input.as_bytes().chain(SlowEof(Duration::from_millis(100))));
+        assert!(rpc_looper.mainloop(|| r, &mut state).is_ok());
+        let mut alerts = Vec::new();
+        let mut responses = Vec::new();
+        while let Some(msg) = rx.next_timeout(Duration::from_millis(100)) {
+            let msg = msg.unwrap();
+            if msg.get_method() == Some("update") {
+                apply_update(cache, &msg.get_params().unwrap()["update"]);
+            } else if msg.get_method() == Some("alert") {
+                alerts.push(msg.get_params().unwrap()["msg"].clone());
+            } else if msg.is_response() {
+                responses.push(msg.into_response().unwrap());
+            }
+        }
+        (alerts, responses)
+    };
+
+    let (_, responses) = run(&[
+        r#"{"method":"client_started","params":{}}"#.to_owned(),
+        json!({"id": 0, "method": "new_view",
+            "params": {"file_path": path, "mode": "view_only_mmap"}}).to_string(),
+    ], &mut cache);
+    assert_eq!(responses, vec![Ok(json!("view-id-1"))]);
+    // bytes which aren't UTF-8 are replaced
+    assert_eq!(line_texts(&cache), vec!["first\n", "second \u{fffd}\n", "third\n", ""]);
+
+    let (alerts, responses) = run(&[
+        edit("insert", json!({"chars": "x"})),
+        request(1, "cut", json!([])),
+        request(2, "find", json!({"chars": "THIRD", "case_sensitive": false})),
+        edit("find_next", json!({"wrap_around": true})),
+        request(3, "copy", json!([])),
+        request(4, "get_buffer_info", json!([])),
+    ], &mut cache);
+    assert_eq!(alerts, vec![json!("readonly: this view can't be edited")]);
+    assert!(responses[0].is_err());
+    assert_eq!(responses[1], Ok(json!("THIRD")));
+    assert_eq!(responses[2], Ok(json!("third")));
+    assert_eq!(responses[3].as_ref().unwrap()["read_only"], json!(true));
+    assert_eq!(line_texts(&cache), vec!["first\n", "second \u{fffd}\n", "third\n", ""]);
+
+    let (_, responses) = run(&[
+        request(5, "promote_to_editable", json!([])),
+        edit("insert", json!({"chars": "x"})),
+    ], &mut cache);
+    assert_eq!(responses, vec![Ok(json!({"size": 21}))]);
+    assert_eq!(line_texts(&cache), vec!["xfirst\n", "second \u{fffd}\n", "third\n", ""]);
+}
+
+#[test]
+/// Tests that alerts and errors have message ids with structured params,
+/// and that translators can list the messages.
+fn test_message_catalog() {
+    let mut path = env::temp_dir();
+    path.push("xi-test-messages.log");
+    fs::write(&path, "text\n").unwrap();
+
+    let mut state = MainState::new();
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let request = |id: u64, method: &str, params: Value| json!({"id": id, "method": "edit",
+        "params": {"view_id": "view-id-1", "method": method, "params": params}}).to_string();
+    let input = [
+        r#"{"method":"client_started","params":{}}"#.to_owned(),
+        json!({"id": 0, "method": "new_view",
+            "params": {"file_path": path, "mode": "view_only_mmap"}}).to_string(),
+        json!({"method": "edit", "params": {"view_id": "view-id-1", "method": "insert",
+            "params": {"chars": "x"}}}).to_string(),
+        request(1, "cut", json!([])),
+        json!({"id": 2, "method": "new_view",
+            "params": {"file_path": "/no/such/dir/file.txt", "mode": "view_only_mmap"}})
+            .to_string(),
+        r#"{"id":3,"method":"list_message_ids"}"#.to_owned(),
+    ].join("\n");
+    let r = io::BufReader::new(input.as_bytes().chain(SlowEof(Duration::from_millis(100))));
+    assert!(rpc_looper.mainloop(|| r, &mut state).is_ok());
+    let mut alerts = Vec::new();
+    let mut responses = Vec::new();
+    while let Some(msg) = rx.next_timeout(Duration::from_millis(100)) {
+        let msg = msg.unwrap();
+        if msg.get_method() == Some("alert") {
+            alerts.push(msg.get_params().unwrap().clone());
+        } else if msg.is_response() {
+            responses.push(msg.into_response().unwrap());
+        }
+    }
+
+    assert_eq!(alerts[0]["id"], json!("view_cant_be_edited"));
+    assert_eq!(alerts[0]["params"], json!({}));
+    assert_eq!(alerts[0]["fallback_text"], alerts[0]["msg"]);
+    let message = |response: &Result<Value, RemoteError>| match *response {
+        Err(RemoteError::Custom { data: Some(ref data), .. }) => data["message"].clone(),
+        ref other => panic!("unexpected {:?}", other),
+    };
+    assert_eq!(message(&responses[1])["id"], json!("view_cant_be_edited"));
+    let open_failed = message(&responses[2]);
+    assert_eq!(open_failed["id"], json!("file_open_failed"));
+    assert_eq!(open_failed["params"]["path"], json!("/no/such/dir/file.txt"));
+    assert!(open_failed["fallback_text"].as_str().unwrap().starts_with("can't open /no/such"));
+
+    let catalog = responses[3].as_ref().unwrap().as_array().unwrap();
+    let loaded = catalog.iter().find(|msg| msg["id"] == json!("file_loaded_into_memory"))
+        .unwrap();
+    assert_eq!(loaded["params"], json!(["path", "megabytes"]));
+    for msg in catalog {
+        // numbers are sent as params, and never formatted into the text
+        let text = msg["fallback_text"].as_str().unwrap();
+        assert!(!text.chars().any(|c| c.is_ascii_digit()), "{}", text);
+    }
+}
+
+#[test]
+/// Tests that a burst of theme previews recomputes styles once, and that
+/// cancelling restores the committed theme.
+fn test_theme_preview() {
+    let mut state = MainState::new();
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let themes = ["base16-ocean.dark", "base16-eighties.dark", "base16-mocha.dark",
+        "base16-ocean.light", "Solarized (dark)"];
+    let preview = |name: &str| json!({"method": "preview_theme",
+        "params": {"theme_name": name}}).to_string();
+    let get_config = |id: u64| json!({"id": id, "method": "get_config"})
+        .to_string();
+    let get_stats = |id: u64| json!({"id": id, "method": "edit", "params": {
+        "view_id": "view-id-1", "method": "debug_get_stats", "params": []}}).to_string();
+    let mut run = |rpcs: &[String]| {
+        let input = rpcs.join("\n");
+        let r = io::BufReader::new(input.as_bytes().chain(SlowEof(Duration::from_millis(100))));
+        assert!(rpc_looper.mainloop(|| r, &mut state).is_ok());
+        let mut changed = Vec::new();
+        let mut responses = Vec::new();
+        while let Some(msg) = rx.next_timeout(Duration::from_millis(100)) {
+            let msg = msg.unwrap();
+            if msg.get_method() == Some("theme_changed") {
+                changed.push(msg.get_params().unwrap()["name"].clone());
+            } else if msg.is_response() {
+                responses.push(msg.into_response().unwrap().unwrap());
+            }
+        }
+        (changed, responses)
+    };
+
+    let mut rpcs = vec![
+        r#"{"method":"client_started","params":{}}"#.to_owned(),
+        r#"{"id":0,"method":"new_view","params":{}}"#.to_owned(),
+    ];
+    rpcs.extend(themes.iter().map(|name| preview(name)));
+    rpcs.push(get_config(1));
+    let (changed, responses) = run(&rpcs);
+    assert_eq!(changed, themes.iter().map(|name| json!(name)).collect::<Vec<_>>());
+    assert_eq!(responses[1]["theme"], json!("InspiredGitHub"));
+    assert_eq!(responses[1]["previewed_theme"], json!("Solarized (dark)"));
+
+    let (_, responses) = run(&[get_stats(2)]);
+    assert_eq!(responses[0]["theme_recomputes"], json!(1));
+    // the plugin section has the limits, though no plugins are running
+    assert_eq!(responses[0]["plugins"]["running"], json!({}));
+    assert_eq!(responses[0]["plugins"]["limits"]["hard_limit_factor"], json!(4));
+
+    let (changed, responses) = run(&[
+        r#"{"method":"cancel_theme_preview"}"#.to_owned(),
+        get_config(3),
+    ]);
+    assert_eq!(changed, vec![json!("InspiredGitHub")]);
+    assert_eq!(responses[0]["previewed_theme"], Value::Null);
+
+    let (_, responses) = run(&[
+        preview("base16-ocean.light"),
+        r#"{"method":"commit_theme"}"#.to_owned(),
+        get_config(4),
+        r#"{"method":"cancel_theme_preview"}"#.to_owned(),
+        get_stats(5),
+    ]);
+    assert_eq!(responses[0]["theme"], json!("base16-ocean.light"));
+    assert_eq!(responses[0]["previewed_theme"], Value::Null);
+    assert_eq!(responses[1]["theme_recomputes"], json!(3));
+}
+
+#[test]
+/// Tests that safe mode uses the default config and starts no plugins,
+/// until the user's config and plugins are enabled again.
+fn test_safe_mode() {
+    let mut state = MainState::with_safe_mode(true);
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let get_config = |id: u64| json!({"id": id, "method": "get_config"}).to_string();
+    let mut run = |rpcs: &[String]| {
+        let input = rpcs.join("\n");
+        let r = io::BufReader::new(input.as_bytes().chain(SlowEof(Duration::from_millis(100))));
+        assert!(rpc_looper.mainloop(|| r, &mut state).is_ok());
+        let mut alerts = Vec::new();
+        let mut responses = Vec::new();
+        while let Some(msg) = rx.next_timeout(Duration::from_millis(100)) {
+            let msg = msg.unwrap();
+            if msg.get_method() == Some("alert") {
+                alerts.push(msg.get_params().unwrap()["msg"].clone());
+            } else if msg.is_response() {
+                responses.push(msg.into_response().unwrap().unwrap());
+            }
+        }
+        (alerts, responses)
+    };
+
+    let (alerts, responses) = run(&[
+        r#"{"method":"client_started","params":{}}"#.to_owned(),
+        r#"{"id":0,"method":"new_view","params":{}}"#.to_owned(),
+        r#"{"method":"modify_user_config","params":{"changes":{"tab_size":2}}}"#.to_owned(),
+        r#"{"method":"modify_user_config","params":{"changes":{"no_such_key":1}}}"#.to_owned(),
+        get_config(1),
+    ]);
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(responses[1]["tab_size"], json!(4));
+    assert_eq!(responses[1]["provenance"], json!({
+        "safe_mode": true,
+        "user_config": "disabled",
+        "plugins": "disabled",
+    }));
+
+    let (alerts, responses) = run(&[
+        r#"{"method":"reload_user_config"}"#.to_owned(),
+        r#"{"method":"restart_plugins","params":{"plugins":["syntect"]}}"#.to_owned(),
+        get_config(2),
+    ]);
+    // the invalid change is only reported once the config is enabled
+    assert_eq!(alerts.len(), 1);
+    assert!(alerts[0].as_str().unwrap().contains("no_such_key"));
+    assert_eq!(responses[0]["tab_size"], json!(2));
+    assert_eq!(responses[0]["provenance"]["user_config"], json!("enabled"));
+    assert_eq!(responses[0]["provenance"]["plugins"], json!({"only": ["syntect"]}));
+
+    // safe mode can also be asked for by the client
+    let mut state = MainState::new();
+    let json = make_reader(r#"{"method":"client_started","params":{"safe_mode":true}}
+{"id":0,"method":"get_config"}"#);
+    let (tx, mut rx) = test_channel();
+    assert!(RpcLoop::new(tx).mainloop(|| json, &mut state).is_ok());
+    assert_eq!(rx.expect_object().get_method(), Some("alert"));
+    assert_eq!(rx.expect_object().get_method(), Some("available_themes"));
+    assert_eq!(rx.expect_response().unwrap()["provenance"]["safe_mode"], json!(true));
+}
+
+/// Pastes `chars` into a new view with the `paste_sanitize` config `mode`,
+/// then runs `rpcs`, returning the lines of the view and the alerts sent.
+fn paste_sanitized(mode: &str, chars: &str, rpcs: &[Value]) -> (Vec<String>, Vec<Value>) {
+    let mut state = MainState::new();
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let edit = |method: &str, params: Value| json!({"method": "edit", "params": {
+        "view_id": "view-id-1", "method": method, "params": params}});
+    let mut input = vec![
+        json!({"method": "client_started", "params": {}}),
+        json!({"id": 0, "method": "new_view", "params": {}}),
+        json!({"method": "modify_user_config", "params": {"changes": {"paste_sanitize": mode}}}),
+        edit("paste", json!({"chars": chars})),
+    ];
+    input.extend(rpcs.iter().map(|rpc| edit(rpc["method"].as_str().unwrap(),
+                                            rpc["params"].clone())));
+    let input = input.iter().map(|rpc| rpc.to_string()).collect::<Vec<_>>().join("\n");
+    assert!(rpc_looper.mainloop(|| make_reader(&input), &mut state).is_ok());
+    let mut cache = Vec::new();
+    let mut alerts = Vec::new();
+    while let Some(msg) = rx.next_timeout(Duration::from_millis(100)) {
+        let msg = msg.unwrap();
+        match msg.get_method() {
+            Some("update") => apply_update(&mut cache, &msg.get_params().unwrap()["update"]),
+            Some("alert") => alerts.push(msg.get_params().unwrap().clone()),
+            _ => (),
+        }
+    }
+    (line_texts(&cache), alerts)
+}
+
+#[test]
+/// Tests each way of sanitizing pasted text.
+fn test_paste_sanitize() {
+    const TERMINAL: &str = "\u{1B}[32mok\u{1B}[0m\r\ndone\u{7}";
+    const TROJAN: &str = "x = 1 /*\u{202E} } \u{2066}if admin\u{2069} */";
+
+    let (lines, alerts) = paste_sanitized("off", TERMINAL, &[]);
+    assert_eq!(lines, vec!["\u{1B}[32mok\u{1B}[0m\r\n", "done\u{7}"]);
+    assert!(alerts.is_empty());
+    // bidi control characters are warned of even when sanitization is off
+    let (lines, alerts) = paste_sanitized("off", TROJAN, &[]);
+    assert_eq!(lines, vec![TROJAN]);
+    assert!(alerts[0]["msg"].as_str().unwrap().contains("3 bidi control characters"));
+
+    let (lines, alerts) = paste_sanitized("warn", TERMINAL, &[]);
+    assert_eq!(lines.len(), 2);
+    assert_eq!(alerts[0]["msg"], json!("The pasted text contains 1 control character, \
+        2 terminal escape sequences and 1 carriage return."));
+
+    let (lines, alerts) = paste_sanitized("strip", TERMINAL, &[]);
+    assert_eq!(lines, vec!["ok\n", "done"]);
+    assert!(alerts.is_empty());
+    let (lines, _) = paste_sanitized("strip", TROJAN, &[]);
+    assert_eq!(lines, vec!["x = 1 /* } if admin */"]);
+
+    // the user is asked, and nothing is pasted until they answer
+    let (lines, alerts) = paste_sanitized("ask", TERMINAL, &[]);
+    assert_eq!(lines, vec![""]);
+    assert_eq!(alerts[0]["actions"], json!(["strip", "verbatim", "cancel"]));
+    assert_eq!(alerts[0]["view_id"], json!("view-id-1"));
+    let resolve = |action: &str| json!({"method": "resolve_paste", "params": {"action": action}});
+    let (lines, _) = paste_sanitized("ask", TERMINAL, &[resolve("strip")]);
+    assert_eq!(lines, vec!["ok\n", "done"]);
+    let (lines, _) = paste_sanitized("ask", TERMINAL, &[resolve("verbatim")]);
+    assert_eq!(lines[1], "done\u{7}");
+    let (lines, _) = paste_sanitized("ask", TERMINAL, &[resolve("cancel"), resolve("verbatim")]);
+    assert_eq!(lines, vec![""]);
+    let (lines, alerts) = paste_sanitized("ask", "plain", &[]);
+    assert_eq!((lines, alerts.len()), (vec!["plain".to_owned()], 0));
+}
+
+/// A reader which waits before reporting EOF, so that timers can fire.
+struct SlowEof(Duration);
+
+impl io::Read for SlowEof {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        ::std::thread::sleep(self.0);
+        Ok(0)
+    }
+}
+
+#[test]
+/// Tests that buffers are saved after a burst of edits, and when they
+/// lose focus, leaving the whitespace on the caret's line.
+fn test_auto_save() {
+    let mut path = env::temp_dir();
+    path.push("xi-test-auto-save.txt");
+    let _ = fs::remove_file(&path);
+    let new_view = json!({"id": 0, "method": "new_view", "params": {"file_path": path}});
+
+    let mut state = MainState::new();
+    let (tx, _rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let input = format!(r#"{{"method":"client_started","params":{{}}}}
+{{"method":"modify_user_config","params":{{"changes":{{"auto_save":"after_delay","auto_save_delay":100,"trim_whitespace_on_save":true}}}}}}
+{}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"insert","params":{{"chars":"foo  \n"}}}}}}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"insert","params":{{"chars":"b"}}}}}}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"insert","params":{{"chars":"a"}}}}}}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"insert","params":{{"chars":"r "}}}}}}
+"#, new_view);
+    let r = io::BufReader::new(input.as_bytes().chain(SlowEof(Duration::from_millis(500))));
+    assert!(rpc_looper.mainloop(|| r, &mut state).is_ok());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "foo\nbar ");
+
+    let json = make_reader(r#"{"method":"modify_user_config","params":{"changes":{"auto_save":"on_focus_lost"}}}
+{"method":"edit","params":{"view_id":"view-id-1","method":"insert","params":{"chars":"baz"}}}
+{"method":"focus_changed","params":{"view_id":"view-id-1","focused":true}}"#);
+    assert!(rpc_looper.mainloop(|| json, &mut state).is_ok());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "foo\nbar ");
+    let json = make_reader(r#"{"method":"focus_changed","params":{"view_id":"view-id-1","focused":false}}"#);
+    assert!(rpc_looper.mainloop(|| json, &mut state).is_ok());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "foo\nbar baz");
+    let _ = fs::remove_file(&path);
+}
+
 //TODO: test saving rpc
 //TODO: test plugin rpc
 
@@ -203,6 +1073,9 @@ const TEXT_EDIT_RPCS: &str = r#"{"method":"edit","params":{"view_id":"view-id-1"
 {"method":"edit","params":{"view_id":"view-id-1","method":"undo","params":[]}}
 {"method":"edit","params":{"view_id":"view-id-1","method":"redo","params":[]}}
 {"method":"edit","params":{"view_id":"view-id-1","method":"transpose","params":[]}}
+{"method":"edit","params":{"view_id":"view-id-1","method":"surround","params":{"open":"("}}}
+{"method":"edit","params":{"view_id":"view-id-1","method":"change_surround","params":{"from":"(","to":"["}}}
+{"method":"edit","params":{"view_id":"view-id-1","method":"unsurround","params":[]}}
 {"id":2,"method":"edit","params":{"view_id":"view-id-1","method":"cut","params":[]}}"#;
 
 const OTHER_EDIT_RPCS: &str = r#"{"method":"edit","params":{"view_id":"view-id-1","method":"scroll","params":[0,1]}}
@@ -216,4 +1089,91 @@ const OTHER_EDIT_RPCS: &str = r#"{"method":"edit","params":{"view_id":"view-id-1
 {"method":"edit","params":{"view_id":"view-id-1","method":"find_previous","params":{"wrap_around":true}}}
 {"method":"edit","params":{"view_id":"view-id-1","method":"debug_rewrap","params":[]}}
 {"method":"edit","params":{"view_id":"view-id-1","method":"debug_print_spans","params":[]}}
-{"id":3,"method":"edit","params":{"view_id":"view-id-1","method":"copy","params":[]}}"#;
+{"id":3,"method":"edit","params":{"view_id":"view-id-1","method":"copy","params":[]}}
+{"id":5,"method":"edit","params":{"view_id":"view-id-1","method":"debug_get_stats","params":[]}}"#;
+
+#[test]
+/// Negotiates compression, and measures the bytes sent while scrolling
+/// through a large file with highlights on every line.
+fn test_compressed_updates() {
+    let mut state = MainState::new();
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let text = (0..5000)
+        .map(|i| format!("    let value_{} = compute(\"item {}\", {});\n", i, i, i * 7))
+        .collect::<String>();
+    let insert = json!({"method": "edit", "params": {"view_id": "view-id-1",
+        "method": "insert", "params": {"chars": text}}});
+    let json = make_reader(format!(r#"{{"id":0,"method":"negotiate_compression","params":{{"algorithms":["zstd","deflate"],"threshold":4096}}}}
+{{"id":1,"method":"new_view","params":{{}}}}
+{}
+{{"id":2,"method":"edit","params":{{"view_id":"view-id-1","method":"find","params":{{"case_sensitive":false,"chars":"value"}}}}}}
+{{"method":"edit","params":{{"view_id":"view-id-1","method":"move_to_beginning_of_document","params":[]}}}}"#, insert));
+    assert!(rpc_looper.mainloop(|| json, &mut state).is_ok());
+    assert_eq!(rx.expect_response(),
+               Ok(json!({"algorithm": "deflate", "threshold": 4096})));
+    while rx.next_timeout(Duration::from_millis(100)).is_some() {}
+
+    let scrolls = (0..50)
+        .map(|i| format!(r#"{{"method":"edit","params":{{"view_id":"view-id-1","method":"request_lines","params":[{},{}]}}}}"#,
+                         i * 100, i * 100 + 100))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let json = make_reader(scrolls);
+    assert!(rpc_looper.mainloop(|| json, &mut state).is_ok());
+    let (mut sent, mut uncompressed, mut n_compressed) = (0, 0, 0);
+    while let Some(line) = rx.next_line_timeout(Duration::from_millis(100)) {
+        let msg = rx.parse(&line).unwrap();
+        let plain = json!({"method": msg.get_method(), "params": msg.get_params()});
+        sent += line.len();
+        uncompressed += plain.to_string().len();
+        if line.contains(r#""compressed":"deflate""#) {
+            n_compressed += 1;
+        }
+    }
+    assert!(n_compressed > 0);
+    assert!(sent * 3 < uncompressed);
+}
+
+#[test]
+/// Tests that requests with unknown methods, and views of missing files,
+/// fail with suggestions of what was meant.
+fn test_suggestions() {
+    let mut dir = env::temp_dir();
+    dir.push("xi-test-suggestions");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("notes.txt"), "notes").unwrap();
+
+    let mut state = MainState::new();
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let json = [
+        r#"{"method":"client_started","params":{}}"#.to_owned(),
+        r#"{"id":0,"method":"new_view","params":{}}"#.to_owned(),
+        r#"{"id":1,"method":"edit","params":{"view_id":"view-id-1","method":"cpoy","params":[]}}"#
+            .to_owned(),
+        r#"{"id":2,"method":"new_veiw","params":{}}"#.to_owned(),
+        json!({"id": 3, "method": "new_view", "params": {
+            "file_path": dir.join("ntoes.txt"), "mode": "view_only_mmap"}}).to_string(),
+    ].join("\n");
+    let json = make_reader(&json);
+    assert!(rpc_looper.mainloop(|| json, &mut state).is_ok());
+    let mut errors = Vec::new();
+    while let Some(msg) = rx.next_timeout(Duration::from_millis(100)) {
+        let msg = msg.unwrap();
+        if msg.is_response() {
+            if let Err(err) = msg.into_response().unwrap() {
+                errors.push(err);
+            }
+        }
+    }
+    let suggestions = errors.iter().map(|err| match *err {
+        RemoteError::InvalidRequest(Some(ref data)) => data["suggestions"].clone(),
+        RemoteError::Custom { ref data, .. } => data.as_ref().unwrap()["suggestions"].clone(),
+        _ => panic!("unexpected error {:?}", err),
+    }).collect::<Vec<_>>();
+    assert_eq!(suggestions, vec![json!(["copy"]), json!(["new_view"]),
+                                 json!([dir.join("notes.txt")])]);
+    fs::remove_dir_all(&dir).unwrap();
+}
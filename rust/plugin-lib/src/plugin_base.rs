@@ -64,6 +64,48 @@ impl ScopeSpan {
 	}
 }
 
+/// The spans of a region of the plugin's layer, in a `batch_update`; they
+/// replace those from `start` to `start + len`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpanUpdate {
+    pub start: usize,
+    pub len: usize,
+    pub spans: Vec<ScopeSpan>,
+}
+
+/// A span of the plugin's style layer, for a plugin which sends styles
+/// rather than scopes. `style` has the fields of a style in a `def_style`
+/// notification to the frontend, such as `fg_color` and `italic`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StyleSpan {
+    pub start: usize,
+    pub end: usize,
+    pub style: Value,
+}
+
+/// An operation of an `edit_spans` request, on the spans of the plugin's
+/// layer.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SpanOp {
+    /// Keeps the spans of the next `n` bytes.
+    Retain(usize),
+    /// Clears the spans of the next `n` bytes.
+    Skip(usize),
+    /// Replaces the spans of the next `len` bytes with `spans`, whose
+    /// offsets are relative to the start of those bytes.
+    Insert { len: usize, spans: Vec<ScopeSpan> },
+}
+
+/// A span whose scopes matched the selector of a `find_scopes` request.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ScopeMatch {
+    pub start: usize,
+    pub end: usize,
+    /// The id of the plugin whose layer the span is in.
+    pub plugin: usize,
+}
+
 pub struct PluginCtx<'a>(RpcCtx<'a>);
 
 impl<'a> PluginCtx<'a> {
@@ -102,6 +144,83 @@ impl<'a> PluginCtx<'a> {
         self.send_rpc_notification("update_spans", &params);
     }
 
+    /// Adds `scopes` and updates several regions of the plugin's spans at
+    /// once, so that the core resolves their styles once.
+    pub fn batch_update(&self, view_id: &str, rev: u64, scopes: &[Vec<String>],
+                        updates: &[SpanUpdate]) {
+        let params = json!({
+            "view_id": view_id,
+            "rev": rev,
+            "scopes": scopes,
+            "updates": updates,
+        });
+        self.send_rpc_notification("batch_update", &params);
+    }
+
+    pub fn update_style_spans(&self, view_id: &str, start: usize, len: usize, rev: u64,
+                              spans: &[StyleSpan]) {
+        let params = json!({
+            "view_id": view_id,
+            "start": start,
+            "len": len,
+            "rev": rev,
+            "spans": spans,
+        });
+        self.send_rpc_notification("update_style_spans", &params);
+    }
+
+    /// Updates the spans of the plugin's layer with `ops`, which must cover
+    /// the buffer as of `rev`.
+    pub fn edit_spans(&self, view_id: &str, rev: u64, ops: &[SpanOp]) -> Result<(), Error> {
+        let params = json!({
+            "view_id": view_id,
+            "rev": rev,
+            "ops": ops,
+        });
+        match self.send_rpc_request("edit_spans", &params) {
+            Ok(Value::Null) => Ok(()),
+            Ok(_) => Err(Error::WrongReturnType),
+            Err(err) => Err(Error::RpcError(err)),
+        }
+    }
+
+    /// Returns the spans from `start` to `end`, in any plugin's layer, whose
+    /// scopes match the scope selector `selector`.
+    pub fn find_scopes(&self, view_id: &str, selector: &str, start: usize,
+                       end: usize) -> Result<Vec<ScopeMatch>, Error> {
+        let params = json!({
+            "view_id": view_id,
+            "selector": selector,
+            "start": start,
+            "end": end,
+        });
+        match self.send_rpc_request("find_scopes", &params) {
+            Ok(result) => serde_json::from_value(result).map_err(|_| Error::WrongReturnType),
+            Err(err) => Err(Error::RpcError(err)),
+        }
+    }
+
+    /// Returns the scopes at `offset`, those of each plugin's layer in order
+    /// of priority.
+    pub fn get_scope_at(&self, view_id: &str, offset: usize) -> Result<Vec<String>, Error> {
+        let params = json!({
+            "view_id": view_id,
+            "offset": offset,
+        });
+        match self.send_rpc_request("get_scope_at", &params) {
+            Ok(result) => serde_json::from_value(result).map_err(|_| Error::WrongReturnType),
+            Err(err) => Err(Error::RpcError(err)),
+        }
+    }
+
+    pub fn set_layer_priority(&self, view_id: &str, priority: u32) {
+        let params = json!({
+            "view_id": view_id,
+            "priority": priority,
+        });
+        self.send_rpc_notification("set_layer_priority", &params);
+    }
+
     fn send_rpc_notification(&self, method: &str, params: &Value) {
         self.0.get_peer().send_rpc_notification(method, params)
     }
@@ -22,7 +22,7 @@ use rand::{thread_rng, Rng};
 use plugin_base;
 use plugin_base::{PluginBufferInfo, PluginRequest};
 
-pub use plugin_base::{Error, ScopeSpan};
+pub use plugin_base::{Error, ScopeMatch, ScopeSpan, SpanOp, SpanUpdate, StyleSpan};
 
 const CHUNK_SIZE: usize = 1024 * 1024;
 const CACHE_SIZE: usize = 1024;
@@ -193,6 +193,31 @@ impl<'a, S: Default + Clone> PluginCtx<'a, S> {
         self.peer.update_spans(&self.state.view_id, start, len, self.state.rev, spans)
     }
 
+    pub fn batch_update(&self, scopes: &[Vec<String>], updates: &[SpanUpdate]) {
+        self.peer.batch_update(&self.state.view_id, self.state.rev, scopes, updates)
+    }
+
+    pub fn update_style_spans(&self, start: usize, len: usize, spans: &[StyleSpan]) {
+        self.peer.update_style_spans(&self.state.view_id, start, len, self.state.rev, spans)
+    }
+
+    pub fn edit_spans(&self, ops: &[SpanOp]) -> Result<(), Error> {
+        self.peer.edit_spans(&self.state.view_id, self.state.rev, ops)
+    }
+
+    pub fn find_scopes(&self, selector: &str, start: usize, end: usize)
+                       -> Result<Vec<ScopeMatch>, Error> {
+        self.peer.find_scopes(&self.state.view_id, selector, start, end)
+    }
+
+    pub fn get_scope_at(&self, offset: usize) -> Result<Vec<String>, Error> {
+        self.peer.get_scope_at(&self.state.view_id, offset)
+    }
+
+    pub fn set_layer_priority(&self, priority: u32) {
+        self.peer.set_layer_priority(&self.state.view_id, priority)
+    }
+
     /// Determines whether an incoming request (or notification) is pending. This
     /// is intended to reduce latency for bulk operations done in the background.
     pub fn request_is_pending(&self) -> bool {
@@ -246,6 +246,30 @@ impl<N: NodeInfo> Delta<N> {
         )
     }
 
+    /// Returns the edits of the delta, in order: each replaces an interval
+    /// of the base document with the given number of new units. Intervals
+    /// neither overlap nor touch each other.
+    pub fn edits(&self) -> Vec<(Interval, usize)> {
+        let mut edits = Vec::new();
+        let (mut last_end, mut inserted) = (0, 0);
+        for el in &self.els {
+            match *el {
+                DeltaElement::Copy(beg, end) => {
+                    if beg > last_end || inserted > 0 {
+                        edits.push((Interval::new_closed_open(last_end, beg), inserted));
+                    }
+                    last_end = end;
+                    inserted = 0;
+                }
+                DeltaElement::Insert(ref n) => inserted += n.len(),
+            }
+        }
+        if last_end < self.base_len || inserted > 0 {
+            edits.push((Interval::new_closed_open(last_end, self.base_len), inserted));
+        }
+        edits
+    }
+
     /// Iterates over all the inserts of the delta.
     pub fn iter_inserts(&self) -> InsertsIter<N> {
         InsertsIter {
@@ -627,7 +651,7 @@ impl<'a, N: NodeInfo> Iterator for DeletionsIter<'a, N> {
 
 #[cfg(test)]
 mod tests {
-    use rope::Rope;
+    use rope::{Rope, RopeInfo};
     use delta::{Delta, Builder, DeltaRegion};
     use interval::Interval;
     use test_helpers::find_deletions;
@@ -734,4 +758,22 @@ mod tests {
         assert_eq!(Some(DeltaRegion::new(8, 4, 2)), iter.next());
         assert_eq!(None, iter.next());
     }
+
+    #[test]
+    fn edits() {
+        let mut builder = Builder::new(10);
+        builder.replace(Interval::new_closed_open(0, 0), Rope::from("a"));
+        builder.delete(Interval::new_closed_open(3, 5));
+        builder.replace(Interval::new_closed_open(6, 8), Rope::from("bc"));
+        builder.replace(Interval::new_closed_open(10, 10), Rope::from("d"));
+        let delta = builder.build();
+        assert_eq!("a0125bc89d", delta.apply_to_string("0123456789"));
+        let edits = delta.edits().into_iter()
+            .map(|(iv, len)| (iv.start(), iv.end(), len))
+            .collect::<Vec<_>>();
+        assert_eq!(edits, vec![(0, 0, 1), (3, 5, 0), (6, 8, 2), (10, 10, 1)]);
+
+        let unchanged = Builder::<RopeInfo>::new(10).build();
+        assert!(unchanged.edits().is_empty());
+    }
 }
@@ -284,6 +284,12 @@ impl Engine {
         self.revs.last().unwrap().max_undo_so_far
     }
 
+    /// Returns the number of revisions kept, which doesn't grow with edits
+    /// whose undo groups are collected by `gc`.
+    pub fn revision_count(&self) -> usize {
+        self.revs.len()
+    }
+
     /// Get revision id of head revision.
     pub fn get_head_rev_id(&self) -> RevId {
         self.revs.last().unwrap().rev_id
@@ -302,11 +308,30 @@ impl Engine {
     /// A delta that, when applied to `base_rev`, results in the current head. Panics
     /// if there is not at least one edit.
     pub fn delta_rev_head(&self, base_rev: RevToken) -> Delta<RopeInfo> {
-        let ix = self.find_rev_token(base_rev).expect("base revision not found");
+        self.try_delta_rev_head(base_rev).expect("base revision not found")
+    }
+
+    /// As `delta_rev_head`, but returns `None` if `base_rev` is unknown, as when
+    /// it has been garbage collected.
+    pub fn try_delta_rev_head(&self, base_rev: RevToken) -> Option<Delta<RopeInfo>> {
+        let ix = self.find_rev_token(base_rev)?;
         let prev_from_union = self.deletes_from_cur_union_for_index(ix);
         // TODO: this does 2 calls to Delta::synthesize and 1 to apply, this probably could be better.
         let old_tombstones = shuffle_tombstones(&self.text, &self.tombstones, &self.deletes_from_union, &prev_from_union);
-        Delta::synthesize(&old_tombstones, &prev_from_union, &self.deletes_from_union)
+        Some(Delta::synthesize(&old_tombstones, &prev_from_union, &self.deletes_from_union))
+    }
+
+    /// A delta that, when applied to the text with the undo groups in `from`
+    /// undone, results in the text with those in `to` undone. The engine is
+    /// left unchanged.
+    pub fn delta_between_undos(&self, from: &BTreeSet<usize>, to: &BTreeSet<usize>)
+        -> Delta<RopeInfo>
+    {
+        let (_, from_deletes) = self.compute_undo(from);
+        let (_, to_deletes) = self.compute_undo(to);
+        let from_tombstones = shuffle_tombstones(&self.text, &self.tombstones,
+                                                 &self.deletes_from_union, &from_deletes);
+        Delta::synthesize(&from_tombstones, &from_deletes, &to_deletes)
     }
 
     // TODO: don't construct transform if subsets are empty
@@ -602,6 +627,28 @@ impl Engine {
         assert_eq!(1, self.revs.len(), "Revisions were added to an Engine before set_session_id, these may collide.");
         self.session = session;
     }
+
+    /// Like `set_session_id`, but for an Engine which already has revisions,
+    /// which must be identical in every Engine it is merged with, as the
+    /// initial contents from `Engine::new` are when each loads the same text.
+    /// Only the revisions made from now on are in the new session.
+    pub fn start_session(&mut self, session: SessionId) {
+        self.session = session;
+    }
+
+    /// Returns the session ID of the revisions made by this Engine.
+    pub fn session_id(&self) -> SessionId {
+        self.session
+    }
+
+    /// Returns true if any revision is an undo. Merging Engines with undos
+    /// isn't supported yet.
+    pub fn has_undos(&self) -> bool {
+        self.revs[1..].iter().any(|rev| match rev.edit {
+            Undo { .. } => true,
+            Edit { .. } => false,
+        })
+    }
 }
 
 // ======== Generic helpers
@@ -971,6 +1018,28 @@ mod tests {
         assert_eq!("ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz", String::from(engine.get_head()));
     }
 
+    #[test]
+    fn delta_between_undos() {
+        let mut engine = Engine::new(Rope::from(TEST_STR));
+        let first_rev = engine.get_head_rev_id().token();
+        engine.edit_rev(1, 1, first_rev, build_delta_1());
+        let after_first_edit = engine.get_head_rev_id().token();
+        engine.edit_rev(0, 2, first_rev, build_delta_2());
+        let head = String::from(engine.get_head());
+        let none = BTreeSet::new();
+        let second: BTreeSet<usize> = [2].iter().cloned().collect();
+        let both: BTreeSet<usize> = [1, 2].iter().cloned().collect();
+        let d = engine.delta_between_undos(&none, &both);
+        assert_eq!(TEST_STR, d.apply_to_string(&head));
+        let d = engine.delta_between_undos(&second, &both);
+        let after_first = String::from(engine.get_rev(after_first_edit).unwrap());
+        assert_eq!(TEST_STR, d.apply_to_string(&after_first));
+        let d = engine.delta_between_undos(&both, &none);
+        assert_eq!(head, d.apply_to_string(TEST_STR));
+        // the head is unchanged
+        assert_eq!(head, String::from(engine.get_head()));
+    }
+
     #[test]
     fn gc() {
         let mut engine = Engine::new(Rope::from(TEST_STR));
@@ -1588,4 +1657,25 @@ mod tests {
         ];
         MergeTestState::new(3).run_script(&script[..]);
     }
+
+    #[test]
+    fn merge_sessions_started_after_initial_contents() {
+        let edited = |session, delta| {
+            let mut engine = Engine::new(Rope::from("abc"));
+            engine.start_session(session);
+            let base_rev = engine.get_head_rev_id().token();
+            engine.edit_rev(1, 1, base_rev, parse_delta(delta));
+            engine
+        };
+        let mut a = edited((2, 0), "-x--");
+        let mut b = edited((3, 0), "---y");
+        assert_eq!(a.session_id(), (2, 0));
+        a.merge(&edited((3, 0), "---y"));
+        b.merge(&edited((2, 0), "-x--"));
+        assert_eq!(String::from(a.get_head()), "axbcy");
+        assert_eq!(String::from(b.get_head()), "axbcy");
+        assert!(!a.has_undos());
+        a.undo([1].iter().cloned().collect());
+        assert!(a.has_undos());
+    }
 }
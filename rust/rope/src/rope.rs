@@ -24,6 +24,7 @@ use std::str;
 
 use tree::{Leaf, Node, NodeInfo, Metric, TreeBuilder, Cursor};
 use interval::Interval;
+use delta::Delta;
 
 use bytecount;
 use memchr::memchr;
@@ -38,6 +39,9 @@ const MAX_LEAF: usize = 1024;
 /// (bytes) and has boundaries at code points.
 pub type Rope = Node<RopeInfo>;
 
+/// An edit of a rope.
+pub type RopeDelta = Delta<RopeInfo>;
+
 impl Leaf for String {
     fn len(&self) -> usize {
         self.len()
@@ -20,8 +20,11 @@ use std::marker::PhantomData;
 use std::mem;
 use std::fmt;
 
+use serde::ser::{Serialize, SerializeSeq, Serializer};
+use serde::de::{self, Deserialize, Deserializer};
+
 use tree::{Leaf, Node, NodeInfo, TreeBuilder, Cursor};
-use delta::Transformer;
+use delta::{Delta, Transformer};
 use interval::Interval;
 
 const MIN_LEAF: usize = 32;
@@ -157,6 +160,12 @@ pub struct SpanIter<'a, T: 'a + Clone + Default> {
 }
 
 impl<T: Clone + Default> Spans<T> {
+    /// Returns the number of spans. Spans are counted in pieces, one for
+    /// each leaf of the tree they overlap.
+    pub fn count(&self) -> usize {
+        self.info().n_spans
+    }
+
     /// Perform operational transformation on a spans object intended to be edited into
     /// a sequence at the given offset.
 
@@ -182,6 +191,25 @@ impl<T: Clone + Default> Spans<T> {
         builder.build()
     }
 
+    /// Returns the spans after the edits of `delta`, which applies to a
+    /// sequence of the same length. The spans of the text it keeps are kept,
+    /// cut where they cross a deletion, and inserted text has no spans.
+    ///
+    /// The cost is in the number of edits, not the number of spans.
+    pub fn apply_delta<N: NodeInfo>(&self, delta: &Delta<N>) -> Self {
+        let mut b = TreeBuilder::new();
+        let mut last_end = 0;
+        for (iv, new_len) in delta.edits() {
+            self.push_subseq(&mut b, Interval::new_closed_open(last_end, iv.start()));
+            if new_len > 0 {
+                b.push(SpansBuilder::new(new_len).build());
+            }
+            last_end = iv.end();
+        }
+        self.push_subseq(&mut b, Interval::new_closed_open(last_end, self.len()));
+        b.build()
+    }
+
     /// Creates a new Spans instance by merging spans from `other` with `self`,
     /// using a closure to transform values.
     ///
@@ -283,6 +311,25 @@ impl<T: Clone + Default> Spans<T> {
         sb.build()
     }
 
+    /// Returns the span covering the unit at `offset`, if any. It is found
+    /// within the leaf containing `offset`, without iterating over the
+    /// spans before it. A span which crosses leaves is returned as the piece
+    /// of it within that leaf.
+    pub fn span_at(&self, offset: usize) -> Option<(Interval, &T)> {
+        if offset >= self.len() {
+            return None;
+        }
+        let cursor = Cursor::new(self, offset);
+        let (leaf, pos) = match cursor.get_leaf() {
+            Some(leaf) => leaf,
+            None => return None,
+        };
+        let unit = Interval::new_closed_open(pos, pos + 1);
+        leaf.spans.iter()
+            .find(|span| !span.iv.intersect(unit).is_empty())
+            .map(|span| (span.iv.translate(offset - pos), &span.data))
+    }
+
     // possible future: an iterator that takes an interval, so results are the same as
     // taking a subseq on the spans object. Would require specialized Cursor.
     pub fn iter(&self) -> SpanIter<T> {
@@ -303,6 +350,41 @@ impl<T: Clone + Default + fmt::Debug> fmt::Debug for Spans<T> {
     }
 }
 
+/// `Spans` are serialized as an array of `(start, end, data)` tuples, in
+/// order. The length isn't kept: deserialized spans end with the last span.
+impl<T: Clone + Default + Serialize> Serialize for Spans<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        let mut seq = serializer.serialize_seq(Some(self.count()))?;
+        for (iv, data) in self.iter() {
+            seq.serialize_element(&(iv.start(), iv.end(), data))?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de, T: Clone + Default + Deserialize<'de>> Deserialize<'de> for Spans<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>,
+    {
+        let spans = Vec::<(usize, usize, T)>::deserialize(deserializer)?;
+        let mut prev_end = 0;
+        for &(start, end, _) in &spans {
+            if start < prev_end || end < start {
+                return Err(de::Error::custom(format!(
+                    "span {}..{} is out of order", start, end)));
+            }
+            prev_end = end;
+        }
+        let mut sb = SpansBuilder::new(prev_end);
+        for (start, end, data) in spans {
+            sb.add_span(Interval::new_closed_open(start, end), data);
+        }
+        Ok(sb.build())
+    }
+}
+
 impl<'a, T: Clone + Default> Iterator for SpanIter<'a, T> {
     type Item = (Interval, &'a T);
 
@@ -369,6 +451,8 @@ mod tests {
         assert_eq!(*val, 16);
 
         assert!(merged_iter.next().is_none());
+        assert_eq!(merged.count(), 6);
+        assert_eq!(red.count(), 2);
     }
 
     #[test]
@@ -419,4 +503,91 @@ mod tests {
 
         assert!(merged_iter.next().is_none());
     }
+
+    #[test]
+    fn test_span_at() {
+        // spans over several leaves, with gaps between them
+        let n = MAX_LEAF * 4;
+        let mut sb = SpansBuilder::new(n * 3);
+        for i in 0..n {
+            sb.add_span(Interval::new_closed_open(i * 3, i * 3 + 2), i);
+        }
+        let spans = sb.build();
+        assert!(spans.count() > MAX_LEAF);
+        for i in 0..n {
+            let iv = Interval::new_closed_open(i * 3, i * 3 + 2);
+            assert_eq!(spans.span_at(i * 3), Some((iv, &i)));
+            assert_eq!(spans.span_at(i * 3 + 1).map(|(_, &val)| val), Some(i));
+            assert_eq!(spans.span_at(i * 3 + 2), None);
+        }
+        assert_eq!(spans.span_at(n * 3), None);
+
+        let mut sb = SpansBuilder::new(4);
+        sb.add_span(Interval::new_open_open(0, 2), 1);
+        let spans = sb.build();
+        assert_eq!(spans.span_at(0).map(|(_, &val)| val), Some(1));
+        assert_eq!(spans.span_at(2), None);
+    }
+
+    #[test]
+    fn test_apply_delta() {
+        use delta::Builder;
+        use rope::Rope;
+
+        // 1 1 1 1   2 2     3 3
+        let mut sb = SpansBuilder::new(10);
+        sb.add_span(Interval::new_closed_open(0, 4), 1u32);
+        sb.add_span(Interval::new_closed_open(5, 7), 2);
+        sb.add_span(Interval::new_closed_open(8, 10), 3);
+        let spans = sb.build();
+
+        let mut builder = Builder::new(10);
+        builder.replace(Interval::new_closed_open(2, 2), Rope::from("ab"));
+        builder.delete(Interval::new_closed_open(6, 9));
+        let spans = spans.apply_delta(&builder.build());
+        assert_eq!(spans.len(), 9);
+        let spans = spans.iter()
+            .map(|(iv, &val)| (iv.start(), iv.end(), val))
+            .collect::<Vec<_>>();
+        assert_eq!(spans, vec![(0, 2, 1), (4, 6, 1), (7, 8, 2), (8, 9, 3)]);
+    }
+
+    /// Compares spans by their contents, which `Spans` itself can't.
+    #[derive(Debug)]
+    struct Contents(Spans<u32>);
+
+    impl PartialEq for Contents {
+        fn eq(&self, other: &Contents) -> bool {
+            self.0.len() == other.0.len() && self.0.iter().eq(other.0.iter())
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Contents {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            Spans::deserialize(deserializer).map(Contents)
+        }
+    }
+
+    #[test]
+    fn test_ser_de() {
+        use serde_test::{Token, assert_ser_tokens, assert_de_tokens, assert_de_tokens_error};
+        let mut sb = SpansBuilder::new(10);
+        sb.add_span(Interval::new_closed_open(1, 3), 7);
+        sb.add_span(Interval::new_closed_open(5, 6), 8);
+        let spans: Spans<u32> = sb.build();
+        let tokens = |second_start| vec![
+            Token::Seq { len: Some(2) },
+            Token::Tuple { len: 3 }, Token::U64(1), Token::U64(3), Token::U32(7), Token::TupleEnd,
+            Token::Tuple { len: 3 }, Token::U64(second_start), Token::U64(6), Token::U32(8),
+            Token::TupleEnd,
+            Token::SeqEnd,
+        ];
+        assert_ser_tokens(&spans, &tokens(5));
+        // the gap after the last span isn't kept
+        let mut sb = SpansBuilder::new(6);
+        sb.add_span(Interval::new_closed_open(1, 3), 7);
+        sb.add_span(Interval::new_closed_open(5, 6), 8);
+        assert_de_tokens(&Contents(sb.build()), &tokens(5));
+        assert_de_tokens_error::<Contents>(&tokens(2), "span 2..6 is out of order");
+    }
 }
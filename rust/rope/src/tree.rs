@@ -180,6 +180,10 @@ impl<N: NodeInfo> Node<N> {
         self.0.height
     }
 
+    pub(crate) fn info(&self) -> &N {
+        &self.0.info
+    }
+
     fn is_leaf(&self) -> bool {
         self.0.height == 0
     }
@@ -0,0 +1,192 @@
+// Copyright 2017 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Optional compression of large messages.
+//!
+//! Once both peers have agreed on an algorithm (see `negotiate`), a
+//! message longer than the agreed threshold may be sent as the object
+//! `{"compressed": <algorithm name>, "data": <base64 encoded payload>}`,
+//! on a single line. Compressed messages are decompressed by the
+//! `MessageReader`, and are otherwise indistinguishable from plain ones.
+//!
+//! The available algorithms depend on the crate's features; currently
+//! the only one is `"deflate"`, behind the `deflate` feature.
+
+use std::io;
+
+use serde_json::{self, Value};
+
+use error::ReadError;
+
+/// The threshold used if the peer doesn't request one.
+pub const DEFAULT_THRESHOLD: usize = 1 << 16;
+
+/// Messages shorter than this are never compressed.
+pub const MIN_THRESHOLD: usize = 1 << 10;
+
+/// A compression algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    #[cfg(feature = "deflate")]
+    Deflate,
+}
+
+/// The compression settings for one direction of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub algorithm: Algorithm,
+    /// Messages longer than this many bytes are compressed.
+    pub threshold: usize,
+}
+
+impl Algorithm {
+    /// Returns the algorithms supported by this build, most preferred first.
+    pub fn supported() -> Vec<Algorithm> {
+        #[allow(unused_mut)]
+        let mut algorithms = Vec::new();
+        #[cfg(feature = "deflate")]
+        algorithms.push(Algorithm::Deflate);
+        algorithms
+    }
+
+    /// Returns the algorithm's name, as used on the wire.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "deflate")]
+            Algorithm::Deflate => "deflate",
+        }
+    }
+
+    /// Returns the supported algorithm called `name`, if any.
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        Algorithm::supported().into_iter().find(|alg| alg.name() == name)
+    }
+
+    #[allow(unused_variables)]
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "deflate")]
+            Algorithm::Deflate => {
+                use std::io::Write;
+                let mut encoder = ::libflate::deflate::Encoder::new(Vec::new());
+                encoder.write_all(data)?;
+                encoder.finish().into_result()
+            }
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "deflate")]
+            Algorithm::Deflate => {
+                use std::io::Read;
+                let mut decoder = ::libflate::deflate::Decoder::new(data);
+                let mut result = Vec::new();
+                decoder.read_to_end(&mut result)?;
+                Ok(result)
+            }
+        }
+    }
+}
+
+/// Chooses the compression to use with a peer offering `algorithms`, in
+/// order of its preference, and requesting `threshold`. Returns `None` if
+/// no offered algorithm is supported.
+pub fn negotiate<S: AsRef<str>>(algorithms: &[S], threshold: Option<usize>)
+                                -> Option<Compression> {
+    algorithms.iter()
+        .filter_map(|name| Algorithm::from_name(name.as_ref()))
+        .next()
+        .map(|algorithm| Compression {
+            algorithm: algorithm,
+            threshold: threshold.unwrap_or(DEFAULT_THRESHOLD).max(MIN_THRESHOLD),
+        })
+}
+
+/// Returns the line to send for the serialized message `msg`, which is
+/// compressed if it is longer than the threshold.
+pub fn encode(msg: String, compression: Option<&Compression>) -> String {
+    let compression = match compression {
+        Some(compression) if msg.len() > compression.threshold => compression,
+        _ => return msg,
+    };
+    match compression.algorithm.compress(msg.as_bytes()) {
+        Ok(data) => serde_json::to_string(&json!({
+            "compressed": compression.algorithm.name(),
+            "data": ::base64::encode(&data),
+        })).unwrap(),
+        Err(e) => {
+            print_err!("failed to compress message: {}", e);
+            msg
+        }
+    }
+}
+
+/// If `val` is a compressed message, returns the message it contains.
+pub fn decode(val: Value) -> Result<Value, ReadError> {
+    let (name, data) = match (val.get("compressed"), val.get("data")) {
+        (Some(&Value::String(ref name)), Some(&Value::String(ref data))) => (name, data),
+        _ => return Ok(val),
+    };
+    let algorithm = Algorithm::from_name(name)
+        .ok_or_else(|| ReadError::Compression(format!("unsupported algorithm '{}'", name)))?;
+    let data = ::base64::decode(data).map_err(|e| ReadError::Compression(e.to_string()))?;
+    let msg = algorithm.decompress(&data)
+        .map_err(|e| ReadError::Compression(e.to_string()))?;
+    Ok(serde_json::from_slice(&msg)?)
+}
+
+#[cfg(all(test, feature = "deflate"))]
+mod tests {
+    use super::*;
+
+    fn deflate(threshold: usize) -> Compression {
+        Compression { algorithm: Algorithm::Deflate, threshold: threshold }
+    }
+
+    #[test]
+    fn negotiation() {
+        assert_eq!(negotiate(&["zstd", "deflate"], None), Some(deflate(DEFAULT_THRESHOLD)));
+        assert_eq!(negotiate(&["deflate"], Some(0)), Some(deflate(MIN_THRESHOLD)));
+        assert_eq!(negotiate(&["lzma"], None), None);
+        assert_eq!(negotiate::<&str>(&[], None), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let msg = json!({"method": "update", "params": {"text": "abc\n".repeat(1000)}});
+        let line = serde_json::to_string(&msg).unwrap();
+        let compression = deflate(MIN_THRESHOLD);
+        let encoded = encode(line.clone(), Some(&compression));
+        assert!(encoded.len() < line.len() / 10);
+        let val = serde_json::from_str::<Value>(&encoded).unwrap();
+        assert_eq!(val["compressed"], json!("deflate"));
+        assert_eq!(decode(val).unwrap(), msg);
+
+        // short messages, and uncompressed connections, are unchanged
+        let short = r#"{"id":1,"result":null}"#.to_owned();
+        assert_eq!(encode(short.clone(), Some(&compression)), short);
+        assert_eq!(encode(line.clone(), None), line);
+        assert_eq!(decode(msg.clone()).unwrap(), msg);
+    }
+
+    #[test]
+    fn bad_messages() {
+        let unknown = json!({"compressed": "lzma", "data": ""});
+        assert!(decode(unknown).is_err());
+        let corrupt = json!({"compressed": "deflate", "data": "not base64!"});
+        assert!(decode(corrupt).is_err());
+    }
+}
@@ -43,6 +43,8 @@ pub enum ReadError {
     NotObject,
     /// The the method and params were not recognized by the handler.
     UnknownRequest(JsonError),
+    /// A compressed message could not be decompressed.
+    Compression(String),
     /// The peer closed the connection.
     Disconnect,
 }
@@ -145,6 +147,7 @@ impl fmt::Display for ReadError {
             ReadError::Json(ref err) => write!(f, "JSON Error: {:?}", err),
             ReadError::NotObject => write!(f, "JSON message was not an object."),
             ReadError::UnknownRequest(ref err) => write!(f, "Unknown request: {:?}", err),
+            ReadError::Compression(ref msg) => write!(f, "Compression Error: {}", msg),
             ReadError::Disconnect => write!(f, "Peer closed the connection."),
         }
     }
@@ -28,12 +28,16 @@ extern crate serde_json;
 extern crate serde_derive;
 extern crate serde;
 extern crate crossbeam;
+extern crate base64;
+#[cfg(feature = "deflate")]
+extern crate libflate;
 
 #[macro_use]
 mod macros;
 mod parse;
 mod error;
 
+pub mod compression;
 pub mod test_utils;
 
 use std::collections::{BTreeMap, VecDeque};
@@ -41,11 +45,13 @@ use std::io::{self, BufRead, Write};
 use std::sync::{Arc, Mutex, Condvar};
 use std::sync::mpsc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use serde_json::Value;
 use serde::de::DeserializeOwned;
 
 use parse::{Call, Response, RpcObject, MessageReader};
+use compression::Compression;
 pub use error::{Error, ReadError, RemoteError};
 
 
@@ -82,6 +88,9 @@ pub trait Peer: Send + 'static {
     /// pending. This is intended to reduce latency for bulk operations
     /// done in the background.
     fn request_is_pending(&self) -> bool;
+    /// Sets the compression applied to large outgoing messages, which
+    /// should have been negotiated with the peer.
+    fn set_compression(&self, compression: Option<Compression>);
 }
 
 /// The `Peer` trait object.
@@ -90,6 +99,7 @@ pub type RpcPeer = Box<Peer>;
 pub struct RpcCtx<'a> {
     peer: RpcPeer,
     idle: &'a mut VecDeque<usize>,
+    timers: &'a mut Vec<(Instant, usize)>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -113,6 +123,11 @@ pub trait Handler {
     fn handle_notification(&mut self, ctx: RpcCtx, rpc: Self::Notification);
     fn handle_request(&mut self, ctx: RpcCtx, rpc: Self::Request)
                       -> Result<Value, RemoteError>;
+    /// Returns the error sent for a request which couldn't be parsed,
+    /// given the parse error.
+    fn invalid_request(&mut self, err: RemoteError) -> RemoteError {
+        err
+    }
     #[allow(unused_variables)]
     fn idle(&mut self, ctx: RpcCtx, token: usize) {}
 }
@@ -159,6 +174,7 @@ struct RpcState<W: Write> {
     writer: Mutex<W>,
     id: AtomicUsize,
     pending: Mutex<BTreeMap<usize, ResponseHandler>>,
+    compression: Mutex<Option<Compression>>,
 }
 
 /// A structure holding the state of a main loop for handling RPC's.
@@ -177,6 +193,7 @@ impl<W: Write + Send> RpcLoop<W> {
             writer: Mutex::new(writer),
             id: AtomicUsize::new(0),
             pending: Mutex::new(BTreeMap::new()),
+            compression: Mutex::new(None),
         }));
         RpcLoop {
             reader: MessageReader::default(),
@@ -245,7 +262,17 @@ impl<W: Write + Send> RpcLoop<W> {
             });
 
             let mut idle = VecDeque::<usize>::new();
+            let mut timers = Vec::<(Instant, usize)>::new();
             loop {
+                // expired timers are handled as idle work
+                let now = Instant::now();
+                if timers.iter().any(|&(deadline, _)| deadline <= now) {
+                    let (due, pending): (Vec<_>, Vec<_>) = timers.drain(..)
+                        .partition(|&(deadline, _)| deadline <= now);
+                    timers = pending;
+                    idle.extend(due.into_iter().map(|(_, token)| token));
+                }
+
                 // pending messages take priority over idle work
                 let json = if !idle.is_empty() {
                     if let Some(json) = peer.try_get_rx() {
@@ -255,10 +282,19 @@ impl<W: Write + Send> RpcLoop<W> {
                         let ctx = RpcCtx {
                             peer: Box::new(peer.clone()),
                             idle: &mut idle,
+                            timers: &mut timers,
                         };
                         handler.idle(ctx, token);
                         continue;
                     }
+                } else if let Some(deadline) = timers.iter().map(|&(d, _)| d).min() {
+                    let timeout = if deadline > now { deadline - now } else {
+                        Duration::from_secs(0)
+                    };
+                    match peer.get_rx_timeout(timeout) {
+                        Some(json) => json,
+                        None => continue,
+                    }
                 } else {
                     peer.get_rx()
                 };
@@ -274,6 +310,7 @@ impl<W: Write + Send> RpcLoop<W> {
                 let ctx = RpcCtx {
                     peer: Box::new(peer.clone()),
                     idle: &mut idle,
+                    timers: &mut timers,
                 };
 
                 let parsed = json.into_rpc::<H::Notification, H::Request>();
@@ -283,7 +320,10 @@ impl<W: Write + Send> RpcLoop<W> {
                         peer.respond(result, id);
                     }
                     Ok(Call::Notification(cmd)) => handler.handle_notification(ctx, cmd),
-                    Ok(Call::InvalidRequest(id, err)) => peer.respond(Err(err), id),
+                    Ok(Call::InvalidRequest(id, err)) => {
+                        let err = handler.invalid_request(err);
+                        peer.respond(Err(err), id);
+                    }
                     Err(err) => {
                         peer.disconnect();
                         return ReadError::UnknownRequest(err)
@@ -308,6 +348,12 @@ impl<'a> RpcCtx<'a> {
     pub fn schedule_idle(&mut self, token: usize) {
         self.idle.push_back(token);
     }
+
+    /// Schedule the idle handler to be run with `token` once `deadline`
+    /// has passed, and there are no requests pending.
+    pub fn schedule_timer(&mut self, deadline: Instant, token: usize) {
+        self.timers.push((deadline, token));
+    }
 }
 
 impl<W: Write + Send + 'static> Peer for RawPeer<W> {
@@ -343,11 +389,16 @@ impl<W: Write + Send + 'static> Peer for RawPeer<W> {
         let queue = self.0.rx_queue.lock().unwrap();
         !queue.is_empty()
     }
+
+    fn set_compression(&self, compression: Option<Compression>) {
+        *self.0.compression.lock().unwrap() = compression;
+    }
 }
 
 impl<W:Write> RawPeer<W> {
     fn send(&self, v: &Value) -> Result<(), io::Error> {
-        let mut s = serde_json::to_string(v).unwrap();
+        let s = serde_json::to_string(v).unwrap();
+        let mut s = compression::encode(s, self.0.compression.lock().unwrap().as_ref());
         s.push('\n');
         self.0.writer.lock().unwrap().write_all(s.as_bytes())
         // Technically, maybe we should flush here, but doesn't seem to be required.
@@ -410,6 +461,20 @@ impl<W:Write> RawPeer<W> {
         queue.pop_front().unwrap()
     }
 
+    /// Get a message from the receive queue, blocking for at most `timeout`.
+    fn get_rx_timeout(&self, timeout: Duration) -> Option<Result<RpcObject, ReadError>> {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.0.rx_queue.lock().unwrap();
+        while queue.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            queue = self.0.rx_cvar.wait_timeout(queue, deadline - now).unwrap().0;
+        }
+        queue.pop_front()
+    }
+
     /// Adds a message to the receive queue. The message should only
     /// be `None` if the read thread is exiting.
     fn put_rx(&self, json: Result<RpcObject, ReadError>) {
@@ -20,6 +20,7 @@ use serde_json::{self, Value, Error as JsonError};
 use serde::de::DeserializeOwned;
 
 use error::{RemoteError, ReadError};
+use compression;
 
 
 /// A unique identifier attached to request RPCs.
@@ -74,12 +75,13 @@ impl MessageReader {
         }
     }
 
-    /// Attempts to parse a &str as an RPC Object.
+    /// Attempts to parse a &str as an RPC Object, decompressing it if
+    /// necessary.
     ///
     /// This should not be called directly unless you are writing tests.
     #[doc(hidden)]
     pub fn parse(&self, s: &str) -> Result<RpcObject, ReadError> {
-        let val = serde_json::from_str::<Value>(&s)?;
+        let val = compression::decode(serde_json::from_str::<Value>(&s)?)?;
         if !val.is_object() {
             Err(ReadError::NotObject)
         } else {
@@ -99,6 +101,11 @@ impl RpcObject {
         self.0.get("method").and_then(Value::as_str)
     }
 
+    /// Returns the 'params' field of the underlying object, if present.
+    pub fn get_params(&self) -> Option<&Value> {
+        self.0.get("params")
+    }
+
     /// Returns `true` if this object looks like an RPC response;
     /// that is, if it has an 'id' field and does _not_ have a 'method'
     /// field.
@@ -55,6 +55,17 @@ impl DummyReader {
             .map(|s| self.0.parse(&s))
     }
 
+    /// Reads the next raw line sent to the channel, returning `None` if
+    /// the wait exceeds `timeout`.
+    pub fn next_line_timeout(&mut self, timeout: Duration) -> Option<String> {
+        self.1.recv_timeout(timeout).ok()
+    }
+
+    /// Parses a line returned by `next_line_timeout`.
+    pub fn parse(&self, line: &str) -> Result<RpcObject, ReadError> {
+        self.0.parse(line)
+    }
+
     /// Reads and parses a response object.
     ///
     /// # Panics
@@ -16,8 +16,8 @@
 extern crate serde_json;
 extern crate xi_rpc;
 
-use std::time::Duration;
-use std::io;
+use std::time::{Duration, Instant};
+use std::io::{self, Read};
 
 use serde_json::Value;
 use xi_rpc::{Handler, RpcLoop, RpcCtx, RpcCall, RemoteError, ReadError};
@@ -93,3 +93,93 @@ fn test_bad_json_err() {
         Ok(()) => panic!("Expected an error"),
     }
 }
+
+#[cfg(feature = "deflate")]
+#[test]
+fn test_compression_round_trip() {
+    use xi_rpc::Peer;
+    use xi_rpc::compression::{self, Compression, Algorithm};
+
+    let mut handler = EchoHandler;
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    rpc_looper.get_raw_peer().set_compression(Some(Compression {
+        algorithm: Algorithm::Deflate,
+        threshold: compression::MIN_THRESHOLD,
+    }));
+    // a large request arrives compressed, and the echoed response is
+    // compressed in turn; a small one is neither.
+    let words = "plz ".repeat(10_000);
+    let request = json!({"id": 1, "method": "hullo", "params": {"words": words}});
+    let small = r#"{"id": 2, "method": "hullo", "params": {"words": "yay"}}"#;
+    let line = compression::encode(request.to_string(), Some(&Compression {
+        algorithm: Algorithm::Deflate,
+        threshold: 0,
+    }));
+    assert!(line.len() < words.len() / 10);
+    let r = make_reader(format!("{}\n{}", line, small));
+    assert!(rpc_looper.mainloop(|| r, &mut handler).is_ok());
+
+    let line = rx.next_line_timeout(Duration::from_secs(1)).unwrap();
+    assert!(line.len() < words.len() / 10);
+    let resp = rx.parse(&line).unwrap().into_response().unwrap().unwrap();
+    assert_eq!(resp["words"], json!(words));
+    let line = rx.next_line_timeout(Duration::from_secs(1)).unwrap();
+    assert!(!line.contains("compressed"));
+    assert_eq!(rx.parse(&line).unwrap().into_response().unwrap().unwrap()["words"],
+               json!("yay"));
+}
+
+/// A reader which waits before reporting EOF, so that timers can fire.
+struct SlowEof(Duration);
+
+impl io::Read for SlowEof {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        ::std::thread::sleep(self.0);
+        Ok(0)
+    }
+}
+
+/// Handler that notifies the peer when timers scheduled by each
+/// notification fire.
+pub struct TimerHandler;
+
+#[allow(unused)]
+impl Handler for TimerHandler {
+    type Notification = RpcCall;
+    type Request = RpcCall;
+    fn handle_notification(&mut self, mut ctx: RpcCtx, rpc: Self::Notification) {
+        let delay = rpc.params["delay"].as_u64().unwrap();
+        ctx.schedule_timer(Instant::now() + Duration::from_millis(delay), delay as usize);
+    }
+    fn handle_request(&mut self, ctx: RpcCtx, rpc: Self::Request)
+                      -> Result<Value, RemoteError> {
+        Ok(rpc.params)
+    }
+    fn idle(&mut self, ctx: RpcCtx, token: usize) {
+        ctx.get_peer().send_rpc_notification("fired", &json!({"delay": token}));
+    }
+}
+
+#[test]
+fn test_timers() {
+    let mut handler = TimerHandler;
+    let (tx, mut rx) = test_channel();
+    let mut rpc_looper = RpcLoop::new(tx);
+    let input = r#"{"method": "wait", "params": {"delay": 60}}
+{"method": "wait", "params": {"delay": 20}}
+{"method": "wait", "params": {"delay": 10000}}
+"#;
+    let r = io::BufReader::new(input.as_bytes().chain(SlowEof(Duration::from_millis(200))));
+    assert!(rpc_looper.mainloop(|| r, &mut handler).is_ok());
+    let fired = (0..2)
+        .map(|_| {
+            let msg = rx.expect_object();
+            assert_eq!(msg.get_method(), Some("fired"));
+            msg.get_params().unwrap()["delay"].clone()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(fired, vec![json!(20), json!(60)]);
+    // timers which haven't expired when the connection closes never fire
+    assert!(rx.next_timeout(Duration::from_millis(100)).is_none());
+}
@@ -11,6 +11,7 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+use std::env;
 use std::io;
 
 #[macro_use]
@@ -24,7 +25,8 @@ use xi_core_lib::MainState;
 use xi_rpc::RpcLoop;
 
 fn main() {
-    let mut state = MainState::new();
+    let safe_mode = env::args().skip(1).any(|arg| arg == "--safe-mode");
+    let mut state = MainState::with_safe_mode(safe_mode);
     let stdin = io::stdin();
     let stdout = io::stdout();
     let mut rpc_looper = RpcLoop::new(stdout);